@@ -0,0 +1 @@
+ALTER TABLE chapters ADD COLUMN ordinal INTEGER NOT NULL DEFAULT 0;
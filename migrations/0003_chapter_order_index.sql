@@ -0,0 +1,12 @@
+ALTER TABLE chapters ADD COLUMN order_index INTEGER NOT NULL DEFAULT 0;
+
+UPDATE chapters SET order_index = (
+  SELECT ranked.order_index FROM (
+    SELECT id, row_number() OVER (
+      PARTITION BY book_id
+      ORDER BY coalesce(published_at, created_at), ordinal
+    ) AS order_index
+    FROM chapters
+  ) AS ranked
+  WHERE ranked.id = chapters.id
+);
@@ -0,0 +1 @@
+ALTER TABLE books ADD COLUMN conversion_options TEXT NOT NULL DEFAULT '{}';
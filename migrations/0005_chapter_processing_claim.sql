@@ -0,0 +1,2 @@
+ALTER TABLE chapters ADD COLUMN processing_started_at TEXT;
+ALTER TABLE chapters ADD COLUMN processing_worker_id TEXT;
@@ -0,0 +1,5 @@
+CREATE TABLE task_leases (
+  name TEXT PRIMARY KEY NOT NULL,
+  holder TEXT NOT NULL,
+  expires_at TEXT NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE books ADD COLUMN ignore_robots_txt INTEGER NOT NULL DEFAULT 0;
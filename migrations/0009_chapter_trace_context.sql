@@ -0,0 +1 @@
+ALTER TABLE chapters ADD COLUMN trace_context TEXT;
@@ -0,0 +1,12 @@
+CREATE TABLE deliveries (
+  id BLOB PRIMARY KEY NOT NULL,
+  subscription_id BLOB NOT NULL,
+  chapter_id BLOB NOT NULL,
+  chapter_created_at TEXT NOT NULL,
+  delivered_at TEXT NOT NULL,
+
+  CONSTRAINT fk_subscription_id FOREIGN KEY(subscription_id) REFERENCES subscriptions(id) ON DELETE CASCADE
+  CONSTRAINT fk_chapter_id FOREIGN KEY(chapter_id) REFERENCES chapters(id) ON DELETE CASCADE
+);
+
+CREATE INDEX deliveries_delivered_at ON deliveries(delivered_at);
@@ -0,0 +1,14 @@
+CREATE TABLE delivery_attempts (
+  id BLOB PRIMARY KEY NOT NULL,
+  subscription_id BLOB NOT NULL,
+  chapter_ids TEXT NOT NULL,
+  attempt INTEGER NOT NULL,
+  succeeded INTEGER NOT NULL,
+  error TEXT,
+  retry_at TEXT,
+  attempted_at TEXT NOT NULL,
+
+  CONSTRAINT fk_subscription_id FOREIGN KEY(subscription_id) REFERENCES subscriptions(id) ON DELETE CASCADE
+);
+
+CREATE INDEX delivery_attempts_subscription_id ON delivery_attempts(subscription_id, attempted_at);
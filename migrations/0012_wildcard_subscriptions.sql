@@ -0,0 +1,14 @@
+CREATE TABLE wildcard_subscriptions (
+  id BLOB PRIMARY KEY NOT NULL,
+  subscriber_id BLOB NOT NULL,
+  chunk_size NUMBER NOT NULL DEFAULT 1,
+  excluded_book_ids TEXT NOT NULL DEFAULT '[]',
+  created_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL,
+
+  CONSTRAINT fk_subscriber_id FOREIGN KEY(subscriber_id) REFERENCES subscribers(id) ON DELETE CASCADE
+);
+
+ALTER TABLE subscriptions ADD COLUMN wildcard_subscription_id BLOB REFERENCES wildcard_subscriptions(id) ON DELETE CASCADE;
+
+CREATE UNIQUE INDEX subscriptions_wildcard_book ON subscriptions(wildcard_subscription_id, book_id);
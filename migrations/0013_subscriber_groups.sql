@@ -0,0 +1,9 @@
+CREATE TABLE subscriber_group_members (
+  group_id BLOB NOT NULL,
+  subscriber_id BLOB NOT NULL,
+  created_at TEXT NOT NULL,
+
+  PRIMARY KEY(group_id, subscriber_id),
+  CONSTRAINT fk_group_id FOREIGN KEY(group_id) REFERENCES subscribers(id) ON DELETE CASCADE,
+  CONSTRAINT fk_subscriber_id FOREIGN KEY(subscriber_id) REFERENCES subscribers(id) ON DELETE CASCADE
+);
@@ -0,0 +1,44 @@
+ALTER TABLE books ADD COLUMN provider_identity TEXT;
+ALTER TABLE books ADD COLUMN canonical_book_id BLOB REFERENCES books(id) ON DELETE SET NULL;
+
+-- Matches BookMetadata::provider_identity.
+UPDATE books SET provider_identity = CASE
+  WHEN json_type(metadata, '$.RoyalRoad') IS NOT NULL
+    THEN 'RoyalRoad:' || json_extract(metadata, '$.RoyalRoad.book_id')
+  ELSE json_extract(metadata, '$')
+END;
+
+-- The oldest book for each source is kept and the rest become aliases of it.
+UPDATE books SET canonical_book_id = (
+  SELECT canonical.id FROM books AS canonical
+  WHERE canonical.provider_identity = books.provider_identity
+  ORDER BY canonical.created_at, canonical.id
+  LIMIT 1
+);
+UPDATE books SET canonical_book_id = NULL WHERE canonical_book_id = id;
+
+-- Subscriptions move to the canonical book, pointing at its copy of their last delivered
+-- chapter where one exists.
+UPDATE subscriptions SET
+  last_delivered_chapter_id = (
+    SELECT canonical_chapter.id FROM chapters AS alias_chapter
+    JOIN books ON books.id = alias_chapter.book_id
+    JOIN chapters AS canonical_chapter
+      ON canonical_chapter.book_id = books.canonical_book_id
+      AND canonical_chapter.metadata = alias_chapter.metadata
+    WHERE alias_chapter.id = subscriptions.last_delivered_chapter_id
+  ),
+  last_delivered_chapter_created_at = coalesce((
+    SELECT canonical_chapter.created_at FROM chapters AS alias_chapter
+    JOIN books ON books.id = alias_chapter.book_id
+    JOIN chapters AS canonical_chapter
+      ON canonical_chapter.book_id = books.canonical_book_id
+      AND canonical_chapter.metadata = alias_chapter.metadata
+    WHERE alias_chapter.id = subscriptions.last_delivered_chapter_id
+  ), last_delivered_chapter_created_at),
+  book_id = (SELECT canonical_book_id FROM books WHERE books.id = subscriptions.book_id)
+WHERE book_id IN (SELECT id FROM books WHERE canonical_book_id IS NOT NULL);
+
+UPDATE books SET provider_identity = NULL WHERE canonical_book_id IS NOT NULL;
+
+CREATE UNIQUE INDEX books_provider_identity ON books(provider_identity);
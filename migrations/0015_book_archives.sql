@@ -0,0 +1,16 @@
+ALTER TABLE books ADD COLUMN archived_at TEXT;
+ALTER TABLE chapters ADD COLUMN pruned_at TEXT;
+
+CREATE TABLE book_artifacts (
+  id BLOB PRIMARY KEY NOT NULL,
+  book_id BLOB NOT NULL,
+  kind TEXT NOT NULL,
+  content BLOB NOT NULL,
+  chapters NUMBER NOT NULL,
+  created_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL,
+
+  CONSTRAINT fk_book_id FOREIGN KEY(book_id) REFERENCES books(id) ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX book_artifacts_book_kind ON book_artifacts(book_id, kind);
@@ -0,0 +1,18 @@
+CREATE TABLE tags (
+  id BLOB PRIMARY KEY NOT NULL,
+  name TEXT NOT NULL UNIQUE,
+  created_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL
+);
+
+CREATE TABLE book_tags (
+  book_id BLOB NOT NULL,
+  tag_id BLOB NOT NULL,
+  created_at TEXT NOT NULL,
+
+  PRIMARY KEY(book_id, tag_id),
+  CONSTRAINT fk_book_id FOREIGN KEY(book_id) REFERENCES books(id) ON DELETE CASCADE
+  CONSTRAINT fk_tag_id FOREIGN KEY(tag_id) REFERENCES tags(id) ON DELETE CASCADE
+);
+
+CREATE INDEX book_tags_tag ON book_tags(tag_id);
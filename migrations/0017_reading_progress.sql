@@ -0,0 +1,18 @@
+CREATE TABLE kosync_users (
+  username TEXT PRIMARY KEY NOT NULL,
+  userkey TEXT NOT NULL,
+  created_at TEXT NOT NULL
+);
+
+CREATE TABLE reading_progress (
+  username TEXT NOT NULL,
+  document TEXT NOT NULL,
+  progress TEXT NOT NULL,
+  percentage REAL NOT NULL,
+  device TEXT NOT NULL,
+  device_id TEXT NOT NULL,
+  updated_at TEXT NOT NULL,
+
+  PRIMARY KEY(username, document),
+  CONSTRAINT fk_username FOREIGN KEY(username) REFERENCES kosync_users(username) ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+ALTER TABLE books ADD COLUMN delivery_templates TEXT NOT NULL DEFAULT '{}';
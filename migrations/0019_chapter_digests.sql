@@ -0,0 +1,3 @@
+-- Digests of existing bodies are recorded by the first integrity scan.
+ALTER TABLE chapters ADD COLUMN html_digest TEXT;
+ALTER TABLE chapters ADD COLUMN epub_digest TEXT;
@@ -0,0 +1,2 @@
+-- Epubs converted before versions were stamped are left NULL and count as older than any version.
+ALTER TABLE chapters ADD COLUMN conversion_version INTEGER;
@@ -0,0 +1,2 @@
+-- The title as the provider found it, before normalization.
+ALTER TABLE chapters ADD COLUMN raw_title TEXT;
@@ -0,0 +1,14 @@
+CREATE TABLE share_links (
+  id BLOB PRIMARY KEY NOT NULL,
+  chapter_id BLOB NOT NULL,
+  format TEXT NOT NULL,
+  expires_at TEXT,
+  revoked_at TEXT,
+  access_count INTEGER NOT NULL DEFAULT 0,
+  last_accessed_at TEXT,
+  created_at TEXT NOT NULL,
+
+  CONSTRAINT fk_chapter_id FOREIGN KEY(chapter_id) REFERENCES chapters(id) ON DELETE CASCADE
+);
+
+CREATE INDEX share_links_chapter ON share_links(chapter_id);
@@ -0,0 +1,13 @@
+CREATE TABLE websub_subscriptions (
+  book_id BLOB PRIMARY KEY NOT NULL,
+  hub TEXT NOT NULL,
+  topic TEXT NOT NULL,
+  secret TEXT NOT NULL,
+  verified_at TEXT,
+  lease_expires_at TEXT,
+  last_notified_at TEXT,
+  created_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL,
+
+  CONSTRAINT fk_book_id FOREIGN KEY(book_id) REFERENCES books(id) ON DELETE CASCADE
+);
@@ -0,0 +1,5 @@
+-- Chapters are held back from subscriptions without early access until this time.
+ALTER TABLE chapters ADD COLUMN deliver_after TEXT;
+-- How long after publication a book's new chapters stay early access.
+ALTER TABLE books ADD COLUMN early_access_secs INTEGER;
+ALTER TABLE subscriptions ADD COLUMN early_access BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,6 @@
+-- A public source for books whose primary source releases chapters to patrons first.
+ALTER TABLE books ADD COLUMN public_metadata TEXT;
+-- When the book's public source released the chapter.
+ALTER TABLE chapters ADD COLUMN public_published_at TEXT;
+-- Set on chapters which only the public source found, filling gaps in the primary source.
+ALTER TABLE chapters ADD COLUMN from_public_source BOOLEAN NOT NULL DEFAULT FALSE;
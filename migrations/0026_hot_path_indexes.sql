@@ -0,0 +1,15 @@
+-- Indexes for the queries the pipeline runs on every tick. GET /explainHotQueries shows
+-- whether sqlite still uses them.
+CREATE INDEX chapters_book_order ON chapters(book_id, order_index);
+
+-- Partial indexes only hold the chapters waiting at each stage, so they stay small however
+-- many chapters have been delivered.
+CREATE INDEX chapters_awaiting_hydration ON chapters(order_index)
+  WHERE html IS NULL AND pruned_at IS NULL;
+CREATE INDEX chapters_awaiting_conversion ON chapters(order_index)
+  WHERE html IS NOT NULL AND epub IS NULL;
+CREATE INDEX chapters_with_epub ON chapters(book_id, created_at)
+  WHERE epub IS NOT NULL;
+
+CREATE INDEX subscriptions_subscriber ON subscriptions(subscriber_id);
+CREATE INDEX subscriptions_book ON subscriptions(book_id);
@@ -0,0 +1,3 @@
+-- Settings new subscriptions of the subscriber start with. Unset falls back to the usual default.
+ALTER TABLE subscribers ADD COLUMN default_chunk_size INTEGER;
+ALTER TABLE subscribers ADD COLUMN default_early_access BOOLEAN;
@@ -0,0 +1,25 @@
+-- Where each chapter is in the pipeline, kept by the tasks as they fetch, convert and prune
+-- bodies, rather than inferred from which bodies are null.
+ALTER TABLE chapters ADD COLUMN state TEXT NOT NULL DEFAULT 'discovered';
+-- Consecutive failures at the chapter's current stage, and the last of their errors.
+ALTER TABLE chapters ADD COLUMN failures INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE chapters ADD COLUMN last_error TEXT;
+
+UPDATE chapters SET state = CASE
+    WHEN pruned_at IS NOT NULL THEN 'pruned'
+    WHEN epub IS NOT NULL THEN 'converted'
+    WHEN html IS NOT NULL THEN 'hydrated'
+    ELSE 'discovered'
+  END;
+
+DROP INDEX chapters_awaiting_hydration;
+DROP INDEX chapters_awaiting_conversion;
+DROP INDEX chapters_with_epub;
+-- Partial indexes per state, like those they replace, so they hold only the chapters waiting
+-- at each stage.
+CREATE INDEX chapters_awaiting_hydration ON chapters(order_index)
+  WHERE state = 'discovered';
+CREATE INDEX chapters_awaiting_conversion ON chapters(order_index)
+  WHERE state = 'hydrated';
+CREATE INDEX chapters_converted ON chapters(book_id, created_at)
+  WHERE state = 'converted';
@@ -0,0 +1,20 @@
+-- The volumes, or arcs, a book's chapters are grouped into.
+CREATE TABLE volumes (
+  id BLOB PRIMARY KEY NOT NULL,
+  book_id BLOB NOT NULL,
+  number INTEGER NOT NULL,
+  title TEXT,
+  -- Set on volumes created by detection, whose chapters detection may reassign.
+  detected BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL,
+
+  UNIQUE(book_id, number),
+  CONSTRAINT fk_book_id FOREIGN KEY(book_id) REFERENCES books(id) ON DELETE CASCADE
+);
+
+ALTER TABLE chapters ADD COLUMN volume_id BLOB REFERENCES volumes(id) ON DELETE SET NULL;
+CREATE INDEX chapters_volume ON chapters(volume_id);
+
+-- Detect volumes from the titles of newly discovered chapters.
+ALTER TABLE books ADD COLUMN detect_volumes BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,17 @@
+CREATE TABLE anthology_subscriptions (
+  id BLOB PRIMARY KEY NOT NULL,
+  subscriber_id BLOB NOT NULL,
+  name TEXT NOT NULL,
+  book_ids TEXT NOT NULL DEFAULT '[]',
+  -- How often the digest of the books' new chapters is sent.
+  period_secs INTEGER NOT NULL,
+  last_sent_at TEXT,
+  created_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL,
+
+  CONSTRAINT fk_subscriber_id FOREIGN KEY(subscriber_id) REFERENCES subscribers(id) ON DELETE CASCADE
+);
+
+ALTER TABLE subscriptions ADD COLUMN anthology_subscription_id BLOB REFERENCES anthology_subscriptions(id) ON DELETE CASCADE;
+
+CREATE UNIQUE INDEX subscriptions_anthology_book ON subscriptions(anthology_subscription_id, book_id);
@@ -0,0 +1,4 @@
+-- An ordinary address, for deliveries sent inline rather than as epubs to a kindle.
+ALTER TABLE subscribers ADD COLUMN email TEXT;
+
+ALTER TABLE subscriptions ADD COLUMN format TEXT NOT NULL DEFAULT 'epub';
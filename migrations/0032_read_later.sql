@@ -0,0 +1,4 @@
+-- A read-later service, such as Pocket, which chapters are saved to, and the subscriber's
+-- token for its api.
+ALTER TABLE subscribers ADD COLUMN read_later_service TEXT;
+ALTER TABLE subscribers ADD COLUMN read_later_token TEXT;
@@ -0,0 +1,3 @@
+-- An apprise url, such as signal:// or whatsapp://, which notifications are sent to through
+-- the apprise gateway.
+ALTER TABLE subscribers ADD COLUMN notify_url TEXT;
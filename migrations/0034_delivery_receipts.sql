@@ -0,0 +1,19 @@
+-- The messages each delivery attempt was sent as, and what their channel has since reported
+-- of them, such as mailgun's delivered and opened events.
+CREATE TABLE delivery_receipts (
+  id BLOB PRIMARY KEY NOT NULL,
+  attempt_id BLOB NOT NULL,
+  subscriber_id BLOB NOT NULL,
+  channel TEXT NOT NULL,
+  message_id TEXT NOT NULL,
+  state TEXT NOT NULL,
+  detail TEXT,
+  created_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL,
+
+  CONSTRAINT fk_attempt_id FOREIGN KEY(attempt_id) REFERENCES delivery_attempts(id) ON DELETE CASCADE
+  CONSTRAINT fk_subscriber_id FOREIGN KEY(subscriber_id) REFERENCES subscribers(id) ON DELETE CASCADE
+);
+
+CREATE INDEX delivery_receipts_attempt_id ON delivery_receipts(attempt_id);
+CREATE INDEX delivery_receipts_message_id ON delivery_receipts(message_id);
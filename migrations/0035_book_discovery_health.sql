@@ -0,0 +1,5 @@
+-- The last time the book was checked for new chapters without error, and the failed checks
+-- since.
+ALTER TABLE books ADD COLUMN last_discovered_at TEXT;
+ALTER TABLE books ADD COLUMN discovery_failures INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE books ADD COLUMN last_discovery_error TEXT;
@@ -0,0 +1,13 @@
+-- Backfills of books' backlists, with the page they continue from so an interrupted backfill
+-- resumes instead of scraping its pages again.
+CREATE TABLE backfills (
+  book_id BLOB PRIMARY KEY NOT NULL,
+  next_page INTEGER NOT NULL DEFAULT 1,
+  chapters_found INTEGER NOT NULL DEFAULT 0,
+  last_error TEXT,
+  started_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL,
+  completed_at TEXT,
+
+  CONSTRAINT fk_book_id FOREIGN KEY(book_id) REFERENCES books(id) ON DELETE CASCADE
+);
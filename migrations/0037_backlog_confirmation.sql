@@ -0,0 +1,5 @@
+-- Deliveries larger than the safety cap wait until an admin confirms them. A confirmation
+-- covers the chapters created up to when it was given, which are then sent all at once or
+-- paced out in deliveries within the cap.
+ALTER TABLE subscriptions ADD COLUMN backlog_delivery TEXT;
+ALTER TABLE subscriptions ADD COLUMN backlog_confirmed_through TEXT;
@@ -0,0 +1,6 @@
+-- Failed deliveries and bounces of the subscriber since its last success, and when it was
+-- paused after failing for too long. Paused subscribers are sent nothing until resumed.
+ALTER TABLE subscribers ADD COLUMN delivery_failures INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE subscribers ADD COLUMN failing_since TEXT;
+ALTER TABLE subscribers ADD COLUMN last_failure TEXT;
+ALTER TABLE subscribers ADD COLUMN paused_at TEXT;
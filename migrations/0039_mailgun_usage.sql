@@ -0,0 +1,6 @@
+-- Messages sent through mailgun each month, as `YYYY-MM` in UTC, to keep within its quota.
+CREATE TABLE mailgun_usage (
+  month TEXT PRIMARY KEY NOT NULL,
+  messages INTEGER NOT NULL DEFAULT 0,
+  updated_at TEXT NOT NULL
+);
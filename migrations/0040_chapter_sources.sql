@@ -0,0 +1,9 @@
+-- The raw email or page each chapter was parsed from, so it can be parsed again after a fix.
+-- Sources are stored by the SHA-256 of their contents, in this table or another store.
+ALTER TABLE chapters ADD COLUMN source_key TEXT;
+
+CREATE TABLE chapter_sources (
+  source_key TEXT PRIMARY KEY NOT NULL,
+  body BLOB NOT NULL,
+  created_at TEXT NOT NULL
+);
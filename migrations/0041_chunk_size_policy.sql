@@ -0,0 +1,5 @@
+-- Chunk sizes below one were accepted before they were validated, and either delivered every
+-- chapter check or never delivered at all.
+UPDATE subscriptions SET chunk_size = 1 WHERE chunk_size < 1;
+UPDATE wildcard_subscriptions SET chunk_size = 1 WHERE chunk_size < 1;
+UPDATE subscribers SET default_chunk_size = NULL WHERE default_chunk_size < 1;
@@ -0,0 +1,2 @@
+-- Wildcard subscriptions with a tag only cover the books with that tag.
+ALTER TABLE wildcard_subscriptions ADD COLUMN tag_id BLOB REFERENCES tags(id) ON DELETE CASCADE;
@@ -0,0 +1,13 @@
+-- Secrets used to reach providers, such as AWS keys and site passwords, so they can be rotated
+-- without a redeploy. Values are encrypted with the key in CEREAL_CREDENTIALS_KEY, with the
+-- nonce stored ahead of the ciphertext.
+CREATE TABLE credentials (
+  id BLOB PRIMARY KEY NOT NULL,
+  name TEXT NOT NULL UNIQUE,
+  value BLOB NOT NULL,
+  created_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL
+);
+
+-- Books may name the credential holding the password to their chapters.
+ALTER TABLE books ADD COLUMN password_credential TEXT;
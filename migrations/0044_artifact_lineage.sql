@@ -0,0 +1,11 @@
+ALTER TABLE book_artifacts ADD COLUMN conversion_version INTEGER;
+ALTER TABLE book_artifacts ADD COLUMN conversion_options TEXT;
+
+CREATE TABLE book_artifact_sources (
+  artifact_id BLOB NOT NULL,
+  chapter_id BLOB NOT NULL,
+  html_digest TEXT NOT NULL,
+
+  PRIMARY KEY(artifact_id, chapter_id),
+  CONSTRAINT fk_artifact_id FOREIGN KEY(artifact_id) REFERENCES book_artifacts(id) ON DELETE CASCADE
+);
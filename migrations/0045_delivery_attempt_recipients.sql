@@ -0,0 +1,2 @@
+-- The recipients of a group an attempt reached, so a retry of the same chapters skips them.
+ALTER TABLE delivery_attempts ADD COLUMN delivered_recipient_ids TEXT NOT NULL DEFAULT '[]';
@@ -0,0 +1,9 @@
+-- Unsubscriptions this server asked a hub for, which the hub's verification must match before it
+-- is confirmed.
+CREATE TABLE websub_unsubscriptions (
+  book_id BLOB PRIMARY KEY NOT NULL,
+  topic TEXT NOT NULL,
+  requested_at TEXT NOT NULL,
+
+  CONSTRAINT fk_book_id FOREIGN KEY(book_id) REFERENCES books(id) ON DELETE CASCADE
+);
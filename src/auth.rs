@@ -0,0 +1,128 @@
+use axum::{
+    extract::MatchedPath,
+    http::{header, Method, Request},
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+
+use crate::{config::config, error::ApiError, util::content_digest};
+
+/// What a token may do. Each scope includes the ones before it, so admin tokens can do
+/// everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Scope {
+    /// Only GET endpoints, for feed readers and other integrations which fetch chapters.
+    Read,
+    /// Creating and updating resources.
+    Write,
+    /// Deleting resources and operating the server.
+    Admin,
+}
+
+/// A bearer token accepted by the api. Only the token's SHA-256 is configured, so the config
+/// file doesn't hold usable credentials.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiToken {
+    pub name: String,
+    #[serde(rename = "tokenSha256")]
+    pub token_sha256: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl ApiToken {
+    fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|x| *x >= scope)
+    }
+}
+
+/// Routes which don't take the scope their method implies. None marks routes which are public
+/// because they check credentials of their own, or report health to orchestrators.
+const ROUTE_SCOPES: &[(&str, Option<Scope>)] = &[
+    ("/readyz", None),
+    ("/shared/:id", None),
+    // Devices can't send a bearer token, so accounts are created with one unless
+    // `kosyncOpenRegistration` is set.
+    ("/users/create", Some(Scope::Write)),
+    ("/users/auth", None),
+    ("/syncs/progress", None),
+    ("/syncs/progress/:document", None),
+    // Hubs authenticate notifications by signing them with the subscription's secret.
+    ("/websub/callback/:id", None),
+    // Mailgun signs its events with the webhook signing key.
+    ("/webhooks/mailgun", None),
+    ("/listTaskSchedules", Some(Scope::Admin)),
+    ("/setTaskSchedule", Some(Scope::Admin)),
+    ("/reconvertChapters", Some(Scope::Admin)),
+    ("/reconvertBook", Some(Scope::Admin)),
+    ("/archiveBook", Some(Scope::Admin)),
+    ("/setReadOnly", Some(Scope::Admin)),
+    ("/explainHotQueries", Some(Scope::Admin)),
+    ("/confirmDelivery", Some(Scope::Admin)),
+    ("/resumeSubscriber", Some(Scope::Admin)),
+    ("/reparseSource", Some(Scope::Admin)),
+    ("/reloadConfig", Some(Scope::Admin)),
+    ("/logFilter", Some(Scope::Admin)),
+    ("/setLogFilter", Some(Scope::Admin)),
+    ("/createCredential", Some(Scope::Admin)),
+    ("/rotateCredential", Some(Scope::Admin)),
+    ("/listCredentials", Some(Scope::Admin)),
+];
+
+/// The scope needed to call the route: read for GET, admin for DELETE and write otherwise,
+/// unless the route is listed in ROUTE_SCOPES.
+fn required_scope(method: &Method, path: &str) -> Option<Scope> {
+    if path == "/users/create" && config().kosync_open_registration {
+        return None;
+    }
+    if let Some((_, scope)) = ROUTE_SCOPES.iter().find(|(route, _)| *route == path) {
+        return *scope;
+    }
+    match *method {
+        Method::GET | Method::HEAD => Some(Scope::Read),
+        Method::DELETE => Some(Scope::Admin),
+        _ => Some(Scope::Write),
+    }
+}
+
+/// Rejects requests whose bearer token lacks the scope the route requires. Tokens are only
+/// checked once some are configured, so servers without `apiTokens` stay open.
+pub async fn require_scope<B>(request: Request<B>, next: Next<B>) -> Result<Response, ApiError> {
+    let tokens = config().api_tokens.clone();
+    if tokens.is_empty() {
+        return Ok(next.run(request).await);
+    }
+    let path = match request.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_owned(),
+        // Unrouted requests fall through to the 404 response.
+        None => return Ok(next.run(request).await),
+    };
+    let scope = match required_scope(request.method(), &path) {
+        Some(scope) => scope,
+        None => return Ok(next.run(request).await),
+    };
+    let digest = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .map(|x| content_digest(x.trim().as_bytes()));
+    let token = digest.and_then(|digest| {
+        tokens
+            .into_iter()
+            .find(|x| x.token_sha256.eq_ignore_ascii_case(&digest))
+    });
+    match token {
+        Some(token) if token.allows(scope) => Ok(next.run(request).await),
+        Some(token) => Err(ApiError::Forbidden(format!(
+            "Token {:?} lacks the {:?} scope needed for {} {}",
+            token.name,
+            scope,
+            request.method(),
+            path
+        ))),
+        None => Err(ApiError::Unauthorized),
+    }
+}
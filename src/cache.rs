@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use moka::future::Cache;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    controllers::status::StatusResponse,
+    models::{
+        changes::{subscribe, Change, Entity},
+        BookSummary, ShallowChapter,
+    },
+};
+
+/// How long a cached response is served for at most. Writes made by other instances sharing the
+/// database, and the few which aren't published as changes, show up after this long.
+const RESPONSE_TTL: Duration = Duration::from_secs(30);
+
+/// /status reports time based figures, such as delivery latency, so is kept more briefly.
+const STATUS_TTL: Duration = Duration::from_secs(5);
+
+/// How many books' chapter lists are kept.
+const MAX_CHAPTER_LISTS: u64 = 256;
+
+/// Responses of the hot read endpoints, kept in memory so polling clients don't each query the
+/// database. Entries are dropped as soon as a change which could alter them is published.
+#[derive(Clone)]
+pub struct ResponseCache {
+    books: Cache<(), Vec<BookSummary>>,
+    chapters: Cache<Uuid, Vec<ShallowChapter>>,
+    status: Cache<(), StatusResponse>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache {
+            books: Cache::builder()
+                .max_capacity(1)
+                .time_to_live(RESPONSE_TTL)
+                .build(),
+            chapters: Cache::builder()
+                .max_capacity(MAX_CHAPTER_LISTS)
+                .time_to_live(RESPONSE_TTL)
+                .build(),
+            status: Cache::builder()
+                .max_capacity(1)
+                .time_to_live(STATUS_TTL)
+                .build(),
+        }
+    }
+}
+
+impl ResponseCache {
+    pub fn new() -> ResponseCache {
+        ResponseCache::default()
+    }
+
+    /// Every book, as returned by /listBooks without a tag.
+    pub(crate) fn books(&self) -> &Cache<(), Vec<BookSummary>> {
+        &self.books
+    }
+
+    /// A book's chapters without their bodies, keyed by book.
+    pub(crate) fn chapters(&self) -> &Cache<Uuid, Vec<ShallowChapter>> {
+        &self.chapters
+    }
+
+    pub(crate) fn status(&self) -> &Cache<(), StatusResponse> {
+        &self.status
+    }
+
+    async fn invalidate(&self, change: &Change) {
+        // Every change moves some backlog or count in the status.
+        self.status.invalidate_all();
+        match (change.entity, change.book_id) {
+            (Entity::Book, _) => {
+                self.books.invalidate_all();
+                self.chapters.invalidate(&change.id).await;
+            }
+            // Books are listed with a summary of their chapters.
+            (Entity::Chapter, Some(book_id)) => {
+                self.books.invalidate_all();
+                self.chapters.invalidate(&book_id).await
+            }
+            // Chapters list the volume they belong to.
+            (Entity::Volume, Some(book_id)) => self.chapters.invalidate(&book_id).await,
+            // Deleted chapters don't name their book.
+            (Entity::Chapter, None) => {
+                self.books.invalidate_all();
+                self.chapters.invalidate_all();
+            }
+            _ => {}
+        }
+    }
+
+    fn invalidate_all(&self) {
+        self.books.invalidate_all();
+        self.chapters.invalidate_all();
+        self.status.invalidate_all();
+    }
+
+    /// Drops cached responses as changes are published. Runs until the change bus closes.
+    pub async fn invalidate_on_changes(self) {
+        let mut receiver = subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(change) => self.invalidate(&change).await,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Missed {} changes, dropping every cached response", missed);
+                    self.invalidate_all();
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
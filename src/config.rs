@@ -0,0 +1,250 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use chrono::NaiveTime;
+use serde::Deserialize;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::ApiToken,
+    error::{ApiError, ApiResult},
+    models::DeliveryTemplates,
+    providers::http::reset_clients,
+    tasks::schedule::{LoopSchedule, TaskLoop},
+    telemetry::{parse_log_filter, set_log_filter},
+};
+
+/// Settings read from the json file named by CEREAL_CONFIG, or `cereal.json` in the working
+/// directory if it exists. Everything is optional, and the file is read again on SIGHUP or
+/// `POST /reloadConfig`.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "headerProfiles")]
+    pub header_profiles: HeaderProfiles,
+    /// Templates used for books which don't set their own.
+    #[serde(rename = "deliveryTemplates")]
+    pub delivery_templates: DeliveryTemplates,
+    /// Bearer tokens and their scopes. The api is open to anyone until one is added. Feeds then
+    /// need a token too, so they are no longer published to a WebSub hub.
+    #[serde(rename = "apiTokens")]
+    pub api_tokens: Vec<ApiToken>,
+    /// Let anyone create a KOReader sync account from their device, rather than only holders
+    /// of a token with the write scope.
+    #[serde(rename = "kosyncOpenRegistration")]
+    pub kosync_open_registration: bool,
+    /// The space the database may use, for warnings from /storageStats.
+    #[serde(rename = "storageQuotaBytes")]
+    pub storage_quota_bytes: Option<i64>,
+    /// The messages mailgun may send each month before charging for more. Email deliveries
+    /// are throttled as it is approached, and wait for the next month once it is reached.
+    #[serde(rename = "mailgunMonthlyQuota")]
+    pub mailgun_monthly_quota: Option<i64>,
+    /// Pause the background loops and refuse writes, regardless of the admin api.
+    #[serde(rename = "readOnly")]
+    pub read_only: bool,
+    /// Times of day when providers aren't scraped.
+    #[serde(rename = "blackoutWindows")]
+    pub blackout_windows: Vec<BlackoutWindow>,
+    /// Which logs are kept, in the syntax of RUST_LOG, such as
+    /// `info,cereal_rewrite::providers=debug`. Defaults to `info`.
+    #[serde(rename = "logFilter")]
+    pub log_filter: Option<String>,
+    /// Minimum seconds between requests to these hosts and their subdomains, over the built in
+    /// intervals and CEREAL_HOST_RATE_LIMITS.
+    #[serde(rename = "hostRateLimits")]
+    pub host_rate_limits: HashMap<String, f64>,
+    /// Schedules for the background loops, over the environment. Schedules set through the
+    /// admin api still take precedence.
+    #[serde(rename = "taskSchedules")]
+    pub task_schedules: HashMap<TaskLoop, LoopSchedule>,
+}
+
+/// A daily stretch of time, in UTC, during which scraping is paused, such as while a site does
+/// nightly maintenance. It covers every provider request unless limited to some hosts or books.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlackoutWindow {
+    /// As `HH:MM:SS`. A window which ends before it starts runs past midnight.
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Hosts the window applies to, along with their subdomains. Empty for all hosts.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Books the window applies to. Empty for all books.
+    #[serde(default)]
+    pub books: Vec<Uuid>,
+}
+
+impl BlackoutWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// The headers sent with provider requests. Each provider's profile is layered over the
+/// default profile, so it only needs the fields it changes.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderProfiles {
+    pub default: HeaderProfile,
+    /// Keyed by provider, one of `royalroad`, `pale` or `wanderingInn`.
+    pub providers: HashMap<String, HeaderProfile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderProfile {
+    #[serde(rename = "userAgent")]
+    pub user_agent: Option<String>,
+    #[serde(rename = "acceptLanguage")]
+    pub accept_language: Option<String>,
+    /// Any other headers to send, by name.
+    pub headers: HashMap<String, String>,
+    /// Keep cookies set by the site between requests.
+    #[serde(rename = "cookieStore")]
+    pub cookie_store: Option<bool>,
+}
+
+impl HeaderProfile {
+    /// Returns this profile with any fields it leaves unset taken from `base`.
+    pub fn or(&self, base: &HeaderProfile) -> HeaderProfile {
+        let mut headers = base.headers.clone();
+        headers.extend(self.headers.clone());
+        HeaderProfile {
+            user_agent: self.user_agent.clone().or_else(|| base.user_agent.clone()),
+            accept_language: self
+                .accept_language
+                .clone()
+                .or_else(|| base.accept_language.clone()),
+            headers,
+            cookie_store: self.cookie_store.or(base.cookie_store),
+        }
+    }
+}
+
+/// Where Docker and Kubernetes mount secrets, unless CEREAL_SECRETS_DIR names another directory.
+const SECRETS_DIR: &str = "/run/secrets";
+
+/// A secret such as an api key, from the environment variable of its name, else the file named
+/// by `<name>_FILE`, else a file of its name, as is or lowercased, in the secrets directory. This
+/// keeps secrets out of the environment, where `docker inspect` shows them. Files are read each
+/// time, so a rotated secret is picked up without a restart, and the trailing newline most
+/// secret files end with is dropped.
+pub fn secret(name: &str) -> Option<String> {
+    if let Ok(value) = env::var(name) {
+        return Some(value);
+    }
+    let path = match env::var(format!("{}_FILE", name)) {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => {
+            let dir = env::var("CEREAL_SECRETS_DIR").unwrap_or_else(|_| String::from(SECRETS_DIR));
+            [name.to_owned(), name.to_lowercase()]
+                .into_iter()
+                .map(|x| Path::new(&dir).join(x))
+                .find(|x| x.is_file())?
+        }
+    };
+    match fs::read_to_string(&path) {
+        Ok(value) => Some(value.trim_end_matches(['\r', '\n']).to_owned()),
+        Err(e) => {
+            warn!("Failed to read {} from {}: {}", name, path.display(), e);
+            None
+        }
+    }
+}
+
+static CONFIG: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+
+fn config_path() -> Option<PathBuf> {
+    match env::var("CEREAL_CONFIG") {
+        Ok(path) => Some(PathBuf::from(path)),
+        Err(_) => {
+            let path = PathBuf::from("cereal.json");
+            path.exists().then_some(path)
+        }
+    }
+}
+
+/// Reads the config file and applies it, failing if it exists but can't be parsed or holds an
+/// invalid value, in which case the config in effect is kept. Settings are read as they are
+/// used, so loops and deliveries already running finish with the config they started with.
+pub fn load_config() -> ApiResult<()> {
+    let config: Config = match config_path() {
+        Some(path) => {
+            info!("Reading config from {}", path.display());
+            serde_json::from_slice(&fs::read(path)?)?
+        }
+        None => Config::default(),
+    };
+    if let Some((host, secs)) = config
+        .host_rate_limits
+        .iter()
+        .find(|(_, secs)| Duration::try_from_secs_f64(**secs).is_err())
+    {
+        return Err(ApiError::InvalidRequest(format!(
+            "The rate limit for {} must be a number of seconds, found {}",
+            host, secs
+        )));
+    }
+    if let Some((task, _)) = config
+        .task_schedules
+        .iter()
+        .find(|(_, schedule)| schedule.interval_secs == 0)
+    {
+        return Err(ApiError::InvalidRequest(format!(
+            "The intervalSecs of the {} loop must be greater than zero",
+            task.name()
+        )));
+    }
+    let filter = parse_log_filter(config.log_filter.as_deref())?;
+    let header_profiles = config.header_profiles.clone();
+    let previous = CONFIG.write().unwrap().replace(Arc::new(config));
+    set_log_filter(filter);
+    // Clients are built with their headers, so they are built again with the new ones.
+    if previous.is_some_and(|x| x.header_profiles != header_profiles) {
+        reset_clients();
+    }
+    Ok(())
+}
+
+/// Reloads the config file each time the process receives SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_hangup() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(
+                "Failed to listen for SIGHUP, the config can't be reloaded by signal: {}",
+                e
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading the config");
+        if let Err(e) = load_config() {
+            error!(
+                "Failed to reload the config, keeping the current one: {}",
+                e
+            );
+        }
+    }
+}
+
+/// The current config, or the defaults if none has been loaded.
+pub fn config() -> Arc<Config> {
+    CONFIG.read().unwrap().clone().unwrap_or_default()
+}
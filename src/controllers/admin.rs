@@ -0,0 +1,231 @@
+use axum::{
+    extract::State,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    config::{config, load_config},
+    error::ApiError,
+    models::{ChapterClient, MaintenanceClient, QueryPlan},
+    read_only::{is_read_only, set_read_only},
+    tasks::{
+        chapter_body_conversion::CONVERSION_VERSION,
+        schedule::{get_schedule, set_schedule, LoopSchedule, TaskLoop},
+    },
+    telemetry::{log_filter, parse_log_filter, set_log_filter},
+    AppState,
+};
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct TaskScheduleResponse {
+    task: TaskLoop,
+    #[serde(flatten)]
+    schedule: LoopSchedule,
+}
+
+#[instrument(skip(state))]
+async fn list_task_schedules_handler(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<TaskScheduleResponse>>, ApiError> {
+    let mut schedules = Vec::new();
+    for task in TaskLoop::ALL {
+        schedules.push(TaskScheduleResponse {
+            task,
+            schedule: get_schedule(&state.pool, task).await?,
+        });
+    }
+    Ok(schedules.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetTaskScheduleRequest {
+    task: TaskLoop,
+    /// Omit to restore the configured default.
+    schedule: Option<LoopSchedule>,
+}
+
+#[instrument(skip(state))]
+async fn set_task_schedule_handler(
+    State(state): State<AppState>,
+    Json(request): Json<SetTaskScheduleRequest>,
+) -> Result<Json<TaskScheduleResponse>, ApiError> {
+    if let Some(schedule) = &request.schedule {
+        if schedule.interval_secs == 0 {
+            return Err(ApiError::InvalidRequest(String::from(
+                "intervalSecs must be greater than zero",
+            )));
+        }
+    }
+    let schedule = set_schedule(&state.pool, request.task, request.schedule.as_ref()).await?;
+    Ok(TaskScheduleResponse {
+        task: request.task,
+        schedule,
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReconvertChaptersRequest {
+    /// Reconvert epubs older than this conversion version, defaulting to the current one.
+    #[serde(rename = "belowVersion")]
+    below_version: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ReconvertChaptersResponse {
+    #[serde(rename = "clearedChapters")]
+    cleared_chapters: u64,
+    #[serde(rename = "conversionVersion")]
+    conversion_version: i64,
+}
+
+/// Clears every epub converted by an older pipeline so the conversion loop regenerates them,
+/// for after sanitization, styling or the conversion backend changes.
+#[instrument(skip(state))]
+async fn reconvert_chapters_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ReconvertChaptersRequest>,
+) -> Result<Json<ReconvertChaptersResponse>, ApiError> {
+    let below_version = request.below_version.unwrap_or(CONVERSION_VERSION);
+    let cleared_chapters = ChapterClient::new(&state.pool)
+        .clear_epubs(None, Some(below_version))
+        .await?;
+    Ok(ReconvertChaptersResponse {
+        cleared_chapters,
+        conversion_version: CONVERSION_VERSION,
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ReadOnlyResponse {
+    #[serde(rename = "readOnly")]
+    read_only: bool,
+}
+
+#[instrument(skip(state))]
+async fn get_read_only_handler(
+    State(state): State<AppState>,
+) -> Result<Json<ReadOnlyResponse>, ApiError> {
+    Ok(ReadOnlyResponse {
+        read_only: is_read_only(&state.pool).await,
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetReadOnlyRequest {
+    #[serde(rename = "readOnly")]
+    read_only: bool,
+}
+
+/// Pauses the background loops and refuses writes on every instance, for backups and
+/// debugging against the live database. Reads and downloads keep working.
+#[instrument(skip(state))]
+async fn set_read_only_handler(
+    State(state): State<AppState>,
+    Json(request): Json<SetReadOnlyRequest>,
+) -> Result<Json<ReadOnlyResponse>, ApiError> {
+    set_read_only(&state.pool, request.read_only).await?;
+    let read_only = is_read_only(&state.pool).await;
+    if read_only != request.read_only {
+        return Err(ApiError::InvalidRequest(String::from(
+            "Read-only mode is set in the config file",
+        )));
+    }
+    Ok(ReadOnlyResponse { read_only }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ReloadConfigResponse {
+    #[serde(rename = "reloadedAt")]
+    reloaded_at: DateTime<Utc>,
+}
+
+/// Reads the config file again and applies it without a restart. Only this process reloads, so
+/// separate workers are sent SIGHUP instead. An invalid file is refused and the current config
+/// kept.
+#[instrument]
+async fn reload_config_handler() -> Result<Json<ReloadConfigResponse>, ApiError> {
+    load_config()?;
+    Ok(ReloadConfigResponse {
+        reloaded_at: Utc::now(),
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct LogFilterResponse {
+    /// Unset if the process logs without a filter it can change.
+    filter: Option<String>,
+}
+
+#[instrument]
+async fn get_log_filter_handler() -> Result<Json<LogFilterResponse>, ApiError> {
+    Ok(LogFilterResponse {
+        filter: log_filter(),
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetLogFilterRequest {
+    /// Directives in the syntax of RUST_LOG, such as `info,cereal_rewrite::providers=debug`.
+    /// Omit to restore the configured filter.
+    filter: Option<String>,
+}
+
+/// Changes which logs this process keeps until it restarts or its config is reloaded, so a
+/// failing provider can be debugged without restarting with another filter.
+#[instrument]
+async fn set_log_filter_handler(
+    Json(request): Json<SetLogFilterRequest>,
+) -> Result<Json<LogFilterResponse>, ApiError> {
+    let directives = request.filter.or_else(|| config().log_filter.clone());
+    set_log_filter(parse_log_filter(directives.as_deref())?);
+    Ok(LogFilterResponse {
+        filter: log_filter(),
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ExplainHotQueriesResponse {
+    queries: Vec<QueryPlan>,
+    /// Set if any query scans a table it should find rows in through an index.
+    regressed: bool,
+}
+
+/// Shows how sqlite plans the queries the pipeline runs on every tick, to catch a migration or
+/// query change which stops them using their indexes.
+#[instrument(skip(state))]
+async fn explain_hot_queries_handler(
+    State(state): State<AppState>,
+) -> Result<Json<ExplainHotQueriesResponse>, ApiError> {
+    let queries = MaintenanceClient::new(&state.pool)
+        .explain_hot_queries()
+        .await?;
+    let regressed = queries.iter().any(|x| !x.unexpected_scans.is_empty());
+    Ok(ExplainHotQueriesResponse { queries, regressed }.into())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/listTaskSchedules", get(list_task_schedules_handler))
+        .route("/setTaskSchedule", post(set_task_schedule_handler))
+        .route("/reconvertChapters", post(reconvert_chapters_handler))
+        .route("/readOnly", get(get_read_only_handler))
+        .route("/setReadOnly", post(set_read_only_handler))
+        .route("/explainHotQueries", get(explain_hot_queries_handler))
+        .route("/reloadConfig", post(reload_config_handler))
+        .route("/logFilter", get(get_log_filter_handler))
+        .route("/setLogFilter", post(set_log_filter_handler))
+}
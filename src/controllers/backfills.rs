@@ -0,0 +1,67 @@
+use axum::{
+    extract::{Query, State},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    models::{Backfill, BackfillClient},
+    AppState,
+};
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StartBackfillRequest {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+    /// Start again from the first page rather than continuing a backfill in progress.
+    #[serde(default)]
+    restart: bool,
+}
+
+/// Backfills the chapters older than the book's feed reaches. Discovery works through the
+/// backlist a few pages at a time, resuming where it left off.
+#[instrument(skip(state))]
+async fn start_backfill_handler(
+    State(state): State<AppState>,
+    Json(request): Json<StartBackfillRequest>,
+) -> Result<Json<Backfill>, ApiError> {
+    let backfill = BackfillClient::new(&state.pool)
+        .start_backfill(&request.book_id, request.restart)
+        .await?;
+    Ok(backfill.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GetBackfillRequest {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn get_backfill_handler(
+    State(state): State<AppState>,
+    Query(request): Query<GetBackfillRequest>,
+) -> Result<Json<Backfill>, ApiError> {
+    let backfill = BackfillClient::new(&state.pool)
+        .get_backfill(&request.book_id)
+        .await?;
+    match backfill {
+        Some(x) => Ok(x.into()),
+        None => Err(ApiError::ResourceNotFound {
+            resource_type: String::from("backfill"),
+            id: request.book_id.to_string(),
+        }),
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/startBackfill", post(start_backfill_handler))
+        .route("/getBackfill", get(get_backfill_handler))
+}
@@ -1,37 +1,97 @@
+use std::io;
+
 use axum::{
-    extract::{Query, State},
+    body::StreamBody,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{delete, get, post},
     Json, Router,
 };
 use chrono::Utc;
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tracing::instrument;
+use tokio::sync::mpsc;
+use tracing::{error, instrument};
 use uuid::Uuid;
 
 use crate::{
     error::ApiError,
-    models::{Book, BookClient, BookMetadata},
+    models::{
+        Book, BookArtifact, BookArtifactClient, BookClient, BookMetadata, BookSummary,
+        ChapterClient, ConversionOptions, DeliveryTemplates, SubscriptionClient, TagClient,
+        VolumeClient, OMNIBUS_ARTIFACT,
+    },
+    providers::http::with_robots_txt_ignored,
+    tasks::{
+        chapter_body_conversion::{
+            chapter_metadata_opf, generate_omnibus_epub, validate_extra_args, validate_transforms,
+            CONVERSION_VERSION,
+        },
+        delivery::validate_templates,
+        integrity::verify_chapter,
+        volumes::detect_book_volumes,
+    },
+    util::{is_not_modified, ZipStream},
     AppState,
 };
 
+use super::validation::{ValidJson, Validate, Validator};
+
 #[derive(Debug, PartialEq, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct CreateBookRequest {
     title: String,
     author: String,
     metadata: BookMetadata,
+    #[serde(rename = "conversionOptions", default)]
+    conversion_options: ConversionOptions,
+    #[serde(rename = "deliveryTemplates", default)]
+    delivery_templates: DeliveryTemplates,
+    #[serde(rename = "ignoreRobotsTxt", default)]
+    ignore_robots_txt: bool,
+}
+
+impl Validate for CreateBookRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.non_empty("title", Some(&self.title));
+        validator.non_empty("author", Some(&self.author));
+    }
 }
 
 #[instrument(skip(state))]
 async fn create_book_handler(
     State(state): State<AppState>,
-    Json(request): Json<CreateBookRequest>,
+    ValidJson(request): ValidJson<CreateBookRequest>,
 ) -> Result<Json<Book>, ApiError> {
+    validate_templates(&request.delivery_templates).map_err(ApiError::InvalidRequest)?;
+    validate_transforms(&request.conversion_options.transforms)
+        .map_err(ApiError::InvalidRequest)?;
+    validate_extra_args(&request.conversion_options.extra_args)
+        .map_err(ApiError::InvalidRequest)?;
     let pool = state.pool;
     let client = BookClient::new(&pool);
+    // A serial is only fetched once, so asking for it again returns the existing book.
+    let existing = client
+        .get_book_by_provider_identity(&request.metadata.provider_identity())
+        .await?;
+    if let Some(book) = existing {
+        return Ok(book.into());
+    }
+    let provider = request.metadata.chapter_provider();
+    if let Err(e) = with_robots_txt_ignored(request.ignore_robots_txt, provider.validate()).await {
+        return Err(ApiError::InvalidMetadata(format!("{:#}", e)));
+    }
     let book = client
-        .create_book(&request.title, &request.author, &request.metadata)
+        .create_book(
+            &request.title,
+            &request.author,
+            &request.metadata,
+            &request.conversion_options,
+            &request.delivery_templates,
+            request.ignore_robots_txt,
+        )
         .await?;
     Ok(book.into())
 }
@@ -42,6 +102,20 @@ struct UpdateBookRequest {
     id: Uuid,
     title: Option<String>,
     author: Option<String>,
+    metadata: Option<BookMetadata>,
+    #[serde(rename = "conversionOptions")]
+    conversion_options: Option<ConversionOptions>,
+    #[serde(rename = "deliveryTemplates")]
+    delivery_templates: Option<DeliveryTemplates>,
+    #[serde(rename = "ignoreRobotsTxt")]
+    ignore_robots_txt: Option<bool>,
+}
+
+impl Validate for UpdateBookRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.non_empty("title", self.title.as_deref());
+        validator.non_empty("author", self.author.as_deref());
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize)]
@@ -51,32 +125,222 @@ struct UpdateBookResponse {
     title: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<BookMetadata>,
+    #[serde(rename = "conversionOptions")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conversion_options: Option<ConversionOptions>,
+    #[serde(rename = "deliveryTemplates")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delivery_templates: Option<DeliveryTemplates>,
+    #[serde(rename = "ignoreRobotsTxt")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ignore_robots_txt: Option<bool>,
     updated_at: chrono::DateTime<Utc>,
 }
 
 #[instrument(skip(state))]
 async fn update_book_handler(
     State(state): State<AppState>,
-    Json(request): Json<UpdateBookRequest>,
+    ValidJson(request): ValidJson<UpdateBookRequest>,
 ) -> Result<Json<UpdateBookResponse>, ApiError> {
+    if let Some(templates) = &request.delivery_templates {
+        validate_templates(templates).map_err(ApiError::InvalidRequest)?;
+    }
+    if let Some(options) = &request.conversion_options {
+        validate_transforms(&options.transforms).map_err(ApiError::InvalidRequest)?;
+        validate_extra_args(&options.extra_args).map_err(ApiError::InvalidRequest)?;
+    }
     let pool = state.pool;
     let client = BookClient::new(&pool);
+    if let Some(metadata) = &request.metadata {
+        let ignore_robots_txt = match request.ignore_robots_txt {
+            Some(x) => x,
+            None => client
+                .get_book(&request.id)
+                .await?
+                .map(|x| x.ignore_robots_txt)
+                .unwrap_or_default(),
+        };
+        let provider = metadata.chapter_provider();
+        if let Err(e) = with_robots_txt_ignored(ignore_robots_txt, provider.validate()).await {
+            return Err(ApiError::InvalidMetadata(format!("{:#}", e)));
+        }
+    }
     let book = client
         .update_book(
             &request.id,
             request.title.as_deref(),
             request.author.as_deref(),
+            request.metadata.as_ref(),
+            request.conversion_options.as_ref(),
+            request.delivery_templates.as_ref(),
+            request.ignore_robots_txt,
         )
         .await?;
     Ok(UpdateBookResponse {
         id: book.id,
         title: request.title,
         author: request.author,
+        metadata: request.metadata,
+        conversion_options: request.conversion_options,
+        delivery_templates: request.delivery_templates,
+        ignore_robots_txt: request.ignore_robots_txt,
         updated_at: book.updated_at,
     }
     .into())
 }
 
+#[derive(PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetBookPasswordRequest {
+    id: Uuid,
+    password: Option<String>,
+    /// Names a credential to read the password from instead, so it can be rotated in one place.
+    #[serde(rename = "passwordCredential")]
+    password_credential: Option<String>,
+}
+
+impl std::fmt::Debug for SetBookPasswordRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SetBookPasswordRequest")
+            .field("id", &self.id)
+            .field("password_set", &self.password.is_some())
+            .field("password_credential", &self.password_credential)
+            .finish()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct SetBookPasswordResponse {
+    id: Uuid,
+    #[serde(rename = "updatedChapters")]
+    updated_chapters: u64,
+    #[serde(rename = "updatedAt")]
+    updated_at: chrono::DateTime<Utc>,
+}
+
+#[instrument(skip(state))]
+async fn set_book_password_handler(
+    State(state): State<AppState>,
+    Json(request): Json<SetBookPasswordRequest>,
+) -> Result<Json<SetBookPasswordResponse>, ApiError> {
+    let pool = state.pool;
+    let book = BookClient::new(&pool)
+        .set_book_password(
+            &request.id,
+            request.password.as_deref(),
+            request.password_credential.as_deref(),
+        )
+        .await?;
+    // Chapters which have not been delivered yet would otherwise keep the stale password
+    // found in their email, and those not fetched yet would keep retrying with it.
+    let updated_chapters = ChapterClient::new(&pool)
+        .set_password_for_undelivered_chapters(&book.id, request.password.as_deref())
+        .await?;
+    Ok(SetBookPasswordResponse {
+        id: book.id,
+        updated_chapters,
+        updated_at: book.updated_at,
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetBookPublicSourceRequest {
+    id: Uuid,
+    /// Omit to fetch the book from its primary source alone.
+    #[serde(rename = "publicMetadata")]
+    public_metadata: Option<BookMetadata>,
+}
+
+/// Adds a public source to a book whose primary source releases chapters to patrons first,
+/// such as a Patreon book whose chapters later appear on Royal Road.
+#[instrument(skip(state))]
+async fn set_book_public_source_handler(
+    State(state): State<AppState>,
+    Json(request): Json<SetBookPublicSourceRequest>,
+) -> Result<Json<Book>, ApiError> {
+    let pool = state.pool;
+    let client = BookClient::new(&pool);
+    let book = match client.get_book(&request.id).await? {
+        Some(x) => x,
+        None => {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("book"),
+                id: request.id.to_string(),
+            })
+        }
+    };
+    if let Some(metadata) = &request.public_metadata {
+        if metadata.provider_identity() == book.metadata.provider_identity() {
+            return Err(ApiError::InvalidRequest(String::from(
+                "The public source must differ from the book's source",
+            )));
+        }
+        let provider = metadata.chapter_provider();
+        if let Err(e) = with_robots_txt_ignored(book.ignore_robots_txt, provider.validate()).await {
+            return Err(ApiError::InvalidMetadata(format!("{:#}", e)));
+        }
+    }
+    let book = client
+        .set_public_metadata(&book.id, request.public_metadata.as_ref())
+        .await?;
+    Ok(book.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetBookEarlyAccessRequest {
+    id: Uuid,
+    /// Omit to deliver new chapters to every subscription as soon as they are ready.
+    #[serde(rename = "earlyAccessSecs")]
+    early_access_secs: Option<i64>,
+}
+
+/// Sets how long the book's newly discovered chapters are reserved for early access
+/// subscriptions. Chapters already discovered keep their embargo.
+#[instrument(skip(state))]
+async fn set_book_early_access_handler(
+    State(state): State<AppState>,
+    Json(request): Json<SetBookEarlyAccessRequest>,
+) -> Result<Json<Book>, ApiError> {
+    if matches!(request.early_access_secs, Some(x) if x < 0) {
+        return Err(ApiError::InvalidRequest(String::from(
+            "earlyAccessSecs must not be negative",
+        )));
+    }
+    let book = BookClient::new(&state.pool)
+        .set_early_access_secs(&request.id, request.early_access_secs)
+        .await?;
+    Ok(book.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetBookVolumeDetectionRequest {
+    id: Uuid,
+    #[serde(rename = "detectVolumes")]
+    detect_volumes: bool,
+}
+
+/// Sets whether newly discovered chapters are sorted into volumes detected from their titles.
+/// Turning detection on also sorts the chapters already discovered.
+#[instrument(skip(state))]
+async fn set_book_volume_detection_handler(
+    State(state): State<AppState>,
+    Json(request): Json<SetBookVolumeDetectionRequest>,
+) -> Result<Json<Book>, ApiError> {
+    let book = BookClient::new(&state.pool)
+        .set_detect_volumes(&request.id, request.detect_volumes)
+        .await?;
+    if book.detect_volumes {
+        detect_book_volumes(&state.pool, &book).await?;
+    }
+    Ok(book.into())
+}
+
 #[derive(Debug, PartialEq, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct GetBookRequest {
@@ -100,17 +364,37 @@ async fn get_book_handler(
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListBooksRequest {
+    /// Only list books with this tag.
+    #[serde(rename = "tagId")]
+    tag_id: Option<Uuid>,
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize)]
 struct ListBooksResult {
-    books: Vec<Book>,
+    books: Vec<BookSummary>,
 }
 
 async fn list_books_handler(
     State(state): State<AppState>,
+    Query(request): Query<ListBooksRequest>,
 ) -> Result<Json<ListBooksResult>, ApiError> {
     let pool = state.pool;
     let client = BookClient::new(&pool);
-    let books = client.list_books().await?;
+    // Tagging books isn't published as a change, so only the full list is cached.
+    let books = match request.tag_id {
+        Some(tag_id) => client.list_book_summaries(Some(&tag_id)).await?,
+        None => match state.cache.books().get(&()).await {
+            Some(books) => books,
+            None => {
+                let books = client.list_book_summaries(None).await?;
+                state.cache.books().insert((), books.clone()).await;
+                books
+            }
+        },
+    };
     Ok(ListBooksResult { books }.into())
 }
 
@@ -131,11 +415,348 @@ async fn delete_book_handler(
     Ok(json!({}).into())
 }
 
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReconvertBookRequest {
+    id: Uuid,
+    /// Only reconvert epubs older than this conversion version. Omit to reconvert them all.
+    #[serde(rename = "belowVersion")]
+    below_version: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ReconvertResponse {
+    #[serde(rename = "clearedChapters")]
+    cleared_chapters: u64,
+    #[serde(rename = "conversionVersion")]
+    conversion_version: i64,
+}
+
+/// Clears the book's epubs so the conversion loop regenerates them with the current pipeline.
+#[instrument(skip(state))]
+async fn reconvert_book_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ReconvertBookRequest>,
+) -> Result<Json<ReconvertResponse>, ApiError> {
+    let pool = state.pool;
+    if BookClient::new(&pool)
+        .get_book(&request.id)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::ResourceNotFound {
+            resource_type: String::from("book"),
+            id: request.id.to_string(),
+        });
+    }
+    let cleared_chapters = ChapterClient::new(&pool)
+        .clear_epubs(Some(&request.id), request.below_version)
+        .await?;
+    Ok(ReconvertResponse {
+        cleared_chapters,
+        conversion_version: CONVERSION_VERSION,
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ArchiveBookRequest {
+    id: Uuid,
+    /// Drop the bodies of the book's chapters once the omnibus is stored.
+    #[serde(rename = "pruneChapters", default)]
+    prune_chapters: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ArchiveBookResponse {
+    book: Book,
+    omnibus: BookArtifact,
+    #[serde(rename = "prunedChapters")]
+    pruned_chapters: u64,
+}
+
+/// Archives a finished serial: it is no longer checked for new chapters, and the whole book is
+/// kept as one omnibus epub. Archiving again regenerates the omnibus unless the chapters have
+/// been pruned.
+#[instrument(skip(state))]
+async fn archive_book_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ArchiveBookRequest>,
+) -> Result<Json<ArchiveBookResponse>, ApiError> {
+    let pool = state.pool;
+    let book_client = BookClient::new(&pool);
+    let chapter_client = ChapterClient::new(&pool);
+    let artifact_client = BookArtifactClient::new(&pool);
+    let book = match book_client.get_book(&request.id).await? {
+        Some(x) => x,
+        None => {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("book"),
+                id: request.id.to_string(),
+            })
+        }
+    };
+
+    if request.prune_chapters {
+        // Pruned chapters can't be delivered, so every subscriber must have caught up.
+        for subscription in SubscriptionClient::new(&pool)
+            .list_subscriptions_for_book(&book.id)
+            .await?
+        {
+            let behind = chapter_client
+                .count_chapters_after(
+                    &book.id,
+                    subscription.last_delivered_chapter_created_at.as_ref(),
+                )
+                .await?;
+            if behind > 0 {
+                return Err(ApiError::InvalidRequest(format!(
+                    "Subscription {} has {} undelivered chapters, prune once it has caught up",
+                    subscription.id, behind
+                )));
+            }
+        }
+    }
+
+    let mut chapters = Vec::new();
+    for chapter in chapter_client.list_chapters(&book.id).await? {
+        chapters.push(verify_chapter(&pool, chapter).await?);
+    }
+    if chapters.is_empty() {
+        return Err(ApiError::InvalidRequest(String::from(
+            "The book has no chapters to archive",
+        )));
+    }
+    let missing = chapters.iter().filter(|x| x.html.is_none()).count();
+    let existing = artifact_client
+        .get_artifact(&book.id, OMNIBUS_ARTIFACT)
+        .await?;
+    let omnibus = match (missing, existing) {
+        (0, _) => {
+            let epub = generate_omnibus_epub(&book, &chapters)
+                .await
+                .map_err(|e| ApiError::Conversion(format!("{:#}", e)))?;
+            artifact_client
+                .save_artifact(
+                    &book.id,
+                    OMNIBUS_ARTIFACT,
+                    &epub,
+                    &chapters,
+                    CONVERSION_VERSION,
+                )
+                .await?
+        }
+        // The chapters were pruned when the book was last archived.
+        (_, Some(existing)) if book.archived_at.is_some() => existing,
+        (missing, _) => {
+            return Err(ApiError::InvalidRequest(format!(
+                "{} chapters have not been fetched yet",
+                missing
+            )))
+        }
+    };
+
+    let book = book_client.archive_book(&book.id).await?;
+    let pruned_chapters = match request.prune_chapters {
+        true => chapter_client.prune_chapter_bodies(&book.id).await?,
+        false => 0,
+    };
+    Ok(ArchiveBookResponse {
+        book,
+        omnibus,
+        pruned_chapters,
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GetBookOmnibusRequest {
+    id: Uuid,
+}
+
+/// Downloads the omnibus, streamed from the database rather than read into memory first.
+/// Responses carry an ETag which changes whenever the omnibus is regenerated, so clients
+/// polling with If-None-Match only download it again once it changes.
+#[instrument(skip(state, headers))]
+async fn get_book_omnibus_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(request): Query<GetBookOmnibusRequest>,
+) -> Result<Response, ApiError> {
+    let pool = state.pool;
+    let omnibus = BookArtifactClient::new(&pool)
+        .stream_artifact(&request.id, OMNIBUS_ARTIFACT)
+        .await?;
+    let omnibus = match omnibus {
+        Some(x) => x,
+        None => {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("omnibus"),
+                id: request.id.to_string(),
+            })
+        }
+    };
+    let etag = format!(
+        "\"{}-{}\"",
+        omnibus.updated_at.timestamp_millis(),
+        omnibus.length
+    );
+    if is_not_modified(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    Ok((
+        [
+            (header::CONTENT_TYPE, String::from("application/epub+zip")),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.epub\"", request.id),
+            ),
+            (header::CONTENT_LENGTH, omnibus.length.to_string()),
+            (header::ETAG, etag),
+        ],
+        StreamBody::new(omnibus.chunks),
+    )
+        .into_response())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+struct BookEpubsZipPath {
+    id: Uuid,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BookEpubsZipQuery {
+    /// Write a calibre metadata OPF beside each epub, so adding the extracted folder to a calibre
+    /// library keeps the series, series index and tags.
+    #[serde(default)]
+    calibre: bool,
+}
+
+/// Streams a zip of the book's chapter epubs in reading order, for sideloading. Chapters are
+/// read and written one at a time, so only one epub is held in memory at once.
+#[instrument(skip(state))]
+async fn book_epubs_zip_handler(
+    State(state): State<AppState>,
+    Path(path): Path<BookEpubsZipPath>,
+    Query(query): Query<BookEpubsZipQuery>,
+) -> Result<Response, ApiError> {
+    let pool = state.pool;
+    let book = match BookClient::new(&pool).get_book(&path.id).await? {
+        Some(x) => x,
+        None => {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("book"),
+                id: path.id.to_string(),
+            })
+        }
+    };
+    let chapter_client = ChapterClient::new(&pool);
+    let chapters: Vec<_> = chapter_client
+        .list_chapters_shallow(&book.id)
+        .await?
+        .into_iter()
+        .filter(|x| x.epub_bytes.is_some())
+        .sorted_by_key(|x| x.order_index)
+        .collect();
+    if chapters.is_empty() {
+        return Err(ApiError::InvalidRequest(String::from(
+            "The book has no converted chapters",
+        )));
+    }
+
+    let tags: Vec<String> = TagClient::new(&pool)
+        .list_tags_for_book(&book.id)
+        .await?
+        .into_iter()
+        .map(|x| x.name)
+        .collect();
+    let positions = VolumeClient::new(&pool).chapter_positions(&book.id).await?;
+
+    let file_name = sanitize_filename::sanitize(format!("{}.zip", book.title));
+
+    // A small buffer keeps the reader from running ahead of a slow download.
+    let (sender, receiver) = mpsc::channel::<io::Result<Vec<u8>>>(1);
+    tokio::spawn(async move {
+        let mut zip = ZipStream::new();
+        for shallow in chapters {
+            let chapter = match chapter_client.get_chapter(shallow.id).await {
+                Ok(Some(x)) => verify_chapter(&pool, x).await,
+                // Deleted or pruned since it was listed.
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            let chapter = match chapter {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Error reading chapter {} for zip: {}", shallow.id, e);
+                    let _ = sender.send(Err(io::Error::other(e))).await;
+                    return;
+                }
+            };
+            let epub = match &chapter.epub {
+                Some(x) => x,
+                None => continue,
+            };
+            let stem = sanitize_filename::sanitize(format!(
+                "{:04} {}",
+                chapter.order_index, chapter.title
+            ));
+            let modified = chapter.published_at.unwrap_or(chapter.created_at);
+            let mut bytes = zip
+                .add_file(&format!("{}.epub", stem), epub, modified)
+                .map_err(io::Error::other);
+            if let (Ok(written), true) = (&mut bytes, query.calibre) {
+                let opf = chapter_metadata_opf(&book, &chapter, positions.get(&chapter.id), &tags);
+                match zip.add_file(&format!("{}.opf", stem), opf.as_bytes(), modified) {
+                    Ok(x) => written.extend(x),
+                    Err(e) => bytes = Err(io::Error::other(e)),
+                }
+            }
+            let failed = bytes.is_err();
+            // The download was abandoned if the receiver is gone.
+            if sender.send(bytes).await.is_err() || failed {
+                return;
+            }
+        }
+        let bytes = zip.finish().map_err(io::Error::other);
+        let _ = sender.send(bytes).await;
+    });
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|x| (x, receiver))
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, String::from("application/zip")),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file_name.replace('"', "")),
+            ),
+        ],
+        StreamBody::new(stream),
+    )
+        .into_response())
+}
+
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/createBook", post(create_book_handler))
         .route("/updateBook", post(update_book_handler))
+        .route("/setBookPassword", post(set_book_password_handler))
+        .route("/setBookEarlyAccess", post(set_book_early_access_handler))
+        .route("/setBookPublicSource", post(set_book_public_source_handler))
+        .route(
+            "/setBookVolumeDetection",
+            post(set_book_volume_detection_handler),
+        )
         .route("/getBook", get(get_book_handler))
         .route("/listBooks", get(list_books_handler))
         .route("/deleteBook", delete(delete_book_handler))
+        .route("/reconvertBook", post(reconvert_book_handler))
+        .route("/archiveBook", post(archive_book_handler))
+        .route("/getBookOmnibus", get(get_book_omnibus_handler))
+        .route("/books/:id/epubs.zip", get(book_epubs_zip_handler))
 }
@@ -1,5 +1,8 @@
 use axum::{
-    extract::{Query, State},
+    body::{Bytes, StreamBody},
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     routing::{delete, get, post},
     Json, Router,
 };
@@ -11,10 +14,21 @@ use uuid::Uuid;
 
 use crate::{
     error::ApiError,
-    models::{Chapter, ChapterClient, ChapterMetadata, ShallowChapter},
+    models::{
+        BookClient, Chapter, ChapterBody, ChapterClient, ChapterMetadata, ChapterState, NewChapter,
+        ShallowChapter,
+    },
+    providers::{split_archive, ArchiveLimits},
+    tasks::{
+        integrity::{verify_body_stream, verify_chapter},
+        reparse::{reparse_book, reparse_chapter, SourceReparse},
+    },
+    util::{content_etag, is_not_modified, STORED_BODY_CSP},
     AppState,
 };
 
+use super::validation::{ValidJson, Validate, Validator};
+
 #[derive(Debug, PartialEq, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct CreateChapterRequest {
@@ -26,10 +40,17 @@ struct CreateChapterRequest {
     published_at: Option<DateTime<Utc>>,
 }
 
+impl Validate for CreateChapterRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.non_empty("title", Some(&self.title));
+        validator.chapter_metadata("metadata", &self.metadata);
+    }
+}
+
 #[instrument(skip(state))]
 async fn create_chapter_handler(
     State(state): State<AppState>,
-    Json(request): Json<CreateChapterRequest>,
+    ValidJson(request): ValidJson<CreateChapterRequest>,
 ) -> Result<Json<Chapter>, ApiError> {
     let pool = state.pool;
     let client = ChapterClient::new(&pool);
@@ -55,6 +76,12 @@ struct UpdateChapterRequest {
     published_at: Option<DateTime<Utc>>,
 }
 
+impl Validate for UpdateChapterRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.non_empty("title", self.title.as_deref());
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize)]
 struct UpdateChapterResponse {
     id: Uuid,
@@ -67,7 +94,7 @@ struct UpdateChapterResponse {
 #[instrument(skip(state))]
 async fn update_chapter_handler(
     State(state): State<AppState>,
-    Json(request): Json<UpdateChapterRequest>,
+    ValidJson(request): ValidJson<UpdateChapterRequest>,
 ) -> Result<Json<UpdateChapterResponse>, ApiError> {
     let pool = state.pool;
     let client = ChapterClient::new(&pool);
@@ -88,27 +115,216 @@ async fn update_chapter_handler(
     .into())
 }
 
+#[derive(PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetChapterMetadataRequest {
+    id: Uuid,
+    metadata: ChapterMetadata,
+    /// Fetch the chapter again with the new metadata. Defaults to true.
+    #[serde(default = "default_rehydrate")]
+    rehydrate: bool,
+}
+
+impl Validate for SetChapterMetadataRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.chapter_metadata("metadata", &self.metadata);
+    }
+}
+
+fn default_rehydrate() -> bool {
+    true
+}
+
+impl std::fmt::Debug for SetChapterMetadataRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Metadata may hold a password.
+        f.debug_struct("SetChapterMetadataRequest")
+            .field("id", &self.id)
+            .field("rehydrate", &self.rehydrate)
+            .finish()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct SetChapterMetadataResponse {
+    id: Uuid,
+    metadata: ChapterMetadata,
+    #[serde(rename = "updatedAt")]
+    updated_at: chrono::DateTime<Utc>,
+}
+
+/// Replaces the chapter's provider metadata, such as to fix the url or password of a chapter
+/// which can't be fetched, and queues it to be fetched again.
+#[instrument(skip(state))]
+async fn set_chapter_metadata_handler(
+    State(state): State<AppState>,
+    ValidJson(request): ValidJson<SetChapterMetadataRequest>,
+) -> Result<Json<SetChapterMetadataResponse>, ApiError> {
+    let pool = state.pool;
+    let client = ChapterClient::new(&pool);
+    let chapter = match client.get_chapter(request.id).await? {
+        Some(x) => x,
+        None => {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("chapter"),
+                id: request.id.to_string(),
+            })
+        }
+    };
+    let book = BookClient::new(&pool).get_book(&chapter.book_id).await?;
+    if request.rehydrate
+        && !matches!(&book, Some(book) if request.metadata.body_provider(book).is_some())
+    {
+        return Err(ApiError::InvalidRequest(String::from(
+            "Chapters with this metadata can't be fetched, set rehydrate to false",
+        )));
+    }
+    let chapter = client
+        .set_chapter_metadata(&request.id, &request.metadata, request.rehydrate)
+        .await?;
+    Ok(SetChapterMetadataResponse {
+        id: chapter.id,
+        metadata: chapter.metadata,
+        updated_at: chapter.updated_at,
+    }
+    .into())
+}
+
 #[derive(Debug, PartialEq, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
-struct GetChapterRequest {
+struct SetChapterDeliverAfterRequest {
+    id: Uuid,
+    /// Omit to release the chapter to every subscription.
+    #[serde(rename = "deliverAfter")]
+    deliver_after: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct SetChapterDeliverAfterResponse {
     id: Uuid,
+    #[serde(rename = "deliverAfter")]
+    deliver_after: Option<DateTime<Utc>>,
+    #[serde(rename = "updatedAt")]
+    updated_at: chrono::DateTime<Utc>,
 }
 
+/// Embargoes the chapter until `deliverAfter`, or lifts its embargo. Subscriptions without
+/// early access receive neither it nor any later chapter until then, keeping them in order.
 #[instrument(skip(state))]
+async fn set_chapter_deliver_after_handler(
+    State(state): State<AppState>,
+    Json(request): Json<SetChapterDeliverAfterRequest>,
+) -> Result<Json<SetChapterDeliverAfterResponse>, ApiError> {
+    let chapter = ChapterClient::new(&state.pool)
+        .set_deliver_after(&request.id, request.deliver_after.as_ref())
+        .await?;
+    Ok(SetChapterDeliverAfterResponse {
+        id: chapter.id,
+        deliver_after: chapter.deliver_after,
+        updated_at: chapter.updated_at,
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GetChapterRequest {
+    id: Uuid,
+}
+
+/// Returns the chapter including its html and epub bodies. Responses carry a content hash as
+/// their ETag, so clients polling with If-None-Match don't download unchanged bodies again.
+/// Bodies which fail their integrity check are left out and queued for regeneration.
+#[instrument(skip(state, headers))]
 async fn get_chapter_handler(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Query(request): Query<GetChapterRequest>,
-) -> Result<Json<Chapter>, ApiError> {
+) -> Result<Response, ApiError> {
     let pool = state.pool;
     let client = ChapterClient::new(&pool);
-    let chapter = client.get_chapter(request.id).await?;
-    match chapter {
-        Some(x) => Ok(x.into()),
-        None => Err(ApiError::ResourceNotFound {
-            resource_type: String::from("chapter"),
-            id: request.id.to_string(),
-        }),
+    let chapter = match client.get_chapter(request.id).await? {
+        Some(x) => x,
+        None => {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("chapter"),
+                id: request.id.to_string(),
+            })
+        }
+    };
+    let chapter = verify_chapter(&pool, chapter).await?;
+    let body = serde_json::to_vec(&chapter)?;
+    let etag = content_etag(&body);
+    if is_not_modified(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
     }
+    Ok((
+        [
+            (header::CONTENT_TYPE, String::from("application/json")),
+            (header::ETAG, etag),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+struct ChapterBodyPath {
+    id: Uuid,
+    body: ChapterBody,
+}
+
+/// Downloads one of the chapter's bodies on its own, streamed to the client a chunk at a time.
+/// Both are served as attachments under a policy which blocks scripts, since the html isn't
+/// sanitized. The ETag is the body's stored digest, so it matches the content hash
+/// /getChapter would use.
+#[instrument(skip(state, headers))]
+async fn chapter_body_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(path): Path<ChapterBodyPath>,
+) -> Result<Response, ApiError> {
+    let pool = state.pool;
+    let blob = ChapterClient::new(&pool)
+        .stream_body(&path.id, path.body)
+        .await?
+        .ok_or_else(|| ApiError::ResourceNotFound {
+            resource_type: format!("chapter {}", path.body.as_str()),
+            id: path.id.to_string(),
+        })?;
+    let etag = blob.digest.as_ref().map(|x| format!("\"{}\"", x));
+    if let Some(etag) = etag.as_ref().filter(|x| is_not_modified(&headers, x)) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag.clone())]).into_response());
+    }
+    let (content_type, extension) = match path.body {
+        ChapterBody::Html => ("text/html; charset=utf-8", "html"),
+        ChapterBody::Epub => ("application/epub+zip", "epub"),
+    };
+    let mut headers = vec![
+        (header::CONTENT_LENGTH, blob.length.to_string()),
+        (header::CONTENT_TYPE, String::from(content_type)),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.{}\"", path.id, extension),
+        ),
+        (
+            header::CONTENT_SECURITY_POLICY,
+            String::from(STORED_BODY_CSP),
+        ),
+        (header::X_CONTENT_TYPE_OPTIONS, String::from("nosniff")),
+    ];
+    if let Some(etag) = etag {
+        headers.push((header::ETAG, etag));
+    }
+    let mut response =
+        StreamBody::new(verify_body_stream(&pool, path.id, path.body, blob)).into_response();
+    for (name, value) in headers {
+        // Every value is ascii.
+        response
+            .headers_mut()
+            .insert(name, HeaderValue::from_str(&value).unwrap());
+    }
+    Ok(response)
 }
 
 #[derive(Debug, PartialEq, Clone, Deserialize)]
@@ -116,6 +332,8 @@ async fn get_chapter_handler(
 struct ListChaptersRequest {
     #[serde(rename = "bookId")]
     book_id: Uuid,
+    /// Only list chapters in this state.
+    state: Option<ChapterState>,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize)]
@@ -127,13 +345,220 @@ struct ListChaptersResult {
 async fn list_chapters_handler(
     State(state): State<AppState>,
     Query(request): Query<ListChaptersRequest>,
+) -> Result<Json<ListChaptersResult>, ApiError> {
+    let cache = state.cache.chapters();
+    let chapters = match cache.get(&request.book_id).await {
+        Some(x) => x,
+        None => {
+            let client = ChapterClient::new(&state.pool);
+            let chapters = client.list_chapters_shallow(&request.book_id).await?;
+            cache.insert(request.book_id, chapters.clone()).await;
+            chapters
+        }
+    };
+    let chapters = match request.state {
+        Some(state) => chapters.into_iter().filter(|x| x.state == state).collect(),
+        None => chapters,
+    };
+    Ok(ListChaptersResult { chapters }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReorderChaptersRequest {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+    #[serde(rename = "chapterIds")]
+    chapter_ids: Vec<Uuid>,
+}
+
+#[instrument(skip(state))]
+async fn reorder_chapters_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ReorderChaptersRequest>,
 ) -> Result<Json<ListChaptersResult>, ApiError> {
     let pool = state.pool;
     let client = ChapterClient::new(&pool);
+    client
+        .reorder_chapters(&request.book_id, &request.chapter_ids)
+        .await?;
     let chapters = client.list_chapters_shallow(&request.book_id).await?;
     Ok(ListChaptersResult { chapters }.into())
 }
 
+/// The largest archive /importChapters accepts.
+const IMPORT_LIMIT_BYTES: usize = 100 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImportChaptersRequest {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+    /// Place the chapters before the book's existing chapters, for early arcs which are no
+    /// longer online. They are then only delivered to subscriptions which haven't started.
+    #[serde(default)]
+    prepend: bool,
+}
+
+/// Splits an uploaded epub, or zip of html files, into chapters of the book. The archive is
+/// sent as the request body.
+#[instrument(skip(state, archive), fields(archive_bytes = archive.len()))]
+async fn import_chapters_handler(
+    State(state): State<AppState>,
+    Query(request): Query<ImportChaptersRequest>,
+    archive: Bytes,
+) -> Result<Json<ListChaptersResult>, ApiError> {
+    let pool = state.pool;
+    if BookClient::new(&pool)
+        .get_book(&request.book_id)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::ResourceNotFound {
+            resource_type: String::from("book"),
+            id: request.book_id.to_string(),
+        });
+    }
+    let imported = split_archive(&archive, ArchiveLimits::default())
+        .map_err(|e| ApiError::InvalidRequest(format!("{:#}", e)))?;
+    let new_chapters: Vec<NewChapter> = imported
+        .into_iter()
+        .enumerate()
+        .map(|(ordinal, x)| NewChapter {
+            title: x.title,
+            raw_title: None,
+            deliver_after: None,
+            metadata: ChapterMetadata::Imported { source: x.source },
+            book_id: request.book_id,
+            html: Some(x.html),
+            epub: None,
+            published_at: None,
+            ordinal: ordinal as i64,
+            source: None,
+        })
+        .collect();
+
+    let client = ChapterClient::new(&pool);
+    match request.prepend {
+        true => {
+            client
+                .prepend_chapters(&request.book_id, &new_chapters)
+                .await?
+        }
+        false => client.create_chapters(&new_chapters).await?,
+    };
+    let chapters = client.list_chapters_shallow(&request.book_id).await?;
+    Ok(ListChaptersResult { chapters }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChapterStateRequest {
+    id: Uuid,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ChapterStateResponse {
+    id: Uuid,
+    state: ChapterState,
+    #[serde(rename = "updatedAt")]
+    updated_at: chrono::DateTime<Utc>,
+}
+
+impl From<Chapter> for ChapterStateResponse {
+    fn from(chapter: Chapter) -> Self {
+        ChapterStateResponse {
+            id: chapter.id,
+            state: chapter.state,
+            updated_at: chapter.updated_at,
+        }
+    }
+}
+
+/// Holds a chapter out of the pipeline and deliveries, such as one whose source is broken,
+/// until it is retried.
+#[instrument(skip(state))]
+async fn quarantine_chapter_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ChapterStateRequest>,
+) -> Result<Json<ChapterStateResponse>, ApiError> {
+    let chapter = ChapterClient::new(&state.pool)
+        .quarantine_chapter(&request.id)
+        .await?;
+    Ok(ChapterStateResponse::from(chapter).into())
+}
+
+/// Puts a failed or quarantined chapter back into the pipeline, at the stage its bodies reached.
+#[instrument(skip(state))]
+async fn retry_chapter_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ChapterStateRequest>,
+) -> Result<Json<ChapterStateResponse>, ApiError> {
+    let chapter = ChapterClient::new(&state.pool)
+        .retry_chapter(&request.id)
+        .await?;
+    Ok(ChapterStateResponse::from(chapter).into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReparseSourceQuery {
+    #[serde(rename = "chapterId")]
+    chapter_id: Option<Uuid>,
+    #[serde(rename = "bookId")]
+    book_id: Option<Uuid>,
+    /// Report what would change without saving it.
+    #[serde(default)]
+    preview: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ReparseSourceResponse {
+    chapters: Vec<SourceReparse>,
+}
+
+/// Parses a chapter, or each chapter of a book, again from the email or page it was found in,
+/// for after a parsing fix, reporting how the title, metadata and html changed. Epubs are
+/// regenerated from the new html.
+#[instrument(skip(state))]
+async fn reparse_source_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ReparseSourceQuery>,
+) -> Result<Json<ReparseSourceResponse>, ApiError> {
+    let (book_id, chapter) = match (query.chapter_id, query.book_id) {
+        (Some(chapter_id), None) => {
+            let chapter = ChapterClient::new(&state.pool)
+                .get_chapter(chapter_id)
+                .await?
+                .ok_or_else(|| ApiError::ResourceNotFound {
+                    resource_type: String::from("chapter"),
+                    id: chapter_id.to_string(),
+                })?;
+            (chapter.book_id, Some(chapter))
+        }
+        (None, Some(book_id)) => (book_id, None),
+        _ => {
+            return Err(ApiError::InvalidRequest(String::from(
+                "Exactly one of chapterId and bookId must be given",
+            )))
+        }
+    };
+    let book = BookClient::new(&state.pool)
+        .get_book(&book_id)
+        .await?
+        .ok_or_else(|| ApiError::ResourceNotFound {
+            resource_type: String::from("book"),
+            id: book_id.to_string(),
+        })?;
+    let chapters = match chapter {
+        Some(chapter) => vec![reparse_chapter(&state.pool, &book, &chapter, query.preview)
+            .await
+            .map_err(|e| ApiError::InvalidRequest(format!("{:#}", e)))?],
+        None => reparse_book(&state.pool, &book, query.preview).await?,
+    };
+    Ok(ReparseSourceResponse { chapters }.into())
+}
+
 #[derive(Debug, PartialEq, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct DeleteChapterRequest {
@@ -157,5 +582,19 @@ pub fn router() -> Router<AppState> {
         .route("/updateChapter", post(update_chapter_handler))
         .route("/getChapter", get(get_chapter_handler))
         .route("/listChapters", get(list_chapters_handler))
+        .route("/chapters/:id/:body", get(chapter_body_handler))
+        .route("/reorderChapters", post(reorder_chapters_handler))
+        .route("/setChapterMetadata", post(set_chapter_metadata_handler))
+        .route(
+            "/setChapterDeliverAfter",
+            post(set_chapter_deliver_after_handler),
+        )
+        .route(
+            "/importChapters",
+            post(import_chapters_handler).layer(DefaultBodyLimit::max(IMPORT_LIMIT_BYTES)),
+        )
+        .route("/quarantineChapter", post(quarantine_chapter_handler))
+        .route("/retryChapter", post(retry_chapter_handler))
+        .route("/reparseSource", post(reparse_source_handler))
         .route("/deleteChapter", delete(delete_chapter_handler))
 }
@@ -0,0 +1,103 @@
+use axum::{
+    extract::State,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::instrument;
+
+use crate::{
+    error::ApiError,
+    models::{Credential, CredentialClient},
+    AppState,
+};
+
+use super::validation::{ValidJson, Validate, Validator};
+
+#[derive(PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetCredentialRequest {
+    name: String,
+    value: String,
+}
+
+impl std::fmt::Debug for SetCredentialRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SetCredentialRequest")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl Validate for SetCredentialRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.non_empty("name", Some(&self.name));
+        validator.non_empty("value", Some(&self.value));
+    }
+}
+
+#[instrument(skip(state))]
+async fn create_credential_handler(
+    State(state): State<AppState>,
+    ValidJson(request): ValidJson<SetCredentialRequest>,
+) -> Result<Json<Credential>, ApiError> {
+    let pool = state.pool;
+    let credential = CredentialClient::new(&pool)
+        .create_credential(&request.name, &request.value)
+        .await?;
+    Ok(credential.into())
+}
+
+/// Replaces a credential's value. Providers use it from their next pass, without a redeploy.
+#[instrument(skip(state))]
+async fn rotate_credential_handler(
+    State(state): State<AppState>,
+    ValidJson(request): ValidJson<SetCredentialRequest>,
+) -> Result<Json<Credential>, ApiError> {
+    let pool = state.pool;
+    let credential = CredentialClient::new(&pool)
+        .rotate_credential(&request.name, &request.value)
+        .await?;
+    Ok(credential.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ListCredentialsResult {
+    credentials: Vec<Credential>,
+}
+
+#[instrument(skip(state))]
+async fn list_credentials_handler(
+    State(state): State<AppState>,
+) -> Result<Json<ListCredentialsResult>, ApiError> {
+    let pool = state.pool;
+    let credentials = CredentialClient::new(&pool).list_credentials().await?;
+    Ok(ListCredentialsResult { credentials }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeleteCredentialRequest {
+    name: String,
+}
+
+#[instrument(skip(state))]
+async fn delete_credential_handler(
+    State(state): State<AppState>,
+    Json(request): Json<DeleteCredentialRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let pool = state.pool;
+    CredentialClient::new(&pool)
+        .delete_credential(&request.name)
+        .await?;
+    Ok(json!({}).into())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/createCredential", post(create_credential_handler))
+        .route("/rotateCredential", post(rotate_credential_handler))
+        .route("/listCredentials", get(list_credentials_handler))
+        .route("/deleteCredential", delete(delete_credential_handler))
+}
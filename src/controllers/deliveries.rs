@@ -0,0 +1,222 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::secret,
+    error::ApiError,
+    models::{
+        BacklogDelivery, DeliveryAttempt, DeliveryClient, DeliveryReceipt, ReceiptState,
+        Subscription, SubscriptionClient,
+    },
+    tasks::delivery::{
+        normalize_message_id, pending_deliveries, record_subscriber_failure,
+        record_subscriber_success, PendingDelivery,
+    },
+    AppState,
+};
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct PendingDeliveriesResponse {
+    deliveries: Vec<PendingDelivery>,
+}
+
+#[instrument(skip(state))]
+async fn pending_deliveries_handler(
+    State(state): State<AppState>,
+) -> Result<Json<PendingDeliveriesResponse>, ApiError> {
+    let deliveries = pending_deliveries(&state.pool).await?;
+    Ok(PendingDeliveriesResponse { deliveries }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfirmDeliveryRequest {
+    #[serde(rename = "subscriptionId")]
+    subscription_id: Uuid,
+    /// Send the backlog in a single delivery, or paced out in deliveries within the cap.
+    delivery: BacklogDelivery,
+}
+
+/// Lets a subscription's undelivered chapters through the delivery cap. Only chapters created
+/// by now are confirmed, so a later flood waits for confirmation again.
+#[instrument(skip(state))]
+async fn confirm_delivery_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ConfirmDeliveryRequest>,
+) -> Result<Json<Subscription>, ApiError> {
+    let subscription = SubscriptionClient::new(&state.pool)
+        .confirm_backlog(&request.subscription_id, request.delivery, &Utc::now())
+        .await?;
+    info!(
+        "Confirmed {} delivery of the backlog of subscription {}",
+        request.delivery.as_str(),
+        subscription.id
+    );
+    Ok(subscription.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListDeliveryAttemptsRequest {
+    #[serde(rename = "subscriptionId")]
+    subscription_id: Uuid,
+    limit: Option<i64>,
+}
+
+/// An attempt along with what its channels have reported of the messages it was sent as.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct LedgerEntry {
+    #[serde(flatten)]
+    attempt: DeliveryAttempt,
+    receipts: Vec<DeliveryReceipt>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ListDeliveryAttemptsResponse {
+    attempts: Vec<LedgerEntry>,
+}
+
+/// Lists the subscription's delivery attempts, most recent first, with whether each message
+/// was accepted, delivered or opened.
+#[instrument(skip(state))]
+async fn list_delivery_attempts_handler(
+    State(state): State<AppState>,
+    Query(request): Query<ListDeliveryAttemptsRequest>,
+) -> Result<Json<ListDeliveryAttemptsResponse>, ApiError> {
+    let client = DeliveryClient::new(&state.pool);
+    let limit = request.limit.unwrap_or(20).clamp(1, 100);
+    let mut attempts = Vec::new();
+    for attempt in client
+        .list_attempts(&request.subscription_id, limit)
+        .await?
+    {
+        let receipts = client.list_receipts(&attempt.id).await?;
+        attempts.push(LedgerEntry { attempt, receipts });
+    }
+    Ok(ListDeliveryAttemptsResponse { attempts }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+struct MailgunSignature {
+    timestamp: String,
+    token: String,
+    signature: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+struct MailgunWebhook {
+    signature: MailgunSignature,
+    #[serde(rename = "event-data")]
+    event_data: serde_json::Value,
+}
+
+/// Whether the signature is the HMAC of the timestamp and token keyed by the webhook signing
+/// key, and recent enough that it isn't a replay.
+fn is_valid_mailgun_signature(signing_key: &str, signature: &MailgunSignature) -> bool {
+    let recent = signature
+        .timestamp
+        .parse::<i64>()
+        .map(|x| (Utc::now().timestamp() - x).abs() < 15 * 60)
+        .unwrap_or(false);
+    let bytes: Option<Vec<u8>> = (0..signature.signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(signature.signature.get(i..i + 2)?, 16).ok())
+        .collect();
+    let bytes = match (recent, bytes) {
+        (true, Some(x)) => x,
+        _ => return false,
+    };
+    // Compared in constant time.
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.as_bytes()).unwrap();
+    mac.update(signature.timestamp.as_bytes());
+    mac.update(signature.token.as_bytes());
+    mac.verify_slice(&bytes).is_ok()
+}
+
+/// The receipt state a mailgun event moves its message to, if any. Temporary failures are
+/// retried by mailgun, so only permanent ones count.
+fn mailgun_receipt_state(event: &serde_json::Value) -> Option<ReceiptState> {
+    match event.get("event")?.as_str()? {
+        "accepted" => Some(ReceiptState::Accepted),
+        "delivered" => Some(ReceiptState::Delivered),
+        "opened" => Some(ReceiptState::Opened),
+        "rejected" => Some(ReceiptState::Failed),
+        "failed" => match event.get("severity").and_then(|x| x.as_str()) {
+            Some("temporary") => None,
+            _ => Some(ReceiptState::Failed),
+        },
+        _ => None,
+    }
+}
+
+/// Ingests mailgun's delivery events, from the webhook signing key in
+/// CEREAL_MAILGUN_WEBHOOK_SIGNING_KEY, into the receipts of the messages they're about.
+/// Mailgun doesn't retry events answered with 406, so forged ones are refused that way.
+#[instrument(skip(state, request))]
+async fn mailgun_webhook_handler(
+    State(state): State<AppState>,
+    Json(request): Json<MailgunWebhook>,
+) -> Result<StatusCode, ApiError> {
+    let signing_key = secret("CEREAL_MAILGUN_WEBHOOK_SIGNING_KEY").ok_or_else(|| {
+        ApiError::InvalidRequest(String::from(
+            "Mailgun webhooks are disabled, set CEREAL_MAILGUN_WEBHOOK_SIGNING_KEY to enable them",
+        ))
+    })?;
+    if !is_valid_mailgun_signature(&signing_key, &request.signature) {
+        warn!("Refusing mailgun event with a bad signature");
+        return Ok(StatusCode::NOT_ACCEPTABLE);
+    }
+    let event = &request.event_data;
+    let message_id = event
+        .pointer("/message/headers/message-id")
+        .and_then(|x| x.as_str())
+        .map(normalize_message_id);
+    let (message_id, receipt_state) = match (message_id, mailgun_receipt_state(event)) {
+        (Some(message_id), Some(receipt_state)) => (message_id, receipt_state),
+        _ => return Ok(StatusCode::OK),
+    };
+    let detail = event
+        .pointer("/delivery-status/message")
+        .or_else(|| event.pointer("/delivery-status/description"))
+        .or_else(|| event.get("reason"))
+        .and_then(|x| x.as_str())
+        .filter(|x| !x.is_empty());
+    let changed = DeliveryClient::new(&state.pool)
+        .advance_receipts(&message_id, receipt_state, detail)
+        .await?;
+    if !changed.is_empty() {
+        info!("Message {} is now {}", message_id, receipt_state.as_str());
+    }
+    // Bounces count against the subscriber the message was sent to, as a failed send does.
+    for receipt in changed {
+        match receipt_state {
+            ReceiptState::Failed => {
+                let failure = detail.unwrap_or("the message bounced");
+                record_subscriber_failure(&state.pool, &receipt.subscriber_id, failure).await
+            }
+            ReceiptState::Delivered | ReceiptState::Opened => {
+                record_subscriber_success(&state.pool, &receipt.subscriber_id).await
+            }
+            ReceiptState::Accepted => {}
+        }
+    }
+    Ok(StatusCode::OK)
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/pendingDeliveries", get(pending_deliveries_handler))
+        .route("/confirmDelivery", post(confirm_delivery_handler))
+        .route("/listDeliveryAttempts", get(list_delivery_attempts_handler))
+        .route("/webhooks/mailgun", post(mailgun_webhook_handler))
+}
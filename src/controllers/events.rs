@@ -0,0 +1,53 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::Query,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+    Router,
+};
+use futures::Stream;
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::instrument;
+
+use crate::{
+    models::changes::{subscribe, Entity},
+    AppState,
+};
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EventsRequest {
+    /// Only stream changes to this kind of entity.
+    entity: Option<Entity>,
+}
+
+/// Streams changes made through this instance as server-sent events, each a json `Change`.
+/// A `lagged` event reports how many changes were missed by a client reading too slowly.
+#[instrument]
+async fn events_handler(
+    Query(request): Query<EventsRequest>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = subscribe();
+    let stream = futures::stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(change) if !matches!(request.entity, Some(x) if x != change.entity) => {
+                    Event::default().event("change").json_data(&change).ok()?
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    Event::default().event("lagged").data(missed.to_string())
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((Ok(event), receiver));
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/events", get(events_handler))
+}
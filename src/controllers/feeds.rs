@@ -0,0 +1,355 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    models::{Book, BookClient, Chapter, ChapterClient},
+    tasks::{
+        chapter_body_conversion::escape_xml,
+        websub::{feed_path, hub_url},
+    },
+    AppState,
+};
+
+/// How many of the most recent chapters a feed lists.
+const FEED_LENGTH: i64 = 50;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FeedFormat {
+    Rss,
+    Atom,
+    Json,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+struct BookFeedPath {
+    id: Uuid,
+    format: FeedFormat,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+struct FirehoseFeedPath {
+    format: FeedFormat,
+}
+
+/// A feed of a book's latest chapters with their full content.
+#[instrument(skip(state))]
+async fn book_feed_handler(
+    State(state): State<AppState>,
+    Path(path): Path<BookFeedPath>,
+) -> Result<Response, ApiError> {
+    let pool = state.pool;
+    let book = match BookClient::new(&pool).get_book(&path.id).await? {
+        Some(x) => x,
+        None => {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("book"),
+                id: path.id.to_string(),
+            })
+        }
+    };
+    let chapters = ChapterClient::new(&pool)
+        .list_recent_chapters(Some(&book.id), FEED_LENGTH)
+        .await?;
+    let feed = Feed {
+        id: format!("urn:uuid:{}", book.id),
+        title: book.title.clone(),
+        author: Some(book.author.clone()),
+        self_path: feed_path(Some(&book.id)),
+        books: HashMap::from([(book.id, book)]),
+        chapters,
+    };
+    Ok(feed.render(path.format))
+}
+
+/// A feed of the latest chapters of every book, each categorized by its book.
+#[instrument(skip(state))]
+async fn firehose_feed_handler(
+    State(state): State<AppState>,
+    Path(path): Path<FirehoseFeedPath>,
+) -> Result<Response, ApiError> {
+    let pool = state.pool;
+    let books = BookClient::new(&pool)
+        .list_books()
+        .await?
+        .into_iter()
+        .map(|x| (x.id, x))
+        .collect();
+    let chapters = ChapterClient::new(&pool)
+        .list_recent_chapters(None, FEED_LENGTH)
+        .await?;
+    let feed = Feed {
+        id: String::from("urn:cereal:feeds:all"),
+        title: String::from("Cereal: all books"),
+        author: None,
+        self_path: feed_path(None),
+        books,
+        chapters,
+    };
+    Ok(feed.render(path.format))
+}
+
+struct Feed {
+    id: String,
+    title: String,
+    author: Option<String>,
+    /// The path of the feed, less its format.
+    self_path: String,
+    books: HashMap<Uuid, Book>,
+    /// Newest first.
+    chapters: Vec<Chapter>,
+}
+
+struct Entry<'a> {
+    id: String,
+    title: &'a str,
+    book: Option<&'a Book>,
+    content: String,
+    published: DateTime<Utc>,
+    updated: DateTime<Utc>,
+}
+
+impl Feed {
+    fn entries(&self) -> impl Iterator<Item = Entry<'_>> {
+        self.chapters.iter().map(|chapter| Entry {
+            id: format!("urn:uuid:{}", chapter.id),
+            title: &chapter.title,
+            book: self.books.get(&chapter.book_id),
+            content: String::from_utf8_lossy(chapter.html.as_deref().unwrap_or_default())
+                .into_owned(),
+            published: chapter.published_at.unwrap_or(chapter.created_at),
+            updated: chapter.updated_at,
+        })
+    }
+
+    fn updated(&self) -> DateTime<Utc> {
+        self.chapters
+            .iter()
+            .map(|x| x.updated_at)
+            .max()
+            .unwrap_or_else(Utc::now)
+    }
+
+    /// Where the feed is served, absolute if CEREAL_PUBLIC_URL names where the server is
+    /// reachable.
+    fn url(&self, format: FeedFormat) -> String {
+        let base = env::var("CEREAL_PUBLIC_URL").unwrap_or_default();
+        let format = match format {
+            FeedFormat::Rss => "rss",
+            FeedFormat::Atom => "atom",
+            FeedFormat::Json => "json",
+        };
+        format!("{}{}{}", base.trim_end_matches('/'), self.self_path, format)
+    }
+
+    fn render(&self, format: FeedFormat) -> Response {
+        let (content_type, body) = match format {
+            FeedFormat::Rss => ("application/rss+xml; charset=utf-8", self.rss()),
+            FeedFormat::Atom => ("application/atom+xml; charset=utf-8", self.atom()),
+            FeedFormat::Json => ("application/feed+json; charset=utf-8", self.json_feed()),
+        };
+        ([(header::CONTENT_TYPE, content_type)], body).into_response()
+    }
+
+    fn rss(&self) -> String {
+        let items = self
+            .entries()
+            .map(|entry| rss::Item {
+                title: Some(entry.title.to_owned()),
+                guid: Some(rss::Guid {
+                    value: entry.id,
+                    permalink: false,
+                }),
+                author: entry.book.map(|x| x.author.clone()),
+                categories: entry
+                    .book
+                    .map(|x| rss::Category {
+                        name: x.title.clone(),
+                        domain: Some(format!("urn:uuid:{}", x.id)),
+                    })
+                    .into_iter()
+                    .collect(),
+                pub_date: Some(entry.published.to_rfc2822()),
+                content: Some(entry.content),
+                ..Default::default()
+            })
+            .collect();
+        let mut channel = rss::Channel {
+            title: self.title.clone(),
+            link: self.url(FeedFormat::Rss),
+            description: self.title.clone(),
+            last_build_date: Some(self.updated().to_rfc2822()),
+            items,
+            ..Default::default()
+        };
+        if let Some(hub) = hub_url() {
+            let links = [("hub", hub), ("self", self.url(FeedFormat::Rss))]
+                .into_iter()
+                .map(|(rel, href)| rss::extension::Extension {
+                    name: String::from("atom:link"),
+                    attrs: BTreeMap::from([
+                        (String::from("rel"), String::from(rel)),
+                        (String::from("href"), href),
+                    ]),
+                    ..Default::default()
+                })
+                .collect();
+            channel.namespaces.insert(
+                String::from("atom"),
+                String::from("http://www.w3.org/2005/Atom"),
+            );
+            channel.extensions.insert(
+                String::from("atom"),
+                BTreeMap::from([(String::from("link"), links)]),
+            );
+        }
+        channel.to_string()
+    }
+
+    fn atom(&self) -> String {
+        let entries: String = self
+            .entries()
+            .map(|entry| {
+                let book_metadata = entry
+                    .book
+                    .map(|x| {
+                        format!(
+                            "    <author><name>{}</name></author>\n    <category term=\"{}\" label=\"{}\"/>\n",
+                            escape_xml(&x.author),
+                            x.id,
+                            escape_xml(&x.title)
+                        )
+                    })
+                    .unwrap_or_default();
+                format!(
+                    "  <entry>\n    <id>{id}</id>\n    <title>{title}</title>\n    <published>{published}</published>\n    <updated>{updated}</updated>\n{book_metadata}    <content type=\"html\">{content}</content>\n  </entry>\n",
+                    id = entry.id,
+                    title = escape_xml(entry.title),
+                    published = entry.published.to_rfc3339(),
+                    updated = entry.updated.to_rfc3339(),
+                    content = escape_xml(&entry.content),
+                )
+            })
+            .collect();
+        let hub = hub_url()
+            .map(|x| format!("  <link rel=\"hub\" href=\"{}\"/>\n", escape_xml(&x)))
+            .unwrap_or_default();
+        let author = self
+            .author
+            .as_deref()
+            .map(|x| format!("  <author><name>{}</name></author>\n", escape_xml(x)))
+            .unwrap_or_default();
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <id>{id}</id>
+  <title>{title}</title>
+  <updated>{updated}</updated>
+  <link rel="self" href="{link}"/>
+{hub}{author}{entries}</feed>
+"#,
+            id = escape_xml(&self.id),
+            title = escape_xml(&self.title),
+            updated = self.updated().to_rfc3339(),
+            link = escape_xml(&self.url(FeedFormat::Atom)),
+        )
+    }
+
+    fn json_feed(&self) -> String {
+        let items = self
+            .entries()
+            .map(|entry| JsonFeedItem {
+                id: entry.id,
+                title: entry.title,
+                content_html: entry.content,
+                date_published: entry.published,
+                date_modified: entry.updated,
+                authors: entry
+                    .book
+                    .map(|x| JsonFeedAuthor { name: &x.author })
+                    .into_iter()
+                    .collect(),
+                tags: entry.book.map(|x| x.title.as_str()).into_iter().collect(),
+            })
+            .collect();
+        let feed = JsonFeed {
+            version: "https://jsonfeed.org/version/1.1",
+            title: &self.title,
+            feed_url: self.url(FeedFormat::Json),
+            authors: self
+                .author
+                .as_deref()
+                .map(|name| JsonFeedAuthor { name })
+                .into_iter()
+                .collect(),
+            hubs: hub_url()
+                .map(|url| JsonFeedHub {
+                    hub_type: "WebSub",
+                    url,
+                })
+                .into_iter()
+                .collect(),
+            items,
+        };
+        // Serializing strings and dates can't fail.
+        serde_json::to_string(&feed).unwrap()
+    }
+}
+
+#[derive(Serialize)]
+struct JsonFeed<'a> {
+    version: &'static str,
+    title: &'a str,
+    feed_url: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    authors: Vec<JsonFeedAuthor<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    hubs: Vec<JsonFeedHub>,
+    items: Vec<JsonFeedItem<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedHub {
+    #[serde(rename = "type")]
+    hub_type: &'static str,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct JsonFeedAuthor<'a> {
+    name: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem<'a> {
+    id: String,
+    title: &'a str,
+    content_html: String,
+    date_published: DateTime<Utc>,
+    date_modified: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    authors: Vec<JsonFeedAuthor<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<&'a str>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/feeds/:format", get(firehose_feed_handler))
+        .route("/books/:id/feeds/:format", get(book_feed_handler))
+}
@@ -0,0 +1,212 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use tracing::{error, instrument};
+
+use crate::{
+    error::ApiError,
+    models::{KosyncUser, ReadingProgress, ReadingProgressClient},
+    AppState,
+};
+
+/// Errors in the form KOReader's progress sync plugin understands, which differs from the
+/// rest of the api.
+#[derive(Debug)]
+enum KosyncError {
+    Unauthorized,
+    UserExists,
+    InvalidRequest,
+    Server(ApiError),
+}
+
+impl From<ApiError> for KosyncError {
+    fn from(value: ApiError) -> Self {
+        KosyncError::Server(value)
+    }
+}
+
+impl IntoResponse for KosyncError {
+    fn into_response(self) -> axum::response::Response {
+        let (status, code, message) = match &self {
+            KosyncError::Unauthorized => (StatusCode::UNAUTHORIZED, 2001, "Unauthorized"),
+            KosyncError::UserExists => (
+                StatusCode::PAYMENT_REQUIRED,
+                2002,
+                "Username is already registered.",
+            ),
+            KosyncError::InvalidRequest => (StatusCode::FORBIDDEN, 2003, "Invalid request"),
+            KosyncError::Server(e) => {
+                error!("Error handling kosync request: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    2000,
+                    "Unknown server error.",
+                )
+            }
+        };
+        (status, Json(json!({ "code": code, "message": message }))).into_response()
+    }
+}
+
+/// Checks the x-auth-user and x-auth-key headers KOReader sends with each request.
+async fn authorize(
+    client: &ReadingProgressClient,
+    headers: &HeaderMap,
+) -> Result<KosyncUser, KosyncError> {
+    let header = |name: &str| headers.get(name).and_then(|x| x.to_str().ok());
+    let (username, userkey) = match (header("x-auth-user"), header("x-auth-key")) {
+        (Some(username), Some(userkey)) => (username, userkey),
+        _ => return Err(KosyncError::Unauthorized),
+    };
+    match client.get_user(username).await? {
+        // The route is public, so the key is compared in constant time.
+        Some(user) if bool::from(user.userkey.as_bytes().ct_eq(userkey.as_bytes())) => Ok(user),
+        _ => Err(KosyncError::Unauthorized),
+    }
+}
+
+#[derive(PartialEq, Clone, Deserialize)]
+struct CreateUserRequest {
+    username: String,
+    password: String,
+}
+
+impl std::fmt::Debug for CreateUserRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CreateUserRequest")
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+#[instrument(skip(state))]
+async fn create_user_handler(
+    State(state): State<AppState>,
+    Json(request): Json<CreateUserRequest>,
+) -> Result<impl IntoResponse, KosyncError> {
+    if request.username.is_empty() || request.password.is_empty() {
+        return Err(KosyncError::InvalidRequest);
+    }
+    let client = ReadingProgressClient::new(&state.pool);
+    let user = match client
+        .create_user(&request.username, &request.password)
+        .await
+    {
+        Err(ApiError::InvalidRequest(_)) => return Err(KosyncError::UserExists),
+        x => x?,
+    };
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "username": user.username })),
+    ))
+}
+
+#[instrument(skip(state, headers))]
+async fn auth_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, KosyncError> {
+    let client = ReadingProgressClient::new(&state.pool);
+    authorize(&client, &headers).await?;
+    Ok(json!({ "authorized": "OK" }).into())
+}
+
+// KOReader may send more fields than these in later versions, so unknown fields are allowed.
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+struct UpdateProgressRequest {
+    document: String,
+    progress: String,
+    percentage: f64,
+    device: String,
+    device_id: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct UpdateProgressResponse {
+    document: String,
+    timestamp: i64,
+}
+
+#[instrument(skip(state, headers))]
+async fn update_progress_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<UpdateProgressRequest>,
+) -> Result<Json<UpdateProgressResponse>, KosyncError> {
+    let client = ReadingProgressClient::new(&state.pool);
+    let user = authorize(&client, &headers).await?;
+    if request.document.is_empty() {
+        return Err(KosyncError::InvalidRequest);
+    }
+    let progress = client
+        .set_progress(
+            &user.username,
+            &request.document,
+            &request.progress,
+            request.percentage,
+            &request.device,
+            &request.device_id,
+        )
+        .await?;
+    Ok(UpdateProgressResponse {
+        document: progress.document,
+        timestamp: progress.updated_at.timestamp(),
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct GetProgressResponse {
+    document: String,
+    progress: String,
+    percentage: f64,
+    device: String,
+    device_id: String,
+    timestamp: i64,
+}
+
+impl From<ReadingProgress> for GetProgressResponse {
+    fn from(value: ReadingProgress) -> Self {
+        GetProgressResponse {
+            document: value.document,
+            progress: value.progress,
+            percentage: value.percentage,
+            device: value.device,
+            device_id: value.device_id,
+            timestamp: value.updated_at.timestamp(),
+        }
+    }
+}
+
+#[instrument(skip(state, headers))]
+async fn get_progress_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(document): Path<String>,
+) -> Result<Json<serde_json::Value>, KosyncError> {
+    let client = ReadingProgressClient::new(&state.pool);
+    let user = authorize(&client, &headers).await?;
+    // KOReader expects an empty object for documents it hasn't synced yet.
+    let progress = match client.get_progress(&user.username, &document).await? {
+        Some(x) => serde_json::to_value(GetProgressResponse::from(x)).map_err(ApiError::from)?,
+        None => json!({}),
+    };
+    Ok(progress.into())
+}
+
+/// The subset of the KOReader sync server api used by its progress sync plugin, so devices can
+/// use cereal as their custom sync server.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/users/create", post(create_user_handler))
+        .route("/users/auth", get(auth_handler))
+        .route("/syncs/progress", put(update_progress_handler))
+        .route("/syncs/progress/:document", get(get_progress_handler))
+}
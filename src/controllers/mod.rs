@@ -1,4 +1,17 @@
+pub mod admin;
+pub mod backfills;
 pub mod books;
 pub mod chapters;
+pub mod credentials;
+pub mod deliveries;
+pub mod events;
+pub mod feeds;
+pub mod kosync;
+pub mod shares;
+pub mod status;
 pub mod subscribers;
 pub mod subscriptions;
+pub mod tags;
+pub mod validation;
+pub mod volumes;
+pub mod websub;
@@ -0,0 +1,253 @@
+use std::env;
+
+use axum::{
+    body::StreamBody,
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{
+    config::secret,
+    error::ApiError,
+    models::{ChapterBody, ChapterClient, ShareFormat, ShareLink, ShareLinkClient},
+    tasks::integrity::verify_body_stream,
+    util::STORED_BODY_CSP,
+    AppState,
+};
+
+/// The key share links are signed with, from CEREAL_SHARE_SECRET. Changing it invalidates
+/// every link handed out.
+fn share_secret() -> Result<String, ApiError> {
+    secret("CEREAL_SHARE_SECRET").ok_or_else(|| {
+        ApiError::InvalidRequest(String::from(
+            "Sharing is disabled, set CEREAL_SHARE_SECRET to enable it",
+        ))
+    })
+}
+
+fn mac(secret: &str, id: &Uuid) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(id.as_bytes());
+    mac
+}
+
+fn sign(secret: &str, id: &Uuid) -> String {
+    mac(secret, id)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+fn is_valid_signature(secret: &str, id: &Uuid, signature: &str) -> bool {
+    let bytes: Option<Vec<u8>> = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(signature.get(i..i + 2)?, 16).ok())
+        .collect();
+    match bytes {
+        // Compared in constant time.
+        Some(bytes) => mac(secret, id).verify_slice(&bytes).is_ok(),
+        None => false,
+    }
+}
+
+/// The url of the link, absolute if CEREAL_PUBLIC_URL names where the server is reachable.
+fn share_url(secret: &str, id: &Uuid) -> String {
+    let base = env::var("CEREAL_PUBLIC_URL").unwrap_or_default();
+    format!(
+        "{}/shared/{}?signature={}",
+        base.trim_end_matches('/'),
+        id,
+        sign(secret, id)
+    )
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShareChapterRequest {
+    #[serde(rename = "chapterId")]
+    chapter_id: Uuid,
+    format: ShareFormat,
+    /// Omit for a link which lasts until it is revoked.
+    #[serde(rename = "expiresInSecs")]
+    expires_in_secs: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ShareChapterResponse {
+    link: ShareLink,
+    url: String,
+}
+
+/// Creates a signed link to the chapter which can be handed to someone without access to the
+/// rest of the api.
+#[instrument(skip(state))]
+async fn share_chapter_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ShareChapterRequest>,
+) -> Result<Json<ShareChapterResponse>, ApiError> {
+    let secret = share_secret()?;
+    let expires_at = match request.expires_in_secs {
+        Some(secs) if secs <= 0 => {
+            return Err(ApiError::InvalidRequest(String::from(
+                "expiresInSecs must be greater than zero",
+            )))
+        }
+        Some(secs) => Some(Utc::now() + Duration::seconds(secs)),
+        None => None,
+    };
+    // Shares are public, so chapters in early access can't be shared until they are released.
+    let chapter = ChapterClient::new(&state.pool)
+        .get_chapter_shallow(&request.chapter_id)
+        .await?;
+    if let Some(deliver_after) = chapter.and_then(|x| x.deliver_after) {
+        if deliver_after > Utc::now() {
+            return Err(ApiError::InvalidRequest(format!(
+                "Chapter {} is in early access until {}, so it can't be shared yet",
+                request.chapter_id, deliver_after
+            )));
+        }
+    }
+    let link = ShareLinkClient::new(&state.pool)
+        .create_share_link(&request.chapter_id, request.format, expires_at.as_ref())
+        .await?;
+    let url = share_url(&secret, &link.id);
+    Ok(ShareChapterResponse { link, url }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RevokeShareLinkRequest {
+    id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn revoke_share_link_handler(
+    State(state): State<AppState>,
+    Json(request): Json<RevokeShareLinkRequest>,
+) -> Result<Json<ShareLink>, ApiError> {
+    let link = ShareLinkClient::new(&state.pool)
+        .revoke_share_link(&request.id)
+        .await?;
+    Ok(link.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListShareLinksRequest {
+    #[serde(rename = "chapterId")]
+    chapter_id: Uuid,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ListShareLinksResponse {
+    links: Vec<ShareLink>,
+}
+
+#[instrument(skip(state))]
+async fn list_share_links_handler(
+    State(state): State<AppState>,
+    Query(request): Query<ListShareLinksRequest>,
+) -> Result<Json<ListShareLinksResponse>, ApiError> {
+    let links = ShareLinkClient::new(&state.pool)
+        .list_share_links(&request.chapter_id)
+        .await?;
+    Ok(ListShareLinksResponse { links }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+struct SharedChapterPath {
+    id: Uuid,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SharedChapterQuery {
+    signature: String,
+}
+
+/// Serves a shared chapter. Links which are unsigned, expired or revoked, or whose chapter is
+/// still in early access, are reported as missing so that they reveal nothing about the chapter.
+#[instrument(skip(state, query))]
+async fn shared_chapter_handler(
+    State(state): State<AppState>,
+    Path(path): Path<SharedChapterPath>,
+    Query(query): Query<SharedChapterQuery>,
+) -> Result<Response, ApiError> {
+    let pool = state.pool;
+    let not_found = || ApiError::ResourceNotFound {
+        resource_type: String::from("share link"),
+        id: path.id.to_string(),
+    };
+    let secret = share_secret().map_err(|_| not_found())?;
+    if !is_valid_signature(&secret, &path.id, &query.signature) {
+        return Err(not_found());
+    }
+    let client = ShareLinkClient::new(&pool);
+    let link = match client.get_share_link(&path.id).await? {
+        Some(x) if x.is_active() => x,
+        _ => return Err(not_found()),
+    };
+    let body = match link.format {
+        ShareFormat::Html => ChapterBody::Html,
+        ShareFormat::Epub => ChapterBody::Epub,
+    };
+    let chapter_client = ChapterClient::new(&pool);
+    let chapter = match chapter_client.get_chapter_shallow(&link.chapter_id).await? {
+        Some(x) if x.deliver_after.is_none_or(|x| x <= Utc::now()) => x,
+        _ => return Err(not_found()),
+    };
+    let blob = chapter_client
+        .stream_body(&chapter.id, body)
+        .await?
+        .ok_or_else(|| ApiError::ResourceNotFound {
+            resource_type: format!("chapter {}", link.format.as_str()),
+            id: chapter.id.to_string(),
+        })?;
+    client.record_access(&link.id).await?;
+    let content_type = match body {
+        ChapterBody::Html => String::from("text/html; charset=utf-8"),
+        ChapterBody::Epub => String::from("application/epub+zip"),
+    };
+    let disposition = match body {
+        ChapterBody::Html => String::from("inline"),
+        ChapterBody::Epub => format!(
+            "attachment; filename=\"{}\"",
+            sanitize_filename::sanitize(format!("{}.epub", chapter.title))
+        ),
+    };
+    let length = blob.length;
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                String::from(STORED_BODY_CSP),
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, String::from("nosniff")),
+        ],
+        StreamBody::new(verify_body_stream(&pool, chapter.id, body, blob)),
+    )
+        .into_response())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/shareChapter", post(share_chapter_handler))
+        .route("/revokeShareLink", post(revoke_share_link_handler))
+        .route("/listShareLinks", get(list_share_links_handler))
+        .route("/shared/:id", get(shared_chapter_handler))
+}
@@ -0,0 +1,338 @@
+use std::collections::BTreeMap;
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use serde_json::json;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{
+    config::config,
+    error::ApiError,
+    models::{
+        Backlog, BookClient, BookStorage, ChapterClient, ChapterState, DeliveryClient,
+        StorageClient, SubscriptionClient,
+    },
+    providers::{
+        circuit::{circuit, Circuit, CircuitState},
+        http::{host_stats, HostStats},
+    },
+    tasks::{
+        chapter_body_conversion::{epub_backend, EpubBackend},
+        delivery::{
+            mail_usage, sender_warnings, undeliverable_subscriptions, MailUsage,
+            UndeliverableSubscription,
+        },
+        instance_id,
+        maintenance::{last_maintenance_report, MaintenanceReport},
+    },
+    AppState,
+};
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub(crate) struct StatusResponse {
+    #[serde(rename = "instanceId")]
+    instance_id: &'static str,
+    #[serde(rename = "epubBackend")]
+    epub_backend: EpubBackend,
+    backlog: PipelineBacklog,
+    /// How many chapters are at each stage of the pipeline, including those it gave up on.
+    #[serde(rename = "chapterStates")]
+    chapter_states: BTreeMap<ChapterState, i64>,
+    #[serde(rename = "deliveryLatency")]
+    delivery_latency: DeliveryLatency,
+    /// Subscriptions held back because their subscriber has no usable delivery channel.
+    #[serde(rename = "undeliverableSubscriptions")]
+    undeliverable_subscriptions: Vec<UndeliverableSubscription>,
+    /// Reasons amazon may silently drop kindle emails from the configured from address.
+    #[serde(rename = "kindleSenderWarnings")]
+    kindle_sender_warnings: Vec<String>,
+    /// Messages mailgun sent this month, against the quota in the config.
+    #[serde(rename = "mailgunUsage")]
+    mailgun_usage: MailUsage,
+    /// The last database maintenance run, by any instance.
+    maintenance: Option<MaintenanceReport>,
+}
+
+/// The chapters waiting at each stage of the pipeline. A growing backlog, or an old oldest
+/// chapter, shows which stage is falling behind.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct PipelineBacklog {
+    hydration: Backlog,
+    conversion: Backlog,
+    delivery: Backlog,
+}
+
+/// Time from discovery to delivery of the chapters delivered within the window.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct DeliveryLatency {
+    #[serde(rename = "windowHours")]
+    window_hours: i64,
+    deliveries: usize,
+    #[serde(rename = "medianSecs")]
+    median_secs: Option<i64>,
+    #[serde(rename = "p95Secs")]
+    p95_secs: Option<i64>,
+    #[serde(rename = "maxSecs")]
+    max_secs: Option<i64>,
+}
+
+const DELIVERY_LATENCY_WINDOW_HOURS: i64 = 24;
+
+#[instrument(skip(state))]
+async fn status_handler(State(state): State<AppState>) -> Result<Json<StatusResponse>, ApiError> {
+    if let Some(status) = state.cache.status().get(&()).await {
+        return Ok(status.into());
+    }
+    let chapter_client = ChapterClient::new(&state.pool);
+    let backlog = PipelineBacklog {
+        hydration: chapter_client.hydration_backlog().await?,
+        conversion: chapter_client.conversion_backlog().await?,
+        delivery: SubscriptionClient::new(&state.pool)
+            .delivery_backlog()
+            .await?,
+    };
+
+    let since = Utc::now() - Duration::hours(DELIVERY_LATENCY_WINDOW_HOURS);
+    let mut latencies: Vec<i64> = DeliveryClient::new(&state.pool)
+        .list_deliveries_since(&since)
+        .await?
+        .iter()
+        .map(|x| x.latency().num_seconds())
+        .collect();
+    latencies.sort_unstable();
+    let percentile = |p: usize| {
+        let last = latencies.len().checked_sub(1)?;
+        latencies.get(last * p / 100).copied()
+    };
+    let delivery_latency = DeliveryLatency {
+        window_hours: DELIVERY_LATENCY_WINDOW_HOURS,
+        deliveries: latencies.len(),
+        median_secs: percentile(50),
+        p95_secs: percentile(95),
+        max_secs: latencies.last().copied(),
+    };
+
+    let status = StatusResponse {
+        instance_id: instance_id(),
+        epub_backend: epub_backend().await.clone(),
+        backlog,
+        chapter_states: chapter_client.count_by_state().await?,
+        delivery_latency,
+        undeliverable_subscriptions: undeliverable_subscriptions(&state.pool).await?,
+        kindle_sender_warnings: sender_warnings(),
+        mailgun_usage: mail_usage(&state.pool).await?,
+        maintenance: last_maintenance_report(&state.pool).await?,
+    };
+    state.cache.status().insert((), status.clone()).await;
+    Ok(status.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct StorageStatsResponse {
+    #[serde(rename = "databaseBytes")]
+    database_bytes: i64,
+    /// Space inside the database file which a vacuum would return.
+    #[serde(rename = "freeBytes")]
+    free_bytes: i64,
+    #[serde(rename = "htmlBytes")]
+    html_bytes: i64,
+    #[serde(rename = "epubBytes")]
+    epub_bytes: i64,
+    #[serde(rename = "artifactBytes")]
+    artifact_bytes: i64,
+    books: Vec<BookStorage>,
+    /// Chapter bytes stored per day over the growth window.
+    #[serde(rename = "dailyGrowthBytes")]
+    daily_growth_bytes: i64,
+    /// The database size in 30 days if growth continues at the current rate.
+    #[serde(rename = "projectedBytes30Days")]
+    projected_bytes_30_days: i64,
+    #[serde(rename = "quotaBytes")]
+    quota_bytes: Option<i64>,
+    warnings: Vec<String>,
+}
+
+const STORAGE_GROWTH_WINDOW_DAYS: i64 = 30;
+
+/// Warn once this share of the quota is used.
+const STORAGE_QUOTA_WARNING_RATIO: f64 = 0.8;
+
+#[instrument(skip(state))]
+async fn storage_stats_handler(
+    State(state): State<AppState>,
+) -> Result<Json<StorageStatsResponse>, ApiError> {
+    let client = StorageClient::new(&state.pool);
+    let books = client.list_book_storage().await?;
+    let (database_bytes, free_bytes) = client.database_bytes().await?;
+    let since = Utc::now() - Duration::days(STORAGE_GROWTH_WINDOW_DAYS);
+    let daily_growth_bytes = client.chapter_bytes_since(&since).await? / STORAGE_GROWTH_WINDOW_DAYS;
+    let projected_bytes_30_days = database_bytes + daily_growth_bytes * 30;
+
+    let quota_bytes = config().storage_quota_bytes;
+    let mut warnings = Vec::new();
+    if let Some(quota) = quota_bytes {
+        if database_bytes >= quota {
+            warnings.push(format!(
+                "The database uses {} bytes, over the quota of {} bytes",
+                database_bytes, quota
+            ));
+        } else if database_bytes as f64 >= quota as f64 * STORAGE_QUOTA_WARNING_RATIO {
+            warnings.push(format!(
+                "The database uses {} bytes, {:.0}% of the quota of {} bytes",
+                database_bytes,
+                database_bytes as f64 * 100.0 / quota as f64,
+                quota
+            ));
+        }
+        if database_bytes < quota && daily_growth_bytes > 0 {
+            let days_left = (quota - database_bytes) / daily_growth_bytes;
+            if days_left <= 30 {
+                warnings.push(format!(
+                    "At the current rate the quota will be reached in {} days",
+                    days_left
+                ));
+            }
+        }
+    }
+
+    Ok(StorageStatsResponse {
+        database_bytes,
+        free_bytes,
+        html_bytes: books.iter().map(|x| x.html_bytes).sum(),
+        epub_bytes: books.iter().map(|x| x.epub_bytes).sum(),
+        artifact_bytes: books.iter().map(|x| x.artifact_bytes).sum(),
+        books,
+        daily_growth_bytes,
+        projected_bytes_30_days,
+        quota_bytes,
+        warnings,
+    }
+    .into())
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ScrapingStatsResponse {
+    /// Stats are kept by each instance, from when it started.
+    #[serde(rename = "instanceId")]
+    instance_id: &'static str,
+    hosts: Vec<HostStats>,
+}
+
+#[instrument]
+async fn scraping_stats_handler() -> Json<ScrapingStatsResponse> {
+    ScrapingStatsResponse {
+        instance_id: instance_id(),
+        hosts: host_stats(),
+    }
+    .into()
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ProviderHealthResponse {
+    /// Whether every provider's circuit is closed.
+    healthy: bool,
+    /// Circuits are kept by each instance, from when it started.
+    #[serde(rename = "instanceId")]
+    instance_id: &'static str,
+    providers: Vec<ProviderHealth>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ProviderHealth {
+    provider: &'static str,
+    healthy: bool,
+    circuit: Circuit,
+    books: Vec<BookDiscoveryHealth>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct BookDiscoveryHealth {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+    title: String,
+    #[serde(rename = "lastDiscoveredAt")]
+    last_discovered_at: Option<DateTime<Utc>>,
+    #[serde(rename = "discoveryFailures")]
+    discovery_failures: i64,
+    #[serde(rename = "lastDiscoveryError")]
+    last_discovery_error: Option<String>,
+}
+
+/// Summarizes discovery from each provider which books are fetched from, for people and
+/// external monitors. Books with a public source are listed under both of their providers.
+#[instrument(skip(state))]
+async fn provider_health_handler(
+    State(state): State<AppState>,
+) -> Result<Json<ProviderHealthResponse>, ApiError> {
+    let books = BookClient::new(&state.pool).list_books().await?;
+    let mut providers: BTreeMap<&'static str, Vec<BookDiscoveryHealth>> = BTreeMap::new();
+    for book in books
+        .iter()
+        .filter(|x| x.canonical_book_id.is_none() && x.archived_at.is_none())
+    {
+        let health = BookDiscoveryHealth {
+            book_id: book.id,
+            title: book.title.clone(),
+            last_discovered_at: book.last_discovered_at,
+            discovery_failures: book.discovery_failures,
+            last_discovery_error: book.last_discovery_error.clone(),
+        };
+        if let Some(public) = &book.public_metadata {
+            providers
+                .entry(public.provider_name())
+                .or_default()
+                .push(health.clone());
+        }
+        providers
+            .entry(book.metadata.provider_name())
+            .or_default()
+            .push(health);
+    }
+    let providers: Vec<ProviderHealth> = providers
+        .into_iter()
+        .map(|(provider, books)| {
+            let circuit = circuit(provider);
+            ProviderHealth {
+                provider,
+                healthy: circuit.state == CircuitState::Closed,
+                circuit,
+                books,
+            }
+        })
+        .collect();
+    Ok(ProviderHealthResponse {
+        healthy: providers.iter().all(|x| x.healthy),
+        instance_id: instance_id(),
+        providers,
+    }
+    .into())
+}
+
+/// Ready when the database is reachable and chapters can be converted to epubs.
+#[instrument(skip(state))]
+async fn readyz_handler(State(state): State<AppState>) -> (StatusCode, Json<serde_json::Value>) {
+    if let Err(e) = sqlx::query("SELECT 1").execute(&state.pool).await {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({ "error": format!("Database unavailable: {}", e) }).into(),
+        );
+    }
+    if let EpubBackend::Unavailable { reason } = epub_backend().await {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({ "error": format!("Epub conversion unavailable: {}", reason) }).into(),
+        );
+    }
+    (StatusCode::OK, json!({}).into())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/status", get(status_handler))
+        .route("/readyz", get(readyz_handler))
+        .route("/storageStats", get(storage_stats_handler))
+        .route("/scrapingStats", get(scraping_stats_handler))
+        .route("/providerHealth", get(provider_health_handler))
+}
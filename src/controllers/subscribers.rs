@@ -1,9 +1,14 @@
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, HashMap},
+};
+
 use axum::{
     extract::{Query, State},
     routing::{delete, get, post},
     Json, Router,
 };
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tracing::instrument;
@@ -11,25 +16,123 @@ use uuid::Uuid;
 
 use crate::{
     error::ApiError,
-    models::{Subscriber, SubscriberClient},
+    models::{
+        AnthologySubscriptionClient, BookClient, ChapterClient, DeliveryAttempt, DeliveryClient,
+        ReadLaterService, Subscriber, SubscriberClient, SubscriptionClient, SubscriptionDefaults,
+        WildcardSubscriptionClient,
+    },
+    tasks::{
+        delivery::{
+            channel_health, delivery_decision, is_kindle_address, recipients, ChannelHealth,
+            DeliveryDecision,
+        },
+        schedule::{get_schedule, TaskLoop},
+    },
     AppState,
 };
 
-#[derive(Debug, PartialEq, Clone, Deserialize)]
+use super::validation::{ValidJson, Validate, Validator};
+
+#[derive(PartialEq, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct CreateSubscriberRequest {
     name: String,
     #[serde(rename = "kindleEmail")]
     kindle_email: Option<String>,
+    email: Option<String>,
     #[serde(rename = "pushoverKey")]
     pushover_key: Option<String>,
+    #[serde(rename = "readLaterService")]
+    read_later_service: Option<ReadLaterService>,
+    #[serde(rename = "readLaterToken")]
+    read_later_token: Option<String>,
+    #[serde(rename = "notifyUrl")]
+    notify_url: Option<String>,
+    #[serde(rename = "subscriptionDefaults", default)]
+    subscription_defaults: SubscriptionDefaults,
+}
+
+impl std::fmt::Debug for CreateSubscriberRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CreateSubscriberRequest")
+            .field("name", &self.name)
+            .field("kindle_email", &self.kindle_email)
+            .field("email", &self.email)
+            .field("pushover_key", &self.pushover_key)
+            .field("read_later_service", &self.read_later_service)
+            .field("read_later_token_set", &self.read_later_token.is_some())
+            .field("notify_url", &self.notify_url)
+            .field("subscription_defaults", &self.subscription_defaults)
+            .finish()
+    }
+}
+
+impl Validate for CreateSubscriberRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validate_contact_fields(
+            validator,
+            self.kindle_email.as_deref(),
+            self.email.as_deref(),
+            self.notify_url.as_deref(),
+        );
+        validator.non_empty("name", Some(&self.name));
+        validator.chunk_size(
+            "subscriptionDefaults.chunkSize",
+            self.subscription_defaults.chunk_size,
+        );
+    }
+}
+
+/// Rejects kindle emails amazon wouldn't deliver to a device, which would otherwise only show
+/// up as silently missing chapters. Blank fields clear them, so are allowed.
+fn validate_contact_fields(
+    validator: &mut Validator,
+    kindle_email: Option<&str>,
+    email: Option<&str>,
+    notify_url: Option<&str>,
+) {
+    if let Some(x) = kindle_email.filter(|x| !x.trim().is_empty()) {
+        validator.check("kindleEmail", is_kindle_address(x), || {
+            format!("{:?} must be a @kindle.com or @free.kindle.com address", x)
+        });
+    }
+    validator.email("email", email);
+    // Apprise urls name their service by scheme, as in `signal://...`.
+    if let Some(x) = notify_url.filter(|x| !x.trim().is_empty()) {
+        validator.check("notifyUrl", x.contains("://"), || {
+            format!("{:?} is not an apprise url", x)
+        });
+    }
+}
+
+/// Pairs the read-later service with its token, which must be set together.
+fn read_later(
+    service: Option<ReadLaterService>,
+    token: Option<&str>,
+) -> Result<Option<(ReadLaterService, &str)>, ApiError> {
+    match (service, token.map(str::trim)) {
+        (None, None) => Ok(None),
+        (Some(ReadLaterService::Instapaper), Some(x)) if !x.contains(':') => {
+            Err(ApiError::InvalidRequest(String::from(
+                "readLaterToken must be username:password for instapaper",
+            )))
+        }
+        (Some(service), Some(x)) if !x.is_empty() => Ok(Some((service, x))),
+        _ => Err(ApiError::InvalidRequest(String::from(
+            "readLaterService and readLaterToken must be set together",
+        ))),
+    }
 }
 
 #[instrument(skip(state))]
 async fn create_subscriber_handler(
     State(state): State<AppState>,
-    Json(request): Json<CreateSubscriberRequest>,
+    ValidJson(request): ValidJson<CreateSubscriberRequest>,
 ) -> Result<Json<Subscriber>, ApiError> {
+    let read_later = read_later(
+        request.read_later_service,
+        request.read_later_token.as_deref(),
+    )?;
     let pool = state.pool;
     let client = SubscriberClient::new(&pool);
     let subscriber = client
@@ -37,20 +140,68 @@ async fn create_subscriber_handler(
             &request.name,
             request.pushover_key.as_deref(),
             request.kindle_email.as_deref(),
+            request.email.as_deref(),
+            read_later,
+            request.notify_url.as_deref(),
+            &request.subscription_defaults,
         )
         .await?;
     Ok(subscriber.into())
 }
 
-#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[derive(PartialEq, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct UpdateSubscriberRequest {
     id: Uuid,
     name: Option<String>,
     #[serde(rename = "kindleEmail")]
     kindle_email: Option<String>,
+    email: Option<String>,
     #[serde(rename = "pushoverKey")]
     pushover_key: Option<String>,
+    #[serde(rename = "readLaterService")]
+    read_later_service: Option<ReadLaterService>,
+    #[serde(rename = "readLaterToken")]
+    read_later_token: Option<String>,
+    #[serde(rename = "notifyUrl")]
+    notify_url: Option<String>,
+    /// Only the defaults which are set are changed.
+    #[serde(rename = "subscriptionDefaults")]
+    subscription_defaults: Option<SubscriptionDefaults>,
+}
+
+impl std::fmt::Debug for UpdateSubscriberRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpdateSubscriberRequest")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("kindle_email", &self.kindle_email)
+            .field("email", &self.email)
+            .field("pushover_key", &self.pushover_key)
+            .field("read_later_service", &self.read_later_service)
+            .field("read_later_token_set", &self.read_later_token.is_some())
+            .field("notify_url", &self.notify_url)
+            .field("subscription_defaults", &self.subscription_defaults)
+            .finish()
+    }
+}
+
+impl Validate for UpdateSubscriberRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validate_contact_fields(
+            validator,
+            self.kindle_email.as_deref(),
+            self.email.as_deref(),
+            self.notify_url.as_deref(),
+        );
+        validator.non_empty("name", self.name.as_deref());
+        validator.chunk_size(
+            "subscriptionDefaults.chunkSize",
+            self.subscription_defaults
+                .as_ref()
+                .and_then(|x| x.chunk_size),
+        );
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize)]
@@ -64,14 +215,29 @@ struct UpdateSubscriberResponse {
     #[serde(rename = "kindleEmail")]
     #[serde(skip_serializing_if = "Option::is_none")]
     kindle_email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(rename = "readLaterService")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_later_service: Option<ReadLaterService>,
+    #[serde(rename = "notifyUrl")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notify_url: Option<String>,
+    #[serde(rename = "subscriptionDefaults")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subscription_defaults: Option<SubscriptionDefaults>,
     updated_at: chrono::DateTime<Utc>,
 }
 
 #[instrument(skip(state))]
 async fn update_subscriber_handler(
     State(state): State<AppState>,
-    Json(request): Json<UpdateSubscriberRequest>,
+    ValidJson(request): ValidJson<UpdateSubscriberRequest>,
 ) -> Result<Json<UpdateSubscriberResponse>, ApiError> {
+    let read_later = read_later(
+        request.read_later_service,
+        request.read_later_token.as_deref(),
+    )?;
     let pool = state.pool;
     let client = SubscriberClient::new(&pool);
     let subscriber = client
@@ -79,7 +245,11 @@ async fn update_subscriber_handler(
             &request.id,
             request.name.as_deref(),
             request.kindle_email.as_deref(),
+            request.email.as_deref(),
             request.pushover_key.as_deref(),
+            read_later,
+            request.notify_url.as_deref(),
+            request.subscription_defaults.as_ref(),
         )
         .await?;
     Ok(UpdateSubscriberResponse {
@@ -87,6 +257,12 @@ async fn update_subscriber_handler(
         name: request.name,
         pushover_key: request.pushover_key,
         kindle_email: request.kindle_email,
+        email: request.email,
+        read_later_service: request.read_later_service,
+        notify_url: request.notify_url,
+        subscription_defaults: request
+            .subscription_defaults
+            .map(|_| subscriber.subscription_defaults),
         updated_at: subscriber.updated_at,
     }
     .into())
@@ -115,6 +291,418 @@ async fn get_subscriber_handler(
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubscriberOverviewRequest {
+    id: Uuid,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct SubscriberOverviewResponse {
+    subscriber: Subscriber,
+    subscriptions: Vec<SubscriptionOverview>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct SubscriptionOverview {
+    #[serde(rename = "subscriptionId")]
+    subscription_id: Uuid,
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+    #[serde(rename = "bookTitle")]
+    book_title: String,
+    /// Chapters discovered since the last delivery, whether or not they are ready to send.
+    #[serde(rename = "chaptersBehind")]
+    chapters_behind: i64,
+    /// Whether the next delivery run would deliver, or None if nothing is ready to send.
+    #[serde(rename = "nextDelivery")]
+    next_delivery: Option<DeliveryDecision>,
+    /// When the next delivery is expected by, if one is due.
+    #[serde(rename = "nextDeliveryExpectedBy")]
+    next_delivery_expected_by: Option<DateTime<Utc>>,
+    #[serde(rename = "lastAttempt")]
+    last_attempt: Option<DeliveryAttempt>,
+    #[serde(rename = "channelHealth")]
+    channel_health: ChannelHealth,
+}
+
+#[instrument(skip(state))]
+async fn subscriber_overview_handler(
+    State(state): State<AppState>,
+    Query(request): Query<SubscriberOverviewRequest>,
+) -> Result<Json<SubscriberOverviewResponse>, ApiError> {
+    let pool = state.pool;
+    let subscriber = SubscriberClient::new(&pool)
+        .get_subscriber(request.id)
+        .await?
+        .ok_or_else(|| ApiError::ResourceNotFound {
+            resource_type: String::from("subscriber"),
+            id: request.id.to_string(),
+        })?;
+    let book_client = BookClient::new(&pool);
+    let chapter_client = ChapterClient::new(&pool);
+    let delivery_client = DeliveryClient::new(&pool);
+    let delivery_interval =
+        Duration::seconds(get_schedule(&pool, TaskLoop::Delivery).await?.interval_secs as i64);
+
+    let recipients = recipients(&pool, &subscriber).await?;
+
+    let mut subscriptions = Vec::new();
+    for subscription in SubscriptionClient::new(&pool)
+        .list_subscriptions(&subscriber.id)
+        .await?
+    {
+        let book_title = book_client
+            .get_book(&subscription.book_id)
+            .await?
+            .map(|x| x.title)
+            .unwrap_or_default();
+        let chapters_behind = chapter_client
+            .count_chapters_after(
+                &subscription.book_id,
+                subscription.last_delivered_chapter_created_at.as_ref(),
+            )
+            .await?;
+        let next_delivery = delivery_decision(&pool, &subscriber, &subscription).await?;
+        let next_delivery_expected_by = match &next_delivery {
+            Some(DeliveryDecision::Ready) => Some(Utc::now() + delivery_interval),
+            Some(DeliveryDecision::RetryBackoff { retry_at, .. }) => {
+                Some(*retry_at + delivery_interval)
+            }
+            Some(DeliveryDecision::Embargoed { deliver_after }) => {
+                Some(*deliver_after + delivery_interval)
+            }
+            Some(DeliveryDecision::Paced { next_at })
+            | Some(DeliveryDecision::MailQuota { next_at, .. }) => {
+                Some(*next_at + delivery_interval)
+            }
+            _ => None,
+        };
+        subscriptions.push(SubscriptionOverview {
+            subscription_id: subscription.id,
+            book_id: subscription.book_id,
+            book_title,
+            chapters_behind,
+            next_delivery,
+            next_delivery_expected_by,
+            last_attempt: delivery_client.latest_attempt(&subscription.id).await?,
+            channel_health: channel_health(&subscriber, &recipients),
+        });
+    }
+
+    Ok(SubscriberOverviewResponse {
+        subscriber,
+        subscriptions,
+    }
+    .into())
+}
+
+/// How many past entries /subscriberActivity returns unless asked for another limit.
+const DEFAULT_ACTIVITY_LIMIT: i64 = 100;
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubscriberActivityRequest {
+    id: Uuid,
+    limit: Option<i64>,
+}
+
+/// Something which happened, or is scheduled to happen, for a subscriber. No history of
+/// subscription changes is kept, so a subscription shows when it was created and when it was
+/// last changed.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+enum ActivityEvent {
+    DeliveryAttempted {
+        #[serde(rename = "subscriptionId")]
+        subscription_id: Uuid,
+        #[serde(rename = "bookTitle")]
+        book_title: Option<String>,
+        chapters: usize,
+        attempt: i64,
+        succeeded: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    SubscriptionCreated {
+        #[serde(rename = "subscriptionId")]
+        subscription_id: Uuid,
+        #[serde(rename = "bookTitle")]
+        book_title: Option<String>,
+    },
+    SubscriptionUpdated {
+        #[serde(rename = "subscriptionId")]
+        subscription_id: Uuid,
+        #[serde(rename = "bookTitle")]
+        book_title: Option<String>,
+    },
+    WildcardSubscriptionCreated {
+        #[serde(rename = "wildcardSubscriptionId")]
+        wildcard_subscription_id: Uuid,
+    },
+    WildcardSubscriptionUpdated {
+        #[serde(rename = "wildcardSubscriptionId")]
+        wildcard_subscription_id: Uuid,
+    },
+    AnthologySubscriptionCreated {
+        #[serde(rename = "anthologySubscriptionId")]
+        anthology_subscription_id: Uuid,
+        name: String,
+    },
+    AnthologySubscriptionUpdated {
+        #[serde(rename = "anthologySubscriptionId")]
+        anthology_subscription_id: Uuid,
+        name: String,
+    },
+    /// The anthology's next digest, which is the only activity in the future.
+    DigestScheduled {
+        #[serde(rename = "anthologySubscriptionId")]
+        anthology_subscription_id: Uuid,
+        name: String,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ActivityEntry {
+    at: DateTime<Utc>,
+    #[serde(flatten)]
+    event: ActivityEvent,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct SubscriberActivityResponse {
+    activity: Vec<ActivityEntry>,
+}
+
+/// Creation and, if it has changed since, the latest change of a subscription.
+fn lifecycle(
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    event: impl Fn(bool) -> ActivityEvent,
+) -> Vec<ActivityEntry> {
+    let mut entries = vec![ActivityEntry {
+        at: created_at,
+        event: event(false),
+    }];
+    if updated_at > created_at {
+        entries.push(ActivityEntry {
+            at: updated_at,
+            event: event(true),
+        });
+    }
+    entries
+}
+
+/// Merges the subscriber's delivery attempts, subscription changes and upcoming digests into
+/// one feed, latest first. Upcoming digests are always included, and past activity is limited
+/// to the most recent `limit` entries.
+#[instrument(skip(state))]
+async fn subscriber_activity_handler(
+    State(state): State<AppState>,
+    Query(request): Query<SubscriberActivityRequest>,
+) -> Result<Json<SubscriberActivityResponse>, ApiError> {
+    let pool = state.pool;
+    let subscriber = SubscriberClient::new(&pool)
+        .get_subscriber(request.id)
+        .await?
+        .ok_or_else(|| ApiError::ResourceNotFound {
+            resource_type: String::from("subscriber"),
+            id: request.id.to_string(),
+        })?;
+    let limit = request.limit.unwrap_or(DEFAULT_ACTIVITY_LIMIT).max(0);
+    let book_client = BookClient::new(&pool);
+
+    let subscriptions = SubscriptionClient::new(&pool)
+        .list_subscriptions(&subscriber.id)
+        .await?;
+    let mut book_titles = HashMap::new();
+    for subscription in &subscriptions {
+        if let Entry::Vacant(entry) = book_titles.entry(subscription.book_id) {
+            let book = book_client.get_book(&subscription.book_id).await?;
+            entry.insert(book.map(|x| x.title));
+        }
+    }
+    let book_title = |subscription_id: &Uuid| {
+        subscriptions
+            .iter()
+            .find(|x| x.id == *subscription_id)
+            .and_then(|x| book_titles.get(&x.book_id).cloned().flatten())
+    };
+
+    let mut past = Vec::new();
+    for attempt in DeliveryClient::new(&pool)
+        .list_subscriber_attempts(&subscriber.id, limit)
+        .await?
+    {
+        past.push(ActivityEntry {
+            at: attempt.attempted_at,
+            event: ActivityEvent::DeliveryAttempted {
+                subscription_id: attempt.subscription_id,
+                book_title: book_title(&attempt.subscription_id),
+                chapters: attempt.chapter_ids.len(),
+                attempt: attempt.attempt,
+                succeeded: attempt.succeeded,
+                error: attempt.error,
+            },
+        });
+    }
+    for subscription in &subscriptions {
+        past.extend(lifecycle(
+            subscription.created_at,
+            subscription.updated_at,
+            |updated| {
+                let (subscription_id, book_title) = (subscription.id, book_title(&subscription.id));
+                match updated {
+                    false => ActivityEvent::SubscriptionCreated {
+                        subscription_id,
+                        book_title,
+                    },
+                    true => ActivityEvent::SubscriptionUpdated {
+                        subscription_id,
+                        book_title,
+                    },
+                }
+            },
+        ));
+    }
+    for wildcard in WildcardSubscriptionClient::new(&pool)
+        .list_wildcard_subscriptions(Some(&subscriber.id))
+        .await?
+    {
+        past.extend(lifecycle(
+            wildcard.created_at,
+            wildcard.updated_at,
+            |updated| {
+                let wildcard_subscription_id = wildcard.id;
+                match updated {
+                    false => ActivityEvent::WildcardSubscriptionCreated {
+                        wildcard_subscription_id,
+                    },
+                    true => ActivityEvent::WildcardSubscriptionUpdated {
+                        wildcard_subscription_id,
+                    },
+                }
+            },
+        ));
+    }
+    let mut upcoming = Vec::new();
+    for anthology in AnthologySubscriptionClient::new(&pool)
+        .list_anthology_subscriptions(Some(&subscriber.id))
+        .await?
+    {
+        past.extend(lifecycle(
+            anthology.created_at,
+            anthology.updated_at,
+            |updated| {
+                let (anthology_subscription_id, name) = (anthology.id, anthology.name.clone());
+                match updated {
+                    false => ActivityEvent::AnthologySubscriptionCreated {
+                        anthology_subscription_id,
+                        name,
+                    },
+                    true => ActivityEvent::AnthologySubscriptionUpdated {
+                        anthology_subscription_id,
+                        name,
+                    },
+                }
+            },
+        ));
+        upcoming.push(ActivityEntry {
+            at: anthology.due_at(),
+            event: ActivityEvent::DigestScheduled {
+                anthology_subscription_id: anthology.id,
+                name: anthology.name,
+            },
+        });
+    }
+
+    past.sort_by_key(|x| Reverse(x.at));
+    past.truncate(limit as usize);
+    upcoming.sort_by_key(|x| Reverse(x.at));
+    upcoming.extend(past);
+    Ok(SubscriberActivityResponse { activity: upcoming }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GroupMemberRequest {
+    #[serde(rename = "groupId")]
+    group_id: Uuid,
+    #[serde(rename = "subscriberId")]
+    subscriber_id: Uuid,
+}
+
+/// Makes a subscriber a member of a group, which is any other subscriber. Deliveries to the
+/// group are then sent to each member rather than to the group's own channels. Groups can't
+/// be nested.
+#[instrument(skip(state))]
+async fn add_group_member_handler(
+    State(state): State<AppState>,
+    Json(request): Json<GroupMemberRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if request.group_id == request.subscriber_id {
+        return Err(ApiError::InvalidRequest(String::from(
+            "A subscriber can't be a member of itself.",
+        )));
+    }
+    let pool = state.pool;
+    let client = SubscriberClient::new(&pool);
+    let memberships = client.list_group_memberships().await?;
+    if memberships
+        .iter()
+        .any(|(group_id, _)| *group_id == request.subscriber_id)
+    {
+        return Err(ApiError::InvalidRequest(format!(
+            "Subscriber {} is a group, and groups can't be members of groups.",
+            request.subscriber_id
+        )));
+    }
+    if memberships
+        .iter()
+        .any(|(_, member_id)| *member_id == request.group_id)
+    {
+        return Err(ApiError::InvalidRequest(format!(
+            "Subscriber {} is a member of a group, and groups can't be members of groups.",
+            request.group_id
+        )));
+    }
+    client
+        .add_group_member(&request.group_id, &request.subscriber_id)
+        .await?;
+    Ok(json!({}).into())
+}
+
+#[instrument(skip(state))]
+async fn remove_group_member_handler(
+    State(state): State<AppState>,
+    Json(request): Json<GroupMemberRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let pool = state.pool;
+    let client = SubscriberClient::new(&pool);
+    client
+        .remove_group_member(&request.group_id, &request.subscriber_id)
+        .await?;
+    Ok(json!({}).into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListGroupMembersRequest {
+    #[serde(rename = "groupId")]
+    group_id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn list_group_members_handler(
+    State(state): State<AppState>,
+    Query(request): Query<ListGroupMembersRequest>,
+) -> Result<Json<ListSubscribersResult>, ApiError> {
+    let pool = state.pool;
+    let client = SubscriberClient::new(&pool);
+    let subscribers = client.list_group_members(&request.group_id).await?;
+    Ok(ListSubscribersResult { subscribers }.into())
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize)]
 struct ListSubscribersResult {
     subscribers: Vec<Subscriber>,
@@ -147,11 +735,42 @@ async fn delete_subscriber_handler(
     Ok(json!({}).into())
 }
 
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResumeSubscriberRequest {
+    id: Uuid,
+}
+
+/// Resumes deliveries to a subscriber paused after repeated delivery failures, once whatever
+/// was failing has been fixed.
+#[instrument(skip(state))]
+async fn resume_subscriber_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ResumeSubscriberRequest>,
+) -> Result<Json<Subscriber>, ApiError> {
+    let pool = state.pool;
+    let client = SubscriberClient::new(&pool);
+    let subscriber = client.resume_subscriber(&request.id).await?;
+    Ok(subscriber.into())
+}
+
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/createSubscriber", post(create_subscriber_handler))
         .route("/updateSubscriber", post(update_subscriber_handler))
         .route("/getSubscriber", get(get_subscriber_handler))
         .route("/listSubscribers", get(list_subscribers_handler))
+        .route("/subscriberOverview", get(subscriber_overview_handler))
+        .route("/subscriberActivity", get(subscriber_activity_handler))
+        .route("/addSubscriberGroupMember", post(add_group_member_handler))
+        .route(
+            "/removeSubscriberGroupMember",
+            delete(remove_group_member_handler),
+        )
+        .route(
+            "/listSubscriberGroupMembers",
+            get(list_group_members_handler),
+        )
         .route("/deleteSubscriber", delete(delete_subscriber_handler))
+        .route("/resumeSubscriber", post(resume_subscriber_handler))
 }
@@ -3,46 +3,165 @@ use axum::{
     routing::{delete, get, post},
     Json, Router,
 };
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tracing::instrument;
+use sqlx::{Pool, Sqlite};
+use tracing::{info, instrument};
 use uuid::Uuid;
 
 use crate::{
     error::ApiError,
-    models::{ChapterClient, Subscription, SubscriptionClient},
+    models::{
+        AnthologySubscription, AnthologySubscriptionClient, Book, BookClient, BookMetadata,
+        ChapterClient, ConversionOptions, DeliveryFormat, DeliveryTemplates, PendingSubscription,
+        RoyalRoadOptions, Subscription, SubscriptionClient, WildcardSubscription,
+        WildcardSubscriptionClient,
+    },
+    providers::{get_fiction_details, parse_fiction_url},
+    tasks::delivery::{sync_anthology_subscriptions, sync_wildcard_subscriptions},
     AppState,
 };
 
+use super::validation::{ValidJson, Validate, Validator};
+
 #[derive(Debug, PartialEq, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct CreateSubscriptionRequest {
     #[serde(rename = "subscriberId")]
     subscriber_id: Uuid,
+    /// The book is named by exactly one of its id, its royalroad url or its title.
     #[serde(rename = "bookId")]
-    book_id: Uuid,
+    book_id: Option<Uuid>,
+    #[serde(rename = "bookUrl")]
+    book_url: Option<String>,
+    #[serde(rename = "bookTitle")]
+    book_title: Option<String>,
+    /// Create the book when no book fetches the serial at `bookUrl` yet.
+    #[serde(rename = "createBook", default)]
+    create_book: bool,
+    /// Unset chunk size and early access fall back to the subscriber's defaults.
     #[serde(rename = "chunkSize")]
     chunk_size: Option<i32>,
+    #[serde(rename = "earlyAccess")]
+    early_access: Option<bool>,
+    #[serde(default)]
+    format: DeliveryFormat,
     #[serde(rename = "lastDeliveredChapterId")]
     last_delivered_chapter_id: Option<Uuid>,
 }
 
+impl Validate for CreateSubscriptionRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.url("bookUrl", self.book_url.as_deref());
+        validator.non_empty("bookTitle", self.book_title.as_deref());
+        validator.chunk_size("chunkSize", self.chunk_size);
+    }
+}
+
+/// Finds the book the subscription request names, creating it from its royalroad url if asked.
+async fn resolve_book(
+    pool: &Pool<Sqlite>,
+    request: &CreateSubscriptionRequest,
+) -> Result<Book, ApiError> {
+    let client = BookClient::new(pool);
+    match (&request.book_id, &request.book_url, &request.book_title) {
+        (Some(book_id), None, None) => {
+            client
+                .get_book(book_id)
+                .await?
+                .ok_or_else(|| ApiError::ResourceNotFound {
+                    resource_type: String::from("book"),
+                    id: book_id.to_string(),
+                })
+        }
+        (None, Some(url), None) => {
+            let royalroad_book_id = parse_fiction_url(url).ok_or_else(|| {
+                ApiError::InvalidRequest(format!("{} is not a royalroad fiction url", url))
+            })?;
+            let metadata = BookMetadata::RoyalRoad {
+                book_id: royalroad_book_id,
+                options: RoyalRoadOptions::default(),
+            };
+            let existing = client
+                .get_book_by_provider_identity(&metadata.provider_identity())
+                .await?;
+            match existing {
+                Some(book) => Ok(book),
+                None if request.create_book => {
+                    let (title, author) = get_fiction_details(royalroad_book_id)
+                        .await
+                        .map_err(|e| ApiError::InvalidMetadata(format!("{:#}", e)))?;
+                    if let Err(e) = metadata.chapter_provider().validate().await {
+                        return Err(ApiError::InvalidMetadata(format!("{:#}", e)));
+                    }
+                    info!("Creating book {} for a subscription to {}", title, url);
+                    client
+                        .create_book(
+                            &title,
+                            &author,
+                            &metadata,
+                            &ConversionOptions::default(),
+                            &DeliveryTemplates::default(),
+                            false,
+                        )
+                        .await
+                }
+                None => Err(ApiError::ResourceNotFound {
+                    resource_type: String::from("book"),
+                    id: url.clone(),
+                }),
+            }
+        }
+        (None, None, Some(title)) => {
+            let mut books = client.find_books_by_title(title).await?;
+            match books.len() {
+                0 => Err(ApiError::ResourceNotFound {
+                    resource_type: String::from("book"),
+                    id: title.clone(),
+                }),
+                1 => Ok(books.remove(0)),
+                _ => Err(ApiError::InvalidRequest(format!(
+                    "{} books are titled {}, subscribe by id instead",
+                    books.len(),
+                    title
+                ))),
+            }
+        }
+        _ => Err(ApiError::InvalidRequest(String::from(
+            "Name the book by exactly one of bookId, bookUrl or bookTitle",
+        ))),
+    }
+}
+
 #[instrument(skip(state))]
 async fn create_subscription_handler(
     State(state): State<AppState>,
-    Json(request): Json<CreateSubscriptionRequest>,
+    ValidJson(request): ValidJson<CreateSubscriptionRequest>,
 ) -> Result<Json<Subscription>, ApiError> {
+    if request.create_book && request.book_url.is_none() {
+        return Err(ApiError::InvalidRequest(String::from(
+            "Books can only be created from their bookUrl",
+        )));
+    }
     let pool = state.pool;
     let subscription_client = SubscriptionClient::new(&pool);
     let chapter_client = ChapterClient::new(&pool);
 
+    let book = resolve_book(&pool, &request).await?;
+    if let Some(canonical_book_id) = book.canonical_book_id {
+        return Err(ApiError::InvalidRequest(format!(
+            "Book {} is an alias of book {}, subscribe to that instead",
+            book.id, canonical_book_id
+        )));
+    }
+
     let mut latest_chapter = request.last_delivered_chapter_id;
     // Request doesn't include a latest chapter id, default to the most recent
     // chapter, so that creating a subscription doesn't immediately spam.
     if latest_chapter.is_none() {
         latest_chapter = chapter_client
-            .most_recent_chapter_by_created_at(&request.book_id)
+            .most_recent_chapter_by_created_at(&book.id)
             .await?
             .map(|x| x.id);
     };
@@ -50,8 +169,10 @@ async fn create_subscription_handler(
     let subscription = subscription_client
         .create_subscription(
             &request.subscriber_id,
-            &request.book_id,
+            &book.id,
             request.chunk_size.as_ref(),
+            request.early_access,
+            request.format,
             latest_chapter.as_ref(),
         )
         .await?;
@@ -63,41 +184,211 @@ async fn create_subscription_handler(
 #[serde(deny_unknown_fields)]
 struct UpdateSubscriptionRequest {
     id: Uuid,
+    /// Moves the subscription to another book, delivering chapters after
+    /// `lastDeliveredChapterId` or otherwise after the book's most recent chapter.
+    #[serde(rename = "bookId")]
+    book_id: Option<Uuid>,
+    /// Transfers the subscription to another subscriber.
+    #[serde(rename = "subscriberId")]
+    subscriber_id: Option<Uuid>,
+    #[serde(rename = "lastDeliveredChapterId")]
+    last_delivered_chapter_id: Option<Uuid>,
     #[serde(rename = "chunkSize")]
     chunk_size: Option<i32>,
+    #[serde(rename = "earlyAccess")]
+    early_access: Option<bool>,
+    format: Option<DeliveryFormat>,
+}
+
+impl Validate for UpdateSubscriptionRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.chunk_size("chunkSize", self.chunk_size);
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize)]
 struct UpdateSubscriptionResponse {
     id: Uuid,
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+    #[serde(rename = "subscriberId")]
+    subscriber_id: Uuid,
+    #[serde(rename = "lastDeliveredChapterId")]
+    last_delivered_chapter_id: Option<Uuid>,
     #[serde(rename = "chunkSize")]
     chunk_size: Option<i32>,
+    #[serde(rename = "earlyAccess", skip_serializing_if = "Option::is_none")]
+    early_access: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<DeliveryFormat>,
     updated_at: chrono::DateTime<Utc>,
 }
 
 #[instrument(skip(state))]
 async fn update_subscription_handler(
     State(state): State<AppState>,
-    Json(request): Json<UpdateSubscriptionRequest>,
+    ValidJson(request): ValidJson<UpdateSubscriptionRequest>,
 ) -> Result<Json<UpdateSubscriptionResponse>, ApiError> {
-    if request.chunk_size.is_none() {
+    let moves = request.book_id.is_some()
+        || request.subscriber_id.is_some()
+        || request.last_delivered_chapter_id.is_some();
+    let updates =
+        request.chunk_size.is_some() || request.early_access.is_some() || request.format.is_some();
+    if !moves && !updates {
         return Err(ApiError::InvalidRequest(String::from(
-            "Expected one of [chunk_size] to be set but none were.",
+            "Expected one of [bookId, subscriberId, lastDeliveredChapterId, chunkSize, earlyAccess, format] to be set but none were.",
         )));
     }
     let pool = state.pool;
     let client = SubscriptionClient::new(&pool);
-    let subscriber = client
-        .update_subscription(&request.id, request.chunk_size)
-        .await?;
+    if let Some(book_id) = &request.book_id {
+        let book = BookClient::new(&pool).get_book(book_id).await?;
+        if let Some(canonical_book_id) = book.and_then(|x| x.canonical_book_id) {
+            return Err(ApiError::InvalidRequest(format!(
+                "Book {} is an alias of book {}, subscribe to that instead",
+                book_id, canonical_book_id
+            )));
+        }
+    }
+    let subscription = match moves {
+        true => {
+            client
+                .move_subscription(
+                    &request.id,
+                    request.book_id.as_ref(),
+                    request.subscriber_id.as_ref(),
+                    request.last_delivered_chapter_id.as_ref(),
+                    request.chunk_size,
+                    request.early_access,
+                    request.format,
+                )
+                .await?
+        }
+        false => {
+            client
+                .update_subscription(
+                    &request.id,
+                    request.chunk_size,
+                    request.early_access,
+                    request.format,
+                )
+                .await?
+        }
+    };
     Ok(UpdateSubscriptionResponse {
-        id: subscriber.id,
-        updated_at: subscriber.updated_at,
+        id: subscription.id,
+        book_id: subscription.book_id,
+        subscriber_id: subscription.subscriber_id,
+        last_delivered_chapter_id: subscription.last_delivered_chapter_id,
+        updated_at: subscription.updated_at,
         chunk_size: request.chunk_size,
+        early_access: request.early_access,
+        format: request.format,
     }
     .into())
 }
 
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetSubscriptionPositionRequest {
+    id: Uuid,
+    /// The last chapter delivered. Exactly one of this and `at` is set.
+    #[serde(rename = "chapterId")]
+    chapter_id: Option<Uuid>,
+    /// Treats every chapter created by this time as delivered.
+    at: Option<DateTime<Utc>>,
+    /// Report how many chapters would be pending without moving the position.
+    #[serde(default)]
+    preview: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct SetSubscriptionPositionResponse {
+    id: Uuid,
+    #[serde(rename = "lastDeliveredChapterId")]
+    last_delivered_chapter_id: Option<Uuid>,
+    #[serde(rename = "lastDeliveredChapterCreatedAt")]
+    last_delivered_chapter_created_at: Option<DateTime<Utc>>,
+    /// Converted chapters waiting to be delivered before and after the move.
+    #[serde(rename = "pendingBefore")]
+    pending_before: i64,
+    #[serde(rename = "pendingAfter")]
+    pending_after: i64,
+    applied: bool,
+}
+
+/// Repairs where a subscription's delivery has got to, as a chapter of its book or a time.
+/// Chapters created after the position are delivered again, or skipped when it moves forward.
+#[instrument(skip(state))]
+async fn set_subscription_position_handler(
+    State(state): State<AppState>,
+    Json(request): Json<SetSubscriptionPositionRequest>,
+) -> Result<Json<SetSubscriptionPositionResponse>, ApiError> {
+    let pool = state.pool;
+    let client = SubscriptionClient::new(&pool);
+    let subscription =
+        client
+            .get_subscription(request.id)
+            .await?
+            .ok_or_else(|| ApiError::ResourceNotFound {
+                resource_type: String::from("subscription"),
+                id: request.id.to_string(),
+            })?;
+    let chapters = ChapterClient::new(&pool)
+        .list_chapters_shallow(&subscription.book_id)
+        .await?;
+    let position = match (request.chapter_id, request.at) {
+        (Some(chapter_id), None) => {
+            let chapter = chapters.iter().find(|x| x.id == chapter_id);
+            match chapter {
+                Some(x) => Some(x),
+                None => {
+                    return Err(ApiError::InvalidRequest(format!(
+                        "Chapter {} isn't in book {}",
+                        chapter_id, subscription.book_id
+                    )))
+                }
+            }
+        }
+        (None, Some(at)) => chapters
+            .iter()
+            .filter(|x| x.created_at <= at)
+            .max_by_key(|x| x.created_at),
+        _ => {
+            return Err(ApiError::InvalidRequest(String::from(
+                "Expected exactly one of [chapterId, at] to be set",
+            )))
+        }
+    };
+    let created_at = position.map(|x| x.created_at);
+    let mut response = SetSubscriptionPositionResponse {
+        id: subscription.id,
+        last_delivered_chapter_id: position.map(|x| x.id),
+        last_delivered_chapter_created_at: created_at,
+        pending_before: client
+            .count_pending_chapters(
+                &subscription.book_id,
+                subscription.last_delivered_chapter_created_at.as_ref(),
+            )
+            .await?,
+        pending_after: client
+            .count_pending_chapters(&subscription.book_id, created_at.as_ref())
+            .await?,
+        applied: false,
+    };
+    if !request.preview {
+        client
+            .set_position(
+                &subscription.id,
+                response.last_delivered_chapter_id.as_ref(),
+                created_at.as_ref(),
+            )
+            .await?;
+        response.applied = true;
+    }
+    Ok(response.into())
+}
+
 #[derive(Debug, PartialEq, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct GetSubscriptionRequest {
@@ -108,10 +399,10 @@ struct GetSubscriptionRequest {
 async fn get_subscription_handler(
     State(state): State<AppState>,
     Query(request): Query<GetSubscriptionRequest>,
-) -> Result<Json<Subscription>, ApiError> {
+) -> Result<Json<PendingSubscription>, ApiError> {
     let pool = state.pool;
     let client = SubscriptionClient::new(&pool);
-    let subscriber = client.get_subscription(request.id).await?;
+    let subscriber = client.get_pending_subscription(request.id).await?;
     match subscriber {
         Some(x) => Ok(x.into()),
         None => Err(ApiError::ResourceNotFound {
@@ -129,7 +420,7 @@ struct ListSubscriptionsRequest {
 
 #[derive(Debug, PartialEq, Clone, Serialize)]
 struct ListSubscriptionsResult {
-    subscriptions: Vec<Subscription>,
+    subscriptions: Vec<PendingSubscription>,
 }
 
 #[instrument(skip(state))]
@@ -139,7 +430,29 @@ async fn list_subscriptions_handler(
 ) -> Result<Json<ListSubscriptionsResult>, ApiError> {
     let pool = state.pool;
     let client = SubscriptionClient::new(&pool);
-    let subscriptions = client.list_subscriptions(&request.subscriber_id).await?;
+    let subscriptions = client
+        .list_pending_subscriptions(Some(&request.subscriber_id), None)
+        .await?;
+    Ok(ListSubscriptionsResult { subscriptions }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListBookSubscriptionsRequest {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn list_book_subscriptions_handler(
+    State(state): State<AppState>,
+    Query(request): Query<ListBookSubscriptionsRequest>,
+) -> Result<Json<ListSubscriptionsResult>, ApiError> {
+    let pool = state.pool;
+    let client = SubscriptionClient::new(&pool);
+    let subscriptions = client
+        .list_pending_subscriptions(None, Some(&request.book_id))
+        .await?;
     Ok(ListSubscriptionsResult { subscriptions }.into())
 }
 
@@ -156,15 +469,414 @@ async fn delete_subscription_handler(
 ) -> Result<Json<serde_json::Value>, ApiError> {
     let pool = state.pool;
     let client = SubscriptionClient::new(&pool);
+    let subscription = client.get_subscription(request.id).await?;
+    // It would only be recreated by its wildcard or anthology subscription.
+    if let Some(wildcard_subscription_id) = subscription
+        .as_ref()
+        .and_then(|x| x.wildcard_subscription_id)
+    {
+        return Err(ApiError::InvalidRequest(format!(
+            "Subscription {} belongs to wildcard subscription {}, exclude its book there instead.",
+            request.id, wildcard_subscription_id
+        )));
+    }
+    if let Some(anthology_subscription_id) = subscription
+        .as_ref()
+        .and_then(|x| x.anthology_subscription_id)
+    {
+        return Err(ApiError::InvalidRequest(format!(
+            "Subscription {} belongs to anthology subscription {}, remove its book there instead.",
+            request.id, anthology_subscription_id
+        )));
+    }
     client.delete_subscription(request.id).await?;
     Ok(json!({}).into())
 }
 
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateWildcardSubscriptionRequest {
+    #[serde(rename = "subscriberId")]
+    subscriber_id: Uuid,
+    #[serde(rename = "chunkSize")]
+    chunk_size: Option<i32>,
+    #[serde(rename = "excludedBookIds", default)]
+    excluded_book_ids: Vec<Uuid>,
+    /// Only cover books with this tag, including those tagged later.
+    #[serde(rename = "tagId")]
+    tag_id: Option<Uuid>,
+}
+
+impl Validate for CreateWildcardSubscriptionRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.chunk_size("chunkSize", self.chunk_size);
+    }
+}
+
+#[instrument(skip(state))]
+async fn create_wildcard_subscription_handler(
+    State(state): State<AppState>,
+    ValidJson(request): ValidJson<CreateWildcardSubscriptionRequest>,
+) -> Result<Json<WildcardSubscription>, ApiError> {
+    let pool = state.pool;
+    let subscription = WildcardSubscriptionClient::new(&pool)
+        .create_wildcard_subscription(
+            &request.subscriber_id,
+            request.chunk_size.as_ref(),
+            &request.excluded_book_ids,
+            request.tag_id.as_ref(),
+        )
+        .await?;
+    sync_wildcard_subscriptions(&pool).await?;
+    Ok(subscription.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpdateWildcardSubscriptionRequest {
+    id: Uuid,
+    #[serde(rename = "chunkSize")]
+    chunk_size: Option<i32>,
+    #[serde(rename = "excludedBookIds")]
+    excluded_book_ids: Option<Vec<Uuid>>,
+}
+
+impl Validate for UpdateWildcardSubscriptionRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.chunk_size("chunkSize", self.chunk_size);
+    }
+}
+
+#[instrument(skip(state))]
+async fn update_wildcard_subscription_handler(
+    State(state): State<AppState>,
+    ValidJson(request): ValidJson<UpdateWildcardSubscriptionRequest>,
+) -> Result<Json<WildcardSubscription>, ApiError> {
+    if request.chunk_size.is_none() && request.excluded_book_ids.is_none() {
+        return Err(ApiError::InvalidRequest(String::from(
+            "Expected one of [chunkSize, excludedBookIds] to be set but none were.",
+        )));
+    }
+    let pool = state.pool;
+    let subscription = WildcardSubscriptionClient::new(&pool)
+        .update_wildcard_subscription(
+            &request.id,
+            request.chunk_size,
+            request.excluded_book_ids.as_deref(),
+        )
+        .await?;
+    if let Some(chunk_size) = request.chunk_size {
+        SubscriptionClient::new(&pool)
+            .set_wildcard_members_chunk_size(&subscription.id, chunk_size)
+            .await?;
+    }
+    sync_wildcard_subscriptions(&pool).await?;
+    Ok(subscription.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GetWildcardSubscriptionRequest {
+    id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn get_wildcard_subscription_handler(
+    State(state): State<AppState>,
+    Query(request): Query<GetWildcardSubscriptionRequest>,
+) -> Result<Json<WildcardSubscription>, ApiError> {
+    let pool = state.pool;
+    let client = WildcardSubscriptionClient::new(&pool);
+    match client.get_wildcard_subscription(&request.id).await? {
+        Some(x) => Ok(x.into()),
+        None => Err(ApiError::ResourceNotFound {
+            resource_type: String::from("wildcardSubscription"),
+            id: request.id.to_string(),
+        }),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListWildcardSubscriptionsRequest {
+    #[serde(rename = "subscriberId")]
+    subscriber_id: Uuid,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ListWildcardSubscriptionsResult {
+    subscriptions: Vec<WildcardSubscription>,
+}
+
+#[instrument(skip(state))]
+async fn list_wildcard_subscriptions_handler(
+    State(state): State<AppState>,
+    Query(request): Query<ListWildcardSubscriptionsRequest>,
+) -> Result<Json<ListWildcardSubscriptionsResult>, ApiError> {
+    let pool = state.pool;
+    let subscriptions = WildcardSubscriptionClient::new(&pool)
+        .list_wildcard_subscriptions(Some(&request.subscriber_id))
+        .await?;
+    Ok(ListWildcardSubscriptionsResult { subscriptions }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeleteWildcardSubscriptionRequest {
+    id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn delete_wildcard_subscription_handler(
+    State(state): State<AppState>,
+    Json(request): Json<DeleteWildcardSubscriptionRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let pool = state.pool;
+    let client = WildcardSubscriptionClient::new(&pool);
+    client.delete_wildcard_subscription(&request.id).await?;
+    Ok(json!({}).into())
+}
+
+/// Checks that an anthology is named, covers at least one book, and has a positive period.
+fn validate_anthology_fields(
+    validator: &mut Validator,
+    name: Option<&str>,
+    book_ids: Option<&[Uuid]>,
+    period_secs: Option<i64>,
+) {
+    validator.non_empty("name", name);
+    if let Some(x) = period_secs {
+        validator.check("periodSecs", x > 0, || String::from("must be positive"));
+    }
+    if let Some(x) = book_ids {
+        validator.check("bookIds", !x.is_empty(), || {
+            String::from("must name at least one book")
+        });
+    }
+}
+
+/// Checks that each of the anthology's books exists.
+async fn validate_anthology_books(
+    pool: &Pool<Sqlite>,
+    book_ids: Option<&[Uuid]>,
+) -> Result<(), ApiError> {
+    let book_client = BookClient::new(pool);
+    for book_id in book_ids.unwrap_or_default() {
+        if book_client.get_book(book_id).await?.is_none() {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("book"),
+                id: book_id.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateAnthologySubscriptionRequest {
+    #[serde(rename = "subscriberId")]
+    subscriber_id: Uuid,
+    name: String,
+    #[serde(rename = "bookIds")]
+    book_ids: Vec<Uuid>,
+    /// How often the digest is sent, e.g. 604800 for weekly.
+    #[serde(rename = "periodSecs")]
+    period_secs: i64,
+}
+
+impl Validate for CreateAnthologySubscriptionRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validate_anthology_fields(
+            validator,
+            Some(&self.name),
+            Some(&self.book_ids),
+            Some(self.period_secs),
+        );
+    }
+}
+
+#[instrument(skip(state))]
+async fn create_anthology_subscription_handler(
+    State(state): State<AppState>,
+    ValidJson(request): ValidJson<CreateAnthologySubscriptionRequest>,
+) -> Result<Json<AnthologySubscription>, ApiError> {
+    let pool = state.pool;
+    validate_anthology_books(&pool, Some(&request.book_ids)).await?;
+    let subscription = AnthologySubscriptionClient::new(&pool)
+        .create_anthology_subscription(
+            &request.subscriber_id,
+            request.name.trim(),
+            &request.book_ids,
+            request.period_secs,
+        )
+        .await?;
+    sync_anthology_subscriptions(&pool).await?;
+    Ok(subscription.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpdateAnthologySubscriptionRequest {
+    id: Uuid,
+    name: Option<String>,
+    #[serde(rename = "bookIds")]
+    book_ids: Option<Vec<Uuid>>,
+    #[serde(rename = "periodSecs")]
+    period_secs: Option<i64>,
+}
+
+impl Validate for UpdateAnthologySubscriptionRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validate_anthology_fields(
+            validator,
+            self.name.as_deref(),
+            self.book_ids.as_deref(),
+            self.period_secs,
+        );
+    }
+}
+
+#[instrument(skip(state))]
+async fn update_anthology_subscription_handler(
+    State(state): State<AppState>,
+    ValidJson(request): ValidJson<UpdateAnthologySubscriptionRequest>,
+) -> Result<Json<AnthologySubscription>, ApiError> {
+    if request.name.is_none() && request.book_ids.is_none() && request.period_secs.is_none() {
+        return Err(ApiError::InvalidRequest(String::from(
+            "Expected one of [name, bookIds, periodSecs] to be set but none were.",
+        )));
+    }
+    let pool = state.pool;
+    validate_anthology_books(&pool, request.book_ids.as_deref()).await?;
+    let subscription = AnthologySubscriptionClient::new(&pool)
+        .update_anthology_subscription(
+            &request.id,
+            request.name.as_deref().map(str::trim),
+            request.book_ids.as_deref(),
+            request.period_secs,
+        )
+        .await?;
+    sync_anthology_subscriptions(&pool).await?;
+    Ok(subscription.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GetAnthologySubscriptionRequest {
+    id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn get_anthology_subscription_handler(
+    State(state): State<AppState>,
+    Query(request): Query<GetAnthologySubscriptionRequest>,
+) -> Result<Json<AnthologySubscription>, ApiError> {
+    let pool = state.pool;
+    let client = AnthologySubscriptionClient::new(&pool);
+    match client.get_anthology_subscription(&request.id).await? {
+        Some(x) => Ok(x.into()),
+        None => Err(ApiError::ResourceNotFound {
+            resource_type: String::from("anthologySubscription"),
+            id: request.id.to_string(),
+        }),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListAnthologySubscriptionsRequest {
+    #[serde(rename = "subscriberId")]
+    subscriber_id: Uuid,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ListAnthologySubscriptionsResult {
+    subscriptions: Vec<AnthologySubscription>,
+}
+
+#[instrument(skip(state))]
+async fn list_anthology_subscriptions_handler(
+    State(state): State<AppState>,
+    Query(request): Query<ListAnthologySubscriptionsRequest>,
+) -> Result<Json<ListAnthologySubscriptionsResult>, ApiError> {
+    let pool = state.pool;
+    let subscriptions = AnthologySubscriptionClient::new(&pool)
+        .list_anthology_subscriptions(Some(&request.subscriber_id))
+        .await?;
+    Ok(ListAnthologySubscriptionsResult { subscriptions }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeleteAnthologySubscriptionRequest {
+    id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn delete_anthology_subscription_handler(
+    State(state): State<AppState>,
+    Json(request): Json<DeleteAnthologySubscriptionRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let pool = state.pool;
+    let client = AnthologySubscriptionClient::new(&pool);
+    client.delete_anthology_subscription(&request.id).await?;
+    Ok(json!({}).into())
+}
+
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/createSubscription", post(create_subscription_handler))
         .route("/updateSubscription", post(update_subscription_handler))
+        .route(
+            "/setSubscriptionPosition",
+            post(set_subscription_position_handler),
+        )
         .route("/getSubscription", get(get_subscription_handler))
         .route("/listSubscriptions", get(list_subscriptions_handler))
+        .route(
+            "/listBookSubscriptions",
+            get(list_book_subscriptions_handler),
+        )
         .route("/deleteSubscription", delete(delete_subscription_handler))
+        .route(
+            "/createWildcardSubscription",
+            post(create_wildcard_subscription_handler),
+        )
+        .route(
+            "/updateWildcardSubscription",
+            post(update_wildcard_subscription_handler),
+        )
+        .route(
+            "/getWildcardSubscription",
+            get(get_wildcard_subscription_handler),
+        )
+        .route(
+            "/listWildcardSubscriptions",
+            get(list_wildcard_subscriptions_handler),
+        )
+        .route(
+            "/deleteWildcardSubscription",
+            delete(delete_wildcard_subscription_handler),
+        )
+        .route(
+            "/createAnthologySubscription",
+            post(create_anthology_subscription_handler),
+        )
+        .route(
+            "/updateAnthologySubscription",
+            post(update_anthology_subscription_handler),
+        )
+        .route(
+            "/getAnthologySubscription",
+            get(get_anthology_subscription_handler),
+        )
+        .route(
+            "/listAnthologySubscriptions",
+            get(list_anthology_subscriptions_handler),
+        )
+        .route(
+            "/deleteAnthologySubscription",
+            delete(delete_anthology_subscription_handler),
+        )
 }
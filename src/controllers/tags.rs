@@ -0,0 +1,180 @@
+use axum::{
+    extract::{Query, State},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    models::{Tag, TagClient},
+    AppState,
+};
+
+use super::validation::{ValidJson, Validate, Validator};
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateTagRequest {
+    name: String,
+}
+
+impl Validate for CreateTagRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.non_empty("name", Some(&self.name));
+    }
+}
+
+#[instrument(skip(state))]
+async fn create_tag_handler(
+    State(state): State<AppState>,
+    ValidJson(request): ValidJson<CreateTagRequest>,
+) -> Result<Json<Tag>, ApiError> {
+    let pool = state.pool;
+    let tag = TagClient::new(&pool).create_tag(&request.name).await?;
+    Ok(tag.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpdateTagRequest {
+    id: Uuid,
+    name: String,
+}
+
+impl Validate for UpdateTagRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.non_empty("name", Some(&self.name));
+    }
+}
+
+#[instrument(skip(state))]
+async fn update_tag_handler(
+    State(state): State<AppState>,
+    ValidJson(request): ValidJson<UpdateTagRequest>,
+) -> Result<Json<Tag>, ApiError> {
+    let pool = state.pool;
+    let tag = TagClient::new(&pool)
+        .update_tag(&request.id, &request.name)
+        .await?;
+    Ok(tag.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GetTagRequest {
+    id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn get_tag_handler(
+    State(state): State<AppState>,
+    Query(request): Query<GetTagRequest>,
+) -> Result<Json<Tag>, ApiError> {
+    let pool = state.pool;
+    let tag = TagClient::new(&pool).get_tag(&request.id).await?;
+    match tag {
+        Some(x) => Ok(x.into()),
+        None => Err(ApiError::ResourceNotFound {
+            resource_type: String::from("tag"),
+            id: request.id.to_string(),
+        }),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ListTagsResult {
+    tags: Vec<Tag>,
+}
+
+#[instrument(skip(state))]
+async fn list_tags_handler(
+    State(state): State<AppState>,
+) -> Result<Json<ListTagsResult>, ApiError> {
+    let pool = state.pool;
+    let tags = TagClient::new(&pool).list_tags().await?;
+    Ok(ListTagsResult { tags }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeleteTagRequest {
+    id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn delete_tag_handler(
+    State(state): State<AppState>,
+    Json(request): Json<DeleteTagRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let pool = state.pool;
+    TagClient::new(&pool).delete_tag(&request.id).await?;
+    Ok(json!({}).into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BookTagRequest {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+    #[serde(rename = "tagId")]
+    tag_id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn tag_book_handler(
+    State(state): State<AppState>,
+    Json(request): Json<BookTagRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let pool = state.pool;
+    TagClient::new(&pool)
+        .tag_book(&request.book_id, &request.tag_id)
+        .await?;
+    Ok(json!({}).into())
+}
+
+#[instrument(skip(state))]
+async fn untag_book_handler(
+    State(state): State<AppState>,
+    Json(request): Json<BookTagRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let pool = state.pool;
+    TagClient::new(&pool)
+        .untag_book(&request.book_id, &request.tag_id)
+        .await?;
+    Ok(json!({}).into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListBookTagsRequest {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn list_book_tags_handler(
+    State(state): State<AppState>,
+    Query(request): Query<ListBookTagsRequest>,
+) -> Result<Json<ListTagsResult>, ApiError> {
+    let pool = state.pool;
+    let tags = TagClient::new(&pool)
+        .list_tags_for_book(&request.book_id)
+        .await?;
+    Ok(ListTagsResult { tags }.into())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/createTag", post(create_tag_handler))
+        .route("/updateTag", post(update_tag_handler))
+        .route("/getTag", get(get_tag_handler))
+        .route("/listTags", get(list_tags_handler))
+        .route("/deleteTag", delete(delete_tag_handler))
+        .route("/tagBook", post(tag_book_handler))
+        .route("/untagBook", delete(untag_book_handler))
+        .route("/listBookTags", get(list_book_tags_handler))
+}
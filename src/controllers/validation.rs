@@ -0,0 +1,141 @@
+use async_trait::async_trait;
+use axum::{
+    body::HttpBody,
+    extract::FromRequest,
+    http::Request,
+    response::{IntoResponse, Response},
+    BoxError, Json,
+};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    error::{ApiError, ApiResult, FieldError},
+    models::{max_chunk_size, ChapterMetadata, MIN_CHUNK_SIZE},
+};
+
+/// Collects every field of a request which fails its checks, so they can all be reported at
+/// once. Fields are named as they are in the request body, with nested fields dotted.
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn check(&mut self, field: &str, valid: bool, message: impl FnOnce() -> String) {
+        if !valid {
+            self.errors.push(FieldError {
+                field: field.to_owned(),
+                message: message(),
+            });
+        }
+    }
+
+    pub fn non_empty(&mut self, field: &str, value: Option<&str>) {
+        if let Some(x) = value {
+            self.check(field, !x.trim().is_empty(), || {
+                String::from("must not be empty")
+            });
+        }
+    }
+
+    pub fn chunk_size(&mut self, field: &str, chunk_size: Option<i32>) {
+        if let Some(x) = chunk_size {
+            let max = max_chunk_size();
+            self.check(field, (MIN_CHUNK_SIZE..=max).contains(&x), || {
+                format!(
+                    "must be between {} and {}, found {}",
+                    MIN_CHUNK_SIZE, max, x
+                )
+            });
+        }
+    }
+
+    /// Blank emails are allowed, as they clear the email they are set as.
+    pub fn email(&mut self, field: &str, email: Option<&str>) {
+        if let Some(x) = email.map(str::trim).filter(|x| !x.is_empty()) {
+            self.check(field, is_email(x), || {
+                format!("{:?} is not an email address", x)
+            });
+        }
+    }
+
+    pub fn url(&mut self, field: &str, url: Option<&str>) {
+        if let Some(x) = url {
+            self.check(field, is_web_url(x), || {
+                format!("{:?} is not an http or https url", x)
+            });
+        }
+    }
+
+    pub fn chapter_metadata(&mut self, field: &str, metadata: &ChapterMetadata) {
+        match metadata {
+            ChapterMetadata::Pale { url } | ChapterMetadata::TheWanderingInnPatreon { url, .. } => {
+                self.url(&format!("{}.url", field), Some(url))
+            }
+            ChapterMetadata::RoyalRoad { .. }
+            | ChapterMetadata::TheDailyGrindPatreon
+            | ChapterMetadata::ApparatusOfChangePatreon
+            | ChapterMetadata::Imported { .. } => {}
+        }
+    }
+
+    pub fn finish(self) -> ApiResult<()> {
+        match self.errors.is_empty() {
+            true => Ok(()),
+            false => Err(ApiError::Validation(self.errors)),
+        }
+    }
+}
+
+fn is_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && !email.contains(char::is_whitespace)
+                && domain.split('.').count() > 1
+                && domain.split('.').all(|x| !x.is_empty())
+        }
+        None => false,
+    }
+}
+
+fn is_web_url(url: &str) -> bool {
+    match Url::parse(url) {
+        Ok(x) => matches!(x.scheme(), "http" | "https") && x.host_str().is_some(),
+        Err(_) => false,
+    }
+}
+
+/// Checks a request's fields beyond what deserializing it does.
+pub trait Validate {
+    fn validate(&self, validator: &mut Validator);
+}
+
+/// Extracts a json request body like [`Json`], then validates it, rejecting it with every
+/// field which failed as a 422.
+#[derive(Debug)]
+pub struct ValidJson<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for ValidJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let mut validator = Validator::default();
+        value.validate(&mut validator);
+        validator.finish().map_err(IntoResponse::into_response)?;
+        Ok(ValidJson(value))
+    }
+}
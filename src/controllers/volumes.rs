@@ -0,0 +1,189 @@
+use axum::{
+    extract::{Query, State},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    models::{BookClient, Volume, VolumeClient},
+    tasks::volumes::detect_book_volumes,
+    AppState,
+};
+
+use super::validation::{ValidJson, Validate, Validator};
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateVolumeRequest {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+    number: i64,
+    title: Option<String>,
+    /// Chapters moved into the new volume.
+    #[serde(rename = "chapterIds", default)]
+    chapter_ids: Vec<Uuid>,
+}
+
+impl Validate for CreateVolumeRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.non_empty("title", self.title.as_deref());
+    }
+}
+
+#[instrument(skip(state))]
+async fn create_volume_handler(
+    State(state): State<AppState>,
+    ValidJson(request): ValidJson<CreateVolumeRequest>,
+) -> Result<Json<Volume>, ApiError> {
+    let client = VolumeClient::new(&state.pool);
+    let volume = client
+        .create_volume(
+            &request.book_id,
+            request.number,
+            request.title.as_deref(),
+            false,
+        )
+        .await?;
+    if !request.chapter_ids.is_empty() {
+        client
+            .set_volume_chapters(&volume, &request.chapter_ids)
+            .await?;
+    }
+    Ok(volume.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpdateVolumeRequest {
+    id: Uuid,
+    number: Option<i64>,
+    title: Option<String>,
+    /// Replaces the volume's chapters. Omit to leave them as they are.
+    #[serde(rename = "chapterIds")]
+    chapter_ids: Option<Vec<Uuid>>,
+}
+
+impl Validate for UpdateVolumeRequest {
+    fn validate(&self, validator: &mut Validator) {
+        validator.non_empty("title", self.title.as_deref());
+    }
+}
+
+/// Updates the volume. Volumes edited by hand are no longer changed by detection.
+#[instrument(skip(state))]
+async fn update_volume_handler(
+    State(state): State<AppState>,
+    ValidJson(request): ValidJson<UpdateVolumeRequest>,
+) -> Result<Json<Volume>, ApiError> {
+    let client = VolumeClient::new(&state.pool);
+    let volume = client
+        .update_volume(&request.id, request.number, request.title.as_deref())
+        .await?;
+    if let Some(chapter_ids) = &request.chapter_ids {
+        client.set_volume_chapters(&volume, chapter_ids).await?;
+    }
+    Ok(volume.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GetVolumeRequest {
+    id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn get_volume_handler(
+    State(state): State<AppState>,
+    Query(request): Query<GetVolumeRequest>,
+) -> Result<Json<Volume>, ApiError> {
+    let volume = VolumeClient::new(&state.pool)
+        .get_volume(&request.id)
+        .await?;
+    match volume {
+        Some(x) => Ok(x.into()),
+        None => Err(ApiError::ResourceNotFound {
+            resource_type: String::from("volume"),
+            id: request.id.to_string(),
+        }),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListVolumesRequest {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ListVolumesResult {
+    volumes: Vec<Volume>,
+}
+
+#[instrument(skip(state))]
+async fn list_volumes_handler(
+    State(state): State<AppState>,
+    Query(request): Query<ListVolumesRequest>,
+) -> Result<Json<ListVolumesResult>, ApiError> {
+    let volumes = VolumeClient::new(&state.pool)
+        .list_volumes(&request.book_id)
+        .await?;
+    Ok(ListVolumesResult { volumes }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeleteVolumeRequest {
+    id: Uuid,
+}
+
+#[instrument(skip(state))]
+async fn delete_volume_handler(
+    State(state): State<AppState>,
+    Json(request): Json<DeleteVolumeRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    VolumeClient::new(&state.pool)
+        .delete_volume(&request.id)
+        .await?;
+    Ok(json!({}).into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DetectVolumesRequest {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+}
+
+/// Sorts the book's chapters into volumes detected from their titles, whether or not the book
+/// detects volumes as chapters are discovered.
+#[instrument(skip(state))]
+async fn detect_volumes_handler(
+    State(state): State<AppState>,
+    Json(request): Json<DetectVolumesRequest>,
+) -> Result<Json<ListVolumesResult>, ApiError> {
+    let book = BookClient::new(&state.pool)
+        .get_book(&request.book_id)
+        .await?
+        .ok_or_else(|| ApiError::ResourceNotFound {
+            resource_type: String::from("book"),
+            id: request.book_id.to_string(),
+        })?;
+    let volumes = detect_book_volumes(&state.pool, &book).await?;
+    Ok(ListVolumesResult { volumes }.into())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/createVolume", post(create_volume_handler))
+        .route("/updateVolume", post(update_volume_handler))
+        .route("/getVolume", get(get_volume_handler))
+        .route("/listVolumes", get(list_volumes_handler))
+        .route("/deleteVolume", delete(delete_volume_handler))
+        .route("/detectVolumes", post(detect_volumes_handler))
+}
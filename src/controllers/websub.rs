@@ -0,0 +1,212 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::Sha256;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    models::{BookClient, WebSubSubscription, WebSubSubscriptionClient},
+    tasks::websub::{discover_pushed_chapters, subscribe_book, unsubscribe_book},
+    AppState,
+};
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebSubBookRequest {
+    #[serde(rename = "bookId")]
+    book_id: Uuid,
+}
+
+/// Subscribes the book to its source's WebSub hub, so new chapters are discovered as soon as
+/// the hub announces them. Polling continues as a fallback.
+#[instrument(skip(state))]
+async fn subscribe_websub_handler(
+    State(state): State<AppState>,
+    Json(request): Json<WebSubBookRequest>,
+) -> Result<Json<WebSubSubscription>, ApiError> {
+    let pool = state.pool;
+    let book = match BookClient::new(&pool).get_book(&request.book_id).await? {
+        Some(x) => x,
+        None => {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("book"),
+                id: request.book_id.to_string(),
+            })
+        }
+    };
+    Ok(subscribe_book(&pool, &book).await?.into())
+}
+
+#[instrument(skip(state))]
+async fn unsubscribe_websub_handler(
+    State(state): State<AppState>,
+    Json(request): Json<WebSubBookRequest>,
+) -> Result<(), ApiError> {
+    unsubscribe_book(&state.pool, &request.book_id).await
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+struct ListWebSubSubscriptionsResult {
+    subscriptions: Vec<WebSubSubscription>,
+}
+
+#[instrument(skip(state))]
+async fn list_websub_subscriptions_handler(
+    State(state): State<AppState>,
+) -> Result<Json<ListWebSubSubscriptionsResult>, ApiError> {
+    let subscriptions = WebSubSubscriptionClient::new(&state.pool)
+        .list_subscriptions()
+        .await?;
+    Ok(ListWebSubSubscriptionsResult { subscriptions }.into())
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+struct CallbackPath {
+    id: Uuid,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+struct VerificationRequest {
+    #[serde(rename = "hub.mode")]
+    mode: String,
+    #[serde(rename = "hub.topic")]
+    topic: String,
+    #[serde(rename = "hub.challenge")]
+    challenge: Option<String>,
+    #[serde(rename = "hub.lease_seconds")]
+    lease_seconds: Option<i64>,
+    #[serde(rename = "hub.reason")]
+    reason: Option<String>,
+}
+
+/// Answers the hub's check that this server asked for a subscription or unsubscription, by
+/// echoing its challenge. Requests which don't match what was asked for are refused.
+#[instrument(skip(state))]
+async fn verify_callback_handler(
+    State(state): State<AppState>,
+    Path(path): Path<CallbackPath>,
+    Query(request): Query<VerificationRequest>,
+) -> Result<Response, ApiError> {
+    let client = WebSubSubscriptionClient::new(&state.pool);
+    let subscription = client
+        .get_subscription(&path.id)
+        .await?
+        .filter(|x| x.topic == request.topic);
+    let confirmed = match (request.mode.as_str(), subscription) {
+        ("unsubscribe", _) => {
+            client
+                .confirm_unsubscription(&path.id, &request.topic)
+                .await?
+        }
+        ("subscribe", Some(_)) => {
+            let lease_expires_at = request
+                .lease_seconds
+                .map(|x| Utc::now() + Duration::seconds(x));
+            client
+                .mark_verified(&path.id, lease_expires_at.as_ref())
+                .await?;
+            info!("Hub verified the WebSub subscription for book {}", path.id);
+            true
+        }
+        ("denied", _) => {
+            warn!(
+                "Hub denied the WebSub subscription for book {}: {:?}",
+                path.id, request.reason
+            );
+            return Ok(StatusCode::OK.into_response());
+        }
+        _ => false,
+    };
+    match (confirmed, request.challenge) {
+        (true, Some(challenge)) => Ok(challenge.into_response()),
+        _ => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+/// Whether the `X-Hub-Signature` header is the HMAC of the body keyed by the subscription's
+/// secret. Hubs sign with SHA-1 or SHA-256.
+fn is_valid_signature(secret: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+    let signature = match headers.get("x-hub-signature").and_then(|x| x.to_str().ok()) {
+        Some(x) => x,
+        None => return false,
+    };
+    let (method, signature) = match signature.split_once('=') {
+        Some(x) => x,
+        None => return false,
+    };
+    let bytes: Option<Vec<u8>> = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(signature.get(i..i + 2)?, 16).ok())
+        .collect();
+    let bytes = match bytes {
+        Some(x) => x,
+        None => return false,
+    };
+    // HMAC accepts keys of any length, and is compared in constant time.
+    match method {
+        "sha1" => {
+            let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).unwrap();
+            mac.update(body);
+            mac.verify_slice(&bytes).is_ok()
+        }
+        "sha256" => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+            mac.update(body);
+            mac.verify_slice(&bytes).is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Receives the hub's announcement that the source feed changed, and checks the book for new
+/// chapters in the background. Unsigned or forged announcements are acknowledged but ignored,
+/// as WebSub asks, so they can't be used to probe the secret.
+#[instrument(skip(state, headers, body))]
+async fn notify_callback_handler(
+    State(state): State<AppState>,
+    Path(path): Path<CallbackPath>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let pool = state.pool;
+    let client = WebSubSubscriptionClient::new(&pool);
+    let subscription = match client.get_subscription(&path.id).await? {
+        Some(x) => x,
+        None => return Ok(StatusCode::GONE),
+    };
+    if !is_valid_signature(&subscription.secret, &headers, &body) {
+        warn!(
+            "Ignoring WebSub notification for book {} with a bad signature",
+            path.id
+        );
+        return Ok(StatusCode::ACCEPTED);
+    }
+    client.record_notification(&path.id).await?;
+    tokio::spawn(async move { discover_pushed_chapters(&pool, path.id).await });
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/subscribeWebSub", post(subscribe_websub_handler))
+        .route("/unsubscribeWebSub", post(unsubscribe_websub_handler))
+        .route(
+            "/listWebSubSubscriptions",
+            get(list_websub_subscriptions_handler),
+        )
+        .route(
+            "/websub/callback/:id",
+            get(verify_callback_handler).post(notify_callback_handler),
+        )
+}
@@ -1,10 +1,31 @@
-use axum::{http::StatusCode, response::IntoResponse};
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use itertools::Itertools;
+use serde::Serialize;
+use serde_json::json;
 use thiserror::Error;
 
+/// A request field which failed validation, named as it is in the request body.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+fn describe_fields(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|x| format!("{}: {}", x.field, x.message))
+        .join("; ")
+}
+
 #[derive(Error, Debug)]
 pub enum ApiError {
     #[error("{0}")]
     InvalidRequest(String),
+    #[error("Provider metadata failed validation: {0}")]
+    InvalidMetadata(String),
+    #[error("The request failed validation: {}", describe_fields(.0))]
+    Validation(Vec<FieldError>),
     #[error("Resource of type {resource_type} with id {id:?} not found.")]
     ResourceNotFound { resource_type: String, id: String },
     #[error("Failed to serialize a value to json: {0}")]
@@ -15,6 +36,18 @@ pub enum ApiError {
     TowerServer(#[from] hyper::Error),
     #[error("An io error occurred: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Epub conversion failed: {0}")]
+    Conversion(String),
+    #[error("The database failed its integrity check: {0}")]
+    DatabaseIntegrity(String),
+    #[error("The server is in read-only mode.")]
+    ReadOnly,
+    #[error("A valid bearer token is required.")]
+    Unauthorized,
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("A request to another server failed: {0}")]
+    Upstream(String),
 }
 
 pub type ApiResult<T> = Result<T, ApiError>;
@@ -27,6 +60,26 @@ impl IntoResponse for ApiError {
                 resource_type: _,
                 id: _,
             } => (StatusCode::NOT_FOUND, self.to_string()).into_response(),
+            ApiError::InvalidRequest(_) => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
+            ApiError::InvalidMetadata(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()).into_response()
+            }
+            ApiError::Validation(errors) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({ "message": self.to_string(), "errors": errors })),
+            )
+                .into_response(),
+            ApiError::ReadOnly => {
+                (StatusCode::SERVICE_UNAVAILABLE, self.to_string()).into_response()
+            }
+            ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, self.to_string()).into_response(),
+            ApiError::Forbidden(_) => (StatusCode::FORBIDDEN, self.to_string()).into_response(),
+            ApiError::Upstream(_) => (StatusCode::BAD_GATEWAY, self.to_string()).into_response(),
+            ApiError::Conversion(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+            }
             _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     }
@@ -0,0 +1,364 @@
+pub mod auth;
+pub mod cache;
+pub mod config;
+pub mod controllers;
+pub mod error;
+pub mod models;
+pub mod providers;
+pub mod read_only;
+pub mod tasks;
+pub mod telemetry;
+mod util;
+
+use controllers::{
+    admin, backfills, books, chapters, credentials, deliveries, events, feeds, kosync, shares,
+    status, subscribers, subscriptions, tags, volumes, websub,
+};
+use error::{ApiError, ApiResult};
+use itertools::Itertools;
+
+use axum::{middleware, Router};
+use cache::ResponseCache;
+use futures::Future;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Pool, Sqlite,
+};
+use std::net::SocketAddr;
+use std::{env, path::Path, str::FromStr};
+use tower_http::trace::TraceLayer;
+use tracing::{error, warn};
+
+#[derive(Clone)]
+pub struct AppState {
+    pool: Pool<Sqlite>,
+    cache: ResponseCache,
+}
+
+/// Opens the database, creating and migrating it as needed.
+pub async fn connect_db() -> ApiResult<Pool<Sqlite>> {
+    let create_db = !Path::new("./data.db").try_exists()?;
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(5)
+        .connect_with(SqliteConnectOptions::from_str("sqlite:data.db")?.create_if_missing(true))
+        .await?;
+
+    if create_db {
+        new_db(pool.clone()).await?;
+    } else {
+        check_db(&pool).await?;
+    }
+    migrate_db(pool.clone()).await?;
+    Ok(pool)
+}
+
+/// Opens an empty in-memory database with the current schema, for tests.
+pub async fn connect_memory_db() -> ApiResult<Pool<Sqlite>> {
+    // Each connection to an in-memory database gets a database of its own, so the pool keeps
+    // exactly one connection open.
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(SqliteConnectOptions::from_str("sqlite::memory:")?)
+        .await?;
+    new_db(pool.clone()).await?;
+    migrate_db(pool.clone()).await?;
+    Ok(pool)
+}
+
+/// Refuses to use a damaged database, or one migrated by a newer release, rather than serve
+/// subtly broken data. Set CEREAL_SKIP_INTEGRITY_CHECK=true to start anyway, for recovery.
+async fn check_db(pool: &Pool<Sqlite>) -> ApiResult<()> {
+    if env::var("CEREAL_SKIP_INTEGRITY_CHECK").is_ok_and(|x| x == "true") {
+        warn!("Skipping the database integrity check");
+        return Ok(());
+    }
+    let (version,): (i64,) = sqlx::query_as("PRAGMA user_version")
+        .fetch_one(pool)
+        .await?;
+    if version as usize > MIGRATIONS.len() {
+        return Err(ApiError::DatabaseIntegrity(format!(
+            "The database is at schema version {} but this release only knows {}",
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+    // quick_check skips the index consistency checks, so it stays fast on large databases.
+    let problems: Vec<(String,)> = sqlx::query_as("PRAGMA quick_check").fetch_all(pool).await?;
+    match problems.as_slice() {
+        [(result,)] if result == "ok" => Ok(()),
+        problems => Err(ApiError::DatabaseIntegrity(
+            problems.iter().map(|(x,)| x.as_str()).join("; "),
+        )),
+    }
+}
+
+/// Runs the API server, restarting it if it fails.
+pub async fn serve(pool: Pool<Sqlite>) {
+    // Books are validated against their providers, which read credentials from the vault.
+    if let Err(e) = models::CredentialClient::new(&pool).refresh().await {
+        error!(?e, "Failed to read credentials");
+    }
+    // The cache outlives server restarts, so it is only invalidated by one task.
+    let cache = ResponseCache::new();
+    tokio::spawn(cache.clone().invalidate_on_changes());
+    loop {
+        let x = tokio::spawn(get_server_future(pool.clone(), cache.clone())).await;
+        error!("API server thread failed. Restarting the thread.");
+        match x {
+            Ok(Ok(_)) => error!("API Server returned OK. This should not be possible."),
+            Ok(Err(err)) => error!(?err, "API Server has failed."),
+            Err(err) => error!(?err, "API Server has paniced. This should not be possible."),
+        };
+    }
+}
+
+/// Runs the discovery, hydration, conversion, delivery, integrity and maintenance loops,
+/// restarting any that fail.
+pub async fn work(pool: Pool<Sqlite>) {
+    // Detect the epub backend up front so a missing calibre install is reported at startup.
+    tasks::chapter_body_conversion::epub_backend().await;
+    tasks::schedule::wait_while_read_only(&pool, "background tasks").await;
+    if let Err(e) = tasks::recovery::recover(&pool).await {
+        error!(?e, "Startup recovery failed");
+    }
+
+    let mut check_for_new_chapters = Box::pin(tokio::spawn(
+        tasks::chapter_discovery::check_for_new_chap_loop(pool.clone()),
+    ));
+    let mut chapter_body_fetcher = Box::pin(tokio::spawn(
+        tasks::chapter_body_hydration::check_for_bodiless_chap_loop(pool.clone()),
+    ));
+    let mut chapter_epub_converter = Box::pin(tokio::spawn(
+        tasks::chapter_body_conversion::check_for_epubless_chap_loop(pool.clone()),
+    ));
+    let mut mailman = Box::pin(tokio::spawn(
+        tasks::delivery::check_for_ready_delivery_loop(pool.clone()),
+    ));
+    let mut integrity_scanner = Box::pin(tokio::spawn(tasks::integrity::integrity_scan_loop(
+        pool.clone(),
+    )));
+    let mut janitor = Box::pin(tokio::spawn(tasks::maintenance::maintenance_loop(
+        pool.clone(),
+    )));
+    let mut websub_publisher = Box::pin(tokio::spawn(tasks::websub::websub_publish_loop()));
+    let mut websub_renewer = Box::pin(tokio::spawn(tasks::websub::websub_renewal_loop(
+        pool.clone(),
+    )));
+    let mut announcer = Box::pin(tokio::spawn(tasks::delivery::announcement_loop(
+        pool.clone(),
+    )));
+    loop {
+        tokio::select! {
+            x = &mut check_for_new_chapters => {
+                error!("New chapter check thread failed. Restarting the thread.");
+                match x {
+                    Ok(_) => error!("New chapter check returned OK. This should not be possible."),
+                    Err(err) => error!(?err, "New chapter check has paniced. This should not be possible."),
+                };
+                check_for_new_chapters.set(tokio::spawn(tasks::chapter_discovery::check_for_new_chap_loop(pool.clone())));
+
+            }
+            x = &mut chapter_body_fetcher => {
+                error!("Chapter Body fetch thread failed. Restarting the thread.");
+                match x {
+                    Ok(_) => error!("Chapter body fetch returned OK. This should not be possible."),
+                    Err(err) => error!(?err, "Chapter body fetch has paniced. This should not be possible."),
+                };
+                chapter_body_fetcher.set(tokio::spawn(tasks::chapter_body_hydration::check_for_bodiless_chap_loop(pool.clone())));
+
+            }
+            x = &mut chapter_epub_converter => {
+                error!("Chapter epub converter thread failed. Restarting the thread.");
+                match x {
+                    Ok(_) => error!("Chapter epub converter thread returned OK. This should not be possible."),
+                    Err(err) => error!(?err, "Chapter epub converter thread has paniced. This should not be possible."),
+                };
+                chapter_epub_converter.set(tokio::spawn(tasks::chapter_body_conversion::check_for_epubless_chap_loop(pool.clone())));
+            }
+            x = &mut mailman => {
+                error!("Mailman thread failed. Restarting the thread.");
+                match x {
+                    Ok(_) => error!("Mailman thread returned OK. This should not be possible."),
+                    Err(err) => error!(?err, "Mailman thread has paniced. This should not be possible."),
+                };
+                mailman.set(tokio::spawn(tasks::delivery::check_for_ready_delivery_loop(pool.clone())));
+            }
+            x = &mut integrity_scanner => {
+                error!("Integrity scan thread failed. Restarting the thread.");
+                match x {
+                    Ok(_) => error!("Integrity scan thread returned OK. This should not be possible."),
+                    Err(err) => error!(?err, "Integrity scan thread has paniced. This should not be possible."),
+                };
+                integrity_scanner.set(tokio::spawn(tasks::integrity::integrity_scan_loop(pool.clone())));
+            }
+            x = &mut janitor => {
+                error!("Maintenance thread failed. Restarting the thread.");
+                match x {
+                    Ok(_) => error!("Maintenance thread returned OK. This should not be possible."),
+                    Err(err) => error!(?err, "Maintenance thread has paniced. This should not be possible."),
+                };
+                janitor.set(tokio::spawn(tasks::maintenance::maintenance_loop(pool.clone())));
+            }
+            x = &mut websub_publisher => {
+                error!("WebSub publisher thread failed. Restarting the thread.");
+                match x {
+                    Ok(_) => error!("WebSub publisher thread returned OK. This should not be possible."),
+                    Err(err) => error!(?err, "WebSub publisher thread has paniced. This should not be possible."),
+                };
+                websub_publisher.set(tokio::spawn(tasks::websub::websub_publish_loop()));
+            }
+            x = &mut websub_renewer => {
+                error!("WebSub renewal thread failed. Restarting the thread.");
+                match x {
+                    Ok(_) => error!("WebSub renewal thread returned OK. This should not be possible."),
+                    Err(err) => error!(?err, "WebSub renewal thread has paniced. This should not be possible."),
+                };
+                websub_renewer.set(tokio::spawn(tasks::websub::websub_renewal_loop(pool.clone())));
+            }
+            x = &mut announcer => {
+                error!("Announcement thread failed. Restarting the thread.");
+                match x {
+                    Ok(_) => error!("Announcement thread returned OK. This should not be possible."),
+                    Err(err) => error!(?err, "Announcement thread has paniced. This should not be possible."),
+                };
+                announcer.set(tokio::spawn(tasks::delivery::announcement_loop(pool.clone())));
+            }
+        }
+    }
+}
+
+fn get_server_future(
+    pool: Pool<Sqlite>,
+    cache: ResponseCache,
+) -> impl Future<Output = Result<(), hyper::Error>> {
+    let state = AppState { pool, cache };
+
+    let subscribers = subscribers::router();
+    let books = books::router();
+    let chapters = chapters::router();
+    let subscriptions = subscriptions::router();
+    let deliveries = deliveries::router();
+    let status = status::router();
+    let admin = admin::router();
+    let tags = tags::router();
+    let kosync = kosync::router();
+    let shares = shares::router();
+    let events = events::router();
+    let feeds = feeds::router();
+    let websub = websub::router();
+    let volumes = volumes::router();
+    let backfills = backfills::router();
+    let credentials = credentials::router();
+
+    let app = Router::new()
+        .merge(subscribers)
+        .merge(chapters)
+        .merge(books)
+        .merge(subscriptions)
+        .merge(deliveries)
+        .merge(status)
+        .merge(admin)
+        .merge(tags)
+        .merge(kosync)
+        .merge(shares)
+        .merge(events)
+        .merge(feeds)
+        .merge(websub)
+        .merge(volumes)
+        .merge(backfills)
+        .merge(credentials)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            read_only::reject_writes,
+        ))
+        .layer(middleware::from_fn(auth::require_scope))
+        .layer(TraceLayer::new_for_http())
+        .with_state(state);
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    axum::Server::bind(&addr).serve(app.into_make_service_with_connect_info::<SocketAddr>())
+}
+
+async fn new_db(pool: Pool<Sqlite>) -> ApiResult<()> {
+    warn!("Running schema setup script");
+    sqlx::query(&String::from_utf8_lossy(include_bytes!(
+        "../create_tables.sql"
+    )))
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    Ok(())
+}
+
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/0001_book_password.sql"),
+    include_str!("../migrations/0002_chapter_ordinal.sql"),
+    include_str!("../migrations/0003_chapter_order_index.sql"),
+    include_str!("../migrations/0004_book_conversion_options.sql"),
+    include_str!("../migrations/0005_chapter_processing_claim.sql"),
+    include_str!("../migrations/0006_task_leases.sql"),
+    include_str!("../migrations/0007_settings.sql"),
+    include_str!("../migrations/0008_book_ignore_robots_txt.sql"),
+    include_str!("../migrations/0009_chapter_trace_context.sql"),
+    include_str!("../migrations/0010_deliveries.sql"),
+    include_str!("../migrations/0011_delivery_attempts.sql"),
+    include_str!("../migrations/0012_wildcard_subscriptions.sql"),
+    include_str!("../migrations/0013_subscriber_groups.sql"),
+    include_str!("../migrations/0014_book_provider_identity.sql"),
+    include_str!("../migrations/0015_book_archives.sql"),
+    include_str!("../migrations/0016_tags.sql"),
+    include_str!("../migrations/0017_reading_progress.sql"),
+    include_str!("../migrations/0018_book_delivery_templates.sql"),
+    include_str!("../migrations/0019_chapter_digests.sql"),
+    include_str!("../migrations/0020_conversion_version.sql"),
+    include_str!("../migrations/0021_chapter_raw_titles.sql"),
+    include_str!("../migrations/0022_share_links.sql"),
+    include_str!("../migrations/0023_websub_subscriptions.sql"),
+    include_str!("../migrations/0024_delivery_embargo.sql"),
+    include_str!("../migrations/0025_public_sources.sql"),
+    include_str!("../migrations/0026_hot_path_indexes.sql"),
+    include_str!("../migrations/0027_subscriber_defaults.sql"),
+    include_str!("../migrations/0028_chapter_state.sql"),
+    include_str!("../migrations/0029_volumes.sql"),
+    include_str!("../migrations/0030_anthology_subscriptions.sql"),
+    include_str!("../migrations/0031_inline_html_delivery.sql"),
+    include_str!("../migrations/0032_read_later.sql"),
+    include_str!("../migrations/0033_notify_url.sql"),
+    include_str!("../migrations/0034_delivery_receipts.sql"),
+    include_str!("../migrations/0035_book_discovery_health.sql"),
+    include_str!("../migrations/0036_backfills.sql"),
+    include_str!("../migrations/0037_backlog_confirmation.sql"),
+    include_str!("../migrations/0038_subscriber_sunset.sql"),
+    include_str!("../migrations/0039_mailgun_usage.sql"),
+    include_str!("../migrations/0040_chapter_sources.sql"),
+    include_str!("../migrations/0041_chunk_size_policy.sql"),
+    include_str!("../migrations/0042_tag_wildcard_subscriptions.sql"),
+    include_str!("../migrations/0043_credentials.sql"),
+    include_str!("../migrations/0044_artifact_lineage.sql"),
+    include_str!("../migrations/0045_delivery_attempt_recipients.sql"),
+    include_str!("../migrations/0046_websub_unsubscriptions.sql"),
+];
+
+async fn migrate_db(pool: Pool<Sqlite>) -> ApiResult<()> {
+    // The schema version is tracked in sqlite's user_version pragma, which is 0 for a
+    // database freshly created from create_tables.sql.
+    let (version,): (i64,) = sqlx::query_as("PRAGMA user_version")
+        .fetch_one(&pool)
+        .await?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let version = index + 1;
+        warn!("Running schema migration {}", version);
+        let mut transaction = pool.begin().await?;
+        sqlx::query(migration).execute(&mut transaction).await?;
+        sqlx::query(&format!("PRAGMA user_version = {}", version))
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    util::is_foreign_key_error,
+};
+
+use super::decode_uuid;
+
+/// Subscribes a subscriber to several books at once, sending whatever new chapters they have
+/// as a single digest once every period. A regular subscription is kept for each book, so that
+/// each has its own last delivered chapter, but those are only delivered through the digest.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct AnthologySubscription {
+    pub id: Uuid,
+    #[serde(rename = "subscriberId")]
+    pub subscriber_id: Uuid,
+    pub name: String,
+    #[serde(rename = "bookIds")]
+    pub book_ids: Vec<Uuid>,
+    #[serde(rename = "periodSecs")]
+    pub period_secs: i64,
+    #[serde(rename = "lastSentAt")]
+    pub last_sent_at: Option<DateTime<Utc>>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for AnthologySubscription {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        let book_ids: String = row.try_get("book_ids")?;
+        let book_ids =
+            serde_json::from_str(&book_ids).map_err(|err| sqlx::Error::ColumnDecode {
+                index: "book_ids".into(),
+                source: Box::new(err),
+            })?;
+        Ok(AnthologySubscription {
+            id: decode_uuid(row, "id")?,
+            subscriber_id: decode_uuid(row, "subscriber_id")?,
+            name: row.try_get("name")?,
+            book_ids,
+            period_secs: row.try_get("period_secs")?,
+            last_sent_at: row.try_get("last_sent_at")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+impl AnthologySubscription {
+    /// When the next digest is sent, a period after the last one or after the anthology was
+    /// created.
+    pub fn due_at(&self) -> DateTime<Utc> {
+        self.last_sent_at.unwrap_or(self.created_at) + chrono::Duration::seconds(self.period_secs)
+    }
+}
+
+pub struct AnthologySubscriptionClient {
+    pool: Pool<Sqlite>,
+}
+
+impl AnthologySubscriptionClient {
+    pub fn new(pool: &Pool<Sqlite>) -> AnthologySubscriptionClient {
+        AnthologySubscriptionClient { pool: pool.clone() }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn create_anthology_subscription(
+        &self,
+        subscriber_id: &Uuid,
+        name: &str,
+        book_ids: &[Uuid],
+        period_secs: i64,
+    ) -> ApiResult<AnthologySubscription> {
+        let subscription = sqlx::query_as::<_, AnthologySubscription>(
+            "INSERT INTO anthology_subscriptions(id, subscriber_id, name, book_ids, period_secs, created_at, updated_at)
+            VALUES(?, ?, ?, ?, ?, ?, ?)
+            RETURNING *;",
+        )
+        .bind(Uuid::new_v4().as_bytes().as_slice())
+        .bind(subscriber_id.as_bytes().as_slice())
+        .bind(name)
+        .bind(serde_json::to_string(book_ids)?)
+        .bind(period_secs)
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match subscription {
+            Ok(subscription) => Ok(subscription),
+            Err(e) => match is_foreign_key_error(&e) {
+                true => Err(ApiError::ResourceNotFound {
+                    id: subscriber_id.to_string(),
+                    resource_type: String::from("subscriber"),
+                }),
+                false => Err(e.into()),
+            },
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn update_anthology_subscription(
+        &self,
+        id: &Uuid,
+        name: Option<&str>,
+        book_ids: Option<&[Uuid]>,
+        period_secs: Option<i64>,
+    ) -> ApiResult<AnthologySubscription> {
+        let book_ids = book_ids.map(serde_json::to_string).transpose()?;
+        let subscription = sqlx::query_as::<_, AnthologySubscription>(
+            "UPDATE anthology_subscriptions
+                 SET name = coalesce(?, name),
+                  book_ids = coalesce(?, book_ids),
+                  period_secs = coalesce(?, period_secs),
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(name)
+        .bind(book_ids)
+        .bind(period_secs)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match subscription {
+            Some(x) => Ok(x),
+            None => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("anthologySubscription"),
+            }),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn set_last_sent_at(&self, id: &Uuid, last_sent_at: &DateTime<Utc>) -> ApiResult<()> {
+        sqlx::query(
+            "UPDATE anthology_subscriptions
+                 SET last_sent_at = ?,
+                  updated_at = ?
+                 WHERE id = ?",
+        )
+        .bind(last_sent_at)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_anthology_subscription(
+        &self,
+        id: &Uuid,
+    ) -> ApiResult<Option<AnthologySubscription>> {
+        let subscription = sqlx::query_as::<_, AnthologySubscription>(
+            "SELECT * FROM anthology_subscriptions WHERE id = ?",
+        )
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(subscription)
+    }
+
+    /// Lists the subscriber's anthology subscriptions, or everyone's if `subscriber_id` is
+    /// None.
+    #[instrument(skip(self))]
+    pub async fn list_anthology_subscriptions(
+        &self,
+        subscriber_id: Option<&Uuid>,
+    ) -> ApiResult<Vec<AnthologySubscription>> {
+        let subscriptions = sqlx::query_as::<_, AnthologySubscription>(
+            "SELECT * FROM anthology_subscriptions WHERE coalesce(subscriber_id = ?, true)",
+        )
+        .bind(subscriber_id.map(|x| x.as_bytes().as_slice()))
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(subscriptions)
+    }
+
+    /// Deletes the anthology subscription along with the subscriptions it created.
+    #[instrument(skip(self))]
+    pub async fn delete_anthology_subscription(&self, id: &Uuid) -> ApiResult<()> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("DELETE FROM subscriptions WHERE anthology_subscription_id = ?")
+            .bind(id.as_bytes().as_slice())
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        sqlx::query("DELETE FROM anthology_subscriptions WHERE id = ?")
+            .bind(id.as_bytes().as_slice())
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+}
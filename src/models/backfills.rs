@@ -0,0 +1,163 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    util::is_foreign_key_error,
+};
+
+use super::decode_uuid;
+
+/// A walk through a book's backlist, for chapters older than its feed reaches. Discovery works
+/// through a few pages each run and records the page to continue from, so a backfill picks up
+/// where it left off after a restart or a failed page.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Backfill {
+    #[serde(rename = "bookId")]
+    pub book_id: Uuid,
+    #[serde(rename = "nextPage")]
+    pub next_page: i64,
+    #[serde(rename = "chaptersFound")]
+    pub chapters_found: i64,
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+    #[serde(rename = "startedAt")]
+    pub started_at: DateTime<Utc>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+    #[serde(rename = "completedAt")]
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for Backfill {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(Backfill {
+            book_id: decode_uuid(row, "book_id")?,
+            next_page: row.try_get("next_page")?,
+            chapters_found: row.try_get("chapters_found")?,
+            last_error: row.try_get("last_error")?,
+            started_at: row.try_get("started_at")?,
+            updated_at: row.try_get("updated_at")?,
+            completed_at: row.try_get("completed_at")?,
+        })
+    }
+}
+
+pub struct BackfillClient {
+    pool: Pool<Sqlite>,
+}
+
+impl BackfillClient {
+    pub fn new(pool: &Pool<Sqlite>) -> BackfillClient {
+        BackfillClient { pool: pool.clone() }
+    }
+
+    /// Starts a backfill of the book from its first page. A backfill already in progress is
+    /// left to continue unless `restart` is set, while a completed one always starts over.
+    #[instrument(skip(self))]
+    pub async fn start_backfill(&self, book_id: &Uuid, restart: bool) -> ApiResult<Backfill> {
+        let started = sqlx::query_as::<_, Backfill>(
+            "INSERT INTO backfills(book_id, started_at, updated_at)
+                 VALUES(?, ?, ?)
+                 ON CONFLICT(book_id) DO UPDATE
+                 SET next_page = 1,
+                  chapters_found = 0,
+                  last_error = NULL,
+                  started_at = excluded.started_at,
+                  updated_at = excluded.updated_at,
+                  completed_at = NULL
+                 WHERE ? OR backfills.completed_at IS NOT NULL
+                 RETURNING *;",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .bind(restart)
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match started {
+            Ok(Some(x)) => Ok(x),
+            Ok(None) => {
+                self.get_backfill(book_id)
+                    .await?
+                    .ok_or_else(|| ApiError::ResourceNotFound {
+                        id: book_id.to_string(),
+                        resource_type: String::from("backfill"),
+                    })
+            }
+            Err(e) => match is_foreign_key_error(&e) {
+                true => Err(ApiError::ResourceNotFound {
+                    id: book_id.to_string(),
+                    resource_type: String::from("book"),
+                }),
+                false => Err(e.into()),
+            },
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_backfill(&self, book_id: &Uuid) -> ApiResult<Option<Backfill>> {
+        let backfill = sqlx::query_as::<_, Backfill>("SELECT * FROM backfills WHERE book_id = ?")
+            .bind(book_id.as_bytes().as_slice())
+            .fetch_optional(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(backfill)
+    }
+
+    /// Records that a page was processed, so the backfill continues from `next_page`.
+    #[instrument(skip(self))]
+    pub async fn advance_backfill(
+        &self,
+        book_id: &Uuid,
+        next_page: i64,
+        chapters_found: i64,
+    ) -> ApiResult<()> {
+        sqlx::query(
+            "UPDATE backfills
+                 SET next_page = ?,
+                  chapters_found = chapters_found + ?,
+                  last_error = NULL,
+                  updated_at = ?
+                 WHERE book_id = ?",
+        )
+        .bind(next_page)
+        .bind(chapters_found)
+        .bind(Utc::now())
+        .bind(book_id.as_bytes().as_slice())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(())
+    }
+
+    /// Records why the backfill's next page couldn't be processed. It is tried again on the
+    /// next run.
+    #[instrument(skip(self))]
+    pub async fn record_backfill_error(&self, book_id: &Uuid, error: &str) -> ApiResult<()> {
+        sqlx::query("UPDATE backfills SET last_error = ?, updated_at = ? WHERE book_id = ?")
+            .bind(error)
+            .bind(Utc::now())
+            .bind(book_id.as_bytes().as_slice())
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn complete_backfill(&self, book_id: &Uuid) -> ApiResult<()> {
+        sqlx::query("UPDATE backfills SET completed_at = ?, updated_at = ? WHERE book_id = ?")
+            .bind(Utc::now())
+            .bind(Utc::now())
+            .bind(book_id.as_bytes().as_slice())
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use std::io;
+
+use chrono::{DateTime, Utc};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use sqlx::{Pool, Sqlite};
+use tracing::{info_span, Instrument};
+
+use crate::error::{ApiError, ApiResult};
+
+/// How much of a body each chunk of the stream holds.
+const CHUNK_BYTES: usize = 256 * 1024;
+
+/// A stored body which is read from the database once it is consumed, rather than when it is
+/// opened, and handed out a chunk at a time.
+pub struct BlobStream {
+    pub length: i64,
+    /// The hex encoded SHA-256 recorded when the body was stored, if any.
+    pub digest: Option<String>,
+    pub updated_at: DateTime<Utc>,
+    pub chunks: BoxStream<'static, ApiResult<Vec<u8>>>,
+}
+
+/// Where a blob is stored, and the column which changes whenever it is replaced.
+pub(super) struct BlobLocation {
+    pub table: &'static str,
+    pub column: &'static str,
+    pub version_column: &'static str,
+    pub rowid: i64,
+}
+
+/// Reads the blob with a single query once the stream is first polled, then hands it out a chunk
+/// at a time. Reading it a `substr` per chunk instead loaded the whole blob for every chunk, as
+/// sqlx has no incremental blob I/O. The stream fails if the blob was replaced since `length`
+/// and `version` were read.
+pub(super) fn read_chunks(
+    pool: &Pool<Sqlite>,
+    location: BlobLocation,
+    length: i64,
+    version: Option<String>,
+) -> BoxStream<'static, ApiResult<Vec<u8>>> {
+    let query = format!(
+        "SELECT CAST({column} AS BLOB) FROM {table}
+             WHERE rowid = ? AND CAST({version_column} AS TEXT) IS ?",
+        column = location.column,
+        table = location.table,
+        version_column = location.version_column,
+    );
+    let (pool, rowid) = (pool.clone(), location.rowid);
+    let blob = async move {
+        let blob: Option<(Vec<u8>,)> = sqlx::query_as(&query)
+            .bind(rowid)
+            .bind(version)
+            .fetch_optional(&pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        match blob {
+            Some((blob,)) if blob.len() as i64 == length => Ok(blob),
+            _ => Err(ApiError::Io(io::Error::other(
+                "The body was replaced before it was read",
+            ))),
+        }
+    };
+    futures::stream::once(blob)
+        .map_ok(|blob| {
+            futures::stream::iter(0..blob.len().div_ceil(CHUNK_BYTES)).map(move |index| {
+                let start = index * CHUNK_BYTES;
+                Ok(blob[start..blob.len().min(start + CHUNK_BYTES)].to_vec())
+            })
+        })
+        .try_flatten()
+        .boxed()
+}
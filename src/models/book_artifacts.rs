@@ -0,0 +1,236 @@
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    util::{content_digest, is_foreign_key_error},
+};
+
+use super::{
+    blobs::{read_chunks, BlobLocation, BlobStream},
+    decode_uuid, Chapter,
+};
+
+/// The epub of every chapter of a book, generated when it is archived.
+pub const OMNIBUS_ARTIFACT: &str = "omnibus";
+
+pub struct BookArtifactClient {
+    pool: Pool<Sqlite>,
+}
+
+/// A file generated for a whole book rather than a single chapter. A book has at most one
+/// artifact of each kind.
+#[derive(PartialEq, Clone, Serialize)]
+pub struct BookArtifact {
+    pub id: Uuid,
+    #[serde(rename = "bookId")]
+    pub book_id: Uuid,
+    pub kind: String,
+    #[serde(skip)]
+    pub content: Vec<u8>,
+    /// How many chapters the artifact was generated from.
+    pub chapters: i64,
+    #[serde(rename = "createdAt")]
+    pub created_at: chrono::DateTime<Utc>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: chrono::DateTime<Utc>,
+}
+
+impl std::fmt::Debug for BookArtifact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BookArtifact")
+            .field("id", &self.id)
+            .field("book_id", &self.book_id)
+            .field("kind", &self.kind)
+            .field("content_bytes", &self.content.len())
+            .field("chapters", &self.chapters)
+            .field("created_at", &self.created_at)
+            .field("updated_at", &self.updated_at)
+            .finish()
+    }
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for BookArtifact {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(BookArtifact {
+            id: decode_uuid(row, "id")?,
+            book_id: decode_uuid(row, "book_id")?,
+            kind: row.try_get("kind")?,
+            content: row.try_get("content")?,
+            chapters: row.try_get("chapters")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+impl BookArtifactClient {
+    pub fn new(pool: &Pool<Sqlite>) -> BookArtifactClient {
+        BookArtifactClient { pool: pool.clone() }
+    }
+
+    /// Stores the artifact, replacing any earlier artifact of the same kind, along with its
+    /// lineage: the body of each chapter it was generated from, and the conversion version and
+    /// options it was generated with.
+    #[instrument(skip(self, content, chapters))]
+    pub async fn save_artifact(
+        &self,
+        book_id: &Uuid,
+        kind: &str,
+        content: &[u8],
+        chapters: &[Chapter],
+        conversion_version: i64,
+    ) -> ApiResult<BookArtifact> {
+        let mut transaction = self.pool.begin().await?;
+        let artifact = sqlx::query_as::<_, BookArtifact>(
+            "INSERT INTO book_artifacts(id, book_id, kind, content, chapters, conversion_version, conversion_options, created_at, updated_at)
+                 VALUES(?, ?, ?, ?, ?, ?, (SELECT conversion_options FROM books WHERE id = ?), ?, ?)
+                 ON CONFLICT(book_id, kind) DO UPDATE
+                 SET content = excluded.content,
+                  chapters = excluded.chapters,
+                  conversion_version = excluded.conversion_version,
+                  conversion_options = excluded.conversion_options,
+                  updated_at = excluded.updated_at
+                 RETURNING *;",
+        )
+        .bind(Uuid::new_v4().as_bytes().as_slice())
+        .bind(book_id.as_bytes().as_slice())
+        .bind(kind)
+        .bind(content)
+        .bind(chapters.len() as i64)
+        .bind(conversion_version)
+        .bind(book_id.as_bytes().as_slice())
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .fetch_one(&mut transaction)
+        .instrument(info_span!("Querying db"))
+        .await;
+        let artifact = match artifact {
+            Ok(artifact) => artifact,
+            Err(e) if is_foreign_key_error(&e) => {
+                return Err(ApiError::ResourceNotFound {
+                    id: book_id.to_string(),
+                    resource_type: String::from("book"),
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        sqlx::query("DELETE FROM book_artifact_sources WHERE artifact_id = ?")
+            .bind(artifact.id.as_bytes().as_slice())
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        for chapter in chapters {
+            // Bodies stored before digests were kept have none until the integrity scan runs.
+            let html_digest = match (&chapter.html_digest, &chapter.html) {
+                (Some(digest), _) => digest.clone(),
+                (None, Some(html)) => content_digest(html),
+                (None, None) => continue,
+            };
+            sqlx::query(
+                "INSERT INTO book_artifact_sources(artifact_id, chapter_id, html_digest)
+                     VALUES(?, ?, ?);",
+            )
+            .bind(artifact.id.as_bytes().as_slice())
+            .bind(chapter.id.as_bytes().as_slice())
+            .bind(html_digest)
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(artifact)
+    }
+
+    /// Removes artifacts superseded since they were generated: one of their chapters was
+    /// revised or deleted, or the book's conversion options or the conversion version changed.
+    /// Only artifacts which can be regenerated are removed, so a book with any chapter lacking
+    /// its body, such as one pruned when it was archived, keeps its artifacts. Artifacts saved
+    /// before lineage was recorded are kept too. Returns the number removed.
+    #[instrument(skip(self))]
+    pub async fn prune_superseded_artifacts(&self, conversion_version: i64) -> ApiResult<u64> {
+        let result = sqlx::query(
+            "DELETE FROM book_artifacts
+                 WHERE id IN (
+                  SELECT a.id
+                  FROM book_artifacts a
+                  JOIN books b ON b.id = a.book_id
+                  WHERE a.conversion_version IS NOT NULL
+                   AND NOT EXISTS (
+                    SELECT 1 FROM chapters c
+                    WHERE c.book_id = a.book_id AND (c.html IS NULL OR c.pruned_at IS NOT NULL)
+                   )
+                   AND (
+                    a.conversion_version < ?
+                    OR a.conversion_options IS NOT b.conversion_options
+                    OR EXISTS (
+                     SELECT 1 FROM book_artifact_sources s
+                     LEFT JOIN chapters c ON c.id = s.chapter_id
+                     WHERE s.artifact_id = a.id
+                      AND (c.id IS NULL OR coalesce(c.html_digest, s.html_digest) IS NOT s.html_digest)
+                    )
+                   )
+                 );",
+        )
+        .bind(conversion_version)
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_artifact(
+        &self,
+        book_id: &Uuid,
+        kind: &str,
+    ) -> ApiResult<Option<BookArtifact>> {
+        let artifact = sqlx::query_as::<_, BookArtifact>(
+            "SELECT * FROM book_artifacts WHERE book_id = ? AND kind = ?",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .bind(kind)
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(artifact)
+    }
+
+    /// Streams the artifact's content, read once the stream is consumed.
+    #[instrument(skip(self))]
+    pub async fn stream_artifact(
+        &self,
+        book_id: &Uuid,
+        kind: &str,
+    ) -> ApiResult<Option<BlobStream>> {
+        let row: Option<(i64, i64, String, chrono::DateTime<Utc>)> = sqlx::query_as(
+            "SELECT rowid, length(content), CAST(updated_at AS TEXT), updated_at
+                 FROM book_artifacts WHERE book_id = ? AND kind = ?",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .bind(kind)
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        let (rowid, length, version, updated_at) = match row {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let location = BlobLocation {
+            table: "book_artifacts",
+            column: "content",
+            version_column: "updated_at",
+            rowid,
+        };
+        Ok(Some(BlobStream {
+            length,
+            digest: None,
+            updated_at,
+            chunks: read_chunks(&self.pool, location, length, Some(version)),
+        }))
+    }
+}
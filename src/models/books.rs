@@ -4,9 +4,28 @@ use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
 use tracing::{info_span, instrument, Instrument};
 use uuid::Uuid;
 
-use crate::error::{ApiError, ApiResult};
+use crate::{
+    error::{ApiError, ApiResult},
+    util::is_unique_error,
+};
 
-use super::decode_uuid;
+use super::{
+    changes::{publish, Change, ChangeKind, Entity},
+    decode_optional_uuid, decode_uuid, ChapterClient, CredentialClient,
+};
+
+/// Books with a summary of their chapters. The latest chapter is the last in reading order,
+/// dated by when it was published or otherwise found. The backlog counts chapters still to be
+/// fetched or converted.
+const BOOK_SUMMARIES: &str = "SELECT books.*,
+         count(chapters.id) AS chapter_count,
+         coalesce(sum(chapters.state IN ('discovered', 'hydrated')), 0) AS pipeline_backlog,
+         (SELECT title FROM chapters AS latest WHERE latest.book_id = books.id
+             ORDER BY order_index DESC LIMIT 1) AS latest_chapter_title,
+         (SELECT coalesce(published_at, created_at) FROM chapters AS latest
+             WHERE latest.book_id = books.id ORDER BY order_index DESC LIMIT 1) AS latest_chapter_at
+     FROM books
+     LEFT JOIN chapters ON chapters.book_id = books.id";
 
 pub struct BookClient {
     pool: Pool<Sqlite>,
@@ -14,13 +33,192 @@ pub struct BookClient {
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum BookMetadata {
-    RoyalRoad { book_id: u64 },
+    RoyalRoad {
+        book_id: u64,
+        #[serde(default)]
+        options: RoyalRoadOptions,
+    },
     Pale,
     TheWanderingInnPatreon,
     TheDailyGrindPatreon,
     ApparatusOfChangePatreon,
 }
 
+/// Controls how royalroad chapter pages are cleaned up before their bodies are stored.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoyalRoadOptions {
+    /// Keep the author's note shown above the chapter.
+    pub keep_author_note_before: bool,
+    /// Keep the author's note shown below the chapter.
+    pub keep_author_note_after: bool,
+    /// Show the contents of spoiler blocks instead of dropping them behind a button.
+    pub expand_spoilers: bool,
+    /// Remove the hidden anti-piracy paragraphs royalroad injects into chapters.
+    pub remove_watermarks: bool,
+}
+
+impl Default for RoyalRoadOptions {
+    fn default() -> Self {
+        Self {
+            keep_author_note_before: false,
+            keep_author_note_after: false,
+            expand_spoilers: false,
+            remove_watermarks: true,
+        }
+    }
+}
+
+/// Per-book settings passed through to calibre when chapters are converted to epubs.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConversionOptions {
+    /// CSS properties removed from the source html. Defaults to fonts and colors.
+    pub filter_css: Option<Vec<String>>,
+    /// CSS appended to the stylesheet of the generated epub.
+    pub extra_css: Option<String>,
+    /// Font family embedded into the generated epub.
+    pub embed_font_family: Option<String>,
+    pub embed_all_fonts: bool,
+    /// Line height in points.
+    pub line_height: Option<f32>,
+    /// Additional arguments passed to ebook-convert, limited to options which only change the
+    /// output.
+    pub extra_args: Vec<String>,
+    /// Language code written to the epub metadata, e.g. "en".
+    pub language: Option<String>,
+    /// Publisher written to the epub metadata.
+    pub publisher: Option<String>,
+    /// Applied in order to each chapter's html before it is converted, to fix the serial's
+    /// recurring formatting quirks.
+    pub transforms: Vec<HtmlTransform>,
+}
+
+/// A change made to chapter html before conversion.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase", deny_unknown_fields)]
+pub enum HtmlTransform {
+    /// Replaces every match of the regex. The replacement may refer to groups as `$1`.
+    Replace {
+        pattern: String,
+        #[serde(default)]
+        replacement: String,
+    },
+    /// Removes the elements matching the css selector, along with their contents.
+    Strip { selector: String },
+    /// Replaces each link to a footnote within the chapter with the footnote's text, and
+    /// removes the footnote. Links are matched by `references`, defaulting to any link to an
+    /// anchor in the page.
+    #[serde(rename_all = "camelCase")]
+    InlineFootnotes {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        references: Option<String>,
+    },
+    /// Moves the chapter's footnotes to endnotes at its end, linked from their references and
+    /// back, which epub readers show as popups. Footnotes are found as links to an element in
+    /// the chapter, or else as `[1]` or a superscript number with a later paragraph starting
+    /// with the same marker.
+    Endnotes,
+    /// Cleans up the typography of plain text sources, which read poorly on e-ink otherwise.
+    Typography(TypographyOptions),
+}
+
+/// The passes of the typography transform, each on unless turned off.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct TypographyOptions {
+    /// Curl straight quotes and apostrophes.
+    pub quotes: bool,
+    /// Replace `--` with an em dash.
+    pub dashes: bool,
+    /// Replace `...` with an ellipsis.
+    pub ellipses: bool,
+    /// Replace paragraphs of only `* * *`, `###`, `~~~` and the like with a rule.
+    pub scene_breaks: bool,
+}
+
+impl Default for TypographyOptions {
+    fn default() -> Self {
+        Self {
+            quotes: true,
+            dashes: true,
+            ellipses: true,
+            scene_breaks: true,
+        }
+    }
+}
+
+impl TryFrom<(&SqliteRow, &str)> for ConversionOptions {
+    type Error = sqlx::Error;
+
+    fn try_from(value: (&SqliteRow, &str)) -> core::result::Result<Self, Self::Error> {
+        let (row, index) = value;
+        let options: String = row.try_get(index)?;
+        let options = serde_json::from_str(&options).map_err(|err| sqlx::Error::ColumnDecode {
+            index: index.into(),
+            source: Box::new(err),
+        })?;
+        Ok(options)
+    }
+}
+
+impl ConversionOptions {
+    pub fn json(&self) -> ApiResult<String> {
+        let json = serde_json::to_string(self)?;
+        Ok(json)
+    }
+}
+
+/// Templates for the names of delivered epubs, the subjects of their emails and the push
+/// notifications announcing them. Templates may use the variables listed in
+/// `TEMPLATE_VARIABLES`, e.g. `{sequence} {book} - {chapter}` or `{count} new chapters`.
+/// Unset templates fall back to the configured defaults.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeliveryTemplates {
+    #[serde(rename = "fileName", skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// The pushover message sent for a delivery.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notification: Option<String>,
+}
+
+impl TryFrom<(&SqliteRow, &str)> for DeliveryTemplates {
+    type Error = sqlx::Error;
+
+    fn try_from(value: (&SqliteRow, &str)) -> core::result::Result<Self, Self::Error> {
+        let (row, index) = value;
+        let templates: String = row.try_get(index)?;
+        let templates =
+            serde_json::from_str(&templates).map_err(|err| sqlx::Error::ColumnDecode {
+                index: index.into(),
+                source: Box::new(err),
+            })?;
+        Ok(templates)
+    }
+}
+
+impl DeliveryTemplates {
+    pub fn json(&self) -> ApiResult<String> {
+        let json = serde_json::to_string(self)?;
+        Ok(json)
+    }
+
+    /// Returns these templates with any left unset taken from `base`.
+    pub fn or(&self, base: &DeliveryTemplates) -> DeliveryTemplates {
+        DeliveryTemplates {
+            file_name: self.file_name.clone().or_else(|| base.file_name.clone()),
+            subject: self.subject.clone().or_else(|| base.subject.clone()),
+            notification: self
+                .notification
+                .clone()
+                .or_else(|| base.notification.clone()),
+        }
+    }
+}
+
 impl TryFrom<(&SqliteRow, &str)> for BookMetadata {
     type Error = sqlx::Error;
 
@@ -41,20 +239,99 @@ impl BookMetadata {
         let json = serde_json::to_string(self)?;
         Ok(json)
     }
+
+    /// Identifies the serial at its source regardless of options, so two books can't fetch
+    /// the same serial.
+    pub fn provider_identity(&self) -> String {
+        match self {
+            BookMetadata::RoyalRoad { book_id, .. } => format!("RoyalRoad:{}", book_id),
+            BookMetadata::Pale => String::from("Pale"),
+            BookMetadata::TheWanderingInnPatreon => String::from("TheWanderingInnPatreon"),
+            BookMetadata::TheDailyGrindPatreon => String::from("TheDailyGrindPatreon"),
+            BookMetadata::ApparatusOfChangePatreon => String::from("ApparatusOfChangePatreon"),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize)]
+#[derive(PartialEq, Clone, Serialize)]
 pub struct Book {
     pub id: Uuid,
     pub title: String,
     pub author: String,
     pub metadata: BookMetadata,
+    /// Never sent back to clients or logged, only used when fetching the book's chapters.
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    /// Names the credential holding the password to the book's chapters, used when neither
+    /// the chapter nor the book has a password of its own.
+    #[serde(rename = "passwordCredential", skip_serializing_if = "Option::is_none")]
+    pub password_credential: Option<String>,
+    #[serde(rename = "conversionOptions")]
+    pub conversion_options: ConversionOptions,
+    #[serde(rename = "deliveryTemplates")]
+    pub delivery_templates: DeliveryTemplates,
+    /// Fetch chapters even where the source's robots.txt disallows it.
+    #[serde(rename = "ignoreRobotsTxt")]
+    pub ignore_robots_txt: bool,
+    /// New chapters are held back from subscriptions without early access until this long
+    /// after they were published, for sources which release to patrons first.
+    #[serde(rename = "earlyAccessSecs")]
+    pub early_access_secs: Option<i64>,
+    /// Sort new chapters into volumes detected from their titles.
+    #[serde(rename = "detectVolumes")]
+    pub detect_volumes: bool,
+    /// Where the book's chapters are released publicly, when the primary source releases them
+    /// to patrons first. Public chapters release and retitle their early counterparts.
+    #[serde(rename = "publicMetadata", skip_serializing_if = "Option::is_none")]
+    pub public_metadata: Option<BookMetadata>,
+    /// Set on books which duplicated another book's source. Aliases aren't fetched and their
+    /// subscriptions were moved to the canonical book.
+    #[serde(rename = "canonicalBookId", skip_serializing_if = "Option::is_none")]
+    pub canonical_book_id: Option<Uuid>,
+    /// Set once the serial is finished. Archived books are no longer checked for new chapters.
+    #[serde(rename = "archivedAt", skip_serializing_if = "Option::is_none")]
+    pub archived_at: Option<chrono::DateTime<Utc>>,
+    /// The last time the book, and its public source if it has one, was checked for new
+    /// chapters without error.
+    #[serde(rename = "lastDiscoveredAt")]
+    pub last_discovered_at: Option<chrono::DateTime<Utc>>,
+    /// Checks for new chapters which failed since the last which didn't.
+    #[serde(rename = "discoveryFailures")]
+    pub discovery_failures: i64,
+    #[serde(rename = "lastDiscoveryError")]
+    pub last_discovery_error: Option<String>,
     #[serde(rename = "createdAt")]
     pub created_at: chrono::DateTime<Utc>,
     #[serde(rename = "updatedAt")]
     pub updated_at: chrono::DateTime<Utc>,
 }
 
+impl std::fmt::Debug for Book {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Book")
+            .field("id", &self.id)
+            .field("title", &self.title)
+            .field("author", &self.author)
+            .field("metadata", &self.metadata)
+            .field("password_set", &self.password.is_some())
+            .field("password_credential", &self.password_credential)
+            .field("conversion_options", &self.conversion_options)
+            .field("delivery_templates", &self.delivery_templates)
+            .field("ignore_robots_txt", &self.ignore_robots_txt)
+            .field("early_access_secs", &self.early_access_secs)
+            .field("detect_volumes", &self.detect_volumes)
+            .field("public_metadata", &self.public_metadata)
+            .field("canonical_book_id", &self.canonical_book_id)
+            .field("archived_at", &self.archived_at)
+            .field("last_discovered_at", &self.last_discovered_at)
+            .field("discovery_failures", &self.discovery_failures)
+            .field("last_discovery_error", &self.last_discovery_error)
+            .field("created_at", &self.created_at)
+            .field("updated_at", &self.updated_at)
+            .finish()
+    }
+}
+
 impl<'r> sqlx::FromRow<'r, SqliteRow> for Book {
     fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
         Ok(Book {
@@ -62,65 +339,359 @@ impl<'r> sqlx::FromRow<'r, SqliteRow> for Book {
             title: row.try_get("title")?,
             author: row.try_get("author")?,
             metadata: (row, "metadata").try_into()?,
+            password: row.try_get("password")?,
+            password_credential: row.try_get("password_credential")?,
+            conversion_options: (row, "conversion_options").try_into()?,
+            delivery_templates: (row, "delivery_templates").try_into()?,
+            ignore_robots_txt: row.try_get("ignore_robots_txt")?,
+            early_access_secs: row.try_get("early_access_secs")?,
+            detect_volumes: row.try_get("detect_volumes")?,
+            public_metadata: match row.try_get::<Option<String>, _>("public_metadata")? {
+                Some(_) => Some((row, "public_metadata").try_into()?),
+                None => None,
+            },
+            canonical_book_id: decode_optional_uuid(row, "canonical_book_id")?,
+            archived_at: row.try_get("archived_at")?,
+            last_discovered_at: row.try_get("last_discovered_at")?,
+            discovery_failures: row.try_get("discovery_failures")?,
+            last_discovery_error: row.try_get("last_discovery_error")?,
             created_at: row.try_get("created_at")?,
             updated_at: row.try_get("updated_at")?,
         })
     }
 }
 
+/// A book along with how many chapters it has, its latest, and how many are still in the
+/// pipeline, as listed on a dashboard.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct BookSummary {
+    #[serde(flatten)]
+    pub book: Book,
+    #[serde(rename = "chapterCount")]
+    pub chapter_count: i64,
+    #[serde(rename = "latestChapterTitle")]
+    pub latest_chapter_title: Option<String>,
+    #[serde(rename = "latestChapterAt")]
+    pub latest_chapter_at: Option<chrono::DateTime<Utc>>,
+    #[serde(rename = "pipelineBacklog")]
+    pub pipeline_backlog: i64,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for BookSummary {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(BookSummary {
+            book: Book::from_row(row)?,
+            chapter_count: row.try_get("chapter_count")?,
+            latest_chapter_title: row.try_get("latest_chapter_title")?,
+            latest_chapter_at: row.try_get("latest_chapter_at")?,
+            pipeline_backlog: row.try_get("pipeline_backlog")?,
+        })
+    }
+}
+
 impl BookClient {
     pub fn new(pool: &Pool<Sqlite>) -> BookClient {
         BookClient { pool: pool.clone() }
     }
 
+    /// Creates the book, or returns the existing book if one already fetches the same serial.
     #[instrument(skip(self))]
     pub async fn create_book(
         &self,
         title: &str,
         author: &str,
         metadata: &BookMetadata,
+        conversion_options: &ConversionOptions,
+        delivery_templates: &DeliveryTemplates,
+        ignore_robots_txt: bool,
     ) -> ApiResult<Book> {
         let book = sqlx::query_as::<_, Book>(
-            "INSERT INTO books(id, title, author, metadata, created_at, updated_at) 
-            VALUES(?, ?, ?, ?, ?, ?) 
+            "INSERT INTO books(id, title, author, metadata, provider_identity, conversion_options, delivery_templates, ignore_robots_txt, created_at, updated_at) 
+            VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?) 
+            ON CONFLICT(provider_identity) DO NOTHING
             RETURNING *;",
         )
         .bind(Uuid::new_v4().as_bytes().as_slice())
         .bind(title)
         .bind(author)
         .bind(metadata.json()?)
+        .bind(metadata.provider_identity())
+        .bind(conversion_options.json()?)
+        .bind(delivery_templates.json()?)
+        .bind(ignore_robots_txt)
         .bind(Utc::now())
         .bind(Utc::now())
-        .fetch_one(&self.pool)
+        .fetch_optional(&self.pool)
         .instrument(info_span!("Querying db"))
         .await?;
-        Ok(book)
+        match book {
+            Some(x) => {
+                publish(Change::new(Entity::Book, x.id, ChangeKind::Created));
+                Ok(x)
+            }
+            None => self
+                .get_book_by_provider_identity(&metadata.provider_identity())
+                .await?
+                .ok_or_else(|| ApiError::InvalidRequest(String::from("Failed to create book"))),
+        }
     }
 
+    /// Updates the fields given. Changing the book's html transforms clears its chapters' epubs,
+    /// so the conversion loop converts them again with the new transforms.
+    #[allow(clippy::too_many_arguments)]
     #[instrument(skip(self))]
     pub async fn update_book(
         &self,
         id: &Uuid,
         title: Option<&str>,
         author: Option<&str>,
+        metadata: Option<&BookMetadata>,
+        conversion_options: Option<&ConversionOptions>,
+        delivery_templates: Option<&DeliveryTemplates>,
+        ignore_robots_txt: Option<bool>,
     ) -> ApiResult<Book> {
+        let previous_transforms = match conversion_options {
+            Some(_) => self
+                .get_book(id)
+                .await?
+                .map(|x| x.conversion_options.transforms),
+            None => None,
+        };
         let book = sqlx::query_as::<_, Book>(
             "UPDATE books
                  SET title = coalesce(?, title),
                   author = coalesce(?, author), 
+                  metadata = coalesce(?, metadata), 
+                  provider_identity = coalesce(?, provider_identity), 
+                  conversion_options = coalesce(?, conversion_options), 
+                  delivery_templates = coalesce(?, delivery_templates), 
+                  ignore_robots_txt = coalesce(?, ignore_robots_txt), 
                   updated_at = ?
                  WHERE id = ? 
                  RETURNING *;",
         )
         .bind(title)
         .bind(author)
+        .bind(metadata.map(|x| x.json()).transpose()?)
+        .bind(metadata.map(|x| x.provider_identity()))
+        .bind(conversion_options.map(|x| x.json()).transpose()?)
+        .bind(delivery_templates.map(|x| x.json()).transpose()?)
+        .bind(ignore_robots_txt)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        let book = match book {
+            Err(e) if is_unique_error(&e) => {
+                return Err(ApiError::InvalidRequest(String::from(
+                    "Another book already fetches this serial",
+                )))
+            }
+            x => x?,
+        };
+        match book {
+            Some(x) => {
+                publish(Change::new(Entity::Book, x.id, ChangeKind::Updated));
+                if previous_transforms.is_some_and(|t| t != x.conversion_options.transforms) {
+                    ChapterClient::new(&self.pool)
+                        .clear_epubs(Some(&x.id), None)
+                        .await?;
+                }
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("book"),
+            }),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn set_public_metadata(
+        &self,
+        id: &Uuid,
+        public_metadata: Option<&BookMetadata>,
+    ) -> ApiResult<Book> {
+        let book = sqlx::query_as::<_, Book>(
+            "UPDATE books
+                 SET public_metadata = ?,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(public_metadata.map(|x| x.json()).transpose()?)
         .bind(Utc::now())
         .bind(id.as_bytes().as_slice())
         .fetch_optional(&self.pool)
         .instrument(info_span!("Querying db"))
         .await?;
         match book {
-            Some(x) => Ok(x),
+            Some(x) => {
+                publish(Change::new(Entity::Book, x.id, ChangeKind::Updated));
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("book"),
+            }),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn set_early_access_secs(
+        &self,
+        id: &Uuid,
+        early_access_secs: Option<i64>,
+    ) -> ApiResult<Book> {
+        let book = sqlx::query_as::<_, Book>(
+            "UPDATE books
+                 SET early_access_secs = ?,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(early_access_secs)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match book {
+            Some(x) => {
+                publish(Change::new(Entity::Book, x.id, ChangeKind::Updated));
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("book"),
+            }),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn set_detect_volumes(&self, id: &Uuid, detect_volumes: bool) -> ApiResult<Book> {
+        let book = sqlx::query_as::<_, Book>(
+            "UPDATE books
+                 SET detect_volumes = ?,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(detect_volumes)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match book {
+            Some(x) => {
+                publish(Change::new(Entity::Book, x.id, ChangeKind::Updated));
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("book"),
+            }),
+        }
+    }
+
+    /// Records a check of the book for new chapters, which failed if there is an error. This
+    /// isn't an edit to the book, so it isn't published as a change.
+    #[instrument(skip(self))]
+    pub async fn record_discovery(&self, id: &Uuid, error: Option<&str>) -> ApiResult<()> {
+        let query = match error {
+            Some(_) => sqlx::query(
+                "UPDATE books
+                     SET discovery_failures = discovery_failures + 1,
+                      last_discovery_error = ?
+                     WHERE id = ?",
+            )
+            .bind(error),
+            None => sqlx::query(
+                "UPDATE books
+                     SET discovery_failures = 0,
+                      last_discovery_error = NULL,
+                      last_discovered_at = ?
+                     WHERE id = ?",
+            )
+            .bind(Utc::now()),
+        };
+        query
+            .bind(id.as_bytes().as_slice())
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self, password))]
+    pub async fn set_book_password(
+        &self,
+        id: &Uuid,
+        password: Option<&str>,
+        password_credential: Option<&str>,
+    ) -> ApiResult<Book> {
+        if let Some(name) = password_credential {
+            if CredentialClient::new(&self.pool)
+                .get_credential(name)
+                .await?
+                .is_none()
+            {
+                return Err(ApiError::ResourceNotFound {
+                    id: name.to_owned(),
+                    resource_type: String::from("credential"),
+                });
+            }
+        }
+        let book = sqlx::query_as::<_, Book>(
+            "UPDATE books
+                 SET password = ?,
+                  password_credential = ?,
+                  updated_at = ?
+                 WHERE id = ? 
+                 RETURNING *;",
+        )
+        .bind(password)
+        .bind(password_credential)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match book {
+            Some(x) => {
+                publish(Change::new(Entity::Book, x.id, ChangeKind::Updated));
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("book"),
+            }),
+        }
+    }
+
+    /// Marks the book as archived, keeping the original time if it already was.
+    #[instrument(skip(self))]
+    pub async fn archive_book(&self, id: &Uuid) -> ApiResult<Book> {
+        let book = sqlx::query_as::<_, Book>(
+            "UPDATE books
+                 SET archived_at = coalesce(archived_at, ?),
+                  updated_at = ?
+                 WHERE id = ? 
+                 RETURNING *;",
+        )
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match book {
+            Some(x) => {
+                publish(Change::new(Entity::Book, x.id, ChangeKind::Updated));
+                Ok(x)
+            }
             None => Err(ApiError::ResourceNotFound {
                 id: id.to_string(),
                 resource_type: String::from("book"),
@@ -138,6 +709,31 @@ impl BookClient {
         Ok(book)
     }
 
+    #[instrument(skip(self))]
+    pub async fn get_book_by_provider_identity(&self, identity: &str) -> ApiResult<Option<Book>> {
+        let book = sqlx::query_as::<_, Book>("SELECT * FROM books WHERE provider_identity = ?")
+            .bind(identity)
+            .fetch_optional(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(book)
+    }
+
+    /// Books titled `title`, ignoring case. Aliases are left out, as they share their canonical
+    /// book's title.
+    #[instrument(skip(self))]
+    pub async fn find_books_by_title(&self, title: &str) -> ApiResult<Vec<Book>> {
+        let books = sqlx::query_as::<_, Book>(
+            "SELECT * FROM books
+                 WHERE title = ? COLLATE NOCASE AND canonical_book_id IS NULL",
+        )
+        .bind(title.trim())
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(books)
+    }
+
     #[instrument(skip(self))]
     pub async fn list_books(&self) -> ApiResult<Vec<Book>> {
         let books = sqlx::query_as::<_, Book>("SELECT * FROM books")
@@ -147,6 +743,37 @@ impl BookClient {
         Ok(books)
     }
 
+    #[instrument(skip(self))]
+    pub async fn list_books_with_tag(&self, tag_id: &Uuid) -> ApiResult<Vec<Book>> {
+        let books = sqlx::query_as::<_, Book>(
+            "SELECT books.* FROM books
+                 JOIN book_tags ON book_tags.book_id = books.id
+                 WHERE book_tags.tag_id = ?",
+        )
+        .bind(tag_id.as_bytes().as_slice())
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(books)
+    }
+
+    /// Summarizes every book, or only those with the tag, in one query.
+    #[instrument(skip(self))]
+    pub async fn list_book_summaries(&self, tag_id: Option<&Uuid>) -> ApiResult<Vec<BookSummary>> {
+        let books = sqlx::query_as::<_, BookSummary>(&format!(
+            "{} WHERE ? IS NULL
+                 OR books.id IN (SELECT book_id FROM book_tags WHERE tag_id = ?)
+                 GROUP BY books.id",
+            BOOK_SUMMARIES
+        ))
+        .bind(tag_id.map(|x| x.as_bytes().as_slice()))
+        .bind(tag_id.map(|x| x.as_bytes().as_slice()))
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(books)
+    }
+
     #[instrument(skip(self))]
     pub async fn delete_book(&self, id: &Uuid) -> ApiResult<()> {
         sqlx::query("DELETE FROM books WHERE id = ?")
@@ -154,6 +781,7 @@ impl BookClient {
             .execute(&self.pool)
             .instrument(info_span!("Querying db"))
             .await?;
+        publish(Change::new(Entity::Book, *id, ChangeKind::Deleted));
         Ok(())
     }
 }
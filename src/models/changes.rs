@@ -0,0 +1,78 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// How many changes a slow subscriber may fall behind before it misses some.
+const CHANGE_BUFFER: usize = 1024;
+
+static CHANGES: OnceLock<broadcast::Sender<Change>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Entity {
+    Book,
+    Chapter,
+    Subscriber,
+    Subscription,
+    Delivery,
+    Volume,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// A write made through a model client. Only the entity's id is carried, so subscribers read
+/// the current state themselves rather than trusting a copy which may be stale.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Change {
+    pub entity: Entity,
+    pub id: Uuid,
+    pub kind: ChangeKind,
+    /// The book the entity belongs to, for chapters, subscriptions and volumes.
+    #[serde(rename = "bookId", skip_serializing_if = "Option::is_none")]
+    pub book_id: Option<Uuid>,
+    pub at: DateTime<Utc>,
+}
+
+impl Change {
+    pub fn new(entity: Entity, id: Uuid, kind: ChangeKind) -> Change {
+        Change {
+            entity,
+            id,
+            kind,
+            book_id: None,
+            at: Utc::now(),
+        }
+    }
+
+    pub fn in_book(self, book_id: Uuid) -> Change {
+        Change {
+            book_id: Some(book_id),
+            ..self
+        }
+    }
+}
+
+fn sender() -> &'static broadcast::Sender<Change> {
+    CHANGES.get_or_init(|| broadcast::channel(CHANGE_BUFFER).0)
+}
+
+/// Announces a change to everything in this process which subscribed. Changes made by other
+/// instances sharing the database aren't seen.
+pub fn publish(change: Change) {
+    // Sending only fails when nobody is subscribed.
+    let _ = sender().send(change);
+}
+
+/// Receives every change published after the call.
+pub fn subscribe() -> broadcast::Receiver<Change> {
+    sender().subscribe()
+}
@@ -0,0 +1,44 @@
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+
+use crate::error::ApiResult;
+
+/// Raw chapter sources kept in the database, keyed by the SHA-256 of their contents.
+pub struct ChapterSourceClient {
+    pool: Pool<Sqlite>,
+}
+
+impl ChapterSourceClient {
+    pub fn new(pool: &Pool<Sqlite>) -> ChapterSourceClient {
+        ChapterSourceClient { pool: pool.clone() }
+    }
+
+    /// Stores the source, unless one with the same key already is.
+    #[instrument(skip(self, body))]
+    pub async fn put_source(&self, source_key: &str, body: &[u8]) -> ApiResult<()> {
+        sqlx::query(
+            "INSERT INTO chapter_sources(source_key, body, created_at)
+                 VALUES(?, ?, ?)
+                 ON CONFLICT(source_key) DO NOTHING;",
+        )
+        .bind(source_key)
+        .bind(body)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_source(&self, source_key: &str) -> ApiResult<Option<Vec<u8>>> {
+        let body: Option<(Vec<u8>,)> =
+            sqlx::query_as("SELECT body FROM chapter_sources WHERE source_key = ?")
+                .bind(source_key)
+                .fetch_optional(&self.pool)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        Ok(body.map(|(x,)| x))
+    }
+}
@@ -1,39 +1,223 @@
-use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Duration, Utc};
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use sqlx::{sqlite::SqliteRow, Pool, QueryBuilder, Row, Sqlite, Transaction};
 use tracing::{error, info_span, instrument, Instrument};
 use uuid::Uuid;
 
 use crate::{
     error::{ApiError, ApiResult},
-    util::is_foreign_key_error,
+    telemetry::current_trace_context,
+    util::{content_digest, is_foreign_key_error},
+};
+
+use super::{
+    blobs::{read_chunks, BlobLocation, BlobStream},
+    changes::{publish, Change, ChangeKind, Entity},
+    decode_optional_uuid, decode_uuid,
 };
 
-use super::decode_uuid;
+// The queries run on every tick of the pipeline, which /explainHotQueries checks are indexed.
+
+/// Chapters whose bodies the hydration loop fetches, those an unpaused subscriber's
+/// subscription is waiting on first.
+pub(super) const CHAPTERS_WITHOUT_BODIES: &str = "SELECT chapters.*, EXISTS(SELECT 1 FROM subscriptions JOIN subscribers ON subscribers.id = subscriptions.subscriber_id WHERE subscriptions.book_id = chapters.book_id AND subscribers.paused_at IS NULL AND coalesce(subscriptions.last_delivered_chapter_created_at < chapters.created_at, true)) AS priority FROM chapters WHERE state = 'discovered' ORDER BY priority DESC, order_index DESC";
+
+/// Chapters the conversion loop converts to epubs, those an unpaused subscriber's subscription
+/// is waiting on first.
+pub(super) const CHAPTERS_READY_FOR_CONVERSION: &str = "SELECT chapters.*, EXISTS(SELECT 1 FROM subscriptions JOIN subscribers ON subscribers.id = subscriptions.subscriber_id WHERE subscriptions.book_id = chapters.book_id AND subscribers.paused_at IS NULL AND coalesce(subscriptions.last_delivered_chapter_created_at < chapters.created_at, true)) AS priority FROM chapters WHERE state = 'hydrated' ORDER BY priority DESC, order_index DESC";
+
+pub(super) const HYDRATION_BACKLOG: &str = "SELECT count(*) AS chapters, min(created_at) AS oldest_created_at FROM chapters WHERE state = 'discovered'";
+
+pub(super) const CONVERSION_BACKLOG: &str = "SELECT count(*) AS chapters, min(created_at) AS oldest_created_at FROM chapters WHERE state = 'hydrated'";
+
+/// A book's converted chapters created after a time, which the delivery loop sends.
+pub(super) const CHAPTERS_WITH_EPUB: &str = "SELECT * FROM chapters WHERE state = 'converted' AND coalesce(created_at > ?,  true) AND book_id = ? ORDER BY order_index ASC";
+
+/// The columns a `ShallowChapter` is read from: every column but the bodies, which are replaced
+/// by their sizes.
+macro_rules! shallow_chapter_columns {
+    () => {
+        "id, book_id, title, raw_title, metadata, length(html) as html_bytes, length(epub) as epub_bytes, conversion_version, state, failures, last_error, volume_id, published_at, deliver_after, public_published_at, from_public_source, ordinal, order_index, created_at, updated_at"
+    };
+}
+
+pub(super) const CHAPTERS_SHALLOW: &str = concat!(
+    "SELECT ",
+    shallow_chapter_columns!(),
+    " FROM chapters where book_id = ? ORDER BY order_index DESC"
+);
+
+const CHAPTER_SHALLOW: &str = concat!(
+    "SELECT ",
+    shallow_chapter_columns!(),
+    " FROM chapters where id = ?"
+);
+
+/// How many times in a row a chapter may fail to be fetched or converted before the pipeline
+/// gives up on it.
+pub const MAX_CHAPTER_FAILURES: i64 = 5;
+
+/// The most parameters a statement may bind in the oldest sqlite versions still supported.
+const MAX_BIND_PARAMETERS: usize = 999;
+
+/// The parameters bound for each row of a chapter insert.
+const NEW_CHAPTER_PARAMETERS: usize = 18;
 
 #[derive(PartialEq, Clone, Eq)]
 pub struct NewChapter {
     pub title: String,
+    /// The title as the provider found it, if it was normalized.
+    pub raw_title: Option<String>,
     pub metadata: ChapterMetadata,
     pub book_id: Uuid,
     pub html: Option<Vec<u8>>,
     pub epub: Option<Vec<u8>>,
     pub published_at: Option<chrono::DateTime<Utc>>,
+    /// Hold the chapter back from subscriptions without early access until this time.
+    pub deliver_after: Option<chrono::DateTime<Utc>>,
+    /// Position of the chapter amongst chapters discovered from the same source, used to
+    /// order chapters which share a publish date.
+    pub ordinal: i64,
+    /// The raw input the chapter was parsed from, such as its email. Its digest is recorded as
+    /// the chapter's source key, so it must be stored before the chapter is created.
+    pub source: Option<Vec<u8>>,
 }
 
 impl std::fmt::Debug for NewChapter {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("NewChapter")
             .field("title", &self.title)
+            .field("raw_title", &self.raw_title)
             .field("metadata", &self.metadata)
             .field("book_id", &self.book_id)
             .field("html_bytes", &self.html.as_ref().map(|x| x.len()))
             .field("epub_bytes", &self.epub.as_ref().map(|x| x.len()))
             .field("published_at", &self.published_at)
+            .field("deliver_after", &self.deliver_after)
+            .field("ordinal", &self.ordinal)
+            .field("source_bytes", &self.source.as_ref().map(|x| x.len()))
             .finish()
     }
 }
 
+/// One of the bodies stored for a chapter.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChapterBody {
+    Html,
+    Epub,
+}
+
+impl ChapterBody {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChapterBody::Html => "html",
+            ChapterBody::Epub => "epub",
+        }
+    }
+
+    fn digest_column(&self) -> &'static str {
+        match self {
+            ChapterBody::Html => "html_digest",
+            ChapterBody::Epub => "epub_digest",
+        }
+    }
+}
+
+/// Where a chapter is in the pipeline. The hydration and conversion loops each take chapters
+/// in one state and move them on to the next.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChapterState {
+    /// Waiting for its html to be fetched.
+    Discovered,
+    /// Waiting to be converted to an epub.
+    Hydrated,
+    /// Ready to be delivered.
+    Converted,
+    /// Failed MAX_CHAPTER_FAILURES times in a row, and won't be tried again until it is retried.
+    Failed,
+    /// Held out of the pipeline and deliveries by an admin until it is retried.
+    Quarantined,
+    /// Its bodies were dropped once the book was kept in an omnibus.
+    Pruned,
+}
+
+impl ChapterState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChapterState::Discovered => "discovered",
+            ChapterState::Hydrated => "hydrated",
+            ChapterState::Converted => "converted",
+            ChapterState::Failed => "failed",
+            ChapterState::Quarantined => "quarantined",
+            ChapterState::Pruned => "pruned",
+        }
+    }
+
+    /// The state of a new chapter with the given bodies.
+    fn of_bodies(html: bool, epub: bool) -> ChapterState {
+        match (html, epub) {
+            (_, true) => ChapterState::Converted,
+            (true, false) => ChapterState::Hydrated,
+            (false, false) => ChapterState::Discovered,
+        }
+    }
+}
+
+impl TryFrom<(&SqliteRow, &str)> for ChapterState {
+    type Error = sqlx::Error;
+
+    fn try_from(value: (&SqliteRow, &str)) -> core::result::Result<Self, Self::Error> {
+        let (row, index) = value;
+        let state: String = row.try_get(index)?;
+        serde_json::from_value(serde_json::Value::String(state)).map_err(|err| {
+            sqlx::Error::ColumnDecode {
+                index: index.into(),
+                source: Box::new(err),
+            }
+        })
+    }
+}
+
+/// The chapters waiting at a stage of the pipeline.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Backlog {
+    pub chapters: i64,
+    #[serde(rename = "oldestCreatedAt")]
+    pub oldest_created_at: Option<DateTime<Utc>>,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for Backlog {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(Backlog {
+            chapters: row.try_get("chapters")?,
+            oldest_created_at: row.try_get("oldest_created_at")?,
+        })
+    }
+}
+
+/// A chapter waiting for the hydration or conversion loop.
+#[derive(Debug, PartialEq, Clone)]
+pub struct QueuedChapter {
+    pub chapter: Chapter,
+    /// Set if a subscription is waiting on the chapter, such as a new release of a subscribed
+    /// book, rather than it being part of a backlist nobody has reached yet.
+    pub priority: bool,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for QueuedChapter {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(QueuedChapter {
+            chapter: sqlx::FromRow::from_row(row)?,
+            priority: row.try_get("priority")?,
+        })
+    }
+}
+
 pub struct ChapterClient {
     pool: Pool<Sqlite>,
 }
@@ -53,6 +237,10 @@ pub enum ChapterMetadata {
     },
     TheDailyGrindPatreon,
     ApparatusOfChangePatreon,
+    /// Split out of an uploaded epub or zip, from the file at `source` within it.
+    Imported {
+        source: String,
+    },
 }
 
 impl TryFrom<(&SqliteRow, &str)> for ChapterMetadata {
@@ -75,19 +263,75 @@ impl ChapterMetadata {
         let json = serde_json::to_string(self)?;
         Ok(json)
     }
+
+    /// Where the chapter can be read on the web, if it was found somewhere with a link.
+    pub fn url(&self) -> Option<String> {
+        match self {
+            ChapterMetadata::RoyalRoad {
+                royalroad_chapter_id,
+                ..
+            } => Some(format!(
+                "https://www.royalroad.com/fiction/chapter/{}",
+                royalroad_chapter_id
+            )),
+            ChapterMetadata::Pale { url } => Some(url.clone()),
+            ChapterMetadata::TheWanderingInnPatreon { url, .. } => Some(url.clone()),
+            ChapterMetadata::TheDailyGrindPatreon
+            | ChapterMetadata::ApparatusOfChangePatreon
+            | ChapterMetadata::Imported { .. } => None,
+        }
+    }
 }
 
 #[derive(PartialEq, Clone, Serialize)]
 pub struct Chapter {
     pub id: Uuid,
     pub title: String,
+    /// The title as the provider found it, before normalization.
+    #[serde(rename = "rawTitle")]
+    pub raw_title: Option<String>,
     pub metadata: ChapterMetadata,
     #[serde(rename = "bookId")]
     pub book_id: Uuid,
     pub html: Option<Vec<u8>>,
     pub epub: Option<Vec<u8>>,
+    /// SHA-256 of the html as it was stored, checked to catch corruption.
+    #[serde(rename = "htmlDigest")]
+    pub html_digest: Option<String>,
+    /// SHA-256 of the epub as it was stored.
+    #[serde(rename = "epubDigest")]
+    pub epub_digest: Option<String>,
+    /// The version of the conversion pipeline which generated the epub.
+    #[serde(rename = "conversionVersion")]
+    pub conversion_version: Option<i64>,
+    pub state: ChapterState,
+    /// Consecutive failures to fetch or convert the chapter.
+    pub failures: i64,
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+    /// The volume, or arc, the chapter belongs to.
+    #[serde(rename = "volumeId")]
+    pub volume_id: Option<Uuid>,
     #[serde(rename = "publishedAt")]
     pub published_at: Option<chrono::DateTime<Utc>>,
+    /// Subscriptions without early access aren't delivered the chapter before this time.
+    #[serde(rename = "deliverAfter")]
+    pub deliver_after: Option<chrono::DateTime<Utc>>,
+    /// When the book's public source released the chapter.
+    #[serde(rename = "publicPublishedAt")]
+    pub public_published_at: Option<chrono::DateTime<Utc>>,
+    /// Set if only the book's public source found the chapter.
+    #[serde(rename = "fromPublicSource")]
+    pub from_public_source: bool,
+    pub ordinal: i64,
+    #[serde(rename = "orderIndex")]
+    pub order_index: i64,
+    /// The W3C traceparent of the span which discovered the chapter.
+    #[serde(skip)]
+    pub trace_context: Option<String>,
+    /// Finds the raw email or page the chapter was parsed from in the source store.
+    #[serde(rename = "sourceKey")]
+    pub source_key: Option<String>,
     #[serde(rename = "createdAt")]
     pub created_at: chrono::DateTime<Utc>,
     #[serde(rename = "updatedAt")]
@@ -99,11 +343,25 @@ impl std::fmt::Debug for Chapter {
         f.debug_struct("Chapter")
             .field("id", &self.id)
             .field("title", &self.title)
+            .field("raw_title", &self.raw_title)
             .field("metadata", &self.metadata)
             .field("book_id", &self.book_id)
             .field("html_bytes", &self.html.as_ref().map(|x| x.len()))
             .field("epub_bytes", &self.epub.as_ref().map(|x| x.len()))
+            .field("html_digest", &self.html_digest)
+            .field("epub_digest", &self.epub_digest)
+            .field("conversion_version", &self.conversion_version)
+            .field("state", &self.state)
+            .field("failures", &self.failures)
+            .field("last_error", &self.last_error)
+            .field("volume_id", &self.volume_id)
             .field("published_at", &self.published_at)
+            .field("deliver_after", &self.deliver_after)
+            .field("public_published_at", &self.public_published_at)
+            .field("from_public_source", &self.from_public_source)
+            .field("ordinal", &self.ordinal)
+            .field("order_index", &self.order_index)
+            .field("source_key", &self.source_key)
             .field("created_at", &self.created_at)
             .field("updated_at", &self.updated_at)
             .finish()
@@ -116,10 +374,25 @@ impl<'r> sqlx::FromRow<'r, SqliteRow> for Chapter {
             id: decode_uuid(row, "id")?,
             book_id: decode_uuid(row, "book_id")?,
             title: row.try_get("title")?,
+            raw_title: row.try_get("raw_title")?,
             html: row.try_get("html")?,
             epub: row.try_get("epub")?,
+            html_digest: row.try_get("html_digest")?,
+            epub_digest: row.try_get("epub_digest")?,
+            conversion_version: row.try_get("conversion_version")?,
+            state: (row, "state").try_into()?,
+            failures: row.try_get("failures")?,
+            last_error: row.try_get("last_error")?,
+            volume_id: decode_optional_uuid(row, "volume_id")?,
             metadata: (row, "metadata").try_into()?,
             published_at: row.try_get("published_at")?,
+            deliver_after: row.try_get("deliver_after")?,
+            public_published_at: row.try_get("public_published_at")?,
+            from_public_source: row.try_get("from_public_source")?,
+            ordinal: row.try_get("ordinal")?,
+            order_index: row.try_get("order_index")?,
+            trace_context: row.try_get("trace_context")?,
+            source_key: row.try_get("source_key")?,
             created_at: row.try_get("created_at")?,
             updated_at: row.try_get("updated_at")?,
         })
@@ -130,13 +403,35 @@ impl<'r> sqlx::FromRow<'r, SqliteRow> for Chapter {
 pub struct ShallowChapter {
     pub id: Uuid,
     pub title: String,
+    #[serde(rename = "rawTitle")]
+    pub raw_title: Option<String>,
     pub metadata: ChapterMetadata,
     #[serde(rename = "bookId")]
     pub book_id: Uuid,
     pub html_bytes: Option<i64>,
     pub epub_bytes: Option<i64>,
+    #[serde(rename = "conversionVersion")]
+    pub conversion_version: Option<i64>,
+    pub state: ChapterState,
+    pub failures: i64,
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+    #[serde(rename = "volumeId")]
+    pub volume_id: Option<Uuid>,
     #[serde(rename = "publishedAt")]
     pub published_at: Option<chrono::DateTime<Utc>>,
+    /// Subscriptions without early access aren't delivered the chapter before this time.
+    #[serde(rename = "deliverAfter")]
+    pub deliver_after: Option<chrono::DateTime<Utc>>,
+    /// When the book's public source released the chapter.
+    #[serde(rename = "publicPublishedAt")]
+    pub public_published_at: Option<chrono::DateTime<Utc>>,
+    /// Set if only the book's public source found the chapter.
+    #[serde(rename = "fromPublicSource")]
+    pub from_public_source: bool,
+    pub ordinal: i64,
+    #[serde(rename = "orderIndex")]
+    pub order_index: i64,
     #[serde(rename = "createdAt")]
     pub created_at: chrono::DateTime<Utc>,
     #[serde(rename = "updatedAt")]
@@ -148,11 +443,22 @@ impl std::fmt::Debug for ShallowChapter {
         f.debug_struct("ShallowChapter")
             .field("id", &self.id)
             .field("title", &self.title)
+            .field("raw_title", &self.raw_title)
             .field("metadata", &self.metadata)
             .field("book_id", &self.book_id)
             .field("html_bytes", &self.html_bytes)
             .field("epub_bytes", &self.epub_bytes)
+            .field("conversion_version", &self.conversion_version)
+            .field("state", &self.state)
+            .field("failures", &self.failures)
+            .field("last_error", &self.last_error)
+            .field("volume_id", &self.volume_id)
             .field("published_at", &self.published_at)
+            .field("deliver_after", &self.deliver_after)
+            .field("public_published_at", &self.public_published_at)
+            .field("from_public_source", &self.from_public_source)
+            .field("ordinal", &self.ordinal)
+            .field("order_index", &self.order_index)
             .field("created_at", &self.created_at)
             .field("updated_at", &self.updated_at)
             .finish()
@@ -165,10 +471,21 @@ impl<'r> sqlx::FromRow<'r, SqliteRow> for ShallowChapter {
             id: decode_uuid(row, "id")?,
             book_id: decode_uuid(row, "book_id")?,
             title: row.try_get("title")?,
+            raw_title: row.try_get("raw_title")?,
             html_bytes: row.try_get("html_bytes")?,
             epub_bytes: row.try_get("epub_bytes")?,
+            conversion_version: row.try_get("conversion_version")?,
+            state: (row, "state").try_into()?,
+            failures: row.try_get("failures")?,
+            last_error: row.try_get("last_error")?,
+            volume_id: decode_optional_uuid(row, "volume_id")?,
             metadata: (row, "metadata").try_into()?,
             published_at: row.try_get("published_at")?,
+            deliver_after: row.try_get("deliver_after")?,
+            public_published_at: row.try_get("public_published_at")?,
+            from_public_source: row.try_get("from_public_source")?,
+            ordinal: row.try_get("ordinal")?,
+            order_index: row.try_get("order_index")?,
             created_at: row.try_get("created_at")?,
             updated_at: row.try_get("updated_at")?,
         })
@@ -191,8 +508,8 @@ impl ChapterClient {
         published_at: Option<chrono::DateTime<Utc>>,
     ) -> ApiResult<Chapter> {
         let chapter = sqlx::query_as::<_, Chapter>(
-            "INSERT INTO chapters(id, book_id, title, metadata, html, epub, published_at, created_at, updated_at) 
-            VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?) 
+            "INSERT INTO chapters(id, book_id, title, metadata, html, html_digest, epub, epub_digest, state, published_at, order_index, trace_context, created_at, updated_at) 
+            VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, (SELECT coalesce(max(order_index), 0) + 1 FROM chapters WHERE book_id = ?), ?, ?, ?) 
             RETURNING *;",
         )
         .bind(Uuid::new_v4().as_bytes().as_slice())
@@ -200,15 +517,26 @@ impl ChapterClient {
         .bind(title)
         .bind(metadata.json()?)
         .bind(html)
+        .bind(html.map(|x| content_digest(x)))
         .bind(epub)
+        .bind(epub.map(|x| content_digest(x)))
+        .bind(ChapterState::of_bodies(html.is_some(), epub.is_some()).as_str())
         .bind(published_at)
+        .bind(book_id.as_bytes().as_slice())
+        .bind(current_trace_context())
         .bind(Utc::now())
         .bind(Utc::now())
         .fetch_one(&self.pool)
         .instrument(info_span!("Querying db"))
         .await;
         match chapter {
-            Ok(chapter) => Ok(chapter),
+            Ok(chapter) => {
+                publish(
+                    Change::new(Entity::Chapter, chapter.id, ChangeKind::Created)
+                        .in_book(chapter.book_id),
+                );
+                Ok(chapter)
+            }
             Err(e) => match is_foreign_key_error(&e) {
                 true => Err(ApiError::ResourceNotFound {
                     id: book_id.to_string(),
@@ -220,36 +548,15 @@ impl ChapterClient {
     }
 
     pub async fn create_chapters(&self, chapters: &Vec<NewChapter>) -> ApiResult<Vec<Chapter>> {
-        let transaction = self.pool.begin().await?;
-        let mut inserted_chapters = Vec::with_capacity(chapters.len());
-        for chapter in chapters {
-            let inserted_chapter = sqlx::query_as::<_, Chapter>(
-            "INSERT INTO chapters(id, book_id, title, metadata, html, epub, published_at, created_at, updated_at) 
-            VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?) 
-            RETURNING *;",
-                )
-                .bind(Uuid::new_v4().as_bytes().as_slice())
-                .bind(chapter.book_id.as_bytes().as_slice())
-                .bind(&chapter.title)
-                .bind(chapter.metadata.json()?)
-                .bind(chapter.html.as_ref())
-                .bind(chapter.epub.as_ref())
-                .bind(chapter.published_at)
-                .bind(Utc::now())
-                .bind(Utc::now())
-                .fetch_one(&self.pool)
-                .instrument(info_span!("Querying db"))
-                .await;
-            match inserted_chapter {
-                Ok(chapter) => inserted_chapters.push(chapter),
-                Err(e) => {
-                    error!("Error occurred, cancelling transaction: {}", e);
-                    transaction.rollback().await?;
-                    return Err(e.into());
-                }
-            }
-        }
+        let mut transaction = self.pool.begin().await?;
+        let inserted_chapters = insert_chapters(&mut transaction, chapters).await?;
         transaction.commit().await?;
+        for chapter in &inserted_chapters {
+            publish(
+                Change::new(Entity::Chapter, chapter.id, ChangeKind::Created)
+                    .in_book(chapter.book_id),
+            );
+        }
         Ok(inserted_chapters)
     }
 
@@ -262,11 +569,26 @@ impl ChapterClient {
         epub: Option<&Vec<u8>>,
         published_at: Option<&chrono::DateTime<Utc>>,
     ) -> ApiResult<Chapter> {
+        // A new body moves the chapter on, unless it was held out of the pipeline.
+        let next_state = match (html, epub) {
+            (_, Some(_)) => Some(ChapterState::Converted.as_str()),
+            (Some(_), None) => Some(ChapterState::Hydrated.as_str()),
+            (None, None) => None,
+        };
         let chapter = sqlx::query_as::<_, Chapter>(
             "UPDATE chapters
                  SET title = coalesce(?, title),
                   html = coalesce(?, html), 
+                  html_digest = coalesce(?, html_digest), 
                   epub = coalesce(?, epub), 
+                  epub_digest = coalesce(?, epub_digest), 
+                  state = CASE
+                    WHEN ? IS NULL OR state IN ('quarantined', 'pruned') THEN state
+                    WHEN epub IS NOT NULL THEN 'converted'
+                    ELSE ?
+                  END,
+                  failures = CASE WHEN ? IS NULL THEN failures ELSE 0 END,
+                  last_error = CASE WHEN ? IS NULL THEN last_error ELSE NULL END,
                   published_at = coalesce(?, published_at),
                   updated_at = ?
                  WHERE id = ? 
@@ -274,7 +596,13 @@ impl ChapterClient {
         )
         .bind(title)
         .bind(html)
+        .bind(html.map(|x| content_digest(x)))
         .bind(epub)
+        .bind(epub.map(|x| content_digest(x)))
+        .bind(next_state)
+        .bind(next_state)
+        .bind(next_state)
+        .bind(next_state)
         .bind(published_at)
         .bind(Utc::now())
         .bind(id.as_bytes().as_slice())
@@ -282,7 +610,10 @@ impl ChapterClient {
         .instrument(info_span!("Querying db"))
         .await?;
         match chapter {
-            Some(x) => Ok(x),
+            Some(x) => {
+                publish(Change::new(Entity::Chapter, x.id, ChangeKind::Updated).in_book(x.book_id));
+                Ok(x)
+            }
             None => Err(ApiError::ResourceNotFound {
                 resource_type: String::from("chapter"),
                 id: id.to_string(),
@@ -290,6 +621,544 @@ impl ChapterClient {
         }
     }
 
+    /// Replaces the chapter's html, and its title and metadata where given, with those parsed
+    /// again from its source, dropping its epub so the conversion loop regenerates it. The raw
+    /// title is only replaced along with the title. Quarantined and pruned chapters stay so.
+    #[instrument(skip(self, html))]
+    pub async fn replace_parsed(
+        &self,
+        id: &Uuid,
+        title: Option<&str>,
+        raw_title: Option<&str>,
+        metadata: Option<&ChapterMetadata>,
+        html: &[u8],
+    ) -> ApiResult<Chapter> {
+        let chapter = sqlx::query_as::<_, Chapter>(
+            "UPDATE chapters
+                 SET title = coalesce(?, title),
+                  raw_title = CASE WHEN ? IS NULL THEN raw_title ELSE ? END,
+                  metadata = coalesce(?, metadata),
+                  html = ?,
+                  html_digest = ?,
+                  epub = NULL,
+                  epub_digest = NULL,
+                  conversion_version = NULL,
+                  state = CASE WHEN state IN ('quarantined', 'pruned') THEN state ELSE 'hydrated' END,
+                  failures = 0,
+                  last_error = NULL,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(title)
+        .bind(title)
+        .bind(raw_title)
+        .bind(metadata.map(|x| x.json()).transpose()?)
+        .bind(html)
+        .bind(content_digest(html))
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match chapter {
+            Some(x) => {
+                publish(Change::new(Entity::Chapter, x.id, ChangeKind::Updated).in_book(x.book_id));
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                resource_type: String::from("chapter"),
+                id: id.to_string(),
+            }),
+        }
+    }
+
+    /// Replaces the chapter's provider metadata. With `rehydrate`, its bodies are dropped so the
+    /// hydration loop fetches it again using the new metadata, even if it had been pruned.
+    #[instrument(skip(self))]
+    pub async fn set_chapter_metadata(
+        &self,
+        id: &Uuid,
+        metadata: &ChapterMetadata,
+        rehydrate: bool,
+    ) -> ApiResult<Chapter> {
+        let chapter = sqlx::query_as::<_, Chapter>(
+            "UPDATE chapters
+                 SET metadata = ?,
+                  html = CASE WHEN ? THEN NULL ELSE html END,
+                  html_digest = CASE WHEN ? THEN NULL ELSE html_digest END,
+                  epub = CASE WHEN ? THEN NULL ELSE epub END,
+                  epub_digest = CASE WHEN ? THEN NULL ELSE epub_digest END,
+                  conversion_version = CASE WHEN ? THEN NULL ELSE conversion_version END,
+                  pruned_at = CASE WHEN ? THEN NULL ELSE pruned_at END,
+                  state = CASE WHEN ? THEN 'discovered' ELSE state END,
+                  failures = CASE WHEN ? THEN 0 ELSE failures END,
+                  last_error = CASE WHEN ? THEN NULL ELSE last_error END,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(metadata.json()?)
+        .bind(rehydrate)
+        .bind(rehydrate)
+        .bind(rehydrate)
+        .bind(rehydrate)
+        .bind(rehydrate)
+        .bind(rehydrate)
+        .bind(rehydrate)
+        .bind(rehydrate)
+        .bind(rehydrate)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match chapter {
+            Some(x) => {
+                publish(Change::new(Entity::Chapter, x.id, ChangeKind::Updated).in_book(x.book_id));
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                resource_type: String::from("chapter"),
+                id: id.to_string(),
+            }),
+        }
+    }
+
+    /// Holds the chapter back from subscriptions without early access until `deliver_after`,
+    /// or releases it if unset.
+    #[instrument(skip(self))]
+    pub async fn set_deliver_after(
+        &self,
+        id: &Uuid,
+        deliver_after: Option<&chrono::DateTime<Utc>>,
+    ) -> ApiResult<Chapter> {
+        let chapter = sqlx::query_as::<_, Chapter>(
+            "UPDATE chapters
+                 SET deliver_after = ?,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(deliver_after)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match chapter {
+            Some(x) => {
+                publish(Change::new(Entity::Chapter, x.id, ChangeKind::Updated).in_book(x.book_id));
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                resource_type: String::from("chapter"),
+                id: id.to_string(),
+            }),
+        }
+    }
+
+    /// Marks the chapter as being processed by the worker. Returns false if another worker
+    /// holds a claim that started less than `lease` ago, in which case the chapter must be
+    /// left alone.
+    #[instrument(skip(self))]
+    pub async fn claim_chapter(
+        &self,
+        id: &Uuid,
+        worker_id: &str,
+        lease: Duration,
+    ) -> ApiResult<bool> {
+        let now = Utc::now();
+        let result = sqlx::query(
+            "UPDATE chapters
+                 SET processing_started_at = ?,
+                  processing_worker_id = ?
+                 WHERE id = ?
+                  AND (processing_started_at IS NULL OR processing_started_at < ?);",
+        )
+        .bind(now)
+        .bind(worker_id)
+        .bind(id.as_bytes().as_slice())
+        .bind(now - lease)
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Clears a claim made by `claim_chapter`, unless it has since been taken over by another
+    /// worker.
+    #[instrument(skip(self))]
+    pub async fn release_chapter(&self, id: &Uuid, worker_id: &str) -> ApiResult<()> {
+        sqlx::query(
+            "UPDATE chapters
+                 SET processing_started_at = NULL,
+                  processing_worker_id = NULL
+                 WHERE id = ?
+                  AND processing_worker_id = ?;",
+        )
+        .bind(id.as_bytes().as_slice())
+        .bind(worker_id)
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(())
+    }
+
+    /// Clears the claims held by `worker_id`, which a restarted process can no longer be
+    /// working on, and any claim older than `lease`. Returns the number of claims cleared.
+    #[instrument(skip(self))]
+    pub async fn release_stale_claims(&self, worker_id: &str, lease: Duration) -> ApiResult<u64> {
+        let result = sqlx::query(
+            "UPDATE chapters
+                 SET processing_started_at = NULL,
+                  processing_worker_id = NULL
+                 WHERE processing_started_at IS NOT NULL
+                  AND (processing_worker_id = ? OR processing_started_at < ?);",
+        )
+        .bind(worker_id)
+        .bind(Utc::now() - lease)
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Moves chapters whose state claims a body or epub they don't have back to the state their
+    /// columns support, so the hydration and conversion loops pick them up again. Returns the
+    /// number of chapters moved.
+    #[instrument(skip(self))]
+    pub async fn requeue_stuck_chapters(&self) -> ApiResult<u64> {
+        let rows = sqlx::query(
+            "UPDATE chapters
+                 SET state = CASE WHEN html IS NOT NULL THEN 'hydrated' ELSE 'discovered' END,
+                  updated_at = ?
+                 WHERE (state = 'hydrated' AND html IS NULL)
+                  OR (state = 'converted' AND epub IS NULL)
+                 RETURNING id, book_id;",
+        )
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        publish_updated(&rows)
+    }
+
+    /// Records a failure to fetch or convert the chapter, giving up on it once it has failed
+    /// MAX_CHAPTER_FAILURES times in a row. Nothing is recorded if the chapter has since left
+    /// `state`.
+    #[instrument(skip(self))]
+    pub async fn record_failure(
+        &self,
+        id: &Uuid,
+        state: ChapterState,
+        error: &str,
+    ) -> ApiResult<Option<Chapter>> {
+        let chapter = sqlx::query_as::<_, Chapter>(
+            "UPDATE chapters
+                 SET failures = failures + 1,
+                  last_error = ?,
+                  state = CASE WHEN failures + 1 >= ? THEN 'failed' ELSE state END,
+                  updated_at = ?
+                 WHERE id = ? AND state = ?
+                 RETURNING *;",
+        )
+        .bind(error)
+        .bind(MAX_CHAPTER_FAILURES)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .bind(state.as_str())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        if let Some(x) = &chapter {
+            publish(Change::new(Entity::Chapter, x.id, ChangeKind::Updated).in_book(x.book_id));
+        }
+        Ok(chapter)
+    }
+
+    /// Holds the chapter out of the pipeline and deliveries until it is retried.
+    #[instrument(skip(self))]
+    pub async fn quarantine_chapter(&self, id: &Uuid) -> ApiResult<Chapter> {
+        self.set_state(id, "'quarantined'").await
+    }
+
+    /// Returns the chapter to the stage its bodies put it at, clearing its failures, so failed
+    /// and quarantined chapters are picked up again.
+    #[instrument(skip(self))]
+    pub async fn retry_chapter(&self, id: &Uuid) -> ApiResult<Chapter> {
+        self.set_state(
+            id,
+            "CASE
+                WHEN pruned_at IS NOT NULL THEN 'pruned'
+                WHEN epub IS NOT NULL THEN 'converted'
+                WHEN html IS NOT NULL THEN 'hydrated'
+                ELSE 'discovered'
+              END",
+        )
+        .await
+    }
+
+    /// Sets the state to `state_expression`, an sql expression over the chapter's columns.
+    async fn set_state(&self, id: &Uuid, state_expression: &str) -> ApiResult<Chapter> {
+        let chapter = sqlx::query_as::<_, Chapter>(&format!(
+            "UPDATE chapters
+                 SET state = {},
+                  failures = 0,
+                  last_error = NULL,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+            state_expression
+        ))
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match chapter {
+            Some(x) => {
+                publish(Change::new(Entity::Chapter, x.id, ChangeKind::Updated).in_book(x.book_id));
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                resource_type: String::from("chapter"),
+                id: id.to_string(),
+            }),
+        }
+    }
+
+    /// Counts the chapters in each state.
+    #[instrument(skip(self))]
+    pub async fn count_by_state(&self) -> ApiResult<BTreeMap<ChapterState, i64>> {
+        let rows: Vec<(String, i64)> =
+            sqlx::query_as("SELECT state, count(*) FROM chapters GROUP BY state")
+                .fetch_all(&self.pool)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        rows.into_iter()
+            .map(|(state, count)| {
+                let state = serde_json::from_value(serde_json::Value::String(state))?;
+                Ok((state, count))
+            })
+            .collect()
+    }
+
+    /// Replaces the password embedded in the metadata of every Wandering Inn chapter of the
+    /// book which some subscription has yet to receive, and retries those which failed to be
+    /// fetched, likely with the old password. Returns the number of chapters updated.
+    #[instrument(skip(self, password))]
+    pub async fn set_password_for_undelivered_chapters(
+        &self,
+        book_id: &Uuid,
+        password: Option<&str>,
+    ) -> ApiResult<u64> {
+        let result = sqlx::query(
+            "UPDATE chapters
+                 SET metadata = json_set(metadata, '$.TheWanderingInnPatreon.password', ?),
+                  state = CASE WHEN state = 'failed' AND html IS NULL THEN 'discovered' ELSE state END,
+                  failures = CASE WHEN html IS NULL THEN 0 ELSE failures END,
+                  updated_at = ?
+                 WHERE book_id = ?
+                  AND state != 'pruned'
+                  AND json_type(metadata, '$.TheWanderingInnPatreon') IS NOT NULL
+                  AND (NOT EXISTS(SELECT 1 FROM subscriptions WHERE subscriptions.book_id = chapters.book_id)
+                   OR EXISTS(SELECT 1 FROM subscriptions
+                     WHERE subscriptions.book_id = chapters.book_id
+                      AND coalesce(subscriptions.last_delivered_chapter_created_at < chapters.created_at, true)));",
+        )
+        .bind(password)
+        .bind(Utc::now())
+        .bind(book_id.as_bytes().as_slice())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Drops the bodies of the book's chapters once they are kept in an omnibus. Pruned
+    /// chapters are not fetched or converted again.
+    #[instrument(skip(self))]
+    pub async fn prune_chapter_bodies(&self, book_id: &Uuid) -> ApiResult<u64> {
+        let rows = sqlx::query(
+            "UPDATE chapters
+                 SET html = NULL,
+                  html_digest = NULL,
+                  epub = NULL,
+                  epub_digest = NULL,
+                  state = 'pruned',
+                  pruned_at = ?,
+                  updated_at = ?
+                 WHERE book_id = ? AND pruned_at IS NULL
+                 RETURNING id, book_id;",
+        )
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .bind(book_id.as_bytes().as_slice())
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        publish_updated(&rows)
+    }
+
+    /// Stores a converted epub, stamped with the version of the pipeline which generated it.
+    #[instrument(skip(self, epub))]
+    pub async fn save_epub(
+        &self,
+        id: &Uuid,
+        epub: &[u8],
+        conversion_version: i64,
+    ) -> ApiResult<Chapter> {
+        let chapter = sqlx::query_as::<_, Chapter>(
+            "UPDATE chapters
+                 SET epub = ?,
+                  epub_digest = ?,
+                  conversion_version = ?,
+                  state = CASE WHEN state = 'quarantined' THEN state ELSE 'converted' END,
+                  failures = 0,
+                  last_error = NULL,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(epub)
+        .bind(content_digest(epub))
+        .bind(conversion_version)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        publish(
+            Change::new(Entity::Chapter, chapter.id, ChangeKind::Updated).in_book(chapter.book_id),
+        );
+        Ok(chapter)
+    }
+
+    /// Drops epubs converted by a pipeline older than `below_version`, or all epubs if it is
+    /// None, so the conversion loop regenerates them. Limited to the book if one is given.
+    /// Chapters without html are left alone since they can't be converted again.
+    #[instrument(skip(self))]
+    pub async fn clear_epubs(
+        &self,
+        book_id: Option<&Uuid>,
+        below_version: Option<i64>,
+    ) -> ApiResult<u64> {
+        let rows = sqlx::query(
+            "UPDATE chapters
+                 SET epub = NULL,
+                  epub_digest = NULL,
+                  conversion_version = NULL,
+                  state = CASE WHEN state = 'converted' THEN 'hydrated' ELSE state END,
+                  updated_at = ?
+                 WHERE epub IS NOT NULL
+                  AND html IS NOT NULL
+                  AND (? IS NULL OR book_id = ?)
+                  AND (? IS NULL OR conversion_version IS NULL OR conversion_version < ?)
+                 RETURNING id, book_id;",
+        )
+        .bind(Utc::now())
+        .bind(book_id.map(|x| x.as_bytes().to_vec()))
+        .bind(book_id.map(|x| x.as_bytes().to_vec()))
+        .bind(below_version)
+        .bind(below_version)
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        publish_updated(&rows)
+    }
+
+    /// Records the key of the source the chapter's body was parsed from.
+    #[instrument(skip(self))]
+    pub async fn set_source_key(&self, id: &Uuid, source_key: &str) -> ApiResult<()> {
+        sqlx::query("UPDATE chapters SET source_key = ?, updated_at = ? WHERE id = ?")
+            .bind(source_key)
+            .bind(Utc::now())
+            .bind(id.as_bytes().as_slice())
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(())
+    }
+
+    /// Records digests for the chapter's bodies, for chapters stored before digests were kept.
+    #[instrument(skip(self))]
+    pub async fn set_missing_digests(
+        &self,
+        id: &Uuid,
+        html_digest: Option<&str>,
+        epub_digest: Option<&str>,
+    ) -> ApiResult<()> {
+        sqlx::query(
+            "UPDATE chapters
+                 SET html_digest = coalesce(html_digest, ?),
+                  epub_digest = coalesce(epub_digest, ?)
+                 WHERE id = ?;",
+        )
+        .bind(html_digest)
+        .bind(epub_digest)
+        .bind(id.as_bytes().as_slice())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(())
+    }
+
+    /// Drops corrupted bodies so that the hydration and conversion loops regenerate them.
+    #[instrument(skip(self))]
+    pub async fn clear_corrupted_bodies(&self, id: &Uuid, html: bool, epub: bool) -> ApiResult<()> {
+        let rows = sqlx::query(
+            "UPDATE chapters
+                 SET html = CASE WHEN ? THEN NULL ELSE html END,
+                  html_digest = CASE WHEN ? THEN NULL ELSE html_digest END,
+                  epub = CASE WHEN ? THEN NULL ELSE epub END,
+                  epub_digest = CASE WHEN ? THEN NULL ELSE epub_digest END,
+                  state = CASE
+                    WHEN NOT (? OR ?) OR state IN ('quarantined', 'pruned') THEN state
+                    WHEN ? OR html IS NULL THEN 'discovered'
+                    ELSE 'hydrated'
+                  END,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING id, book_id;",
+        )
+        .bind(html)
+        .bind(html)
+        .bind(epub)
+        .bind(epub)
+        .bind(html)
+        .bind(epub)
+        .bind(html)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        publish_updated(&rows)?;
+        Ok(())
+    }
+
+    /// Ids of every chapter with a stored body, for scanning one chapter at a time.
+    #[instrument(skip(self))]
+    pub async fn list_chapter_ids_with_bodies(&self) -> ApiResult<Vec<Uuid>> {
+        let ids: Vec<(Vec<u8>,)> = sqlx::query_as(
+            "SELECT id FROM chapters WHERE html IS NOT NULL OR epub IS NOT NULL ORDER BY created_at",
+        )
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        ids.into_iter()
+            .map(|(id,)| {
+                Uuid::from_slice(&id).map_err(|e| {
+                    ApiError::Database(sqlx::Error::ColumnDecode {
+                        index: String::from("id"),
+                        source: Box::new(e),
+                    })
+                })
+            })
+            .collect()
+    }
+
     #[instrument(skip(self))]
     pub async fn get_chapter(&self, id: Uuid) -> ApiResult<Option<Chapter>> {
         let book = sqlx::query_as::<_, Chapter>("SELECT * FROM chapters WHERE id = ?")
@@ -300,26 +1169,211 @@ impl ChapterClient {
         Ok(book)
     }
 
+    /// Streams one of the chapter's bodies, read once the stream is consumed. None if the chapter
+    /// or the body doesn't exist.
+    #[instrument(skip(self))]
+    pub async fn stream_body(&self, id: &Uuid, body: ChapterBody) -> ApiResult<Option<BlobStream>> {
+        let row: Option<(i64, i64, Option<String>, DateTime<Utc>)> = sqlx::query_as(&format!(
+            "SELECT rowid, length(CAST({column} AS BLOB)), {digest_column}, updated_at
+                 FROM chapters WHERE id = ? AND {column} IS NOT NULL",
+            column = body.as_str(),
+            digest_column = body.digest_column(),
+        ))
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        let (rowid, length, digest, updated_at) = match row {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let location = BlobLocation {
+            table: "chapters",
+            column: body.as_str(),
+            version_column: body.digest_column(),
+            rowid,
+        };
+        Ok(Some(BlobStream {
+            length,
+            chunks: read_chunks(&self.pool, location, length, digest.clone()),
+            digest,
+            updated_at,
+        }))
+    }
+
     #[instrument(skip(self))]
     pub async fn list_chapters(&self, book_id: &Uuid) -> ApiResult<Vec<Chapter>> {
-        let chapters =
-            sqlx::query_as::<_, Chapter>("SELECT * FROM chapters where book_id = ? ORDER BY coalesce(published_at, created_at) DESC")
-                .bind(book_id.as_bytes().as_slice())
-                .fetch_all(&self.pool)
-                .instrument(info_span!("Querying db"))
-                .await?;
+        let chapters = sqlx::query_as::<_, Chapter>(
+            "SELECT * FROM chapters where book_id = ? ORDER BY order_index DESC",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(chapters)
+    }
+
+    /// The most recently discovered chapters with bodies, across every book if no book is given.
+    /// Chapters still in early access are left out until their `deliver_after`.
+    #[instrument(skip(self))]
+    pub async fn list_recent_chapters(
+        &self,
+        book_id: Option<&Uuid>,
+        limit: i64,
+    ) -> ApiResult<Vec<Chapter>> {
+        let chapters = sqlx::query_as::<_, Chapter>(
+            "SELECT * FROM chapters
+             WHERE html IS NOT NULL AND (?1 IS NULL OR book_id = ?1)
+              AND (deliver_after IS NULL OR deliver_after <= ?3)
+             ORDER BY created_at DESC LIMIT ?2",
+        )
+        .bind(book_id.map(|x| x.as_bytes().as_slice()))
+        .bind(limit)
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
         Ok(chapters)
     }
 
+    /// The chapter without its bodies.
+    #[instrument(skip(self))]
+    pub async fn get_chapter_shallow(&self, id: &Uuid) -> ApiResult<Option<ShallowChapter>> {
+        let chapter = sqlx::query_as::<_, ShallowChapter>(CHAPTER_SHALLOW)
+            .bind(id.as_bytes().as_slice())
+            .fetch_optional(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(chapter)
+    }
+
     #[instrument(skip(self))]
     pub async fn list_chapters_shallow(&self, book_id: &Uuid) -> ApiResult<Vec<ShallowChapter>> {
-        let chapters =
-            sqlx::query_as::<_, ShallowChapter>("SELECT id, book_id, title, metadata, length(html) as html_bytes, length(epub) as epub_bytes, published_at, created_at, updated_at FROM chapters where book_id = ? ORDER BY coalesce(published_at, created_at) DESC")
+        let chapters = sqlx::query_as::<_, ShallowChapter>(CHAPTERS_SHALLOW)
+            .bind(book_id.as_bytes().as_slice())
+            .fetch_all(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(chapters)
+    }
+
+    /// Moves the given chapters into the order they are provided in, reusing the positions
+    /// they currently occupy within the book. Chapters which aren't provided keep their place.
+    #[instrument(skip(self))]
+    pub async fn reorder_chapters(&self, book_id: &Uuid, chapter_ids: &[Uuid]) -> ApiResult<()> {
+        if !chapter_ids.iter().all_unique() {
+            return Err(ApiError::InvalidRequest(String::from(
+                "Chapter ids must not contain duplicates.",
+            )));
+        }
+
+        let mut transaction = self.pool.begin().await?;
+        let mut order_indices = Vec::with_capacity(chapter_ids.len());
+        for chapter_id in chapter_ids {
+            let order_index: Option<(i64,)> =
+                sqlx::query_as("SELECT order_index FROM chapters WHERE id = ? AND book_id = ?")
+                    .bind(chapter_id.as_bytes().as_slice())
+                    .bind(book_id.as_bytes().as_slice())
+                    .fetch_optional(&mut transaction)
+                    .instrument(info_span!("Querying db"))
+                    .await?;
+            match order_index {
+                Some((x,)) => order_indices.push(x),
+                None => {
+                    return Err(ApiError::ResourceNotFound {
+                        resource_type: String::from("chapter"),
+                        id: chapter_id.to_string(),
+                    })
+                }
+            }
+        }
+
+        order_indices.sort_unstable();
+        let now = Utc::now();
+        for (chapter_id, order_index) in chapter_ids.iter().zip(order_indices) {
+            sqlx::query("UPDATE chapters SET order_index = ?, updated_at = ? WHERE id = ?")
+                .bind(order_index)
+                .bind(now)
+                .bind(chapter_id.as_bytes().as_slice())
+                .execute(&mut transaction)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        }
+        transaction.commit().await?;
+        for chapter_id in chapter_ids {
+            publish(
+                Change::new(Entity::Chapter, *chapter_id, ChangeKind::Updated).in_book(*book_id),
+            );
+        }
+        Ok(())
+    }
+
+    /// Inserts the chapters before the book's existing chapters, for early arcs which are no
+    /// longer online, all in one transaction. Their creation times are moved to before the
+    /// existing chapters', so they are only delivered to subscriptions which haven't started
+    /// the book.
+    #[instrument(skip(self, chapters), fields(chapters = chapters.len()))]
+    pub async fn prepend_chapters(
+        &self,
+        book_id: &Uuid,
+        chapters: &[NewChapter],
+    ) -> ApiResult<Vec<Chapter>> {
+        let mut transaction = self.pool.begin().await?;
+        let (earliest,): (Option<DateTime<Utc>>,) =
+            sqlx::query_as("SELECT min(created_at) FROM chapters WHERE book_id = ?")
                 .bind(book_id.as_bytes().as_slice())
-                .fetch_all(&self.pool)
+                .fetch_one(&mut transaction)
                 .instrument(info_span!("Querying db"))
                 .await?;
-        Ok(chapters)
+        let mut inserted = insert_chapters(&mut transaction, chapters).await?;
+        if let (Some(earliest), Some(first_inserted)) = (earliest, inserted.first()) {
+            // The existing chapters move up to make room for the inserted ones before them.
+            let first_inserted = first_inserted.order_index;
+            let count = inserted.len() as i64;
+            let now = Utc::now();
+            let (first,): (i64,) = sqlx::query_as(
+                "SELECT min(order_index) FROM chapters WHERE book_id = ? AND order_index < ?",
+            )
+            .bind(book_id.as_bytes().as_slice())
+            .bind(first_inserted)
+            .fetch_one(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+            sqlx::query(
+                "UPDATE chapters SET order_index = order_index + ?, updated_at = ?
+                     WHERE book_id = ? AND order_index < ?",
+            )
+            .bind(count)
+            .bind(now)
+            .bind(book_id.as_bytes().as_slice())
+            .bind(first_inserted)
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+            for (index, chapter) in inserted.iter_mut().enumerate() {
+                chapter.order_index = first + index as i64;
+                chapter.created_at = earliest - Duration::milliseconds(count - index as i64);
+                chapter.updated_at = now;
+                sqlx::query(
+                    "UPDATE chapters SET order_index = ?, created_at = ?, updated_at = ? WHERE id = ?",
+                )
+                .bind(chapter.order_index)
+                .bind(chapter.created_at)
+                .bind(now)
+                .bind(chapter.id.as_bytes().as_slice())
+                .execute(&mut transaction)
+                .instrument(info_span!("Querying db"))
+                .await?;
+            }
+        }
+        transaction.commit().await?;
+        for chapter in &inserted {
+            publish(
+                Change::new(Entity::Chapter, chapter.id, ChangeKind::Created)
+                    .in_book(chapter.book_id),
+            );
+        }
+        Ok(inserted)
     }
 
     #[instrument(skip(self))]
@@ -329,19 +1383,22 @@ impl ChapterClient {
             .execute(&self.pool)
             .instrument(info_span!("Querying db"))
             .await?;
+        publish(Change::new(Entity::Chapter, *id, ChangeKind::Deleted));
         Ok(())
     }
 
     #[instrument(skip(self))]
-    pub async fn most_recent_chapter_by_published_at(
+    pub async fn most_recent_chapter_by_order_index(
         &self,
         book_id: &Uuid,
     ) -> ApiResult<Option<Chapter>> {
-        let book = sqlx::query_as::<_, Chapter>("SELECT * FROM chapters WHERE book_id = ? ORDER BY coalesce(published_at, created_at) DESC LIMIT 1")
-            .bind(book_id.as_bytes().as_slice())
-            .fetch_optional(&self.pool)
-            .instrument(info_span!("Querying db"))
-            .await?;
+        let book = sqlx::query_as::<_, Chapter>(
+            "SELECT * FROM chapters WHERE book_id = ? ORDER BY order_index DESC LIMIT 1",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
         Ok(book)
     }
 
@@ -360,39 +1417,274 @@ impl ChapterClient {
         Ok(book)
     }
 
+    /// The book's latest chapter from its primary source, ignoring gaps filled by its public
+    /// source.
     #[instrument(skip(self))]
-    pub async fn list_chapters_without_bodies(&self) -> ApiResult<Vec<Chapter>> {
-        let chapters =
-            sqlx::query_as::<_, Chapter>("SELECT * FROM chapters where html IS NULL ORDER BY coalesce(published_at, created_at) DESC")
-                .fetch_all(&self.pool)
+    pub async fn most_recent_primary_chapter(&self, book_id: &Uuid) -> ApiResult<Option<Chapter>> {
+        let chapter = sqlx::query_as::<_, Chapter>(
+            "SELECT * FROM chapters
+             WHERE book_id = ? AND NOT from_public_source
+             ORDER BY created_at DESC LIMIT 1",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(chapter)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn latest_public_published_at(
+        &self,
+        book_id: &Uuid,
+    ) -> ApiResult<Option<DateTime<Utc>>> {
+        let (published_at,): (Option<DateTime<Utc>>,) =
+            sqlx::query_as("SELECT max(public_published_at) FROM chapters WHERE book_id = ?")
+                .bind(book_id.as_bytes().as_slice())
+                .fetch_one(&self.pool)
                 .instrument(info_span!("Querying db"))
                 .await?;
+        Ok(published_at)
+    }
+
+    /// When the latest of the book's chapters created before `created_before` was published.
+    #[instrument(skip(self))]
+    pub async fn latest_published_before(
+        &self,
+        book_id: &Uuid,
+        created_before: &DateTime<Utc>,
+    ) -> ApiResult<Option<DateTime<Utc>>> {
+        let (published_at,): (Option<DateTime<Utc>>,) = sqlx::query_as(
+            "SELECT max(coalesce(published_at, created_at)) FROM chapters WHERE book_id = ? AND created_at < ?",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .bind(created_before)
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(published_at)
+    }
+
+    /// Records the chapter's public release, taking the public source's title and lifting any
+    /// embargo, as the chapter is now free for everyone.
+    #[instrument(skip(self))]
+    pub async fn mark_public(
+        &self,
+        id: &Uuid,
+        title: &str,
+        published_at: &DateTime<Utc>,
+        from_public_source: bool,
+    ) -> ApiResult<Chapter> {
+        let chapter = sqlx::query_as::<_, Chapter>(
+            "UPDATE chapters
+                 SET title = ?,
+                  public_published_at = ?,
+                  from_public_source = from_public_source OR ?,
+                  deliver_after = NULL,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(title)
+        .bind(published_at)
+        .bind(from_public_source)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match chapter {
+            Some(x) => {
+                publish(Change::new(Entity::Chapter, x.id, ChangeKind::Updated).in_book(x.book_id));
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                resource_type: String::from("chapter"),
+                id: id.to_string(),
+            }),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_chapters_without_bodies(&self) -> ApiResult<Vec<QueuedChapter>> {
+        let chapters = sqlx::query_as::<_, QueuedChapter>(CHAPTERS_WITHOUT_BODIES)
+            .fetch_all(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
         Ok(chapters)
     }
 
     #[instrument(skip(self))]
-    pub async fn list_chapters_ready_for_epub_conversion(&self) -> ApiResult<Vec<Chapter>> {
-        let chapters =
-            sqlx::query_as::<_, Chapter>("SELECT * FROM chapters WHERE html IS NOT NULL AND epub IS NULL ORDER BY coalesce(published_at, created_at) DESC")
-                .fetch_all(&self.pool)
-                .instrument(info_span!("Querying db"))
-                .await?;
+    pub async fn list_chapters_ready_for_epub_conversion(&self) -> ApiResult<Vec<QueuedChapter>> {
+        let chapters = sqlx::query_as::<_, QueuedChapter>(CHAPTERS_READY_FOR_CONVERSION)
+            .fetch_all(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
         Ok(chapters)
     }
 
+    /// Chapters waiting for their bodies to be fetched.
+    #[instrument(skip(self))]
+    pub async fn hydration_backlog(&self) -> ApiResult<Backlog> {
+        let backlog = sqlx::query_as::<_, Backlog>(HYDRATION_BACKLOG)
+            .fetch_one(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(backlog)
+    }
+
+    /// Chapters with bodies waiting to be converted to epubs.
+    #[instrument(skip(self))]
+    pub async fn conversion_backlog(&self) -> ApiResult<Backlog> {
+        let backlog = sqlx::query_as::<_, Backlog>(CONVERSION_BACKLOG)
+            .fetch_one(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(backlog)
+    }
+
+    /// Counts the book's chapters created after `datetime`, at any stage of the pipeline.
+    #[instrument(skip(self))]
+    pub async fn count_chapters_after(
+        &self,
+        book_id: &Uuid,
+        datetime: Option<&DateTime<Utc>>,
+    ) -> ApiResult<i64> {
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT count(*) FROM chapters WHERE coalesce(created_at > ?, true) AND book_id = ?",
+        )
+        .bind(datetime)
+        .bind(book_id.as_bytes().as_slice())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(count)
+    }
+
     #[instrument(skip(self))]
     pub async fn list_chapters_with_epub(
         &self,
         book_id: &Uuid,
         datetime: Option<&DateTime<Utc>>,
     ) -> ApiResult<Vec<Chapter>> {
-        let chapters =
-            sqlx::query_as::<_, Chapter>("SELECT * FROM chapters WHERE epub IS NOT NULL AND coalesce(created_at > ?,  true) AND book_id = ? ORDER BY coalesce(published_at, created_at) ASC")
+        let chapters = sqlx::query_as::<_, Chapter>(CHAPTERS_WITH_EPUB)
             .bind(datetime)
             .bind(book_id.as_bytes().as_slice())
-                .fetch_all(&self.pool)
+            .fetch_all(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(chapters)
+    }
+}
+
+/// Inserts the chapters at the end of their books within the transaction, returning them in
+/// order. Their changes are left for the caller to publish once the transaction commits.
+async fn insert_chapters(
+    transaction: &mut Transaction<'_, Sqlite>,
+    chapters: &[NewChapter],
+) -> ApiResult<Vec<Chapter>> {
+    let now = Utc::now();
+    let trace_context = current_trace_context();
+
+    // New chapters are appended to the end of their book in publication order. Each is created
+    // at least a microsecond after the one before it in the book, since a subscription's
+    // delivery cursor is a creation time and would otherwise skip the rest of a batch delivered
+    // in part.
+    let mut latest: HashMap<Uuid, (i64, DateTime<Utc>)> = HashMap::new();
+    let mut rows = Vec::with_capacity(chapters.len());
+    for chapter in chapters
+        .iter()
+        .sorted_by_key(|x| (x.book_id, x.published_at.unwrap_or(now), x.ordinal))
+    {
+        let (order_index, created_at) = match latest.get_mut(&chapter.book_id) {
+            Some(x) => x,
+            None => {
+                let (max_order_index, max_created_at): (i64, Option<DateTime<Utc>>) = sqlx::query_as(
+                    "SELECT coalesce(max(order_index), 0), max(created_at) FROM chapters WHERE book_id = ?",
+                )
+                .bind(chapter.book_id.as_bytes().as_slice())
+                .fetch_one(&mut *transaction)
                 .instrument(info_span!("Querying db"))
                 .await?;
-        Ok(chapters)
+                latest.entry(chapter.book_id).or_insert((
+                    max_order_index,
+                    max_created_at.unwrap_or(DateTime::<Utc>::MIN_UTC),
+                ))
+            }
+        };
+        *order_index += 1;
+        *created_at = now.max(*created_at + Duration::microseconds(1));
+        rows.push((
+            Uuid::new_v4(),
+            chapter,
+            chapter.metadata.json()?,
+            *order_index,
+            *created_at,
+        ));
+    }
+
+    let mut inserted_chapters = Vec::with_capacity(rows.len());
+    for batch in rows.chunks(MAX_BIND_PARAMETERS / NEW_CHAPTER_PARAMETERS) {
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO chapters(id, book_id, title, raw_title, metadata, html, html_digest, epub, epub_digest, state, published_at, deliver_after, ordinal, order_index, trace_context, source_key, created_at, updated_at) ",
+        );
+        query.push_values(
+            batch,
+            |mut row, (id, chapter, metadata, order_index, created_at)| {
+                row.push_bind(id.as_bytes().as_slice())
+                    .push_bind(chapter.book_id.as_bytes().as_slice())
+                    .push_bind(&chapter.title)
+                    .push_bind(chapter.raw_title.as_ref())
+                    .push_bind(metadata)
+                    .push_bind(chapter.html.as_ref())
+                    .push_bind(chapter.html.as_deref().map(content_digest))
+                    .push_bind(chapter.epub.as_ref())
+                    .push_bind(chapter.epub.as_deref().map(content_digest))
+                    .push_bind(
+                        ChapterState::of_bodies(chapter.html.is_some(), chapter.epub.is_some())
+                            .as_str(),
+                    )
+                    .push_bind(chapter.published_at)
+                    .push_bind(chapter.deliver_after)
+                    .push_bind(chapter.ordinal)
+                    .push_bind(order_index)
+                    .push_bind(trace_context.as_ref())
+                    .push_bind(chapter.source.as_deref().map(content_digest))
+                    .push_bind(created_at)
+                    .push_bind(now);
+            },
+        );
+        query.push(" RETURNING *;");
+        let inserted = query
+            .build_query_as::<Chapter>()
+            .fetch_all(&mut *transaction)
+            .instrument(info_span!("Querying db"))
+            .await;
+        match inserted {
+            Ok(x) => inserted_chapters.extend(x),
+            Err(e) => {
+                error!("Error occurred, cancelling transaction: {}", e);
+                return Err(e.into());
+            }
+        }
+    }
+    // Rows returned by a multi-row insert aren't guaranteed to be in order.
+    inserted_chapters.sort_by_key(|x| (x.book_id, x.order_index));
+    Ok(inserted_chapters)
+}
+
+/// Publishes an update of each chapter returned by `RETURNING id, book_id`, returning how many
+/// there were.
+fn publish_updated(rows: &[SqliteRow]) -> ApiResult<u64> {
+    for row in rows {
+        publish(
+            Change::new(
+                Entity::Chapter,
+                decode_uuid(row, "id")?,
+                ChangeKind::Updated,
+            )
+            .in_book(decode_uuid(row, "book_id")?),
+        );
     }
+    Ok(rows.len() as u64)
 }
@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    sync::{OnceLock, RwLock},
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, warn, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    config::secret,
+    error::{ApiError, ApiResult},
+    util::is_unique_error,
+};
+
+use super::decode_uuid;
+
+/// The secret holding the key credentials are encrypted with. Any string will do, as the key is
+/// derived from its digest.
+const KEY_VAR: &str = "CEREAL_CREDENTIALS_KEY";
+
+/// AES-GCM nonces are 96 bits, and are stored ahead of the ciphertext.
+const NONCE_LEN: usize = 12;
+
+/// The credentials each process last read from the vault, by name, for providers to look up
+/// without a connection to the database.
+static CREDENTIALS: OnceLock<RwLock<HashMap<String, String>>> = OnceLock::new();
+
+/// A secret used to reach a provider, such as an AWS key or a site's password. Its value is
+/// encrypted at rest and never returned by the API.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Credential {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for Credential {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(Credential {
+            id: decode_uuid(row, "id")?,
+            name: row.try_get("name")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+fn vault_key() -> Option<Aes256Gcm> {
+    let key = secret(KEY_VAR).filter(|x| !x.is_empty())?;
+    Some(Aes256Gcm::new(&Sha256::digest(key.as_bytes())))
+}
+
+fn encrypt(value: &str) -> ApiResult<Vec<u8>> {
+    let cipher = vault_key().ok_or_else(|| {
+        ApiError::InvalidRequest(format!("{} must be set to store credentials", KEY_VAR))
+    })?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, value.as_bytes())
+        .map_err(|_| ApiError::InvalidRequest(String::from("The credential can't be encrypted")))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+/// Fails if the credential was encrypted with another key, or was tampered with.
+fn decrypt(cipher: &Aes256Gcm, stored: &[u8]) -> Option<String> {
+    if stored.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+    let value = cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()?;
+    String::from_utf8(value).ok()
+}
+
+/// The credential with the name in the vault, or else the secret of the same name from the
+/// environment or a mounted file, so instances configured that way keep working.
+pub fn credential(name: &str) -> Option<String> {
+    let vault = CREDENTIALS
+        .get()
+        .and_then(|x| x.read().unwrap().get(name).cloned());
+    vault.or_else(|| secret(name))
+}
+
+pub struct CredentialClient {
+    pool: Pool<Sqlite>,
+}
+
+fn duplicate_name_error(name: &str) -> ApiError {
+    ApiError::InvalidRequest(format!("A credential named {:?} already exists", name))
+}
+
+impl CredentialClient {
+    pub fn new(pool: &Pool<Sqlite>) -> CredentialClient {
+        CredentialClient { pool: pool.clone() }
+    }
+
+    #[instrument(skip(self, value))]
+    pub async fn create_credential(&self, name: &str, value: &str) -> ApiResult<Credential> {
+        let credential = sqlx::query_as::<_, Credential>(
+            "INSERT INTO credentials(id, name, value, created_at, updated_at)
+                 VALUES(?, ?, ?, ?, ?)
+                 RETURNING *;",
+        )
+        .bind(Uuid::new_v4().as_bytes().as_slice())
+        .bind(name)
+        .bind(encrypt(value)?)
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match credential {
+            Ok(x) => {
+                self.refresh().await?;
+                Ok(x)
+            }
+            Err(e) if is_unique_error(&e) => Err(duplicate_name_error(name)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the credential's value, which providers pick up the next time the vault is read.
+    #[instrument(skip(self, value))]
+    pub async fn rotate_credential(&self, name: &str, value: &str) -> ApiResult<Credential> {
+        let credential = sqlx::query_as::<_, Credential>(
+            "UPDATE credentials
+                 SET value = ?,
+                  updated_at = ?
+                 WHERE name = ?
+                 RETURNING *;",
+        )
+        .bind(encrypt(value)?)
+        .bind(Utc::now())
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match credential {
+            Some(x) => {
+                self.refresh().await?;
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                id: name.to_owned(),
+                resource_type: String::from("credential"),
+            }),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_credential(&self, name: &str) -> ApiResult<Option<Credential>> {
+        let credential =
+            sqlx::query_as::<_, Credential>("SELECT * FROM credentials WHERE name = ?")
+                .bind(name)
+                .fetch_optional(&self.pool)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        Ok(credential)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_credentials(&self) -> ApiResult<Vec<Credential>> {
+        let credentials =
+            sqlx::query_as::<_, Credential>("SELECT * FROM credentials ORDER BY name")
+                .fetch_all(&self.pool)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        Ok(credentials)
+    }
+
+    /// Deletes the credential, unless a book still takes its password from it.
+    #[instrument(skip(self))]
+    pub async fn delete_credential(&self, name: &str) -> ApiResult<()> {
+        let mut transaction = self.pool.begin().await?;
+        let books: i64 =
+            sqlx::query_scalar("SELECT count(*) FROM books WHERE password_credential = ?")
+                .bind(name)
+                .fetch_one(&mut transaction)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        if books > 0 {
+            return Err(ApiError::InvalidRequest(format!(
+                "Credential {} is the password of {} books, which must be changed first",
+                name, books
+            )));
+        }
+        sqlx::query("DELETE FROM credentials WHERE name = ?")
+            .bind(name)
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        transaction.commit().await?;
+        self.refresh().await
+    }
+
+    /// Reads and decrypts every credential into this process's cache. Credentials which can't be
+    /// decrypted, such as after the key was changed, are left out so providers fall back to the
+    /// environment.
+    #[instrument(skip(self))]
+    pub async fn refresh(&self) -> ApiResult<()> {
+        let rows: Vec<(String, Vec<u8>)> = sqlx::query_as("SELECT name, value FROM credentials")
+            .fetch_all(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        let mut credentials = HashMap::new();
+        if !rows.is_empty() {
+            match vault_key() {
+                Some(cipher) => {
+                    for (name, value) in rows {
+                        match decrypt(&cipher, &value) {
+                            Some(value) => {
+                                credentials.insert(name, value);
+                            }
+                            None => {
+                                warn!("Credential {} can't be decrypted with {}", name, KEY_VAR)
+                            }
+                        }
+                    }
+                }
+                None => warn!(
+                    "{} credentials are stored but {} is not set",
+                    rows.len(),
+                    KEY_VAR
+                ),
+            }
+        }
+        *CREDENTIALS.get_or_init(Default::default).write().unwrap() = credentials;
+        Ok(())
+    }
+}
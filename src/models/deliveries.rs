@@ -0,0 +1,392 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+use uuid::Uuid;
+
+use crate::error::ApiResult;
+
+use super::{
+    changes::{publish, Change, ChangeKind, Entity},
+    decode_uuid, Chapter,
+};
+
+/// A record of a chapter having been delivered to a subscription.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Delivery {
+    pub id: Uuid,
+    #[serde(rename = "subscriptionId")]
+    pub subscription_id: Uuid,
+    #[serde(rename = "chapterId")]
+    pub chapter_id: Uuid,
+    /// When the chapter was discovered.
+    #[serde(rename = "chapterCreatedAt")]
+    pub chapter_created_at: DateTime<Utc>,
+    #[serde(rename = "deliveredAt")]
+    pub delivered_at: DateTime<Utc>,
+}
+
+impl Delivery {
+    /// Time from the chapter's discovery to its delivery.
+    pub fn latency(&self) -> Duration {
+        self.delivered_at - self.chapter_created_at
+    }
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for Delivery {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(Delivery {
+            id: decode_uuid(row, "id")?,
+            subscription_id: decode_uuid(row, "subscription_id")?,
+            chapter_id: decode_uuid(row, "chapter_id")?,
+            chapter_created_at: row.try_get("chapter_created_at")?,
+            delivered_at: row.try_get("delivered_at")?,
+        })
+    }
+}
+
+/// One attempt at sending chapters to a subscription. Failed attempts are retried from
+/// `retry_at`.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct DeliveryAttempt {
+    pub id: Uuid,
+    #[serde(rename = "subscriptionId")]
+    pub subscription_id: Uuid,
+    #[serde(rename = "chapterIds")]
+    pub chapter_ids: Vec<Uuid>,
+    /// Counts consecutive attempts at the same delivery, starting from 1.
+    pub attempt: i64,
+    pub succeeded: bool,
+    pub error: Option<String>,
+    #[serde(rename = "retryAt")]
+    pub retry_at: Option<DateTime<Utc>>,
+    /// The recipients the attempt reached, which a retry of the same chapters doesn't send to
+    /// again.
+    #[serde(rename = "deliveredRecipientIds")]
+    pub delivered_recipient_ids: Vec<Uuid>,
+    #[serde(rename = "attemptedAt")]
+    pub attempted_at: DateTime<Utc>,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for DeliveryAttempt {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        let decode_ids = |index: &str| -> core::result::Result<Vec<Uuid>, sqlx::Error> {
+            let ids: String = row.try_get(index)?;
+            serde_json::from_str(&ids).map_err(|err| sqlx::Error::ColumnDecode {
+                index: index.into(),
+                source: Box::new(err),
+            })
+        };
+        Ok(DeliveryAttempt {
+            id: decode_uuid(row, "id")?,
+            subscription_id: decode_uuid(row, "subscription_id")?,
+            chapter_ids: decode_ids("chapter_ids")?,
+            attempt: row.try_get("attempt")?,
+            succeeded: row.try_get("succeeded")?,
+            error: row.try_get("error")?,
+            retry_at: row.try_get("retry_at")?,
+            delivered_recipient_ids: decode_ids("delivered_recipient_ids")?,
+            attempted_at: row.try_get("attempted_at")?,
+        })
+    }
+}
+
+/// What a channel has reported of a message, in the order they happen. A message only moves
+/// forward, so a late `delivered` event doesn't hide that it was opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReceiptState {
+    /// The channel took the message for sending.
+    Accepted,
+    /// The channel gave up on sending the message.
+    Failed,
+    Delivered,
+    Opened,
+}
+
+impl ReceiptState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReceiptState::Accepted => "accepted",
+            ReceiptState::Failed => "failed",
+            ReceiptState::Delivered => "delivered",
+            ReceiptState::Opened => "opened",
+        }
+    }
+}
+
+impl TryFrom<(&SqliteRow, &str)> for ReceiptState {
+    type Error = sqlx::Error;
+
+    fn try_from(value: (&SqliteRow, &str)) -> core::result::Result<Self, Self::Error> {
+        let (row, index) = value;
+        let state: String = row.try_get(index)?;
+        serde_json::from_value(serde_json::Value::String(state)).map_err(|err| {
+            sqlx::Error::ColumnDecode {
+                index: index.into(),
+                source: Box::new(err),
+            }
+        })
+    }
+}
+
+/// A message sent for a delivery attempt, through a channel which reports its fate.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SentMessage {
+    pub subscriber_id: Uuid,
+    pub channel: String,
+    pub message_id: String,
+}
+
+/// What is known of a message sent for a delivery attempt.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct DeliveryReceipt {
+    pub id: Uuid,
+    #[serde(rename = "attemptId")]
+    pub attempt_id: Uuid,
+    #[serde(rename = "subscriberId")]
+    pub subscriber_id: Uuid,
+    pub channel: String,
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+    pub state: ReceiptState,
+    /// The channel's explanation of the state, such as why sending failed.
+    pub detail: Option<String>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for DeliveryReceipt {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(DeliveryReceipt {
+            id: decode_uuid(row, "id")?,
+            attempt_id: decode_uuid(row, "attempt_id")?,
+            subscriber_id: decode_uuid(row, "subscriber_id")?,
+            channel: row.try_get("channel")?,
+            message_id: row.try_get("message_id")?,
+            state: (row, "state").try_into()?,
+            detail: row.try_get("detail")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+pub struct DeliveryClient {
+    pool: Pool<Sqlite>,
+}
+
+impl DeliveryClient {
+    pub fn new(pool: &Pool<Sqlite>) -> DeliveryClient {
+        DeliveryClient { pool: pool.clone() }
+    }
+
+    #[instrument(skip(self, chapters), fields(chapter.ids = ?chapters.iter().map(|x| x.id).collect::<Vec<_>>()))]
+    pub async fn record_deliveries(
+        &self,
+        subscription_id: &Uuid,
+        chapters: &[Chapter],
+    ) -> ApiResult<Vec<Delivery>> {
+        let mut transaction = self.pool.begin().await?;
+        let mut deliveries = Vec::with_capacity(chapters.len());
+        let delivered_at = Utc::now();
+        for chapter in chapters {
+            let delivery = sqlx::query_as::<_, Delivery>(
+                "INSERT INTO deliveries(id, subscription_id, chapter_id, chapter_created_at, delivered_at)
+                 VALUES(?, ?, ?, ?, ?)
+                 RETURNING *;",
+            )
+            .bind(Uuid::new_v4().as_bytes().as_slice())
+            .bind(subscription_id.as_bytes().as_slice())
+            .bind(chapter.id.as_bytes().as_slice())
+            .bind(chapter.created_at)
+            .bind(delivered_at)
+            .fetch_one(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+            deliveries.push(delivery);
+        }
+        transaction.commit().await?;
+        for delivery in &deliveries {
+            publish(Change::new(
+                Entity::Delivery,
+                delivery.id,
+                ChangeKind::Created,
+            ));
+        }
+        Ok(deliveries)
+    }
+
+    /// Records an attempt at delivering the chapters. A failed attempt should have a `retry_at`.
+    #[instrument(skip(self))]
+    pub async fn record_attempt(
+        &self,
+        subscription_id: &Uuid,
+        chapter_ids: &[Uuid],
+        attempt: i64,
+        error: Option<&str>,
+        retry_at: Option<&DateTime<Utc>>,
+        delivered_recipient_ids: &[Uuid],
+    ) -> ApiResult<DeliveryAttempt> {
+        let attempt = sqlx::query_as::<_, DeliveryAttempt>(
+            "INSERT INTO delivery_attempts(id, subscription_id, chapter_ids, attempt, succeeded, error, retry_at, delivered_recipient_ids, attempted_at)
+             VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?)
+             RETURNING *;",
+        )
+        .bind(Uuid::new_v4().as_bytes().as_slice())
+        .bind(subscription_id.as_bytes().as_slice())
+        .bind(serde_json::to_string(chapter_ids)?)
+        .bind(attempt)
+        .bind(error.is_none())
+        .bind(error)
+        .bind(retry_at)
+        .bind(serde_json::to_string(delivered_recipient_ids)?)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(attempt)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn latest_attempt(
+        &self,
+        subscription_id: &Uuid,
+    ) -> ApiResult<Option<DeliveryAttempt>> {
+        let attempt = sqlx::query_as::<_, DeliveryAttempt>(
+            "SELECT * FROM delivery_attempts WHERE subscription_id = ? ORDER BY attempted_at DESC LIMIT 1",
+        )
+        .bind(subscription_id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(attempt)
+    }
+
+    /// Lists the subscription's attempts, most recent first.
+    #[instrument(skip(self))]
+    pub async fn list_attempts(
+        &self,
+        subscription_id: &Uuid,
+        limit: i64,
+    ) -> ApiResult<Vec<DeliveryAttempt>> {
+        let attempts = sqlx::query_as::<_, DeliveryAttempt>(
+            "SELECT * FROM delivery_attempts WHERE subscription_id = ? ORDER BY attempted_at DESC LIMIT ?",
+        )
+        .bind(subscription_id.as_bytes().as_slice())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(attempts)
+    }
+
+    /// Lists the attempts at any of the subscriber's subscriptions, most recent first.
+    #[instrument(skip(self))]
+    pub async fn list_subscriber_attempts(
+        &self,
+        subscriber_id: &Uuid,
+        limit: i64,
+    ) -> ApiResult<Vec<DeliveryAttempt>> {
+        let attempts = sqlx::query_as::<_, DeliveryAttempt>(
+            "SELECT delivery_attempts.* FROM delivery_attempts
+                 JOIN subscriptions ON subscriptions.id = delivery_attempts.subscription_id
+                 WHERE subscriptions.subscriber_id = ?
+                 ORDER BY attempted_at DESC LIMIT ?",
+        )
+        .bind(subscriber_id.as_bytes().as_slice())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(attempts)
+    }
+
+    /// Records the messages the attempt was sent as, each accepted by its channel.
+    #[instrument(skip(self, messages), fields(messages = messages.len()))]
+    pub async fn record_receipts(
+        &self,
+        attempt_id: &Uuid,
+        messages: &[SentMessage],
+    ) -> ApiResult<()> {
+        let mut transaction = self.pool.begin().await?;
+        for message in messages {
+            sqlx::query(
+                "INSERT INTO delivery_receipts(id, attempt_id, subscriber_id, channel, message_id, state, created_at, updated_at)
+                 VALUES(?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(Uuid::new_v4().as_bytes().as_slice())
+            .bind(attempt_id.as_bytes().as_slice())
+            .bind(message.subscriber_id.as_bytes().as_slice())
+            .bind(&message.channel)
+            .bind(&message.message_id)
+            .bind(ReceiptState::Accepted.as_str())
+            .bind(Utc::now())
+            .bind(Utc::now())
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Moves the message's receipts forward to the state, leaving those already past it.
+    /// Returns the receipts which changed, which are none for messages cereal didn't send.
+    #[instrument(skip(self))]
+    pub async fn advance_receipts(
+        &self,
+        message_id: &str,
+        state: ReceiptState,
+        detail: Option<&str>,
+    ) -> ApiResult<Vec<DeliveryReceipt>> {
+        let receipts = sqlx::query_as::<_, DeliveryReceipt>(
+            "SELECT * FROM delivery_receipts WHERE message_id = ?",
+        )
+        .bind(message_id)
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        let mut changed = Vec::new();
+        for receipt in receipts.into_iter().filter(|x| x.state < state) {
+            sqlx::query(
+                "UPDATE delivery_receipts SET state = ?, detail = ?, updated_at = ? WHERE id = ?",
+            )
+            .bind(state.as_str())
+            .bind(detail)
+            .bind(Utc::now())
+            .bind(receipt.id.as_bytes().as_slice())
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+            changed.push(receipt);
+        }
+        Ok(changed)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_receipts(&self, attempt_id: &Uuid) -> ApiResult<Vec<DeliveryReceipt>> {
+        let receipts = sqlx::query_as::<_, DeliveryReceipt>(
+            "SELECT * FROM delivery_receipts WHERE attempt_id = ? ORDER BY created_at",
+        )
+        .bind(attempt_id.as_bytes().as_slice())
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(receipts)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_deliveries_since(&self, since: &DateTime<Utc>) -> ApiResult<Vec<Delivery>> {
+        let deliveries = sqlx::query_as::<_, Delivery>(
+            "SELECT * FROM deliveries WHERE delivered_at > ? ORDER BY delivered_at ASC",
+        )
+        .bind(since)
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(deliveries)
+    }
+}
@@ -0,0 +1,64 @@
+use chrono::{Duration, Utc};
+use sqlx::{Pool, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+
+use crate::error::ApiResult;
+
+/// Named, expiring locks which let several instances share one database without running the
+/// same job twice.
+pub struct LeaseClient {
+    pool: Pool<Sqlite>,
+}
+
+impl LeaseClient {
+    pub fn new(pool: &Pool<Sqlite>) -> LeaseClient {
+        LeaseClient { pool: pool.clone() }
+    }
+
+    /// Takes the named lease for `ttl`. Returns false if it is held by another holder and has
+    /// not yet expired. A holder may re-acquire its own lease to extend it.
+    #[instrument(skip(self))]
+    pub async fn acquire_lease(&self, name: &str, holder: &str, ttl: Duration) -> ApiResult<bool> {
+        let now = Utc::now();
+        let result = sqlx::query(
+            "INSERT INTO task_leases(name, holder, expires_at)
+                 VALUES(?, ?, ?)
+                 ON CONFLICT(name) DO UPDATE
+                 SET holder = excluded.holder,
+                  expires_at = excluded.expires_at
+                 WHERE task_leases.holder = excluded.holder OR task_leases.expires_at < ?;",
+        )
+        .bind(name)
+        .bind(holder)
+        .bind(now + ttl)
+        .bind(now)
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn release_lease(&self, name: &str, holder: &str) -> ApiResult<()> {
+        sqlx::query("DELETE FROM task_leases WHERE name = ? AND holder = ?")
+            .bind(name)
+            .bind(holder)
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(())
+    }
+
+    /// Drops the leases held by `holder`, which a restarted process can no longer be using, and
+    /// any lease which has expired. Returns the number of leases dropped.
+    #[instrument(skip(self))]
+    pub async fn release_stale_leases(&self, holder: &str) -> ApiResult<u64> {
+        let result = sqlx::query("DELETE FROM task_leases WHERE holder = ? OR expires_at < ?")
+            .bind(holder)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
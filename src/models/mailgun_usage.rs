@@ -0,0 +1,46 @@
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+
+use crate::error::ApiResult;
+
+/// Messages sent through mailgun each month, by every instance using the database.
+pub struct MailgunUsageClient {
+    pool: Pool<Sqlite>,
+}
+
+impl MailgunUsageClient {
+    pub fn new(pool: &Pool<Sqlite>) -> MailgunUsageClient {
+        MailgunUsageClient { pool: pool.clone() }
+    }
+
+    /// Adds to the messages sent in the month, given as `YYYY-MM`.
+    #[instrument(skip(self))]
+    pub async fn add_messages(&self, month: &str, messages: i64) -> ApiResult<()> {
+        sqlx::query(
+            "INSERT INTO mailgun_usage(month, messages, updated_at)
+                 VALUES(?, ?, ?)
+                 ON CONFLICT(month) DO UPDATE
+                 SET messages = messages + excluded.messages,
+                  updated_at = excluded.updated_at;",
+        )
+        .bind(month)
+        .bind(messages)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn messages_sent(&self, month: &str) -> ApiResult<i64> {
+        let messages: Option<(i64,)> =
+            sqlx::query_as("SELECT messages FROM mailgun_usage WHERE month = ?")
+                .bind(month)
+                .fetch_optional(&self.pool)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        Ok(messages.map_or(0, |(x,)| x))
+    }
+}
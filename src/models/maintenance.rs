@@ -0,0 +1,206 @@
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+
+use crate::error::ApiResult;
+
+use super::{chapters, subscriptions};
+
+/// SQLite's auto_vacuum mode which frees pages on request rather than on every commit.
+const AUTO_VACUUM_INCREMENTAL: i64 = 2;
+
+/// A query run on every tick of the pipeline: its name, its sql and the tables it is expected
+/// to scan in full.
+struct HotQuery {
+    name: &'static str,
+    sql: &'static str,
+    expected_scans: &'static [&'static str],
+}
+
+const HOT_QUERIES: &[HotQuery] = &[
+    HotQuery {
+        name: "chaptersWithoutBodies",
+        sql: chapters::CHAPTERS_WITHOUT_BODIES,
+        expected_scans: &[],
+    },
+    HotQuery {
+        name: "chaptersReadyForConversion",
+        sql: chapters::CHAPTERS_READY_FOR_CONVERSION,
+        expected_scans: &[],
+    },
+    HotQuery {
+        name: "hydrationBacklog",
+        sql: chapters::HYDRATION_BACKLOG,
+        expected_scans: &[],
+    },
+    HotQuery {
+        name: "conversionBacklog",
+        sql: chapters::CONVERSION_BACKLOG,
+        expected_scans: &[],
+    },
+    HotQuery {
+        name: "chaptersWithEpub",
+        sql: chapters::CHAPTERS_WITH_EPUB,
+        expected_scans: &[],
+    },
+    HotQuery {
+        name: "chaptersShallow",
+        sql: chapters::CHAPTERS_SHALLOW,
+        expected_scans: &[],
+    },
+    HotQuery {
+        name: "subscriptionsForSubscriber",
+        sql: subscriptions::SUBSCRIPTIONS_FOR_SUBSCRIBER,
+        expected_scans: &[],
+    },
+    HotQuery {
+        name: "subscriptionsForBook",
+        sql: subscriptions::SUBSCRIPTIONS_FOR_BOOK,
+        expected_scans: &[],
+    },
+    HotQuery {
+        name: "deliveryBacklog",
+        sql: subscriptions::DELIVERY_BACKLOG,
+        expected_scans: &["subscriptions"],
+    },
+];
+
+/// How sqlite runs a hot query.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct QueryPlan {
+    pub name: &'static str,
+    pub sql: &'static str,
+    /// The steps of the plan, as EXPLAIN QUERY PLAN describes them.
+    pub plan: Vec<String>,
+    /// Tables read in full without an index where an index was expected, which makes the query
+    /// slow down as the table grows.
+    #[serde(rename = "unexpectedScans")]
+    pub unexpected_scans: Vec<String>,
+}
+
+/// Housekeeping statements run against the database file itself.
+pub struct MaintenanceClient {
+    pool: Pool<Sqlite>,
+}
+
+impl MaintenanceClient {
+    pub fn new(pool: &Pool<Sqlite>) -> MaintenanceClient {
+        MaintenanceClient { pool: pool.clone() }
+    }
+
+    /// Lets sqlite refresh the statistics its query planner relies on.
+    #[instrument(skip(self))]
+    pub async fn optimize(&self) -> ApiResult<()> {
+        sqlx::query("PRAGMA optimize")
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(())
+    }
+
+    async fn free_pages(&self) -> ApiResult<i64> {
+        let (pages,): (i64,) = sqlx::query_as("PRAGMA freelist_count")
+            .fetch_one(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(pages)
+    }
+
+    /// Returns free pages to the filesystem. Databases created without incremental auto vacuum
+    /// are switched to it, which takes one full vacuum. Returns the pages freed and whether a
+    /// full vacuum was needed.
+    #[instrument(skip(self))]
+    pub async fn vacuum(&self) -> ApiResult<(i64, bool)> {
+        let before = self.free_pages().await?;
+        // The mode only changes on the connection which then vacuums.
+        let mut connection = self.pool.acquire().await?;
+        let (mode,): (i64,) = sqlx::query_as("PRAGMA auto_vacuum")
+            .fetch_one(&mut connection)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        let full = mode != AUTO_VACUUM_INCREMENTAL;
+        if full {
+            sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+                .execute(&mut connection)
+                .await?;
+            sqlx::query("VACUUM")
+                .execute(&mut connection)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        } else {
+            sqlx::query("PRAGMA incremental_vacuum")
+                .execute(&mut connection)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        }
+        drop(connection);
+        Ok((before - self.free_pages().await?, full))
+    }
+
+    /// Merges the segments of every fts5 index.
+    #[instrument(skip(self))]
+    pub async fn optimize_fts_indexes(&self) -> ApiResult<Vec<String>> {
+        let tables: Vec<(String,)> = sqlx::query_as(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND sql LIKE '%USING fts5%'",
+        )
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        let mut optimized = Vec::with_capacity(tables.len());
+        for (table,) in tables {
+            // Table names can't be bound, but these come from the schema itself.
+            let table = table.replace('"', "\"\"");
+            sqlx::query(&format!(
+                "INSERT INTO \"{0}\"(\"{0}\") VALUES('optimize')",
+                table
+            ))
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+            optimized.push(table);
+        }
+        Ok(optimized)
+    }
+
+    /// Removes artifacts whose book is gone, left behind if foreign keys were ever disabled.
+    #[instrument(skip(self))]
+    pub async fn delete_orphaned_artifacts(&self) -> ApiResult<u64> {
+        let result =
+            sqlx::query("DELETE FROM book_artifacts WHERE book_id NOT IN (SELECT id FROM books)")
+                .execute(&self.pool)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Explains how sqlite runs each of the queries the pipeline runs on every tick, flagging
+    /// those which no longer use an index.
+    #[instrument(skip(self))]
+    pub async fn explain_hot_queries(&self) -> ApiResult<Vec<QueryPlan>> {
+        let mut plans = Vec::with_capacity(HOT_QUERIES.len());
+        for query in HOT_QUERIES {
+            // Parameters left unbound are null, which doesn't change the plan.
+            let rows: Vec<(i64, i64, i64, String)> =
+                sqlx::query_as(&format!("EXPLAIN QUERY PLAN {}", query.sql))
+                    .fetch_all(&self.pool)
+                    .instrument(info_span!("Querying db"))
+                    .await?;
+            let plan: Vec<String> = rows.into_iter().map(|(_, _, _, detail)| detail).collect();
+            // Index scans read "SCAN table USING INDEX name".
+            let unexpected_scans = plan
+                .iter()
+                .filter(|x| !x.contains(" USING "))
+                .filter_map(|x| x.strip_prefix("SCAN "))
+                .map(|x| x.split_whitespace().next().unwrap_or(x).to_owned())
+                .filter(|x| !query.expected_scans.contains(&x.as_str()))
+                .collect();
+            plans.push(QueryPlan {
+                name: query.name,
+                sql: query.sql,
+                plan,
+                unexpected_scans,
+            });
+        }
+        Ok(plans)
+    }
+}
@@ -1,20 +1,80 @@
+mod anthology_subscriptions;
+mod backfills;
+mod blobs;
+mod book_artifacts;
 mod books;
+pub mod changes;
+mod chapter_sources;
 mod chapters;
+mod credentials;
+mod deliveries;
+mod leases;
+mod mailgun_usage;
+mod maintenance;
+mod reading_progress;
+mod settings;
+mod share_links;
+mod storage;
 mod subscribers;
 mod subscriptions;
+mod tags;
+mod volumes;
+mod websub_subscriptions;
+mod wildcard_subscriptions;
 use sqlx::{sqlite::SqliteRow, Row};
 use uuid::Uuid;
 
-pub use books::{Book, BookClient, BookMetadata};
-pub use chapters::{Chapter, ChapterClient, ChapterMetadata, NewChapter, ShallowChapter};
-pub use subscribers::{Subscriber, SubscriberClient};
-pub use subscriptions::{Subscription, SubscriptionClient};
+pub use anthology_subscriptions::{AnthologySubscription, AnthologySubscriptionClient};
+pub use backfills::{Backfill, BackfillClient};
+pub use blobs::BlobStream;
+pub use book_artifacts::{BookArtifact, BookArtifactClient, OMNIBUS_ARTIFACT};
+pub use books::{
+    Book, BookClient, BookMetadata, BookSummary, ConversionOptions, DeliveryTemplates,
+    HtmlTransform, RoyalRoadOptions, TypographyOptions,
+};
+pub use chapter_sources::ChapterSourceClient;
+pub use chapters::{
+    Backlog, Chapter, ChapterBody, ChapterClient, ChapterMetadata, ChapterState, NewChapter,
+    QueuedChapter, ShallowChapter,
+};
+pub use credentials::{credential, Credential, CredentialClient};
+pub use deliveries::{
+    Delivery, DeliveryAttempt, DeliveryClient, DeliveryReceipt, ReceiptState, SentMessage,
+};
+pub use leases::LeaseClient;
+pub use mailgun_usage::MailgunUsageClient;
+pub use maintenance::{MaintenanceClient, QueryPlan};
+pub use reading_progress::{KosyncUser, ReadingProgress, ReadingProgressClient};
+pub use settings::SettingsClient;
+pub use share_links::{ShareFormat, ShareLink, ShareLinkClient};
+pub use storage::{BookStorage, StorageClient};
+pub use subscribers::{ReadLaterService, Subscriber, SubscriberClient, SubscriptionDefaults};
+pub use subscriptions::{
+    max_chunk_size, BacklogDelivery, DeliveryFormat, PendingSubscription, Subscription,
+    SubscriptionClient, MIN_CHUNK_SIZE,
+};
+pub use tags::{Tag, TagClient};
+pub use volumes::{Volume, VolumeClient, VolumePosition};
+pub use websub_subscriptions::{WebSubSubscription, WebSubSubscriptionClient};
+pub use wildcard_subscriptions::{WildcardSubscription, WildcardSubscriptionClient};
+
+/// A uuid column held something other than the 16 bytes of a uuid, such as a uuid stored as
+/// text.
+#[derive(Debug, thiserror::Error)]
+#[error("expected the 16 bytes of a uuid, found {len} bytes {preview:?}")]
+struct InvalidUuid {
+    len: usize,
+    preview: String,
+}
 
 fn decode_uuid(row: &SqliteRow, index: &str) -> core::result::Result<Uuid, sqlx::Error> {
     let id: &[u8] = row.try_get(index)?;
-    let id: &[u8; 16] = id.try_into().map_err(|err| sqlx::Error::ColumnDecode {
+    let id: &[u8; 16] = id.try_into().map_err(|_| sqlx::Error::ColumnDecode {
         index: index.into(),
-        source: Box::new(err),
+        source: Box::new(InvalidUuid {
+            len: id.len(),
+            preview: String::from_utf8_lossy(&id[..id.len().min(40)]).into_owned(),
+        }),
     })?;
     Ok(*Uuid::from_bytes_ref(id))
 }
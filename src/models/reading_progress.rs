@@ -0,0 +1,160 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+
+use crate::{
+    error::{ApiError, ApiResult},
+    util::is_unique_error,
+};
+
+/// A KOReader sync account. The key is the hash of the password computed by the device, and
+/// is compared as given.
+#[derive(PartialEq, Clone)]
+pub struct KosyncUser {
+    pub username: String,
+    pub userkey: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl std::fmt::Debug for KosyncUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KosyncUser")
+            .field("username", &self.username)
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for KosyncUser {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(KosyncUser {
+            username: row.try_get("username")?,
+            userkey: row.try_get("userkey")?,
+            created_at: row.try_get("created_at")?,
+        })
+    }
+}
+
+/// How far a user has read a document, as last reported by one of their devices. Documents
+/// are identified by the hash KOReader computes from the file.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct ReadingProgress {
+    pub username: String,
+    pub document: String,
+    /// The reader's position within the document, opaque to the server.
+    pub progress: String,
+    pub percentage: f64,
+    pub device: String,
+    pub device_id: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for ReadingProgress {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(ReadingProgress {
+            username: row.try_get("username")?,
+            document: row.try_get("document")?,
+            progress: row.try_get("progress")?,
+            percentage: row.try_get("percentage")?,
+            device: row.try_get("device")?,
+            device_id: row.try_get("device_id")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+pub struct ReadingProgressClient {
+    pool: Pool<Sqlite>,
+}
+
+impl ReadingProgressClient {
+    pub fn new(pool: &Pool<Sqlite>) -> ReadingProgressClient {
+        ReadingProgressClient { pool: pool.clone() }
+    }
+
+    #[instrument(skip(self, userkey))]
+    pub async fn create_user(&self, username: &str, userkey: &str) -> ApiResult<KosyncUser> {
+        let user = sqlx::query_as::<_, KosyncUser>(
+            "INSERT INTO kosync_users(username, userkey, created_at)
+                 VALUES(?, ?, ?)
+                 RETURNING *;",
+        )
+        .bind(username)
+        .bind(userkey)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match user {
+            Ok(user) => Ok(user),
+            Err(e) if is_unique_error(&e) => Err(ApiError::InvalidRequest(format!(
+                "Username {:?} is already registered",
+                username
+            ))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_user(&self, username: &str) -> ApiResult<Option<KosyncUser>> {
+        let user = sqlx::query_as::<_, KosyncUser>("SELECT * FROM kosync_users WHERE username = ?")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(user)
+    }
+
+    /// Records the user's position in the document, replacing the previous one.
+    #[instrument(skip(self))]
+    pub async fn set_progress(
+        &self,
+        username: &str,
+        document: &str,
+        progress: &str,
+        percentage: f64,
+        device: &str,
+        device_id: &str,
+    ) -> ApiResult<ReadingProgress> {
+        let progress = sqlx::query_as::<_, ReadingProgress>(
+            "INSERT INTO reading_progress(username, document, progress, percentage, device, device_id, updated_at)
+                 VALUES(?, ?, ?, ?, ?, ?, ?)
+                 ON CONFLICT(username, document) DO UPDATE
+                 SET progress = excluded.progress,
+                  percentage = excluded.percentage,
+                  device = excluded.device,
+                  device_id = excluded.device_id,
+                  updated_at = excluded.updated_at
+                 RETURNING *;",
+        )
+        .bind(username)
+        .bind(document)
+        .bind(progress)
+        .bind(percentage)
+        .bind(device)
+        .bind(device_id)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(progress)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_progress(
+        &self,
+        username: &str,
+        document: &str,
+    ) -> ApiResult<Option<ReadingProgress>> {
+        let progress = sqlx::query_as::<_, ReadingProgress>(
+            "SELECT * FROM reading_progress WHERE username = ? AND document = ?",
+        )
+        .bind(username)
+        .bind(document)
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(progress)
+    }
+}
@@ -0,0 +1,59 @@
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{Pool, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+
+use crate::error::ApiResult;
+
+/// Runtime settings stored as json, shared by every instance using the database.
+pub struct SettingsClient {
+    pool: Pool<Sqlite>,
+}
+
+impl SettingsClient {
+    pub fn new(pool: &Pool<Sqlite>) -> SettingsClient {
+        SettingsClient { pool: pool.clone() }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_setting<T: DeserializeOwned>(&self, key: &str) -> ApiResult<Option<T>> {
+        let value: Option<(String,)> = sqlx::query_as("SELECT value FROM settings WHERE key = ?")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        match value {
+            Some((value,)) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    #[instrument(skip(self, value))]
+    pub async fn set_setting<T: Serialize>(&self, key: &str, value: &T) -> ApiResult<()> {
+        let value = serde_json::to_string(value)?;
+        sqlx::query(
+            "INSERT INTO settings(key, value, updated_at)
+                 VALUES(?, ?, ?)
+                 ON CONFLICT(key) DO UPDATE
+                 SET value = excluded.value,
+                  updated_at = excluded.updated_at;",
+        )
+        .bind(key)
+        .bind(value)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_setting(&self, key: &str) -> ApiResult<()> {
+        sqlx::query("DELETE FROM settings WHERE key = ?")
+            .bind(key)
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(())
+    }
+}
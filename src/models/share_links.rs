@@ -0,0 +1,180 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    util::is_foreign_key_error,
+};
+
+use super::decode_uuid;
+
+/// Which of the chapter's bodies a share link serves.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ShareFormat {
+    Html,
+    Epub,
+}
+
+impl ShareFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShareFormat::Html => "html",
+            ShareFormat::Epub => "epub",
+        }
+    }
+}
+
+/// A link which serves one chapter to anyone holding its signed url, until it expires or is
+/// revoked.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct ShareLink {
+    pub id: Uuid,
+    #[serde(rename = "chapterId")]
+    pub chapter_id: Uuid,
+    pub format: ShareFormat,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(rename = "revokedAt")]
+    pub revoked_at: Option<DateTime<Utc>>,
+    #[serde(rename = "accessCount")]
+    pub access_count: i64,
+    #[serde(rename = "lastAccessedAt")]
+    pub last_accessed_at: Option<DateTime<Utc>>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl ShareLink {
+    /// Whether the link may still be used.
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none() && !matches!(self.expires_at, Some(x) if x <= Utc::now())
+    }
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for ShareLink {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        let format: String = row.try_get("format")?;
+        let format = match format.as_str() {
+            "html" => ShareFormat::Html,
+            "epub" => ShareFormat::Epub,
+            _ => {
+                return Err(sqlx::Error::ColumnDecode {
+                    index: String::from("format"),
+                    source: format!("Unknown share format {:?}", format).into(),
+                })
+            }
+        };
+        Ok(ShareLink {
+            id: decode_uuid(row, "id")?,
+            chapter_id: decode_uuid(row, "chapter_id")?,
+            format,
+            expires_at: row.try_get("expires_at")?,
+            revoked_at: row.try_get("revoked_at")?,
+            access_count: row.try_get("access_count")?,
+            last_accessed_at: row.try_get("last_accessed_at")?,
+            created_at: row.try_get("created_at")?,
+        })
+    }
+}
+
+pub struct ShareLinkClient {
+    pool: Pool<Sqlite>,
+}
+
+impl ShareLinkClient {
+    pub fn new(pool: &Pool<Sqlite>) -> ShareLinkClient {
+        ShareLinkClient { pool: pool.clone() }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn create_share_link(
+        &self,
+        chapter_id: &Uuid,
+        format: ShareFormat,
+        expires_at: Option<&DateTime<Utc>>,
+    ) -> ApiResult<ShareLink> {
+        let link = sqlx::query_as::<_, ShareLink>(
+            "INSERT INTO share_links(id, chapter_id, format, expires_at, created_at)
+                 VALUES(?, ?, ?, ?, ?)
+                 RETURNING *;",
+        )
+        .bind(Uuid::new_v4().as_bytes().as_slice())
+        .bind(chapter_id.as_bytes().as_slice())
+        .bind(format.as_str())
+        .bind(expires_at)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match link {
+            Ok(link) => Ok(link),
+            Err(e) if is_foreign_key_error(&e) => Err(ApiError::ResourceNotFound {
+                id: chapter_id.to_string(),
+                resource_type: String::from("chapter"),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_share_link(&self, id: &Uuid) -> ApiResult<Option<ShareLink>> {
+        let link = sqlx::query_as::<_, ShareLink>("SELECT * FROM share_links WHERE id = ?")
+            .bind(id.as_bytes().as_slice())
+            .fetch_optional(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(link)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_share_links(&self, chapter_id: &Uuid) -> ApiResult<Vec<ShareLink>> {
+        let links = sqlx::query_as::<_, ShareLink>(
+            "SELECT * FROM share_links WHERE chapter_id = ? ORDER BY created_at DESC",
+        )
+        .bind(chapter_id.as_bytes().as_slice())
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(links)
+    }
+
+    /// Revokes the link. Revoking it again keeps the original revocation time.
+    #[instrument(skip(self))]
+    pub async fn revoke_share_link(&self, id: &Uuid) -> ApiResult<ShareLink> {
+        let link = sqlx::query_as::<_, ShareLink>(
+            "UPDATE share_links
+                 SET revoked_at = coalesce(revoked_at, ?)
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        link.ok_or_else(|| ApiError::ResourceNotFound {
+            id: id.to_string(),
+            resource_type: String::from("share link"),
+        })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn record_access(&self, id: &Uuid) -> ApiResult<()> {
+        sqlx::query(
+            "UPDATE share_links
+                 SET access_count = access_count + 1,
+                  last_accessed_at = ?
+                 WHERE id = ?;",
+        )
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(())
+    }
+}
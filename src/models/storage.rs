@@ -0,0 +1,106 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+use uuid::Uuid;
+
+use crate::error::ApiResult;
+
+use super::decode_uuid;
+
+/// The space taken by a book's chapter bodies and artifacts.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct BookStorage {
+    #[serde(rename = "bookId")]
+    pub book_id: Uuid,
+    pub title: String,
+    pub chapters: i64,
+    #[serde(rename = "htmlBytes")]
+    pub html_bytes: i64,
+    #[serde(rename = "epubBytes")]
+    pub epub_bytes: i64,
+    #[serde(rename = "artifactBytes")]
+    pub artifact_bytes: i64,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for BookStorage {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(BookStorage {
+            book_id: decode_uuid(row, "book_id")?,
+            title: row.try_get("title")?,
+            chapters: row.try_get("chapters")?,
+            html_bytes: row.try_get("html_bytes")?,
+            epub_bytes: row.try_get("epub_bytes")?,
+            artifact_bytes: row.try_get("artifact_bytes")?,
+        })
+    }
+}
+
+/// Reports how much space the database uses. Blob sizes are read with `length()` so that the
+/// blobs themselves are never loaded.
+pub struct StorageClient {
+    pool: Pool<Sqlite>,
+}
+
+impl StorageClient {
+    pub fn new(pool: &Pool<Sqlite>) -> StorageClient {
+        StorageClient { pool: pool.clone() }
+    }
+
+    /// Every book's usage, largest first.
+    #[instrument(skip(self))]
+    pub async fn list_book_storage(&self) -> ApiResult<Vec<BookStorage>> {
+        let books = sqlx::query_as::<_, BookStorage>(
+            "SELECT books.id AS book_id,
+                  books.title AS title,
+                  coalesce(c.chapters, 0) AS chapters,
+                  coalesce(c.html_bytes, 0) AS html_bytes,
+                  coalesce(c.epub_bytes, 0) AS epub_bytes,
+                  coalesce(a.artifact_bytes, 0) AS artifact_bytes
+                 FROM books
+                 LEFT JOIN (
+                  SELECT book_id,
+                   count(*) AS chapters,
+                   sum(coalesce(length(html), 0)) AS html_bytes,
+                   sum(coalesce(length(epub), 0)) AS epub_bytes
+                  FROM chapters GROUP BY book_id
+                 ) c ON c.book_id = books.id
+                 LEFT JOIN (
+                  SELECT book_id, sum(length(content)) AS artifact_bytes
+                  FROM book_artifacts GROUP BY book_id
+                 ) a ON a.book_id = books.id
+                 ORDER BY coalesce(c.html_bytes, 0) + coalesce(c.epub_bytes, 0) + coalesce(a.artifact_bytes, 0) DESC",
+        )
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(books)
+    }
+
+    /// The size of the database file, and how much of it is free pages awaiting a vacuum.
+    #[instrument(skip(self))]
+    pub async fn database_bytes(&self) -> ApiResult<(i64, i64)> {
+        let (total, free): (i64, i64) = sqlx::query_as(
+            "SELECT page_count * page_size, freelist_count * page_size
+                 FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+        )
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok((total, free))
+    }
+
+    /// Bytes of chapter bodies stored for chapters discovered since `since`.
+    #[instrument(skip(self))]
+    pub async fn chapter_bytes_since(&self, since: &DateTime<Utc>) -> ApiResult<i64> {
+        let (bytes,): (i64,) = sqlx::query_as(
+            "SELECT coalesce(sum(coalesce(length(html), 0) + coalesce(length(epub), 0)), 0)
+                 FROM chapters WHERE created_at > ?",
+        )
+        .bind(since)
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(bytes)
+    }
+}
@@ -1,38 +1,146 @@
 use chrono::Utc;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
 use tracing::{info_span, instrument, Instrument};
 use uuid::Uuid;
 
-use crate::error::{ApiError, ApiResult};
+use crate::{
+    error::{ApiError, ApiResult},
+    util::is_foreign_key_error,
+};
 
-use super::decode_uuid;
+use super::{
+    changes::{publish, Change, ChangeKind, Entity},
+    decode_uuid,
+};
 
 pub struct SubscriberClient {
     pool: Pool<Sqlite>,
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize)]
+/// Settings the subscriber's new subscriptions, including those made by wildcard subscriptions,
+/// start with unless the request sets them.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriptionDefaults {
+    #[serde(rename = "chunkSize", skip_serializing_if = "Option::is_none")]
+    pub chunk_size: Option<i32>,
+    #[serde(rename = "earlyAccess", skip_serializing_if = "Option::is_none")]
+    pub early_access: Option<bool>,
+}
+
+/// A read-later service which chapters can be saved to in place of, or alongside, an e-reader.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReadLaterService {
+    /// Saved by url. The token is the account's `username:password`.
+    Instapaper,
+    /// Saved by url. The token is an access token for CEREAL_POCKET_CONSUMER_KEY's app.
+    Pocket,
+    /// Saved with the chapter's html, so chapters without a public url can be read. The token
+    /// is a Readwise access token.
+    ReadwiseReader,
+}
+
+impl ReadLaterService {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReadLaterService::Instapaper => "instapaper",
+            ReadLaterService::Pocket => "pocket",
+            ReadLaterService::ReadwiseReader => "readwiseReader",
+        }
+    }
+}
+
+fn decode_read_later_service(row: &SqliteRow) -> Result<Option<ReadLaterService>, sqlx::Error> {
+    let service: Option<String> = row.try_get("read_later_service")?;
+    service
+        .map(|x| serde_json::from_value(serde_json::Value::String(x)))
+        .transpose()
+        .map_err(|err| sqlx::Error::ColumnDecode {
+            index: "read_later_service".into(),
+            source: Box::new(err),
+        })
+}
+
+#[derive(PartialEq, Clone, Serialize)]
 pub struct Subscriber {
     pub id: Uuid,
     pub name: String,
     #[serde(rename = "kindleEmail")]
     pub kindle_email: Option<String>,
+    /// Where subscriptions delivered as inline html are sent.
+    pub email: Option<String>,
     #[serde(rename = "pushoverKey")]
     pub pushover_key: Option<String>,
+    #[serde(rename = "readLaterService")]
+    pub read_later_service: Option<ReadLaterService>,
+    /// Never sent back to clients or logged, only used when saving chapters to the service.
+    #[serde(skip_serializing)]
+    pub read_later_token: Option<String>,
+    /// An apprise url, such as `signal://...`, which notifications are sent to.
+    #[serde(rename = "notifyUrl")]
+    pub notify_url: Option<String>,
+    #[serde(rename = "subscriptionDefaults")]
+    pub subscription_defaults: SubscriptionDefaults,
+    /// Failed deliveries and bounces since the last successful one.
+    #[serde(rename = "deliveryFailures")]
+    pub delivery_failures: i64,
+    #[serde(rename = "failingSince")]
+    pub failing_since: Option<chrono::DateTime<Utc>>,
+    #[serde(rename = "lastFailure")]
+    pub last_failure: Option<String>,
+    /// Set once the subscriber has failed for too long. Paused subscribers are sent nothing
+    /// until an admin resumes them.
+    #[serde(rename = "pausedAt")]
+    pub paused_at: Option<chrono::DateTime<Utc>>,
     #[serde(rename = "createdAt")]
     pub created_at: chrono::DateTime<Utc>,
     #[serde(rename = "updatedAt")]
     pub updated_at: chrono::DateTime<Utc>,
 }
 
+impl std::fmt::Debug for Subscriber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscriber")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("kindle_email", &self.kindle_email)
+            .field("email", &self.email)
+            .field("pushover_key", &self.pushover_key)
+            .field("read_later_service", &self.read_later_service)
+            .field("read_later_token_set", &self.read_later_token.is_some())
+            .field("notify_url", &self.notify_url)
+            .field("subscription_defaults", &self.subscription_defaults)
+            .field("delivery_failures", &self.delivery_failures)
+            .field("failing_since", &self.failing_since)
+            .field("last_failure", &self.last_failure)
+            .field("paused_at", &self.paused_at)
+            .field("created_at", &self.created_at)
+            .field("updated_at", &self.updated_at)
+            .finish()
+    }
+}
+
 impl<'r> sqlx::FromRow<'r, SqliteRow> for Subscriber {
     fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
         Ok(Subscriber {
             id: decode_uuid(row, "id")?,
             name: row.try_get("name")?,
             kindle_email: row.try_get("kindle_email")?,
+            email: row.try_get("email")?,
             pushover_key: row.try_get("pushover_key")?,
+            read_later_service: decode_read_later_service(row)?,
+            read_later_token: row.try_get("read_later_token")?,
+            notify_url: row.try_get("notify_url")?,
+            subscription_defaults: SubscriptionDefaults {
+                chunk_size: row.try_get("default_chunk_size")?,
+                early_access: row.try_get("default_early_access")?,
+            },
+            delivery_failures: row.try_get("delivery_failures")?,
+            failing_since: row.try_get("failing_since")?,
+            last_failure: row.try_get("last_failure")?,
+            paused_at: row.try_get("paused_at")?,
             created_at: row.try_get("created_at")?,
             updated_at: row.try_get("updated_at")?,
         })
@@ -44,57 +152,96 @@ impl SubscriberClient {
         SubscriberClient { pool: pool.clone() }
     }
 
-    #[instrument(skip(self))]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self, read_later))]
     pub async fn create_subscriber(
         &self,
         name: &str,
         pushover_key: Option<&str>,
         kindle_email: Option<&str>,
+        email: Option<&str>,
+        read_later: Option<(ReadLaterService, &str)>,
+        notify_url: Option<&str>,
+        subscription_defaults: &SubscriptionDefaults,
     ) -> ApiResult<Subscriber> {
         let subscriber = sqlx::query_as::<_, Subscriber>(
-            "INSERT INTO subscribers(id, name, kindle_email, pushover_key, created_at, updated_at) 
-            VALUES(?, ?, ?, ?, ?, ?) 
+            "INSERT INTO subscribers(id, name, kindle_email, email, pushover_key, read_later_service,
+                read_later_token, notify_url, default_chunk_size, default_early_access, created_at,
+                updated_at) 
+            VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) 
             RETURNING *;",
         )
         .bind(Uuid::new_v4().as_bytes().as_slice())
         .bind(name)
         .bind(kindle_email)
+        .bind(email)
         .bind(pushover_key)
+        .bind(read_later.map(|(service, _)| service.as_str()))
+        .bind(read_later.map(|(_, token)| token))
+        .bind(notify_url)
+        .bind(subscription_defaults.chunk_size)
+        .bind(subscription_defaults.early_access)
         .bind(Utc::now())
         .bind(Utc::now())
         .fetch_one(&self.pool)
         .instrument(info_span!("Querying db"))
         .await?;
+        publish(Change::new(
+            Entity::Subscriber,
+            subscriber.id,
+            ChangeKind::Created,
+        ));
         Ok(subscriber)
     }
 
-    #[instrument(skip(self))]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self, read_later))]
     pub async fn update_subscriber(
         &self,
         id: &Uuid,
         name: Option<&str>,
         kindle_email: Option<&str>,
+        email: Option<&str>,
         pushover_key: Option<&str>,
+        read_later: Option<(ReadLaterService, &str)>,
+        notify_url: Option<&str>,
+        subscription_defaults: Option<&SubscriptionDefaults>,
     ) -> ApiResult<Subscriber> {
+        let defaults = subscription_defaults.cloned().unwrap_or_default();
         let subscriber = sqlx::query_as::<_, Subscriber>(
             "UPDATE subscribers
                  SET kindle_email = coalesce(?, kindle_email),
+                  email = coalesce(?, email),
                   pushover_key = coalesce(?, pushover_key), 
+                  read_later_service = coalesce(?, read_later_service),
+                  read_later_token = coalesce(?, read_later_token),
+                  notify_url = coalesce(?, notify_url),
                   name = coalesce(?, name),
+                  default_chunk_size = coalesce(?, default_chunk_size),
+                  default_early_access = coalesce(?, default_early_access),
                   updated_at = ?
                  WHERE id = ? 
                  RETURNING *;",
         )
         .bind(kindle_email)
+        .bind(email)
         .bind(pushover_key)
+        .bind(read_later.map(|(service, _)| service.as_str()))
+        .bind(read_later.map(|(_, token)| token))
+        .bind(notify_url)
         .bind(name)
+        .bind(defaults.chunk_size)
+        .bind(defaults.early_access)
         .bind(Utc::now())
         .bind(id.as_bytes().as_slice())
         .fetch_optional(&self.pool)
         .instrument(info_span!("Querying db"))
         .await?;
         match subscriber {
-            Some(x) => Ok(x),
+            Some(x) => {
+                publish(Change::new(Entity::Subscriber, x.id, ChangeKind::Updated));
+                Ok(x)
+            }
             None => Err(ApiError::ResourceNotFound {
                 id: id.to_string(),
                 resource_type: String::from("subscriber"),
@@ -121,6 +268,181 @@ impl SubscriberClient {
         Ok(subscribers)
     }
 
+    /// Adds a member to a group. A group is a subscriber whose deliveries are sent to each of
+    /// its members instead of to itself.
+    #[instrument(skip(self))]
+    pub async fn add_group_member(&self, group_id: &Uuid, subscriber_id: &Uuid) -> ApiResult<()> {
+        let result = sqlx::query(
+            "INSERT INTO subscriber_group_members(group_id, subscriber_id, created_at)
+                 VALUES(?, ?, ?)
+                 ON CONFLICT DO NOTHING",
+        )
+        .bind(group_id.as_bytes().as_slice())
+        .bind(subscriber_id.as_bytes().as_slice())
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => match is_foreign_key_error(&e) {
+                true => Err(ApiError::ResourceNotFound {
+                    id: format!("{} or {}", group_id, subscriber_id),
+                    resource_type: String::from("subscriber"),
+                }),
+                false => Err(e.into()),
+            },
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn remove_group_member(
+        &self,
+        group_id: &Uuid,
+        subscriber_id: &Uuid,
+    ) -> ApiResult<()> {
+        sqlx::query(
+            "DELETE FROM subscriber_group_members WHERE group_id = ? AND subscriber_id = ?",
+        )
+        .bind(group_id.as_bytes().as_slice())
+        .bind(subscriber_id.as_bytes().as_slice())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_group_members(&self, group_id: &Uuid) -> ApiResult<Vec<Subscriber>> {
+        let subscribers = sqlx::query_as::<_, Subscriber>(
+            "SELECT subscribers.* FROM subscribers
+                 JOIN subscriber_group_members ON subscriber_group_members.subscriber_id = subscribers.id
+                 WHERE subscriber_group_members.group_id = ?",
+        )
+        .bind(group_id.as_bytes().as_slice())
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(subscribers)
+    }
+
+    /// Lists every group membership as (group id, member id) pairs.
+    #[instrument(skip(self))]
+    pub async fn list_group_memberships(&self) -> ApiResult<Vec<(Uuid, Uuid)>> {
+        let rows = sqlx::query("SELECT group_id, subscriber_id FROM subscriber_group_members")
+            .fetch_all(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        let memberships = rows
+            .iter()
+            .map(|row| {
+                Ok((
+                    decode_uuid(row, "group_id")?,
+                    decode_uuid(row, "subscriber_id")?,
+                ))
+            })
+            .collect::<Result<_, sqlx::Error>>()?;
+        Ok(memberships)
+    }
+
+    /// Counts a failed delivery or bounce against the subscriber.
+    #[instrument(skip(self))]
+    pub async fn record_delivery_failure(
+        &self,
+        id: &Uuid,
+        error: &str,
+    ) -> ApiResult<Option<Subscriber>> {
+        let subscriber = sqlx::query_as::<_, Subscriber>(
+            "UPDATE subscribers
+                 SET delivery_failures = delivery_failures + 1,
+                  failing_since = coalesce(failing_since, ?),
+                  last_failure = ?,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(Utc::now())
+        .bind(error)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(subscriber)
+    }
+
+    /// Clears the subscriber's failures after a successful delivery.
+    #[instrument(skip(self))]
+    pub async fn record_delivery_success(&self, id: &Uuid) -> ApiResult<()> {
+        let result = sqlx::query(
+            "UPDATE subscribers
+                 SET delivery_failures = 0,
+                  failing_since = NULL,
+                  last_failure = NULL,
+                  updated_at = ?
+                 WHERE id = ? AND delivery_failures > 0",
+        )
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        if result.rows_affected() > 0 {
+            publish(Change::new(Entity::Subscriber, *id, ChangeKind::Updated));
+        }
+        Ok(())
+    }
+
+    /// Pauses the subscriber, keeping the original time if it already was.
+    #[instrument(skip(self))]
+    pub async fn pause_subscriber(&self, id: &Uuid) -> ApiResult<Subscriber> {
+        self.set_paused(id, Some(Utc::now())).await
+    }
+
+    /// Resumes deliveries to the subscriber, starting its failures over.
+    #[instrument(skip(self))]
+    pub async fn resume_subscriber(&self, id: &Uuid) -> ApiResult<Subscriber> {
+        self.set_paused(id, None).await
+    }
+
+    async fn set_paused(
+        &self,
+        id: &Uuid,
+        paused_at: Option<chrono::DateTime<Utc>>,
+    ) -> ApiResult<Subscriber> {
+        let paused = paused_at.is_some();
+        let subscriber = sqlx::query_as::<_, Subscriber>(
+            "UPDATE subscribers
+                 SET paused_at = CASE WHEN ? THEN coalesce(paused_at, ?) END,
+                  delivery_failures = CASE WHEN ? THEN delivery_failures ELSE 0 END,
+                  failing_since = CASE WHEN ? THEN failing_since END,
+                  last_failure = CASE WHEN ? THEN last_failure END,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(paused)
+        .bind(paused_at)
+        .bind(paused)
+        .bind(paused)
+        .bind(paused)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match subscriber {
+            Some(x) => {
+                publish(Change::new(Entity::Subscriber, x.id, ChangeKind::Updated));
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("subscriber"),
+            }),
+        }
+    }
+
     #[instrument(skip(self))]
     pub async fn delete_subscriber(&self, id: Uuid) -> ApiResult<()> {
         sqlx::query("DELETE FROM subscribers WHERE id = ?")
@@ -128,6 +450,7 @@ impl SubscriberClient {
             .execute(&self.pool)
             .instrument(info_span!("Querying db"))
             .await?;
+        publish(Change::new(Entity::Subscriber, id, ChangeKind::Deleted));
         Ok(())
     }
 }
@@ -1,17 +1,128 @@
+use std::env;
+
 use chrono::Utc;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
 use tracing::{info_span, instrument, Instrument};
 use uuid::Uuid;
 
 use crate::error::{ApiError, ApiResult};
 
-use super::{decode_optional_uuid, decode_uuid, BookClient, ChapterClient, SubscriberClient};
+use super::{
+    changes::{publish, Change, ChangeKind, Entity},
+    decode_optional_uuid, decode_uuid, AnthologySubscription, Backlog, BookClient, Chapter,
+    ChapterClient, SubscriberClient, WildcardSubscription,
+};
+
+// The queries run on every tick of the delivery loop, which /explainHotQueries checks are
+// indexed.
+
+pub(super) const SUBSCRIPTIONS_FOR_SUBSCRIBER: &str =
+    "SELECT * FROM subscriptions WHERE subscriber_id = ?";
+
+pub(super) const SUBSCRIPTIONS_FOR_BOOK: &str = "SELECT * FROM subscriptions WHERE book_id = ?";
+
+/// Converted chapters not yet delivered, counted once for each subscription waiting for them.
+/// Every subscription is read, so the scan of subscriptions is expected.
+pub(super) const DELIVERY_BACKLOG: &str =
+    "SELECT count(*) AS chapters, min(chapters.created_at) AS oldest_created_at
+         FROM subscriptions
+         JOIN chapters ON chapters.book_id = subscriptions.book_id
+         WHERE chapters.state = 'converted'
+          AND coalesce(chapters.created_at > subscriptions.last_delivered_chapter_created_at, true)";
+
+/// Subscriptions with how many converted chapters are waiting to be delivered to each, for
+/// showing to readers rather than for delivery.
+const SUBSCRIPTIONS_WITH_PENDING: &str =
+    "SELECT subscriptions.*, count(chapters.id) AS pending_chapters
+         FROM subscriptions
+         LEFT JOIN chapters ON chapters.book_id = subscriptions.book_id
+          AND chapters.state = 'converted'
+          AND coalesce(chapters.created_at > subscriptions.last_delivered_chapter_created_at, true)";
 
 pub struct SubscriptionClient {
     pool: Pool<Sqlite>,
 }
 
+/// How a subscription's chapters are sent.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DeliveryFormat {
+    /// An epub emailed to the subscriber's kindle.
+    #[default]
+    Epub,
+    /// The chapters' html in the body of an email to the subscriber's email address, for
+    /// reading in a mail client or forwarding to read-later services.
+    InlineHtml,
+}
+
+impl DeliveryFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryFormat::Epub => "epub",
+            DeliveryFormat::InlineHtml => "inlineHtml",
+        }
+    }
+}
+
+impl TryFrom<(&SqliteRow, &str)> for DeliveryFormat {
+    type Error = sqlx::Error;
+
+    fn try_from(value: (&SqliteRow, &str)) -> core::result::Result<Self, Self::Error> {
+        let (row, index) = value;
+        let format: String = row.try_get(index)?;
+        serde_json::from_value(serde_json::Value::String(format)).map_err(|err| {
+            sqlx::Error::ColumnDecode {
+                index: index.into(),
+                source: Box::new(err),
+            }
+        })
+    }
+}
+
+/// How a backlog larger than the delivery cap is sent once an admin confirms it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BacklogDelivery {
+    /// In a single delivery, however large.
+    All,
+    /// In deliveries within the cap, spaced apart.
+    Paced,
+}
+
+impl BacklogDelivery {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BacklogDelivery::All => "all",
+            BacklogDelivery::Paced => "paced",
+        }
+    }
+}
+
+fn decode_backlog_delivery(row: &SqliteRow) -> Result<Option<BacklogDelivery>, sqlx::Error> {
+    let delivery: Option<String> = row.try_get("backlog_delivery")?;
+    delivery
+        .map(|x| serde_json::from_value(serde_json::Value::String(x)))
+        .transpose()
+        .map_err(|err| sqlx::Error::ColumnDecode {
+            index: "backlog_delivery".into(),
+            source: Box::new(err),
+        })
+}
+
+/// The fewest chapters a subscription waits for, as waiting for none would deliver empty chunks.
+pub const MIN_CHUNK_SIZE: i32 = 1;
+
+/// The most chapters a subscription waits for before delivering them together, from
+/// CEREAL_MAX_CHUNK_SIZE. Larger chunks make epubs too large to email.
+pub fn max_chunk_size() -> i32 {
+    env::var("CEREAL_MAX_CHUNK_SIZE")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .filter(|x| *x >= MIN_CHUNK_SIZE)
+        .unwrap_or(100)
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct Subscription {
     pub id: Uuid,
@@ -25,12 +136,55 @@ pub struct Subscription {
     pub last_delivered_chapter_id: Option<Uuid>,
     #[serde(rename = "lastDeliveredChapterCreatedAt")]
     pub last_delivered_chapter_created_at: Option<chrono::DateTime<Utc>>,
+    /// Set if the subscription was created by a wildcard subscription.
+    #[serde(rename = "wildcardSubscriptionId")]
+    pub wildcard_subscription_id: Option<Uuid>,
+    /// Set if the subscription was created by an anthology subscription, whose digest delivers
+    /// its chapters.
+    #[serde(rename = "anthologySubscriptionId")]
+    pub anthology_subscription_id: Option<Uuid>,
+    /// Deliver chapters as soon as they are ready, ignoring their embargo, for patrons.
+    #[serde(rename = "earlyAccess")]
+    pub early_access: bool,
+    pub format: DeliveryFormat,
+    /// How the confirmed backlog is sent, for deliveries larger than the cap.
+    #[serde(rename = "backlogDelivery")]
+    pub backlog_delivery: Option<BacklogDelivery>,
+    /// The confirmation covers chapters created up to this time.
+    #[serde(rename = "backlogConfirmedThrough")]
+    pub backlog_confirmed_through: Option<chrono::DateTime<Utc>>,
     #[serde(rename = "createdAt")]
     pub created_at: chrono::DateTime<Utc>,
     #[serde(rename = "updatedAt")]
     pub updated_at: chrono::DateTime<Utc>,
 }
 
+/// A subscription along with how many chapters are ready and waiting to be delivered to it.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct PendingSubscription {
+    #[serde(flatten)]
+    pub subscription: Subscription,
+    #[serde(rename = "pendingChapters")]
+    pub pending_chapters: i64,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for PendingSubscription {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(PendingSubscription {
+            subscription: Subscription::from_row(row)?,
+            pending_chapters: row.try_get("pending_chapters")?,
+        })
+    }
+}
+
+impl Subscription {
+    /// How many chapters are delivered together, kept within the chunk size policy for rows
+    /// saved before it, or above a since lowered maximum.
+    pub fn effective_chunk_size(&self) -> usize {
+        self.chunk_size.clamp(MIN_CHUNK_SIZE, max_chunk_size()) as usize
+    }
+}
+
 impl<'r> sqlx::FromRow<'r, SqliteRow> for Subscription {
     fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
         Ok(Subscription {
@@ -40,6 +194,12 @@ impl<'r> sqlx::FromRow<'r, SqliteRow> for Subscription {
             last_delivered_chapter_id: decode_optional_uuid(row, "last_delivered_chapter_id")?,
             last_delivered_chapter_created_at: row.try_get("last_delivered_chapter_created_at")?,
             chunk_size: row.try_get("chunk_size")?,
+            wildcard_subscription_id: decode_optional_uuid(row, "wildcard_subscription_id")?,
+            anthology_subscription_id: decode_optional_uuid(row, "anthology_subscription_id")?,
+            early_access: row.try_get("early_access")?,
+            format: (row, "format").try_into()?,
+            backlog_delivery: decode_backlog_delivery(row)?,
+            backlog_confirmed_through: row.try_get("backlog_confirmed_through")?,
             created_at: row.try_get("created_at")?,
             updated_at: row.try_get("updated_at")?,
         })
@@ -56,6 +216,8 @@ impl SubscriptionClient {
         subscriber_id: &Uuid,
         book_id: &Uuid,
         chunk_size: Option<&i32>,
+        early_access: Option<bool>,
+        format: DeliveryFormat,
         last_delivered_chapter_id: Option<&Uuid>,
     ) -> ApiResult<Subscription> {
         // Sqlite doesn't tell us _which_ foreign key causes an error, so we must do some checks
@@ -91,28 +253,35 @@ impl SubscriptionClient {
             }
         }
 
-        if subscriber_client
+        let subscriber = match subscriber_client
             .get_subscriber(*subscriber_id)
             .instrument(info_span!("Querying db"))
             .await?
-            .is_none()
         {
-            return Err(ApiError::ResourceNotFound {
-                resource_type: "subscriber".to_owned(),
-                id: subscriber_id.to_string(),
-            });
-        }
+            Some(x) => x,
+            None => {
+                return Err(ApiError::ResourceNotFound {
+                    resource_type: "subscriber".to_owned(),
+                    id: subscriber_id.to_string(),
+                });
+            }
+        };
+        let defaults = subscriber.subscription_defaults;
+        let chunk_size = chunk_size.copied().or(defaults.chunk_size).unwrap_or(1);
+        let early_access = early_access.or(defaults.early_access).unwrap_or(false);
 
         let subscription = sqlx::query_as::<_, Subscription>(
-            "INSERT INTO subscriptions(id, book_id, subscriber_id, chunk_size, last_delivered_chapter_id,
-                last_delivered_chapter_created_at, created_at, updated_at) 
-            VALUES(?, ?, ?, coalesce(?, 1), ?, ?, ?, ?) 
+            "INSERT INTO subscriptions(id, book_id, subscriber_id, chunk_size, early_access, format,
+                last_delivered_chapter_id, last_delivered_chapter_created_at, created_at, updated_at) 
+            VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?) 
             RETURNING *;",
         )
         .bind(Uuid::new_v4().as_bytes().as_slice())
         .bind(book_id.as_bytes().as_slice())
         .bind(subscriber_id.as_bytes().as_slice())
         .bind(chunk_size)
+        .bind(early_access)
+        .bind(format.as_str())
         .bind(last_delivered_chapter_id.map(|x| x.as_bytes().as_slice()))
         .bind(chapter_created_at)
         .bind(Utc::now())
@@ -120,30 +289,340 @@ impl SubscriptionClient {
         .fetch_one(&self.pool)
             .instrument(info_span!("Querying db"))
         .await?;
+        publish(
+            Change::new(Entity::Subscription, subscription.id, ChangeKind::Created)
+                .in_book(subscription.book_id),
+        );
         Ok(subscription)
     }
 
+    /// Creates the subscription to `book_id` which a wildcard subscription covers, delivering
+    /// chapters created after `last_delivered_chapter`. Early access follows the subscriber's
+    /// defaults.
+    #[instrument(skip(self, last_delivered_chapter), fields(chapter.id = %last_delivered_chapter.id))]
+    pub async fn create_wildcard_member(
+        &self,
+        wildcard: &WildcardSubscription,
+        book_id: &Uuid,
+        last_delivered_chapter: &Chapter,
+    ) -> ApiResult<Subscription> {
+        let subscription = sqlx::query_as::<_, Subscription>(
+            "INSERT INTO subscriptions(id, book_id, subscriber_id, chunk_size, early_access,
+                last_delivered_chapter_id, last_delivered_chapter_created_at, wildcard_subscription_id,
+                created_at, updated_at)
+            VALUES(?, ?, ?, ?,
+                coalesce((SELECT default_early_access FROM subscribers WHERE id = ?), false),
+                ?, ?, ?, ?, ?)
+            RETURNING *;",
+        )
+        .bind(Uuid::new_v4().as_bytes().as_slice())
+        .bind(book_id.as_bytes().as_slice())
+        .bind(wildcard.subscriber_id.as_bytes().as_slice())
+        .bind(wildcard.chunk_size)
+        .bind(wildcard.subscriber_id.as_bytes().as_slice())
+        .bind(last_delivered_chapter.id.as_bytes().as_slice())
+        .bind(last_delivered_chapter.created_at)
+        .bind(wildcard.id.as_bytes().as_slice())
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        publish(
+            Change::new(Entity::Subscription, subscription.id, ChangeKind::Created)
+                .in_book(subscription.book_id),
+        );
+        Ok(subscription)
+    }
+
+    /// Creates the subscription to `book_id` which an anthology subscription covers, delivering
+    /// chapters created after `last_delivered_chapter` in its digests. Early access follows the
+    /// subscriber's defaults.
+    #[instrument(skip(self, last_delivered_chapter), fields(chapter.id = %last_delivered_chapter.id))]
+    pub async fn create_anthology_member(
+        &self,
+        anthology: &AnthologySubscription,
+        book_id: &Uuid,
+        last_delivered_chapter: &Chapter,
+    ) -> ApiResult<Subscription> {
+        let subscription = sqlx::query_as::<_, Subscription>(
+            "INSERT INTO subscriptions(id, book_id, subscriber_id, early_access,
+                last_delivered_chapter_id, last_delivered_chapter_created_at, anthology_subscription_id,
+                created_at, updated_at)
+            VALUES(?, ?, ?,
+                coalesce((SELECT default_early_access FROM subscribers WHERE id = ?), false),
+                ?, ?, ?, ?, ?)
+            RETURNING *;",
+        )
+        .bind(Uuid::new_v4().as_bytes().as_slice())
+        .bind(book_id.as_bytes().as_slice())
+        .bind(anthology.subscriber_id.as_bytes().as_slice())
+        .bind(anthology.subscriber_id.as_bytes().as_slice())
+        .bind(last_delivered_chapter.id.as_bytes().as_slice())
+        .bind(last_delivered_chapter.created_at)
+        .bind(anthology.id.as_bytes().as_slice())
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        publish(
+            Change::new(Entity::Subscription, subscription.id, ChangeKind::Created)
+                .in_book(subscription.book_id),
+        );
+        Ok(subscription)
+    }
+
+    /// Applies a wildcard subscription's chunk size to the subscriptions it created.
+    #[instrument(skip(self))]
+    pub async fn set_wildcard_members_chunk_size(
+        &self,
+        wildcard_subscription_id: &Uuid,
+        chunk_size: i32,
+    ) -> ApiResult<()> {
+        sqlx::query(
+            "UPDATE subscriptions
+                 SET chunk_size = ?,
+                  updated_at = ?
+                 WHERE wildcard_subscription_id = ?",
+        )
+        .bind(chunk_size)
+        .bind(Utc::now())
+        .bind(wildcard_subscription_id.as_bytes().as_slice())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     pub async fn update_subscription(
         &self,
         id: &Uuid,
         chunk_size: Option<i32>,
+        early_access: Option<bool>,
+        format: Option<DeliveryFormat>,
     ) -> ApiResult<Subscription> {
         let subscription = sqlx::query_as::<_, Subscription>(
             "UPDATE subscriptions
                  SET chunk_size = coalesce(?, chunk_size),
+                  early_access = coalesce(?, early_access),
+                  format = coalesce(?, format),
                   updated_at = ?
                  WHERE id = ? 
                  RETURNING *;",
         )
         .bind(chunk_size)
+        .bind(early_access)
+        .bind(format.map(|x| x.as_str()))
         .bind(Utc::now())
         .bind(id.as_bytes().as_slice())
         .fetch_optional(&self.pool)
         .instrument(info_span!("Querying db"))
         .await?;
         match subscription {
-            Some(x) => Ok(x),
+            Some(x) => {
+                publish(
+                    Change::new(Entity::Subscription, x.id, ChangeKind::Updated).in_book(x.book_id),
+                );
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("subscription"),
+            }),
+        }
+    }
+
+    /// Moves the subscription to another book or subscriber. Delivery to a new book resumes
+    /// after `last_delivered_chapter_id`, which must be one of its chapters, or otherwise after
+    /// its most recent chapter, so moving doesn't send its whole backlog. A backlog confirmed for
+    /// the old book is forgotten. Subscriptions created by wildcard or anthology subscriptions
+    /// belong to them, so can't be moved. The chunk size, early access and format are changed
+    /// along with the move where set, all in one transaction.
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self))]
+    pub async fn move_subscription(
+        &self,
+        id: &Uuid,
+        book_id: Option<&Uuid>,
+        subscriber_id: Option<&Uuid>,
+        last_delivered_chapter_id: Option<&Uuid>,
+        chunk_size: Option<i32>,
+        early_access: Option<bool>,
+        format: Option<DeliveryFormat>,
+    ) -> ApiResult<Subscription> {
+        let mut transaction = self.pool.begin().await?;
+        let existing =
+            sqlx::query_as::<_, Subscription>("SELECT * FROM subscriptions WHERE id = ?")
+                .bind(id.as_bytes().as_slice())
+                .fetch_optional(&mut transaction)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        let existing = match existing {
+            Some(x) => x,
+            None => {
+                return Err(ApiError::ResourceNotFound {
+                    id: id.to_string(),
+                    resource_type: String::from("subscription"),
+                })
+            }
+        };
+        if existing.wildcard_subscription_id.is_some()
+            || existing.anthology_subscription_id.is_some()
+        {
+            return Err(ApiError::InvalidRequest(format!(
+                "Subscription {} is managed by a wildcard or anthology subscription, change that instead",
+                id
+            )));
+        }
+
+        // Sqlite doesn't tell us _which_ foreign key causes an error, so we must do some checks
+        let book_id = book_id.copied().unwrap_or(existing.book_id);
+        let (books,): (i64,) = sqlx::query_as("SELECT count(*) FROM books WHERE id = ?")
+            .bind(book_id.as_bytes().as_slice())
+            .fetch_one(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        if books == 0 {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("book"),
+                id: book_id.to_string(),
+            });
+        }
+        let subscriber_id = subscriber_id.copied().unwrap_or(existing.subscriber_id);
+        let (subscribers,): (i64,) =
+            sqlx::query_as("SELECT count(*) FROM subscribers WHERE id = ?")
+                .bind(subscriber_id.as_bytes().as_slice())
+                .fetch_one(&mut transaction)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        if subscribers == 0 {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("subscriber"),
+                id: subscriber_id.to_string(),
+            });
+        }
+
+        let book_changed = book_id != existing.book_id;
+        let last_delivered = match last_delivered_chapter_id {
+            Some(chapter_id) => {
+                let chapter = sqlx::query_as::<_, Chapter>("SELECT * FROM chapters WHERE id = ?")
+                    .bind(chapter_id.as_bytes().as_slice())
+                    .fetch_optional(&mut transaction)
+                    .instrument(info_span!("Querying db"))
+                    .await?;
+                match chapter {
+                    Some(x) if x.book_id == book_id => Some((Some(x.id), Some(x.created_at))),
+                    Some(_) => {
+                        return Err(ApiError::InvalidRequest(format!(
+                            "Chapter {} isn't in book {}",
+                            chapter_id, book_id
+                        )))
+                    }
+                    None => {
+                        return Err(ApiError::ResourceNotFound {
+                            resource_type: String::from("chapter"),
+                            id: chapter_id.to_string(),
+                        })
+                    }
+                }
+            }
+            None if book_changed => {
+                let chapter = sqlx::query_as::<_, Chapter>(
+                    "SELECT * FROM chapters WHERE book_id = ? ORDER BY created_at DESC LIMIT 1",
+                )
+                .bind(book_id.as_bytes().as_slice())
+                .fetch_optional(&mut transaction)
+                .instrument(info_span!("Querying db"))
+                .await?;
+                Some((
+                    chapter.as_ref().map(|x| x.id),
+                    chapter.map(|x| x.created_at),
+                ))
+            }
+            None => None,
+        };
+        let (last_delivered_chapter_id, last_delivered_chapter_created_at) = last_delivered
+            .unwrap_or((
+                existing.last_delivered_chapter_id,
+                existing.last_delivered_chapter_created_at,
+            ));
+
+        let subscription = sqlx::query_as::<_, Subscription>(
+            "UPDATE subscriptions
+                 SET book_id = ?,
+                  subscriber_id = ?,
+                  last_delivered_chapter_id = ?,
+                  last_delivered_chapter_created_at = ?,
+                  backlog_delivery = CASE WHEN ? THEN NULL ELSE backlog_delivery END,
+                  backlog_confirmed_through = CASE WHEN ? THEN NULL ELSE backlog_confirmed_through END,
+                  chunk_size = coalesce(?, chunk_size),
+                  early_access = coalesce(?, early_access),
+                  format = coalesce(?, format),
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .bind(subscriber_id.as_bytes().as_slice())
+        .bind(last_delivered_chapter_id.as_ref().map(|x| x.as_bytes().as_slice()))
+        .bind(last_delivered_chapter_created_at)
+        .bind(book_changed)
+        .bind(book_changed)
+        .bind(chunk_size)
+        .bind(early_access)
+        .bind(format.map(|x| x.as_str()))
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_one(&mut transaction)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        transaction.commit().await?;
+        if book_changed {
+            publish(
+                Change::new(Entity::Subscription, subscription.id, ChangeKind::Updated)
+                    .in_book(existing.book_id),
+            );
+        }
+        publish(
+            Change::new(Entity::Subscription, subscription.id, ChangeKind::Updated)
+                .in_book(subscription.book_id),
+        );
+        Ok(subscription)
+    }
+
+    /// Confirms delivery of the chapters created up to `through`, however far they exceed the
+    /// delivery cap.
+    #[instrument(skip(self))]
+    pub async fn confirm_backlog(
+        &self,
+        id: &Uuid,
+        delivery: BacklogDelivery,
+        through: &chrono::DateTime<Utc>,
+    ) -> ApiResult<Subscription> {
+        let subscription = sqlx::query_as::<_, Subscription>(
+            "UPDATE subscriptions
+                 SET backlog_delivery = ?,
+                  backlog_confirmed_through = ?,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(delivery.as_str())
+        .bind(through)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match subscription {
+            Some(x) => {
+                publish(
+                    Change::new(Entity::Subscription, x.id, ChangeKind::Updated).in_book(x.book_id),
+                );
+                Ok(x)
+            }
             None => Err(ApiError::ResourceNotFound {
                 id: id.to_string(),
                 resource_type: String::from("subscription"),
@@ -163,19 +642,66 @@ impl SubscriptionClient {
     }
 
     #[instrument(skip(self))]
-    pub async fn list_subscriptions(&self, subscriber_id: &Uuid) -> ApiResult<Vec<Subscription>> {
-        let subscriptions = sqlx::query_as::<_, Subscription>(
-            "
-        SELECT * FROM subscriptions 
-        WHERE subscriber_id = ?",
-        )
-        .bind(subscriber_id.as_bytes().as_slice())
+    pub async fn get_pending_subscription(
+        &self,
+        id: Uuid,
+    ) -> ApiResult<Option<PendingSubscription>> {
+        let subscription = sqlx::query_as::<_, PendingSubscription>(&format!(
+            "{} WHERE subscriptions.id = ? GROUP BY subscriptions.id",
+            SUBSCRIPTIONS_WITH_PENDING
+        ))
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(subscription)
+    }
+
+    /// The subscriber's subscriptions, or the book's, with their pending chapters.
+    #[instrument(skip(self))]
+    pub async fn list_pending_subscriptions(
+        &self,
+        subscriber_id: Option<&Uuid>,
+        book_id: Option<&Uuid>,
+    ) -> ApiResult<Vec<PendingSubscription>> {
+        let subscriptions = sqlx::query_as::<_, PendingSubscription>(&format!(
+            "{} WHERE coalesce(subscriptions.subscriber_id = ?, true)
+                 AND coalesce(subscriptions.book_id = ?, true)
+                 GROUP BY subscriptions.id
+                 ORDER BY subscriptions.created_at",
+            SUBSCRIPTIONS_WITH_PENDING
+        ))
+        .bind(subscriber_id.map(|x| x.as_bytes().as_slice()))
+        .bind(book_id.map(|x| x.as_bytes().as_slice()))
         .fetch_all(&self.pool)
         .instrument(info_span!("Querying db"))
         .await?;
         Ok(subscriptions)
     }
 
+    #[instrument(skip(self))]
+    pub async fn list_subscriptions(&self, subscriber_id: &Uuid) -> ApiResult<Vec<Subscription>> {
+        let subscriptions = sqlx::query_as::<_, Subscription>(SUBSCRIPTIONS_FOR_SUBSCRIBER)
+            .bind(subscriber_id.as_bytes().as_slice())
+            .fetch_all(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(subscriptions)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_subscriptions_for_book(
+        &self,
+        book_id: &Uuid,
+    ) -> ApiResult<Vec<Subscription>> {
+        let subscriptions = sqlx::query_as::<_, Subscription>(SUBSCRIPTIONS_FOR_BOOK)
+            .bind(book_id.as_bytes().as_slice())
+            .fetch_all(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(subscriptions)
+    }
+
     #[instrument(skip(self))]
     pub async fn delete_subscription(&self, id: Uuid) -> ApiResult<()> {
         sqlx::query("DELETE FROM subscriptions WHERE id = ?")
@@ -183,9 +709,82 @@ impl SubscriptionClient {
             .execute(&self.pool)
             .instrument(info_span!("Querying db"))
             .await?;
+        publish(Change::new(Entity::Subscription, id, ChangeKind::Deleted));
         Ok(())
     }
 
+    /// How many of the book's converted chapters a subscription delivered up to `after` would
+    /// be waiting for, counted as `pending_chapters` is.
+    #[instrument(skip(self))]
+    pub async fn count_pending_chapters(
+        &self,
+        book_id: &Uuid,
+        after: Option<&chrono::DateTime<Utc>>,
+    ) -> ApiResult<i64> {
+        let pending = sqlx::query_scalar::<_, i64>(
+            "SELECT count(*) FROM chapters
+                 WHERE book_id = ?
+                  AND state = 'converted'
+                  AND coalesce(created_at > ?, true)",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .bind(after)
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(pending)
+    }
+
+    /// Converted chapters not yet delivered, counting a chapter once for each subscription
+    /// waiting for it.
+    #[instrument(skip(self))]
+    pub async fn delivery_backlog(&self) -> ApiResult<Backlog> {
+        let backlog = sqlx::query_as::<_, Backlog>(DELIVERY_BACKLOG)
+            .fetch_one(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(backlog)
+    }
+
+    /// Moves the subscription's delivery position, such as to repair it by hand. Chapters
+    /// created after `chapter_created_at` are pending, and all of the book's chapters are when
+    /// it is unset.
+    #[instrument(skip(self))]
+    pub async fn set_position(
+        &self,
+        id: &Uuid,
+        chapter_id: Option<&Uuid>,
+        chapter_created_at: Option<&chrono::DateTime<Utc>>,
+    ) -> ApiResult<Subscription> {
+        let subscription = sqlx::query_as::<_, Subscription>(
+            "UPDATE subscriptions
+                 SET last_delivered_chapter_id = ?,
+                  last_delivered_chapter_created_at = ?,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(chapter_id.map(|x| x.as_bytes().as_slice()))
+        .bind(chapter_created_at)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match subscription {
+            Some(x) => {
+                publish(
+                    Change::new(Entity::Subscription, x.id, ChangeKind::Updated).in_book(x.book_id),
+                );
+                Ok(x)
+            }
+            None => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("subscription"),
+            }),
+        }
+    }
+
     #[instrument(skip(self))]
     pub async fn set_last_delivered_chapter(
         &self,
@@ -209,7 +808,12 @@ impl SubscriptionClient {
         .instrument(info_span!("Querying db"))
         .await?;
         match subscription {
-            Some(x) => Ok(x),
+            Some(x) => {
+                publish(
+                    Change::new(Entity::Subscription, x.id, ChangeKind::Updated).in_book(x.book_id),
+                );
+                Ok(x)
+            }
             None => Err(ApiError::ResourceNotFound {
                 id: id.to_string(),
                 resource_type: String::from("subscription"),
@@ -0,0 +1,176 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    util::{is_foreign_key_error, is_unique_error},
+};
+
+use super::decode_uuid;
+
+/// A label for grouping books, such as a genre, an author or a shared universe. Books may
+/// have any number of tags.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Tag {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for Tag {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(Tag {
+            id: decode_uuid(row, "id")?,
+            name: row.try_get("name")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+pub struct TagClient {
+    pool: Pool<Sqlite>,
+}
+
+fn duplicate_name_error(name: &str) -> ApiError {
+    ApiError::InvalidRequest(format!("A tag named {:?} already exists", name))
+}
+
+impl TagClient {
+    pub fn new(pool: &Pool<Sqlite>) -> TagClient {
+        TagClient { pool: pool.clone() }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn create_tag(&self, name: &str) -> ApiResult<Tag> {
+        let tag = sqlx::query_as::<_, Tag>(
+            "INSERT INTO tags(id, name, created_at, updated_at)
+                 VALUES(?, ?, ?, ?)
+                 RETURNING *;",
+        )
+        .bind(Uuid::new_v4().as_bytes().as_slice())
+        .bind(name)
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match tag {
+            Ok(tag) => Ok(tag),
+            Err(e) if is_unique_error(&e) => Err(duplicate_name_error(name)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn update_tag(&self, id: &Uuid, name: &str) -> ApiResult<Tag> {
+        let tag = sqlx::query_as::<_, Tag>(
+            "UPDATE tags
+                 SET name = ?,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(name)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match tag {
+            Ok(Some(tag)) => Ok(tag),
+            Ok(None) => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("tag"),
+            }),
+            Err(e) if is_unique_error(&e) => Err(duplicate_name_error(name)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_tag(&self, id: &Uuid) -> ApiResult<Option<Tag>> {
+        let tag = sqlx::query_as::<_, Tag>("SELECT * FROM tags WHERE id = ?")
+            .bind(id.as_bytes().as_slice())
+            .fetch_optional(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(tag)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_tags(&self) -> ApiResult<Vec<Tag>> {
+        let tags = sqlx::query_as::<_, Tag>("SELECT * FROM tags ORDER BY name")
+            .fetch_all(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(tags)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_tag(&self, id: &Uuid) -> ApiResult<()> {
+        sqlx::query("DELETE FROM tags WHERE id = ?")
+            .bind(id.as_bytes().as_slice())
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn tag_book(&self, book_id: &Uuid, tag_id: &Uuid) -> ApiResult<()> {
+        let result = sqlx::query(
+            "INSERT INTO book_tags(book_id, tag_id, created_at)
+                 VALUES(?, ?, ?)
+                 ON CONFLICT DO NOTHING",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .bind(tag_id.as_bytes().as_slice())
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => match is_foreign_key_error(&e) {
+                true => Err(ApiError::ResourceNotFound {
+                    id: format!("{} or {}", book_id, tag_id),
+                    resource_type: String::from("book or tag"),
+                }),
+                false => Err(e.into()),
+            },
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn untag_book(&self, book_id: &Uuid, tag_id: &Uuid) -> ApiResult<()> {
+        sqlx::query("DELETE FROM book_tags WHERE book_id = ? AND tag_id = ?")
+            .bind(book_id.as_bytes().as_slice())
+            .bind(tag_id.as_bytes().as_slice())
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_tags_for_book(&self, book_id: &Uuid) -> ApiResult<Vec<Tag>> {
+        let tags = sqlx::query_as::<_, Tag>(
+            "SELECT tags.* FROM tags
+                 JOIN book_tags ON book_tags.tag_id = tags.id
+                 WHERE book_tags.book_id = ?
+                 ORDER BY tags.name",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(tags)
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use serde::Serialize;
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    util::{is_foreign_key_error, is_unique_error},
+};
+
+use super::{
+    changes::{publish, Change, ChangeKind, Entity},
+    decode_uuid,
+};
+
+/// A volume, or arc, of a book. Chapters belong to at most one volume, which their epubs are
+/// shelved under.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Volume {
+    pub id: Uuid,
+    #[serde(rename = "bookId")]
+    pub book_id: Uuid,
+    pub number: i64,
+    pub title: Option<String>,
+    /// Set if the volume was created by detection from chapter titles.
+    pub detected: bool,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for Volume {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(Volume {
+            id: decode_uuid(row, "id")?,
+            book_id: decode_uuid(row, "book_id")?,
+            number: row.try_get("number")?,
+            title: row.try_get("title")?,
+            detected: row.try_get("detected")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+impl Volume {
+    /// The volume's title, or its number if it has none.
+    pub fn name(&self) -> String {
+        match &self.title {
+            Some(title) => title.clone(),
+            None => format!("Volume {}", self.number),
+        }
+    }
+}
+
+/// Where a chapter sits within its volume, counting from 1.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VolumePosition {
+    pub volume: Volume,
+    pub index: i64,
+}
+
+/// Numbers each chapter of the book within its volume, in reading order.
+const VOLUME_POSITIONS: &str = "SELECT chapters.id AS chapter_id,
+        row_number() OVER (PARTITION BY chapters.volume_id ORDER BY chapters.order_index) AS position,
+        volumes.*
+     FROM chapters
+     JOIN volumes ON volumes.id = chapters.volume_id
+     WHERE chapters.book_id = ?";
+
+fn decode_position(row: &SqliteRow) -> Result<(Uuid, VolumePosition), sqlx::Error> {
+    Ok((
+        decode_uuid(row, "chapter_id")?,
+        VolumePosition {
+            volume: sqlx::FromRow::from_row(row)?,
+            index: row.try_get("position")?,
+        },
+    ))
+}
+
+pub struct VolumeClient {
+    pool: Pool<Sqlite>,
+}
+
+fn duplicate_number_error(number: i64) -> ApiError {
+    ApiError::InvalidRequest(format!("The book already has a volume {}", number))
+}
+
+fn published(volume: Volume, kind: ChangeKind) -> Volume {
+    publish(Change::new(Entity::Volume, volume.id, kind).in_book(volume.book_id));
+    volume
+}
+
+impl VolumeClient {
+    pub fn new(pool: &Pool<Sqlite>) -> VolumeClient {
+        VolumeClient { pool: pool.clone() }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn create_volume(
+        &self,
+        book_id: &Uuid,
+        number: i64,
+        title: Option<&str>,
+        detected: bool,
+    ) -> ApiResult<Volume> {
+        let volume = sqlx::query_as::<_, Volume>(
+            "INSERT INTO volumes(id, book_id, number, title, detected, created_at, updated_at)
+                 VALUES(?, ?, ?, ?, ?, ?, ?)
+                 RETURNING *;",
+        )
+        .bind(Uuid::new_v4().as_bytes().as_slice())
+        .bind(book_id.as_bytes().as_slice())
+        .bind(number)
+        .bind(title)
+        .bind(detected)
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match volume {
+            Ok(x) => Ok(published(x, ChangeKind::Created)),
+            Err(e) if is_unique_error(&e) => Err(duplicate_number_error(number)),
+            Err(e) if is_foreign_key_error(&e) => Err(ApiError::ResourceNotFound {
+                id: book_id.to_string(),
+                resource_type: String::from("book"),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Updates the volume, which detection then treats as set by hand.
+    #[instrument(skip(self))]
+    pub async fn update_volume(
+        &self,
+        id: &Uuid,
+        number: Option<i64>,
+        title: Option<&str>,
+    ) -> ApiResult<Volume> {
+        let volume = sqlx::query_as::<_, Volume>(
+            "UPDATE volumes
+                 SET number = coalesce(?, number),
+                  title = coalesce(?, title),
+                  detected = FALSE,
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(number)
+        .bind(title)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match volume {
+            Ok(Some(x)) => Ok(published(x, ChangeKind::Updated)),
+            Ok(None) => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("volume"),
+            }),
+            Err(e) if is_unique_error(&e) => Err(duplicate_number_error(number.unwrap_or(0))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_volume(&self, id: &Uuid) -> ApiResult<Option<Volume>> {
+        let volume = sqlx::query_as::<_, Volume>("SELECT * FROM volumes WHERE id = ?")
+            .bind(id.as_bytes().as_slice())
+            .fetch_optional(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(volume)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_volumes(&self, book_id: &Uuid) -> ApiResult<Vec<Volume>> {
+        let volumes =
+            sqlx::query_as::<_, Volume>("SELECT * FROM volumes WHERE book_id = ? ORDER BY number")
+                .bind(book_id.as_bytes().as_slice())
+                .fetch_all(&self.pool)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        Ok(volumes)
+    }
+
+    /// Deletes the volume. Its chapters are left without a volume.
+    #[instrument(skip(self))]
+    pub async fn delete_volume(&self, id: &Uuid) -> ApiResult<()> {
+        let volume = sqlx::query_as::<_, Volume>("DELETE FROM volumes WHERE id = ? RETURNING *")
+            .bind(id.as_bytes().as_slice())
+            .fetch_optional(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        if let Some(x) = volume {
+            published(x, ChangeKind::Deleted);
+        }
+        Ok(())
+    }
+
+    /// Makes the chapters the volume's only chapters. Chapters must belong to the volume's book.
+    #[instrument(skip(self))]
+    pub async fn set_volume_chapters(
+        &self,
+        volume: &Volume,
+        chapter_ids: &[Uuid],
+    ) -> ApiResult<()> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("UPDATE chapters SET volume_id = NULL, updated_at = ? WHERE volume_id = ?")
+            .bind(Utc::now())
+            .bind(volume.id.as_bytes().as_slice())
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        for chapter_id in chapter_ids {
+            let result = sqlx::query(
+                "UPDATE chapters SET volume_id = ?, updated_at = ? WHERE id = ? AND book_id = ?",
+            )
+            .bind(volume.id.as_bytes().as_slice())
+            .bind(Utc::now())
+            .bind(chapter_id.as_bytes().as_slice())
+            .bind(volume.book_id.as_bytes().as_slice())
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+            if result.rows_affected() == 0 {
+                transaction.rollback().await?;
+                return Err(ApiError::ResourceNotFound {
+                    id: chapter_id.to_string(),
+                    resource_type: format!("chapter of book {}", volume.book_id),
+                });
+            }
+        }
+        transaction.commit().await?;
+        publish(
+            Change::new(Entity::Volume, volume.id, ChangeKind::Updated).in_book(volume.book_id),
+        );
+        Ok(())
+    }
+
+    /// Moves each chapter to the volume paired with it, in one transaction.
+    #[instrument(skip(self, assignments), fields(chapters = assignments.len()))]
+    pub async fn assign_chapters(
+        &self,
+        book_id: &Uuid,
+        assignments: &[(Uuid, Uuid)],
+    ) -> ApiResult<()> {
+        let mut transaction = self.pool.begin().await?;
+        for (chapter_id, volume_id) in assignments {
+            sqlx::query(
+                "UPDATE chapters SET volume_id = ?, updated_at = ? WHERE id = ? AND book_id = ?",
+            )
+            .bind(volume_id.as_bytes().as_slice())
+            .bind(Utc::now())
+            .bind(chapter_id.as_bytes().as_slice())
+            .bind(book_id.as_bytes().as_slice())
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        }
+        transaction.commit().await?;
+        for volume_id in assignments.iter().map(|(_, x)| x).unique() {
+            publish(Change::new(Entity::Volume, *volume_id, ChangeKind::Updated).in_book(*book_id));
+        }
+        Ok(())
+    }
+
+    /// The volume and position of each of the book's chapters which belongs to a volume.
+    #[instrument(skip(self))]
+    pub async fn chapter_positions(
+        &self,
+        book_id: &Uuid,
+    ) -> ApiResult<HashMap<Uuid, VolumePosition>> {
+        let rows = sqlx::query(VOLUME_POSITIONS)
+            .bind(book_id.as_bytes().as_slice())
+            .fetch_all(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        let positions = rows.iter().map(decode_position).collect::<Result<_, _>>()?;
+        Ok(positions)
+    }
+
+    /// The chapter's volume and its position within it, if it belongs to one.
+    #[instrument(skip(self))]
+    pub async fn chapter_position(
+        &self,
+        book_id: &Uuid,
+        chapter_id: &Uuid,
+    ) -> ApiResult<Option<VolumePosition>> {
+        Ok(self.chapter_positions(book_id).await?.remove(chapter_id))
+    }
+}
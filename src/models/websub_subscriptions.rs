@@ -0,0 +1,223 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    util::is_foreign_key_error,
+};
+
+use super::decode_uuid;
+
+/// A book's subscription to its source feed at a WebSub hub, so the hub pushes new chapters
+/// rather than waiting for the book to be polled.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct WebSubSubscription {
+    #[serde(rename = "bookId")]
+    pub book_id: Uuid,
+    pub hub: String,
+    pub topic: String,
+    /// Signs the hub's notifications. Never returned by the api.
+    #[serde(skip)]
+    pub secret: String,
+    /// When the hub last confirmed the subscription. Unset until the hub verifies it.
+    #[serde(rename = "verifiedAt")]
+    pub verified_at: Option<DateTime<Utc>>,
+    #[serde(rename = "leaseExpiresAt")]
+    pub lease_expires_at: Option<DateTime<Utc>>,
+    #[serde(rename = "lastNotifiedAt")]
+    pub last_notified_at: Option<DateTime<Utc>>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for WebSubSubscription {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        Ok(WebSubSubscription {
+            book_id: decode_uuid(row, "book_id")?,
+            hub: row.try_get("hub")?,
+            topic: row.try_get("topic")?,
+            secret: row.try_get("secret")?,
+            verified_at: row.try_get("verified_at")?,
+            lease_expires_at: row.try_get("lease_expires_at")?,
+            last_notified_at: row.try_get("last_notified_at")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+pub struct WebSubSubscriptionClient {
+    pool: Pool<Sqlite>,
+}
+
+impl WebSubSubscriptionClient {
+    pub fn new(pool: &Pool<Sqlite>) -> WebSubSubscriptionClient {
+        WebSubSubscriptionClient { pool: pool.clone() }
+    }
+
+    /// Records a subscription request for the book, replacing any earlier one. It stays
+    /// unverified until the hub confirms it. An unsubscription still waiting to be verified is
+    /// dropped, so the hub can't confirm it against the new subscription.
+    #[instrument(skip(self, secret))]
+    pub async fn upsert_subscription(
+        &self,
+        book_id: &Uuid,
+        hub: &str,
+        topic: &str,
+        secret: &str,
+    ) -> ApiResult<WebSubSubscription> {
+        let now = Utc::now();
+        sqlx::query("DELETE FROM websub_unsubscriptions WHERE book_id = ?;")
+            .bind(book_id.as_bytes().as_slice())
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        let subscription = sqlx::query_as::<_, WebSubSubscription>(
+            "INSERT INTO websub_subscriptions(book_id, hub, topic, secret, created_at, updated_at)
+                 VALUES(?, ?, ?, ?, ?, ?)
+                 ON CONFLICT(book_id) DO UPDATE SET
+                  hub = excluded.hub,
+                  topic = excluded.topic,
+                  secret = excluded.secret,
+                  updated_at = excluded.updated_at
+                 RETURNING *;",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .bind(hub)
+        .bind(topic)
+        .bind(secret)
+        .bind(now)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match subscription {
+            Ok(x) => Ok(x),
+            Err(e) if is_foreign_key_error(&e) => Err(ApiError::ResourceNotFound {
+                id: book_id.to_string(),
+                resource_type: String::from("book"),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_subscription(&self, book_id: &Uuid) -> ApiResult<Option<WebSubSubscription>> {
+        let subscription = sqlx::query_as::<_, WebSubSubscription>(
+            "SELECT * FROM websub_subscriptions WHERE book_id = ?",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(subscription)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_subscriptions(&self) -> ApiResult<Vec<WebSubSubscription>> {
+        let subscriptions = sqlx::query_as::<_, WebSubSubscription>(
+            "SELECT * FROM websub_subscriptions ORDER BY created_at",
+        )
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(subscriptions)
+    }
+
+    /// Subscriptions whose lease ends before `before`, or which the hub never verified.
+    #[instrument(skip(self))]
+    pub async fn list_expiring_subscriptions(
+        &self,
+        before: &DateTime<Utc>,
+    ) -> ApiResult<Vec<WebSubSubscription>> {
+        let subscriptions = sqlx::query_as::<_, WebSubSubscription>(
+            "SELECT * FROM websub_subscriptions
+                 WHERE lease_expires_at IS NULL OR lease_expires_at < ?",
+        )
+        .bind(before)
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(subscriptions)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn mark_verified(
+        &self,
+        book_id: &Uuid,
+        lease_expires_at: Option<&DateTime<Utc>>,
+    ) -> ApiResult<()> {
+        let now = Utc::now();
+        sqlx::query(
+            "UPDATE websub_subscriptions
+                 SET verified_at = ?, lease_expires_at = ?, updated_at = ?
+                 WHERE book_id = ?;",
+        )
+        .bind(now)
+        .bind(lease_expires_at)
+        .bind(now)
+        .bind(book_id.as_bytes().as_slice())
+        .execute(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn record_notification(&self, book_id: &Uuid) -> ApiResult<()> {
+        sqlx::query("UPDATE websub_subscriptions SET last_notified_at = ? WHERE book_id = ?;")
+            .bind(Utc::now())
+            .bind(book_id.as_bytes().as_slice())
+            .execute(&self.pool)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        Ok(())
+    }
+
+    /// Forgets the book's subscription and records that its unsubscription was asked for, for
+    /// the hub's verification to be checked against. None if the book had no subscription.
+    #[instrument(skip(self))]
+    pub async fn unsubscribe(&self, book_id: &Uuid) -> ApiResult<Option<WebSubSubscription>> {
+        let mut transaction = self.pool.begin().await?;
+        let subscription = sqlx::query_as::<_, WebSubSubscription>(
+            "DELETE FROM websub_subscriptions WHERE book_id = ? RETURNING *;",
+        )
+        .bind(book_id.as_bytes().as_slice())
+        .fetch_optional(&mut transaction)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        if let Some(subscription) = &subscription {
+            sqlx::query(
+                "INSERT OR REPLACE INTO websub_unsubscriptions(book_id, topic, requested_at)
+                     VALUES(?, ?, ?);",
+            )
+            .bind(book_id.as_bytes().as_slice())
+            .bind(&subscription.topic)
+            .bind(Utc::now())
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(subscription)
+    }
+
+    /// Whether an unsubscription from the topic was asked for on behalf of the book, which is
+    /// then settled so the hub can only confirm it once.
+    #[instrument(skip(self))]
+    pub async fn confirm_unsubscription(&self, book_id: &Uuid, topic: &str) -> ApiResult<bool> {
+        let result =
+            sqlx::query("DELETE FROM websub_unsubscriptions WHERE book_id = ? AND topic = ?;")
+                .bind(book_id.as_bytes().as_slice())
+                .bind(topic)
+                .execute(&self.pool)
+                .instrument(info_span!("Querying db"))
+                .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
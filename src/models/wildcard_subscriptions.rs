@@ -0,0 +1,191 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{sqlite::SqliteRow, Pool, Row, Sqlite};
+use tracing::{info_span, instrument, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    util::is_foreign_key_error,
+};
+
+use super::{decode_optional_uuid, decode_uuid, TagClient};
+
+/// Subscribes a subscriber to every book, current and future, apart from those excluded, or to
+/// every book with a tag. A regular subscription is kept for each covered book, so that each
+/// has its own last delivered chapter.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct WildcardSubscription {
+    pub id: Uuid,
+    #[serde(rename = "subscriberId")]
+    pub subscriber_id: Uuid,
+    #[serde(rename = "chunkSize")]
+    pub chunk_size: i32,
+    #[serde(rename = "excludedBookIds")]
+    pub excluded_book_ids: Vec<Uuid>,
+    /// Only books with this tag are covered, including those tagged later.
+    #[serde(rename = "tagId", skip_serializing_if = "Option::is_none")]
+    pub tag_id: Option<Uuid>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl<'r> sqlx::FromRow<'r, SqliteRow> for WildcardSubscription {
+    fn from_row(row: &'r SqliteRow) -> core::result::Result<Self, sqlx::Error> {
+        let excluded_book_ids: String = row.try_get("excluded_book_ids")?;
+        let excluded_book_ids =
+            serde_json::from_str(&excluded_book_ids).map_err(|err| sqlx::Error::ColumnDecode {
+                index: "excluded_book_ids".into(),
+                source: Box::new(err),
+            })?;
+        Ok(WildcardSubscription {
+            id: decode_uuid(row, "id")?,
+            subscriber_id: decode_uuid(row, "subscriber_id")?,
+            chunk_size: row.try_get("chunk_size")?,
+            excluded_book_ids,
+            tag_id: decode_optional_uuid(row, "tag_id")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+pub struct WildcardSubscriptionClient {
+    pool: Pool<Sqlite>,
+}
+
+impl WildcardSubscriptionClient {
+    pub fn new(pool: &Pool<Sqlite>) -> WildcardSubscriptionClient {
+        WildcardSubscriptionClient { pool: pool.clone() }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn create_wildcard_subscription(
+        &self,
+        subscriber_id: &Uuid,
+        chunk_size: Option<&i32>,
+        excluded_book_ids: &[Uuid],
+        tag_id: Option<&Uuid>,
+    ) -> ApiResult<WildcardSubscription> {
+        // Sqlite doesn't tell us _which_ foreign key causes an error, so we must do some checks
+        if let Some(tag_id) = tag_id {
+            if TagClient::new(&self.pool).get_tag(tag_id).await?.is_none() {
+                return Err(ApiError::ResourceNotFound {
+                    id: tag_id.to_string(),
+                    resource_type: String::from("tag"),
+                });
+            }
+        }
+        let subscription = sqlx::query_as::<_, WildcardSubscription>(
+            "INSERT INTO wildcard_subscriptions(id, subscriber_id, chunk_size, excluded_book_ids, tag_id, created_at, updated_at)
+            VALUES(?, ?,
+                coalesce(?, (SELECT default_chunk_size FROM subscribers WHERE id = ?), 1),
+                ?, ?, ?, ?)
+            RETURNING *;",
+        )
+        .bind(Uuid::new_v4().as_bytes().as_slice())
+        .bind(subscriber_id.as_bytes().as_slice())
+        .bind(chunk_size)
+        .bind(subscriber_id.as_bytes().as_slice())
+        .bind(serde_json::to_string(excluded_book_ids)?)
+        .bind(tag_id.map(|x| x.as_bytes().as_slice()))
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await;
+        match subscription {
+            Ok(subscription) => Ok(subscription),
+            Err(e) => match is_foreign_key_error(&e) {
+                true => Err(ApiError::ResourceNotFound {
+                    id: subscriber_id.to_string(),
+                    resource_type: String::from("subscriber"),
+                }),
+                false => Err(e.into()),
+            },
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn update_wildcard_subscription(
+        &self,
+        id: &Uuid,
+        chunk_size: Option<i32>,
+        excluded_book_ids: Option<&[Uuid]>,
+    ) -> ApiResult<WildcardSubscription> {
+        let excluded_book_ids = excluded_book_ids.map(serde_json::to_string).transpose()?;
+        let subscription = sqlx::query_as::<_, WildcardSubscription>(
+            "UPDATE wildcard_subscriptions
+                 SET chunk_size = coalesce(?, chunk_size),
+                  excluded_book_ids = coalesce(?, excluded_book_ids),
+                  updated_at = ?
+                 WHERE id = ?
+                 RETURNING *;",
+        )
+        .bind(chunk_size)
+        .bind(excluded_book_ids)
+        .bind(Utc::now())
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        match subscription {
+            Some(x) => Ok(x),
+            None => Err(ApiError::ResourceNotFound {
+                id: id.to_string(),
+                resource_type: String::from("wildcardSubscription"),
+            }),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_wildcard_subscription(
+        &self,
+        id: &Uuid,
+    ) -> ApiResult<Option<WildcardSubscription>> {
+        let subscription = sqlx::query_as::<_, WildcardSubscription>(
+            "SELECT * FROM wildcard_subscriptions WHERE id = ?",
+        )
+        .bind(id.as_bytes().as_slice())
+        .fetch_optional(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(subscription)
+    }
+
+    /// Lists the subscriber's wildcard subscriptions, or everyone's if `subscriber_id` is None.
+    #[instrument(skip(self))]
+    pub async fn list_wildcard_subscriptions(
+        &self,
+        subscriber_id: Option<&Uuid>,
+    ) -> ApiResult<Vec<WildcardSubscription>> {
+        let subscriptions = sqlx::query_as::<_, WildcardSubscription>(
+            "SELECT * FROM wildcard_subscriptions WHERE coalesce(subscriber_id = ?, true)",
+        )
+        .bind(subscriber_id.map(|x| x.as_bytes().as_slice()))
+        .fetch_all(&self.pool)
+        .instrument(info_span!("Querying db"))
+        .await?;
+        Ok(subscriptions)
+    }
+
+    /// Deletes the wildcard subscription along with the subscriptions it created.
+    #[instrument(skip(self))]
+    pub async fn delete_wildcard_subscription(&self, id: &Uuid) -> ApiResult<()> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("DELETE FROM subscriptions WHERE wildcard_subscription_id = ?")
+            .bind(id.as_bytes().as_slice())
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        sqlx::query("DELETE FROM wildcard_subscriptions WHERE id = ?")
+            .bind(id.as_bytes().as_slice())
+            .execute(&mut transaction)
+            .instrument(info_span!("Querying db"))
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+}
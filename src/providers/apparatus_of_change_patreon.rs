@@ -1,5 +1,3 @@
-use std::env;
-
 use anyhow::anyhow;
 use async_trait::async_trait;
 use chrono::DateTime;
@@ -7,9 +5,6 @@ use chrono::Utc;
 use futures::future::try_join_all;
 use itertools::Itertools;
 use mailparse::MailHeaderMap;
-use rusoto_core::credential::StaticProvider;
-use rusoto_core::HttpClient;
-use rusoto_core::Region;
 use rusoto_s3::GetObjectRequest;
 use rusoto_s3::ListObjectsV2Request;
 use rusoto_s3::Object;
@@ -21,6 +16,7 @@ use uuid::Uuid;
 
 use crate::models::ChapterMetadata;
 
+use super::fixtures::{record_fixture, FixtureKind};
 use super::NewChapter;
 use super::NewChapterProvider;
 
@@ -36,6 +32,10 @@ impl NewChapterProvider for ApparatusOfChangePatreonNewChapterProvider {
     ) -> anyhow::Result<Vec<NewChapter>> {
         return get_chapters(book_id, last_publish_date).await;
     }
+
+    async fn validate(&self) -> anyhow::Result<()> {
+        super::validate_email_bucket().await
+    }
 }
 
 #[tracing::instrument(name = "Listing S3 objects for new emails", level = "info", ret)]
@@ -43,15 +43,7 @@ pub async fn get_chapters(
     book_id: &Uuid,
     last_publish_date: Option<&DateTime<Utc>>,
 ) -> anyhow::Result<Vec<NewChapter>> {
-    let s3 = S3Client::new_with(
-        HttpClient::new().expect("failed to create request dispatcher"),
-        StaticProvider::new_minimal(
-            env::var("AWS_ACCESS_KEY")?,
-            env::var("AWS_SECRET_ACCESS_KEY")?,
-        ),
-        Region::default(),
-    );
-    let bucket = env::var("AWS_EMAIL_BUCKET")?;
+    let (s3, bucket) = super::email_bucket()?;
     let objects = s3
         .list_objects_v2(ListObjectsV2Request {
             bucket: bucket.clone(),
@@ -118,7 +110,22 @@ async fn get_new_chapter_from_email(
         .into_async_read()
         .read_to_end(&mut chapter_bytes)
         .await?;
-    let chapter_email = mailparse::parse_mail(&chapter_bytes)?;
+    record_fixture(
+        "apparatusOfChangePatreon",
+        FixtureKind::Email,
+        &chapter_bytes,
+    )
+    .await;
+    parse_email(book_id, &chapter_bytes, published_at)
+}
+
+/// Parses the chapter out of the raw email, which is kept as the chapter's source.
+pub(super) fn parse_email(
+    book_id: &Uuid,
+    email: &[u8],
+    published_at: Option<DateTime<Utc>>,
+) -> anyhow::Result<Vec<NewChapter>> {
+    let chapter_email = mailparse::parse_mail(email)?;
     let subject = chapter_email.headers.get_first_value("Subject");
     match &subject {
         Some(x) => {
@@ -157,10 +164,14 @@ async fn get_new_chapter_from_email(
         title: chapter_title_from_subject(&subject.unwrap())
             .ok_or_else(|| anyhow!("Failed to find chapter title from email subject"))?
             .into(),
+        raw_title: None,
+        deliver_after: None,
         book_id: *book_id,
         html: Some(body.into_bytes()),
         epub: None,
         published_at,
+        ordinal: 0,
+        source: Some(email.to_vec()),
         metadata: ChapterMetadata::ApparatusOfChangePatreon,
     };
     Ok(Vec::from([chapter]))
@@ -0,0 +1,114 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use tracing::warn;
+
+/// Consecutive failed discoveries, across all of a provider's books, which open its circuit.
+const FAILURE_THRESHOLD: u32 = 5;
+
+/// How long an open circuit skips the provider before letting a discovery through to test it.
+const OPEN_MINUTES: i64 = 15;
+
+static CIRCUITS: OnceLock<Mutex<HashMap<&'static str, Circuit>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CircuitState {
+    /// Discoveries are made as usual.
+    Closed,
+    /// The provider keeps failing, so its books are skipped until `retryAt`.
+    Open,
+    /// One discovery is testing whether the provider has recovered.
+    HalfOpen,
+}
+
+/// Stops discovery from a provider which is down or blocking cereal, rather than failing, and
+/// adding to the provider's load, for each of its books every run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Circuit {
+    pub state: CircuitState,
+    #[serde(rename = "consecutiveFailures")]
+    pub consecutive_failures: u32,
+    #[serde(rename = "openedAt")]
+    pub opened_at: Option<DateTime<Utc>>,
+    #[serde(rename = "retryAt")]
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+impl Default for Circuit {
+    fn default() -> Self {
+        Circuit {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+            retry_at: None,
+        }
+    }
+}
+
+/// Whether a discovery may be made from the provider. Once an open circuit's wait is over, the
+/// next caller is let through to test the provider and the rest are held back until it reports
+/// its result.
+pub fn allows(provider: &'static str) -> bool {
+    let mut circuits = CIRCUITS.get_or_init(Default::default).lock().unwrap();
+    let circuit = circuits.entry(provider).or_default();
+    match (circuit.state, circuit.retry_at) {
+        (CircuitState::Closed, _) => true,
+        (CircuitState::Open, Some(retry_at)) if retry_at <= Utc::now() => {
+            circuit.state = CircuitState::HalfOpen;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Records the result of a discovery from the provider, which closes its circuit on success
+/// and opens it after too many failures, or a failed test.
+pub fn record_result(provider: &'static str, success: bool) {
+    let mut circuits = CIRCUITS.get_or_init(Default::default).lock().unwrap();
+    let circuit = circuits.entry(provider).or_default();
+    if success {
+        *circuit = Circuit::default();
+        return;
+    }
+    circuit.consecutive_failures += 1;
+    if circuit.state == CircuitState::HalfOpen || circuit.consecutive_failures >= FAILURE_THRESHOLD
+    {
+        if circuit.state == CircuitState::Closed {
+            warn!(
+                "Pausing discovery from {} after {} consecutive failures",
+                provider, circuit.consecutive_failures
+            );
+        }
+        let now = Utc::now();
+        circuit.state = CircuitState::Open;
+        circuit.opened_at = Some(now);
+        circuit.retry_at = Some(now + Duration::minutes(OPEN_MINUTES));
+    }
+}
+
+/// Gives back the test of a half-open circuit whose discovery was skipped without reaching the
+/// provider, such as during a blackout, so the next discovery tests it instead.
+pub fn release(provider: &'static str) {
+    let mut circuits = CIRCUITS.get_or_init(Default::default).lock().unwrap();
+    let circuit = circuits.entry(provider).or_default();
+    if circuit.state == CircuitState::HalfOpen {
+        circuit.state = CircuitState::Open;
+        circuit.retry_at = Some(Utc::now());
+    }
+}
+
+/// The provider's circuit on this instance.
+pub fn circuit(provider: &'static str) -> Circuit {
+    CIRCUITS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .get(provider)
+        .cloned()
+        .unwrap_or_default()
+}
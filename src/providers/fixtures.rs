@@ -0,0 +1,161 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use serde_json::{json, Value};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    models::{NewChapter, RoyalRoadOptions},
+    util::content_digest,
+};
+
+use super::{
+    apparatus_of_change_patreon, daily_grind_patreon, pale, royalroad, wandering_inn_patreon,
+};
+
+/// What a recorded response holds, which decides how it is parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixtureKind {
+    /// An RSS feed of new chapters.
+    Feed,
+    /// A table of contents, read when backfilling.
+    ChapterList,
+    /// The page a chapter's body is parsed from.
+    ChapterPage,
+    /// A raw email announcing or holding chapters.
+    Email,
+}
+
+impl FixtureKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FixtureKind::Feed => "feed",
+            FixtureKind::ChapterList => "chapterList",
+            FixtureKind::ChapterPage => "chapterPage",
+            FixtureKind::Email => "email",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<FixtureKind> {
+        match kind {
+            "feed" => Some(FixtureKind::Feed),
+            "chapterList" => Some(FixtureKind::ChapterList),
+            "chapterPage" => Some(FixtureKind::ChapterPage),
+            "email" => Some(FixtureKind::Email),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            FixtureKind::Feed => "xml",
+            FixtureKind::ChapterList | FixtureKind::ChapterPage => "html",
+            FixtureKind::Email => "eml",
+        }
+    }
+}
+
+/// Saves a response fetched by the provider as `<provider>/<kind>/<digest>.<extension>` under
+/// the directory in CEREAL_RECORD_FIXTURES, to be copied into `tests/fixtures`. Nothing is
+/// recorded unless it is set. Providers are named as in book metadata.
+pub(super) async fn record_fixture(provider: &str, kind: FixtureKind, body: &[u8]) {
+    let dir = match env::var("CEREAL_RECORD_FIXTURES") {
+        Ok(x) => PathBuf::from(x).join(provider).join(kind.as_str()),
+        Err(_) => return,
+    };
+    let path = dir.join(format!(
+        "{}.{}",
+        &content_digest(body)[..16],
+        kind.extension()
+    ));
+    let recorded = async {
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(&path, body).await
+    };
+    match recorded.await {
+        Ok(()) => info!("Recorded fixture {}", path.display()),
+        Err(e) => error!("Failed to record fixture {}: {}", path.display(), e),
+    }
+}
+
+fn chapter_fixture(chapter: &NewChapter) -> Value {
+    json!({
+        "title": chapter.title,
+        "metadata": chapter.metadata,
+        "publishedAt": chapter.published_at,
+        "ordinal": chapter.ordinal,
+        "html": chapter.html.as_ref().map(|x| String::from_utf8_lossy(x)),
+    })
+}
+
+fn chapters_fixture(chapters: &[NewChapter]) -> Value {
+    Value::Array(chapters.iter().map(chapter_fixture).collect())
+}
+
+/// Runs the provider's parsing over a recorded response, without fetching anything, for
+/// comparing with the output expected of it. Chapters are parsed into a nil book, and
+/// royalroad ones into fiction 0 with the default options, as the response doesn't record
+/// which book it was fetched for.
+pub fn parse_fixture(provider: &str, kind: FixtureKind, body: &[u8]) -> anyhow::Result<Value> {
+    let book_id = Uuid::nil();
+    let page = || String::from_utf8_lossy(body);
+    let parsed = match (provider, kind) {
+        ("royalroad", FixtureKind::Feed) => {
+            chapters_fixture(&royalroad::parse_feed(0, &book_id, body)?)
+        }
+        ("royalroad", FixtureKind::ChapterList) => {
+            chapters_fixture(&royalroad::parse_chapter_list(0, &book_id, &page())?)
+        }
+        ("royalroad", FixtureKind::ChapterPage) => {
+            let html = royalroad::parse_chapter_body(&page(), &RoyalRoadOptions::default())?;
+            json!({ "html": String::from_utf8_lossy(&html) })
+        }
+        ("pale", FixtureKind::Feed) => chapters_fixture(&pale::parse_feed(&book_id, body)?),
+        ("pale", FixtureKind::ChapterPage) => {
+            let html = pale::parse_chapter_body(&page())?;
+            json!({ "html": String::from_utf8_lossy(&html) })
+        }
+        ("wanderingInnPatreon", FixtureKind::Email) => {
+            chapters_fixture(&wandering_inn_patreon::parse_email(&book_id, body, None)?)
+        }
+        ("wanderingInnPatreon", FixtureKind::ChapterPage) => {
+            json!({ "html": wandering_inn_patreon::parse_chapter_body(&page())? })
+        }
+        ("dailyGrindPatreon", FixtureKind::Email) => {
+            chapters_fixture(&daily_grind_patreon::parse_email(&book_id, body, None)?)
+        }
+        ("apparatusOfChangePatreon", FixtureKind::Email) => chapters_fixture(
+            &apparatus_of_change_patreon::parse_email(&book_id, body, None)?,
+        ),
+        _ => bail!(
+            "Provider {} has no {} responses to parse",
+            provider,
+            kind.as_str()
+        ),
+    };
+    Ok(parsed)
+}
+
+/// Parses a fixture from its path, `<provider>/<kind>/<name>`, relative to the fixtures
+/// directory.
+pub fn parse_fixture_file(fixtures: &Path, path: &Path) -> anyhow::Result<Value> {
+    let relative = path.strip_prefix(fixtures)?;
+    let mut components = relative
+        .components()
+        .map(|x| x.as_os_str().to_string_lossy());
+    let (provider, kind) = match (components.next(), components.next()) {
+        (Some(provider), Some(kind)) => (provider, kind),
+        _ => bail!(
+            "Fixture {} isn't in a provider and kind directory",
+            path.display()
+        ),
+    };
+    let kind =
+        FixtureKind::parse(&kind).with_context(|| format!("Unknown fixture kind {}", kind))?;
+    let body = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    parse_fixture(&provider, kind, &body)
+}
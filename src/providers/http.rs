@@ -0,0 +1,410 @@
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, NaiveTime, Utc};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_LANGUAGE},
+    Client, RequestBuilder, Response, StatusCode, Url,
+};
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::{error, info, instrument, warn, Span};
+use uuid::Uuid;
+
+use crate::{
+    config::{config, BlackoutWindow, HeaderProfile},
+    telemetry::with_trace_context,
+};
+
+use super::robots::{Robots, USER_AGENT_TOKEN};
+
+/// Minimum time between requests to these hosts and their subdomains, so that backfilling a
+/// long book doesn't get the server banned.
+const DEFAULT_HOST_INTERVALS: &[(&str, Duration)] = &[
+    ("royalroad.com", Duration::from_secs(2)),
+    ("wanderinginn.com", Duration::from_secs(5)),
+];
+
+/// Minimum time between requests to hosts without a configured interval.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+static CLIENTS: OnceLock<Mutex<HashMap<String, Client>>> = OnceLock::new();
+static HOST_INTERVALS: OnceLock<HashMap<String, Duration>> = OnceLock::new();
+static NEXT_REQUEST_AT: OnceLock<Mutex<HashMap<String, Instant>>> = OnceLock::new();
+
+/// How long a fetched robots.txt is trusted before it is fetched again.
+const ROBOTS_TXT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Parsed robots.txt files by url, with the time they were fetched.
+type RobotsTxtCache = Mutex<HashMap<String, (Instant, Arc<Robots>)>>;
+
+static ROBOTS_TXT: OnceLock<RobotsTxtCache> = OnceLock::new();
+
+static HOST_STATS: OnceLock<Mutex<HashMap<String, HostStats>>> = OnceLock::new();
+
+/// Statuses with which hosts turn away scrapers they think are going too fast.
+const THROTTLED_STATUSES: &[StatusCode] = &[
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::FORBIDDEN,
+    StatusCode::SERVICE_UNAVAILABLE,
+];
+
+/// What this instance has seen of requests to a host since it started, for tuning rate limits
+/// and finding out why a provider stopped updating.
+#[derive(Debug, PartialEq, Clone, Default, Serialize)]
+pub struct HostStats {
+    pub host: String,
+    pub requests: u64,
+    /// Requests which couldn't be sent or got an unsuccessful status.
+    pub errors: u64,
+    #[serde(rename = "errorRate")]
+    pub error_rate: f64,
+    /// Bytes of response bodies, as far as hosts report their length.
+    #[serde(rename = "bytesFetched")]
+    pub bytes_fetched: u64,
+    /// Time to the response's headers, not counting waits for the request budget.
+    #[serde(rename = "averageLatencyMs")]
+    pub average_latency_ms: Option<u64>,
+    #[serde(rename = "lastRequestAt")]
+    pub last_request_at: Option<DateTime<Utc>>,
+    /// The last time the host answered with a status it uses to turn away scrapers.
+    #[serde(rename = "lastThrottledAt")]
+    pub last_throttled_at: Option<DateTime<Utc>>,
+    #[serde(rename = "lastThrottledStatus")]
+    pub last_throttled_status: Option<u16>,
+    #[serde(skip)]
+    total_latency: Duration,
+}
+
+fn record_request(host: &str, latency: Duration, response: Option<&Response>) {
+    let mut stats = HOST_STATS.get_or_init(Default::default).lock().unwrap();
+    let stats = stats
+        .entry(host.to_lowercase())
+        .or_insert_with(|| HostStats {
+            host: host.to_lowercase(),
+            ..Default::default()
+        });
+    stats.requests += 1;
+    stats.total_latency += latency;
+    stats.last_request_at = Some(Utc::now());
+    match response {
+        Some(response) => {
+            let status = response.status();
+            if !status.is_success() {
+                stats.errors += 1;
+            }
+            if THROTTLED_STATUSES.contains(&status) {
+                stats.last_throttled_at = Some(Utc::now());
+                stats.last_throttled_status = Some(status.as_u16());
+            }
+            stats.bytes_fetched += response.content_length().unwrap_or_default();
+        }
+        None => stats.errors += 1,
+    }
+}
+
+/// The stats of every host requested since this instance started, by host.
+pub fn host_stats() -> Vec<HostStats> {
+    let stats = HOST_STATS.get_or_init(Default::default).lock().unwrap();
+    let mut hosts: Vec<HostStats> = stats
+        .values()
+        .map(|x| HostStats {
+            error_rate: x.errors as f64 / x.requests as f64,
+            average_latency_ms: Some((x.total_latency / x.requests as u32).as_millis() as u64),
+            ..x.clone()
+        })
+        .collect();
+    hosts.sort_by(|a, b| a.host.cmp(&b.host));
+    hosts
+}
+
+tokio::task_local! {
+    static IGNORE_ROBOTS_TXT: bool;
+}
+
+/// Runs `work` with robots.txt rules ignored or honoured, for books which override them.
+pub async fn with_robots_txt_ignored<F: Future>(ignore: bool, work: F) -> F::Output {
+    IGNORE_ROBOTS_TXT.scope(ignore, work).await
+}
+
+fn robots_txt_ignored() -> bool {
+    IGNORE_ROBOTS_TXT.try_with(|x| *x).unwrap_or(false)
+}
+
+/// The profile used for providers and fields which aren't configured.
+fn builtin_header_profile(provider: &str) -> HeaderProfile {
+    match provider {
+        // The site rejects requests which don't look like a browser, and password protected
+        // posts are unlocked with a cookie.
+        "wanderingInn" => HeaderProfile {
+            user_agent: Some(String::from(
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:109.0) Gecko/20100101 Firefox/110.0",
+            )),
+            cookie_store: Some(true),
+            ..Default::default()
+        },
+        _ => HeaderProfile {
+            user_agent: Some(format!(
+                "Mozilla/5.0 (compatible; {}/{}; +https://github.com/JordanSekky/cereal)",
+                USER_AGENT_TOKEN,
+                env!("CARGO_PKG_VERSION")
+            )),
+            accept_language: Some(String::from("en-US,en;q=0.5")),
+            cookie_store: Some(false),
+            ..Default::default()
+        },
+    }
+}
+
+/// The headers sent to the provider, from most to least specific: the provider's configured
+/// profile, its built in profile, the configured default and the built in default.
+pub fn header_profile(provider: &str) -> HeaderProfile {
+    let profiles = config().header_profiles.clone();
+    profiles
+        .providers
+        .get(provider)
+        .cloned()
+        .unwrap_or_default()
+        .or(&builtin_header_profile(provider))
+        .or(&profiles.default)
+        .or(&builtin_header_profile("default"))
+}
+
+fn build_client(provider: &str) -> Result<Client> {
+    let profile = header_profile(provider);
+    let mut headers = HeaderMap::new();
+    if let Some(accept_language) = &profile.accept_language {
+        headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_str(accept_language)?);
+    }
+    for (name, value) in &profile.headers {
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+    let mut builder = Client::builder()
+        .default_headers(headers)
+        .cookie_store(profile.cookie_store.unwrap_or_default());
+    if let Some(user_agent) = &profile.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+    Ok(builder.build()?)
+}
+
+/// The client shared by requests to the provider, which shares one cookie jar if the
+/// provider's profile keeps cookies.
+pub fn client(provider: &str) -> Result<Client> {
+    let mut clients = CLIENTS.get_or_init(Default::default).lock().unwrap();
+    if let Some(client) = clients.get(provider) {
+        return Ok(client.clone());
+    }
+    let client = build_client(provider)?;
+    clients.insert(provider.to_owned(), client.clone());
+    Ok(client)
+}
+
+/// Drops the shared clients, and their cookies, so they are built again with the current header
+/// profiles.
+pub fn reset_clients() {
+    if let Some(clients) = CLIENTS.get() {
+        clients.lock().unwrap().clear();
+    }
+}
+
+/// A client for the provider with a cookie jar of its own, for requests which must not share
+/// cookies with others.
+pub fn new_client(provider: &str) -> Result<Client> {
+    build_client(provider)
+}
+
+/// Reads CEREAL_HOST_RATE_LIMITS, a comma separated list of `host=seconds` pairs which override
+/// the default intervals, e.g. `royalroad.com=3,wanderinginn.com=10`.
+fn host_intervals() -> &'static HashMap<String, Duration> {
+    HOST_INTERVALS.get_or_init(|| {
+        let mut intervals: HashMap<String, Duration> = DEFAULT_HOST_INTERVALS
+            .iter()
+            .map(|(host, interval)| (host.to_string(), *interval))
+            .collect();
+        if let Ok(config) = env::var("CEREAL_HOST_RATE_LIMITS") {
+            for entry in config.split(',').filter(|x| !x.trim().is_empty()) {
+                let parsed = entry.split_once('=').and_then(|(host, secs)| {
+                    let secs = secs.trim().parse::<f64>().ok()?;
+                    Some((host.trim(), Duration::try_from_secs_f64(secs).ok()?))
+                });
+                match parsed {
+                    Some((host, interval)) => {
+                        intervals.insert(host.to_lowercase(), interval);
+                    }
+                    _ => error!("Ignoring invalid CEREAL_HOST_RATE_LIMITS entry {:?}", entry),
+                }
+            }
+        }
+        intervals
+    })
+}
+
+/// Whether the host is the domain or one of its subdomains.
+fn is_within_domain(host: &str, domain: &str) -> bool {
+    let (host, domain) = (host.to_lowercase(), domain.to_lowercase());
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+fn interval_for_host(host: &str) -> Duration {
+    let config = config();
+    // Rate limits in the config file are checked to be valid durations when it's loaded.
+    let configured = config
+        .host_rate_limits
+        .iter()
+        .map(|(domain, secs)| (domain.as_str(), Duration::from_secs_f64(*secs)));
+    host_intervals()
+        .iter()
+        .map(|(domain, interval)| (domain.as_str(), *interval))
+        // The config file comes last, so it wins ties with the environment.
+        .chain(configured)
+        .filter(|(domain, _)| is_within_domain(host, domain))
+        // Prefer the most specific domain.
+        .max_by_key(|(domain, _)| domain.len())
+        .map(|(_, interval)| interval)
+        .unwrap_or(DEFAULT_INTERVAL)
+}
+
+/// Waits until the host's request budget allows another request. Each caller reserves the next
+/// free slot, so concurrent callers are spaced out rather than released together. A crawl delay
+/// longer than the configured interval takes precedence.
+#[instrument(level = "info")]
+pub async fn wait_for_host(host: &str, crawl_delay: Option<Duration>) {
+    let interval = interval_for_host(host).max(crawl_delay.unwrap_or_default());
+    let slot = {
+        let mut next_request_at = NEXT_REQUEST_AT
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap();
+        let now = Instant::now();
+        let next = next_request_at.entry(host.to_lowercase()).or_insert(now);
+        let slot = (*next).max(now);
+        *next = slot + interval;
+        slot
+    };
+    if slot > Instant::now() {
+        info!("Waiting {:?} for request budget", slot - Instant::now());
+    }
+    tokio::time::sleep_until(slot).await;
+}
+
+/// The configured blackout window, if any, which pauses scraping the host for the book right
+/// now. Requests made on behalf of no book in particular only honour windows for every book,
+/// and books without a host to scrape only those for every host.
+pub fn active_blackout(host: Option<&str>, book_id: Option<&Uuid>) -> Option<BlackoutWindow> {
+    let now = Utc::now().time();
+    config()
+        .blackout_windows
+        .iter()
+        .filter(|window| match host {
+            Some(host) => {
+                window.hosts.is_empty() || window.hosts.iter().any(|x| is_within_domain(host, x))
+            }
+            None => window.hosts.is_empty(),
+        })
+        .filter(|window| match book_id {
+            Some(book_id) => window.books.is_empty() || window.books.contains(book_id),
+            None => window.books.is_empty(),
+        })
+        .find(|window| window.contains(now))
+        .cloned()
+}
+
+/// Returned by [`send`] for a host in a blackout window. No request was made, so it says
+/// nothing about whether the provider is working and shouldn't count as one of its failures.
+#[derive(Debug, thiserror::Error)]
+#[error("Scraping {host} is paused until {until} UTC")]
+pub struct BlackoutSkip {
+    pub host: String,
+    pub until: NaiveTime,
+}
+
+/// Whether the request was skipped for a blackout window rather than failing.
+pub fn is_blackout_skip(error: &anyhow::Error) -> bool {
+    error.chain().any(|x| x.is::<BlackoutSkip>())
+}
+
+/// Fetches the robots.txt which applies to the url, or returns the cached copy. Hosts without a
+/// readable robots.txt allow everything.
+#[instrument(level = "info", skip(url), fields(url = %url))]
+async fn robots_txt(client: &Client, url: &Url, host: &str) -> Result<Arc<Robots>> {
+    let robots_url = url.join("/robots.txt")?;
+    let cache = ROBOTS_TXT.get_or_init(Default::default);
+    if let Some((fetched_at, robots)) = cache.lock().unwrap().get(robots_url.as_str()) {
+        if fetched_at.elapsed() < ROBOTS_TXT_TTL {
+            return Ok(robots.clone());
+        }
+    }
+    wait_for_host(host, None).await;
+    let robots = match client.get(robots_url.clone()).send().await {
+        Ok(res) if res.status().is_success() => Robots::parse(&res.text().await?),
+        Ok(res) if res.status() == StatusCode::NOT_FOUND => Robots::allow_all(),
+        Ok(res) => {
+            warn!("Unexpected status {} for {}", res.status(), robots_url);
+            Robots::allow_all()
+        }
+        Err(e) => {
+            warn!("Failed to fetch {}: {}", robots_url, e);
+            Robots::allow_all()
+        }
+    };
+    let robots = Arc::new(robots);
+    cache
+        .lock()
+        .unwrap()
+        .insert(robots_url.to_string(), (Instant::now(), robots.clone()));
+    Ok(robots)
+}
+
+/// Sends the request with `client` once the target host's request budget allows it, failing if
+/// robots.txt disallows it. A host in a blackout window fails with [`BlackoutSkip`]. All
+/// provider requests should go through here.
+#[instrument(level = "info", skip_all, fields(url = tracing::field::Empty))]
+pub async fn send(client: &Client, request: RequestBuilder) -> Result<Response> {
+    let request = with_trace_context(request).build()?;
+    let url = request.url().clone();
+    Span::current().record("url", url.as_str());
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("Request url {} has no host", url))?
+        .to_owned();
+    if let Some(window) = active_blackout(Some(&host), None) {
+        return Err(BlackoutSkip {
+            host,
+            until: window.end,
+        }
+        .into());
+    }
+    let mut crawl_delay = None;
+    if !robots_txt_ignored() {
+        let robots = robots_txt(client, &url, &host).await?;
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_owned(),
+        };
+        if !robots.is_allowed(&path) {
+            bail!("robots.txt of {} disallows fetching {}", host, url);
+        }
+        crawl_delay = robots.crawl_delay;
+    }
+    wait_for_host(&host, crawl_delay).await;
+    let started_at = Instant::now();
+    let response = client.execute(request).await;
+    record_request(&host, started_at.elapsed(), response.as_ref().ok());
+    Ok(response?)
+}
+
+/// Sends a GET request with the provider's shared client.
+pub async fn get(provider: &str, url: &str) -> Result<Response> {
+    let client = client(provider)?;
+    send(&client, client.get(url)).await
+}
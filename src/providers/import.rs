@@ -0,0 +1,228 @@
+use std::io::{Cursor, Read};
+
+use anyhow::{anyhow, bail, Context, Result};
+use itertools::Itertools;
+use scraper::{Html, Selector};
+use zip::ZipArchive;
+
+/// A chapter split out of an uploaded file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedChapter {
+    pub title: String,
+    pub html: Vec<u8>,
+    /// The path of the file within the archive.
+    pub source: String,
+}
+
+/// How much of an archive is read once decompressed, so that a small upload can't inflate to
+/// exhaust memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveLimits {
+    /// The largest a single file may be.
+    pub file_bytes: u64,
+    /// The most that all of the files read may add up to.
+    pub total_bytes: u64,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        Self {
+            file_bytes: 32 * 1024 * 1024,
+            total_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Splits an epub, or a zip of html files, into chapters. Epub chapters follow the spine, and
+/// html files are taken in name order, so numbering them keeps them in reading order. Files
+/// without any text, such as covers, are skipped.
+pub fn split_archive(archive: &[u8], limits: ArchiveLimits) -> Result<Vec<ImportedChapter>> {
+    let mut zip = ZipArchive::new(Cursor::new(archive))
+        .context("The upload is not an epub or zip archive")?;
+    let mut reader = LimitedReader {
+        limits,
+        remaining: limits.total_bytes,
+    };
+    let container = match zip.by_name("META-INF/container.xml") {
+        Ok(mut container) => Some(reader.read_to_string(&mut container, "META-INF/container.xml")?),
+        Err(_) => None,
+    };
+    let paths = match container {
+        Some(container) => epub_spine(&mut zip, &mut reader, &container)?,
+        None => zip
+            .file_names()
+            .filter(|x| is_html(x) && !x.starts_with("__MACOSX/"))
+            .map(String::from)
+            .sorted()
+            .collect(),
+    };
+    let mut chapters = Vec::new();
+    for path in paths {
+        let mut file = zip
+            .by_name(&path)
+            .with_context(|| format!("The archive lists {} but doesn't contain it", path))?;
+        let document = Html::parse_document(&reader.read_to_string(&mut file, &path)?);
+        if let Some(chapter) = chapter_from_document(&document, &path) {
+            chapters.push(chapter);
+        }
+    }
+    if chapters.is_empty() {
+        bail!("The archive contains no chapters");
+    }
+    Ok(chapters)
+}
+
+/// Reads files out of an archive within its limits.
+struct LimitedReader {
+    limits: ArchiveLimits,
+    /// What is left of the total once the files read so far are taken from it.
+    remaining: u64,
+}
+
+impl LimitedReader {
+    fn read_to_string(&mut self, file: &mut impl Read, path: &str) -> Result<String> {
+        let limit = self.limits.file_bytes.min(self.remaining);
+        let mut bytes = Vec::new();
+        // Reading a byte past the limit tells a file which fits exactly from one which doesn't.
+        file.take(limit + 1).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 > self.limits.file_bytes {
+            bail!(
+                "{} is larger than the {} byte limit for a file",
+                path,
+                self.limits.file_bytes
+            );
+        }
+        if bytes.len() as u64 > self.remaining {
+            bail!(
+                "The archive is larger than the {} byte limit once decompressed",
+                self.limits.total_bytes
+            );
+        }
+        self.remaining -= bytes.len() as u64;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+fn is_html(path: &str) -> bool {
+    let path = path.to_lowercase();
+    [".html", ".htm", ".xhtml"]
+        .iter()
+        .any(|x| path.ends_with(x))
+}
+
+fn selector(selector: &str) -> Selector {
+    Selector::parse(selector).unwrap()
+}
+
+/// The paths of the epub's content documents in reading order, read from its package file.
+fn epub_spine(
+    zip: &mut ZipArchive<Cursor<&[u8]>>,
+    reader: &mut LimitedReader,
+    container: &str,
+) -> Result<Vec<String>> {
+    // The html parser copes with the xml well enough to find elements and attributes.
+    let container = Html::parse_document(container);
+    let package_path = container
+        .select(&selector("rootfile"))
+        .find_map(|x| x.value().attr("full-path"))
+        .ok_or_else(|| anyhow!("The epub's container.xml names no package file"))?
+        .to_owned();
+    let package = reader.read_to_string(
+        &mut zip
+            .by_name(&package_path)
+            .with_context(|| format!("The epub is missing its package file {}", package_path))?,
+        &package_path,
+    )?;
+    let package = Html::parse_document(&package);
+    let base = match package_path.rsplit_once('/') {
+        Some((dir, _)) => format!("{}/", dir),
+        None => String::new(),
+    };
+    let items: Vec<(&str, &str, bool)> = package
+        .select(&selector("manifest item"))
+        .filter_map(|x| {
+            let properties = x.value().attr("properties").unwrap_or_default();
+            Some((
+                x.value().attr("id")?,
+                x.value().attr("href")?,
+                properties.split_whitespace().any(|x| x == "nav"),
+            ))
+        })
+        .collect();
+    let paths = package
+        .select(&selector("spine itemref"))
+        .filter_map(|x| x.value().attr("idref"))
+        .filter_map(|idref| items.iter().find(|(id, _, _)| *id == idref))
+        // The table of contents repeats the chapter titles rather than being one.
+        .filter(|(_, _, nav)| !nav)
+        .map(|(_, href, _)| resolve_path(&base, href))
+        .collect();
+    Ok(paths)
+}
+
+/// Joins an href from the package file onto the package's directory.
+fn resolve_path(base: &str, href: &str) -> String {
+    let href = percent_decode(href.split('#').next().unwrap_or_default());
+    let mut parts: Vec<&str> = base.split('/').filter(|x| !x.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+fn percent_decode(href: &str) -> String {
+    let mut bytes = Vec::with_capacity(href.len());
+    let mut rest = href.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|x| u8::from_str_radix(std::str::from_utf8(x).ok()?, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The chapter in the document, titled by its first heading, its title element or else the
+/// file name.
+fn chapter_from_document(document: &Html, path: &str) -> Option<ImportedChapter> {
+    let body = document.select(&selector("body")).next()?;
+    if body.text().all(|x| x.trim().is_empty()) {
+        return None;
+    }
+    let text = |selector: &Selector| {
+        document
+            .select(selector)
+            .map(|x| x.text().join(" ").split_whitespace().join(" "))
+            .find(|x| !x.is_empty())
+    };
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let title = text(&selector("h1, h2, h3"))
+        .or_else(|| text(&selector("title")))
+        .unwrap_or_else(|| {
+            file_name
+                .rsplit_once('.')
+                .map_or(file_name, |(stem, _)| stem)
+                .to_owned()
+        });
+    Some(ImportedChapter {
+        title,
+        html: body.inner_html().into_bytes(),
+        source: path.to_owned(),
+    })
+}
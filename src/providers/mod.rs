@@ -1,14 +1,31 @@
 mod apparatus_of_change_patreon;
+pub mod circuit;
 mod daily_grind_patreon;
+pub mod fixtures;
+pub mod http;
+mod import;
 mod pale;
+mod robots;
 mod royalroad;
+mod titles;
 mod wandering_inn_patreon;
+mod websub;
+
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+pub use import::{split_archive, ArchiveLimits, ImportedChapter};
+pub use royalroad::{get_fiction_details, parse_fiction_url};
+use rusoto_core::{credential::StaticProvider, HttpClient, Region};
+use rusoto_s3::{ListObjectsV2Request, S3Client, S3};
+pub use titles::{detect_volume, normalize_title, title_key};
 use uuid::Uuid;
 pub use wandering_inn_patreon::WanderingInnPatreonNewChapterProvider;
+pub use websub::discover_websub_hub;
 
-use crate::models::{BookMetadata, Chapter, ChapterMetadata, NewChapter};
+use crate::models::{
+    credential, Book, BookMetadata, Chapter, ChapterMetadata, NewChapter, RoyalRoadOptions,
+};
 
 use self::{
     apparatus_of_change_patreon::ApparatusOfChangePatreonNewChapterProvider,
@@ -20,7 +37,12 @@ use self::{
 
 #[async_trait]
 pub trait ChapterBodyProvider {
-    async fn fetch_chapter_body(&self, chapter: &Chapter) -> anyhow::Result<Vec<u8>>;
+    /// Fetches the page the chapter's body is parsed from, which is kept as its source.
+    async fn fetch_chapter_page(&self, chapter: &Chapter) -> anyhow::Result<String>;
+
+    /// Parses the chapter's body out of its page, whether just fetched or stored, so a
+    /// parsing fix can be applied to chapters fetched before it.
+    fn parse_chapter_page(&self, page: &str) -> anyhow::Result<Vec<u8>>;
 }
 
 #[async_trait]
@@ -30,9 +52,82 @@ pub trait NewChapterProvider {
         book_id: &Uuid,
         last_publish_date: Option<&DateTime<Utc>>,
     ) -> anyhow::Result<Vec<NewChapter>>;
+
+    /// Checks that the provider's source is reachable and understood, so misconfigured books
+    /// can be rejected when they are created rather than failing in the background later.
+    async fn validate(&self) -> anyhow::Result<()>;
+
+    /// Fetches a page of the source's backlist, for backfilling chapters older than its feed
+    /// reaches. Pages are numbered from 1, newest first, and None is returned past the last.
+    /// Sources whose feed holds every chapter have no backlist.
+    async fn fetch_backlist_page(
+        &self,
+        _book_id: &Uuid,
+        _page: i64,
+    ) -> anyhow::Result<Option<Vec<NewChapter>>> {
+        Ok(None)
+    }
+}
+
+/// A client for the bucket which patreon emails are delivered to, and the bucket's name. The keys
+/// and bucket are read from the credential vault, falling back to the environment.
+fn email_bucket() -> anyhow::Result<(S3Client, String)> {
+    let s3 = S3Client::new_with(
+        HttpClient::new()?,
+        StaticProvider::new_minimal(
+            credential("AWS_ACCESS_KEY").context("AWS_ACCESS_KEY is not set")?,
+            credential("AWS_SECRET_ACCESS_KEY").context("AWS_SECRET_ACCESS_KEY is not set")?,
+        ),
+        Region::default(),
+    );
+    let bucket = credential("AWS_EMAIL_BUCKET").context("AWS_EMAIL_BUCKET is not set")?;
+    Ok((s3, bucket))
+}
+
+/// Checks that the bucket which patreon emails are delivered to can be listed.
+async fn validate_email_bucket() -> anyhow::Result<()> {
+    let (s3, bucket) = email_bucket()?;
+    s3.list_objects_v2(ListObjectsV2Request {
+        bucket: bucket.clone(),
+        max_keys: Some(1),
+        ..Default::default()
+    })
+    .await
+    .with_context(|| format!("Failed to list email bucket {}", bucket))?;
+    Ok(())
 }
 
 impl BookMetadata {
+    /// Names the provider in span attributes.
+    pub fn provider_name(&self) -> &'static str {
+        match self {
+            BookMetadata::TheWanderingInnPatreon => "wanderingInnPatreon",
+            BookMetadata::TheDailyGrindPatreon => "dailyGrindPatreon",
+            BookMetadata::ApparatusOfChangePatreon => "apparatusOfChangePatreon",
+            BookMetadata::RoyalRoad { .. } => "royalroad",
+            BookMetadata::Pale => "pale",
+        }
+    }
+
+    /// The host the book's chapters are scraped from, for blackout windows. Books whose chapters
+    /// arrive by email have none.
+    pub fn source_host(&self) -> Option<&'static str> {
+        match self {
+            BookMetadata::RoyalRoad { .. } => Some("www.royalroad.com"),
+            BookMetadata::Pale => Some("palewebserial.wordpress.com"),
+            BookMetadata::TheWanderingInnPatreon => Some("wanderinginn.com"),
+            BookMetadata::TheDailyGrindPatreon | BookMetadata::ApparatusOfChangePatreon => None,
+        }
+    }
+
+    /// The feed a WebSub hub can push the book's new chapters from, for sources which support it.
+    pub fn websub_topic(&self) -> Option<&'static str> {
+        match self {
+            BookMetadata::Pale => Some(pale::FEED_URL),
+            _ => None,
+        }
+    }
+
     pub fn chapter_provider(&self) -> Box<dyn NewChapterProvider + Send + Sync> {
         match self {
             BookMetadata::TheWanderingInnPatreon => Box::new(WanderingInnPatreonNewChapterProvider),
@@ -40,7 +135,7 @@ impl BookMetadata {
             BookMetadata::ApparatusOfChangePatreon => {
                 Box::new(ApparatusOfChangePatreonNewChapterProvider)
             }
-            BookMetadata::RoyalRoad { book_id } => Box::new(RoyalroadNewChapterProvider {
+            BookMetadata::RoyalRoad { book_id, .. } => Box::new(RoyalroadNewChapterProvider {
                 royalroad_book_id: *book_id,
             }),
             BookMetadata::Pale => Box::new(PaleNewChapterProvider),
@@ -48,13 +143,26 @@ impl BookMetadata {
     }
 }
 
+/// What a chapter's stored source parses to. Pages only hold the chapter's body, so the title
+/// and metadata are only found again for chapters which arrive by email.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParsedSource {
+    pub title: Option<String>,
+    pub metadata: Option<ChapterMetadata>,
+    pub html: Vec<u8>,
+}
+
 impl ChapterMetadata {
-    pub fn body_provider(&self) -> Option<Box<dyn ChapterBodyProvider + Send + Sync>> {
+    pub fn body_provider(&self, book: &Book) -> Option<Box<dyn ChapterBodyProvider + Send + Sync>> {
         match self {
             ChapterMetadata::TheWanderingInnPatreon { url, password } => {
                 Some(Box::new(WanderingInnPatreonChapterBodyProvider {
                     url: url.clone(),
-                    password: password.clone(),
+                    // Fall back to the book's password when none was found in the email.
+                    password: password
+                        .clone()
+                        .or_else(|| book.password.clone())
+                        .or_else(|| book.password_credential.as_deref().and_then(credential)),
                 }))
             }
             ChapterMetadata::RoyalRoad {
@@ -62,12 +170,51 @@ impl ChapterMetadata {
                 royalroad_chapter_id,
             } => Some(Box::new(RoyalroadChapterBodyProvider {
                 royalroad_chapter_id: *royalroad_chapter_id,
+                options: match &book.metadata {
+                    BookMetadata::RoyalRoad { options, .. } => options.clone(),
+                    _ => RoyalRoadOptions::default(),
+                },
             })),
             ChapterMetadata::Pale { url } => {
                 Some(Box::new(PaleChapterBodyProvider { url: url.clone() }))
             }
             ChapterMetadata::TheDailyGrindPatreon => None,
             ChapterMetadata::ApparatusOfChangePatreon => None,
+            ChapterMetadata::Imported { .. } => None,
         }
     }
+
+    /// Parses the chapter out of its stored source again: its email for chapters which arrive
+    /// by email, and otherwise the page its body was fetched from.
+    pub fn parse_source(&self, book: &Book, source: &[u8]) -> anyhow::Result<ParsedSource> {
+        let chapters = match self {
+            ChapterMetadata::TheDailyGrindPatreon => {
+                daily_grind_patreon::parse_email(&book.id, source, None)?
+            }
+            ChapterMetadata::ApparatusOfChangePatreon => {
+                apparatus_of_change_patreon::parse_email(&book.id, source, None)?
+            }
+            _ => {
+                let provider = self
+                    .body_provider(book)
+                    .ok_or_else(|| anyhow!("The chapter has no source to parse"))?;
+                let html = provider.parse_chapter_page(&String::from_utf8_lossy(source))?;
+                return Ok(ParsedSource {
+                    title: None,
+                    metadata: None,
+                    html,
+                });
+            }
+        };
+        chapters
+            .into_iter()
+            .find_map(|x| {
+                x.html.map(|html| ParsedSource {
+                    title: Some(x.title),
+                    metadata: Some(x.metadata),
+                    html,
+                })
+            })
+            .ok_or_else(|| anyhow!("The email no longer parses to a chapter"))
+    }
 }
@@ -6,6 +6,7 @@ use async_trait::async_trait;
 use chrono::DateTime;
 use chrono::Utc;
 use itertools::Itertools;
+use reqwest::StatusCode;
 use scraper::{Html, Selector};
 use uuid::Uuid;
 
@@ -14,9 +15,16 @@ use crate::models::ChapterMetadata;
 use crate::models::NewChapter;
 use tracing::instrument;
 
+use super::fixtures::{record_fixture, FixtureKind};
+use super::http;
 use super::ChapterBodyProvider;
 use super::NewChapterProvider;
 
+/// Selects the header profile used for requests.
+const PROVIDER: &str = "pale";
+
+pub(super) const FEED_URL: &str = "https://palewebserial.wordpress.com/feed/";
+
 pub struct PaleNewChapterProvider;
 
 #[async_trait]
@@ -29,6 +37,34 @@ impl NewChapterProvider for PaleNewChapterProvider {
     ) -> anyhow::Result<Vec<NewChapter>> {
         return get_chapters(book_id, last_publish_date).await;
     }
+
+    #[instrument(skip(self))]
+    async fn validate(&self) -> anyhow::Result<()> {
+        let content = http::get(PROVIDER, FEED_URL)
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        rss::Channel::read_from(&content[..]).context("Failed to parse the Pale RSS feed")?;
+        Ok(())
+    }
+
+    /// Wordpress pages the feed back to the first post, and answers pages past the last with
+    /// a 404.
+    #[instrument(skip(self))]
+    async fn fetch_backlist_page(
+        &self,
+        book_id: &Uuid,
+        page: i64,
+    ) -> anyhow::Result<Option<Vec<NewChapter>>> {
+        let response = http::get(PROVIDER, &format!("{}?paged={}", FEED_URL, page)).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let content = response.error_for_status()?.bytes().await?;
+        record_fixture("pale", FixtureKind::Feed, &content).await;
+        Ok(Some(parse_feed(book_id, &content)?))
+    }
 }
 
 #[derive(Clone)]
@@ -39,9 +75,14 @@ pub struct PaleChapterBodyProvider {
 #[async_trait]
 impl ChapterBodyProvider for PaleChapterBodyProvider {
     #[instrument(skip(self))]
-    async fn fetch_chapter_body(&self, _chapter: &Chapter) -> anyhow::Result<Vec<u8>> {
-        let url = self.url.clone();
-        Ok(get_chapter_body(&url).await?)
+    async fn fetch_chapter_page(&self, _chapter: &Chapter) -> anyhow::Result<String> {
+        let page = http::get(PROVIDER, &self.url).await?.text().await?;
+        record_fixture("pale", FixtureKind::ChapterPage, page.as_bytes()).await;
+        Ok(page)
+    }
+
+    fn parse_chapter_page(&self, page: &str) -> anyhow::Result<Vec<u8>> {
+        parse_chapter_body(page)
     }
 }
 
@@ -50,16 +91,24 @@ pub async fn get_chapters(
     book_uuid: &Uuid,
     last_publish_date: Option<&DateTime<Utc>>,
 ) -> anyhow::Result<Vec<NewChapter>> {
-    let content = reqwest::get("https://palewebserial.wordpress.com/feed/")
-        .await?
-        .bytes()
-        .await?;
-    let channel = rss::Channel::read_from(&content[..])?;
+    let content = http::get(PROVIDER, FEED_URL).await?.bytes().await?;
+    record_fixture("pale", FixtureKind::Feed, &content).await;
+    let chapters = parse_feed(book_uuid, &content)?;
+    Ok(chapters
+        .into_iter()
+        .filter(|x| x.published_at.as_ref() > last_publish_date)
+        .collect())
+}
+
+pub(super) fn parse_feed(book_uuid: &Uuid, content: &[u8]) -> anyhow::Result<Vec<NewChapter>> {
+    let channel = rss::Channel::read_from(content)?;
     channel
         .items()
         .iter()
         .map(|item| {
             Ok(NewChapter {
+                raw_title: None,
+                deliver_after: None,
                 book_id: *book_uuid,
                 metadata: ChapterMetadata::Pale {
                     url: item
@@ -69,6 +118,8 @@ pub async fn get_chapters(
                 },
                 html: None,
                 epub: None,
+                ordinal: 0,
+                source: None,
                 title: item
                     .title()
                     .ok_or_else(|| anyhow!("No chapter title in RSS item. Item {:?}", &item))?
@@ -88,17 +139,12 @@ pub async fn get_chapters(
                 ),
             })
         })
-        .filter(|x| match x {
-            Ok(y) => y.published_at.as_ref() > last_publish_date,
-            Err(_) => true,
-        })
         .collect()
 }
 
 #[instrument]
-pub async fn get_chapter_body(link: &str) -> Result<Vec<u8>, anyhow::Error> {
-    let res = reqwest::get(link).await?.text().await?;
-    let doc = Html::parse_document(&res);
+pub(super) fn parse_chapter_body(page: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let doc = Html::parse_document(page);
     let chapter_body_elem_selector = Selector::parse("div.entry-content > *").unwrap();
 
     let body = doc
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+/// The user agent token matched against robots.txt groups.
+pub const USER_AGENT_TOKEN: &str = "cereal";
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+/// The rules of a robots.txt which apply to this crawler.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Robots {
+    rules: Vec<Rule>,
+    pub crawl_delay: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<Rule>,
+    crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    /// Allows everything, used when a host has no robots.txt.
+    pub fn allow_all() -> Robots {
+        Robots::default()
+    }
+
+    /// Parses robots.txt, keeping the group for our user agent or else the `*` group.
+    pub fn parse(robots_txt: &str) -> Robots {
+        let mut groups: Vec<Group> = Vec::new();
+        // Consecutive user-agent lines share the rules which follow them.
+        let mut reading_agents = false;
+        for line in robots_txt.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field.trim().to_lowercase(), value.trim()),
+                None => continue,
+            };
+            match field.as_str() {
+                "user-agent" => {
+                    if !reading_agents {
+                        groups.push(Group::default());
+                        reading_agents = true;
+                    }
+                    // A group was pushed above if none was being read.
+                    groups.last_mut().unwrap().agents.push(value.to_lowercase());
+                }
+                "allow" | "disallow" => {
+                    reading_agents = false;
+                    if let Some(group) = groups.last_mut() {
+                        // An empty disallow allows everything.
+                        if !value.is_empty() {
+                            group.rules.push(Rule {
+                                allow: field == "allow",
+                                pattern: value.to_owned(),
+                            });
+                        }
+                    }
+                }
+                "crawl-delay" => {
+                    reading_agents = false;
+                    if let (Some(group), Ok(secs)) = (groups.last_mut(), value.parse::<f64>()) {
+                        if secs.is_finite() && secs >= 0.0 {
+                            group.crawl_delay = Some(Duration::from_secs_f64(secs));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let group = groups
+            .iter()
+            .position(|x| x.agents.iter().any(|a| a == USER_AGENT_TOKEN))
+            .or_else(|| {
+                groups
+                    .iter()
+                    .position(|x| x.agents.iter().any(|a| a == "*"))
+            })
+            .map(|i| groups.swap_remove(i));
+        match group {
+            Some(group) => Robots {
+                rules: group.rules,
+                crawl_delay: group.crawl_delay,
+            },
+            None => Robots::allow_all(),
+        }
+    }
+
+    /// Whether the path (including any query) may be fetched. The longest matching rule
+    /// wins, with allow winning ties.
+    pub fn is_allowed(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|x| matches(&x.pattern, path))
+            .max_by_key(|x| (x.pattern.len(), x.allow))
+            .map(|x| x.allow)
+            .unwrap_or(true)
+    }
+}
+
+/// Matches a robots.txt path pattern, which may contain `*` wildcards and end with `$`.
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut pieces = pattern.split('*');
+    // The first piece must be a prefix of the path.
+    let first = pieces.next().unwrap_or_default();
+    let mut rest = match path.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let pieces: Vec<&str> = pieces.collect();
+    for (i, piece) in pieces.iter().enumerate() {
+        if anchored && i == pieces.len() - 1 {
+            return rest.ends_with(piece);
+        }
+        match rest.find(piece) {
+            Some(index) => rest = &rest[index + piece.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
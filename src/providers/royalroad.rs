@@ -1,14 +1,18 @@
 extern crate futures;
 extern crate reqwest;
 
+use std::collections::HashSet;
+
 use crate::models::Chapter;
 use crate::models::ChapterMetadata;
 use crate::models::NewChapter;
+use crate::models::RoyalRoadOptions;
 
 use anyhow::anyhow;
 use anyhow::Context;
 use async_trait::async_trait;
 use chrono::DateTime;
+use chrono::NaiveDateTime;
 use chrono::Utc;
 use scraper::{Html, Selector};
 use tracing::instrument;
@@ -16,9 +20,14 @@ use uuid::Uuid;
 
 use anyhow::Result;
 
+use super::fixtures::{record_fixture, FixtureKind};
+use super::http;
 use super::ChapterBodyProvider;
 use super::NewChapterProvider;
 
+/// Selects the header profile used for requests.
+const PROVIDER: &str = "royalroad";
+
 pub struct RoyalroadNewChapterProvider {
     pub royalroad_book_id: u64,
 }
@@ -33,56 +42,197 @@ impl NewChapterProvider for RoyalroadNewChapterProvider {
     ) -> anyhow::Result<Vec<NewChapter>> {
         return get_chapters(self.royalroad_book_id, book_id, last_publish_date).await;
     }
+
+    #[instrument(skip(self))]
+    async fn validate(&self) -> anyhow::Result<()> {
+        let content = http::get(PROVIDER, &feed_url(self.royalroad_book_id))
+            .await?
+            .error_for_status()
+            .with_context(|| {
+                format!(
+                    "No royalroad fiction found with id {}",
+                    self.royalroad_book_id
+                )
+            })?
+            .bytes()
+            .await?;
+        rss::Channel::read_from(&content[..]).with_context(|| {
+            format!(
+                "Failed to parse the RSS feed of royalroad fiction {}",
+                self.royalroad_book_id
+            )
+        })?;
+        Ok(())
+    }
+
+    /// The feed only holds the latest chapters, but the fiction's page lists all of them, so
+    /// the backlist is that single page.
+    #[instrument(skip(self))]
+    async fn fetch_backlist_page(
+        &self,
+        book_id: &Uuid,
+        page: i64,
+    ) -> anyhow::Result<Option<Vec<NewChapter>>> {
+        match page {
+            1 => Ok(Some(
+                get_chapter_list(self.royalroad_book_id, book_id).await?,
+            )),
+            _ => Ok(None),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct RoyalroadChapterBodyProvider {
     pub royalroad_chapter_id: u64,
+    pub options: RoyalRoadOptions,
 }
 
 #[async_trait]
 impl ChapterBodyProvider for RoyalroadChapterBodyProvider {
     #[instrument(skip(self))]
-    async fn fetch_chapter_body(&self, _chapter: &Chapter) -> anyhow::Result<Vec<u8>> {
-        Ok(get_chapter_body(&self.royalroad_chapter_id).await?)
+    async fn fetch_chapter_page(&self, _chapter: &Chapter) -> anyhow::Result<String> {
+        let link = format!(
+            "https://www.royalroad.com/fiction/chapter/{}",
+            self.royalroad_chapter_id
+        );
+        let page = http::get(PROVIDER, &link).await?.text().await?;
+        record_fixture("royalroad", FixtureKind::ChapterPage, page.as_bytes()).await;
+        Ok(page)
+    }
+
+    fn parse_chapter_page(&self, page: &str) -> anyhow::Result<Vec<u8>> {
+        parse_chapter_body(page, &self.options)
     }
 }
 
-pub async fn get_chapter_body(royalroad_chapter_id: &u64) -> Result<Vec<u8>> {
-    let link = format!(
-        "https://www.royalroad.com/fiction/chapter/{}",
-        royalroad_chapter_id
-    );
-    let res = reqwest::get(&link).await?.text().await?;
-    let doc = Html::parse_document(&res);
+pub(super) fn parse_chapter_body(page: &str, options: &RoyalRoadOptions) -> Result<Vec<u8>> {
+    let doc = Html::parse_document(page);
     let chapter_body_selector = Selector::parse("div.chapter-inner").unwrap();
 
-    let body = doc
+    let chapter_body = doc
         .select(&chapter_body_selector)
         .next()
-        .ok_or_else(|| anyhow!("Failed to find body in {}", link))?
-        .html();
+        .ok_or_else(|| anyhow!("Failed to find chapter body."))?;
+    let mut body = chapter_body.html();
+
+    // Element html is serialized the same way as its parent, so cleanup is done by replacing
+    // each element's html within the chapter body.
+    if options.expand_spoilers {
+        let spoiler_selector = Selector::parse(".spoiler, .spoiler-new").unwrap();
+        let spoiler_inner_selector = Selector::parse(".spoiler-inner").unwrap();
+        for spoiler in chapter_body.select(&spoiler_selector) {
+            if let Some(inner) = spoiler.select(&spoiler_inner_selector).next() {
+                body = body.replacen(
+                    &spoiler.html(),
+                    &format!("<blockquote>{}</blockquote>", inner.inner_html()),
+                    1,
+                );
+            }
+        }
+    }
+
+    if options.remove_watermarks {
+        let hidden_classes = get_hidden_classes(&doc);
+        let any_selector = Selector::parse("*").unwrap();
+        for watermark in chapter_body
+            .select(&any_selector)
+            .filter(|x| x.value().classes().any(|c| hidden_classes.contains(c)))
+        {
+            body = body.replacen(&watermark.html(), "", 1);
+        }
+    }
+
+    if options.keep_author_note_before || options.keep_author_note_after {
+        let (notes_before, notes_after) = get_author_notes(&doc);
+        if options.keep_author_note_before && !notes_before.is_empty() {
+            body = format!("{}<hr/>{}", notes_before.join("\n"), body);
+        }
+        if options.keep_author_note_after && !notes_after.is_empty() {
+            body = format!("{}<hr/>{}", body, notes_after.join("\n"));
+        }
+    }
+
     Ok(body.into_bytes())
 }
 
+/// Royalroad hides its anti-piracy paragraphs with randomly named classes declared in the
+/// page's stylesheets, so find every class which is styled as hidden.
+fn get_hidden_classes(doc: &Html) -> HashSet<String> {
+    let style_selector = Selector::parse("style").unwrap();
+    let styles: String = doc.select(&style_selector).flat_map(|x| x.text()).collect();
+    styles
+        .split('}')
+        .filter_map(|rule| rule.split_once('{'))
+        .filter(|(_, declarations)| {
+            let declarations = declarations.replace(char::is_whitespace, "");
+            declarations.contains("display:none")
+        })
+        .flat_map(|(selectors, _)| selectors.split(','))
+        .filter_map(|selector| selector.trim().strip_prefix('.'))
+        .filter(|class| {
+            class
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(String::from)
+        .collect()
+}
+
+/// Returns the html of the author's notes shown before and after the chapter body.
+fn get_author_notes(doc: &Html) -> (Vec<String>, Vec<String>) {
+    let portlet_selector = Selector::parse("div.author-note-portlet, div.chapter-inner").unwrap();
+    let note_selector = Selector::parse("div.author-note").unwrap();
+    let mut notes_before = Vec::new();
+    let mut notes_after = Vec::new();
+    let mut seen_chapter_body = false;
+    for portlet in doc.select(&portlet_selector) {
+        if portlet.value().classes().any(|c| c == "chapter-inner") {
+            seen_chapter_body = true;
+            continue;
+        }
+        let note = portlet
+            .select(&note_selector)
+            .next()
+            .map(|x| x.html())
+            .unwrap_or_else(|| portlet.html());
+        match seen_chapter_body {
+            true => notes_after.push(note),
+            false => notes_before.push(note),
+        }
+    }
+    (notes_before, notes_after)
+}
+
 pub async fn get_chapters(
     royalroad_book_id: u64,
     book_uuid: &Uuid,
     last_publish_date: Option<&DateTime<Utc>>,
 ) -> Result<Vec<NewChapter>> {
-    let content = reqwest::get(format!(
-        "https://www.royalroad.com/syndication/{}",
-        royalroad_book_id
-    ))
-    .await?
-    .bytes()
-    .await?;
-    let channel = rss::Channel::read_from(&content[..])?;
+    let content = http::get(PROVIDER, &feed_url(royalroad_book_id))
+        .await?
+        .bytes()
+        .await?;
+    record_fixture("royalroad", FixtureKind::Feed, &content).await;
+    Ok(parse_feed(royalroad_book_id, book_uuid, &content)?
+        .into_iter()
+        .filter(|x| x.published_at.as_ref() > last_publish_date)
+        .collect())
+}
+
+pub(super) fn parse_feed(
+    royalroad_book_id: u64,
+    book_uuid: &Uuid,
+    content: &[u8],
+) -> Result<Vec<NewChapter>> {
+    let channel = rss::Channel::read_from(content)?;
     channel
         .items()
         .iter()
         .map(|item| {
             Ok(NewChapter {
+                raw_title: None,
+                deliver_after: None,
                 book_id: *book_uuid,
                 metadata: ChapterMetadata::RoyalRoad {
                     royalroad_book_id,
@@ -90,6 +240,8 @@ pub async fn get_chapters(
                 },
                 html: None,
                 epub: None,
+                ordinal: 0,
+                source: None,
                 title: item
                     .title()
                     .and_then(|x| x.split_once(" - "))
@@ -111,13 +263,123 @@ pub async fn get_chapters(
                 ),
             })
         })
-        .filter(|x| match x {
-            Ok(y) => y.published_at.as_ref() > last_publish_date,
-            Err(_) => true,
+        .collect()
+}
+
+/// Every chapter listed in the table of contents on the fiction's page.
+#[instrument]
+async fn get_chapter_list(royalroad_book_id: u64, book_uuid: &Uuid) -> Result<Vec<NewChapter>> {
+    let content = http::get(
+        PROVIDER,
+        &format!("https://www.royalroad.com/fiction/{}", royalroad_book_id),
+    )
+    .await?
+    .error_for_status()?
+    .text()
+    .await?;
+    record_fixture("royalroad", FixtureKind::ChapterList, content.as_bytes()).await;
+    parse_chapter_list(royalroad_book_id, book_uuid, &content)
+}
+
+pub(super) fn parse_chapter_list(
+    royalroad_book_id: u64,
+    book_uuid: &Uuid,
+    content: &str,
+) -> Result<Vec<NewChapter>> {
+    let doc = Html::parse_document(content);
+    let row_selector = Selector::parse("table#chapters tbody tr").unwrap();
+    let link_selector = Selector::parse("td a[href]").unwrap();
+    let time_selector = Selector::parse("time[unixtime]").unwrap();
+    doc.select(&row_selector)
+        .enumerate()
+        .map(|(ordinal, row)| {
+            let link = row
+                .select(&link_selector)
+                .next()
+                .ok_or_else(|| anyhow!("No chapter link in royalroad table of contents row"))?;
+            let href = link.value().attr("href").unwrap_or_default();
+            // Links look like /fiction/21220/mother-of-learning/chapter/301778/1-good-morning.
+            let royalroad_chapter_id = href
+                .split('/')
+                .skip_while(|x| *x != "chapter")
+                .nth(1)
+                .and_then(|x| x.parse().ok())
+                .ok_or_else(|| anyhow!("No chapter id in royalroad chapter link {}", href))?;
+            let published_at = row
+                .select(&time_selector)
+                .next()
+                .and_then(|x| x.value().attr("unixtime")?.parse::<i64>().ok())
+                .and_then(|x| NaiveDateTime::from_timestamp_opt(x, 0))
+                .map(|x| DateTime::<Utc>::from_utc(x, Utc));
+            Ok(NewChapter {
+                raw_title: None,
+                deliver_after: None,
+                book_id: *book_uuid,
+                metadata: ChapterMetadata::RoyalRoad {
+                    royalroad_book_id,
+                    royalroad_chapter_id,
+                },
+                html: None,
+                epub: None,
+                ordinal: ordinal as i64,
+                source: None,
+                title: link.text().collect::<String>().trim().to_owned(),
+                published_at,
+            })
         })
         .collect()
 }
 
+/// The id of the fiction a royalroad url points to, from fiction pages such as
+/// `https://www.royalroad.com/fiction/21220/mother-of-learning` and their chapters.
+pub fn parse_fiction_url(url: &str) -> Option<u64> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    if host != "royalroad.com" && !host.ends_with(".royalroad.com") {
+        return None;
+    }
+    let mut segments = url.path_segments()?;
+    match segments.next() {
+        Some("fiction") => segments.next()?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The title and author of the fiction, read from its page.
+#[instrument]
+pub async fn get_fiction_details(royalroad_book_id: u64) -> Result<(String, String)> {
+    let content = http::get(
+        PROVIDER,
+        &format!("https://www.royalroad.com/fiction/{}", royalroad_book_id),
+    )
+    .await?
+    .error_for_status()
+    .with_context(|| format!("No royalroad fiction found with id {}", royalroad_book_id))?
+    .text()
+    .await?;
+    let doc = Html::parse_document(&content);
+    let meta = |property: &str| {
+        let selector = Selector::parse(&format!("meta[property=\"{}\"]", property)).unwrap();
+        doc.select(&selector)
+            .next()
+            .and_then(|x| x.value().attr("content"))
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+    };
+    let title = meta("og:title")
+        .ok_or_else(|| anyhow!("No title on royalroad fiction {}", royalroad_book_id))?;
+    let author = meta("books:author")
+        .ok_or_else(|| anyhow!("No author on royalroad fiction {}", royalroad_book_id))?;
+    Ok((title, author))
+}
+
+fn feed_url(royalroad_book_id: u64) -> String {
+    format!(
+        "https://www.royalroad.com/syndication/{}",
+        royalroad_book_id
+    )
+}
+
 fn get_chapter_id_from_link(link: Option<&str>) -> Result<u64> {
     link.and_then(|link| {
         link.rsplit_once('/')
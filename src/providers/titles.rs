@@ -0,0 +1,171 @@
+use itertools::Itertools;
+
+use crate::models::{Book, BookMetadata};
+
+/// Separators which feeds put between the book's name and the chapter's.
+const PREFIX_SEPARATORS: &[&str] = &["-", "–", "—", ":", "|"];
+
+/// Tidies a chapter title as found by the book's provider: the book's name is stripped from the
+/// front, slugs taken from links are turned back into words, and titles in one case are given
+/// consistent casing.
+pub fn normalize_title(book: &Book, raw: &str) -> String {
+    let title = collapse_whitespace(raw);
+    let title = strip_book_prefix(&title, &book.title).unwrap_or(title);
+    let title = match (&book.metadata, is_slug(&title)) {
+        // Point of view suffixes are written in capitals, so these are never recased.
+        (BookMetadata::TheWanderingInnPatreon, true) => wandering_inn_slug(&title),
+        (BookMetadata::TheWanderingInnPatreon, false) => title,
+        (_, true) => title_case(&title.split(['-', '_']).join(" ")),
+        (_, false) if is_single_case(&title) => title_case(&title),
+        (_, false) => title,
+    };
+    // Never replace a title with nothing.
+    match title.is_empty() {
+        true => collapse_whitespace(raw),
+        false => title,
+    }
+}
+
+/// Identifies a chapter across sources which format its title differently, ignoring case,
+/// spacing and punctuation.
+pub fn title_key(title: &str) -> String {
+    title
+        .chars()
+        .filter(|x| x.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn collapse_whitespace(title: &str) -> String {
+    title.split_whitespace().join(" ")
+}
+
+/// Removes `book_title` and a following separator from the start of the title, as in
+/// "The Wandering Inn - 9.62 GN".
+fn strip_book_prefix(title: &str, book_title: &str) -> Option<String> {
+    let book_title = collapse_whitespace(book_title);
+    if book_title.is_empty() {
+        return None;
+    }
+    let prefix = title.get(..book_title.len())?;
+    if !prefix.eq_ignore_ascii_case(&book_title) {
+        return None;
+    }
+    let rest = title[book_title.len()..].trim_start();
+    let rest = PREFIX_SEPARATORS
+        .iter()
+        .find_map(|x| rest.strip_prefix(x))?
+        .trim();
+    (!rest.is_empty()).then(|| rest.to_owned())
+}
+
+/// Whether the title looks like a url slug, e.g. "9-62-gn".
+fn is_slug(title: &str) -> bool {
+    title.contains(['-', '_'])
+        && title
+            .chars()
+            .all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == '-' || x == '_')
+}
+
+/// The Wandering Inn numbers chapters as volume.chapter with an optional letter suffix for the
+/// point of view, so "9-62-gn" becomes "9.62 GN".
+fn wandering_inn_slug(slug: &str) -> String {
+    let parts: Vec<&str> = slug.split(['-', '_']).filter(|x| !x.is_empty()).collect();
+    let is_number = |x: &str| x.chars().all(|c| c.is_ascii_digit());
+    match parts.as_slice() {
+        [volume, chapter, rest @ ..] if is_number(volume) && is_number(chapter) => {
+            let number = format!("{}.{}", volume, chapter);
+            let suffix = rest.iter().map(|x| x.to_uppercase()).join(" ");
+            [number, suffix].iter().filter(|x| !x.is_empty()).join(" ")
+        }
+        parts => parts.join(" "),
+    }
+}
+
+/// Whether the title is written all in lower or all in upper case.
+fn is_single_case(title: &str) -> bool {
+    title.chars().any(|x| x.is_alphabetic())
+        && (title == title.to_lowercase() || title == title.to_uppercase())
+}
+
+fn title_case(title: &str) -> String {
+    title
+        .split(' ')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first
+                    .to_uppercase()
+                    .chain(chars.flat_map(char::to_lowercase))
+                    .collect(),
+                None => String::new(),
+            }
+        })
+        .join(" ")
+}
+
+/// Words which introduce a volume number in a chapter title, as in "Volume 2: Chapter 5".
+const VOLUME_KEYWORDS: &[&str] = &["volume", "vol", "book", "arc"];
+
+/// The number of the volume, or arc, a chapter belongs to, if its title names one. The Wandering
+/// Inn and Pale number chapters as volume.chapter; other books are matched on markers like
+/// "Volume 2", "Vol. II" or "Arc 3".
+pub fn detect_volume(book: &Book, title: &str) -> Option<i64> {
+    let numbered = match book.metadata {
+        BookMetadata::TheWanderingInnPatreon | BookMetadata::Pale => {
+            title.split_whitespace().find_map(dotted_volume)
+        }
+        _ => None,
+    };
+    numbered.or_else(|| keyword_volume(title))
+}
+
+/// The volume of a volume.chapter number, e.g. 9 for "9.62" and 12 for "12.z".
+fn dotted_volume(word: &str) -> Option<i64> {
+    let word = word.trim_matches(|x: char| !x.is_alphanumeric());
+    let (volume, chapter) = word.split_once('.')?;
+    let is_chapter = !chapter.is_empty() && chapter.chars().all(|x| x.is_alphanumeric());
+    match is_chapter && volume.chars().all(|x| x.is_ascii_digit()) {
+        true => volume.parse().ok(),
+        false => None,
+    }
+}
+
+fn keyword_volume(title: &str) -> Option<i64> {
+    let words = title
+        .to_lowercase()
+        .split(|x: char| !x.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(str::to_owned)
+        .collect_vec();
+    words.iter().tuple_windows().find_map(|(keyword, number)| {
+        match VOLUME_KEYWORDS.contains(&keyword.as_str()) {
+            true => number.parse().ok().or_else(|| roman_numeral(number)),
+            false => None,
+        }
+    })
+}
+
+/// Parses the small roman numerals used to number volumes, up to 39.
+fn roman_numeral(word: &str) -> Option<i64> {
+    if word.is_empty() || !word.chars().all(|x| matches!(x, 'i' | 'v' | 'x')) {
+        return None;
+    }
+    let values = word
+        .chars()
+        .map(|x| match x {
+            'i' => 1,
+            'v' => 5,
+            _ => 10,
+        })
+        .collect_vec();
+    let total = values
+        .iter()
+        .enumerate()
+        .map(|(i, x)| match values.get(i + 1) {
+            Some(next) if next > x => -x,
+            _ => *x,
+        })
+        .sum();
+    Some(total)
+}
@@ -1,5 +1,4 @@
 use std::collections::HashMap;
-use std::env;
 
 use anyhow::anyhow;
 use anyhow::bail;
@@ -7,13 +6,9 @@ use async_trait::async_trait;
 use chrono::DateTime;
 use chrono::Utc;
 use futures::future::try_join_all;
-use itertools::Chunk;
 use itertools::Itertools;
 use mailparse::MailHeaderMap;
 use reqwest::Method;
-use rusoto_core::credential::StaticProvider;
-use rusoto_core::HttpClient;
-use rusoto_core::Region;
 use rusoto_s3::GetObjectRequest;
 use rusoto_s3::ListObjectsV2Request;
 use rusoto_s3::Object;
@@ -29,10 +24,15 @@ use uuid::Uuid;
 use crate::models::Chapter;
 use crate::models::ChapterMetadata;
 
+use super::fixtures::{record_fixture, FixtureKind};
+use super::http;
 use super::ChapterBodyProvider;
 use super::NewChapter;
 use super::NewChapterProvider;
 
+/// Selects the header profile used for requests.
+const PROVIDER: &str = "wanderingInn";
+
 pub struct WanderingInnPatreonNewChapterProvider;
 
 #[async_trait]
@@ -45,6 +45,10 @@ impl NewChapterProvider for WanderingInnPatreonNewChapterProvider {
     ) -> anyhow::Result<Vec<NewChapter>> {
         return get_chapters(book_id, last_publish_date).await;
     }
+
+    async fn validate(&self) -> anyhow::Result<()> {
+        super::validate_email_bucket().await
+    }
 }
 
 #[derive(Clone)]
@@ -56,13 +60,12 @@ pub struct WanderingInnPatreonChapterBodyProvider {
 #[async_trait]
 impl ChapterBodyProvider for WanderingInnPatreonChapterBodyProvider {
     #[instrument(skip(self))]
-    async fn fetch_chapter_body(&self, _chapter: &Chapter) -> anyhow::Result<Vec<u8>> {
-        let url = self.url.clone();
-        let password = self.password.clone();
-        Ok(get_chapter_body(&url, password.as_deref())
-            .await?
-            .as_bytes()
-            .into())
+    async fn fetch_chapter_page(&self, _chapter: &Chapter) -> anyhow::Result<String> {
+        get_chapter_page(&self.url, self.password.as_deref()).await
+    }
+
+    fn parse_chapter_page(&self, page: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(parse_chapter_body(page)?.into_bytes())
     }
 }
 
@@ -71,15 +74,7 @@ pub async fn get_chapters(
     book_id: &Uuid,
     last_publish_date: Option<&DateTime<Utc>>,
 ) -> anyhow::Result<Vec<NewChapter>> {
-    let s3 = S3Client::new_with(
-        HttpClient::new().expect("failed to create request dispatcher"),
-        StaticProvider::new_minimal(
-            env::var("AWS_ACCESS_KEY")?,
-            env::var("AWS_SECRET_ACCESS_KEY")?,
-        ),
-        Region::default(),
-    );
-    let bucket = env::var("AWS_EMAIL_BUCKET")?;
+    let (s3, bucket) = super::email_bucket()?;
     let objects = s3
         .list_objects_v2(ListObjectsV2Request {
             bucket: bucket.clone(),
@@ -153,7 +148,17 @@ async fn get_new_chapter_from_email(
         .into_async_read()
         .read_to_end(&mut chapter_bytes)
         .await?;
-    let chapter_email = mailparse::parse_mail(&chapter_bytes)?;
+    record_fixture("wanderingInnPatreon", FixtureKind::Email, &chapter_bytes).await;
+    parse_email(book_id, &chapter_bytes, published_at)
+}
+
+/// Finds the chapters linked from the raw email, along with the password which unlocks them.
+pub(super) fn parse_email(
+    book_id: &Uuid,
+    email: &[u8],
+    published_at: Option<DateTime<Utc>>,
+) -> anyhow::Result<Vec<NewChapter>> {
+    let chapter_email = mailparse::parse_mail(email)?;
     let subject = chapter_email.headers.get_first_value("Subject");
     info!("Subject is {:?}", subject);
     match subject {
@@ -203,15 +208,23 @@ async fn get_new_chapter_from_email(
     let chapters = doc
         .select(&links_selector)
         .filter_map(|x| x.value().attr("href").map(|y| (y, x.text().join(""))))
-        .filter_map(|(href, link_text)| {
+        // A single email may link several chapter parts which all share a publish date, so
+        // keep the order in which they were linked.
+        .enumerate()
+        .filter_map(|(ordinal, (href, link_text))| {
             Some(NewChapter {
                 title: chapter_title_from_link(&link_text)?.to_owned(),
+                raw_title: None,
+                deliver_after: None,
                 book_id: *book_id,
                 metadata: ChapterMetadata::TheWanderingInnPatreon {
                     url: href.to_owned(),
                     password: password.clone(),
                 },
                 published_at,
+                ordinal: ordinal as i64,
+                // The email only links the chapter, whose page is kept once it is fetched.
+                source: None,
                 html: None,
                 epub: None,
             })
@@ -231,25 +244,40 @@ fn chapter_title_from_link(link: &str) -> Option<&str> {
 }
 
 #[tracing::instrument(name = "Fetching chapter text from link.", level = "info")]
-pub async fn get_chapter_body(url: &str, password: Option<&str>) -> anyhow::Result<String> {
-    let reqwest_client = reqwest::Client::builder().cookie_store(true).build()?;
+pub async fn get_chapter_page(url: &str, password: Option<&str>) -> anyhow::Result<String> {
+    // Each chapter gets its own cookie jar, as the unlock cookie is specific to the password.
+    let reqwest_client = http::new_client(PROVIDER)?;
     if let Some(password) = password {
         let mut form_data = HashMap::with_capacity(2);
         form_data.insert("post_password", password);
         form_data.insert("Submit", "Enter");
-        let password_submit_result = reqwest_client
-            .request(
-                Method::POST,
-                "https://wanderinginn.com/wp-login.php?action=postpass",
-            )
-            .header("User-Agent", "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:109.0) Gecko/20100101 Firefox/110.0")
-            .form(&form_data)
-            .send()
-            .await?;
+        let password_submit_result = http::send(
+            &reqwest_client,
+            reqwest_client
+                .request(
+                    Method::POST,
+                    "https://wanderinginn.com/wp-login.php?action=postpass",
+                )
+                .form(&form_data),
+        )
+        .await?;
         tracing::info!("Submitted password: {:?}", password_submit_result);
     }
-    let res = reqwest_client.get(url).send().await?.text().await?;
-    let doc = Html::parse_document(&res);
+    let page = http::send(&reqwest_client, reqwest_client.get(url))
+        .await?
+        .text()
+        .await?;
+    record_fixture(
+        "wanderingInnPatreon",
+        FixtureKind::ChapterPage,
+        page.as_bytes(),
+    )
+    .await;
+    Ok(page)
+}
+
+pub(super) fn parse_chapter_body(page: &str) -> anyhow::Result<String> {
+    let doc = Html::parse_document(page);
     let chapter_body_elem_selector = Selector::parse("div.entry-content > *").unwrap();
 
     let body = doc
@@ -0,0 +1,48 @@
+use anyhow::{bail, Context, Result};
+use reqwest::header;
+use tracing::instrument;
+
+use super::http;
+
+/// Finds the hub a feed is published to, from its `Link` headers or the `atom:link` elements of
+/// the feed itself, as WordPress advertises it.
+#[instrument(ret, err)]
+pub async fn discover_websub_hub(provider: &str, topic: &str) -> Result<String> {
+    let response = http::get(provider, topic).await?.error_for_status()?;
+    let header_hub = response
+        .headers()
+        .get_all(header::LINK)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .find_map(|link| {
+            let (url, params) = link.split_once(';')?;
+            let is_hub = params
+                .split(';')
+                .any(|x| matches!(x.trim(), "rel=\"hub\"" | "rel=hub"));
+            is_hub.then(|| {
+                url.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_owned()
+            })
+        });
+    if let Some(hub) = header_hub {
+        return Ok(hub);
+    }
+    let content = response.bytes().await?;
+    let channel = rss::Channel::read_from(&content[..])
+        .with_context(|| format!("Failed to parse the feed at {}", topic))?;
+    let feed_hub = channel
+        .extensions()
+        .get("atom")
+        .and_then(|x| x.get("link"))
+        .into_iter()
+        .flatten()
+        .find(|x| x.attrs().get("rel").map(String::as_str) == Some("hub"))
+        .and_then(|x| x.attrs().get("href").cloned());
+    match feed_hub {
+        Some(hub) => Ok(hub),
+        None => bail!("The feed at {} doesn't advertise a WebSub hub", topic),
+    }
+}
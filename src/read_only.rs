@@ -0,0 +1,58 @@
+use axum::{
+    extract::{MatchedPath, State},
+    http::{Method, Request},
+    middleware::Next,
+    response::Response,
+};
+use sqlx::{Pool, Sqlite};
+use tracing::error;
+
+use crate::{config::config, error::ApiError, models::SettingsClient, AppState};
+
+const READ_ONLY_SETTING: &str = "readOnly";
+
+/// The route which turns read-only mode off again, so it is never refused.
+const SET_READ_ONLY_ROUTE: &str = "/setReadOnly";
+
+/// Whether read-only mode is on, either in the config file or through the admin api. Errors
+/// reading the setting count as off, so a flaky database doesn't stop every loop.
+pub async fn is_read_only(pool: &Pool<Sqlite>) -> bool {
+    if config().read_only {
+        return true;
+    }
+    match SettingsClient::new(pool)
+        .get_setting::<bool>(READ_ONLY_SETTING)
+        .await
+    {
+        Ok(x) => x.unwrap_or(false),
+        Err(e) => {
+            error!("Error reading the read-only setting: {}", e);
+            false
+        }
+    }
+}
+
+/// Turns read-only mode on or off for every instance sharing the database. It stays on while
+/// the config file sets it.
+pub async fn set_read_only(pool: &Pool<Sqlite>, read_only: bool) -> Result<(), ApiError> {
+    SettingsClient::new(pool)
+        .set_setting(READ_ONLY_SETTING, &read_only)
+        .await
+}
+
+/// Refuses requests other than GET and HEAD while read-only mode is on.
+pub async fn reject_writes<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ApiError> {
+    let is_read = matches!(*request.method(), Method::GET | Method::HEAD);
+    let is_toggle = request
+        .extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|x| x.as_str() == SET_READ_ONLY_ROUTE);
+    if !is_read && !is_toggle && is_read_only(&state.pool).await {
+        return Err(ApiError::ReadOnly);
+    }
+    Ok(next.run(request).await)
+}
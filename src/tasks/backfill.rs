@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+
+use sqlx::{Pool, Sqlite};
+use tracing::{error, info, instrument};
+
+use crate::{
+    error::ApiResult,
+    models::{BackfillClient, Book, ChapterClient, NewChapter},
+    providers::{
+        http::{is_blackout_skip, with_robots_txt_ignored},
+        normalize_title, title_key,
+    },
+    telemetry::record_provider_result,
+};
+
+/// How many backlist pages a backfill works through each discovery run, so a long backlist
+/// doesn't hold the book's discovery lease for too long.
+const PAGES_PER_RUN: i64 = 5;
+
+/// Continues the book's backfill, if it has one in progress, from the page it last reached.
+/// Each page's chapters not already in the book are placed before its existing chapters and
+/// backdated, so they are only delivered to subscriptions which haven't started the book. The
+/// cursor is saved after every page, so an interrupted backfill doesn't scrape pages again.
+#[instrument(skip_all, fields(book.id = %book.id))]
+pub async fn backfill_book(pool: &Pool<Sqlite>, book: &Book) {
+    let client = BackfillClient::new(pool);
+    let backfill = match client.get_backfill(&book.id).await {
+        Ok(Some(x)) if x.completed_at.is_none() => x,
+        Ok(_) => return,
+        Err(e) => {
+            error!("Error fetching backfill of book {}: {}", book.id, e);
+            return;
+        }
+    };
+
+    let provider = book.metadata.chapter_provider();
+    for page in backfill.next_page..backfill.next_page + PAGES_PER_RUN {
+        let fetched = with_robots_txt_ignored(
+            book.ignore_robots_txt,
+            provider.fetch_backlist_page(&book.id, page),
+        )
+        .await;
+        if let Err(e) = &fetched {
+            if is_blackout_skip(e) {
+                info!("Pausing backfill of book {}: {}", book.id, e);
+                return;
+            }
+        }
+        record_provider_result(book.metadata.provider_name(), "backfill", fetched.is_ok());
+        let chapters = match fetched {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                info!("Finished backfill of book {}", book.id);
+                if let Err(e) = client.complete_backfill(&book.id).await {
+                    error!("Error completing backfill of book {}: {}", book.id, e);
+                }
+                return;
+            }
+            Err(e) => {
+                let message = format!("{:#}", e);
+                error!(
+                    "Error fetching page {} of the backlist of book {}: {}",
+                    page, book.id, message
+                );
+                if let Err(e) = client.record_backfill_error(&book.id, &message).await {
+                    error!("Error recording backfill error of book {}: {}", book.id, e);
+                }
+                return;
+            }
+        };
+        let saved = match prepend_chapters(pool, book, chapters).await {
+            Ok(found) => {
+                client
+                    .advance_backfill(&book.id, page + 1, found as i64)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            error!(
+                "Error saving page {} of the backlist of book {}: {}",
+                page, book.id, e
+            );
+            if let Err(e) = client.record_backfill_error(&book.id, &e.to_string()).await {
+                error!("Error recording backfill error of book {}: {}", book.id, e);
+            }
+            return;
+        }
+    }
+}
+
+/// Creates the chapters the book doesn't have yet before its existing chapters, returning how
+/// many were created.
+async fn prepend_chapters(
+    pool: &Pool<Sqlite>,
+    book: &Book,
+    mut chapters: Vec<NewChapter>,
+) -> ApiResult<usize> {
+    let client = ChapterClient::new(pool);
+    let existing = client.list_chapters_shallow(&book.id).await?;
+    let mut known: HashSet<String> = existing.iter().map(|x| title_key(&x.title)).collect();
+    for chapter in chapters.iter_mut() {
+        let title = normalize_title(book, &chapter.title);
+        if title != chapter.title {
+            chapter.raw_title = Some(std::mem::replace(&mut chapter.title, title));
+        }
+    }
+    chapters.retain(|x| known.insert(title_key(&x.title)));
+    if chapters.is_empty() {
+        return Ok(0);
+    }
+
+    let created = client.prepend_chapters(&book.id, &chapters).await?;
+    info!("Backfilled {} chapters of book {}", created.len(), book.id);
+    Ok(created.len())
+}
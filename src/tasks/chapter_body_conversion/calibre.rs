@@ -1,9 +1,118 @@
 use anyhow::{bail, Context, Result};
-use rand::Rng;
-use std::fs;
+use chrono::{DateTime, Utc};
+use std::{env, fs, time::Duration};
 use tokio::process::Command;
 use tracing::{info, info_span, instrument, Instrument};
 
+use crate::models::ConversionOptions;
+
+use super::native::escape_xml;
+
+const TEMP_DIR_PREFIX: &str = "cereal-conversion-";
+
+/// The ebook-convert options a book's extra arguments may set, and whether each takes a value.
+/// Options which read or write files, or run code, are left out.
+const EXTRA_ARGS: &[(&str, bool)] = &[
+    ("--asciiize", false),
+    ("--base-font-size", true),
+    ("--change-justification", true),
+    ("--chapter", true),
+    ("--chapter-mark", true),
+    ("--disable-font-rescaling", false),
+    ("--dont-split-on-page-breaks", false),
+    ("--duplicate-links-in-toc", false),
+    ("--epub-flatten", false),
+    ("--epub-version", true),
+    ("--expand-css", false),
+    ("--flow-size", true),
+    ("--font-size-mapping", true),
+    ("--insert-blank-line", false),
+    ("--insert-blank-line-size", true),
+    ("--keep-ligatures", false),
+    ("--linearize-tables", false),
+    ("--margin-bottom", true),
+    ("--margin-left", true),
+    ("--margin-right", true),
+    ("--margin-top", true),
+    ("--max-toc-links", true),
+    ("--minimum-line-height", true),
+    ("--no-chapters-in-toc", false),
+    ("--no-default-epub-cover", false),
+    ("--no-svg-cover", false),
+    ("--output-profile", true),
+    ("--page-breaks-before", true),
+    ("--preserve-cover-aspect-ratio", false),
+    ("--pretty-print", false),
+    ("--remove-paragraph-spacing", false),
+    ("--remove-paragraph-spacing-indent-size", true),
+    ("--smarten-punctuation", false),
+    ("--subset-embedded-fonts", false),
+    ("--toc-threshold", true),
+    ("--unsmarten-punctuation", false),
+    ("--use-auto-toc", false),
+];
+
+/// Checks that extra arguments only set allowed ebook-convert options, with their values given
+/// either after an `=` or as the next argument.
+pub fn validate_extra_args(args: &[String]) -> Result<(), String> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (option, value) = match arg.split_once('=') {
+            Some((option, value)) => (option, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        let takes_value = EXTRA_ARGS
+            .iter()
+            .find(|(x, _)| *x == option)
+            .map(|(_, x)| *x)
+            .ok_or_else(|| format!("{:?} is not an allowed ebook-convert option", option))?;
+        match (takes_value, value) {
+            (true, None) if args.next().is_none() => {
+                return Err(format!("{} needs a value", option))
+            }
+            (false, Some(_)) => return Err(format!("{} does not take a value", option)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Metadata written into a generated epub so that readers and libraries sort serial
+/// installments correctly.
+#[derive(Debug, Clone)]
+pub struct EpubMetadata<'a> {
+    pub title: &'a str,
+    pub series: &'a str,
+    pub series_index: i64,
+    pub author: &'a str,
+    pub identifier: String,
+    pub published_at: Option<DateTime<Utc>>,
+    /// Open the book with a cover page and a table of contents, for epubs of a whole book.
+    pub front_matter: bool,
+}
+
+/// Runs `ebook-convert --version`, returning the first line of its output.
+#[instrument(err, level = "info")]
+pub async fn version() -> Result<String> {
+    let output = Command::new("ebook-convert")
+        .arg("--version")
+        .output()
+        .await
+        .with_context(|| "Failed to spawn ebook-convert. Perhaps calibre is not installed?")?;
+    if !output.status.success() {
+        bail!(
+            "ebook-convert --version failed with status {:?}",
+            output.status
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_owned())
+}
+
 #[instrument(
 name = "Converting to mobi",
 err,
@@ -13,36 +122,96 @@ skip(chapter_body),
 pub async fn generate_epub(
     input_extension: &str,
     chapter_body: &[u8],
-    cover_title: &str,
-    book_title: &str,
-    author: &str,
+    metadata: &EpubMetadata<'_>,
+    options: &ConversionOptions,
 ) -> Result<Vec<u8>> {
-    let file_name: String = rand::thread_rng()
-        .sample_iter(rand::distributions::Alphanumeric)
-        .take(30)
-        .map(char::from)
-        .collect();
-    let in_path = format!("/tmp/{}.{}", file_name, input_extension);
-    let out_path = format!("/tmp/{}.epub", file_name);
-    fs::write(&in_path, chapter_body)?;
-    let output = Command::new("ebook-convert")
+    // The directory is private to this user and removed when dropped, including on errors.
+    let dir = tempfile::Builder::new()
+        .prefix(TEMP_DIR_PREFIX)
+        .tempdir()
+        .with_context(|| "Failed to create temporary directory for conversion")?;
+    let in_path = dir.path().join(format!(
+        "chapter.{}",
+        input_extension.trim_start_matches('.')
+    ));
+    let out_path = dir.path().join("chapter.epub");
+    // Calibre carries the document's language into the epub's markup, where readers use it
+    // to hyphenate.
+    match &options.language {
+        Some(language) => fs::write(
+            &in_path,
+            [
+                format!(
+                    "<html lang=\"{0}\" xml:lang=\"{0}\"><body>",
+                    escape_xml(language)
+                )
+                .as_bytes(),
+                chapter_body,
+                b"</body></html>",
+            ]
+            .concat(),
+        )?,
+        None => fs::write(&in_path, chapter_body)?,
+    }
+    let filter_css = match &options.filter_css {
+        Some(properties) => properties.join(","),
+        None => String::from("font-family,color,background"),
+    };
+    let mut command = Command::new("ebook-convert");
+    command
         .arg(&in_path)
         .arg(&out_path)
         .arg("--filter-css")
-        .arg(r#""font-family,color,background""#)
+        .arg(filter_css)
         .arg("--authors")
-        .arg(author)
+        .arg(metadata.author)
         .arg("--title")
-        .arg(cover_title)
+        .arg(metadata.title)
         .arg("--series")
-        .arg(book_title)
+        .arg(metadata.series)
+        .arg("--series-index")
+        .arg(metadata.series_index.to_string())
         .arg("--output-profile")
-        .arg("kindle_oasis")
+        .arg("kindle_oasis");
+    if metadata.front_matter {
+        // Calibre generates a cover by default, so only the contents need asking for.
+        command
+            .arg("--level1-toc")
+            .arg("//h:h1")
+            .arg("--epub-inline-toc");
+    }
+    if let Some(published_at) = metadata.published_at {
+        command.arg("--pubdate").arg(published_at.to_rfc3339());
+    }
+    if let Some(language) = &options.language {
+        command.arg("--language").arg(language);
+    }
+    if let Some(publisher) = &options.publisher {
+        command.arg("--publisher").arg(publisher);
+    }
+    if let Some(extra_css) = &options.extra_css {
+        command.arg("--extra-css").arg(extra_css);
+    }
+    if let Some(font_family) = &options.embed_font_family {
+        command.arg("--embed-font-family").arg(font_family);
+    }
+    if options.embed_all_fonts {
+        command.arg("--embed-all-fonts");
+    }
+    if let Some(line_height) = options.line_height {
+        command.arg("--line-height").arg(line_height.to_string());
+    }
+    // Books saved before extra arguments were checked may still hold disallowed ones.
+    if let Err(e) = validate_extra_args(&options.extra_args) {
+        bail!("Invalid extra arguments for ebook-convert: {}", e);
+    }
+    command.args(&options.extra_args);
+    let output = command
         .output()
         .instrument(info_span!(
-            "Converting file from {} to {}",
-            in_path,
-            out_path
+            "Converting file",
+            in_path = %in_path.display(),
+            out_path = %out_path.display()
         ))
         .await
         .with_context(|| "Failed to spawn ebook-convert. Perhaps calibre is not installed?")?;
@@ -54,8 +223,98 @@ pub async fn generate_epub(
     if !output.status.success() {
         bail!("Calibre conversion failed with status {:?}", output.status);
     }
+    // ebook-convert can't set arbitrary identifiers, so they are written afterwards.
+    let output = Command::new("ebook-meta")
+        .arg(&out_path)
+        .arg("--identifier")
+        .arg(&metadata.identifier)
+        .output()
+        .await
+        .with_context(|| "Failed to spawn ebook-meta. Perhaps calibre is not installed?")?;
+    if !output.status.success() {
+        bail!(
+            "Setting epub identifier failed with status {:?}",
+            output.status
+        );
+    }
     let bytes = fs::read(&out_path)?;
-    fs::remove_file(&in_path)?;
-    fs::remove_file(&out_path)?;
+    dir.close()?;
     Ok(bytes)
 }
+
+/// The conversion directories a sweep removed, and those it left because they are recent enough
+/// to belong to a conversion still running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TempDirSweep {
+    pub removed: usize,
+    pub kept: usize,
+}
+
+/// Removes conversion directories left behind by a crashed or killed process.
+#[instrument(level = "info")]
+pub fn sweep_orphaned_temp_dirs(max_age: Duration) -> Result<TempDirSweep> {
+    let mut sweep = TempDirSweep::default();
+    for entry in fs::read_dir(env::temp_dir())? {
+        let entry = entry?;
+        let is_conversion_dir = entry.file_type()?.is_dir()
+            && entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(TEMP_DIR_PREFIX);
+        if !is_conversion_dir {
+            continue;
+        }
+        let age = entry
+            .metadata()?
+            .modified()?
+            .elapsed()
+            .unwrap_or(Duration::ZERO);
+        if age > max_age {
+            info!("Removing orphaned conversion directory {:?}", entry.path());
+            fs::remove_dir_all(entry.path())?;
+            sweep.removed += 1;
+        } else {
+            sweep.kept += 1;
+        }
+    }
+    Ok(sweep)
+}
+
+/// A standalone OPF in the form calibre keeps beside each book in its library. Calibre reads an
+/// OPF sharing a name with the epub when adding books, keeping the series and tags.
+pub fn metadata_opf(metadata: &EpubMetadata<'_>, tags: &[String], language: &str) -> String {
+    let mut optional_metadata = String::new();
+    if let Some(published_at) = metadata.published_at {
+        optional_metadata.push_str(&format!(
+            "    <dc:date>{}</dc:date>\n",
+            published_at.format("%Y-%m-%dT%H:%M:%SZ")
+        ));
+    }
+    for tag in tags {
+        optional_metadata.push_str(&format!(
+            "    <dc:subject>{}</dc:subject>\n",
+            escape_xml(tag)
+        ));
+    }
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="id">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:identifier id="id" opf:scheme="cereal">{identifier}</dc:identifier>
+    <dc:title>{title}</dc:title>
+    <dc:creator opf:role="aut">{author}</dc:creator>
+    <dc:language>{language}</dc:language>
+{optional_metadata}    <meta name="calibre:series" content="{series}"/>
+    <meta name="calibre:series_index" content="{series_index}"/>
+    <meta name="calibre:title_sort" content="{title}"/>
+  </metadata>
+</package>
+"#,
+        identifier = escape_xml(&metadata.identifier),
+        title = escape_xml(metadata.title),
+        author = escape_xml(metadata.author),
+        language = escape_xml(language),
+        series = escape_xml(metadata.series),
+        series_index = metadata.series_index,
+    )
+}
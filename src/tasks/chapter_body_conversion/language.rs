@@ -0,0 +1,153 @@
+use scraper::Html;
+
+/// How many words of a chapter are enough to tell its language.
+const SAMPLE_WORDS: usize = 2000;
+
+/// The commonest short words of each language written in the latin script, which make up a
+/// good part of any prose in it.
+const STOPWORDS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "the", "and", "of", "to", "is", "was", "that", "it", "he", "she", "you", "with", "his",
+            "her", "for", "not",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "la", "los", "las", "que", "de", "y", "en", "un", "una", "por", "con", "no",
+            "se", "del", "es",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "et", "de", "des", "un", "une", "est", "que", "qui", "dans", "pas",
+            "pour", "il", "elle", "je", "ne",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "das", "und", "ist", "nicht", "ich", "sie", "er", "es", "ein", "eine",
+            "zu", "mit", "den", "auf",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "la", "che", "di", "e", "non", "un", "una", "per", "è", "sono", "con", "del",
+            "della", "gli", "si",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "o", "a", "os", "as", "que", "de", "e", "não", "um", "uma", "com", "para", "do", "da",
+            "em", "é",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "de", "het", "een", "en", "van", "is", "niet", "dat", "ik", "je", "op", "te", "zijn",
+            "met", "voor", "hij",
+        ],
+    ),
+    (
+        "pl",
+        &[
+            "i", "w", "nie", "się", "na", "to", "że", "z", "jest", "do", "jak", "ale", "co", "tak",
+            "go", "mnie",
+        ],
+    ),
+    (
+        "id",
+        &[
+            "yang", "dan", "di", "itu", "dengan", "tidak", "ini", "ke", "untuk", "dari", "saya",
+            "aku", "kamu", "akan", "ada", "dia",
+        ],
+    ),
+    (
+        "sv",
+        &[
+            "och", "att", "det", "som", "en", "är", "på", "jag", "inte", "med", "han", "hon",
+            "för", "av", "den", "var",
+        ],
+    ),
+];
+
+/// The language written in a script other than latin, if the script is used by only one.
+fn script_language(c: char) -> Option<&'static str> {
+    match c {
+        '\u{3040}'..='\u{30ff}' => Some("ja"),
+        '\u{ac00}'..='\u{d7af}' | '\u{1100}'..='\u{11ff}' => Some("ko"),
+        '\u{4e00}'..='\u{9fff}' => Some("zh"),
+        'і' | 'ї' | 'є' | 'ґ' => Some("uk"),
+        '\u{0400}'..='\u{04ff}' => Some("ru"),
+        '\u{0370}'..='\u{03ff}' => Some("el"),
+        '\u{0600}'..='\u{06ff}' => Some("ar"),
+        '\u{0590}'..='\u{05ff}' => Some("he"),
+        '\u{0e00}'..='\u{0e7f}' => Some("th"),
+        _ => None,
+    }
+}
+
+/// Guesses the language of chapter html from its text, as a BCP 47 code. Text in a script used
+/// by one language is decided by its script, and latin text by its commonest words. Returns
+/// None for text too short or mixed to tell.
+pub fn detect_language(html: &[u8]) -> Option<&'static str> {
+    let fragment = Html::parse_fragment(&String::from_utf8_lossy(html));
+    let text: String = fragment.root_element().text().collect();
+    let letters: Vec<char> = text.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.is_empty() {
+        return None;
+    }
+
+    let mut scripts: Vec<(&str, usize)> = Vec::new();
+    for language in letters.iter().filter_map(|c| script_language(*c)) {
+        match scripts.iter_mut().find(|(x, _)| *x == language) {
+            Some((_, count)) => *count += 1,
+            None => scripts.push((language, 1)),
+        }
+    }
+    // Japanese mixes kanji with kana, and Ukrainian shares most of its letters with Russian.
+    if let Some(count) = scripts.iter().find(|(x, _)| *x == "ja").map(|(_, x)| *x) {
+        if count * 10 >= letters.len() {
+            return Some("ja");
+        }
+    }
+    let non_latin: usize = scripts.iter().map(|(_, x)| x).sum();
+    if non_latin * 2 > letters.len() {
+        if scripts.iter().any(|(x, _)| *x == "uk") {
+            return Some("uk");
+        }
+        return scripts.into_iter().max_by_key(|(_, x)| *x).map(|(x, _)| x);
+    }
+
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|x| !x.is_empty())
+        .take(SAMPLE_WORDS)
+        .map(|x| x.to_lowercase())
+        .collect();
+    let mut scores: Vec<(&str, usize)> = STOPWORDS
+        .iter()
+        .map(|(language, stopwords)| {
+            let hits = words
+                .iter()
+                .filter(|x| stopwords.contains(&x.as_str()))
+                .count();
+            (*language, hits)
+        })
+        .collect();
+    scores.sort_by_key(|(_, hits)| std::cmp::Reverse(*hits));
+    let (language, best) = scores[0];
+    let runner_up = scores[1].1;
+    // Short or mixed text is left to the book's default rather than guessed at.
+    if best < 3 || best * 20 < words.len() || best == runner_up {
+        return None;
+    }
+    Some(language)
+}
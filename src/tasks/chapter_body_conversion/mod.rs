@@ -1,53 +1,178 @@
-use std::time::Duration;
+use std::{
+    env,
+    time::{Duration, Instant},
+};
 
-use anyhow::bail;
+use anyhow::{bail, Context};
+use chrono::Utc;
 use itertools::Itertools;
+use serde::Serialize;
 use sqlx::{Pool, Sqlite};
-use tokio::time::MissedTickBehavior;
-use tracing::{info, instrument};
+use tokio::sync::OnceCell;
+use tracing::{error, info, instrument, warn};
 
 use crate::{
-    error,
-    models::{Book, BookClient, Chapter, ChapterClient},
+    models::{
+        AnthologySubscription, Book, BookClient, Chapter, ChapterClient, ChapterState,
+        ConversionOptions, VolumeClient, VolumePosition,
+    },
+    telemetry::{continue_trace, record_loop_duration, record_queue_depth},
 };
 
 mod calibre;
+mod language;
+mod native;
+mod transforms;
+
+use calibre::EpubMetadata;
+pub use calibre::{sweep_orphaned_temp_dirs, validate_extra_args, TempDirSweep};
+pub use language::detect_language;
+pub use native::{escape_xml, sanitize_html};
+pub use transforms::{apply_transforms, validate_transforms};
+
+use super::{
+    record_failure,
+    schedule::{schedule_chapters, LoopTimer, TaskLoop},
+    with_chapter_claim, with_panics_recorded,
+};
+
+/// Stamped on every converted epub. Bump it whenever sanitization, styling or the conversion
+/// backends change the output, then reconvert epubs older than it.
+pub const CONVERSION_VERSION: i64 = 3;
+
+/// Conversions never take an hour, so older conversion directories belong to a dead process.
+pub const ORPHANED_TEMP_DIR_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The means by which chapter html is converted into epubs, chosen once at startup.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "backend", rename_all = "camelCase")]
+pub enum EpubBackend {
+    Calibre {
+        version: String,
+    },
+    /// Calibre is unavailable and the native fallback is in use.
+    Native {
+        reason: String,
+    },
+    /// Calibre is unavailable and the native fallback is disabled.
+    Unavailable {
+        reason: String,
+    },
+}
+
+static EPUB_BACKEND: OnceCell<EpubBackend> = OnceCell::const_new();
+
+pub async fn epub_backend() -> &'static EpubBackend {
+    EPUB_BACKEND.get_or_init(detect_epub_backend).await
+}
+
+async fn detect_epub_backend() -> EpubBackend {
+    // Set CEREAL_EPUB_BACKEND=native to convert natively even when calibre is installed, for
+    // output which doesn't depend on calibre's version.
+    if env::var("CEREAL_EPUB_BACKEND").is_ok_and(|x| x == "native") {
+        info!("Using native epub conversion as configured");
+        return EpubBackend::Native {
+            reason: String::from("Configured by CEREAL_EPUB_BACKEND"),
+        };
+    }
+    match calibre::version().await {
+        Ok(version) => {
+            info!("Using calibre for epub conversion: {}", version);
+            EpubBackend::Calibre { version }
+        }
+        Err(e) => {
+            let reason = format!("{:#}", e);
+            // Set CEREAL_NATIVE_EPUB_FALLBACK=false to fail conversions instead.
+            let fallback = env::var("CEREAL_NATIVE_EPUB_FALLBACK")
+                .map(|x| x != "false")
+                .unwrap_or(true);
+            if fallback {
+                warn!(
+                    "Calibre is unavailable, using native epub conversion: {}",
+                    reason
+                );
+                EpubBackend::Native { reason }
+            } else {
+                error!(
+                    "Calibre is unavailable, epub conversion will fail: {}",
+                    reason
+                );
+                EpubBackend::Unavailable { reason }
+            }
+        }
+    }
+}
+
+/// Books without a language of their own are labelled with their text's, so that readers
+/// hyphenate it and look words up in the right dictionary.
+fn with_detected_language(chapter_body: &[u8], options: &ConversionOptions) -> ConversionOptions {
+    let mut options = options.clone();
+    if options.language.is_none() {
+        options.language = detect_language(chapter_body).map(String::from);
+    }
+    options
+}
+
+async fn generate_epub(
+    chapter_body: &[u8],
+    metadata: &EpubMetadata<'_>,
+    options: &ConversionOptions,
+) -> anyhow::Result<Vec<u8>> {
+    let options = &with_detected_language(chapter_body, options);
+    match epub_backend().await {
+        EpubBackend::Calibre { .. } => {
+            calibre::generate_epub("html", chapter_body, metadata, options).await
+        }
+        EpubBackend::Native { .. } => native::generate_epub(chapter_body, metadata, options),
+        EpubBackend::Unavailable { reason } => {
+            bail!("No epub conversion backend is available: {}", reason)
+        }
+    }
+}
 
 pub async fn check_for_epubless_chap_loop(pool: Pool<Sqlite>) {
-    // 10 sec check interval for all chapters.
-    let mut interval = tokio::time::interval(Duration::from_secs(10));
-    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
     let client = ChapterClient::new(&pool);
 
+    let mut timer = LoopTimer::new(TaskLoop::Conversion);
     loop {
-        // First tick completes immediately.
-        interval.tick().await;
+        let started = Instant::now();
+        if let Err(e) = sweep_orphaned_temp_dirs(ORPHANED_TEMP_DIR_AGE) {
+            error!("Error sweeping orphaned conversion directories {}", e);
+        }
         let chapters = client.list_chapters_ready_for_epub_conversion().await;
         match chapters {
             Ok(chapters) => {
-                for chapter in chapters {
-                    generate_chapter_epub(chapter, &pool).await
+                record_queue_depth(TaskLoop::Conversion.name(), chapters.len());
+                let per_book = TaskLoop::Conversion.per_book_limit();
+                for chapter in schedule_chapters(chapters, per_book) {
+                    let chapter_id = chapter.id;
+                    let work = with_panics_recorded(
+                        &client,
+                        &chapter_id,
+                        ChapterState::Hydrated,
+                        generate_chapter_epub(chapter, &pool),
+                    );
+                    with_chapter_claim(&client, &chapter_id, work).await
                 }
             }
             Err(e) => error!("Error fetching chapters with empty epub fields {}", e),
         }
+        record_loop_duration(TaskLoop::Conversion.name(), started.elapsed());
+        timer.wait_for_next_run(&pool).await;
     }
 }
 
-#[instrument(skip(pool))]
+#[instrument(skip(pool), fields(book.id = %chapter.book_id, chapter.id = %chapter.id))]
 pub async fn generate_chapter_epub(chapter: Chapter, pool: &Pool<Sqlite>) {
+    continue_trace(chapter.trace_context.as_deref());
     let client = ChapterClient::new(pool);
 
     let book_id = chapter.book_id;
     let chapter_id = chapter.id;
-    let mut chapter_body = format!("<h1>{}</h1>", chapter.title).into_bytes();
-    match chapter.html {
-        Some(mut body) => chapter_body.append(&mut body),
-        None => {
-            error!("Chapter id {} had no html body", &chapter_id);
-            return;
-        }
-    };
+    if chapter.html.is_none() {
+        error!("Chapter id {} had no html body", &chapter_id);
+        return;
+    }
 
     let book = match BookClient::new(pool).get_book(&book_id).await {
         Ok(Some(book)) => book,
@@ -67,16 +192,21 @@ pub async fn generate_chapter_epub(chapter: Chapter, pool: &Pool<Sqlite>) {
         }
     };
 
-    let cover_title = &format!("{}: {}", &book.title, &chapter.title);
+    let position = match VolumeClient::new(pool)
+        .chapter_position(&book_id, &chapter_id)
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => {
+            error!(
+                "A database error occurred looking up the volume of chapter {}: {}",
+                &chapter_id, e
+            );
+            return;
+        }
+    };
 
-    let epub_bytes = calibre::generate_epub(
-        ".html",
-        chapter_body.as_slice(),
-        cover_title,
-        &book.title,
-        &book.author,
-    )
-    .await;
+    let epub_bytes = generate_single_chapter_epub(&book, &chapter, position.as_ref()).await;
 
     let epub_bytes = match epub_bytes {
         Ok(x) => x,
@@ -85,6 +215,7 @@ pub async fn generate_chapter_epub(chapter: Chapter, pool: &Pool<Sqlite>) {
                 "A database error occurred converting body to epub for chapter {}: {}",
                 &chapter_id, e
             );
+            record_failure(&client, &chapter_id, ChapterState::Hydrated, &e).await;
             return;
         }
     };
@@ -92,7 +223,7 @@ pub async fn generate_chapter_epub(chapter: Chapter, pool: &Pool<Sqlite>) {
     info!("Generated epub body with length {:?}", epub_bytes.len());
 
     match client
-        .update_chapter(&chapter.id, None, None, Some(&epub_bytes), None)
+        .save_epub(&chapter.id, &epub_bytes, CONVERSION_VERSION)
         .await
     {
         Ok(x) => {
@@ -104,12 +235,49 @@ pub async fn generate_chapter_epub(chapter: Chapter, pool: &Pool<Sqlite>) {
     };
 }
 
-#[instrument]
-pub async fn generate_multichapter_epub(
-    cover_title: &str,
-    chapters: &[Chapter],
+/// Generates the chapter's epub, headed by its title and shelved in the series of its volume
+/// at `position`, or otherwise its book.
+pub async fn generate_single_chapter_epub(
     book: &Book,
+    chapter: &Chapter,
+    position: Option<&VolumePosition>,
 ) -> anyhow::Result<Vec<u8>> {
+    let html = chapter
+        .html
+        .as_ref()
+        .with_context(|| format!("Chapter {} has no html body", chapter.id))?;
+    let mut chapter_body = format!("<h1>{}</h1>", chapter.title).into_bytes();
+    chapter_body.extend(apply_transforms(html, &book.conversion_options.transforms)?);
+
+    let cover_title = &format!("{}: {}", &book.title, &chapter.title);
+    let (series, series_index) = series(book, chapter.order_index, position);
+    let metadata = EpubMetadata {
+        title: cover_title,
+        series: &series,
+        series_index,
+        author: &book.author,
+        identifier: format!("cereal:{}", chapter.id),
+        published_at: chapter.published_at,
+        front_matter: false,
+    };
+
+    generate_epub(chapter_body.as_slice(), &metadata, &book.conversion_options).await
+}
+
+/// The series a chapter's epub is shelved under and its place in it: the chapter's volume if
+/// it belongs to one, otherwise the book.
+fn series(book: &Book, order_index: i64, position: Option<&VolumePosition>) -> (String, i64) {
+    match position {
+        Some(x) => (format!("{}: {}", book.title, x.volume.name()), x.index),
+        None => (book.title.clone(), order_index),
+    }
+}
+
+/// Joins the chapters' bodies in reading order, each under a heading with its title.
+fn combine_chapters<'a>(
+    chapters: &'a [Chapter],
+    book: &Book,
+) -> anyhow::Result<(Vec<&'a Chapter>, Vec<u8>)> {
     if chapters.is_empty() {
         bail!("Provided chapters slice is empty.");
     }
@@ -122,33 +290,48 @@ pub async fn generate_multichapter_epub(
         bail!("Not every chapter has an html body.");
     }
 
-    // Ensure chapters are in publication order.
+    // Ensure chapters are in reading order.
     let chapters = chapters
         .iter()
-        .sorted_by(|a, b| {
-            let a = a.published_at.unwrap_or(a.created_at);
-            let b = b.published_at.unwrap_or(b.created_at);
-            a.cmp(&b)
-        })
+        .sorted_by_key(|x| x.order_index)
         .collect_vec();
 
-    let html_body: Vec<u8> = chapters
-        .iter()
-        .flat_map(|x| {
-            let mut bytes = format!("<h1>{}</h1>", x.title).into_bytes();
-            bytes.append(&mut x.html.clone().unwrap());
-            bytes
-        })
-        .collect();
-
-    let epub_bytes = calibre::generate_epub(
-        ".html",
-        html_body.as_slice(),
-        cover_title,
-        &book.title,
-        &book.author,
-    )
-    .await;
+    let mut html_body = Vec::new();
+    for chapter in &chapters {
+        html_body.extend(format!("<h1>{}</h1>", chapter.title).into_bytes());
+        // Every chapter was checked to have a body above.
+        let html = chapter.html.as_deref().unwrap();
+        html_body.extend(apply_transforms(html, &book.conversion_options.transforms)?);
+    }
+    Ok((chapters, html_body))
+}
+
+/// Generates a single epub of the chapters, shelved in the series of the first chapter at
+/// `first_position`.
+#[instrument]
+pub async fn generate_multichapter_epub(
+    cover_title: &str,
+    chapters: &[Chapter],
+    book: &Book,
+    first_position: Option<&VolumePosition>,
+) -> anyhow::Result<Vec<u8>> {
+    let (chapters, html_body) = combine_chapters(chapters, book)?;
+
+    // Chapters were checked to be non-empty above.
+    let first_chapter = chapters.first().unwrap();
+    let last_chapter = chapters.last().unwrap();
+    let (series, series_index) = series(book, first_chapter.order_index, first_position);
+    let metadata = EpubMetadata {
+        title: cover_title,
+        series: &series,
+        series_index,
+        author: &book.author,
+        identifier: format!("cereal:{}:{}", first_chapter.id, last_chapter.id),
+        published_at: last_chapter.published_at,
+        front_matter: false,
+    };
+
+    let epub_bytes = generate_epub(html_body.as_slice(), &metadata, &book.conversion_options).await;
 
     let epub_bytes = match epub_bytes {
         Ok(x) => x,
@@ -164,3 +347,106 @@ pub async fn generate_multichapter_epub(
     info!("Generated epub body with length {:?}", epub_bytes.len());
     Ok(epub_bytes)
 }
+
+/// The calibre sidecar for a chapter's epub, carrying the same series metadata as the epub
+/// itself along with the book's tags.
+pub fn chapter_metadata_opf(
+    book: &Book,
+    chapter: &Chapter,
+    position: Option<&VolumePosition>,
+    tags: &[String],
+) -> String {
+    let cover_title = format!("{}: {}", &book.title, &chapter.title);
+    let (series, series_index) = series(book, chapter.order_index, position);
+    let metadata = EpubMetadata {
+        title: &cover_title,
+        series: &series,
+        series_index,
+        author: &book.author,
+        identifier: format!("cereal:{}", chapter.id),
+        published_at: chapter.published_at,
+        front_matter: false,
+    };
+    let options = with_detected_language(
+        chapter.html.as_deref().unwrap_or_default(),
+        &book.conversion_options,
+    );
+    let language = options.language.as_deref().unwrap_or("en");
+    calibre::metadata_opf(&metadata, tags, language)
+}
+
+/// Generates the digest of an anthology subscription, with a section for each book headed by
+/// its title and a table of contents of the books. Chapters are given in reading order.
+#[instrument(skip(sections), fields(anthology.id = %anthology.id, books = sections.len()))]
+pub async fn generate_digest_epub(
+    anthology: &AnthologySubscription,
+    title: &str,
+    sections: &[(&Book, &[Chapter])],
+) -> anyhow::Result<Vec<u8>> {
+    if sections.iter().all(|(_, chapters)| chapters.is_empty()) {
+        bail!("Provided sections have no chapters.");
+    }
+    let mut html_body = Vec::new();
+    for (book, chapters) in sections {
+        html_body.extend(format!("<h1>{}</h1>", book.title).into_bytes());
+        for chapter in chapters.iter().sorted_by_key(|x| x.order_index) {
+            let html = chapter
+                .html
+                .as_ref()
+                .with_context(|| format!("Chapter {} has no html body", chapter.id))?;
+            html_body.extend(format!("<h2>{}</h2>", chapter.title).into_bytes());
+            html_body.extend(apply_transforms(html, &book.conversion_options.transforms)?);
+        }
+    }
+
+    let author = sections
+        .iter()
+        .map(|(x, _)| x.author.as_str())
+        .unique()
+        .join(", ");
+    let published_at = sections
+        .iter()
+        .flat_map(|(_, chapters)| chapters.iter())
+        .filter_map(|x| x.published_at)
+        .max();
+    let metadata = EpubMetadata {
+        title,
+        series: &anthology.name,
+        series_index: 1,
+        author: &author,
+        identifier: format!("cereal:{}:{}", anthology.id, Utc::now().timestamp()),
+        published_at,
+        front_matter: true,
+    };
+    generate_epub(
+        html_body.as_slice(),
+        &metadata,
+        &ConversionOptions::default(),
+    )
+    .await
+    .with_context(|| format!("Failed to convert digest for anthology {}", anthology.id))
+}
+
+/// Generates a single epub of the whole book, with a cover and table of contents.
+#[instrument(skip(chapters), fields(book.id = %book.id, chapters = chapters.len()))]
+pub async fn generate_omnibus_epub(book: &Book, chapters: &[Chapter]) -> anyhow::Result<Vec<u8>> {
+    let (chapters, html_body) = combine_chapters(chapters, book)?;
+
+    // Chapters were checked to be non-empty above.
+    let last_chapter = chapters.last().unwrap();
+    let metadata = EpubMetadata {
+        title: &book.title,
+        series: &book.title,
+        series_index: 1,
+        author: &book.author,
+        identifier: format!("cereal:{}", book.id),
+        published_at: last_chapter.published_at,
+        front_matter: true,
+    };
+
+    let epub_bytes = generate_epub(html_body.as_slice(), &metadata, &book.conversion_options)
+        .await
+        .with_context(|| format!("Failed to convert omnibus for book {}", book.id))?;
+    info!("Generated omnibus epub with length {:?}", epub_bytes.len());
+    Ok(epub_bytes)
+}
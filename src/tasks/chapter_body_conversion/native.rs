@@ -0,0 +1,348 @@
+use std::io::{Cursor, Write};
+
+use anyhow::Result;
+use chrono::Utc;
+use ego_tree::NodeRef;
+use scraper::{Html, Node, Selector};
+use tracing::instrument;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::models::ConversionOptions;
+
+use super::calibre::EpubMetadata;
+
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+const DROPPED_ELEMENTS: &[&str] = &[
+    "script", "style", "iframe", "form", "input", "button", "noscript", "object",
+];
+
+struct Section {
+    title: String,
+    xhtml_body: String,
+}
+
+/// Builds an epub without calibre. The html is split into a section per `<h1>`, which is how
+/// chapter bodies are assembled for conversion.
+#[instrument(
+    name = "Converting to epub natively",
+    err,
+    level = "info",
+    skip(chapter_body)
+)]
+pub fn generate_epub(
+    chapter_body: &[u8],
+    metadata: &EpubMetadata<'_>,
+    options: &ConversionOptions,
+) -> Result<Vec<u8>> {
+    let sections = split_sections(&String::from_utf8_lossy(chapter_body), metadata.title);
+    let language = options.language.as_deref().unwrap_or("en");
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    // The mimetype must be the first, uncompressed entry of the archive.
+    zip.start_file(
+        "mimetype",
+        FileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    zip.write_all(b"application/epub+zip")?;
+
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(CONTAINER_XML.as_bytes())?;
+
+    zip.start_file("OEBPS/content.opf", deflated)?;
+    zip.write_all(content_opf(&sections, metadata, options, language).as_bytes())?;
+
+    zip.start_file("OEBPS/toc.ncx", deflated)?;
+    zip.write_all(toc_ncx(&sections, metadata).as_bytes())?;
+
+    zip.start_file("OEBPS/nav.xhtml", deflated)?;
+    zip.write_all(nav_xhtml(&sections, language).as_bytes())?;
+
+    if metadata.front_matter {
+        zip.start_file("OEBPS/cover.xhtml", deflated)?;
+        zip.write_all(cover_xhtml(metadata, language).as_bytes())?;
+    }
+
+    zip.start_file("OEBPS/style.css", deflated)?;
+    zip.write_all(options.extra_css.as_deref().unwrap_or_default().as_bytes())?;
+
+    for (index, section) in sections.iter().enumerate() {
+        zip.start_file(format!("OEBPS/section-{}.xhtml", index), deflated)?;
+        zip.write_all(section_xhtml(section, language).as_bytes())?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+fn split_sections(html: &str, default_title: &str) -> Vec<Section> {
+    let doc = Html::parse_document(html);
+    let body_selector = Selector::parse("body").unwrap();
+    let mut sections: Vec<Section> = Vec::new();
+    if let Some(body) = doc.select(&body_selector).next() {
+        for child in body.children() {
+            let heading = match child.value() {
+                Node::Element(element) if element.name() == "h1" => Some(
+                    child
+                        .descendants()
+                        .filter_map(|x| x.value().as_text().map(|t| t.to_string()))
+                        .collect::<String>(),
+                ),
+                _ => None,
+            };
+            if heading.is_some() || sections.is_empty() {
+                sections.push(Section {
+                    title: heading.unwrap_or_else(|| default_title.to_owned()),
+                    xhtml_body: String::new(),
+                });
+            }
+            // A section was pushed above if there were none.
+            let section = sections.last_mut().unwrap();
+            write_xhtml(child, &mut section.xhtml_body);
+        }
+    }
+    if sections.is_empty() {
+        sections.push(Section {
+            title: default_title.to_owned(),
+            xhtml_body: String::new(),
+        });
+    }
+    sections
+}
+
+/// Serializes html as well formed xhtml, which epub readers require.
+fn write_xhtml(node: NodeRef<Node>, out: &mut String) {
+    match node.value() {
+        Node::Text(text) => out.push_str(&escape_xml(text)),
+        Node::Element(element) => {
+            let name = element.name();
+            if DROPPED_ELEMENTS.contains(&name) {
+                return;
+            }
+            out.push('<');
+            out.push_str(name);
+            for (attr, value) in element.attrs() {
+                // Footnotes are marked up with epub:type, declared on every section.
+                let valid_name = attr == "epub:type"
+                    || attr
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                if !valid_name || attr.starts_with("on") || attr == "xmlns" {
+                    continue;
+                }
+                out.push_str(&format!(" {}=\"{}\"", attr, escape_xml(value)));
+            }
+            if VOID_ELEMENTS.contains(&name) {
+                out.push_str("/>");
+                return;
+            }
+            out.push('>');
+            for child in node.children() {
+                write_xhtml(child, out);
+            }
+            out.push_str(&format!("</{}>", name));
+        }
+        _ => {}
+    }
+}
+
+/// Strips scripts, styles, forms and event handlers from the html as is done for epubs,
+/// leaving markup which is safe to show in an email.
+pub fn sanitize_html(html: &str) -> String {
+    let fragment = Html::parse_fragment(html);
+    let mut out = String::new();
+    for child in fragment.root_element().children() {
+        write_xhtml(child, &mut out);
+    }
+    out
+}
+
+/// Escapes text for use in xml, within elements and double quoted attributes.
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+fn content_opf(
+    sections: &[Section],
+    metadata: &EpubMetadata<'_>,
+    options: &ConversionOptions,
+    language: &str,
+) -> String {
+    let mut optional_metadata = String::new();
+    if let Some(publisher) = &options.publisher {
+        optional_metadata.push_str(&format!(
+            "    <dc:publisher>{}</dc:publisher>\n",
+            escape_xml(publisher)
+        ));
+    }
+    if let Some(published_at) = metadata.published_at {
+        optional_metadata.push_str(&format!(
+            "    <dc:date>{}</dc:date>\n",
+            published_at.format("%Y-%m-%dT%H:%M:%SZ")
+        ));
+    }
+    let manifest: String = (0..sections.len())
+        .map(|i| {
+            format!(
+                "    <item id=\"section-{i}\" href=\"section-{i}.xhtml\" media-type=\"application/xhtml+xml\"/>\n"
+            )
+        })
+        .collect();
+    let mut spine: String = (0..sections.len())
+        .map(|i| format!("    <itemref idref=\"section-{i}\"/>\n"))
+        .collect();
+    let mut front_matter_manifest = String::new();
+    if metadata.front_matter {
+        front_matter_manifest.push_str(
+            "    <item id=\"cover\" href=\"cover.xhtml\" media-type=\"application/xhtml+xml\"/>\n",
+        );
+        // The navigation document doubles as the inline table of contents.
+        spine = format!(
+            "    <itemref idref=\"cover\"/>\n    <itemref idref=\"nav\"/>\n{}",
+            spine
+        );
+    }
+    format!(
+        r##"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:identifier id="id">{identifier}</dc:identifier>
+    <dc:title>{title}</dc:title>
+    <dc:creator>{author}</dc:creator>
+    <dc:language>{language}</dc:language>
+{optional_metadata}    <meta property="dcterms:modified">{modified}</meta>
+    <meta property="belongs-to-collection" id="series">{series}</meta>
+    <meta refines="#series" property="collection-type">series</meta>
+    <meta refines="#series" property="group-position">{series_index}</meta>
+    <meta name="calibre:series" content="{series}"/>
+    <meta name="calibre:series_index" content="{series_index}"/>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    <item id="css" href="style.css" media-type="text/css"/>
+{front_matter_manifest}{manifest}  </manifest>
+  <spine toc="ncx">
+{spine}  </spine>
+</package>
+"##,
+        identifier = escape_xml(&metadata.identifier),
+        title = escape_xml(metadata.title),
+        author = escape_xml(metadata.author),
+        language = escape_xml(language),
+        modified = Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+        series = escape_xml(metadata.series),
+        series_index = metadata.series_index,
+    )
+}
+
+fn toc_ncx(sections: &[Section], metadata: &EpubMetadata<'_>) -> String {
+    let nav_points: String = sections
+        .iter()
+        .enumerate()
+        .map(|(i, section)| {
+            format!(
+                "    <navPoint id=\"nav-{i}\" playOrder=\"{order}\">\n      <navLabel><text>{title}</text></navLabel>\n      <content src=\"section-{i}.xhtml\"/>\n    </navPoint>\n",
+                order = i + 1,
+                title = escape_xml(&section.title),
+            )
+        })
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <head>
+    <meta name="dtb:uid" content="{identifier}"/>
+  </head>
+  <docTitle><text>{title}</text></docTitle>
+  <navMap>
+{nav_points}  </navMap>
+</ncx>
+"#,
+        identifier = escape_xml(&metadata.identifier),
+        title = escape_xml(metadata.title),
+    )
+}
+
+fn nav_xhtml(sections: &[Section], language: &str) -> String {
+    let entries: String = sections
+        .iter()
+        .enumerate()
+        .map(|(i, section)| {
+            format!(
+                "      <li><a href=\"section-{i}.xhtml\">{}</a></li>\n",
+                escape_xml(&section.title)
+            )
+        })
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" lang="{language}" xml:lang="{language}">
+<head><title>Contents</title></head>
+<body>
+  <nav epub:type="toc">
+    <ol>
+{entries}    </ol>
+  </nav>
+</body>
+</html>
+"#,
+        language = escape_xml(language),
+    )
+}
+
+fn cover_xhtml(metadata: &EpubMetadata<'_>, language: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" lang="{language}" xml:lang="{language}">
+<head>
+<title>{title}</title>
+<link rel="stylesheet" type="text/css" href="style.css"/>
+</head>
+<body epub:type="cover" style="text-align: center">
+<h1 style="margin-top: 30%">{title}</h1>
+<p>{author}</p>
+</body>
+</html>
+"#,
+        language = escape_xml(language),
+        title = escape_xml(metadata.title),
+        author = escape_xml(metadata.author),
+    )
+}
+
+fn section_xhtml(section: &Section, language: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" lang="{language}" xml:lang="{language}">
+<head>
+<title>{title}</title>
+<link rel="stylesheet" type="text/css" href="style.css"/>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        language = escape_xml(language),
+        title = escape_xml(&section.title),
+        body = section.xhtml_body,
+    )
+}
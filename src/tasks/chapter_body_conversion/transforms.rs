@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+
+use crate::{
+    models::{HtmlTransform, TypographyOptions},
+    util::content_digest,
+};
+
+use super::native::escape_xml;
+
+/// Links to an anchor within the chapter, which is how footnotes are usually referenced.
+const FOOTNOTE_REFERENCES: &str = "a[href^=\"#\"]";
+
+fn parse_selector(selector: &str) -> Result<Selector> {
+    Selector::parse(selector).map_err(|e| anyhow!("Invalid selector {:?}: {:?}", selector, e))
+}
+
+fn parse_regex(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).with_context(|| format!("Invalid pattern {:?}", pattern))
+}
+
+/// Checks that every pattern and selector of the transforms parses, so that a book's chapters
+/// don't fail to convert over a typo.
+pub fn validate_transforms(transforms: &[HtmlTransform]) -> Result<(), String> {
+    for transform in transforms {
+        let parsed = match transform {
+            HtmlTransform::Replace { pattern, .. } => parse_regex(pattern).map(|_| ()),
+            HtmlTransform::Strip { selector } => parse_selector(selector).map(|_| ()),
+            HtmlTransform::InlineFootnotes { references } => {
+                parse_selector(references.as_deref().unwrap_or(FOOTNOTE_REFERENCES)).map(|_| ())
+            }
+            HtmlTransform::Endnotes | HtmlTransform::Typography(_) => Ok(()),
+        };
+        parsed.map_err(|e| format!("{:#}", e))?;
+    }
+    Ok(())
+}
+
+/// Applies the transforms to the chapter html in order.
+pub fn apply_transforms(html: &[u8], transforms: &[HtmlTransform]) -> Result<Vec<u8>> {
+    if transforms.is_empty() {
+        return Ok(html.to_vec());
+    }
+    let mut body = String::from_utf8_lossy(html).into_owned();
+    for transform in transforms {
+        body = match transform {
+            HtmlTransform::Replace {
+                pattern,
+                replacement,
+            } => parse_regex(pattern)?
+                .replace_all(&body, replacement.as_str())
+                .into_owned(),
+            HtmlTransform::Strip { selector } => strip(&body, &parse_selector(selector)?),
+            HtmlTransform::InlineFootnotes { references } => inline_footnotes(
+                &body,
+                &parse_selector(references.as_deref().unwrap_or(FOOTNOTE_REFERENCES))?,
+            ),
+            HtmlTransform::Endnotes => endnotes(&body),
+            HtmlTransform::Typography(options) => typography(&body, options),
+        };
+    }
+    Ok(body.into_bytes())
+}
+
+// Element html is serialized the same way as its parent, so elements are changed by replacing
+// their html within the body, as the providers clean up chapter pages.
+
+fn strip(body: &str, selector: &Selector) -> String {
+    let fragment = Html::parse_fragment(body);
+    let mut stripped = fragment.root_element().inner_html();
+    for element in fragment.select(selector) {
+        stripped = stripped.replacen(&element.html(), "", 1);
+    }
+    stripped
+}
+
+fn inline_footnotes(body: &str, references: &Selector) -> String {
+    let fragment = Html::parse_fragment(body);
+    let id_selector = Selector::parse("[id]").unwrap();
+    let mut inlined = fragment.root_element().inner_html();
+    for reference in fragment.select(references) {
+        let id = match reference
+            .value()
+            .attr("href")
+            .and_then(|x| x.strip_prefix('#'))
+        {
+            Some(x) if !x.is_empty() => x,
+            _ => continue,
+        };
+        let note = match fragment
+            .select(&id_selector)
+            .find(|x| x.value().id() == Some(id))
+        {
+            Some(x) => x,
+            None => continue,
+        };
+        // Footnotes often end with a link back to their reference.
+        let text: String = note.text().collect();
+        let text = text.trim().trim_end_matches('↩').trim_end();
+        inlined = inlined.replacen(&note.html(), "", 1).replacen(
+            &reference.html(),
+            &format!(" <small>[{}]</small>", escape_xml(text)),
+            1,
+        );
+    }
+    inlined
+}
+
+/// Moves the chapter's footnotes to a list of endnotes at its end, each linked from its
+/// reference and back. Ids are prefixed with the chapter's digest, so they stay unique when
+/// chapters are combined into one epub.
+fn endnotes(body: &str) -> String {
+    let prefix = format!("note-{}", &content_digest(body.as_bytes())[..8]);
+    let mut notes = Vec::new();
+    let mut converted = linked_notes(body, &prefix, &mut notes);
+    // Chapters use one convention or the other, and converted references look numbered.
+    if notes.is_empty() {
+        converted = numbered_notes(body, &prefix, &mut notes);
+    }
+    if notes.is_empty() {
+        return body.to_owned();
+    }
+    let items: String = notes
+        .iter()
+        .enumerate()
+        .map(|(i, content)| {
+            format!(
+                "<li id=\"{prefix}-{n}\" epub:type=\"endnote\" role=\"doc-endnote\">{content} <a href=\"#{prefix}-ref-{n}\" epub:type=\"backlink\" role=\"doc-backlink\">↩</a></li>",
+                n = i + 1
+            )
+        })
+        .collect();
+    format!(
+        "{}<section epub:type=\"endnotes\" role=\"doc-endnotes\"><hr/><ol>{}</ol></section>",
+        remove_empty_lists(&converted),
+        items
+    )
+}
+
+fn noteref(prefix: &str, n: usize) -> String {
+    format!("<a href=\"#{prefix}-{n}\" id=\"{prefix}-ref-{n}\" epub:type=\"noteref\" role=\"doc-noteref\"><sup>{n}</sup></a>")
+}
+
+/// The footnote's content without the marker it starts with.
+fn note_content(html: &str) -> String {
+    let marker = Regex::new(r"^\s*(?:<sup>\s*\[?\d+\]?\s*</sup>|\[\d+\]|\d+[.):])\s*").unwrap();
+    marker.replace(html, "").trim().to_owned()
+}
+
+/// Finds footnotes referenced by links to an element in the chapter.
+fn linked_notes(body: &str, prefix: &str, notes: &mut Vec<String>) -> String {
+    let fragment = Html::parse_fragment(body);
+    let references = Selector::parse(FOOTNOTE_REFERENCES).unwrap();
+    let id_selector = Selector::parse("[id], a[name]").unwrap();
+    let mut converted = fragment.root_element().inner_html();
+    // Notes by their node, so a footnote referenced twice is listed once.
+    let mut numbers = HashMap::new();
+    for reference in fragment.select(&references) {
+        let id = match reference
+            .value()
+            .attr("href")
+            .and_then(|x| x.strip_prefix('#'))
+        {
+            Some(x) if !x.is_empty() => x,
+            _ => continue,
+        };
+        let target = match fragment
+            .select(&id_selector)
+            .find(|x| x.value().id() == Some(id) || x.value().attr("name") == Some(id))
+        {
+            Some(x) => x,
+            None => continue,
+        };
+        // Notes are often marked by an empty anchor at the start of their paragraph.
+        let note = match target.text().all(|x| x.trim().is_empty()) {
+            true => match target.parent().and_then(ElementRef::wrap) {
+                Some(x) => x,
+                None => continue,
+            },
+            false => target,
+        };
+        // Skip backlinks from notes to their references, and links to enclosing elements.
+        let within_note = reference
+            .ancestors()
+            .any(|x| x.id() == note.id() || numbers.contains_key(&x.id()));
+        if within_note || references.matches(&note) {
+            continue;
+        }
+        let n = match numbers.get(&note.id()) {
+            Some(n) => *n,
+            None => {
+                let mut content = note.inner_html();
+                for backlink in note.select(&references) {
+                    content = content.replacen(&backlink.html(), "", 1);
+                }
+                notes.push(note_content(&content));
+                converted = converted.replacen(&note.html(), "", 1);
+                numbers.insert(note.id(), notes.len());
+                notes.len()
+            }
+        };
+        converted = converted.replacen(&reference.html(), &noteref(prefix, n), 1);
+    }
+    converted
+}
+
+/// Finds footnotes marked with `[1]` or a superscript number, whose text is a later paragraph
+/// starting with the same marker.
+fn numbered_notes(body: &str, prefix: &str, notes: &mut Vec<String>) -> String {
+    let fragment = Html::parse_fragment(body);
+    let paragraphs = Selector::parse("p").unwrap();
+    let marker = Regex::new(r"^\s*(?:<sup>\s*\[?(\d+)\]?\s*</sup>|\[(\d+)\])").unwrap();
+    let mut converted = fragment.root_element().inner_html();
+    for paragraph in fragment.select(&paragraphs) {
+        let inner = paragraph.inner_html();
+        let number = match marker.captures(&inner) {
+            Some(x) => x.get(1).or_else(|| x.get(2)).unwrap().as_str().to_owned(),
+            None => continue,
+        };
+        let html = paragraph.html();
+        let position = match converted.find(&html) {
+            Some(x) => x,
+            None => continue,
+        };
+        let reference =
+            Regex::new(&format!(r"<sup>\s*\[?{0}\]?\s*</sup>|\[{0}\]", number)).unwrap();
+        let found = match reference.find(&converted[..position]) {
+            Some(x) => x.range(),
+            None => continue,
+        };
+        notes.push(note_content(&inner));
+        converted.replace_range(position..position + html.len(), "");
+        converted.replace_range(found, &noteref(prefix, notes.len()));
+    }
+    converted
+}
+
+/// Removes the lists left empty once their footnotes were moved.
+fn remove_empty_lists(body: &str) -> String {
+    let empty = Regex::new(r"<(?:ol|ul)(?:\s[^>]*)?>\s*</(?:ol|ul)>").unwrap();
+    empty.replace_all(body, "").into_owned()
+}
+
+/// Elements whose text is left as written.
+const VERBATIM_ELEMENTS: &[&str] = &["pre", "code", "kbd", "samp", "script", "style"];
+
+fn typography(body: &str, options: &TypographyOptions) -> String {
+    let mut body = body.to_owned();
+    if options.scene_breaks {
+        // Only inline formatting may wrap the symbols, so breaks never span paragraphs.
+        let scene_break = Regex::new(
+            r"<p(?:\s[^>]*)?>(?:\s|&nbsp;|</?(?:b|strong|i|em|span|center)(?:\s[^>]*)?>)*(?:[*#~=_•·◇◆-](?:\s|&nbsp;)*){3,}(?:\s|&nbsp;|</?(?:b|strong|i|em|span|center)(?:\s[^>]*)?>)*</p>",
+        )
+        .unwrap();
+        body = scene_break
+            .replace_all(&body, "<hr class=\"scene-break\"/>")
+            .into_owned();
+    }
+
+    // Text is told apart from tags so that attributes are left alone, and quotes are curled by
+    // the text before them, which may end in an earlier element.
+    let segments = Regex::new(r"<[^>]*>|[^<]+").unwrap();
+    let mut out = String::with_capacity(body.len());
+    let mut verbatim: Vec<String> = Vec::new();
+    let mut previous = ' ';
+    for segment in segments.find_iter(&body) {
+        let segment = segment.as_str();
+        if let Some(tag) = segment.strip_prefix('<') {
+            let closing = tag.starts_with('/');
+            let name: String = tag
+                .trim_start_matches('/')
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect::<String>()
+                .to_ascii_lowercase();
+            // Block elements start new text, after which quotes open.
+            if matches!(
+                name.as_str(),
+                "p" | "br" | "div" | "li" | "h1" | "h2" | "h3" | "hr"
+            ) {
+                previous = ' ';
+            }
+            if VERBATIM_ELEMENTS.contains(&name.as_str()) && !tag.ends_with("/>") {
+                match closing {
+                    true => {
+                        if let Some(i) = verbatim.iter().rposition(|x| *x == name) {
+                            verbatim.truncate(i);
+                        }
+                    }
+                    false => verbatim.push(name),
+                }
+            }
+            out.push_str(segment);
+            continue;
+        }
+        if !verbatim.is_empty() {
+            out.push_str(segment);
+            previous = segment.chars().last().unwrap_or(previous);
+            continue;
+        }
+        let mut text = segment.to_owned();
+        if options.dashes {
+            text = text.replace("---", "—").replace("--", "—");
+        }
+        if options.ellipses {
+            text = text.replace(". . .", "…").replace("...", "…");
+        }
+        if options.quotes {
+            text = curl_quotes(&text, previous);
+        }
+        previous = text.chars().last().unwrap_or(previous);
+        out.push_str(&text);
+    }
+    out
+}
+
+/// Curls each straight quote, opening it after whitespace or opening punctuation and closing it
+/// otherwise. Apostrophes before digits, as in '90s, are elisions rather than quotes.
+fn curl_quotes(text: &str, mut previous: char) -> String {
+    let mut curled = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let opens = previous.is_whitespace() || "([{—–-“‘".contains(previous);
+        let curly = match c {
+            '"' if opens => '“',
+            '"' => '”',
+            '\'' if opens && !chars.peek().is_some_and(|x| x.is_ascii_digit()) => '‘',
+            '\'' => '’',
+            _ => c,
+        };
+        curled.push(curly);
+        previous = curly;
+    }
+    curled
+}
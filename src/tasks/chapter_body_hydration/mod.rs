@@ -1,49 +1,140 @@
-use std::time::Duration;
+use std::time::Instant;
 
 use futures::future::join_all;
 use sqlx::{Pool, Sqlite};
-use tokio::time::MissedTickBehavior;
-use tracing::{error, info, instrument};
+use tracing::{error, field, info, instrument, Span};
 
-use crate::models::{Chapter, ChapterClient};
+use crate::{
+    models::{BookClient, Chapter, ChapterClient, ChapterState, CredentialClient},
+    providers::http::{active_blackout, is_blackout_skip, with_robots_txt_ignored},
+    telemetry::{continue_trace, record_loop_duration, record_provider_result, record_queue_depth},
+};
+
+use super::{
+    record_failure,
+    schedule::{schedule_chapters, LoopTimer, TaskLoop},
+    sources::store_source,
+    with_chapter_claim, with_panics_recorded,
+};
 
 pub async fn check_for_bodiless_chap_loop(pool: Pool<Sqlite>) {
-    // 10 sec check interval for all chapters.
-    let mut interval = tokio::time::interval(Duration::from_secs(10));
-    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
     let client = ChapterClient::new(&pool);
 
+    let mut timer = LoopTimer::new(TaskLoop::Hydration);
     loop {
-        // First tick completes immediately.
-        interval.tick().await;
+        let started = Instant::now();
+        // Pick up credentials rotated since the last pass.
+        if let Err(e) = CredentialClient::new(&pool).refresh().await {
+            error!("Error reading credentials {}", e);
+        }
         let chapters = client.list_chapters_without_bodies().await;
         let mut futures = Vec::new();
         match chapters {
             Ok(chapters) => {
-                for chapter in chapters {
-                    futures.push(fetch_chapter_body(chapter, &pool));
+                record_queue_depth(TaskLoop::Hydration.name(), chapters.len());
+                // Requests to a host are paced, so taking books in turns, and new releases before
+                // backlists, keeps one book's backfill from delaying everyone's deliveries.
+                let per_book = TaskLoop::Hydration.per_book_limit();
+                for chapter in schedule_chapters(chapters, per_book) {
+                    let (client, pool) = (&client, &pool);
+                    futures.push(async move {
+                        let chapter_id = chapter.id;
+                        let work = with_panics_recorded(
+                            client,
+                            &chapter_id,
+                            ChapterState::Discovered,
+                            fetch_chapter_body(chapter, pool),
+                        );
+                        with_chapter_claim(client, &chapter_id, work).await
+                    });
                 }
             }
             Err(e) => error!("Error fetching chapters with empty bodies {}", e),
         }
         join_all(futures).await;
+        record_loop_duration(TaskLoop::Hydration.name(), started.elapsed());
+        timer.wait_for_next_run(&pool).await;
     }
 }
 
-#[instrument(skip(pool))]
+#[instrument(
+    skip(pool),
+    fields(book.id = %chapter.book_id, chapter.id = %chapter.id, provider = field::Empty)
+)]
 pub async fn fetch_chapter_body(chapter: Chapter, pool: &Pool<Sqlite>) {
+    continue_trace(chapter.trace_context.as_deref());
     let client = ChapterClient::new(pool);
 
-    let chapter_provider = match chapter.metadata.body_provider() {
+    let book = match BookClient::new(pool).get_book(&chapter.book_id).await {
+        Ok(Some(book)) => book,
+        Ok(None) => {
+            error!(
+                "Book with id {} not found for chapter {}",
+                &chapter.book_id, &chapter.id
+            );
+            return;
+        }
+        Err(e) => {
+            error!(
+                "A database error occurred looking up book with id {} for chapter {}: {}",
+                &chapter.book_id, &chapter.id, e
+            );
+            return;
+        }
+    };
+
+    Span::current().record("provider", book.metadata.provider_name());
+
+    if let Some(window) = active_blackout(book.metadata.source_host(), Some(&book.id)) {
+        info!(
+            "Skipping hydration of chapter {} during blackout until {} UTC",
+            chapter.id, window.end
+        );
+        return;
+    }
+
+    let chapter_provider = match chapter.metadata.body_provider(&book) {
         Some(x) => x,
         None => return,
     };
 
-    let chapter_body = chapter_provider.fetch_chapter_body(&chapter).await;
+    let page = with_robots_txt_ignored(
+        book.ignore_robots_txt,
+        chapter_provider.fetch_chapter_page(&chapter),
+    )
+    .await;
+    if let Err(e) = &page {
+        if is_blackout_skip(e) {
+            info!("Skipping hydration of chapter {}: {}", chapter.id, e);
+            return;
+        }
+    }
+    // The page is kept before parsing, so a chapter which fails to parse can be parsed again
+    // once the parser is fixed.
+    let chapter_body = match page {
+        Ok(page) => {
+            if let Some(source_key) = store_source(pool, page.as_bytes()).await {
+                if let Err(e) = client.set_source_key(&chapter.id, &source_key).await {
+                    error!(
+                        "Failed to record the source of chapter {}: {}",
+                        chapter.id, e
+                    );
+                }
+            }
+            chapter_provider.parse_chapter_page(&page)
+        }
+        Err(e) => Err(e),
+    };
+    record_provider_result(
+        book.metadata.provider_name(),
+        "hydration",
+        chapter_body.is_ok(),
+    );
     let chapter_body = match chapter_body {
         Ok(x) => x,
         Err(e) => {
             error!("Error fetching chapters with empty bodies {}", e);
+            record_failure(&client, &chapter.id, ChapterState::Discovered, &e).await;
             return;
         }
     };
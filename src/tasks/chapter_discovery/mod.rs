@@ -1,40 +1,79 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
 
+use anyhow::anyhow;
+use chrono::Utc;
 use futures::future::join_all;
+use itertools::Itertools;
 use sqlx::{Pool, Sqlite};
-use tokio::time::MissedTickBehavior;
-use tracing::{error, info, instrument};
+use tracing::{error, field, info, instrument, Span};
 use uuid::Uuid;
 
-use crate::models::{BookClient, ChapterClient};
+use crate::{
+    models::{Book, BookClient, ChapterClient, CredentialClient, LeaseClient, ShallowChapter},
+    providers::{
+        circuit,
+        http::{active_blackout, is_blackout_skip, with_robots_txt_ignored},
+        normalize_title, title_key,
+    },
+    telemetry::{record_loop_duration, record_provider_result, record_queue_depth},
+};
+
+use super::{
+    backfill::backfill_book,
+    schedule::{LoopTimer, TaskLoop},
+    sources::store_sources,
+    volumes::detect_book_volumes,
+    with_lease,
+};
 
 pub async fn check_for_new_chap_loop(pool: Pool<Sqlite>) {
-    // 5 min check interval for all book.
-    let mut interval = tokio::time::interval(Duration::from_secs(5 * 60));
-    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
     let client = BookClient::new(&pool);
+    let lease_client = LeaseClient::new(&pool);
 
+    let mut timer = LoopTimer::new(TaskLoop::Discovery);
     loop {
-        // First tick completes immediately.
-        interval.tick().await;
+        let started = Instant::now();
+        // Pick up credentials rotated since the last pass.
+        if let Err(e) = CredentialClient::new(&pool).refresh().await {
+            error!("Error reading credentials {}", e);
+        }
         let books = client.list_books().await;
         let mut futures = Vec::new();
         match books {
             Ok(books) => {
+                // Aliases share their canonical book's source, which is fetched instead, and
+                // archived serials are finished.
+                let books: Vec<_> = books
+                    .into_iter()
+                    .filter(|x| x.canonical_book_id.is_none() && x.archived_at.is_none())
+                    .collect();
+                record_queue_depth(TaskLoop::Discovery.name(), books.len());
                 for book in books {
-                    futures.push(check_for_new_chapters_in_book(book.id, &pool));
+                    let (lease_client, pool) = (&lease_client, &pool);
+                    futures.push(async move {
+                        let lease = format!("discovery:{}", book.id);
+                        let work = check_for_new_chapters_in_book(book.id, pool);
+                        with_lease(lease_client, &lease, chrono::Duration::minutes(10), work).await
+                    });
                 }
             }
             Err(e) => error!("Error fetching books {}", e),
         }
         join_all(futures).await;
+        record_loop_duration(TaskLoop::Discovery.name(), started.elapsed());
+        timer.wait_for_next_run(&pool).await;
     }
 }
 
-#[instrument(skip(pool))]
+#[instrument(skip(pool), fields(book.id = %book_id, provider = field::Empty))]
 pub async fn check_for_new_chapters_in_book(book_id: Uuid, pool: &Pool<Sqlite>) {
     let client = ChapterClient::new(pool);
-    let most_recent_chapter = match client.most_recent_chapter_by_created_at(&book_id).await {
+    // Gaps filled from the public source are newer than the primary source's chapters around
+    // them, so they don't move its cursor.
+    let most_recent_chapter = match client.most_recent_primary_chapter(&book_id).await {
         Ok(x) => x,
         Err(e) => {
             error!(
@@ -59,22 +98,79 @@ pub async fn check_for_new_chapters_in_book(book_id: Uuid, pool: &Pool<Sqlite>)
         }
     };
 
+    Span::current().record("provider", book.metadata.provider_name());
+
+    if let Some(window) = active_blackout(book.metadata.source_host(), Some(&book.id)) {
+        info!(
+            "Skipping discovery for book {} during blackout until {} UTC",
+            book.id, window.end
+        );
+        return;
+    }
+
+    let provider = book.metadata.provider_name();
+    if !circuit::allows(provider) {
+        info!(
+            "Skipping discovery for book {} while {} is failing",
+            book.id, provider
+        );
+        return;
+    }
+
+    let book_client = BookClient::new(pool);
     let chapter_provider = book.metadata.chapter_provider();
-    let new_chapters = chapter_provider
-        .fetch_new_chapters(&book_id, most_recent_chapter_created_at.as_ref())
-        .await;
+    let new_chapters = with_robots_txt_ignored(
+        book.ignore_robots_txt,
+        chapter_provider.fetch_new_chapters(&book_id, most_recent_chapter_created_at.as_ref()),
+    )
+    .await;
+    if let Err(e) = &new_chapters {
+        if is_blackout_skip(e) {
+            info!("Skipping discovery for book {}: {}", book.id, e);
+            circuit::release(provider);
+            return;
+        }
+    }
+    record_provider_result(provider, "discovery", new_chapters.is_ok());
+    circuit::record_result(provider, new_chapters.is_ok());
 
-    let new_chapters = match new_chapters {
+    let mut new_chapters = match new_chapters {
         Ok(chapters) => chapters,
         Err(e) => {
             error!(
                 "Error occurred fetching chapters for book id {}: {}",
                 book_id, e
             );
+            record_discovery(&book_client, &book_id, Some(&format!("{:#}", e))).await;
             return;
         }
     };
 
+    for chapter in new_chapters.iter_mut() {
+        let title = normalize_title(&book, &chapter.title);
+        if title != chapter.title {
+            chapter.raw_title = Some(std::mem::replace(&mut chapter.title, title));
+        }
+        // Patrons' chapters reach everyone else once the public release date has passed.
+        if let (None, Some(secs)) = (chapter.deliver_after, book.early_access_secs) {
+            let published_at = chapter.published_at.unwrap_or_else(Utc::now);
+            chapter.deliver_after = Some(published_at + chrono::Duration::seconds(secs));
+        }
+    }
+
+    // The public source may have found the chapter first.
+    if book.public_metadata.is_some() {
+        let existing: HashSet<String> = match client.list_chapters_shallow(&book_id).await {
+            Ok(x) => x.iter().map(|x| title_key(&x.title)).collect(),
+            Err(e) => {
+                error!("Error listing chapters of book {}: {}", book_id, e);
+                return;
+            }
+        };
+        new_chapters.retain(|x| !existing.contains(&title_key(&x.title)));
+    }
+
+    store_sources(pool, &mut new_chapters).await;
     match client.create_chapters(&new_chapters).await {
         Ok(x) => {
             info!("Created new chapters {:?}", x);
@@ -83,4 +179,127 @@ pub async fn check_for_new_chapters_in_book(book_id: Uuid, pool: &Pool<Sqlite>)
             error!("Failed to create new chapters: {}", e)
         }
     };
+
+    let merged = merge_public_chapters(&book, pool).await;
+    if let Err(e) = &merged {
+        error!(
+            "Error merging public chapters for book id {}: {:#}",
+            book_id, e
+        );
+    }
+    let error = merged.err().map(|e| format!("{:#}", e));
+    record_discovery(&book_client, &book_id, error.as_deref()).await;
+
+    backfill_book(pool, &book).await;
+
+    if book.detect_volumes {
+        if let Err(e) = detect_book_volumes(pool, &book).await {
+            error!("Error detecting volumes of book id {}: {}", book_id, e);
+        }
+    }
+}
+
+async fn record_discovery(client: &BookClient, book_id: &Uuid, error: Option<&str>) {
+    if let Err(e) = client.record_discovery(book_id, error).await {
+        error!(
+            "DB error occurred recording discovery of book {}: {}",
+            book_id, e
+        );
+    }
+}
+
+/// Merges newly released chapters from the book's public source. A public chapter matching an
+/// early access chapter by title releases it and gives it the public title; one matching
+/// nothing fills a gap in the primary source. The book is then ordered as the public source
+/// released its chapters, followed by those still in early access. The first check only looks
+/// at chapters released since the book was added, rather than the public source's backlist.
+#[instrument(skip_all, fields(book.id = %book.id))]
+async fn merge_public_chapters(book: &Book, pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+    let public = match &book.public_metadata {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let client = ChapterClient::new(pool);
+    let since = client
+        .latest_public_published_at(&book.id)
+        .await?
+        .unwrap_or(book.created_at);
+    if let Some(window) = active_blackout(public.source_host(), Some(&book.id)) {
+        info!(
+            "Skipping the public source of book {} during blackout until {} UTC",
+            book.id, window.end
+        );
+        return Ok(());
+    }
+    if !circuit::allows(public.provider_name()) {
+        return Err(anyhow!(
+            "Skipped the public source while {} is failing",
+            public.provider_name()
+        ));
+    }
+    let fetched = with_robots_txt_ignored(
+        book.ignore_robots_txt,
+        public
+            .chapter_provider()
+            .fetch_new_chapters(&book.id, Some(&since)),
+    )
+    .await;
+    if let Err(e) = &fetched {
+        if is_blackout_skip(e) {
+            info!("Skipping the public source of book {}: {}", book.id, e);
+            circuit::release(public.provider_name());
+            return Ok(());
+        }
+    }
+    record_provider_result(public.provider_name(), "discovery", fetched.is_ok());
+    circuit::record_result(public.provider_name(), fetched.is_ok());
+    let fetched = fetched?;
+    if fetched.is_empty() {
+        return Ok(());
+    }
+
+    let existing: HashMap<String, ShallowChapter> = client
+        .list_chapters_shallow(&book.id)
+        .await?
+        .into_iter()
+        .map(|x| (title_key(&x.title), x))
+        .collect();
+    let mut gaps = Vec::new();
+    for mut chapter in fetched {
+        let title = normalize_title(book, &chapter.title);
+        if title != chapter.title {
+            chapter.raw_title = Some(std::mem::replace(&mut chapter.title, title));
+        }
+        let published_at = chapter.published_at.unwrap_or_else(Utc::now);
+        match existing.get(&title_key(&chapter.title)) {
+            Some(x) if x.public_published_at.is_some() => {}
+            Some(x) => {
+                client
+                    .mark_public(&x.id, &chapter.title, &published_at, false)
+                    .await?;
+            }
+            None => gaps.push(chapter),
+        }
+    }
+    // Gaps are chapters the primary source missed, which subscriptions have read past, so they
+    // are backdated like prepended chapters and only delivered to those which haven't started.
+    for chapter in client.prepend_chapters(&book.id, &gaps).await? {
+        let published_at = chapter.published_at.unwrap_or(chapter.created_at);
+        client
+            .mark_public(&chapter.id, &chapter.title, &published_at, true)
+            .await?;
+    }
+
+    let chapters = client.list_chapters_shallow(&book.id).await?;
+    let (public, early): (Vec<_>, Vec<_>) = chapters
+        .into_iter()
+        .partition(|x| x.public_published_at.is_some());
+    let order: Vec<Uuid> = public
+        .iter()
+        .sorted_by_key(|x| (x.public_published_at, x.order_index))
+        .chain(early.iter().sorted_by_key(|x| x.order_index))
+        .map(|x| x.id)
+        .collect();
+    client.reorder_chapters(&book.id, &order).await?;
+    Ok(())
 }
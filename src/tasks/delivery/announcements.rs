@@ -0,0 +1,255 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Sqlite};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    error::ApiResult,
+    models::{
+        changes::{subscribe, ChangeKind, Entity},
+        Book, BookClient, ChapterClient, Subscriber, SubscriberClient,
+    },
+    tasks::chapter_body_conversion::sanitize_html,
+};
+
+use super::{
+    apprise, mail_usage, mailgun, pushover, recipients, usable_channels, Channel, MailQuotaState,
+};
+
+/// How long a book must have gone without a chapter for its next one to be announced as the
+/// book resuming, from CEREAL_ANNOUNCEMENT_HIATUS_DAYS.
+fn hiatus() -> chrono::Duration {
+    let days = env::var("CEREAL_ANNOUNCEMENT_HIATUS_DAYS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(30);
+    chrono::Duration::days(days)
+}
+
+/// Who announcements are sent to, from CEREAL_ANNOUNCEMENT_AUDIENCE: `all` for every
+/// subscriber, or a comma separated list of subscriber ids. Groups are sent to their members.
+#[derive(Debug, Clone, PartialEq)]
+enum Audience {
+    All,
+    Subscribers(Vec<Uuid>),
+}
+
+fn audience() -> Option<Audience> {
+    let value = env::var("CEREAL_ANNOUNCEMENT_AUDIENCE").ok()?;
+    if value.trim() == "all" {
+        return Some(Audience::All);
+    }
+    let ids = value
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .filter_map(|x| match Uuid::parse_str(x) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                error!("Ignoring invalid announcement subscriber id {:?}: {}", x, e);
+                None
+            }
+        })
+        .collect();
+    Some(Audience::Subscribers(ids))
+}
+
+/// A book worth telling the audience about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Announcement {
+    NewBook,
+    /// The book's first chapter after a hiatus, published at the time given.
+    Resumed(DateTime<Utc>),
+}
+
+/// Announces books as they are added, and as they resume after a hiatus, through the
+/// notification channels of the announcement audience, so they can subscribe. Does nothing
+/// unless CEREAL_ANNOUNCEMENT_AUDIENCE is set. Only changes made by this instance are seen.
+pub async fn announcement_loop(pool: Pool<Sqlite>) {
+    let audience = match audience() {
+        Some(x) => x,
+        None => return std::future::pending().await,
+    };
+    info!("Announcing new and resumed books to {:?}", audience);
+    let mut receiver = subscribe();
+    // Chapters discovered together are announced once, when the first of them is seen.
+    let mut announced_batches: HashMap<Uuid, DateTime<Utc>> = HashMap::new();
+    loop {
+        let change = match receiver.recv().await {
+            Ok(x) => x,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Missed {} changes, some books may not be announced", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let announcement = match (change.entity, change.kind, change.book_id) {
+            (Entity::Book, ChangeKind::Created, _) => Some((change.id, Announcement::NewBook)),
+            (Entity::Chapter, ChangeKind::Created, Some(book_id)) => {
+                match resumption(&pool, &change.id, &mut announced_batches).await {
+                    Ok(x) => x.map(|x| (book_id, x)),
+                    Err(e) => {
+                        error!("Error checking whether book {} resumed: {}", book_id, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        if let Some((book_id, announcement)) = announcement {
+            if let Err(e) = announce(&pool, &audience, &book_id, announcement).await {
+                error!("Error announcing book {}: {}", book_id, e);
+            }
+        }
+    }
+}
+
+/// Whether the newly created chapter resumes its book after a hiatus. Backfilled chapters,
+/// which are older than the book's latest, never do.
+async fn resumption(
+    pool: &Pool<Sqlite>,
+    chapter_id: &Uuid,
+    announced_batches: &mut HashMap<Uuid, DateTime<Utc>>,
+) -> ApiResult<Option<Announcement>> {
+    let client = ChapterClient::new(pool);
+    let chapter = match client.get_chapter_shallow(chapter_id).await? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    if announced_batches.get(&chapter.book_id) == Some(&chapter.created_at) {
+        return Ok(None);
+    }
+    let published_at = chapter.published_at.unwrap_or(chapter.created_at);
+    let previous = client
+        .latest_published_before(&chapter.book_id, &chapter.created_at)
+        .await?;
+    match previous {
+        Some(previous) if published_at - previous >= hiatus() => {
+            announced_batches.insert(chapter.book_id, chapter.created_at);
+            Ok(Some(Announcement::Resumed(published_at)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The audience's subscribers, with groups replaced by their members.
+async fn audience_members(pool: &Pool<Sqlite>, audience: &Audience) -> ApiResult<Vec<Subscriber>> {
+    let client = SubscriberClient::new(pool);
+    let subscribers = match audience {
+        Audience::All => client.list_subscribers().await?,
+        Audience::Subscribers(ids) => {
+            let mut subscribers = Vec::new();
+            for id in ids {
+                match client.get_subscriber(*id).await? {
+                    Some(x) => subscribers.push(x),
+                    None => warn!("Announcement subscriber {} doesn't exist", id),
+                }
+            }
+            subscribers
+        }
+    };
+    let mut seen = HashSet::new();
+    let mut members = Vec::new();
+    for subscriber in subscribers {
+        for member in recipients(pool, &subscriber).await? {
+            if seen.insert(member.id) {
+                members.push(member);
+            }
+        }
+    }
+    Ok(members)
+}
+
+#[instrument(skip(pool, audience))]
+async fn announce(
+    pool: &Pool<Sqlite>,
+    audience: &Audience,
+    book_id: &Uuid,
+    announcement: Announcement,
+) -> ApiResult<()> {
+    let book = match BookClient::new(pool).get_book(book_id).await? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    // Aliases are the same book as their canonical book, which was already announced.
+    if book.canonical_book_id.is_some() {
+        return Ok(());
+    }
+    let (subject, message) = announcement_text(&book, announcement);
+    info!("Announcing book {}: {}", book.id, message);
+    // Announcements can be missed, so they aren't emailed once the mailgun quota is approached.
+    let email = mail_usage(pool).await?.state == MailQuotaState::Normal;
+    for subscriber in audience_members(pool, audience).await? {
+        if let Err(e) = notify(&subscriber, &subject, &message, email).await {
+            error!(
+                "Failed to announce book {} to subscriber {}: {:#}",
+                book.id, subscriber.id, e
+            );
+        }
+    }
+    Ok(())
+}
+
+fn announcement_text(book: &Book, announcement: Announcement) -> (String, String) {
+    match announcement {
+        Announcement::NewBook => (
+            format!("New book: {}", book.title),
+            format!(
+                "{} by {} was added and can now be subscribed to.",
+                book.title, book.author
+            ),
+        ),
+        Announcement::Resumed(published_at) => (
+            format!("{} is back", book.title),
+            format!(
+                "{} by {} released a new chapter on {} after a hiatus.",
+                book.title,
+                book.author,
+                published_at.format("%Y-%m-%d")
+            ),
+        ),
+    }
+}
+
+/// Sends the announcement through each of the subscriber's notification channels, and by email
+/// if `send_email` is set. Kindles and read-later services only take chapters, so they aren't
+/// sent announcements.
+async fn notify(
+    subscriber: &Subscriber,
+    subject: &str,
+    message: &str,
+    send_email: bool,
+) -> anyhow::Result<()> {
+    let channels = usable_channels(subscriber);
+    if let (Some(pushover_key), true) = (
+        &subscriber.pushover_key,
+        channels.contains(&Channel::Pushover),
+    ) {
+        pushover::send_message(pushover_key, message)
+            .await
+            .context("Failed to send pushover message")?;
+    }
+    if let (Some(notify_url), true) = (&subscriber.notify_url, channels.contains(&Channel::Notify))
+    {
+        apprise::send_notification(notify_url, subject, message)
+            .await
+            .context("Failed to send apprise notification")?;
+    }
+    if let (Some(email), true) = (
+        &subscriber.email,
+        send_email && channels.contains(&Channel::Email),
+    ) {
+        let html = sanitize_html(&format!("<p>{}</p>", message));
+        mailgun::send_html_email(email, subject, &html)
+            .await
+            .context("Failed to send email")?;
+    }
+    Ok(())
+}
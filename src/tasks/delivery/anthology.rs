@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::ApiResult,
+    models::{
+        AnthologySubscription, AnthologySubscriptionClient, Book, BookClient, Chapter,
+        ChapterClient, DeliveryClient, DeliveryFormat, LeaseClient, Subscriber, SubscriberClient,
+        Subscription, SubscriptionClient,
+    },
+    tasks::{
+        chapter_body_conversion::generate_digest_epub,
+        integrity::{find_corruption, verify_chapter},
+        with_lease,
+    },
+};
+
+use super::{
+    mail_usage, needs_epub, reachable_recipients, recipients, released_chapters, retry_backoff,
+    send_to_recipients, sends_email, take_within_cap, templates::digest_names, unreachable_reason,
+    DeliveryCap, SendOutcome,
+};
+
+/// Creates a subscription for each book an anthology subscription covers and deletes those for
+/// books it no longer does. As with wildcard subscriptions, a book is only subscribed to once
+/// its first chapters have been discovered, starting from the latest of them.
+#[instrument(skip(pool))]
+pub async fn sync_anthology_subscriptions(pool: &Pool<Sqlite>) -> ApiResult<()> {
+    let anthologies = AnthologySubscriptionClient::new(pool)
+        .list_anthology_subscriptions(None)
+        .await?;
+    if anthologies.is_empty() {
+        return Ok(());
+    }
+    let chapter_client = ChapterClient::new(pool);
+    let subscription_client = SubscriptionClient::new(pool);
+
+    for anthology in anthologies {
+        let members: HashMap<Uuid, Uuid> = subscription_client
+            .list_subscriptions(&anthology.subscriber_id)
+            .await?
+            .into_iter()
+            .filter(|x| x.anthology_subscription_id == Some(anthology.id))
+            .map(|x| (x.book_id, x.id))
+            .collect();
+
+        for (book_id, subscription_id) in &members {
+            if !anthology.book_ids.contains(book_id) {
+                info!(
+                    "Book {} was removed from anthology subscription {}, unsubscribing",
+                    book_id, anthology.id
+                );
+                subscription_client
+                    .delete_subscription(*subscription_id)
+                    .await?;
+            }
+        }
+        for book_id in &anthology.book_ids {
+            if members.contains_key(book_id) {
+                continue;
+            }
+            let latest_chapter = match chapter_client
+                .most_recent_chapter_by_created_at(book_id)
+                .await?
+            {
+                Some(x) => x,
+                None => continue,
+            };
+            info!(
+                "Subscribing to book {} for anthology subscription {}",
+                book_id, anthology.id
+            );
+            subscription_client
+                .create_anthology_member(&anthology, book_id, &latest_chapter)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends the digest of each anthology subscription whose period has passed.
+#[instrument(skip(pool))]
+pub async fn send_due_digests(pool: &Pool<Sqlite>) -> ApiResult<()> {
+    let lease_client = LeaseClient::new(pool);
+    for anthology in AnthologySubscriptionClient::new(pool)
+        .list_anthology_subscriptions(None)
+        .await?
+    {
+        if anthology.due_at() > Utc::now() {
+            continue;
+        }
+        let lease = format!("anthology:{}", anthology.id);
+        let work = send_digest(pool, anthology);
+        with_lease(&lease_client, &lease, chrono::Duration::minutes(30), work).await;
+    }
+    Ok(())
+}
+
+/// One book's part of a digest: its subscription and the chapters sent for it.
+struct DigestSection {
+    subscription: Subscription,
+    book: Book,
+    chapters: Vec<Chapter>,
+}
+
+/// The anthology's members with released chapters, in order of book title, or None if the
+/// digest should wait for a failed attempt's backoff. A digest holds at most the delivery cap,
+/// taking each book's earliest chapters in title order, and the rest wait for the next digest.
+async fn digest_sections(
+    pool: &Pool<Sqlite>,
+    anthology: &AnthologySubscription,
+) -> ApiResult<Option<Vec<DigestSection>>> {
+    let book_client = BookClient::new(pool);
+    let chapter_client = ChapterClient::new(pool);
+    let delivery_client = DeliveryClient::new(pool);
+    let members = SubscriptionClient::new(pool)
+        .list_subscriptions(&anthology.subscriber_id)
+        .await?
+        .into_iter()
+        .filter(|x| x.anthology_subscription_id == Some(anthology.id));
+
+    let mut sections = Vec::new();
+    for subscription in members {
+        if let Some(attempt) = delivery_client.latest_attempt(&subscription.id).await? {
+            if let (false, Some(retry_at)) = (attempt.succeeded, attempt.retry_at) {
+                if retry_at > Utc::now() {
+                    return Ok(None);
+                }
+            }
+        }
+        let book = match book_client.get_book(&subscription.book_id).await? {
+            Some(x) => x,
+            None => continue,
+        };
+        let undelivered = chapter_client
+            .list_chapters_with_epub(
+                &book.id,
+                subscription.last_delivered_chapter_created_at.as_ref(),
+            )
+            .await?;
+        let chapters = released_chapters(&subscription, &undelivered);
+        if !chapters.is_empty() {
+            sections.push(DigestSection {
+                subscription,
+                book,
+                chapters,
+            });
+        }
+    }
+    sections.sort_by(|a, b| a.book.title.cmp(&b.book.title));
+    let cap = DeliveryCap::from_env();
+    let mut used = DeliveryCap {
+        chapters: 0,
+        bytes: 0,
+    };
+    for section in sections.iter_mut() {
+        let chapters = std::mem::take(&mut section.chapters);
+        section.chapters = take_within_cap(chapters, &cap, DeliveryFormat::Epub, &mut used);
+    }
+    sections.retain(|x| !x.chapters.is_empty());
+    Ok(Some(sections))
+}
+
+#[instrument(skip_all, fields(anthology.id = %anthology.id, subscriber.id = %anthology.subscriber_id))]
+async fn send_digest(pool: &Pool<Sqlite>, anthology: AnthologySubscription) {
+    let anthology_client = AnthologySubscriptionClient::new(pool);
+    let subscriber = match SubscriberClient::new(pool)
+        .get_subscriber(anthology.subscriber_id)
+        .await
+    {
+        Ok(Some(x)) => x,
+        Ok(None) => return,
+        Err(e) => {
+            error!(
+                "A DB error occurred reading subscriber {}: {}",
+                &anthology.subscriber_id, e
+            );
+            return;
+        }
+    };
+    let sections = match digest_sections(pool, &anthology).await {
+        Ok(Some(x)) => x,
+        Ok(None) => return,
+        Err(e) => {
+            error!(
+                "A DB error occurred finding chapters for anthology subscription {}: {}",
+                &anthology.id, e
+            );
+            return;
+        }
+    };
+
+    // An empty period sends nothing, and the next digest is due a period later.
+    if sections.is_empty() {
+        info!(
+            "No new chapters for anthology subscription {}",
+            &anthology.id
+        );
+        if let Err(e) = anthology_client
+            .set_last_sent_at(&anthology.id, &Utc::now())
+            .await
+        {
+            error!(
+                "A DB error occurred updating anthology subscription {}: {}",
+                &anthology.id, e
+            );
+        }
+        return;
+    }
+
+    // Corrupted bodies are dropped for regeneration, and the digest sent once they return.
+    let corrupted: Vec<&Chapter> = sections
+        .iter()
+        .flat_map(|x| &x.chapters)
+        .filter(|x| find_corruption(x).any())
+        .collect();
+    if !corrupted.is_empty() {
+        for chapter in corrupted {
+            if let Err(e) = verify_chapter(pool, chapter.clone()).await {
+                error!(
+                    "A DB error occurred flagging chapter {} for regeneration: {}",
+                    &chapter.id, e
+                );
+            }
+        }
+        return;
+    }
+
+    let recipients = match recipients(pool, &subscriber).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(
+                "A DB error occurred reading recipients of subscriber {}: {}",
+                &subscriber.id, e
+            );
+            return;
+        }
+    };
+    // Chapters stay in the digest, rather than being skipped, until the subscriber can be
+    // reached.
+    if let Some(reason) = unreachable_reason(&subscriber, &recipients) {
+        info!(
+            "Not sending digest to subscriber {} as {}",
+            &subscriber.id, reason
+        );
+        return;
+    }
+
+    // Digests already bundle their chapters, so they only wait once the quota is used up.
+    if sends_email(&reachable_recipients(&recipients, DeliveryFormat::Epub)) {
+        match mail_usage(pool).await {
+            Ok(usage) => {
+                if let Some(next_at) = usage.deferral(false, None) {
+                    info!(
+                        "Not sending digest for anthology subscription {} until {} to keep within the mailgun quota",
+                        &anthology.id, next_at
+                    );
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("A DB error occurred reading mailgun usage: {}", e);
+                return;
+            }
+        }
+    }
+
+    let delivery_client = DeliveryClient::new(pool);
+    let mut attempts = Vec::new();
+    for section in &sections {
+        let attempt = match delivery_client
+            .latest_attempt(&section.subscription.id)
+            .await
+        {
+            Ok(Some(latest)) if !latest.succeeded => latest.attempt + 1,
+            Ok(_) => 1,
+            Err(e) => {
+                error!(
+                    "A DB error occurred reading delivery attempts for subscription {}: {}",
+                    &section.subscription.id, e
+                );
+                return;
+            }
+        };
+        attempts.push(attempt);
+    }
+
+    let outcome = match send_digest_to(&recipients, &anthology, &sections).await {
+        Ok(outcome) => outcome,
+        Err(e) => SendOutcome::failed(anthology.subscriber_id, e),
+    };
+    let failure = outcome.failure();
+    if let Some(message) = &failure {
+        error!(
+            "Digest for anthology subscription {} failed: {}",
+            &anthology.id, message
+        );
+    }
+    outcome
+        .record_recipient_health(pool, &anthology.subscriber_id)
+        .await;
+    let subscription_client = SubscriptionClient::new(pool);
+    for (section, attempt) in sections.iter().zip(attempts) {
+        let subscription_id = &section.subscription.id;
+        let chapter_ids: Vec<Uuid> = section.chapters.iter().map(|x| x.id).collect();
+        let retry_at = failure
+            .as_ref()
+            .map(|_| Utc::now() + retry_backoff(attempt));
+        let recorded = delivery_client
+            .record_attempt(
+                subscription_id,
+                &chapter_ids,
+                attempt,
+                failure.as_deref(),
+                retry_at.as_ref(),
+                &outcome.delivered,
+            )
+            .await;
+        match recorded {
+            // The digest is one message, so each book's attempt shares its receipts.
+            Ok(attempt) => {
+                if let Err(e) = delivery_client
+                    .record_receipts(&attempt.id, &outcome.sent)
+                    .await
+                {
+                    error!(
+                        "A DB error occurred recording receipts for subscription {}: {}",
+                        subscription_id, e
+                    );
+                }
+            }
+            Err(e) => error!(
+                "A DB error occurred recording delivery for subscription {}: {}",
+                subscription_id, e
+            ),
+        }
+        if failure.is_some() {
+            continue;
+        }
+        let latest_chapter = section
+            .chapters
+            .iter()
+            .max_by_key(|x| x.created_at)
+            .unwrap();
+        if let Err(e) = subscription_client
+            .set_last_delivered_chapter(
+                subscription_id,
+                &latest_chapter.id,
+                &latest_chapter.created_at,
+            )
+            .await
+        {
+            error!(
+                "A DB error occurred setting subscription {} to have latest chapter {}: {}",
+                subscription_id, &latest_chapter.id, e
+            );
+        }
+        if let Err(e) = delivery_client
+            .record_deliveries(subscription_id, &section.chapters)
+            .await
+        {
+            error!(
+                "A DB error occurred recording deliveries for subscription {}: {}",
+                subscription_id, e
+            );
+        }
+    }
+    if failure.is_none() {
+        if let Err(e) = anthology_client
+            .set_last_sent_at(&anthology.id, &Utc::now())
+            .await
+        {
+            error!(
+                "A DB error occurred updating anthology subscription {}: {}",
+                &anthology.id, e
+            );
+        }
+    }
+}
+
+/// Sends the digest to each usable channel of each recipient, generating its epub once.
+async fn send_digest_to(
+    recipients: &[Subscriber],
+    anthology: &AnthologySubscription,
+    sections: &[DigestSection],
+) -> anyhow::Result<SendOutcome> {
+    let reachable = reachable_recipients(recipients, DeliveryFormat::Epub);
+    if reachable.is_empty() {
+        return Err(anyhow!("No recipient has a usable delivery channel"));
+    }
+    let sections: Vec<(&Book, &[Chapter])> = sections
+        .iter()
+        .map(|x| (&x.book, x.chapters.as_slice()))
+        .collect();
+    let names = digest_names(anthology, &sections);
+    let epub = match needs_epub(&reachable) {
+        true => {
+            let bytes = generate_digest_epub(anthology, &names.subject, &sections)
+                .await
+                .context("Failed to create digest epub")?;
+            Some(Bytes::from(bytes))
+        }
+        false => None,
+    };
+    let chapters: Vec<&Chapter> = sections.iter().flat_map(|(_, x)| x.iter()).collect();
+    Ok(send_to_recipients(&reachable, &names, &chapters, epub.as_ref(), None).await)
+}
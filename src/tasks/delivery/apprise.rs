@@ -0,0 +1,33 @@
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::env;
+use tracing::instrument;
+
+use crate::telemetry::with_trace_context;
+
+/// Whether CEREAL_APPRISE_URL names an apprise api gateway, without which no notify urls can
+/// be sent to.
+pub fn is_configured() -> bool {
+    env::var("CEREAL_APPRISE_URL").is_ok()
+}
+
+/// Sends the notification to the apprise url, such as `signal://...` or `whatsapp://...`,
+/// through the gateway, which implements each protocol so that cereal doesn't have to.
+#[instrument(level = "info", err, skip(notify_url))]
+pub async fn send_notification(notify_url: &str, title: &str, body: &str) -> Result<()> {
+    let gateway = env::var("CEREAL_APPRISE_URL").context("Apprise gateway url not provided")?;
+    let client = reqwest::Client::default();
+    with_trace_context(
+        client
+            .post(format!("{}/notify/", gateway.trim_end_matches('/')))
+            .json(&json!({
+                "urls": notify_url,
+                "title": title,
+                "body": body,
+            })),
+    )
+    .send()
+    .await?
+    .error_for_status()?;
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::env;
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+use tracing::{error, instrument};
+
+use crate::{config::config, error::ApiResult, models::MailgunUsageClient};
+
+use super::mailgun::{restore_unrecorded_messages, take_unrecorded_messages};
+
+/// Throttle email deliveries once this share of the monthly quota is used.
+const MAIL_QUOTA_THROTTLE_RATIO: f64 = 0.8;
+
+/// How long email deliveries wait after each other while throttled, so chapters arriving in
+/// the meantime are bundled into one message, from CEREAL_MAILGUN_THROTTLE_SECS.
+fn throttle_interval() -> chrono::Duration {
+    let secs = env::var("CEREAL_MAILGUN_THROTTLE_SECS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(6 * 60 * 60);
+    chrono::Duration::seconds(secs)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MailQuotaState {
+    /// Under the throttling threshold, or no quota is configured.
+    Normal,
+    /// Approaching the quota: backlogs wait for the next month, and other email deliveries are
+    /// spaced out.
+    Throttled,
+    /// The quota is used up, and email deliveries wait for the next month.
+    Exhausted,
+}
+
+/// The messages mailgun has sent this month against the configured quota.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct MailUsage {
+    /// As `YYYY-MM`, in UTC.
+    pub month: String,
+    pub messages: i64,
+    pub quota: Option<i64>,
+    pub state: MailQuotaState,
+    #[serde(rename = "resetsAt")]
+    pub resets_at: DateTime<Utc>,
+}
+
+impl MailUsage {
+    /// When an email delivery should wait until, if it should. Backlogs can wait, so they are
+    /// deferred to the next month as the quota is approached, while new chapters are spaced
+    /// out from the subscription's last successful delivery.
+    pub fn deferral(
+        &self,
+        backlog: bool,
+        last_sent_at: Option<DateTime<Utc>>,
+    ) -> Option<DateTime<Utc>> {
+        match self.state {
+            MailQuotaState::Normal => None,
+            MailQuotaState::Exhausted => Some(self.resets_at),
+            MailQuotaState::Throttled if backlog => Some(self.resets_at),
+            MailQuotaState::Throttled => last_sent_at
+                .map(|x| x + throttle_interval())
+                .filter(|x| *x > Utc::now()),
+        }
+    }
+}
+
+fn month_of(time: &DateTime<Utc>) -> String {
+    time.format("%Y-%m").to_string()
+}
+
+/// The start of the month after the time's, when mailgun's quota resets.
+fn next_month(time: &DateTime<Utc>) -> DateTime<Utc> {
+    let (year, month) = match time.month() {
+        12 => (time.year() + 1, 1),
+        x => (time.year(), x + 1),
+    };
+    let start = NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|x| x.and_hms_opt(0, 0, 0))
+        .expect("The first of a month is a valid date");
+    DateTime::<Utc>::from_utc(start, Utc)
+}
+
+/// Adds the messages this instance sent since it last did to the month's usage. Messages are
+/// counted in the month they're recorded in, so a few sent at the end of a month may count
+/// towards the next.
+async fn record_sent_messages(pool: &Pool<Sqlite>, month: &str) {
+    let messages = take_unrecorded_messages();
+    if messages == 0 {
+        return;
+    }
+    if let Err(e) = MailgunUsageClient::new(pool)
+        .add_messages(month, messages)
+        .await
+    {
+        error!("A DB error occurred recording mailgun usage: {}", e);
+        restore_unrecorded_messages(messages);
+    }
+}
+
+/// This month's mailgun usage, including the messages sent by this instance so far.
+#[instrument(skip(pool))]
+pub async fn mail_usage(pool: &Pool<Sqlite>) -> ApiResult<MailUsage> {
+    let now = Utc::now();
+    let month = month_of(&now);
+    record_sent_messages(pool, &month).await;
+    let messages = MailgunUsageClient::new(pool).messages_sent(&month).await?;
+    let quota = config().mailgun_monthly_quota;
+    let state = match quota {
+        Some(quota) if messages >= quota => MailQuotaState::Exhausted,
+        Some(quota) if messages as f64 >= quota as f64 * MAIL_QUOTA_THROTTLE_RATIO => {
+            MailQuotaState::Throttled
+        }
+        _ => MailQuotaState::Normal,
+    };
+    Ok(MailUsage {
+        month,
+        messages,
+        quota,
+        state,
+        resets_at: next_month(&now),
+    })
+}
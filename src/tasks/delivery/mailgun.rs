@@ -1,12 +1,129 @@
 use anyhow::{bail, Error};
-use reqwest::multipart::Part;
-use std::env;
+use bytes::Bytes;
+use reqwest::{multipart::Part, StatusCode};
+use std::{
+    env,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+use crate::{config::secret, telemetry::with_trace_context};
+
+/// Whether the mailgun credentials and from address are set, without which no emails can be
+/// sent.
+pub fn is_configured() -> bool {
+    secret("CEREAL_MAILGUN_API_KEY").is_some()
+        && ["CEREAL_MAILGUN_API_ENDPOINT", "CEREAL_FROM_EMAIL_ADDRESS"]
+            .iter()
+            .all(|x| env::var(x).is_ok())
+}
+
+/// The domains Amazon accepts send-to-kindle emails at.
+const KINDLE_DOMAINS: &[&str] = &["kindle.com", "free.kindle.com"];
+
+/// Whether the address is a send-to-kindle address, which is the only kind Amazon delivers to
+/// a device.
+pub fn is_kindle_address(email: &str) -> bool {
+    match email.trim().rsplit_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty() && KINDLE_DOMAINS.contains(&domain.to_lowercase().as_str())
+        }
+        None => false,
+    }
+}
+
+/// The domain mailgun sends from, taken from an endpoint such as
+/// `https://api.mailgun.net/v3/mg.example.com/messages`.
+fn sending_domain(endpoint: &str) -> Option<String> {
+    let url = reqwest::Url::parse(endpoint).ok()?;
+    let mut segments = url.path_segments()?;
+    segments.next()?;
+    segments.next().map(|x| x.to_lowercase())
+}
+
+/// Problems with the from address which would likely keep Amazon from accepting emails. Amazon
+/// drops emails from senders missing from the account's approved personal document e-mail
+/// list without telling anyone, so these can only be guesses.
+pub fn sender_warnings() -> Vec<String> {
+    let from = match env::var("CEREAL_FROM_EMAIL_ADDRESS") {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+    // The address may be given as `Name <address>`.
+    let address = match from.rsplit_once('<') {
+        Some((_, rest)) => rest.trim_end_matches('>').trim().to_lowercase(),
+        None => from.trim().to_lowercase(),
+    };
+    let domain = match address.rsplit_once('@') {
+        Some((local, domain)) if !local.is_empty() && !domain.is_empty() => domain.to_owned(),
+        _ => {
+            return vec![format!(
+                "CEREAL_FROM_EMAIL_ADDRESS {:?} is not an email address",
+                from
+            )]
+        }
+    };
+    let mut warnings = Vec::new();
+    let sending_domain = env::var("CEREAL_MAILGUN_API_ENDPOINT")
+        .ok()
+        .and_then(|x| sending_domain(&x));
+    if let Some(sending_domain) = sending_domain {
+        if sending_domain.starts_with("sandbox") {
+            warnings.push(format!(
+                "Mailgun domain {} is a sandbox domain, which only sends to its authorized recipients",
+                sending_domain
+            ));
+        }
+        if domain != sending_domain && !domain.ends_with(&format!(".{}", sending_domain)) {
+            warnings.push(format!(
+                "The from address {} is not on the mailgun domain {}, so it may fail verification",
+                address, sending_domain
+            ));
+        }
+    }
+    let local = address.split('@').next().unwrap_or_default();
+    if ["noreply", "no-reply", "donotreply", "do-not-reply"].contains(&local) {
+        warnings.push(format!(
+            "The from address {} looks like a no-reply address, which subscribers are unlikely to have approved",
+            address
+        ));
+    }
+    if !warnings.is_empty() {
+        warnings.push(format!(
+            "Subscribers must add {} to their kindle's approved personal document e-mail list",
+            address
+        ));
+    }
+    warnings
+}
+
+/// Suggests a fix for an error returned by mailgun, from its status and message.
+fn failure_hint(status: StatusCode, message: &str) -> Option<&'static str> {
+    let message = message.to_lowercase();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        Some("check CEREAL_MAILGUN_API_KEY and that it belongs to the endpoint's region")
+    } else if message.contains("sandbox") || message.contains("authorized recipients") {
+        Some("sandbox domains only send to authorized recipients, add the kindle address in mailgun or use a verified domain")
+    } else if status == StatusCode::NOT_FOUND || message.contains("domain not found") {
+        Some("check the domain in CEREAL_MAILGUN_API_ENDPOINT")
+    } else if status == StatusCode::PAYLOAD_TOO_LARGE || message.contains("too large") {
+        Some("the epub is over mailgun's size limit, lower the subscription's chunk size")
+    } else if message.contains("'from' parameter") || message.contains("from parameter") {
+        Some("check CEREAL_FROM_EMAIL_ADDRESS is a valid address on the mailgun domain")
+    } else if message.contains("'to' parameter") || message.contains("to parameter") {
+        Some("check the subscriber's kindle email or email")
+    } else if status == StatusCode::TOO_MANY_REQUESTS {
+        Some("mailgun is rate limiting this account, the delivery will be retried")
+    } else {
+        None
+    }
+}
 
 #[derive(Clone)]
 struct Attachment {
     pub content_type: String,
     pub file_name: String,
-    pub bytes: Vec<u8>,
+    /// Shared rather than copied when the same epub is sent to several recipients.
+    pub bytes: Bytes,
 }
 
 impl std::fmt::Debug for Attachment {
@@ -64,13 +181,14 @@ impl Message {
     }
 }
 
+/// Sends the message, returning the id mailgun's events will refer to it by.
 #[tracing::instrument(
 name = "Sending an email",
 err,
 level = "info"
 skip(message)
 )]
-async fn send_message(message: Message) -> Result<(), Error> {
+async fn send_message(message: Message) -> Result<Option<String>, Error> {
     let client = reqwest::Client::new();
     let mut form = reqwest::multipart::Form::new()
         .text("to", message.to)
@@ -83,28 +201,73 @@ async fn send_message(message: Message) -> Result<(), Error> {
         form = form.text("html", html);
     }
     if let Some(attachment) = message.attachment {
+        let length = attachment.bytes.len() as u64;
         form = form.part(
             "attachment",
-            Part::bytes(attachment.bytes)
+            Part::stream_with_length(attachment.bytes, length)
                 .file_name(attachment.file_name)
                 .mime_str(&attachment.content_type)?,
         );
     }
-    let mailgun_api_key =
-        env::var("CEREAL_MAILGUN_API_KEY").expect("Mailgun API key not provided.");
-    let send_email_response = client
-        .post(env::var("CEREAL_MAILGUN_API_ENDPOINT").unwrap())
-        .basic_auth("api", Some(mailgun_api_key))
-        .multipart(form)
-        .send()
-        .await?;
-    if !send_email_response.status().is_success() {
-        bail!(
-            "Received unsuccessful status code from mailgun: {}",
-            send_email_response.status()
-        );
+    let mailgun_api_key = secret("CEREAL_MAILGUN_API_KEY").expect("Mailgun API key not provided.");
+    let send_email_response = with_trace_context(
+        client
+            .post(env::var("CEREAL_MAILGUN_API_ENDPOINT").unwrap())
+            .basic_auth("api", Some(mailgun_api_key))
+            .multipart(form),
+    )
+    .send()
+    .await?;
+    let status = send_email_response.status();
+    if !status.is_success() {
+        // Mailgun explains errors in a json message, falling back to plain text.
+        let body = send_email_response.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|x| x.get("message")?.as_str().map(String::from))
+            .unwrap_or(body);
+        match failure_hint(status, &message) {
+            Some(hint) => bail!(
+                "Received unsuccessful status code from mailgun: {}: {} (hint: {})",
+                status,
+                message.trim(),
+                hint
+            ),
+            None => bail!(
+                "Received unsuccessful status code from mailgun: {}: {}",
+                status,
+                message.trim()
+            ),
+        }
     };
-    Ok(())
+    UNRECORDED_MESSAGES.fetch_add(1, Ordering::Relaxed);
+    let body: serde_json::Value = send_email_response.json().await.unwrap_or_default();
+    Ok(body
+        .get("id")
+        .and_then(|x| x.as_str())
+        .map(normalize_message_id))
+}
+
+/// Messages this instance sent which haven't been added to the month's usage yet.
+static UNRECORDED_MESSAGES: AtomicI64 = AtomicI64::new(0);
+
+/// Takes the count of messages sent since it was last taken, for adding to the month's usage.
+pub fn take_unrecorded_messages() -> i64 {
+    UNRECORDED_MESSAGES.swap(0, Ordering::Relaxed)
+}
+
+/// Returns messages taken by `take_unrecorded_messages` which couldn't be recorded, so they
+/// are counted next time.
+pub fn restore_unrecorded_messages(messages: i64) {
+    UNRECORDED_MESSAGES.fetch_add(messages, Ordering::Relaxed);
+}
+
+/// Strips the angle brackets mailgun wraps message ids in when sending, but not in events.
+pub fn normalize_message_id(id: &str) -> String {
+    id.trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_owned()
 }
 
 #[tracing::instrument(
@@ -114,15 +277,15 @@ level = "info"
 skip(bytes, email),
 )]
 pub async fn send_epub_file(
-    bytes: &[u8],
+    bytes: Bytes,
     email: &str,
     chapter_title: &str,
     subject: &str,
-) -> Result<(), Error> {
+) -> Result<Option<String>, Error> {
     let attachment = Attachment {
         content_type: "application/epub+zip".into(),
         file_name: sanitize_filename::sanitize(format!("{}.epub", &chapter_title)),
-        bytes: Vec::from(bytes),
+        bytes,
     };
     let message = Message::new(
         email,
@@ -133,3 +296,18 @@ pub async fn send_epub_file(
     );
     send_message(message).await
 }
+
+#[tracing::instrument(
+name = "Sending an html email",
+err,
+level = "info"
+skip(html, email),
+)]
+pub async fn send_html_email(
+    email: &str,
+    subject: &str,
+    html: &str,
+) -> Result<Option<String>, Error> {
+    let message = Message::new(email, subject, None, Some(html), None);
+    send_message(message).await
+}
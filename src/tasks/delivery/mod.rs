@@ -1,181 +1,1068 @@
+mod announcements;
+mod anthology;
+mod apprise;
+mod mail_quota;
 mod mailgun;
 mod pushover;
-use std::time::Duration;
+mod read_later;
+mod sunset;
+mod templates;
+mod wildcard;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use anyhow::anyhow;
-use futures::future::join_all;
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use serde::Serialize;
 use sqlx::{Pool, Sqlite};
-use tokio::time::MissedTickBehavior;
+use tokio::sync::Semaphore;
 use tracing::{info, instrument};
+use uuid::Uuid;
+
+pub use announcements::announcement_loop;
+pub use anthology::{send_due_digests, sync_anthology_subscriptions};
+pub use mail_quota::{mail_usage, MailQuotaState, MailUsage};
+pub use mailgun::{is_kindle_address, normalize_message_id, sender_warnings};
+pub use sunset::{record_subscriber_failure, record_subscriber_success};
+pub use templates::{delivery_names, validate_templates, DeliveryNames, TEMPLATE_VARIABLES};
+pub use wildcard::sync_wildcard_subscriptions;
 
 use crate::{
     error,
+    error::ApiResult,
     models::{
-        Book, BookClient, Chapter, ChapterClient, Subscriber, SubscriberClient, Subscription,
-        SubscriptionClient,
+        max_chunk_size, AnthologySubscription, AnthologySubscriptionClient, BacklogDelivery, Book,
+        BookClient, Chapter, ChapterClient, DeliveryAttempt, DeliveryClient, DeliveryFormat,
+        LeaseClient, ReadLaterService, SentMessage, Subscriber, SubscriberClient, Subscription,
+        SubscriptionClient, VolumeClient, VolumePosition,
+    },
+    tasks::{
+        catch_panic,
+        chapter_body_conversion::{generate_multichapter_epub, sanitize_html},
+        integrity::find_corruption,
+        integrity::verify_chapter,
+        schedule::{LoopTimer, TaskLoop},
+        with_lease,
+    },
+    telemetry::{
+        continue_trace, link_trace, record_delivery_latency, record_loop_duration,
+        record_queue_depth,
     },
-    tasks::chapter_body_conversion::generate_multichapter_epub,
 };
 
+/// How many deliveries may be sent at once, from CEREAL_DELIVERY_CONCURRENCY.
+fn delivery_concurrency() -> usize {
+    env::var("CEREAL_DELIVERY_CONCURRENCY")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .filter(|x| *x > 0)
+        .unwrap_or(4)
+}
+
+/// How long sending one delivery may take before it is abandoned and retried, from
+/// CEREAL_DELIVERY_TIMEOUT_SECS.
+fn delivery_timeout() -> Duration {
+    let secs = env::var("CEREAL_DELIVERY_TIMEOUT_SECS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(5 * 60);
+    Duration::from_secs(secs)
+}
+
+/// The most chapters, and bytes, one delivery may send before an admin must confirm it, so a
+/// provider fixed after a long breakage doesn't flood inboxes with everything it missed.
+#[derive(Debug, Clone, Copy)]
+struct DeliveryCap {
+    chapters: usize,
+    bytes: usize,
+}
+
+impl DeliveryCap {
+    /// Reads CEREAL_DELIVERY_MAX_CHAPTERS and CEREAL_DELIVERY_MAX_BYTES.
+    fn from_env() -> DeliveryCap {
+        let read = |key: &str, default: usize| {
+            env::var(key)
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .filter(|x| *x > 0)
+                .unwrap_or(default)
+        };
+        DeliveryCap {
+            // A subscription's largest chunk is within the cap unless it is set lower.
+            chapters: read("CEREAL_DELIVERY_MAX_CHAPTERS", max_chunk_size() as usize),
+            bytes: read("CEREAL_DELIVERY_MAX_BYTES", 20 * 1024 * 1024),
+        }
+    }
+
+    fn admits(&self, chapters: &[Chapter], format: DeliveryFormat) -> bool {
+        chapters.len() <= self.chapters && delivery_bytes(chapters, format) <= self.bytes
+    }
+}
+
+/// How long paced backlog deliveries wait after each other, from CEREAL_DELIVERY_PACE_SECS.
+fn backlog_pace() -> chrono::Duration {
+    let secs = env::var("CEREAL_DELIVERY_PACE_SECS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(15 * 60);
+    chrono::Duration::seconds(secs)
+}
+
+/// The size of the chapter as it is sent in the format.
+fn chapter_bytes(chapter: &Chapter, format: DeliveryFormat) -> usize {
+    let body = match format {
+        DeliveryFormat::Epub => &chapter.epub,
+        DeliveryFormat::InlineHtml => &chapter.html,
+    };
+    body.as_ref().map_or(0, |x| x.len())
+}
+
+fn delivery_bytes(chapters: &[Chapter], format: DeliveryFormat) -> usize {
+    chapters.iter().map(|x| chapter_bytes(x, format)).sum()
+}
+
+/// How the subscription's confirmed backlog is sent, if the confirmation covers the earliest of
+/// the chapters. Later chapters need confirming again should they exceed the cap.
+fn confirmed_backlog(subscription: &Subscription, chapters: &[Chapter]) -> Option<BacklogDelivery> {
+    let delivery = subscription.backlog_delivery?;
+    let through = subscription.backlog_confirmed_through?;
+    let earliest = chapters.iter().map(|x| x.created_at).min()?;
+    (earliest <= through).then_some(delivery)
+}
+
+/// The chapters to send now, in reading order: all of them if within the cap, the confirmed
+/// ones of a backlog sent at once, or the earliest as many as fit the cap of a paced backlog.
+fn delivery_batch(subscription: &Subscription, chapters: Vec<Chapter>) -> Vec<Chapter> {
+    let cap = DeliveryCap::from_env();
+    if cap.admits(&chapters, subscription.format) {
+        return chapters;
+    }
+    match confirmed_backlog(subscription, &chapters) {
+        Some(BacklogDelivery::All) => chapters
+            .into_iter()
+            .filter(|x| Some(x.created_at) <= subscription.backlog_confirmed_through)
+            .collect(),
+        Some(BacklogDelivery::Paced) => {
+            let mut used = DeliveryCap {
+                chapters: 0,
+                bytes: 0,
+            };
+            take_within_cap(chapters, &cap, subscription.format, &mut used)
+        }
+        None => chapters,
+    }
+}
+
+/// The earliest created of the chapters which fit in what `used` leaves of the cap, in reading
+/// order, adding them to `used`. They are taken in order of creation as delivery progress is the
+/// latest chapter delivered, and chapters created together, as older batches were, are kept
+/// together so progress can't fall between them. The first chapter of a delivery is taken
+/// whatever its size.
+fn take_within_cap(
+    chapters: Vec<Chapter>,
+    cap: &DeliveryCap,
+    format: DeliveryFormat,
+    used: &mut DeliveryCap,
+) -> Vec<Chapter> {
+    let mut taken: Vec<Chapter> = Vec::new();
+    for chapter in chapters.into_iter().sorted_by_key(|x| x.created_at) {
+        let bytes = chapter_bytes(&chapter, format);
+        let fits =
+            used.chapters == 0 || (used.chapters < cap.chapters && used.bytes + bytes <= cap.bytes);
+        let same_batch = taken
+            .last()
+            .is_some_and(|x| x.created_at == chapter.created_at);
+        if !fits && !same_batch {
+            break;
+        }
+        used.chapters += 1;
+        used.bytes += bytes;
+        taken.push(chapter);
+    }
+    taken.sort_by_key(|x| x.order_index);
+    taken
+}
+
+/// The wait before retrying a delivery whose `attempt`th attempt failed, doubling from a
+/// minute up to an hour.
+fn retry_backoff(attempt: i64) -> chrono::Duration {
+    let exponent = attempt.saturating_sub(1).clamp(0, 6) as u32;
+    chrono::Duration::minutes(2_i64.pow(exponent)).min(chrono::Duration::hours(1))
+}
+
+/// A means of reaching a subscriber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Channel {
+    Pushover,
+    KindleEmail,
+    Email,
+    ReadLater,
+    /// An apprise url, sent through the apprise gateway.
+    Notify,
+}
+
+impl Channel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Pushover => "pushover",
+            Channel::KindleEmail => "kindleEmail",
+            Channel::Email => "email",
+            Channel::ReadLater => "readLater",
+            Channel::Notify => "notify",
+        }
+    }
+}
+
+/// The subscriber's channels which are set up and which this instance is configured to send
+/// through.
+pub fn usable_channels(subscriber: &Subscriber) -> Vec<Channel> {
+    let mut channels = Vec::new();
+    let is_set = |x: &Option<String>| x.as_deref().map(|x| !x.trim().is_empty()).unwrap_or(false);
+    if is_set(&subscriber.pushover_key) && pushover::is_configured() {
+        channels.push(Channel::Pushover);
+    }
+    let is_kindle = subscriber
+        .kindle_email
+        .as_deref()
+        .map(is_kindle_address)
+        .unwrap_or(false);
+    if is_set(&subscriber.kindle_email) && is_kindle && mailgun::is_configured() {
+        channels.push(Channel::KindleEmail);
+    }
+    if is_set(&subscriber.email) && mailgun::is_configured() {
+        channels.push(Channel::Email);
+    }
+    if let (Some(service), true) = (
+        subscriber.read_later_service,
+        is_set(&subscriber.read_later_token),
+    ) {
+        if read_later::is_configured(service) {
+            channels.push(Channel::ReadLater);
+        }
+    }
+    if is_set(&subscriber.notify_url) && apprise::is_configured() {
+        channels.push(Channel::Notify);
+    }
+    channels
+}
+
+/// The channels which carry deliveries in the format. Pushover and apprise only announce them,
+/// and read-later services are sent the chapters themselves whatever the format.
+fn format_channels(format: DeliveryFormat) -> &'static [Channel] {
+    match format {
+        DeliveryFormat::Epub => &[
+            Channel::Pushover,
+            Channel::Notify,
+            Channel::KindleEmail,
+            Channel::ReadLater,
+        ],
+        DeliveryFormat::InlineHtml => &[
+            Channel::Pushover,
+            Channel::Notify,
+            Channel::Email,
+            Channel::ReadLater,
+        ],
+    }
+}
+
+/// Explains why the subscriber has no usable channel, or returns None if it has one.
+fn missing_channel_reason(subscriber: &Subscriber) -> Option<String> {
+    if !usable_channels(subscriber).is_empty() {
+        return None;
+    }
+    let mut reasons = Vec::new();
+    match &subscriber.pushover_key {
+        Some(x) if !x.trim().is_empty() => {
+            reasons.push("a pushover key is set but CEREAL_PUSHOVER_TOKEN is not")
+        }
+        _ => reasons.push("no pushover key is set"),
+    }
+    match &subscriber.kindle_email {
+        Some(x) if !x.trim().is_empty() && !is_kindle_address(x) => {
+            reasons.push("the kindle email is not a @kindle.com or @free.kindle.com address")
+        }
+        Some(x) if !x.trim().is_empty() => {
+            reasons.push("a kindle email is set but mailgun is not configured")
+        }
+        _ => reasons.push("no kindle email is set"),
+    }
+    match &subscriber.email {
+        Some(x) if !x.trim().is_empty() => {
+            reasons.push("an email is set but mailgun is not configured")
+        }
+        _ => reasons.push("no email is set"),
+    }
+    match subscriber.read_later_service {
+        Some(ReadLaterService::Pocket) => {
+            reasons.push("pocket is set but CEREAL_POCKET_CONSUMER_KEY is not")
+        }
+        _ => reasons.push("no read-later service is set"),
+    }
+    match &subscriber.notify_url {
+        Some(x) if !x.trim().is_empty() => {
+            reasons.push("a notify url is set but CEREAL_APPRISE_URL is not")
+        }
+        _ => reasons.push("no notify url is set"),
+    }
+    Some(reasons.join(" and "))
+}
+
+/// The subscribers who receive a subscriber's deliveries: the members of a group, or else the
+/// subscriber itself.
+pub async fn recipients(
+    pool: &Pool<Sqlite>,
+    subscriber: &Subscriber,
+) -> ApiResult<Vec<Subscriber>> {
+    let members = SubscriberClient::new(pool)
+        .list_group_members(&subscriber.id)
+        .await?;
+    match members.is_empty() {
+        true => Ok(vec![subscriber.clone()]),
+        false => Ok(members),
+    }
+}
+
+/// Explains why none of the recipients can be reached, or returns None if one can. Paused
+/// subscribers can't be.
+fn unreachable_reason(subscriber: &Subscriber, recipients: &[Subscriber]) -> Option<String> {
+    if let Some(paused_at) = subscriber.paused_at {
+        return Some(format!(
+            "it was paused at {} after repeated delivery failures",
+            paused_at
+        ));
+    }
+    if recipients
+        .iter()
+        .any(|x| x.paused_at.is_none() && !usable_channels(x).is_empty())
+    {
+        return None;
+    }
+    match recipients {
+        [recipient] if recipient.id == subscriber.id => missing_channel_reason(subscriber),
+        _ => Some(String::from(
+            "no unpaused member of the group has a usable channel",
+        )),
+    }
+}
+
+/// Whether a subscriber, or any member of a group, can be reached, and why not if none can.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct ChannelHealth {
+    pub usable: Vec<Channel>,
+    pub problem: Option<String>,
+}
+
+pub fn channel_health(subscriber: &Subscriber, recipients: &[Subscriber]) -> ChannelHealth {
+    let mut usable = Vec::new();
+    for channel in recipients.iter().flat_map(usable_channels) {
+        if !usable.contains(&channel) {
+            usable.push(channel);
+        }
+    }
+    ChannelHealth {
+        usable,
+        problem: unreachable_reason(subscriber, recipients),
+    }
+}
+
+/// A subscription which is not delivered to because its subscriber can't be reached.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct UndeliverableSubscription {
+    #[serde(rename = "subscriptionId")]
+    pub subscription_id: Uuid,
+    #[serde(rename = "subscriberId")]
+    pub subscriber_id: Uuid,
+    #[serde(rename = "bookId")]
+    pub book_id: Uuid,
+    pub reason: String,
+}
+
+pub async fn undeliverable_subscriptions(
+    pool: &Pool<Sqlite>,
+) -> ApiResult<Vec<UndeliverableSubscription>> {
+    let subscription_client = SubscriptionClient::new(pool);
+    let mut undeliverable = Vec::new();
+    for subscriber in SubscriberClient::new(pool).list_subscribers().await? {
+        let recipients = recipients(pool, &subscriber).await?;
+        let reason = match unreachable_reason(&subscriber, &recipients) {
+            Some(x) => x,
+            None => continue,
+        };
+        for subscription in subscription_client
+            .list_subscriptions(&subscriber.id)
+            .await?
+        {
+            undeliverable.push(UndeliverableSubscription {
+                subscription_id: subscription.id,
+                subscriber_id: subscriber.id,
+                book_id: subscription.book_id,
+                reason: reason.clone(),
+            });
+        }
+    }
+    Ok(undeliverable)
+}
+
 pub async fn check_for_ready_delivery_loop(pool: Pool<Sqlite>) {
-    // 10 sec check interval for all chapters.
-    let mut interval = tokio::time::interval(Duration::from_secs(10));
-    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let concurrency = Arc::new(Semaphore::new(delivery_concurrency()));
+    // Deliveries run independently of the loop, so one still being sent must not be picked up
+    // again by the next run.
+    let in_flight: Arc<Mutex<HashSet<Uuid>>> = Default::default();
 
+    let mut timer = LoopTimer::new(TaskLoop::Delivery);
     loop {
-        // First tick completes immediately.
-        interval.tick().await;
-        let mut futures = Vec::new();
+        let started = Instant::now();
+        if let Err(e) = sync_wildcard_subscriptions(&pool).await {
+            error!("Error syncing wildcard subscriptions {}", e);
+        }
+        if let Err(e) = sync_anthology_subscriptions(&pool).await {
+            error!("Error syncing anthology subscriptions {}", e);
+        }
         let deliveries = find_ready_deliveries(&pool).await;
         match deliveries {
             Ok(deliveries) => {
-                for delivery in deliveries {
-                    let future =
-                        deliver_subscription(delivery.0, delivery.1, delivery.2, delivery.3, &pool);
-                    futures.push(future);
+                record_queue_depth(TaskLoop::Delivery.name(), deliveries.len());
+                for ReadyDelivery {
+                    recipients,
+                    subscription,
+                    book,
+                    chapters,
+                } in deliveries
+                {
+                    if !in_flight.lock().unwrap().insert(subscription.id) {
+                        continue;
+                    }
+                    let (pool, concurrency, in_flight) =
+                        (pool.clone(), concurrency.clone(), in_flight.clone());
+                    tokio::spawn(async move {
+                        let _permit = concurrency.acquire_owned().await;
+                        let subscription_id = subscription.id;
+                        let chapter_ids: Vec<Uuid> = chapters.iter().map(|x| x.id).collect();
+                        let lease = format!("delivery:{}", subscription_id);
+                        let work = catch_panic(deliver_subscription(
+                            recipients,
+                            subscription,
+                            book,
+                            chapters,
+                            &pool,
+                        ));
+                        let lease_client = LeaseClient::new(&pool);
+                        let ttl = chrono::Duration::minutes(30);
+                        if let Some(Err(e)) = with_lease(&lease_client, &lease, ttl, work).await {
+                            record_delivery_panic(&pool, &subscription_id, &chapter_ids, &e).await;
+                        }
+                        in_flight.lock().unwrap().remove(&subscription_id);
+                    });
                 }
             }
             Err(e) => error!("Error fetching chapters with empty epub fields {}", e),
         }
-        join_all(futures).await;
+        if let Err(e) = send_due_digests(&pool).await {
+            error!("Error sending anthology digests {}", e);
+        }
+        record_loop_duration(TaskLoop::Delivery.name(), started.elapsed());
+        timer.wait_for_next_run(&pool).await;
     }
 }
 
-#[instrument(skip(pool), ret)]
-async fn find_ready_deliveries(
-    pool: &Pool<Sqlite>,
-) -> anyhow::Result<Vec<(Subscriber, Subscription, Book, Vec<Chapter>)>> {
-    let mut deliveries = Vec::new();
+/// Why a subscription with new chapters is or isn't being delivered to.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum DeliveryDecision {
+    /// Enough chapters have arrived to fill the subscription's chunk.
+    Ready,
+    AwaitingChunk {
+        chapters: usize,
+        #[serde(rename = "chunkSize")]
+        chunk_size: i32,
+    },
+    /// The last attempt failed and is retried once its backoff has passed.
+    RetryBackoff {
+        attempt: i64,
+        error: Option<String>,
+        #[serde(rename = "retryAt")]
+        retry_at: DateTime<Utc>,
+    },
+    NoUsableChannel {
+        reason: String,
+    },
+    /// The next chapter is reserved for early access subscriptions until `deliverAfter`.
+    Embargoed {
+        #[serde(rename = "deliverAfter")]
+        deliver_after: DateTime<Utc>,
+    },
+    /// The chapters are sent in the digest of the subscription's anthology, due at `dueAt`.
+    AwaitingDigest {
+        #[serde(rename = "anthologySubscriptionId")]
+        anthology_subscription_id: Uuid,
+        #[serde(rename = "dueAt")]
+        due_at: DateTime<Utc>,
+    },
+    /// The chapters exceed the delivery cap, and wait for an admin to confirm them through
+    /// /confirmDelivery.
+    AwaitingConfirmation {
+        chapters: usize,
+        bytes: usize,
+        #[serde(rename = "maxChapters")]
+        max_chapters: usize,
+        #[serde(rename = "maxBytes")]
+        max_bytes: usize,
+    },
+    /// A confirmed backlog is being sent in paced deliveries, the next due at `nextAt`.
+    Paced {
+        #[serde(rename = "nextAt")]
+        next_at: DateTime<Utc>,
+    },
+    /// The delivery sends email, which waits until `nextAt` as mailgun's monthly quota is
+    /// approached or used up.
+    MailQuota {
+        state: MailQuotaState,
+        #[serde(rename = "nextAt")]
+        next_at: DateTime<Utc>,
+    },
+}
+
+struct Candidate {
+    subscriber: Subscriber,
+    recipients: Vec<Subscriber>,
+    subscription: Subscription,
+    book: Book,
+    chapters: Vec<Chapter>,
+    decision: DeliveryDecision,
+}
+
+/// Finds the subscriptions with new chapters and decides whether each should be delivered to,
+/// querying once per book for its subscriptions and its new chapters.
+async fn evaluate_deliveries(pool: &Pool<Sqlite>) -> ApiResult<Vec<Candidate>> {
+    let mut candidates = Vec::new();
 
-    let book_client = BookClient::new(pool);
     let chapter_client = ChapterClient::new(pool);
-    let subscriber_client = SubscriberClient::new(pool);
     let subscription_client = SubscriptionClient::new(pool);
+    let delivery_client = DeliveryClient::new(pool);
+    let usage = mail_usage(pool).await?;
 
-    let subscribers = subscriber_client.list_subscribers().await?;
-    for subscriber in subscribers {
+    let subscribers: HashMap<Uuid, Subscriber> = SubscriberClient::new(pool)
+        .list_subscribers()
+        .await?
+        .into_iter()
+        .map(|x| (x.id, x))
+        .collect();
+    let mut groups: HashMap<Uuid, Vec<Subscriber>> = HashMap::new();
+    for (group_id, member_id) in SubscriberClient::new(pool).list_group_memberships().await? {
+        if let Some(member) = subscribers.get(&member_id) {
+            groups.entry(group_id).or_default().push(member.clone());
+        }
+    }
+    let anthologies: HashMap<Uuid, AnthologySubscription> = AnthologySubscriptionClient::new(pool)
+        .list_anthology_subscriptions(None)
+        .await?
+        .into_iter()
+        .map(|x| (x.id, x))
+        .collect();
+
+    for book in BookClient::new(pool).list_books().await? {
         let subscriptions = subscription_client
-            .list_subscriptions(&subscriber.id)
+            .list_subscriptions_for_book(&book.id)
             .await?;
+        if subscriptions.is_empty() {
+            continue;
+        }
+        // Fetch from the least recently delivered chapter, so one query covers every
+        // subscription to the book.
+        let oldest_delivered = subscriptions
+            .iter()
+            .map(|x| x.last_delivered_chapter_created_at)
+            .min()
+            .flatten();
+        let new_chapters = chapter_client
+            .list_chapters_with_epub(&book.id, oldest_delivered.as_ref())
+            .await?;
+        if new_chapters.is_empty() {
+            continue;
+        }
+
         for subscription in subscriptions {
-            let book = book_client
-                .get_book(&subscription.book_id)
-                .await?
-                .ok_or_else(|| anyhow!("Book not found"))?;
-            let chapters = chapter_client
-                .list_chapters_with_epub(
-                    &book.id,
-                    subscription.last_delivered_chapter_created_at.as_ref(),
-                )
-                .await?;
-            if chapters.len() >= subscription.chunk_size as usize {
-                deliveries.push((subscriber.clone(), subscription, book, chapters));
+            let subscriber = match subscribers.get(&subscription.subscriber_id) {
+                Some(x) => x,
+                None => continue,
+            };
+            let undelivered: Vec<Chapter> = new_chapters
+                .iter()
+                .filter(|x| {
+                    subscription
+                        .last_delivered_chapter_created_at
+                        .map(|last| x.created_at > last)
+                        .unwrap_or(true)
+                })
+                .cloned()
+                .collect();
+            if undelivered.is_empty() {
+                continue;
             }
+            let chapters = released_chapters(&subscription, &undelivered);
+            let recipients = groups
+                .get(&subscriber.id)
+                .cloned()
+                .unwrap_or_else(|| vec![subscriber.clone()]);
+            let anthology = subscription
+                .anthology_subscription_id
+                .and_then(|x| anthologies.get(&x));
+            let decision = match (embargo(&undelivered, &chapters), anthology) {
+                (Some(decision), _) => decision,
+                (None, Some(anthology)) => awaiting_digest(anthology),
+                (None, None) => {
+                    decide_delivery(
+                        &delivery_client,
+                        &usage,
+                        subscriber,
+                        &recipients,
+                        &subscription,
+                        &chapters,
+                    )
+                    .await?
+                }
+            };
+            candidates.push(Candidate {
+                subscriber: subscriber.clone(),
+                recipients,
+                subscription,
+                book: book.clone(),
+                chapters,
+                decision,
+            });
         }
     }
 
-    Ok(deliveries)
+    Ok(candidates)
 }
 
-async fn deliver_subscription(
-    subscriber: Subscriber,
+/// The undelivered chapters the subscription may be sent now, in reading order. Subscriptions
+/// without early access stop at the first embargoed chapter, as delivery progress only moves
+/// forward and would otherwise skip past it.
+fn released_chapters(subscription: &Subscription, chapters: &[Chapter]) -> Vec<Chapter> {
+    let now = Utc::now();
+    chapters
+        .iter()
+        .sorted_by_key(|x| x.created_at)
+        .take_while(|x| {
+            subscription.early_access || !matches!(x.deliver_after, Some(after) if after > now)
+        })
+        .sorted_by_key(|x| x.order_index)
+        .cloned()
+        .collect()
+}
+
+/// The decision for a subscription whose next chapter is embargoed, if none of its chapters
+/// were released.
+fn embargo(undelivered: &[Chapter], released: &[Chapter]) -> Option<DeliveryDecision> {
+    if !released.is_empty() {
+        return None;
+    }
+    let deliver_after = undelivered
+        .iter()
+        .min_by_key(|x| x.created_at)
+        .and_then(|x| x.deliver_after)?;
+    Some(DeliveryDecision::Embargoed { deliver_after })
+}
+
+fn awaiting_digest(anthology: &AnthologySubscription) -> DeliveryDecision {
+    DeliveryDecision::AwaitingDigest {
+        anthology_subscription_id: anthology.id,
+        due_at: anthology.due_at(),
+    }
+}
+
+async fn decide_delivery(
+    delivery_client: &DeliveryClient,
+    usage: &MailUsage,
+    subscriber: &Subscriber,
+    recipients: &[Subscriber],
+    subscription: &Subscription,
+    chapters: &[Chapter],
+) -> ApiResult<DeliveryDecision> {
+    // Chapters stay undelivered, rather than being skipped, until the subscriber can be
+    // reached.
+    if let Some(reason) = unreachable_reason(subscriber, recipients) {
+        return Ok(DeliveryDecision::NoUsableChannel { reason });
+    }
+    let reachable = reachable_recipients(recipients, subscription.format);
+    if reachable.is_empty() {
+        return Ok(DeliveryDecision::NoUsableChannel {
+            reason: format!(
+                "no recipient has a channel for {} deliveries",
+                subscription.format.as_str()
+            ),
+        });
+    }
+    // Nothing is sent before the first chapter is released, whatever the chunk size.
+    if chapters.is_empty() || chapters.len() < subscription.effective_chunk_size() {
+        return Ok(DeliveryDecision::AwaitingChunk {
+            chapters: chapters.len(),
+            chunk_size: subscription.effective_chunk_size() as i32,
+        });
+    }
+    let latest_attempt = delivery_client.latest_attempt(&subscription.id).await?;
+    let cap = DeliveryCap::from_env();
+    let backlog = !cap.admits(chapters, subscription.format);
+    if backlog {
+        match confirmed_backlog(subscription, chapters) {
+            None => {
+                return Ok(DeliveryDecision::AwaitingConfirmation {
+                    chapters: chapters.len(),
+                    bytes: delivery_bytes(chapters, subscription.format),
+                    max_chapters: cap.chapters,
+                    max_bytes: cap.bytes,
+                })
+            }
+            Some(BacklogDelivery::All) => {}
+            Some(BacklogDelivery::Paced) => {
+                if let Some(attempt) = latest_attempt.as_ref().filter(|x| x.succeeded) {
+                    let next_at = attempt.attempted_at + backlog_pace();
+                    if next_at > Utc::now() {
+                        return Ok(DeliveryDecision::Paced { next_at });
+                    }
+                }
+            }
+        }
+    }
+    if sends_email(&reachable) {
+        let last_sent_at = latest_attempt
+            .as_ref()
+            .filter(|x| x.succeeded)
+            .map(|x| x.attempted_at);
+        if let Some(next_at) = usage.deferral(backlog, last_sent_at) {
+            return Ok(DeliveryDecision::MailQuota {
+                state: usage.state,
+                next_at,
+            });
+        }
+    }
+    // A failed delivery waits out its backoff before being retried.
+    if let Some(attempt) = latest_attempt {
+        if let (false, Some(retry_at)) = (attempt.succeeded, attempt.retry_at) {
+            if retry_at > Utc::now() {
+                return Ok(DeliveryDecision::RetryBackoff {
+                    attempt: attempt.attempt,
+                    error: attempt.error,
+                    retry_at,
+                });
+            }
+        }
+    }
+    Ok(DeliveryDecision::Ready)
+}
+
+/// Decides whether the subscription would be delivered to, or returns None if it has no
+/// undelivered converted chapters.
+pub async fn delivery_decision(
+    pool: &Pool<Sqlite>,
+    subscriber: &Subscriber,
+    subscription: &Subscription,
+) -> ApiResult<Option<DeliveryDecision>> {
+    let chapters = ChapterClient::new(pool)
+        .list_chapters_with_epub(
+            &subscription.book_id,
+            subscription.last_delivered_chapter_created_at.as_ref(),
+        )
+        .await?;
+    if chapters.is_empty() {
+        return Ok(None);
+    }
+    let released = released_chapters(subscription, &chapters);
+    if let Some(decision) = embargo(&chapters, &released) {
+        return Ok(Some(decision));
+    }
+    if let Some(anthology_subscription_id) = subscription.anthology_subscription_id {
+        if let Some(anthology) = AnthologySubscriptionClient::new(pool)
+            .get_anthology_subscription(&anthology_subscription_id)
+            .await?
+        {
+            return Ok(Some(awaiting_digest(&anthology)));
+        }
+    }
+    let chapters = released;
+    let recipients = recipients(pool, subscriber).await?;
+    let decision = decide_delivery(
+        &DeliveryClient::new(pool),
+        &mail_usage(pool).await?,
+        subscriber,
+        &recipients,
+        subscription,
+        &chapters,
+    )
+    .await?;
+    Ok(Some(decision))
+}
+
+/// A batch of chapters ready to be sent to a subscription's recipients.
+#[derive(Debug)]
+struct ReadyDelivery {
+    recipients: Vec<Subscriber>,
     subscription: Subscription,
     book: Book,
     chapters: Vec<Chapter>,
-    pool: &Pool<Sqlite>,
-) {
-    let pushover_token = subscriber.pushover_key.clone();
+}
 
-    if let Some(pushover_token) = pushover_token {
-        let message = match chapters.len() {
-            1 => format!(
-                "Delivered new chapter for {}: {}",
-                book.title, chapters[0].title
+#[instrument(skip(pool), ret)]
+async fn find_ready_deliveries(pool: &Pool<Sqlite>) -> ApiResult<Vec<ReadyDelivery>> {
+    let mut deliveries = Vec::new();
+    for candidate in evaluate_deliveries(pool).await? {
+        match candidate.decision {
+            DeliveryDecision::Ready => {
+                let chapters = delivery_batch(&candidate.subscription, candidate.chapters);
+                deliveries.push(ReadyDelivery {
+                    recipients: candidate.recipients,
+                    subscription: candidate.subscription,
+                    book: candidate.book,
+                    chapters,
+                })
+            }
+            DeliveryDecision::AwaitingConfirmation { chapters, .. } => info!(
+                "Not delivering {} chapters to subscription {} until they are confirmed",
+                chapters, &candidate.subscription.id
             ),
-            n => format!(
-                "Delivered new chapters for {}. {} through {}",
-                book.title,
-                chapters[0].title,
-                chapters[n - 1].title
+            DeliveryDecision::NoUsableChannel { reason } => info!(
+                "Not delivering to subscriber {} as {}",
+                &candidate.subscriber.id, reason
             ),
-        };
-        match pushover::send_message(&pushover_token, &message).await {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to send pushover message to subscriber {:?} for book {:?} and chapters {:?}: {}", subscriber, book, chapters, e);
-                return;
+            DeliveryDecision::MailQuota { next_at, .. } => info!(
+                "Not delivering to subscription {} until {} to keep within the mailgun quota",
+                &candidate.subscription.id, next_at
+            ),
+            _ => {}
+        }
+    }
+    Ok(deliveries)
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct PendingChapter {
+    pub id: Uuid,
+    pub title: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// A subscription with undelivered chapters, and whether the next run would deliver them.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct PendingDelivery {
+    #[serde(rename = "subscriptionId")]
+    pub subscription_id: Uuid,
+    #[serde(rename = "subscriberId")]
+    pub subscriber_id: Uuid,
+    #[serde(rename = "subscriberName")]
+    pub subscriber_name: String,
+    #[serde(rename = "bookId")]
+    pub book_id: Uuid,
+    #[serde(rename = "bookTitle")]
+    pub book_title: String,
+    pub chapters: Vec<PendingChapter>,
+    pub channels: Vec<Channel>,
+    pub decision: DeliveryDecision,
+}
+
+/// Runs the delivery checks without sending anything, for finding out why a chapter hasn't
+/// arrived.
+pub async fn pending_deliveries(pool: &Pool<Sqlite>) -> ApiResult<Vec<PendingDelivery>> {
+    let candidates = evaluate_deliveries(pool).await?;
+    Ok(candidates
+        .into_iter()
+        .map(|x| PendingDelivery {
+            subscription_id: x.subscription.id,
+            subscriber_id: x.subscriber.id,
+            channels: channel_health(&x.subscriber, &x.recipients).usable,
+            subscriber_name: x.subscriber.name,
+            book_id: x.book.id,
+            book_title: x.book.title,
+            chapters: x
+                .chapters
+                .into_iter()
+                .map(|x| PendingChapter {
+                    id: x.id,
+                    title: x.title,
+                    created_at: x.created_at,
+                })
+                .collect(),
+            decision: x.decision,
+        })
+        .collect())
+}
+
+#[instrument(
+    skip_all,
+    fields(
+        subscription.id = %subscription.id,
+        subscriber.id = %subscription.subscriber_id,
+        book.id = %book.id,
+        chapter.ids = ?chapters.iter().map(|x| x.id).collect::<Vec<_>>(),
+        provider = book.metadata.provider_name(),
+    )
+)]
+async fn deliver_subscription(
+    recipients: Vec<Subscriber>,
+    subscription: Subscription,
+    book: Book,
+    mut chapters: Vec<Chapter>,
+    pool: &Pool<Sqlite>,
+) {
+    // A single chapter's delivery joins the trace of its discovery, otherwise each is linked.
+    match chapters.as_slice() {
+        [chapter] => continue_trace(chapter.trace_context.as_deref()),
+        chapters => {
+            for chapter in chapters {
+                link_trace(chapter.trace_context.as_deref());
             }
-        };
+        }
     }
 
-    if let Some(kindle_email) = subscriber.kindle_email.clone() {
-        match chapters.len() {
-            1 => {
-                let subject = format!("New Chapter of {}: {}", book.title, chapters[0].title);
-                let result = mailgun::send_epub_file(
-                    chapters[0]
-                        .epub
-                        .as_ref()
-                        .expect("Chapter did not have epub body."),
-                    &kindle_email,
-                    &chapters[0].title,
-                    &subject,
-                )
-                .await;
-                match result {
-                    Ok(_) => info!("Successfully sent kindle email for chapters {:?}", chapters),
-                    Err(e) => {
-                        error!(
-                            "Failed to send kindle email for chapters {:?}: {}",
-                            chapters, e
-                        );
-                        return;
-                    }
-                }
+    // Another instance may have delivered these chapters since they were found.
+    match SubscriptionClient::new(pool)
+        .get_subscription(subscription.id)
+        .await
+    {
+        Ok(Some(current))
+            if current.last_delivered_chapter_id == subscription.last_delivered_chapter_id => {}
+        Ok(_) => {
+            info!(
+                "Subscription {} changed since deliveries were found, skipping",
+                &subscription.id
+            );
+            return;
+        }
+        Err(e) => {
+            error!(
+                "A DB error occurred rechecking subscription {}: {}",
+                &subscription.id, e
+            );
+            return;
+        }
+    }
+
+    // Corrupted epubs are dropped for regeneration, and the chapters delivered once they return.
+    let corrupted: Vec<&Chapter> = chapters
+        .iter()
+        .filter(|x| find_corruption(x).any())
+        .collect();
+    if !corrupted.is_empty() {
+        for chapter in corrupted {
+            if let Err(e) = verify_chapter(pool, chapter.clone()).await {
+                error!(
+                    "A DB error occurred flagging chapter {} for regeneration: {}",
+                    &chapter.id, e
+                );
             }
-            x => {
-                let cover_title = format!(
-                    "{}: {} through {}",
-                    book.title,
-                    chapters[0].title,
-                    chapters[x - 1].title
+        }
+        return;
+    }
+
+    let delivery_client = DeliveryClient::new(pool);
+    let chapter_ids: Vec<Uuid> = chapters.iter().map(|x| x.id).collect();
+    let (attempt, mut delivered) = match delivery_client.latest_attempt(&subscription.id).await {
+        Ok(latest) => next_attempt(latest, &chapter_ids),
+        Err(e) => {
+            error!(
+                "A DB error occurred reading delivery attempts for subscription {}: {}",
+                &subscription.id, e
+            );
+            return;
+        }
+    };
+    // Members of a group which an earlier attempt reached aren't sent the chapters again.
+    let recipients: Vec<Subscriber> = recipients
+        .into_iter()
+        .filter(|x| !delivered.contains(&x.id))
+        .collect();
+
+    // A multichapter epub is shelved under the volume of its first chapter.
+    let first_position = match chapters.iter().min_by_key(|x| x.order_index) {
+        Some(first) => match VolumeClient::new(pool)
+            .chapter_position(&book.id, &first.id)
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                error!(
+                    "A DB error occurred reading the volume of chapter {}: {}",
+                    &first.id, e
                 );
-                let bytes = generate_multichapter_epub(&cover_title, &chapters, &book).await;
-                let bytes = match bytes {
-                    Ok(x) => x,
-                    Err(e) => {
-                        error!(
-                            "Failed to create multichapter epub for chapters {:?}: {}",
-                            chapters, e
-                        );
-                        return;
-                    }
-                };
-                let subject = format!(
-                    "{x} New Chapters of {}: {} through {}",
-                    book.title,
-                    chapters[0].title,
-                    chapters[x - 1].title
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let timeout = delivery_timeout();
+    let outcome = match recipients.is_empty() {
+        // Every recipient left was reached by an earlier attempt.
+        true => SendOutcome::default(),
+        false => match tokio::time::timeout(
+            timeout,
+            send_delivery(
+                &recipients,
+                &book,
+                &mut chapters,
+                first_position.as_ref(),
+                subscription.format,
+            ),
+        )
+        .await
+        {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(e)) => SendOutcome::failed(subscription.subscriber_id, e),
+            Err(_) => SendOutcome::failed(
+                subscription.subscriber_id,
+                anyhow!("Delivery timed out after {:?}", timeout),
+            ),
+        },
+    };
+    delivered.extend(&outcome.delivered);
+    let failure = outcome.failure();
+    let retry_at = failure
+        .as_ref()
+        .map(|_| Utc::now() + retry_backoff(attempt));
+    let recorded = delivery_client
+        .record_attempt(
+            &subscription.id,
+            &chapter_ids,
+            attempt,
+            failure.as_deref(),
+            retry_at.as_ref(),
+            &delivered,
+        )
+        .await;
+    match recorded {
+        Ok(attempt) => {
+            if let Err(e) = delivery_client
+                .record_receipts(&attempt.id, &outcome.sent)
+                .await
+            {
+                error!(
+                    "A DB error occurred recording receipts for subscription {}: {}",
+                    &subscription.id, e
                 );
-                let result = mailgun::send_epub_file(
-                    &bytes,
-                    &kindle_email,
-                    &format!("{} through {}", chapters[0].title, chapters[x - 1].title),
-                    &subject,
-                )
-                .await;
-                match result {
-                    Ok(_) => info!("Successfully sent kindle email for chapters {:?}", chapters),
-                    Err(e) => {
-                        error!(
-                            "Failed to send kindle email for chapters {:?}: {}",
-                            chapters, e
-                        );
-                        return;
-                    }
-                }
             }
         }
+        Err(e) => error!(
+            "A DB error occurred recording delivery for subscription {}: {}",
+            &subscription.id, e
+        ),
+    }
+    outcome
+        .record_recipient_health(pool, &subscription.subscriber_id)
+        .await;
+    if let (Some(message), Some(retry_at)) = (&failure, retry_at) {
+        error!(
+            "Delivery attempt {} for subscription {} failed, retrying at {}: {}",
+            attempt, &subscription.id, retry_at, message
+        );
+        return;
     }
 
     let subscription_client = SubscriptionClient::new(pool);
@@ -197,4 +1084,331 @@ async fn deliver_subscription(
             &subscription.id, latest_chapter, e
         ),
     }
+
+    match delivery_client
+        .record_deliveries(&subscription.id, &chapters)
+        .await
+    {
+        Ok(deliveries) => {
+            for delivery in deliveries {
+                let latency = delivery.latency().to_std().unwrap_or_default();
+                record_delivery_latency(book.metadata.provider_name(), latency);
+            }
+        }
+        Err(e) => error!(
+            "A DB error occurred recording deliveries for subscription {}: {}",
+            &subscription.id, e
+        ),
+    }
+}
+
+/// The number of the next attempt at delivering the chapters, and the recipients which a failed
+/// attempt at the same chapters already reached.
+fn next_attempt(latest: Option<DeliveryAttempt>, chapter_ids: &[Uuid]) -> (i64, Vec<Uuid>) {
+    match latest {
+        Some(latest) if !latest.succeeded => {
+            let delivered = match latest.chapter_ids == chapter_ids {
+                true => latest.delivered_recipient_ids,
+                false => Vec::new(),
+            };
+            (latest.attempt + 1, delivered)
+        }
+        _ => (1, Vec::new()),
+    }
+}
+
+/// Records a delivery which panicked as a failed attempt at sending its chapters, so that it is
+/// retried after a backoff like any other failure. It isn't counted against the subscriber.
+async fn record_delivery_panic(
+    pool: &Pool<Sqlite>,
+    subscription_id: &Uuid,
+    chapter_ids: &[Uuid],
+    error: &anyhow::Error,
+) {
+    let delivery_client = DeliveryClient::new(pool);
+    let (attempt, delivered) = match delivery_client.latest_attempt(subscription_id).await {
+        Ok(latest) => next_attempt(latest, chapter_ids),
+        Err(e) => {
+            error!(
+                "A DB error occurred reading delivery attempts for subscription {}: {}",
+                subscription_id, e
+            );
+            return;
+        }
+    };
+    let retry_at = Utc::now() + retry_backoff(attempt);
+    let message = format!("{:#}", error);
+    error!(
+        "Delivery attempt {} for subscription {} of chapters {:?} panicked, retrying at {}: {}",
+        attempt, subscription_id, chapter_ids, retry_at, message
+    );
+    let recorded = delivery_client
+        .record_attempt(
+            subscription_id,
+            chapter_ids,
+            attempt,
+            Some(&message),
+            Some(&retry_at),
+            &delivered,
+        )
+        .await;
+    if let Err(e) = recorded {
+        error!(
+            "A DB error occurred recording failed delivery for subscription {}: {}",
+            subscription_id, e
+        );
+    }
+}
+
+/// The unpaused recipients with a usable channel for the format, and those channels.
+fn reachable_recipients(
+    recipients: &[Subscriber],
+    format: DeliveryFormat,
+) -> Vec<(&Subscriber, Vec<Channel>)> {
+    let carriers = format_channels(format);
+    recipients
+        .iter()
+        .filter(|x| x.paused_at.is_none())
+        .map(|x| {
+            let channels = usable_channels(x)
+                .into_iter()
+                .filter(|x| carriers.contains(x))
+                .collect();
+            (x, channels)
+        })
+        .filter(|(_, channels): &(_, Vec<Channel>)| !channels.is_empty())
+        .collect()
+}
+
+/// The chapters' sanitized html, each under a heading with its title, for the body of an
+/// inline delivery. Chapters must be in reading order.
+fn inline_html(chapters: &[Chapter]) -> String {
+    let html: String = chapters
+        .iter()
+        .map(|x| {
+            let body = x.html.as_deref().unwrap_or_default();
+            format!("<h1>{}</h1>{}", x.title, String::from_utf8_lossy(body))
+        })
+        .collect();
+    sanitize_html(&html)
+}
+
+/// Whether any of the channels send through mailgun, which counts towards its quota.
+fn sends_email(reachable: &[(&Subscriber, Vec<Channel>)]) -> bool {
+    reachable.iter().any(|(_, channels)| {
+        channels.contains(&Channel::KindleEmail) || channels.contains(&Channel::Email)
+    })
+}
+
+/// Whether any of the recipients is sent the epub, which need only be generated if so.
+fn needs_epub(reachable: &[(&Subscriber, Vec<Channel>)]) -> bool {
+    reachable
+        .iter()
+        .any(|(_, channels)| channels.contains(&Channel::KindleEmail))
+}
+
+/// Sends the chapters to each usable channel of each recipient, failing on the first which
+/// fails. A multichapter epub is only generated once however many recipients there are, and a
+/// single chapter's epub is taken from the chapter rather than copied, leaving it without one.
+async fn send_delivery(
+    recipients: &[Subscriber],
+    book: &Book,
+    chapters: &mut [Chapter],
+    first_position: Option<&VolumePosition>,
+    format: DeliveryFormat,
+) -> anyhow::Result<SendOutcome> {
+    let reachable = reachable_recipients(recipients, format);
+    if reachable.is_empty() {
+        return Err(anyhow!("No recipient has a usable delivery channel"));
+    }
+
+    let names = delivery_names(book, chapters);
+    let epub = match (needs_epub(&reachable), chapters.len()) {
+        (false, _) => None,
+        (true, 1) => Some(Bytes::from(
+            chapters[0]
+                .epub
+                .take()
+                .expect("Chapter did not have epub body."),
+        )),
+        (true, x) => {
+            let cover_title = format!(
+                "{}: {} through {}",
+                book.title,
+                chapters[0].title,
+                chapters[x - 1].title
+            );
+            let bytes = generate_multichapter_epub(&cover_title, chapters, book, first_position)
+                .await
+                .context("Failed to create multichapter epub")?;
+            Some(Bytes::from(bytes))
+        }
+    };
+    let html = match format {
+        DeliveryFormat::InlineHtml => Some(inline_html(chapters)),
+        DeliveryFormat::Epub => None,
+    };
+    let chapters: Vec<&Chapter> = chapters.iter().collect();
+    Ok(send_to_recipients(
+        &reachable,
+        &names,
+        &chapters,
+        epub.as_ref(),
+        html.as_deref(),
+    )
+    .await)
+}
+
+/// What came of sending a delivery to each of its recipients.
+#[derive(Debug, Default)]
+struct SendOutcome {
+    /// Messages sent through channels which report whether they arrive.
+    sent: Vec<SentMessage>,
+    /// The recipients sent the delivery through each of their usable channels.
+    delivered: Vec<Uuid>,
+    /// The recipients a channel failed for, and how.
+    failed: Vec<(Uuid, anyhow::Error)>,
+}
+
+impl SendOutcome {
+    /// A delivery which failed before reaching any recipient.
+    fn failed(subscriber_id: Uuid, error: anyhow::Error) -> Self {
+        Self {
+            failed: vec![(subscriber_id, error)],
+            ..Default::default()
+        }
+    }
+
+    /// Describes every failure, or returns None if every recipient was reached.
+    fn failure(&self) -> Option<String> {
+        match self.failed.is_empty() {
+            true => None,
+            false => Some(
+                self.failed
+                    .iter()
+                    .map(|(_, e)| format!("{:#}", e))
+                    .join("; "),
+            ),
+        }
+    }
+
+    /// Counts each failure against the recipient it failed for, rather than the group the
+    /// delivery was for, and clears the failures of the recipients reached. The subscriber the
+    /// delivery was for is cleared too once nothing failed.
+    async fn record_recipient_health(&self, pool: &Pool<Sqlite>, subscriber_id: &Uuid) {
+        for (recipient_id, e) in &self.failed {
+            record_subscriber_failure(pool, recipient_id, &format!("{:#}", e)).await;
+        }
+        for recipient_id in &self.delivered {
+            record_subscriber_success(pool, recipient_id).await;
+        }
+        if self.failed.is_empty() && !self.delivered.contains(subscriber_id) {
+            record_subscriber_success(pool, subscriber_id).await;
+        }
+    }
+}
+
+/// Sends the notification, the epub to kindles, the inline html to email addresses and the
+/// chapters to read-later services, through each usable channel of each recipient. A recipient
+/// whose channel fails doesn't stop the rest being sent to.
+async fn send_to_recipients(
+    reachable: &[(&Subscriber, Vec<Channel>)],
+    names: &DeliveryNames,
+    chapters: &[&Chapter],
+    epub: Option<&Bytes>,
+    html: Option<&str>,
+) -> SendOutcome {
+    let mut outcome = SendOutcome::default();
+    for (subscriber, channels) in reachable {
+        let result = send_to_recipient(
+            subscriber,
+            channels,
+            names,
+            chapters,
+            epub,
+            html,
+            &mut outcome.sent,
+        )
+        .await;
+        match result {
+            Ok(()) => outcome.delivered.push(subscriber.id),
+            Err(e) => outcome.failed.push((subscriber.id, e)),
+        }
+    }
+    outcome
+}
+
+/// Sends to each of the recipient's usable channels, failing on the first which fails. Messages
+/// sent through channels which report whether they arrive are added to `sent`.
+async fn send_to_recipient(
+    subscriber: &Subscriber,
+    channels: &[Channel],
+    names: &DeliveryNames,
+    chapters: &[&Chapter],
+    epub: Option<&Bytes>,
+    html: Option<&str>,
+    sent: &mut Vec<SentMessage>,
+) -> anyhow::Result<()> {
+    let mut receipt = |channel: Channel, message_id: Option<String>| {
+        if let Some(message_id) = message_id {
+            sent.push(SentMessage {
+                subscriber_id: subscriber.id,
+                channel: channel.as_str().to_owned(),
+                message_id,
+            });
+        }
+    };
+    if let (Some(pushover_token), true) = (
+        &subscriber.pushover_key,
+        channels.contains(&Channel::Pushover),
+    ) {
+        pushover::send_message(pushover_token, &names.notification)
+            .await
+            .with_context(|| format!("Failed to send pushover message to {}", subscriber.id))?;
+    }
+    if let (Some(notify_url), true) = (&subscriber.notify_url, channels.contains(&Channel::Notify))
+    {
+        apprise::send_notification(notify_url, &names.subject, &names.notification)
+            .await
+            .with_context(|| format!("Failed to send apprise notification to {}", subscriber.id))?;
+    }
+    if let (Some(kindle_email), Some(epub)) = (
+        &subscriber.kindle_email,
+        epub.filter(|_| channels.contains(&Channel::KindleEmail)),
+    ) {
+        let message_id =
+            mailgun::send_epub_file(epub.clone(), kindle_email, &names.file_name, &names.subject)
+                .await
+                .with_context(|| format!("Failed to send kindle email to {}", subscriber.id))?;
+        receipt(Channel::KindleEmail, message_id);
+        info!(
+            "Successfully sent kindle email to {} for {:?}",
+            subscriber.id, names.file_name
+        );
+    }
+    if let (Some(email), Some(html)) = (
+        &subscriber.email,
+        html.filter(|_| channels.contains(&Channel::Email)),
+    ) {
+        let message_id = mailgun::send_html_email(email, &names.subject, html)
+            .await
+            .with_context(|| format!("Failed to send email to {}", subscriber.id))?;
+        receipt(Channel::Email, message_id);
+    }
+    if let (Some(service), Some(token), true) = (
+        subscriber.read_later_service,
+        &subscriber.read_later_token,
+        channels.contains(&Channel::ReadLater),
+    ) {
+        read_later::save_chapters(service, token, chapters)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to save chapters to {} for {}",
+                    service.as_str(),
+                    subscriber.id
+                )
+            })?;
+    }
+    Ok(())
 }
@@ -1,19 +1,30 @@
 use anyhow::Result;
-use std::{collections::HashMap, env};
+use std::collections::HashMap;
+use tracing::instrument;
 
+use crate::{config::secret, telemetry::with_trace_context};
+
+/// Whether CEREAL_PUSHOVER_TOKEN is set, without which no messages can be sent.
+pub fn is_configured() -> bool {
+    secret("CEREAL_PUSHOVER_TOKEN").is_some()
+}
+
+#[instrument(level = "info", err, skip(user_code))]
 pub async fn send_message(user_code: &str, message: &str) -> Result<()> {
     let application_key =
-        env::var("CEREAL_PUSHOVER_TOKEN").expect("Pushover app token not provided.");
+        secret("CEREAL_PUSHOVER_TOKEN").expect("Pushover app token not provided.");
     let client = reqwest::Client::default();
     let mut map = HashMap::new();
     map.insert("token", application_key);
     map.insert("user", user_code.into());
     map.insert("message", message.into());
-    let _response = client
-        .post("https://api.pushover.net/1/messages.json")
-        .json(&map)
-        .send()
-        .await?
-        .error_for_status()?;
+    let _response = with_trace_context(
+        client
+            .post("https://api.pushover.net/1/messages.json")
+            .json(&map),
+    )
+    .send()
+    .await?
+    .error_for_status()?;
     Ok(())
 }
@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use tracing::{instrument, warn};
+
+use crate::{
+    config::secret,
+    models::{Chapter, ReadLaterService},
+    tasks::chapter_body_conversion::sanitize_html,
+    telemetry::with_trace_context,
+};
+
+/// Whether chapters can be saved to the service. Pocket only accepts tokens issued to an app,
+/// whose key is CEREAL_POCKET_CONSUMER_KEY.
+pub fn is_configured(service: ReadLaterService) -> bool {
+    match service {
+        ReadLaterService::Pocket => secret("CEREAL_POCKET_CONSUMER_KEY").is_some(),
+        ReadLaterService::Instapaper | ReadLaterService::ReadwiseReader => true,
+    }
+}
+
+/// Saves each chapter to the service, in reading order so they are listed in it in order.
+/// Chapters without a url are skipped, as every service files saves by their url.
+#[instrument(level = "info", err, skip(token, chapters), fields(chapters = chapters.len()))]
+pub async fn save_chapters(
+    service: ReadLaterService,
+    token: &str,
+    chapters: &[&Chapter],
+) -> Result<()> {
+    for chapter in chapters {
+        let url = match chapter.metadata.url() {
+            Some(x) => x,
+            None => {
+                warn!(
+                    "Not saving chapter {} to {} as it has no url",
+                    chapter.id,
+                    service.as_str()
+                );
+                continue;
+            }
+        };
+        match service {
+            ReadLaterService::Instapaper => save_to_instapaper(token, &url, chapter).await,
+            ReadLaterService::Pocket => save_to_pocket(token, &url, chapter).await,
+            ReadLaterService::ReadwiseReader => save_to_readwise(token, &url, chapter).await,
+        }
+        .with_context(|| format!("Failed to save chapter {}", chapter.id))?;
+    }
+    Ok(())
+}
+
+async fn save_to_instapaper(token: &str, url: &str, chapter: &Chapter) -> Result<()> {
+    let (username, password) = token
+        .split_once(':')
+        .ok_or_else(|| anyhow!("The instapaper token is not username:password"))?;
+    let client = reqwest::Client::default();
+    with_trace_context(
+        client
+            .post("https://www.instapaper.com/api/add")
+            .basic_auth(username, Some(password))
+            .form(&[("url", url), ("title", &chapter.title)]),
+    )
+    .send()
+    .await?
+    .error_for_status()?;
+    Ok(())
+}
+
+async fn save_to_pocket(token: &str, url: &str, chapter: &Chapter) -> Result<()> {
+    let consumer_key =
+        secret("CEREAL_POCKET_CONSUMER_KEY").context("Pocket consumer key not provided")?;
+    let client = reqwest::Client::default();
+    with_trace_context(client.post("https://getpocket.com/v3/add").json(&json!({
+        "url": url,
+        "title": chapter.title,
+        "consumer_key": consumer_key,
+        "access_token": token,
+    })))
+    .send()
+    .await?
+    .error_for_status()?;
+    Ok(())
+}
+
+/// Saves the chapter's html along with its url, so that Reader shows the chapter as it was
+/// fetched rather than whatever the url serves to an anonymous visitor.
+async fn save_to_readwise(token: &str, url: &str, chapter: &Chapter) -> Result<()> {
+    let html = chapter
+        .html
+        .as_deref()
+        .map(|x| sanitize_html(&String::from_utf8_lossy(x)));
+    let client = reqwest::Client::default();
+    with_trace_context(
+        client
+            .post("https://readwise.io/api/v3/save/")
+            .header("Authorization", format!("Token {}", token))
+            .json(&json!({
+                "url": url,
+                "title": chapter.title,
+                "html": html,
+                "should_clean_html": false,
+            })),
+    )
+    .send()
+    .await?
+    .error_for_status()?;
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use std::env;
+
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    models::{Subscriber, SubscriberClient},
+    tasks::chapter_body_conversion::sanitize_html,
+};
+
+use super::{apprise, mailgun};
+
+/// How many failed deliveries or bounces in a row pause a subscriber, from
+/// CEREAL_PAUSE_AFTER_FAILURES.
+fn pause_after_failures() -> i64 {
+    env::var("CEREAL_PAUSE_AFTER_FAILURES")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .filter(|x| *x > 0)
+        .unwrap_or(10)
+}
+
+/// How long a subscriber must have been failing before it is paused, so a short outage of a
+/// channel doesn't pause everyone, from CEREAL_PAUSE_AFTER_FAILING_DAYS.
+fn pause_after_failing() -> chrono::Duration {
+    let days = env::var("CEREAL_PAUSE_AFTER_FAILING_DAYS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(3);
+    chrono::Duration::days(days)
+}
+
+fn should_pause(subscriber: &Subscriber) -> bool {
+    let failing_since = match (subscriber.paused_at, subscriber.failing_since) {
+        (None, Some(x)) => x,
+        _ => return false,
+    };
+    subscriber.delivery_failures >= pause_after_failures()
+        && Utc::now() - failing_since >= pause_after_failing()
+}
+
+/// Counts a failed delivery or bounce against the subscriber, pausing it and notifying the
+/// admin once it has failed for long enough.
+#[instrument(skip(pool))]
+pub async fn record_subscriber_failure(pool: &Pool<Sqlite>, subscriber_id: &Uuid, failure: &str) {
+    let client = SubscriberClient::new(pool);
+    let subscriber = match client.record_delivery_failure(subscriber_id, failure).await {
+        Ok(Some(x)) => x,
+        Ok(None) => return,
+        Err(e) => {
+            error!(
+                "A DB error occurred recording a delivery failure for subscriber {}: {}",
+                subscriber_id, e
+            );
+            return;
+        }
+    };
+    if !should_pause(&subscriber) {
+        return;
+    }
+    let subscriber = match client.pause_subscriber(subscriber_id).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(
+                "A DB error occurred pausing subscriber {}: {}",
+                subscriber_id, e
+            );
+            return;
+        }
+    };
+    let message = format!(
+        "Subscriber {} ({}) was paused after {} failed deliveries since {}. The last failure was: {}. Nothing is sent to it until it is resumed.",
+        subscriber.name,
+        subscriber.id,
+        subscriber.delivery_failures,
+        subscriber
+            .failing_since
+            .map(|x| x.format("%Y-%m-%d").to_string())
+            .unwrap_or_default(),
+        failure
+    );
+    info!("{}", message);
+    let subject = format!("Paused subscriber {}", subscriber.name);
+    if let Err(e) = notify_admin(&subject, &message).await {
+        error!(
+            "Failed to notify the admin that subscriber {} was paused: {:#}",
+            subscriber.id, e
+        );
+    }
+}
+
+/// Clears the subscriber's failures after a successful delivery.
+#[instrument(skip(pool))]
+pub async fn record_subscriber_success(pool: &Pool<Sqlite>, subscriber_id: &Uuid) {
+    if let Err(e) = SubscriberClient::new(pool)
+        .record_delivery_success(subscriber_id)
+        .await
+    {
+        error!(
+            "A DB error occurred recording a delivery for subscriber {}: {}",
+            subscriber_id, e
+        );
+    }
+}
+
+/// Sends the message to the admin, through the apprise url in CEREAL_ADMIN_NOTIFY_URL and the
+/// address in CEREAL_ADMIN_EMAIL.
+async fn notify_admin(subject: &str, message: &str) -> anyhow::Result<()> {
+    let notify_url = env::var("CEREAL_ADMIN_NOTIFY_URL").ok();
+    let email = env::var("CEREAL_ADMIN_EMAIL").ok();
+    if notify_url.is_none() && email.is_none() {
+        warn!("Neither CEREAL_ADMIN_NOTIFY_URL nor CEREAL_ADMIN_EMAIL is set, not notifying the admin");
+        return Ok(());
+    }
+    if let Some(notify_url) = notify_url {
+        apprise::send_notification(&notify_url, subject, message)
+            .await
+            .context("Failed to send apprise notification")?;
+    }
+    if let Some(email) = email {
+        let html = sanitize_html(&format!("<p>{}</p>", message));
+        mailgun::send_html_email(&email, subject, &html)
+            .await
+            .context("Failed to send email")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,201 @@
+use chrono::Utc;
+use itertools::Itertools;
+use scraper::Html;
+
+use crate::{
+    config::config,
+    models::{AnthologySubscription, Book, Chapter, DeliveryTemplates},
+};
+
+/// The variables which may appear in delivery templates, each written as `{name}`.
+pub const TEMPLATE_VARIABLES: &[&str] = &[
+    "book",
+    "author",
+    "chapter",
+    "firstChapter",
+    "lastChapter",
+    "range",
+    "count",
+    "words",
+    "sequence",
+    "lastSequence",
+    "date",
+];
+
+/// The attachment name, without extension, email subject and notification of a delivery.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DeliveryNames {
+    pub file_name: String,
+    pub subject: String,
+    pub notification: String,
+}
+
+/// Checks that the templates only use known variables.
+pub fn validate_templates(templates: &DeliveryTemplates) -> Result<(), String> {
+    for template in [
+        &templates.file_name,
+        &templates.subject,
+        &templates.notification,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        for variable in variables(template) {
+            if !TEMPLATE_VARIABLES.contains(&variable) {
+                return Err(format!(
+                    "Unknown variable {{{}}} in template {:?}, expected one of {}",
+                    variable,
+                    template,
+                    TEMPLATE_VARIABLES.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The byte ranges of the `{variable}`s in the template, braces included.
+fn placeholders(template: &str) -> Vec<(usize, usize)> {
+    let mut placeholders = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = template[offset..].find('{') {
+        let start = offset + start;
+        match template[start..].find('}') {
+            Some(end) => {
+                placeholders.push((start, start + end + 1));
+                offset = start + end + 1;
+            }
+            None => break,
+        }
+    }
+    placeholders
+}
+
+/// The names of `{variable}`s used in the template.
+fn variables(template: &str) -> Vec<&str> {
+    placeholders(template)
+        .into_iter()
+        .map(|(start, end)| &template[start + 1..end - 1])
+        .collect()
+}
+
+/// Substitutes each of the template's variables in one pass, so values are never themselves
+/// read as variables. Unknown variables are left as they are.
+fn render(template: &str, values: &[(&str, String)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut offset = 0;
+    for (start, end) in placeholders(template) {
+        let name = &template[start + 1..end - 1];
+        rendered.push_str(&template[offset..start]);
+        match values.iter().find(|(x, _)| *x == name) {
+            Some((_, value)) => rendered.push_str(value),
+            None => rendered.push_str(&template[start..end]),
+        }
+        offset = end;
+    }
+    rendered.push_str(&template[offset..]);
+    rendered
+}
+
+/// The words in the chapter's html body, not counting markup.
+fn word_count(chapter: &Chapter) -> usize {
+    let html = match &chapter.html {
+        Some(x) => String::from_utf8_lossy(x),
+        None => return 0,
+    };
+    Html::parse_fragment(&html)
+        .root_element()
+        .text()
+        .map(|x| x.split_whitespace().count())
+        .sum()
+}
+
+/// Names the delivery of `chapters`, which must be non-empty and in reading order, from the
+/// book's templates or the configured defaults. Without either the names describe the
+/// chapters delivered.
+pub fn delivery_names(book: &Book, chapters: &[Chapter]) -> DeliveryNames {
+    let templates = book.delivery_templates.or(&config().delivery_templates);
+    let first = &chapters[0];
+    let last = &chapters[chapters.len() - 1];
+    let chapter = match chapters.len() {
+        1 => first.title.clone(),
+        _ => format!("{} through {}", first.title, last.title),
+    };
+    let range = match chapters.len() {
+        1 => first.title.clone(),
+        _ => format!("{}\u{2013}{}", first.title, last.title),
+    };
+    let words: usize = chapters.iter().map(word_count).sum();
+    let subject = match chapters.len() {
+        1 => format!("New Chapter of {}: {}", book.title, chapter),
+        n => format!("{n} New Chapters of {}: {}", book.title, chapter),
+    };
+    let notification = match chapters.len() {
+        1 => format!(
+            "Delivered new chapter for {}: {} ({} words)",
+            book.title, chapter, words
+        ),
+        n => format!(
+            "Delivered {} new chapters for {}: {} ({} words)",
+            n, book.title, chapter, words
+        ),
+    };
+    // Sequence numbers are padded so that files sort in reading order.
+    let values = [
+        ("book", book.title.clone()),
+        ("author", book.author.clone()),
+        ("chapter", chapter.clone()),
+        ("firstChapter", first.title.clone()),
+        ("lastChapter", last.title.clone()),
+        ("range", range),
+        ("count", chapters.len().to_string()),
+        ("words", words.to_string()),
+        ("sequence", format!("{:04}", first.order_index)),
+        ("lastSequence", format!("{:04}", last.order_index)),
+        ("date", Utc::now().format("%Y-%m-%d").to_string()),
+    ];
+    DeliveryNames {
+        file_name: match &templates.file_name {
+            Some(template) => render(template, &values)
+                .trim_end_matches(".epub")
+                .to_owned(),
+            None => chapter,
+        },
+        subject: match &templates.subject {
+            Some(template) => render(template, &values),
+            None => subject,
+        },
+        notification: match &templates.notification {
+            Some(template) => render(template, &values),
+            None => notification,
+        },
+    }
+}
+
+/// Names the digest of an anthology subscription from the books' `sections` of chapters.
+/// Delivery templates belong to a single book, so they don't apply to digests.
+pub fn digest_names(
+    anthology: &AnthologySubscription,
+    sections: &[(&Book, &[Chapter])],
+) -> DeliveryNames {
+    let count: usize = sections.iter().map(|(_, x)| x.len()).sum();
+    let words: usize = sections
+        .iter()
+        .flat_map(|(_, x)| x.iter())
+        .map(word_count)
+        .sum();
+    let books = sections.iter().map(|(x, _)| x.title.as_str()).join(", ");
+    let date = Utc::now().format("%Y-%m-%d");
+    let chapters = match count {
+        1 => String::from("1 new chapter"),
+        n => format!("{n} new chapters"),
+    };
+    DeliveryNames {
+        file_name: format!("{} {}", anthology.name, date),
+        subject: format!("{} {}: {} from {}", anthology.name, date, chapters, books),
+        notification: format!(
+            "Delivered {} digest: {} from {} ({} words)",
+            anthology.name, chapters, books, words
+        ),
+    }
+}
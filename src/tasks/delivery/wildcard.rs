@@ -0,0 +1,92 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+use sqlx::{Pool, Sqlite};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::ApiResult,
+    models::{BookClient, ChapterClient, SubscriptionClient, WildcardSubscriptionClient},
+};
+
+/// Creates a subscription for each book a wildcard subscription covers and deletes those for
+/// books it now excludes, or which no longer have its tag. A book is only subscribed to once its
+/// first chapters have been discovered, starting from the latest of them, so that its back
+/// catalogue isn't delivered.
+/// Books the subscriber already has a subscription to of their own are left alone.
+#[instrument(skip(pool))]
+pub async fn sync_wildcard_subscriptions(pool: &Pool<Sqlite>) -> ApiResult<()> {
+    let wildcards = WildcardSubscriptionClient::new(pool)
+        .list_wildcard_subscriptions(None)
+        .await?;
+    if wildcards.is_empty() {
+        return Ok(());
+    }
+    let book_client = BookClient::new(pool);
+    let books: Vec<_> = book_client
+        .list_books()
+        .await?
+        .into_iter()
+        .filter(|x| x.canonical_book_id.is_none())
+        .collect();
+    let chapter_client = ChapterClient::new(pool);
+    let subscription_client = SubscriptionClient::new(pool);
+    let mut tagged_books: HashMap<Uuid, HashSet<Uuid>> = HashMap::new();
+
+    for wildcard in wildcards {
+        let subscriptions = subscription_client
+            .list_subscriptions(&wildcard.subscriber_id)
+            .await?;
+        let members: HashMap<Uuid, Uuid> = subscriptions
+            .iter()
+            .filter(|x| x.wildcard_subscription_id == Some(wildcard.id))
+            .map(|x| (x.book_id, x.id))
+            .collect();
+        let subscribed: HashSet<Uuid> = subscriptions.iter().map(|x| x.book_id).collect();
+        let tagged = match wildcard.tag_id {
+            Some(tag_id) => {
+                if let Entry::Vacant(entry) = tagged_books.entry(tag_id) {
+                    let books = book_client.list_books_with_tag(&tag_id).await?;
+                    entry.insert(books.into_iter().map(|x| x.id).collect());
+                }
+                tagged_books.get(&tag_id)
+            }
+            None => None,
+        };
+
+        for book in &books {
+            let excluded = wildcard.excluded_book_ids.contains(&book.id)
+                || tagged.is_some_and(|x| !x.contains(&book.id));
+            if let Some(subscription_id) = members.get(&book.id) {
+                if excluded {
+                    info!(
+                        "Book {} is excluded from wildcard subscription {}, unsubscribing",
+                        book.id, wildcard.id
+                    );
+                    subscription_client
+                        .delete_subscription(*subscription_id)
+                        .await?;
+                }
+                continue;
+            }
+            if excluded || subscribed.contains(&book.id) {
+                continue;
+            }
+            let latest_chapter = match chapter_client
+                .most_recent_chapter_by_created_at(&book.id)
+                .await?
+            {
+                Some(x) => x,
+                None => continue,
+            };
+            info!(
+                "Subscribing to book {} for wildcard subscription {}",
+                book.id, wildcard.id
+            );
+            subscription_client
+                .create_wildcard_member(&wildcard, &book.id, &latest_chapter)
+                .await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,198 @@
+use std::time::Instant;
+
+use futures::{stream::BoxStream, StreamExt};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    models::{BlobStream, Chapter, ChapterBody, ChapterClient, LeaseClient},
+    telemetry::{record_loop_duration, record_queue_depth},
+    util::content_digest,
+};
+
+use super::{
+    schedule::{LoopTimer, TaskLoop},
+    with_lease,
+};
+
+/// Which of a chapter's stored bodies no longer match their digests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Corruption {
+    pub html: bool,
+    pub epub: bool,
+}
+
+impl Corruption {
+    pub fn any(&self) -> bool {
+        self.html || self.epub
+    }
+}
+
+fn is_corrupted(content: Option<&[u8]>, digest: Option<&str>) -> bool {
+    match (content, digest) {
+        (Some(content), Some(digest)) => content_digest(content) != digest,
+        _ => false,
+    }
+}
+
+/// Compares the chapter's bodies to their digests. Bodies stored before digests were kept
+/// can't be checked until the integrity scan records their digests.
+pub fn find_corruption(chapter: &Chapter) -> Corruption {
+    Corruption {
+        html: is_corrupted(chapter.html.as_deref(), chapter.html_digest.as_deref()),
+        epub: is_corrupted(chapter.epub.as_deref(), chapter.epub_digest.as_deref()),
+    }
+}
+
+/// Checks the chapter's bodies, and if any are corrupted drops them so that the hydration and
+/// conversion loops regenerate them. Returns the chapter as it should be served, without the
+/// corrupted bodies.
+#[instrument(skip(pool, chapter), fields(book.id = %chapter.book_id, chapter.id = %chapter.id))]
+pub async fn verify_chapter(pool: &Pool<Sqlite>, mut chapter: Chapter) -> ApiResult<Chapter> {
+    let corruption = find_corruption(&chapter);
+    if !corruption.any() {
+        return Ok(chapter);
+    }
+    error!(
+        html = corruption.html,
+        epub = corruption.epub,
+        "Chapter {} failed its integrity check, flagging it for regeneration",
+        chapter.id
+    );
+    ChapterClient::new(pool)
+        .clear_corrupted_bodies(&chapter.id, corruption.html, corruption.epub)
+        .await?;
+    if corruption.html {
+        chapter.html = None;
+        chapter.html_digest = None;
+    }
+    if corruption.epub {
+        chapter.epub = None;
+        chapter.epub_digest = None;
+    }
+    Ok(chapter)
+}
+
+/// Checks a streamed body against its digest as it is read. The digest is only known once the
+/// whole body has been read, so a corrupted body ends with an error, which aborts the response
+/// it is streamed into, and is dropped for regeneration.
+pub fn verify_body_stream(
+    pool: &Pool<Sqlite>,
+    chapter_id: Uuid,
+    body: ChapterBody,
+    blob: BlobStream,
+) -> BoxStream<'static, ApiResult<Vec<u8>>> {
+    let digest = match blob.digest {
+        Some(x) => x,
+        None => return blob.chunks,
+    };
+    let pool = pool.clone();
+    futures::stream::try_unfold(
+        (blob.chunks, Sha256::new()),
+        move |(mut chunks, mut hasher)| {
+            let (pool, digest) = (pool.clone(), digest.clone());
+            async move {
+                if let Some(chunk) = chunks.next().await {
+                    let chunk = chunk?;
+                    hasher.update(&chunk);
+                    return Ok(Some((chunk, (chunks, hasher))));
+                }
+                let actual: String = hasher
+                    .finalize()
+                    .iter()
+                    .map(|x| format!("{:02x}", x))
+                    .collect();
+                if actual == digest {
+                    return Ok(None);
+                }
+                error!(
+                    "Chapter {} {} failed its integrity check, flagging it for regeneration",
+                    chapter_id,
+                    body.as_str()
+                );
+                ChapterClient::new(&pool)
+                    .clear_corrupted_bodies(
+                        &chapter_id,
+                        body == ChapterBody::Html,
+                        body == ChapterBody::Epub,
+                    )
+                    .await?;
+                Err(ApiError::DatabaseIntegrity(format!(
+                    "The {} of chapter {} is corrupted",
+                    body.as_str(),
+                    chapter_id
+                )))
+            }
+        },
+    )
+    .boxed()
+}
+
+pub async fn integrity_scan_loop(pool: Pool<Sqlite>) {
+    let lease_client = LeaseClient::new(&pool);
+    let mut timer = LoopTimer::new(TaskLoop::Integrity);
+    loop {
+        let started = Instant::now();
+        // A scan reads every stored body, so only one instance runs it at a time.
+        let work = scan_chapters(&pool);
+        with_lease(&lease_client, "integrity", chrono::Duration::hours(2), work).await;
+        record_loop_duration(TaskLoop::Integrity.name(), started.elapsed());
+        timer.wait_for_next_run(&pool).await;
+    }
+}
+
+/// Verifies every stored chapter body, recording digests for bodies stored before digests
+/// were kept. Chapters are read one at a time to keep memory use flat.
+#[instrument(skip(pool))]
+async fn scan_chapters(pool: &Pool<Sqlite>) {
+    let client = ChapterClient::new(pool);
+    let ids = match client.list_chapter_ids_with_bodies().await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Error listing chapters for the integrity scan {}", e);
+            return;
+        }
+    };
+    record_queue_depth(TaskLoop::Integrity.name(), ids.len());
+    let (mut flagged, mut backfilled) = (0, 0);
+    for id in ids {
+        let chapter = match client.get_chapter(id).await {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Error fetching chapter {} for the integrity scan {}", id, e);
+                continue;
+            }
+        };
+        let html_digest = match (&chapter.html, &chapter.html_digest) {
+            (Some(html), None) => Some(content_digest(html)),
+            _ => None,
+        };
+        let epub_digest = match (&chapter.epub, &chapter.epub_digest) {
+            (Some(epub), None) => Some(content_digest(epub)),
+            _ => None,
+        };
+        if html_digest.is_some() || epub_digest.is_some() {
+            backfilled += 1;
+            if let Err(e) = client
+                .set_missing_digests(&id, html_digest.as_deref(), epub_digest.as_deref())
+                .await
+            {
+                error!("Error recording digests for chapter {} {}", id, e);
+            }
+        }
+        if find_corruption(&chapter).any() {
+            flagged += 1;
+            if let Err(e) = verify_chapter(pool, chapter).await {
+                error!("Error flagging chapter {} for regeneration {}", id, e);
+            }
+        }
+    }
+    info!(
+        flagged,
+        backfilled, "Finished integrity scan of chapter bodies"
+    );
+}
@@ -0,0 +1,122 @@
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use tracing::{error, info, instrument};
+
+use crate::{
+    error::ApiResult,
+    models::{BookArtifactClient, LeaseClient, MaintenanceClient, SettingsClient},
+    telemetry::record_loop_duration,
+};
+
+use super::{
+    chapter_body_conversion::CONVERSION_VERSION,
+    schedule::{LoopTimer, TaskLoop},
+    with_lease,
+};
+
+const REPORT_SETTING: &str = "maintenance:lastReport";
+
+/// What the last maintenance run did, shown in /status.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceReport {
+    #[serde(rename = "startedAt")]
+    pub started_at: DateTime<Utc>,
+    #[serde(rename = "finishedAt")]
+    pub finished_at: DateTime<Utc>,
+    #[serde(rename = "freedPages")]
+    pub freed_pages: i64,
+    /// Whether the database had to be fully vacuumed to enable incremental vacuums.
+    #[serde(rename = "fullVacuum")]
+    pub full_vacuum: bool,
+    #[serde(rename = "ftsIndexesOptimized")]
+    pub fts_indexes_optimized: Vec<String>,
+    #[serde(rename = "orphanedArtifactsRemoved")]
+    pub orphaned_artifacts_removed: u64,
+    /// Artifacts removed because a chapter or the conversion settings changed since they were
+    /// generated.
+    #[serde(rename = "supersededArtifactsRemoved", default)]
+    pub superseded_artifacts_removed: u64,
+    /// Steps which failed. The others still ran.
+    pub errors: Vec<String>,
+}
+
+pub async fn last_maintenance_report(pool: &Pool<Sqlite>) -> ApiResult<Option<MaintenanceReport>> {
+    SettingsClient::new(pool).get_setting(REPORT_SETTING).await
+}
+
+pub async fn maintenance_loop(pool: Pool<Sqlite>) {
+    let lease_client = LeaseClient::new(&pool);
+    let mut timer = LoopTimer::new(TaskLoop::Maintenance);
+    loop {
+        // Wait first so that restarts don't vacuum during startup.
+        timer.wait_for_next_run(&pool).await;
+        let started = Instant::now();
+        let work = run_maintenance(&pool);
+        with_lease(
+            &lease_client,
+            "maintenance",
+            chrono::Duration::hours(2),
+            work,
+        )
+        .await;
+        record_loop_duration(TaskLoop::Maintenance.name(), started.elapsed());
+    }
+}
+
+#[instrument(skip(pool))]
+async fn run_maintenance(pool: &Pool<Sqlite>) {
+    let client = MaintenanceClient::new(pool);
+    let started_at = Utc::now();
+    let mut errors = Vec::new();
+    let mut record = |step: &str, e: &dyn std::fmt::Display| {
+        error!("Maintenance step {} failed: {}", step, e);
+        errors.push(format!("{}: {}", step, e));
+    };
+
+    let orphaned_artifacts_removed = client
+        .delete_orphaned_artifacts()
+        .await
+        .unwrap_or_else(|e| {
+            record("orphanedArtifacts", &e);
+            0
+        });
+    let superseded_artifacts_removed = BookArtifactClient::new(pool)
+        .prune_superseded_artifacts(CONVERSION_VERSION)
+        .await
+        .unwrap_or_else(|e| {
+            record("supersededArtifacts", &e);
+            0
+        });
+    let fts_indexes_optimized = client.optimize_fts_indexes().await.unwrap_or_else(|e| {
+        record("ftsOptimize", &e);
+        Vec::new()
+    });
+    let (freed_pages, full_vacuum) = client.vacuum().await.unwrap_or_else(|e| {
+        record("vacuum", &e);
+        (0, false)
+    });
+    if let Err(e) = client.optimize().await {
+        record("optimize", &e);
+    }
+
+    let report = MaintenanceReport {
+        started_at,
+        finished_at: Utc::now(),
+        freed_pages,
+        full_vacuum,
+        fts_indexes_optimized,
+        orphaned_artifacts_removed,
+        superseded_artifacts_removed,
+        errors,
+    };
+    info!(?report, "Finished database maintenance");
+    if let Err(e) = SettingsClient::new(pool)
+        .set_setting(REPORT_SETTING, &report)
+        .await
+    {
+        error!("Failed to save maintenance report: {}", e);
+    }
+}
@@ -1,4 +1,172 @@
+use std::{env, fs, future::Future, panic::AssertUnwindSafe, path::Path, sync::OnceLock};
+
+use anyhow::anyhow;
+use futures::FutureExt;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::models::{ChapterClient, ChapterState, LeaseClient};
+
+pub mod backfill;
 pub mod chapter_body_conversion;
 pub mod chapter_body_hydration;
 pub mod chapter_discovery;
 pub mod delivery;
+pub mod integrity;
+pub mod maintenance;
+pub mod recovery;
+pub mod reparse;
+pub mod schedule;
+pub mod sources;
+pub mod volumes;
+pub mod websub;
+
+/// How long a claim on a chapter is honoured. Claims left behind by a process that died
+/// mid-work are taken over once they are this old.
+const CHAPTER_CLAIM_LEASE_MINUTES: i64 = 30;
+
+/// Where the generated instance id is kept, alongside the database, unless
+/// CEREAL_INSTANCE_ID_FILE names another file.
+const INSTANCE_ID_FILE: &str = "./instance_id";
+
+static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+
+/// Identifies this process in chapter claims and task leases, so that several instances can
+/// share a database. The id is generated once and kept in a file, so a restarted process
+/// recognises the claims its previous run left behind. Set CEREAL_INSTANCE_ID to override it,
+/// which instances sharing a working directory must.
+pub fn instance_id() -> &'static str {
+    INSTANCE_ID.get_or_init(|| {
+        env::var("CEREAL_INSTANCE_ID").unwrap_or_else(|_| {
+            let path = env::var("CEREAL_INSTANCE_ID_FILE")
+                .unwrap_or_else(|_| String::from(INSTANCE_ID_FILE));
+            stored_instance_id(Path::new(&path))
+        })
+    })
+}
+
+/// The instance id kept in the file, generating and saving one if there is none yet. If it
+/// can't be saved the generated id is still used, for this run only.
+pub fn stored_instance_id(path: &Path) -> String {
+    if let Ok(id) = fs::read_to_string(path) {
+        let id = id.trim();
+        if !id.is_empty() {
+            return id.to_owned();
+        }
+    }
+    let id = format!("instance-{}", Uuid::new_v4());
+    if let Err(e) = fs::write(path, &id) {
+        warn!(
+            "Failed to save the instance id to {}, so claims left by this run won't be released on restart: {}",
+            path.display(),
+            e
+        );
+    }
+    id
+}
+
+/// Runs `work` only if the chapter can be claimed by this worker, so that overlapping ticks and
+/// restarted processes never process the same chapter concurrently.
+pub async fn with_chapter_claim<F: Future<Output = ()>>(
+    client: &ChapterClient,
+    chapter_id: &Uuid,
+    work: F,
+) {
+    let lease = chrono::Duration::minutes(CHAPTER_CLAIM_LEASE_MINUTES);
+    match client.claim_chapter(chapter_id, instance_id(), lease).await {
+        Ok(true) => {}
+        Ok(false) => {
+            info!(
+                "Chapter {} is claimed by another worker, skipping",
+                chapter_id
+            );
+            return;
+        }
+        Err(e) => {
+            error!("Failed to claim chapter {}: {}", chapter_id, e);
+            return;
+        }
+    }
+    work.await;
+    if let Err(e) = client.release_chapter(chapter_id, instance_id()).await {
+        error!("Failed to release claim on chapter {}: {}", chapter_id, e);
+    }
+}
+
+/// Records that the chapter couldn't be moved on from `state`, logging when the pipeline gives
+/// up on it.
+pub async fn record_failure(
+    client: &ChapterClient,
+    chapter_id: &Uuid,
+    state: ChapterState,
+    error: &anyhow::Error,
+) {
+    match client
+        .record_failure(chapter_id, state, &format!("{:#}", error))
+        .await
+    {
+        Ok(Some(x)) if x.state == ChapterState::Failed => warn!(
+            "Giving up on chapter {} after {} failures, retry it once fixed",
+            x.id, x.failures
+        ),
+        Ok(_) => {}
+        Err(e) => error!("Failed to record failure of chapter {}: {}", chapter_id, e),
+    }
+}
+
+/// Runs `work`, returning a panic as an error, so that one malformed item fails on its own
+/// rather than taking down the rest of the loop's run and hiding which item it was.
+pub async fn catch_panic<F: Future>(work: F) -> anyhow::Result<F::Output> {
+    AssertUnwindSafe(work)
+        .catch_unwind()
+        .await
+        .map_err(|panic| {
+            let message = match panic.downcast_ref::<&str>() {
+                Some(x) => x.to_string(),
+                None => match panic.downcast_ref::<String>() {
+                    Some(x) => x.clone(),
+                    None => String::from("no message"),
+                },
+            };
+            anyhow!("Panicked: {}", message)
+        })
+}
+
+/// Runs the work on a chapter, recording a panic as a failure of the chapter in `state`.
+pub async fn with_panics_recorded<F: Future<Output = ()>>(
+    client: &ChapterClient,
+    chapter_id: &Uuid,
+    state: ChapterState,
+    work: F,
+) {
+    if let Err(e) = catch_panic(work).await {
+        error!("Work on chapter {} panicked: {:#}", chapter_id, e);
+        record_failure(client, chapter_id, state, &e).await;
+    }
+}
+
+/// Runs `work` only if this instance can take the named lease, releasing it afterwards. Returns
+/// None without running `work` if another instance holds the lease.
+pub async fn with_lease<F: Future>(
+    client: &LeaseClient,
+    name: &str,
+    ttl: chrono::Duration,
+    work: F,
+) -> Option<F::Output> {
+    match client.acquire_lease(name, instance_id(), ttl).await {
+        Ok(true) => {}
+        Ok(false) => {
+            info!("Lease {} is held by another instance, skipping", name);
+            return None;
+        }
+        Err(e) => {
+            error!("Failed to acquire lease {}: {}", name, e);
+            return None;
+        }
+    }
+    let output = work.await;
+    if let Err(e) = client.release_lease(name, instance_id()).await {
+        error!("Failed to release lease {}: {}", name, e);
+    }
+    Some(output)
+}
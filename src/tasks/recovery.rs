@@ -0,0 +1,61 @@
+use sqlx::{Pool, Sqlite};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    error::ApiResult,
+    models::{ChapterClient, LeaseClient},
+};
+
+use super::{
+    chapter_body_conversion::{sweep_orphaned_temp_dirs, TempDirSweep, ORPHANED_TEMP_DIR_AGE},
+    instance_id, CHAPTER_CLAIM_LEASE_MINUTES,
+};
+
+/// What the startup recovery pass cleaned up after the previous run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RecoverySummary {
+    pub released_claims: u64,
+    pub released_leases: u64,
+    pub requeued_chapters: u64,
+    pub temp_dirs: TempDirSweep,
+}
+
+/// Cleans up after a process that stopped mid-work, before the task loops start: clears stale
+/// chapter claims and task leases, sends chapters stuck in an intermediate state back to the
+/// loop that produces what they are missing, and sweeps orphaned conversion directories.
+#[instrument(skip(pool))]
+pub async fn recover(pool: &Pool<Sqlite>) -> ApiResult<RecoverySummary> {
+    let chapters = ChapterClient::new(pool);
+    let lease = chrono::Duration::minutes(CHAPTER_CLAIM_LEASE_MINUTES);
+    let released_claims = chapters.release_stale_claims(instance_id(), lease).await?;
+    let released_leases = LeaseClient::new(pool)
+        .release_stale_leases(instance_id())
+        .await?;
+    let requeued_chapters = chapters.requeue_stuck_chapters().await?;
+    // A missing or unreadable temp directory must not keep the task loops from starting.
+    let temp_dirs = sweep_orphaned_temp_dirs(ORPHANED_TEMP_DIR_AGE).unwrap_or_else(|e| {
+        error!("Error sweeping orphaned conversion directories {}", e);
+        TempDirSweep::default()
+    });
+
+    let summary = RecoverySummary {
+        released_claims,
+        released_leases,
+        requeued_chapters,
+        temp_dirs,
+    };
+    info!(
+        released_claims,
+        released_leases,
+        requeued_chapters,
+        removed_temp_dirs = temp_dirs.removed,
+        "Startup recovery finished"
+    );
+    if temp_dirs.kept > 0 {
+        warn!(
+            "{} conversion directories younger than {:?} were left in place, another instance may be converting",
+            temp_dirs.kept, ORPHANED_TEMP_DIR_AGE
+        );
+    }
+    Ok(summary)
+}
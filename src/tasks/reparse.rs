@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context};
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::ApiResult,
+    models::{Book, Chapter, ChapterClient, ChapterMetadata, ChapterState},
+    providers::normalize_title,
+};
+
+use super::sources::load_source;
+
+/// How many changed pieces of html a reparse lists on each side.
+const CHANGED_SEGMENTS_SHOWN: usize = 20;
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct FieldChange<T> {
+    pub before: T,
+    pub after: T,
+}
+
+/// How a chapter's html changed. The html is compared a tag at a time, ignoring order, which
+/// is enough to see what a parser change did.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct HtmlChange {
+    #[serde(rename = "bytesBefore")]
+    pub bytes_before: usize,
+    #[serde(rename = "bytesAfter")]
+    pub bytes_after: usize,
+    pub removed: Vec<String>,
+    pub added: Vec<String>,
+}
+
+/// What parsing a chapter's source again changed, or would change when previewed.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct SourceReparse {
+    #[serde(rename = "chapterId")]
+    pub chapter_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<FieldChange<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FieldChange<ChapterMetadata>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub html: Option<HtmlChange>,
+    /// Whether the changes were saved. Previews and unchanged chapters aren't.
+    pub applied: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn segments(html: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(html)
+        .split_inclusive('>')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The segments of `from` which `to` doesn't have, counting repeats.
+fn missing_segments(from: &[String], to: &[String]) -> Vec<String> {
+    let mut remaining: HashMap<&str, usize> = HashMap::new();
+    for x in to {
+        *remaining.entry(x).or_default() += 1;
+    }
+    from.iter()
+        .filter(|x| match remaining.get_mut(x.as_str()) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        })
+        .take(CHANGED_SEGMENTS_SHOWN)
+        .cloned()
+        .collect()
+}
+
+fn html_change(before: Option<&[u8]>, after: &[u8]) -> Option<HtmlChange> {
+    let before = before.unwrap_or_default();
+    if before == after {
+        return None;
+    }
+    let (before_segments, after_segments) = (segments(before), segments(after));
+    Some(HtmlChange {
+        bytes_before: before.len(),
+        bytes_after: after.len(),
+        removed: missing_segments(&before_segments, &after_segments),
+        added: missing_segments(&after_segments, &before_segments),
+    })
+}
+
+/// Parses the chapter out of its stored source again with its provider's current parser, so
+/// parser fixes reach chapters found before them. Changes are saved unless `preview` is set.
+#[instrument(skip_all, fields(chapter.id = %chapter.id, preview))]
+pub async fn reparse_chapter(
+    pool: &Pool<Sqlite>,
+    book: &Book,
+    chapter: &Chapter,
+    preview: bool,
+) -> anyhow::Result<SourceReparse> {
+    // Pruned chapters had their bodies dropped on purpose.
+    if chapter.state == ChapterState::Pruned {
+        bail!("Chapter {} was pruned", chapter.id);
+    }
+    let source_key = chapter
+        .source_key
+        .as_deref()
+        .ok_or_else(|| anyhow!("Chapter {} has no stored source", chapter.id))?;
+    let source = load_source(pool, source_key).await?;
+    let parsed = chapter
+        .metadata
+        .parse_source(book, &source)
+        .context("Failed to parse the chapter's source")?;
+
+    let (title, raw_title) = match &parsed.title {
+        Some(raw) => {
+            let title = normalize_title(book, raw);
+            let raw_title = (title != *raw).then(|| raw.clone());
+            (Some(title), raw_title)
+        }
+        None => (None, None),
+    };
+    let mut reparse = SourceReparse {
+        chapter_id: chapter.id,
+        title: title
+            .as_ref()
+            .filter(|x| **x != chapter.title)
+            .map(|x| FieldChange {
+                before: chapter.title.clone(),
+                after: x.clone(),
+            }),
+        metadata: parsed
+            .metadata
+            .as_ref()
+            .filter(|x| **x != chapter.metadata)
+            .map(|x| FieldChange {
+                before: chapter.metadata.clone(),
+                after: x.clone(),
+            }),
+        html: html_change(chapter.html.as_deref(), &parsed.html),
+        applied: false,
+        error: None,
+    };
+    let changed = reparse.title.is_some() || reparse.metadata.is_some() || reparse.html.is_some();
+    if changed && !preview {
+        ChapterClient::new(pool)
+            .replace_parsed(
+                &chapter.id,
+                title.as_deref(),
+                raw_title.as_deref(),
+                parsed.metadata.as_ref(),
+                &parsed.html,
+            )
+            .await?;
+        info!("Reparsed chapter {} from its source", chapter.id);
+        reparse.applied = true;
+    }
+    Ok(reparse)
+}
+
+/// Reparses each of the book's chapters which has a stored source. A chapter which fails is
+/// reported with its error rather than stopping the others.
+#[instrument(skip_all, fields(book.id = %book.id, preview))]
+pub async fn reparse_book(
+    pool: &Pool<Sqlite>,
+    book: &Book,
+    preview: bool,
+) -> ApiResult<Vec<SourceReparse>> {
+    let client = ChapterClient::new(pool);
+    let mut reparses = Vec::new();
+    for shallow in client.list_chapters_shallow(&book.id).await? {
+        if shallow.state == ChapterState::Pruned {
+            continue;
+        }
+        let chapter = match client.get_chapter(shallow.id).await? {
+            Some(x) if x.source_key.is_some() => x,
+            _ => continue,
+        };
+        match reparse_chapter(pool, book, &chapter, preview).await {
+            Ok(x) => reparses.push(x),
+            Err(e) => {
+                let message = format!("{:#}", e);
+                error!("Failed to reparse chapter {}: {}", chapter.id, message);
+                reparses.push(SourceReparse {
+                    chapter_id: chapter.id,
+                    title: None,
+                    metadata: None,
+                    html: None,
+                    applied: false,
+                    error: Some(message),
+                });
+            }
+        }
+    }
+    Ok(reparses)
+}
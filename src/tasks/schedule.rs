@@ -0,0 +1,262 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    str::FromStr,
+    time::Duration,
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    config::config,
+    error::ApiResult,
+    models::{Chapter, QueuedChapter, SettingsClient},
+    read_only::is_read_only,
+};
+
+/// The background loops whose run frequency can be configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TaskLoop {
+    Discovery,
+    Hydration,
+    Conversion,
+    Delivery,
+    /// Verifies stored chapter bodies against their digests.
+    Integrity,
+    /// Optimizes and vacuums the database.
+    Maintenance,
+    /// Renews WebSub subscriptions before their leases run out.
+    WebSubRenewal,
+}
+
+impl TaskLoop {
+    pub const ALL: [TaskLoop; 7] = [
+        TaskLoop::Discovery,
+        TaskLoop::Hydration,
+        TaskLoop::Conversion,
+        TaskLoop::Delivery,
+        TaskLoop::Integrity,
+        TaskLoop::Maintenance,
+        TaskLoop::WebSubRenewal,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskLoop::Discovery => "discovery",
+            TaskLoop::Hydration => "hydration",
+            TaskLoop::Conversion => "conversion",
+            TaskLoop::Delivery => "delivery",
+            TaskLoop::Integrity => "integrity",
+            TaskLoop::Maintenance => "maintenance",
+            TaskLoop::WebSubRenewal => "websub",
+        }
+    }
+
+    fn setting_key(&self) -> String {
+        format!("schedule:{}", self.name())
+    }
+
+    /// The schedule used when neither the environment nor the admin api override it.
+    fn builtin_schedule(&self) -> LoopSchedule {
+        match self {
+            TaskLoop::Discovery => LoopSchedule {
+                interval_secs: 5 * 60,
+                jitter_secs: 30,
+            },
+            TaskLoop::Integrity => LoopSchedule {
+                interval_secs: 6 * 60 * 60,
+                jitter_secs: 10 * 60,
+            },
+            TaskLoop::Maintenance => LoopSchedule {
+                interval_secs: 24 * 60 * 60,
+                jitter_secs: 60 * 60,
+            },
+            TaskLoop::WebSubRenewal => LoopSchedule {
+                interval_secs: 60 * 60,
+                jitter_secs: 5 * 60,
+            },
+            _ => LoopSchedule {
+                interval_secs: 10,
+                jitter_secs: 2,
+            },
+        }
+    }
+
+    /// Reads CEREAL_<LOOP>_<suffix>, falling back to `default` if it is unset or invalid.
+    fn env_setting<T: FromStr>(&self, suffix: &str, default: T) -> T
+    where
+        T::Err: std::fmt::Display,
+    {
+        let key = format!("CEREAL_{}_{}", self.name().to_uppercase(), suffix);
+        match env::var(&key) {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                error!("Ignoring invalid value {:?} for {}: {}", value, key, e);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// The loop's schedule in the config file, else CEREAL_<LOOP>_INTERVAL_SECS and
+    /// CEREAL_<LOOP>_JITTER_SECS, falling back to the built in schedule.
+    fn default_schedule(&self) -> LoopSchedule {
+        if let Some(schedule) = config().task_schedules.get(self) {
+            return *schedule;
+        }
+        let builtin = self.builtin_schedule();
+        let mut interval_secs = self.env_setting("INTERVAL_SECS", builtin.interval_secs);
+        // A loop which never waits would spin, so a zero interval is refused like the config
+        // file and the admin api refuse it.
+        if interval_secs == 0 {
+            error!(
+                "Ignoring an interval of 0 for the {} loop, which must be greater than zero",
+                self.name()
+            );
+            interval_secs = builtin.interval_secs;
+        }
+        LoopSchedule {
+            interval_secs,
+            jitter_secs: self.env_setting("JITTER_SECS", builtin.jitter_secs),
+        }
+    }
+
+    /// How many chapters of one book the loop works on each run, read from
+    /// CEREAL_<LOOP>_PER_BOOK. The rest wait for later runs, so a book with a long backlog
+    /// doesn't hold up every other book.
+    pub fn per_book_limit(&self) -> usize {
+        self.env_setting("PER_BOOK", DEFAULT_PER_BOOK_LIMIT).max(1)
+    }
+}
+
+/// The default number of chapters of one book the hydration and conversion loops take each run.
+const DEFAULT_PER_BOOK_LIMIT: usize = 10;
+
+/// Takes up to `per_book` of each book's chapters, in turns, so that every book has a chapter
+/// handled before any has a second. Chapters a subscription is waiting on go before the rest,
+/// and each book's chapters otherwise keep their order.
+pub fn schedule_chapters(chapters: Vec<QueuedChapter>, per_book: usize) -> Vec<Chapter> {
+    let mut scheduled = round_robin(chapters, |x| x.chapter.book_id, per_book);
+    scheduled.sort_by_key(|x| !x.priority);
+    scheduled.into_iter().map(|x| x.chapter).collect()
+}
+
+/// Takes up to `per_book` items of each book, in turns, so that every book has an item handled
+/// before any has a second. Each book's items keep their order.
+fn round_robin<T>(items: Vec<T>, book_id: impl Fn(&T) -> Uuid, per_book: usize) -> Vec<T> {
+    let mut positions: HashMap<Uuid, usize> = HashMap::new();
+    let mut queues: Vec<VecDeque<T>> = Vec::new();
+    for item in items {
+        let position = *positions.entry(book_id(&item)).or_insert_with(|| {
+            queues.push(VecDeque::new());
+            queues.len() - 1
+        });
+        queues[position].push_back(item);
+    }
+    let mut scheduled = Vec::new();
+    for _ in 0..per_book {
+        for queue in queues.iter_mut() {
+            scheduled.extend(queue.pop_front());
+        }
+    }
+    scheduled
+}
+
+/// How long a loop waits between runs. A random delay of up to `jitter_secs` is added to each
+/// wait so that runs don't line up exactly on the interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoopSchedule {
+    #[serde(rename = "intervalSecs")]
+    pub interval_secs: u64,
+    #[serde(rename = "jitterSecs")]
+    pub jitter_secs: u64,
+}
+
+/// The schedule currently in effect for the loop. Overrides set through the admin api take
+/// precedence over the config file and the environment.
+pub async fn get_schedule(pool: &Pool<Sqlite>, task: TaskLoop) -> ApiResult<LoopSchedule> {
+    let schedule = SettingsClient::new(pool)
+        .get_setting(&task.setting_key())
+        .await?;
+    Ok(schedule.unwrap_or_else(|| task.default_schedule()))
+}
+
+/// Overrides the schedule for the loop, or restores the default if `schedule` is None. Loops
+/// pick up the change after their current wait.
+pub async fn set_schedule(
+    pool: &Pool<Sqlite>,
+    task: TaskLoop,
+    schedule: Option<&LoopSchedule>,
+) -> ApiResult<LoopSchedule> {
+    let client = SettingsClient::new(pool);
+    match schedule {
+        Some(schedule) => client.set_setting(&task.setting_key(), schedule).await?,
+        None => client.delete_setting(&task.setting_key()).await?,
+    }
+    get_schedule(pool, task).await
+}
+
+/// How often paused loops check whether read-only mode has been turned off.
+const READ_ONLY_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Paces a loop's runs. Runs start on the loop's interval however long each takes, and a run
+/// which overruns the interval delays the next rather than being followed by a burst.
+pub struct LoopTimer {
+    task: TaskLoop,
+    /// When the current run was due to start, which the next is measured from.
+    run_started: Instant,
+    /// The interval in effect, along with the number of seconds it was built for.
+    interval: Option<(u64, Interval)>,
+}
+
+impl LoopTimer {
+    /// A timer for the loop, whose first run starts now.
+    pub fn new(task: TaskLoop) -> Self {
+        Self {
+            task,
+            run_started: Instant::now(),
+            interval: None,
+        }
+    }
+
+    /// Sleeps until the loop should next run, and for as long as read-only mode is on.
+    pub async fn wait_for_next_run(&mut self, pool: &Pool<Sqlite>) {
+        let schedule = match get_schedule(pool, self.task).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Error reading schedule for {:?} loop: {}", self.task, e);
+                self.task.default_schedule()
+            }
+        };
+        // A changed schedule takes effect from the start of the current run.
+        if !matches!(&self.interval, Some((secs, _)) if *secs == schedule.interval_secs) {
+            let period = Duration::from_secs(schedule.interval_secs.max(1));
+            let mut interval = interval_at(self.run_started + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            self.interval = Some((schedule.interval_secs, interval));
+        }
+        if let Some((_, interval)) = &mut self.interval {
+            self.run_started = interval.tick().await;
+        }
+        let jitter = rand::thread_rng().gen_range(0..=schedule.jitter_secs.saturating_mul(1000));
+        tokio::time::sleep(Duration::from_millis(jitter)).await;
+        wait_while_read_only(pool, self.task.name()).await;
+    }
+}
+
+/// Sleeps for as long as read-only mode is on.
+pub async fn wait_while_read_only(pool: &Pool<Sqlite>, name: &str) {
+    if is_read_only(pool).await {
+        info!("Pausing {} while in read-only mode", name);
+        while is_read_only(pool).await {
+            tokio::time::sleep(READ_ONLY_POLL_INTERVAL).await;
+        }
+        info!("Resuming {}", name);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{env, path::PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use sqlx::{Pool, Sqlite};
+use tracing::{error, instrument};
+
+use crate::{
+    models::{ChapterSourceClient, NewChapter},
+    util::content_digest,
+};
+
+/// Somewhere the raw emails and pages chapters are parsed from are kept, by the SHA-256 of
+/// their contents, so a parsing fix can be applied to chapters found before it.
+#[async_trait]
+pub trait SourceStore {
+    async fn put_source(&self, source_key: &str, body: &[u8]) -> anyhow::Result<()>;
+
+    async fn get_source(&self, source_key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+}
+
+/// Keeps sources in the database, alongside their chapters.
+pub struct DatabaseSourceStore {
+    client: ChapterSourceClient,
+}
+
+#[async_trait]
+impl SourceStore for DatabaseSourceStore {
+    async fn put_source(&self, source_key: &str, body: &[u8]) -> anyhow::Result<()> {
+        Ok(self.client.put_source(source_key, body).await?)
+    }
+
+    async fn get_source(&self, source_key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.client.get_source(source_key).await?)
+    }
+}
+
+/// Keeps sources as files in a directory, such as a mounted bucket, to keep them out of the
+/// database.
+pub struct DirectorySourceStore {
+    dir: PathBuf,
+}
+
+impl DirectorySourceStore {
+    fn path(&self, source_key: &str) -> anyhow::Result<PathBuf> {
+        // Keys are hex digests, which can't name a file outside the directory.
+        if source_key.is_empty() || !source_key.chars().all(|x| x.is_ascii_hexdigit()) {
+            bail!("Invalid source key {:?}", source_key);
+        }
+        Ok(self.dir.join(source_key))
+    }
+}
+
+#[async_trait]
+impl SourceStore for DirectorySourceStore {
+    async fn put_source(&self, source_key: &str, body: &[u8]) -> anyhow::Result<()> {
+        let path = self.path(source_key)?;
+        if tokio::fs::metadata(&path).await.is_ok() {
+            return Ok(());
+        }
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        // Written aside and renamed, so a partly written source is never read.
+        let partial = path.with_extension("partial");
+        tokio::fs::write(&partial, body)
+            .await
+            .with_context(|| format!("Failed to write {}", partial.display()))?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+
+    async fn get_source(&self, source_key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(source_key)?).await {
+            Ok(x) => Ok(Some(x)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The store configured by CEREAL_SOURCE_STORE: `database`, the default, `directory` for the
+/// directory in CEREAL_SOURCE_DIR, or `none` to not keep sources.
+pub fn source_store(
+    pool: &Pool<Sqlite>,
+) -> anyhow::Result<Option<Box<dyn SourceStore + Send + Sync>>> {
+    let store = env::var("CEREAL_SOURCE_STORE").unwrap_or_else(|_| String::from("database"));
+    match store.as_str() {
+        "database" => Ok(Some(Box::new(DatabaseSourceStore {
+            client: ChapterSourceClient::new(pool),
+        }))),
+        "directory" => {
+            let dir = env::var("CEREAL_SOURCE_DIR")
+                .context("CEREAL_SOURCE_DIR must be set to store sources in a directory")?;
+            Ok(Some(Box::new(DirectorySourceStore {
+                dir: PathBuf::from(dir),
+            })))
+        }
+        "none" => Ok(None),
+        x => Err(anyhow!("Unknown CEREAL_SOURCE_STORE {:?}", x)),
+    }
+}
+
+/// Keeps the source, returning its key, or None if sources aren't kept or it couldn't be.
+/// Failing to keep a source doesn't stop the chapter being parsed.
+#[instrument(skip(pool, body), fields(body.len = body.len()))]
+pub async fn store_source(pool: &Pool<Sqlite>, body: &[u8]) -> Option<String> {
+    let source_key = content_digest(body);
+    let stored = match source_store(pool) {
+        Ok(Some(store)) => store.put_source(&source_key, body).await,
+        Ok(None) => return None,
+        Err(e) => Err(e),
+    };
+    match stored {
+        Ok(()) => Some(source_key),
+        Err(e) => {
+            error!("Failed to store chapter source {}: {:#}", source_key, e);
+            None
+        }
+    }
+}
+
+/// Keeps the sources of the new chapters, dropping those which couldn't be kept so the
+/// chapters aren't created referring to them.
+pub async fn store_sources(pool: &Pool<Sqlite>, chapters: &mut [NewChapter]) {
+    for chapter in chapters.iter_mut() {
+        if let Some(source) = &chapter.source {
+            if store_source(pool, source).await.is_none() {
+                chapter.source = None;
+            }
+        }
+    }
+}
+
+/// Reads the source stored under the key.
+pub async fn load_source(pool: &Pool<Sqlite>, source_key: &str) -> anyhow::Result<Vec<u8>> {
+    let store = source_store(pool)?.ok_or_else(|| anyhow!("Sources aren't being kept"))?;
+    store
+        .get_source(source_key)
+        .await?
+        .ok_or_else(|| anyhow!("Source {} is missing from the store", source_key))
+}
@@ -0,0 +1,74 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+use sqlx::{Pool, Sqlite};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use crate::{
+    error::ApiResult,
+    models::{Book, ChapterClient, Volume, VolumeClient},
+    providers::detect_volume,
+};
+
+/// Sorts the book's chapters into volumes detected from their titles, creating any volumes
+/// that don't exist yet. Chapters whose titles name no volume follow the chapter before them.
+/// Chapters placed in a volume by hand are left where they are.
+#[instrument(skip_all, fields(book.id = %book.id))]
+pub async fn detect_book_volumes(pool: &Pool<Sqlite>, book: &Book) -> ApiResult<Vec<Volume>> {
+    let volume_client = VolumeClient::new(pool);
+    let mut chapters = ChapterClient::new(pool)
+        .list_chapters_shallow(&book.id)
+        .await?;
+    chapters.sort_by_key(|x| x.order_index);
+
+    let mut volumes: HashMap<i64, Volume> = volume_client
+        .list_volumes(&book.id)
+        .await?
+        .into_iter()
+        .map(|x| (x.number, x))
+        .collect();
+    let detected: HashSet<Uuid> = volumes
+        .values()
+        .filter(|x| x.detected)
+        .map(|x| x.id)
+        .collect();
+
+    let mut assignments = Vec::new();
+    let mut current = None;
+    for chapter in chapters.iter() {
+        if let Some(number) = detect_volume(book, &chapter.title) {
+            current = Some(number);
+        }
+        let number = match current {
+            Some(x) => x,
+            None => continue,
+        };
+        let manual = matches!(chapter.volume_id, Some(x) if !detected.contains(&x));
+        if manual {
+            continue;
+        }
+        if let Entry::Vacant(entry) = volumes.entry(number) {
+            let volume = volume_client
+                .create_volume(&book.id, number, None, true)
+                .await?;
+            info!("Detected volume {} of book {}", number, book.id);
+            entry.insert(volume);
+        }
+        let volume_id = volumes[&number].id;
+        if chapter.volume_id != Some(volume_id) {
+            assignments.push((chapter.id, volume_id));
+        }
+    }
+
+    if !assignments.is_empty() {
+        info!(
+            "Assigning {} chapters of book {} to detected volumes",
+            assignments.len(),
+            book.id
+        );
+        volume_client
+            .assign_chapters(&book.id, &assignments)
+            .await?;
+    }
+    volume_client.list_volumes(&book.id).await
+}
@@ -0,0 +1,302 @@
+use std::{collections::HashSet, env, time::Duration};
+
+use chrono::Utc;
+use rand::{distributions::Alphanumeric, Rng};
+use sqlx::{Pool, Sqlite};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::config,
+    error::{ApiError, ApiResult},
+    models::{
+        changes::{subscribe, Entity},
+        Book, BookClient, LeaseClient, WebSubSubscription, WebSubSubscriptionClient,
+    },
+    providers::discover_websub_hub,
+    telemetry::record_loop_duration,
+};
+
+use super::{
+    chapter_discovery::check_for_new_chapters_in_book,
+    schedule::{LoopTimer, TaskLoop},
+    with_lease,
+};
+
+/// How long chapter changes are gathered before the hub is notified, so a book discovering a
+/// batch of chapters pings the hub once.
+const NOTIFY_DEBOUNCE: Duration = Duration::from_secs(10);
+
+const FEED_FORMATS: &[&str] = &["rss", "atom", "json"];
+
+/// The WebSub hub the output feeds are published to, from CEREAL_WEBSUB_HUB.
+pub fn hub_url() -> Option<String> {
+    env::var("CEREAL_WEBSUB_HUB").ok().filter(|x| !x.is_empty())
+}
+
+/// The path of a book's feeds, or of the feed of every book, less the format.
+pub fn feed_path(book_id: Option<&Uuid>) -> String {
+    match book_id {
+        Some(id) => format!("/books/{}/feeds/", id),
+        None => String::from("/feeds/"),
+    }
+}
+
+/// The absolute urls of the book's feeds in every format, along with the feeds of every book.
+fn feed_urls(base: &str, book_ids: &HashSet<Uuid>) -> Vec<String> {
+    book_ids
+        .iter()
+        .map(Some)
+        .chain([None])
+        .flat_map(|book_id| {
+            FEED_FORMATS.iter().map(move |format| {
+                format!(
+                    "{}{}{}",
+                    base.trim_end_matches('/'),
+                    feed_path(book_id),
+                    format
+                )
+            })
+        })
+        .collect()
+}
+
+/// Notifies the hub whenever chapters are created or updated, which includes their bodies being
+/// hydrated, so subscribers of the output feeds are pushed new chapters instead of polling.
+/// Does nothing unless both CEREAL_WEBSUB_HUB and CEREAL_PUBLIC_URL are set, as the hub must be
+/// able to fetch the feeds. The hub can't send a bearer token, so nothing is published while
+/// `apiTokens` are configured, which close the feeds to it.
+pub async fn websub_publish_loop() {
+    let (hub, base) = match (hub_url(), env::var("CEREAL_PUBLIC_URL")) {
+        (Some(hub), Ok(base)) if !base.is_empty() => (hub, base),
+        (Some(_), _) => {
+            warn!(
+                "CEREAL_WEBSUB_HUB is set without CEREAL_PUBLIC_URL, so feeds won't be published"
+            );
+            return std::future::pending().await;
+        }
+        _ => return std::future::pending().await,
+    };
+    if !config().api_tokens.is_empty() {
+        error!("CEREAL_WEBSUB_HUB is set along with apiTokens, which keep the hub from fetching the feeds, so they won't be published");
+    }
+    info!("Publishing output feeds to WebSub hub {}", hub);
+    let client = reqwest::Client::new();
+    let mut receiver = subscribe();
+    loop {
+        let mut book_ids = HashSet::new();
+        match receiver.recv().await {
+            Ok(change) => {
+                if let (Entity::Chapter, Some(book_id)) = (change.entity, change.book_id) {
+                    book_ids.insert(book_id);
+                } else {
+                    continue;
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!(
+                    "Missed {} changes, only the firehose feeds are published",
+                    missed
+                );
+            }
+            Err(RecvError::Closed) => return,
+        }
+        let deadline = tokio::time::sleep(NOTIFY_DEBOUNCE);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                change = receiver.recv() => match change {
+                    Ok(change) => {
+                        if let (Entity::Chapter, Some(book_id)) = (change.entity, change.book_id) {
+                            book_ids.insert(book_id);
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Missed {} changes while gathering feeds to publish", missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+        // Tokens may have been added by a config reload since the loop started.
+        if !config().api_tokens.is_empty() {
+            warn!(
+                "Not publishing feeds to the WebSub hub, as apiTokens keep it from fetching them"
+            );
+            continue;
+        }
+        for url in feed_urls(&base, &book_ids) {
+            if let Err(e) = notify_hub(&client, &hub, &url).await {
+                error!("Failed to publish {} to WebSub hub: {}", url, e);
+            }
+        }
+    }
+}
+
+#[instrument(skip(client), err)]
+async fn notify_hub(client: &reqwest::Client, hub: &str, url: &str) -> anyhow::Result<()> {
+    client
+        .post(hub)
+        .form(&[("hub.mode", "publish"), ("hub.url", url)])
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// How long subscriptions to source feeds are requested for. Hubs may grant less.
+const LEASE_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+/// Where the hub sends verifications and notifications for the book's subscription.
+fn callback_url(book_id: &Uuid) -> ApiResult<String> {
+    match env::var("CEREAL_PUBLIC_URL") {
+        Ok(base) if !base.is_empty() => Ok(format!(
+            "{}/websub/callback/{}",
+            base.trim_end_matches('/'),
+            book_id
+        )),
+        _ => Err(ApiError::InvalidRequest(String::from(
+            "Set CEREAL_PUBLIC_URL so the hub can reach this server",
+        ))),
+    }
+}
+
+/// Asks the hub of the book's source feed to push it new chapters. The subscription is saved
+/// unverified, and becomes active once the hub confirms it through the callback.
+#[instrument(skip(pool, book), fields(book.id = %book.id))]
+pub async fn subscribe_book(pool: &Pool<Sqlite>, book: &Book) -> ApiResult<WebSubSubscription> {
+    let topic = book.metadata.websub_topic().ok_or_else(|| {
+        ApiError::InvalidRequest(format!(
+            "{} books have no feed which supports WebSub",
+            book.metadata.provider_name()
+        ))
+    })?;
+    let callback = callback_url(&book.id)?;
+    let hub = discover_websub_hub(book.metadata.provider_name(), topic)
+        .await
+        .map_err(|e| ApiError::Upstream(format!("{:#}", e)))?;
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    let subscription = WebSubSubscriptionClient::new(pool)
+        .upsert_subscription(&book.id, &hub, topic, &secret)
+        .await?;
+    let lease_seconds = LEASE_SECONDS.to_string();
+    request_hub(
+        &hub,
+        &[
+            ("hub.mode", "subscribe"),
+            ("hub.topic", topic),
+            ("hub.callback", &callback),
+            ("hub.secret", &secret),
+            ("hub.lease_seconds", &lease_seconds),
+        ],
+    )
+    .await?;
+    Ok(subscription)
+}
+
+/// Asks the hub to stop pushing to the book and forgets the subscription, returning to polling.
+#[instrument(skip(pool))]
+pub async fn unsubscribe_book(pool: &Pool<Sqlite>, book_id: &Uuid) -> ApiResult<()> {
+    // The hub verifies the unsubscription against the one recorded here.
+    let subscription = match WebSubSubscriptionClient::new(pool)
+        .unsubscribe(book_id)
+        .await?
+    {
+        Some(x) => x,
+        None => {
+            return Err(ApiError::ResourceNotFound {
+                resource_type: String::from("websub subscription"),
+                id: book_id.to_string(),
+            })
+        }
+    };
+    let callback = callback_url(book_id)?;
+    request_hub(
+        &subscription.hub,
+        &[
+            ("hub.mode", "unsubscribe"),
+            ("hub.topic", &subscription.topic),
+            ("hub.callback", &callback),
+        ],
+    )
+    .await
+}
+
+async fn request_hub(hub: &str, form: &[(&str, &str)]) -> ApiResult<()> {
+    let response = reqwest::Client::new()
+        .post(hub)
+        .form(form)
+        .send()
+        .await
+        .and_then(|x| x.error_for_status());
+    match response {
+        Ok(_) => Ok(()),
+        Err(e) => Err(ApiError::Upstream(format!("WebSub hub {}: {}", hub, e))),
+    }
+}
+
+/// Checks the book for new chapters after its hub announced an update, sharing the discovery
+/// loop's lease so the book isn't checked twice at once.
+#[instrument(skip(pool))]
+pub async fn discover_pushed_chapters(pool: &Pool<Sqlite>, book_id: Uuid) {
+    let lease = format!("discovery:{}", book_id);
+    let work = check_for_new_chapters_in_book(book_id, pool);
+    with_lease(
+        &LeaseClient::new(pool),
+        &lease,
+        chrono::Duration::minutes(10),
+        work,
+    )
+    .await;
+}
+
+/// Renews subscriptions whose leases end within a day, along with any the hub never verified.
+pub async fn websub_renewal_loop(pool: Pool<Sqlite>) {
+    let lease_client = LeaseClient::new(&pool);
+    let mut timer = LoopTimer::new(TaskLoop::WebSubRenewal);
+    loop {
+        let started = std::time::Instant::now();
+        let work = renew_subscriptions(&pool);
+        with_lease(&lease_client, "websub", chrono::Duration::minutes(10), work).await;
+        record_loop_duration(TaskLoop::WebSubRenewal.name(), started.elapsed());
+        timer.wait_for_next_run(&pool).await;
+    }
+}
+
+async fn renew_subscriptions(pool: &Pool<Sqlite>) {
+    let before = Utc::now() + chrono::Duration::days(1);
+    let subscriptions = match WebSubSubscriptionClient::new(pool)
+        .list_expiring_subscriptions(&before)
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to list expiring WebSub subscriptions: {}", e);
+            return;
+        }
+    };
+    let book_client = BookClient::new(pool);
+    for subscription in subscriptions {
+        let book = match book_client.get_book(&subscription.book_id).await {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Failed to fetch book {}: {}", subscription.book_id, e);
+                continue;
+            }
+        };
+        match subscribe_book(pool, &book).await {
+            Ok(_) => info!("Renewed WebSub subscription for book {}", book.id),
+            Err(e) => error!(
+                "Failed to renew WebSub subscription for book {}: {}",
+                book.id, e
+            ),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram, Meter},
+    Context, KeyValue,
+};
+
+struct Instruments {
+    loop_duration: Histogram<f64>,
+    delivery_latency: Histogram<f64>,
+    provider_requests: Counter<u64>,
+    provider_errors: Counter<u64>,
+}
+
+static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+static QUEUE_DEPTHS: OnceLock<Mutex<HashMap<&'static str, u64>>> = OnceLock::new();
+
+fn meter() -> Meter {
+    global::meter("cereal")
+}
+
+/// Instruments are created on first use, which is after the meter provider is installed.
+fn instruments() -> &'static Instruments {
+    INSTRUMENTS.get_or_init(|| {
+        let meter = meter();
+        Instruments {
+            loop_duration: meter
+                .f64_histogram("cereal.loop.duration")
+                .with_description("Seconds taken by one run of a background loop.")
+                .init(),
+            delivery_latency: meter
+                .f64_histogram("cereal.delivery.latency")
+                .with_description("Seconds from a chapter's discovery to its delivery.")
+                .init(),
+            provider_requests: meter
+                .u64_counter("cereal.provider.requests")
+                .with_description("Chapter discovery and fetch attempts, by provider.")
+                .init(),
+            provider_errors: meter
+                .u64_counter("cereal.provider.errors")
+                .with_description("Failed chapter discovery and fetch attempts, by provider.")
+                .init(),
+        }
+    })
+}
+
+fn queue_depths() -> &'static Mutex<HashMap<&'static str, u64>> {
+    QUEUE_DEPTHS.get_or_init(Default::default)
+}
+
+/// Reports the latest depth of each stage's queue whenever metrics are collected.
+pub(super) fn register_queue_depth_gauge() {
+    let meter = meter();
+    let gauge = meter
+        .u64_observable_gauge("cereal.queue.depth")
+        .with_description("Work items waiting at each pipeline stage when last checked.")
+        .init();
+    let result = meter.register_callback(move |cx| {
+        for (stage, depth) in queue_depths().lock().unwrap().iter() {
+            gauge.observe(cx, *depth, &[KeyValue::new("stage", *stage)]);
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("Failed to register the queue depth gauge: {}", e);
+    }
+}
+
+pub fn record_loop_duration(task: &'static str, duration: Duration) {
+    instruments().loop_duration.record(
+        &Context::current(),
+        duration.as_secs_f64(),
+        &[KeyValue::new("task", task)],
+    );
+}
+
+/// Records the time from a chapter's discovery to its delivery to one subscription.
+pub fn record_delivery_latency(provider: &'static str, latency: Duration) {
+    instruments().delivery_latency.record(
+        &Context::current(),
+        latency.as_secs_f64(),
+        &[KeyValue::new("provider", provider)],
+    );
+}
+
+/// Counts a discovery or fetch against the provider, for error rates.
+pub fn record_provider_result(provider: &'static str, operation: &'static str, success: bool) {
+    let attributes = [
+        KeyValue::new("provider", provider),
+        KeyValue::new("operation", operation),
+    ];
+    let cx = Context::current();
+    instruments().provider_requests.add(&cx, 1, &attributes);
+    if !success {
+        instruments().provider_errors.add(&cx, 1, &attributes);
+    }
+}
+
+pub fn record_queue_depth(stage: &'static str, depth: usize) {
+    queue_depths().lock().unwrap().insert(stage, depth as u64);
+}
@@ -0,0 +1,185 @@
+mod metrics;
+mod propagation;
+
+use std::{env, sync::OnceLock, time::Duration};
+
+use opentelemetry::{
+    global,
+    metrics::MetricsError,
+    runtime::Tokio,
+    sdk::{
+        export::metrics::aggregation::cumulative_temporality_selector,
+        metrics::{controllers::BasicController, selectors},
+        propagation::TraceContextPropagator,
+        trace::{self, Tracer},
+        Resource,
+    },
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tracing_subscriber::{
+    prelude::__tracing_subscriber_SubscriberExt, reload, EnvFilter, Registry,
+};
+
+use crate::{
+    config::secret,
+    error::{ApiError, ApiResult},
+};
+
+/// The log filter used until the config sets one. It filters out low-level debug tracing, such
+/// as from the tokio executor.
+const DEFAULT_LOG_FILTER: &str = "info";
+
+/// Swaps the log filter while the process runs, for config reloads.
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+pub use metrics::{
+    record_delivery_latency, record_loop_duration, record_provider_result, record_queue_depth,
+};
+pub use propagation::{continue_trace, current_trace_context, link_trace, with_trace_context};
+
+/// Where traces and metrics are exported to.
+struct ExporterConfig {
+    endpoint: String,
+    metadata: MetadataMap,
+}
+
+/// Reads OTEL_EXPORTER_OTLP_ENDPOINT and OTEL_EXPORTER_OTLP_HEADERS, a comma separated list of
+/// `key=value` pairs, to export to any OTLP receiver. Failing that, HONEYCOMB_API_KEY and
+/// HONEYCOMB_DATASET export to Honeycomb. Telemetry is only logged if neither is set.
+fn exporter_config() -> Option<ExporterConfig> {
+    let mut metadata = MetadataMap::new();
+    let endpoint = match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) => endpoint,
+        Err(_) => {
+            secret("HONEYCOMB_API_KEY")?;
+            String::from("https://api.honeycomb.io")
+        }
+    };
+    let mut headers: Vec<(String, String)> = Vec::new();
+    if let Some(key) = secret("HONEYCOMB_API_KEY") {
+        headers.push((String::from("x-honeycomb-team"), key));
+    }
+    if let Ok(dataset) = env::var("HONEYCOMB_DATASET") {
+        headers.push((String::from("x-honeycomb-dataset"), dataset));
+    }
+    if let Ok(configured) = env::var("OTEL_EXPORTER_OTLP_HEADERS") {
+        headers.extend(
+            configured
+                .split(',')
+                .filter_map(|x| x.split_once('='))
+                .map(|(key, value)| (key.trim().to_lowercase(), value.trim().to_owned())),
+        );
+    }
+    for (key, value) in headers {
+        match (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value.as_str()),
+        ) {
+            (Ok(key), Ok(value)) => {
+                metadata.insert(key, value);
+            }
+            _ => eprintln!("Ignoring invalid OTLP header {:?}", key),
+        }
+    }
+    Some(ExporterConfig { endpoint, metadata })
+}
+
+fn resource() -> Resource {
+    Resource::new(vec![KeyValue::new(
+        opentelemetry_semantic_conventions::resource::SERVICE_NAME,
+        "cereal_rewrite".to_string(),
+    )])
+}
+
+fn install_tracer(config: &ExporterConfig) -> Result<Tracer, TraceError> {
+    let otlp_exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(&config.endpoint)
+        .with_metadata(config.metadata.clone());
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(otlp_exporter)
+        .with_trace_config(trace::config().with_resource(resource()))
+        .install_batch(Tokio)
+}
+
+fn install_meter(config: &ExporterConfig) -> Result<BasicController, MetricsError> {
+    let otlp_exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(&config.endpoint)
+        .with_metadata(config.metadata.clone());
+    // Boundaries in seconds, covering both loop durations and delivery latencies of up to a day.
+    let boundaries = vec![
+        0.1,
+        0.5,
+        1.0,
+        5.0,
+        10.0,
+        30.0,
+        60.0,
+        300.0,
+        900.0,
+        3600.0,
+        4.0 * 3600.0,
+        24.0 * 3600.0,
+    ];
+    opentelemetry_otlp::new_pipeline()
+        .metrics(
+            selectors::simple::histogram(boundaries),
+            cumulative_temporality_selector(),
+            Tokio,
+        )
+        .with_exporter(otlp_exporter)
+        .with_resource(resource())
+        .with_period(Duration::from_secs(60))
+        .build()
+}
+
+/// Installs the tracing subscriber, and the trace and metric exporters if configured.
+pub fn configure_telemetry() {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = exporter_config().and_then(|config| {
+        // Installing the meter provider makes it the global one that instruments are made from.
+        if let Err(e) = install_meter(&config) {
+            eprintln!("Failed to install the OTLP metrics pipeline: {}", e);
+        }
+        match install_tracer(&config) {
+            Ok(tracer) => Some(tracer),
+            Err(e) => {
+                eprintln!("Failed to install the OTLP trace pipeline: {}", e);
+                None
+            }
+        }
+    });
+    metrics::register_queue_depth_gauge();
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(DEFAULT_LOG_FILTER));
+    let _ = LOG_FILTER.set(handle);
+    let subscriber = Registry::default() // provide underlying span data store
+        .with(filter)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer))) // publish to the otlp backend
+        .with(tracing_subscriber::fmt::Layer::new());
+    tracing::subscriber::set_global_default(subscriber).unwrap();
+}
+
+/// Parses log filter directives in the syntax of RUST_LOG, or the default filter if None.
+pub fn parse_log_filter(directives: Option<&str>) -> ApiResult<EnvFilter> {
+    EnvFilter::try_new(directives.unwrap_or(DEFAULT_LOG_FILTER))
+        .map_err(|e| ApiError::InvalidRequest(format!("Invalid log filter: {}", e)))
+}
+
+/// The directives of the log filter in effect, if telemetry has been configured.
+pub fn log_filter() -> Option<String> {
+    LOG_FILTER.get()?.with_current(|x| x.to_string()).ok()
+}
+
+/// Replaces the log filter, if telemetry has been configured.
+pub fn set_log_filter(filter: EnvFilter) {
+    if let Some(handle) = LOG_FILTER.get() {
+        if let Err(e) = handle.reload(filter) {
+            tracing::error!("Failed to replace the log filter: {}", e);
+        }
+    }
+}
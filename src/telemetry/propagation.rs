@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+use opentelemetry::{global, propagation::Injector, trace::TraceContextExt};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    RequestBuilder,
+};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl<'a> Injector for HeaderInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Adds W3C trace context headers for the current span to an outbound request, so that the
+/// receiving service's spans join our trace.
+pub fn with_trace_context(request: RequestBuilder) -> RequestBuilder {
+    let mut headers = HeaderMap::new();
+    let cx = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut HeaderInjector(&mut headers))
+    });
+    request.headers(headers)
+}
+
+/// The current span's W3C traceparent, which is stored with new chapters so that the spans
+/// that later process them join the trace of their discovery.
+pub fn current_trace_context() -> Option<String> {
+    let mut carrier: HashMap<String, String> = HashMap::new();
+    let cx = Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut carrier));
+    carrier.remove("traceparent")
+}
+
+fn extract_trace_context(traceparent: &str) -> opentelemetry::Context {
+    let carrier = HashMap::from([(String::from("traceparent"), traceparent.to_owned())]);
+    global::get_text_map_propagator(|propagator| propagator.extract(&carrier))
+}
+
+/// Makes the current span a child of a stored trace context.
+pub fn continue_trace(traceparent: Option<&str>) {
+    if let Some(traceparent) = traceparent {
+        Span::current().set_parent(extract_trace_context(traceparent));
+    }
+}
+
+/// Links the current span to a stored trace context, for work which continues several traces.
+pub fn link_trace(traceparent: Option<&str>) {
+    if let Some(traceparent) = traceparent {
+        let cx = extract_trace_context(traceparent);
+        Span::current().add_link(cx.span().span_context().clone());
+    }
+}
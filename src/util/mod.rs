@@ -1,6 +1,51 @@
+mod zip_stream;
+
+pub use zip_stream::ZipStream;
+
+/// The Content-Security-Policy stored chapter bodies are served under, since their html is kept
+/// as the source served it. Scripts, forms and plugins are blocked while images and inline
+/// styles still render.
+pub const STORED_BODY_CSP: &str =
+    "default-src 'none'; img-src * data:; style-src 'unsafe-inline'; sandbox";
+
 pub fn is_foreign_key_error(error: &sqlx::Error) -> bool {
     match error {
         sqlx::Error::Database(error) => matches!(error.message(), "FOREIGN KEY constraint failed"),
         _ => false,
     }
 }
+
+pub fn is_unique_error(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(error) => error.message().starts_with("UNIQUE constraint failed"),
+        _ => false,
+    }
+}
+
+/// The hex encoded SHA-256 of the content.
+pub fn content_digest(content: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(content)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+/// A strong entity tag for the content, quoted as it appears in headers.
+pub fn content_etag(content: &[u8]) -> String {
+    format!("\"{}\"", content_digest(content))
+}
+
+/// Whether the request's If-None-Match header matches the entity tag, meaning the client's copy
+/// is current. The header may list several tags, and weak tags match their strong form.
+pub fn is_not_modified(headers: &axum::http::HeaderMap, etag: &str) -> bool {
+    headers
+        .get(axum::http::header::IF_NONE_MATCH)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| {
+            x.split(',')
+                .map(|x| x.trim())
+                .any(|x| x == "*" || x.trim_start_matches("W/") == etag)
+        })
+        .unwrap_or(false)
+}
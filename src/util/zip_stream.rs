@@ -0,0 +1,130 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+
+/// Writes a zip archive one file at a time, so it can be streamed without holding the whole
+/// archive in memory. Files are stored uncompressed, which suits epubs as they are already
+/// compressed. Only the 32 bit format is written, so archives are limited to 4GiB and 65535
+/// files.
+#[derive(Debug, Default)]
+pub struct ZipStream {
+    central_directory: Vec<u8>,
+    entries: u16,
+    offset: u32,
+}
+
+struct DosDateTime {
+    time: u16,
+    date: u16,
+}
+
+impl From<DateTime<Utc>> for DosDateTime {
+    fn from(value: DateTime<Utc>) -> Self {
+        // Dos timestamps start in 1980 and count seconds in twos.
+        let year = value.year().clamp(1980, 2107) as u16;
+        DosDateTime {
+            time: ((value.hour() as u16) << 11)
+                | ((value.minute() as u16) << 5)
+                | ((value.second() as u16) / 2),
+            date: ((year - 1980) << 9) | ((value.month() as u16) << 5) | (value.day() as u16),
+        }
+    }
+}
+
+/// Version 2.0 of the spec, as the earliest supporting everything written here.
+const VERSION: u16 = 20;
+/// File names are utf-8.
+const UTF8_FLAG: u16 = 1 << 11;
+
+impl ZipStream {
+    pub fn new() -> ZipStream {
+        ZipStream::default()
+    }
+
+    /// Returns the bytes which add the file to the archive.
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        content: &[u8],
+        modified: DateTime<Utc>,
+    ) -> Result<Vec<u8>> {
+        let size = match u32::try_from(content.len()) {
+            Ok(x) => x,
+            Err(_) => bail!("{} is too large for a zip archive", name),
+        };
+        if self.entries == u16::MAX {
+            bail!("Too many files for a zip archive");
+        }
+        let crc = crc32fast::hash(content);
+        let modified = DosDateTime::from(modified);
+        let name = name.as_bytes();
+
+        let mut local_header = Vec::with_capacity(30 + name.len());
+        local_header.extend(0x04034b50u32.to_le_bytes());
+        local_header.extend(VERSION.to_le_bytes());
+        local_header.extend(UTF8_FLAG.to_le_bytes());
+        // Stored, without compression.
+        local_header.extend(0u16.to_le_bytes());
+        local_header.extend(modified.time.to_le_bytes());
+        local_header.extend(modified.date.to_le_bytes());
+        local_header.extend(crc.to_le_bytes());
+        local_header.extend(size.to_le_bytes());
+        local_header.extend(size.to_le_bytes());
+        local_header.extend((name.len() as u16).to_le_bytes());
+        // No extra field.
+        local_header.extend(0u16.to_le_bytes());
+        local_header.extend(name);
+
+        let entry_size = local_header.len() as u64 + content.len() as u64;
+        let next_offset = match u32::try_from(self.offset as u64 + entry_size) {
+            Ok(x) => x,
+            Err(_) => bail!("The zip archive would be larger than 4GiB"),
+        };
+
+        let directory = &mut self.central_directory;
+        directory.extend(0x02014b50u32.to_le_bytes());
+        directory.extend(VERSION.to_le_bytes());
+        directory.extend(VERSION.to_le_bytes());
+        directory.extend(UTF8_FLAG.to_le_bytes());
+        directory.extend(0u16.to_le_bytes());
+        directory.extend(modified.time.to_le_bytes());
+        directory.extend(modified.date.to_le_bytes());
+        directory.extend(crc.to_le_bytes());
+        directory.extend(size.to_le_bytes());
+        directory.extend(size.to_le_bytes());
+        directory.extend((name.len() as u16).to_le_bytes());
+        // Extra field, comment, disk number, internal and external attributes.
+        directory.extend(0u16.to_le_bytes());
+        directory.extend(0u16.to_le_bytes());
+        directory.extend(0u16.to_le_bytes());
+        directory.extend(0u16.to_le_bytes());
+        directory.extend(0u32.to_le_bytes());
+        directory.extend(self.offset.to_le_bytes());
+        directory.extend(name);
+
+        self.entries += 1;
+        self.offset = next_offset;
+        let mut bytes = local_header;
+        bytes.extend(content);
+        Ok(bytes)
+    }
+
+    /// Returns the bytes which end the archive, listing every file added.
+    pub fn finish(self) -> Result<Vec<u8>> {
+        let directory_size = self.central_directory.len() as u32;
+        if u32::try_from(self.offset as u64 + directory_size as u64).is_err() {
+            bail!("The zip archive would be larger than 4GiB");
+        }
+        let mut bytes = self.central_directory;
+        bytes.extend(0x06054b50u32.to_le_bytes());
+        // This disk and the disk the directory starts on.
+        bytes.extend(0u16.to_le_bytes());
+        bytes.extend(0u16.to_le_bytes());
+        bytes.extend(self.entries.to_le_bytes());
+        bytes.extend(self.entries.to_le_bytes());
+        bytes.extend(directory_size.to_le_bytes());
+        bytes.extend(self.offset.to_le_bytes());
+        // No comment.
+        bytes.extend(0u16.to_le_bytes());
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,149 @@
+//! Checks that uploaded archives are split into chapters in reading order, skipping files which
+//! aren't chapters, within the limits on how much they decompress to, and that prepended
+//! chapters go before a book's existing chapters.
+
+use std::io::{Cursor, Write};
+
+mod common;
+
+use cereal_rewrite::{
+    models::{ChapterClient, ChapterMetadata, NewChapter},
+    providers::{split_archive, ArchiveLimits},
+};
+use common::{connect_memory_db, insert_book};
+use zip::{write::FileOptions, ZipWriter};
+
+fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (path, contents) in files {
+        zip.start_file(*path, FileOptions::default()).unwrap();
+        zip.write_all(contents.as_bytes()).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<html><head><title>{}</title></head><body>{}</body></html>",
+        title, body
+    )
+}
+
+#[test]
+fn html_files_are_taken_in_name_order() {
+    let archive = archive(&[
+        ("02.html", &page("Second", "<h1>Chapter 2</h1><p>Two</p>")),
+        ("style.css", "p { margin: 0 }"),
+        ("01.xhtml", &page("First", "<p>One</p>")),
+        ("cover.html", &page("Cover", "<img src=\"cover.jpg\"/>")),
+        ("__MACOSX/._01.xhtml", "resource fork"),
+    ]);
+    let chapters = split_archive(&archive, ArchiveLimits::default()).unwrap();
+    let titles: Vec<(&str, &str)> = chapters
+        .iter()
+        .map(|x| (x.title.as_str(), x.source.as_str()))
+        .collect();
+    assert_eq!(titles, [("First", "01.xhtml"), ("Chapter 2", "02.html")]);
+    assert_eq!(chapters[0].html, b"<p>One</p>");
+}
+
+#[test]
+fn epub_chapters_follow_the_spine() {
+    let package = concat!(
+        "<package><manifest>",
+        "<item id=\"nav\" href=\"nav.xhtml\" properties=\"nav\"/>",
+        "<item id=\"a\" href=\"text/a.xhtml\"/>",
+        "<item id=\"b\" href=\"text/chapter%20b.xhtml\"/>",
+        "<item id=\"css\" href=\"style.css\"/>",
+        "</manifest><spine>",
+        "<itemref idref=\"nav\"/><itemref idref=\"b\"/><itemref idref=\"a\"/>",
+        "</spine></package>"
+    );
+    let archive = archive(&[
+        (
+            "META-INF/container.xml",
+            "<container><rootfiles><rootfile full-path=\"OEBPS/content.opf\"/></rootfiles></container>",
+        ),
+        ("OEBPS/content.opf", package),
+        ("OEBPS/nav.xhtml", &page("Contents", "<p>A, B</p>")),
+        ("OEBPS/text/a.xhtml", &page("A", "<p>A</p>")),
+        ("OEBPS/text/chapter b.xhtml", &page("B", "<p>B</p>")),
+        ("OEBPS/style.css", "p { margin: 0 }"),
+    ]);
+    let chapters = split_archive(&archive, ArchiveLimits::default()).unwrap();
+    let sources: Vec<&str> = chapters.iter().map(|x| x.source.as_str()).collect();
+    assert_eq!(
+        sources,
+        ["OEBPS/text/chapter b.xhtml", "OEBPS/text/a.xhtml"]
+    );
+}
+
+#[test]
+fn archives_are_read_within_their_limits() {
+    let archive = archive(&[
+        ("01.html", &page("First", &"a".repeat(1000))),
+        ("02.html", &page("Second", &"b".repeat(1000))),
+    ]);
+    let limits = |file_bytes, total_bytes| ArchiveLimits {
+        file_bytes,
+        total_bytes,
+    };
+    assert_eq!(
+        split_archive(&archive, limits(2000, 4000)).unwrap().len(),
+        2
+    );
+    let error = split_archive(&archive, limits(500, 4000)).unwrap_err();
+    assert!(format!("{:#}", error).contains("01.html is larger than the 500 byte limit"));
+    let error = split_archive(&archive, limits(2000, 1500)).unwrap_err();
+    assert!(format!("{:#}", error).contains("larger than the 1500 byte limit once decompressed"));
+    assert!(split_archive(b"not a zip", ArchiveLimits::default()).is_err());
+}
+
+#[tokio::test]
+async fn prepended_chapters_go_before_existing_chapters() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await;
+    let client = ChapterClient::new(&pool);
+    let existing = client
+        .create_chapter(
+            &book.id,
+            "Online",
+            &ChapterMetadata::RoyalRoad {
+                royalroad_book_id: 1,
+                royalroad_chapter_id: 1,
+            },
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    let imported: Vec<NewChapter> = ["Lost 1", "Lost 2"]
+        .iter()
+        .enumerate()
+        .map(|(ordinal, title)| NewChapter {
+            title: title.to_string(),
+            raw_title: None,
+            deliver_after: None,
+            metadata: ChapterMetadata::Imported {
+                source: format!("{}.html", ordinal),
+            },
+            book_id: book.id,
+            html: Some(b"<p>Lost</p>".to_vec()),
+            epub: None,
+            published_at: None,
+            ordinal: ordinal as i64,
+            source: None,
+        })
+        .collect();
+    let prepended = client.prepend_chapters(&book.id, &imported).await.unwrap();
+    assert!(prepended.iter().all(|x| x.created_at < existing.created_at));
+
+    let chapters = client.list_chapters_shallow(&book.id).await.unwrap();
+    let mut order: Vec<(i64, &str)> = chapters
+        .iter()
+        .map(|x| (x.order_index, x.title.as_str()))
+        .collect();
+    order.sort();
+    assert_eq!(order, [(1, "Lost 1"), (2, "Lost 2"), (3, "Online")]);
+}
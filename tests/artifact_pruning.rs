@@ -0,0 +1,86 @@
+//! Checks that book artifacts are pruned once superseded by a chapter revision or a new
+//! conversion version, and kept while they can't be regenerated.
+
+mod common;
+
+use cereal_rewrite::models::{
+    Book, BookArtifactClient, Chapter, ChapterClient, ChapterMetadata, OMNIBUS_ARTIFACT,
+};
+use common::{connect_memory_db, insert_book};
+use sqlx::{Pool, Sqlite};
+
+async fn insert_book_with_chapters(pool: &Pool<Sqlite>, book_id: u64) -> (Book, Vec<Chapter>) {
+    let book = insert_book(pool, book_id).await;
+    let mut chapters = Vec::new();
+    for royalroad_chapter_id in 1..=2 {
+        let metadata = ChapterMetadata::RoyalRoad {
+            royalroad_book_id: book_id,
+            royalroad_chapter_id,
+        };
+        let html = format!("<p>Chapter {}</p>", royalroad_chapter_id).into_bytes();
+        let chapter = ChapterClient::new(pool)
+            .create_chapter(&book.id, "Chapter", &metadata, Some(&html), None, None)
+            .await
+            .unwrap();
+        chapters.push(chapter);
+    }
+    (book, chapters)
+}
+
+#[tokio::test]
+async fn superseded_artifacts_are_pruned() {
+    let pool = connect_memory_db().await.unwrap();
+    let artifacts = BookArtifactClient::new(&pool);
+    let chapter_client = ChapterClient::new(&pool);
+    let (revised, revised_chapters) = insert_book_with_chapters(&pool, 1).await;
+    let (pruned, pruned_chapters) = insert_book_with_chapters(&pool, 2).await;
+    for (book, chapters) in [(&revised, &revised_chapters), (&pruned, &pruned_chapters)] {
+        artifacts
+            .save_artifact(&book.id, OMNIBUS_ARTIFACT, b"omnibus", chapters, 1)
+            .await
+            .unwrap();
+    }
+    assert_eq!(artifacts.prune_superseded_artifacts(1).await.unwrap(), 0);
+
+    // Regenerating the html unchanged leaves the lineage intact.
+    chapter_client
+        .replace_parsed(
+            &revised_chapters[0].id,
+            None,
+            None,
+            None,
+            b"<p>Chapter 1</p>",
+        )
+        .await
+        .unwrap();
+    assert_eq!(artifacts.prune_superseded_artifacts(1).await.unwrap(), 0);
+
+    chapter_client
+        .replace_parsed(&revised_chapters[0].id, None, None, None, b"<p>Revised</p>")
+        .await
+        .unwrap();
+    // The pruned book's omnibus is its only copy of the chapters, so it outlives a new version.
+    chapter_client
+        .prune_chapter_bodies(&pruned.id)
+        .await
+        .unwrap();
+    assert_eq!(artifacts.prune_superseded_artifacts(2).await.unwrap(), 1);
+    assert!(artifacts
+        .get_artifact(&revised.id, OMNIBUS_ARTIFACT)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(artifacts
+        .get_artifact(&pruned.id, OMNIBUS_ARTIFACT)
+        .await
+        .unwrap()
+        .is_some());
+
+    let chapters = chapter_client.list_chapters(&revised.id).await.unwrap();
+    artifacts
+        .save_artifact(&revised.id, OMNIBUS_ARTIFACT, b"omnibus", &chapters, 2)
+        .await
+        .unwrap();
+    assert_eq!(artifacts.prune_superseded_artifacts(2).await.unwrap(), 0);
+    assert_eq!(artifacts.prune_superseded_artifacts(3).await.unwrap(), 1);
+}
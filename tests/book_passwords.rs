@@ -0,0 +1,109 @@
+//! Checks that a book's password and a subscriber's read-later token are never serialized or
+//! logged, and that setting the password reaches every chapter a subscription has yet to
+//! receive, fetched or not.
+
+mod common;
+
+use cereal_rewrite::models::{
+    BookClient, BookMetadata, ChapterClient, ChapterMetadata, DeliveryFormat, ReadLaterService,
+    SubscriberClient, SubscriptionClient, SubscriptionDefaults,
+};
+use common::{connect_memory_db, create_book};
+
+#[tokio::test]
+async fn password_reaches_undelivered_chapters_only() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = create_book(&pool, &BookMetadata::TheWanderingInnPatreon).await;
+    let client = ChapterClient::new(&pool);
+    let mut chapters = Vec::new();
+    for (chapter, html) in [("9.50", Some(b"<p>Fetched</p>".to_vec())), ("9.51", None)] {
+        let metadata = ChapterMetadata::TheWanderingInnPatreon {
+            url: format!("https://wanderinginn.com/{}", chapter),
+            password: Some(String::from("old")),
+        };
+        let created = client
+            .create_chapter(&book.id, chapter, &metadata, html.as_ref(), None, None)
+            .await
+            .unwrap();
+        chapters.push(created);
+    }
+    let subscriber = SubscriberClient::new(&pool)
+        .create_subscriber(
+            "reader",
+            None,
+            None,
+            Some("reader@example.com"),
+            None,
+            None,
+            &SubscriptionDefaults::default(),
+        )
+        .await
+        .unwrap();
+
+    // With no subscriptions nothing has been delivered, so every chapter takes the password.
+    assert_eq!(
+        client
+            .set_password_for_undelivered_chapters(&book.id, Some("new"))
+            .await
+            .unwrap(),
+        2
+    );
+    SubscriptionClient::new(&pool)
+        .create_subscription(
+            &subscriber.id,
+            &book.id,
+            None,
+            None,
+            DeliveryFormat::default(),
+            Some(&chapters[0].id),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .set_password_for_undelivered_chapters(&book.id, Some("newer"))
+            .await
+            .unwrap(),
+        1
+    );
+    let password = |metadata: ChapterMetadata| match metadata {
+        ChapterMetadata::TheWanderingInnPatreon { password, .. } => password,
+        _ => None,
+    };
+    let delivered = client.get_chapter(chapters[0].id).await.unwrap().unwrap();
+    let undelivered = client.get_chapter(chapters[1].id).await.unwrap().unwrap();
+    assert_eq!(password(delivered.metadata).as_deref(), Some("new"));
+    assert_eq!(password(undelivered.metadata).as_deref(), Some("newer"));
+
+    let book = BookClient::new(&pool)
+        .set_book_password(&book.id, Some("hunter2"), None)
+        .await
+        .unwrap();
+    assert!(!serde_json::to_string(&book).unwrap().contains("hunter2"));
+    assert!(!format!("{:?}", book).contains("hunter2"));
+}
+
+#[tokio::test]
+async fn read_later_token_is_never_serialized_or_logged() {
+    let pool = connect_memory_db().await.unwrap();
+    let subscriber = SubscriberClient::new(&pool)
+        .create_subscriber(
+            "reader",
+            None,
+            None,
+            None,
+            Some((ReadLaterService::Instapaper, "reader:hunter2")),
+            None,
+            &SubscriptionDefaults::default(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        subscriber.read_later_token.as_deref(),
+        Some("reader:hunter2")
+    );
+    assert!(!serde_json::to_string(&subscriber)
+        .unwrap()
+        .contains("hunter2"));
+    assert!(!format!("{:?}", subscriber).contains("hunter2"));
+}
@@ -0,0 +1,69 @@
+//! Checks that books are listed with the count of their chapters, their latest chapter and the
+//! chapters still in the pipeline, including books with no chapters yet.
+
+mod common;
+
+use cereal_rewrite::models::{BookClient, ChapterClient, ChapterMetadata};
+use chrono::{TimeZone, Utc};
+use common::{connect_memory_db, insert_book};
+
+#[tokio::test]
+async fn books_are_listed_with_their_chapters_summarized() {
+    let pool = connect_memory_db().await.unwrap();
+    let (book, empty) = (
+        insert_book(&pool, 1).await.id,
+        insert_book(&pool, 2).await.id,
+    );
+    let client = ChapterClient::new(&pool);
+    let metadata = |royalroad_chapter_id| ChapterMetadata::RoyalRoad {
+        royalroad_book_id: 1,
+        royalroad_chapter_id,
+    };
+    let body = b"<p>Chapter</p>".to_vec();
+    let published_at = Utc.with_ymd_and_hms(2023, 1, 2, 12, 0, 0).unwrap();
+    client
+        .create_chapter(
+            &book,
+            "1. Converted",
+            &metadata(1),
+            Some(&body),
+            Some(&body),
+            None,
+        )
+        .await
+        .unwrap();
+    client
+        .create_chapter(&book, "2. Hydrated", &metadata(2), Some(&body), None, None)
+        .await
+        .unwrap();
+    client
+        .create_chapter(
+            &book,
+            "3. Discovered",
+            &metadata(3),
+            None,
+            None,
+            Some(published_at),
+        )
+        .await
+        .unwrap();
+
+    let summaries = BookClient::new(&pool)
+        .list_book_summaries(None)
+        .await
+        .unwrap();
+    let summary = summaries.iter().find(|x| x.book.id == book).unwrap();
+    assert_eq!(summary.chapter_count, 3);
+    assert_eq!(summary.pipeline_backlog, 2);
+    assert_eq!(
+        summary.latest_chapter_title.as_deref(),
+        Some("3. Discovered")
+    );
+    assert_eq!(summary.latest_chapter_at, Some(published_at));
+
+    let summary = summaries.iter().find(|x| x.book.id == empty).unwrap();
+    assert_eq!(summary.chapter_count, 0);
+    assert_eq!(summary.pipeline_backlog, 0);
+    assert_eq!(summary.latest_chapter_title, None);
+    assert_eq!(summary.latest_chapter_at, None);
+}
@@ -0,0 +1,34 @@
+//! Fixtures shared by the integration tests.
+
+#![allow(dead_code)]
+
+pub use cereal_rewrite::connect_memory_db;
+use cereal_rewrite::models::{
+    Book, BookClient, BookMetadata, ConversionOptions, DeliveryTemplates, RoyalRoadOptions,
+};
+use sqlx::{Pool, Sqlite};
+
+/// Creates a book with the default conversion options and templates.
+pub async fn create_book(pool: &Pool<Sqlite>, metadata: &BookMetadata) -> Book {
+    BookClient::new(pool)
+        .create_book(
+            "Title",
+            "Author",
+            metadata,
+            &ConversionOptions::default(),
+            &DeliveryTemplates::default(),
+            false,
+        )
+        .await
+        .unwrap()
+}
+
+/// Creates a royalroad book, as the database is created with a book for each of the other
+/// providers, which can't have another.
+pub async fn insert_book(pool: &Pool<Sqlite>, royalroad_book_id: u64) -> Book {
+    let metadata = BookMetadata::RoyalRoad {
+        book_id: royalroad_book_id,
+        options: RoyalRoadOptions::default(),
+    };
+    create_book(pool, &metadata).await
+}
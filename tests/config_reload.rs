@@ -0,0 +1,53 @@
+//! Checks that reloading the config file applies its changes, and that a file with invalid
+//! values is refused while the config in effect is kept.
+
+use std::{env, fs};
+
+use cereal_rewrite::{
+    config::{config, load_config},
+    error::ApiError,
+    tasks::schedule::{LoopSchedule, TaskLoop},
+};
+
+#[test]
+fn reloading_applies_valid_config_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cereal.json");
+    env::set_var("CEREAL_CONFIG", &path);
+
+    fs::write(
+        &path,
+        r#"{
+            "logFilter": "info,cereal_rewrite::providers=debug",
+            "hostRateLimits": {"royalroad.com": 3.5},
+            "taskSchedules": {"discovery": {"intervalSecs": 60, "jitterSecs": 5}}
+        }"#,
+    )
+    .unwrap();
+    load_config().unwrap();
+    assert_eq!(config().host_rate_limits["royalroad.com"], 3.5);
+    assert_eq!(
+        config().task_schedules[&TaskLoop::Discovery],
+        LoopSchedule {
+            interval_secs: 60,
+            jitter_secs: 5
+        }
+    );
+
+    for invalid in [
+        r#"{"hostRateLimits": {"royalroad.com": -1}}"#,
+        r#"{"hostRateLimits": {"royalroad.com": 1e300}}"#,
+        r#"{"taskSchedules": {"delivery": {"intervalSecs": 0, "jitterSecs": 0}}}"#,
+        r#"{"logFilter": "cereal_rewrite=loud"}"#,
+    ] {
+        fs::write(&path, invalid).unwrap();
+        let error = load_config().unwrap_err();
+        assert!(matches!(error, ApiError::InvalidRequest(_)), "{}", error);
+        assert_eq!(config().host_rate_limits["royalroad.com"], 3.5);
+    }
+
+    fs::write(&path, "{}").unwrap();
+    load_config().unwrap();
+    assert!(config().host_rate_limits.is_empty());
+    assert!(config().task_schedules.is_empty());
+}
@@ -0,0 +1,108 @@
+//! Checks that credentials are stored encrypted, are read back into the process for providers
+//! once written, rotated or deleted, and fall back to the environment, and that a credential a
+//! book's password comes from can't be deleted.
+
+mod common;
+
+use std::env;
+
+use cereal_rewrite::{
+    error::ApiError,
+    models::{credential, BookClient, CredentialClient},
+};
+use common::{connect_memory_db, insert_book};
+
+#[tokio::test]
+async fn credentials_are_encrypted_and_rotated() {
+    env::set_var("CEREAL_CREDENTIALS_KEY", "a key only the tests know");
+    env::set_var("CEREAL_TEST_SITE_PASSWORD", "from the environment");
+    let pool = connect_memory_db().await.unwrap();
+    let client = CredentialClient::new(&pool);
+    assert_eq!(
+        credential("CEREAL_TEST_SITE_PASSWORD").as_deref(),
+        Some("from the environment")
+    );
+
+    let created = client
+        .create_credential("CEREAL_TEST_SITE_PASSWORD", "hunter2")
+        .await
+        .unwrap();
+    assert_eq!(
+        credential("CEREAL_TEST_SITE_PASSWORD").as_deref(),
+        Some("hunter2")
+    );
+    let (stored,): (Vec<u8>,) = sqlx::query_as("SELECT value FROM credentials")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(!stored.windows(7).any(|x| x == b"hunter2"));
+
+    let error = client
+        .create_credential("CEREAL_TEST_SITE_PASSWORD", "again")
+        .await
+        .unwrap_err();
+    assert!(matches!(error, ApiError::InvalidRequest(_)), "{}", error);
+
+    let rotated = client
+        .rotate_credential("CEREAL_TEST_SITE_PASSWORD", "correct horse")
+        .await
+        .unwrap();
+    assert_eq!(rotated.id, created.id);
+    assert_eq!(
+        credential("CEREAL_TEST_SITE_PASSWORD").as_deref(),
+        Some("correct horse")
+    );
+    assert_eq!(client.list_credentials().await.unwrap(), [rotated]);
+
+    client
+        .delete_credential("CEREAL_TEST_SITE_PASSWORD")
+        .await
+        .unwrap();
+    assert_eq!(
+        credential("CEREAL_TEST_SITE_PASSWORD").as_deref(),
+        Some("from the environment")
+    );
+    let error = client
+        .rotate_credential("CEREAL_TEST_SITE_PASSWORD", "missing")
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, ApiError::ResourceNotFound { .. }),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn credentials_in_use_are_kept() {
+    env::set_var("CEREAL_CREDENTIALS_KEY", "a key only the tests know");
+    let pool = connect_memory_db().await.unwrap();
+    let client = CredentialClient::new(&pool);
+    client
+        .create_credential("CEREAL_TEST_BOOK_PASSWORD", "hunter2")
+        .await
+        .unwrap();
+    let book = insert_book(&pool, 1).await;
+    let books = BookClient::new(&pool);
+    books
+        .set_book_password(&book.id, None, Some("CEREAL_TEST_BOOK_PASSWORD"))
+        .await
+        .unwrap();
+
+    let error = client
+        .delete_credential("CEREAL_TEST_BOOK_PASSWORD")
+        .await
+        .unwrap_err();
+    assert!(matches!(error, ApiError::InvalidRequest(_)), "{}", error);
+    assert!(client
+        .get_credential("CEREAL_TEST_BOOK_PASSWORD")
+        .await
+        .unwrap()
+        .is_some());
+
+    books.set_book_password(&book.id, None, None).await.unwrap();
+    client
+        .delete_credential("CEREAL_TEST_BOOK_PASSWORD")
+        .await
+        .unwrap();
+}
@@ -0,0 +1,37 @@
+//! Checks that delivery templates substitute each variable once, so that values such as
+//! chapter titles containing `{variable}`s are left as they are.
+
+mod common;
+
+use cereal_rewrite::{
+    models::{BookClient, ChapterClient, ChapterMetadata, DeliveryTemplates},
+    tasks::delivery::delivery_names,
+};
+use common::{connect_memory_db, insert_book};
+
+#[tokio::test]
+async fn values_are_not_substituted_again() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await;
+    let templates = DeliveryTemplates {
+        file_name: Some(String::from("{sequence} {chapter}")),
+        subject: Some(String::from("{book}: {chapter} ({count}) {unknown}")),
+        notification: None,
+    };
+    let book = BookClient::new(&pool)
+        .update_book(&book.id, None, None, None, None, Some(&templates), None)
+        .await
+        .unwrap();
+    let metadata = ChapterMetadata::RoyalRoad {
+        royalroad_book_id: 1,
+        royalroad_chapter_id: 1,
+    };
+    let chapter = ChapterClient::new(&pool)
+        .create_chapter(&book.id, "{count} {book}", &metadata, None, None, None)
+        .await
+        .unwrap();
+
+    let names = delivery_names(&book, &[chapter]);
+    assert_eq!(names.subject, "Title: {count} {book} (1) {unknown}");
+    assert_eq!(names.file_name, "0001 {count} {book}");
+}
@@ -0,0 +1,354 @@
+//! Generates epubs of fixture chapters with the native backend, checks that each is a
+//! well formed epub with the expected table of contents and metadata, and compares its
+//! contents with the golden file under `tests/golden`. Write or accept golden files by running
+//! with CEREAL_UPDATE_FIXTURES=true.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{Cursor, Read},
+    path::Path,
+};
+
+use cereal_rewrite::{
+    models::{
+        Book, BookMetadata, Chapter, ChapterMetadata, ChapterState, ConversionOptions,
+        DeliveryTemplates, Volume, VolumePosition,
+    },
+    tasks::chapter_body_conversion::{generate_multichapter_epub, generate_single_chapter_epub},
+};
+use chrono::{DateTime, TimeZone, Utc};
+use uuid::Uuid;
+use zip::{CompressionMethod, ZipArchive};
+
+fn timestamp(day: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2023, 1, day, 12, 0, 0).unwrap()
+}
+
+fn book() -> Book {
+    Book {
+        id: Uuid::from_u128(1),
+        title: String::from("Mother of Learning"),
+        author: String::from("nobody103"),
+        metadata: BookMetadata::Pale,
+        password: None,
+        password_credential: None,
+        conversion_options: ConversionOptions {
+            language: Some(String::from("en")),
+            publisher: Some(String::from("Royal Road")),
+            extra_css: Some(String::from("p { text-indent: 1em; }")),
+            ..Default::default()
+        },
+        delivery_templates: DeliveryTemplates::default(),
+        ignore_robots_txt: false,
+        early_access_secs: None,
+        detect_volumes: false,
+        public_metadata: None,
+        canonical_book_id: None,
+        archived_at: None,
+        last_discovered_at: None,
+        discovery_failures: 0,
+        last_discovery_error: None,
+        created_at: timestamp(1),
+        updated_at: timestamp(1),
+    }
+}
+
+fn chapter(order_index: i64, title: &str, html: &str) -> Chapter {
+    Chapter {
+        id: Uuid::from_u128(100 + order_index as u128),
+        title: String::from(title),
+        raw_title: None,
+        metadata: ChapterMetadata::Pale {
+            url: format!("https://example.com/{}", order_index),
+        },
+        book_id: Uuid::from_u128(1),
+        html: Some(html.as_bytes().to_vec()),
+        epub: None,
+        html_digest: None,
+        epub_digest: None,
+        conversion_version: None,
+        state: ChapterState::Hydrated,
+        failures: 0,
+        last_error: None,
+        volume_id: None,
+        published_at: Some(timestamp(order_index as u32 + 1)),
+        deliver_after: None,
+        public_published_at: None,
+        from_public_source: false,
+        ordinal: 0,
+        order_index,
+        trace_context: None,
+        source_key: None,
+        created_at: timestamp(order_index as u32 + 1),
+        updated_at: timestamp(order_index as u32 + 1),
+    }
+}
+
+/// The epub's files by name, after checking the archive is laid out as an epub.
+fn read_epub(epub: &[u8]) -> BTreeMap<String, String> {
+    let mut archive = ZipArchive::new(Cursor::new(epub)).expect("The epub is a zip");
+    {
+        let mut mimetype = archive.by_index(0).unwrap();
+        assert_eq!(mimetype.name(), "mimetype");
+        assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+        let mut contents = String::new();
+        mimetype.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "application/epub+zip");
+    }
+    let mut files = BTreeMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        files.insert(file.name().to_owned(), contents);
+    }
+    files
+}
+
+/// The values of the attribute on every tag with the name, in order.
+fn attributes(xml: &str, tag: &str, attribute: &str) -> Vec<String> {
+    xml.split(&format!("<{} ", tag))
+        .skip(1)
+        .filter_map(|x| {
+            let tag = &x[..x.find('>')?];
+            let value = tag.split(&format!("{}=\"", attribute)).nth(1)?;
+            Some(value[..value.find('"')?].to_owned())
+        })
+        .collect()
+}
+
+/// The text of every element with the name, in order.
+fn texts(xml: &str, tag: &str) -> Vec<String> {
+    xml.split(&format!("<{}>", tag))
+        .skip(1)
+        .filter_map(|x| Some(x[..x.find(&format!("</{}>", tag))?].to_owned()))
+        .collect()
+}
+
+/// Checks that every opened element is closed in order, as xhtml requires.
+fn assert_well_formed(name: &str, xml: &str) {
+    let mut open: Vec<&str> = Vec::new();
+    for tag in xml.split('<').skip(1).map(|x| &x[..x.find('>').unwrap()]) {
+        if tag.starts_with('?') || tag.starts_with('!') || tag.ends_with('/') {
+            continue;
+        }
+        match tag.strip_prefix('/') {
+            Some(closed) => assert_eq!(open.pop(), Some(closed), "Mismatched tag in {}", name),
+            None => open.push(tag.split_whitespace().next().unwrap()),
+        }
+    }
+    assert!(open.is_empty(), "Unclosed tags {:?} in {}", open, name);
+}
+
+/// Checks the epub's package and table of contents, returning its files.
+fn check_epub(
+    epub: &[u8],
+    title: &str,
+    author: &str,
+    sections: &[&str],
+) -> BTreeMap<String, String> {
+    let files = read_epub(epub);
+    for (name, contents) in &files {
+        if name.ends_with(".xml")
+            || name.ends_with(".opf")
+            || name.ends_with(".ncx")
+            || name.ends_with(".xhtml")
+        {
+            assert_well_formed(name, contents);
+        }
+    }
+
+    let container = &files["META-INF/container.xml"];
+    let package_path = &attributes(container, "rootfile", "full-path")[0];
+    let package = &files[package_path];
+    let package_dir = Path::new(package_path).parent().unwrap();
+    assert_eq!(texts(package, "dc:title"), [title]);
+    assert_eq!(texts(package, "dc:creator"), [author]);
+    assert_eq!(texts(package, "dc:language").len(), 1);
+    assert_eq!(attributes(package, "dc:identifier", "id"), ["id"]);
+
+    let manifest: BTreeMap<String, String> = attributes(package, "item", "id")
+        .into_iter()
+        .zip(attributes(package, "item", "href"))
+        .collect();
+    for href in manifest.values() {
+        let path = package_dir.join(href).to_string_lossy().into_owned();
+        assert!(
+            files.contains_key(&path),
+            "Manifest item {} is missing",
+            path
+        );
+    }
+    let spine = attributes(package, "itemref", "idref");
+    for idref in &spine {
+        assert!(
+            manifest.contains_key(idref),
+            "Spine item {} isn't in the manifest",
+            idref
+        );
+    }
+    assert_eq!(spine.len(), sections.len());
+
+    let ncx = &files[&package_dir
+        .join(&manifest["ncx"])
+        .to_string_lossy()
+        .into_owned()];
+    let ncx_titles: Vec<String> = texts(ncx, "text").into_iter().skip(1).collect();
+    assert_eq!(ncx_titles, sections);
+    let nav = &files[&package_dir
+        .join(&manifest["nav"])
+        .to_string_lossy()
+        .into_owned()];
+    let nav_links: Vec<String> = texts(nav, "li")
+        .iter()
+        .map(|x| x[x.find('>').unwrap() + 1..x.find("</a>").unwrap()].to_owned())
+        .collect();
+    assert_eq!(nav_links, sections);
+    files
+}
+
+/// Compares the epub's files with the golden file, ignoring when it was generated.
+fn assert_golden(name: &str, files: &BTreeMap<String, String>) {
+    let modified = "<meta property=\"dcterms:modified\">";
+    let rendered: String = files
+        .iter()
+        .map(|(name, contents)| {
+            let contents = match contents.find(modified) {
+                Some(start) => {
+                    let end = start + contents[start..].find("</meta>").unwrap();
+                    format!(
+                        "{}{}{}",
+                        &contents[..start + modified.len()],
+                        "MODIFIED",
+                        &contents[end..]
+                    )
+                }
+                None => contents.clone(),
+            };
+            format!("==> {} <==\n{}\n", name, contents)
+        })
+        .collect();
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.txt", name));
+    if env::var("CEREAL_UPDATE_FIXTURES").is_ok_and(|x| x == "true") {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &rendered).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_else(|_| {
+        panic!(
+            "{} is missing, run with CEREAL_UPDATE_FIXTURES=true to write it",
+            path.display()
+        )
+    });
+    assert!(
+        expected == rendered,
+        "{} no longer matches, got:\n{}",
+        path.display(),
+        rendered
+    );
+}
+
+fn use_native_backend() {
+    env::set_var("CEREAL_EPUB_BACKEND", "native");
+}
+
+#[tokio::test]
+async fn single_chapter_epub() {
+    use_native_backend();
+    let book = book();
+    let chapter = chapter(
+        0,
+        "1. Good Morning Brother",
+        "<p>Zorian's eyes shot open.</p><script>alert(1)</script><p onclick=\"x()\">Kirielle & the <br> bed.</p>",
+    );
+    let epub = generate_single_chapter_epub(&book, &chapter, None)
+        .await
+        .unwrap();
+    let files = check_epub(
+        &epub,
+        "Mother of Learning: 1. Good Morning Brother",
+        "nobody103",
+        &["1. Good Morning Brother"],
+    );
+    assert_golden("single_chapter_epub", &files);
+}
+
+#[tokio::test]
+async fn single_chapter_epub_in_volume() {
+    use_native_backend();
+    let book = book();
+    let chapter = chapter(4, "5. Life's Little Problems", "<p>Arc one.</p>");
+    let position = VolumePosition {
+        volume: Volume {
+            id: Uuid::from_u128(200),
+            book_id: book.id,
+            number: 1,
+            title: Some(String::from("Groundhog Day")),
+            detected: true,
+            created_at: timestamp(1),
+            updated_at: timestamp(1),
+        },
+        index: 5,
+    };
+    let epub = generate_single_chapter_epub(&book, &chapter, Some(&position))
+        .await
+        .unwrap();
+    let files = check_epub(
+        &epub,
+        "Mother of Learning: 5. Life's Little Problems",
+        "nobody103",
+        &["5. Life's Little Problems"],
+    );
+    assert_golden("single_chapter_epub_in_volume", &files);
+}
+
+#[tokio::test]
+async fn single_chapter_epub_language_is_detected() {
+    use_native_backend();
+    let mut book = book();
+    book.conversion_options.language = None;
+    let chapter = chapter(
+        0,
+        "1. Un hidalgo",
+        "<p>En un lugar de la Mancha, de cuyo nombre no quiero acordarme, no ha mucho tiempo que vivía un hidalgo de los de lanza en astillero.</p>",
+    );
+    let epub = generate_single_chapter_epub(&book, &chapter, None)
+        .await
+        .unwrap();
+    let files = read_epub(&epub);
+    assert_eq!(texts(&files["OEBPS/content.opf"], "dc:language"), ["es"]);
+    assert!(files["OEBPS/section-0.xhtml"].contains(" lang=\"es\" xml:lang=\"es\""));
+}
+
+#[tokio::test]
+async fn multichapter_epub() {
+    use_native_backend();
+    let book = book();
+    // Given out of order, to check they are put in reading order.
+    let chapters = [
+        chapter(2, "3. Sparring & Spells", "<p>Third.</p>"),
+        chapter(0, "1. Good Morning Brother", "<p>First.</p>"),
+        chapter(
+            1,
+            "2. Life's Little Problems",
+            "<p>Second.</p><hr><p>Still second.</p>",
+        ),
+    ];
+    let epub = generate_multichapter_epub("Mother of Learning: 1-3", &chapters, &book, None)
+        .await
+        .unwrap();
+    let files = check_epub(
+        &epub,
+        "Mother of Learning: 1-3",
+        "nobody103",
+        &[
+            "1. Good Morning Brother",
+            "2. Life's Little Problems",
+            "3. Sparring &amp; Spells",
+        ],
+    );
+    assert_golden("multichapter_epub", &files);
+}
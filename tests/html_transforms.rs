@@ -0,0 +1,253 @@
+//! Checks that a book's html transforms are read from its conversion options, validated, and
+//! applied to chapter html in order, that changing them sends converted chapters back to be
+//! converted again, and that its extra ebook-convert arguments are limited to allowed options.
+
+mod common;
+
+use cereal_rewrite::{
+    models::{
+        BookClient, ChapterClient, ChapterMetadata, ChapterState, ConversionOptions, HtmlTransform,
+        TypographyOptions,
+    },
+    tasks::chapter_body_conversion::{apply_transforms, validate_extra_args, validate_transforms},
+};
+use common::{connect_memory_db, insert_book};
+
+fn transform(html: &str, transforms: &[HtmlTransform]) -> String {
+    String::from_utf8(apply_transforms(html.as_bytes(), transforms).unwrap()).unwrap()
+}
+
+#[test]
+fn transforms_are_read_from_conversion_options() {
+    let options: ConversionOptions = serde_json::from_str(
+        r#"{"transforms": [
+            {"kind": "replace", "pattern": "\\*\\*\\*", "replacement": "<hr/>"},
+            {"kind": "strip", "selector": ".patreon-plug"},
+            {"kind": "inlineFootnotes"}
+        ]}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        options.transforms,
+        [
+            HtmlTransform::Replace {
+                pattern: String::from(r"\*\*\*"),
+                replacement: String::from("<hr/>"),
+            },
+            HtmlTransform::Strip {
+                selector: String::from(".patreon-plug"),
+            },
+            HtmlTransform::InlineFootnotes { references: None },
+        ]
+    );
+    assert_eq!(
+        serde_json::from_str::<ConversionOptions>("{}")
+            .unwrap()
+            .transforms,
+        []
+    );
+}
+
+#[test]
+fn invalid_transforms_are_rejected() {
+    for invalid in [
+        HtmlTransform::Replace {
+            pattern: String::from("(unclosed"),
+            replacement: String::new(),
+        },
+        HtmlTransform::Strip {
+            selector: String::from("div >"),
+        },
+        HtmlTransform::InlineFootnotes {
+            references: Some(String::from("[")),
+        },
+    ] {
+        assert!(validate_transforms(&[invalid]).is_err());
+    }
+}
+
+#[test]
+fn transforms_are_applied_in_order() {
+    let html =
+        "<p>Start</p><p>***</p><div class=\"patreon-plug\"><p>Support me</p></div><p>End</p>";
+    let transforms = [
+        HtmlTransform::Replace {
+            pattern: String::from(r"<p>\*\*\*</p>"),
+            replacement: String::from("<hr>"),
+        },
+        HtmlTransform::Strip {
+            selector: String::from(".patreon-plug"),
+        },
+        HtmlTransform::Replace {
+            pattern: String::from("(Start|End)"),
+            replacement: String::from("[$1]"),
+        },
+    ];
+    assert_eq!(
+        transform(html, &transforms),
+        "<p>[Start]</p><hr><p>[End]</p>"
+    );
+    assert_eq!(transform(html, &[]), html);
+}
+
+#[test]
+fn footnotes_are_inlined() {
+    let html = concat!(
+        "<p>The spell failed<a href=\"#fn1\">1</a>.</p>",
+        "<ol><li id=\"fn1\">As spells do. <a href=\"#ref1\">↩</a></li></ol>"
+    );
+    let transforms = [HtmlTransform::InlineFootnotes { references: None }];
+    assert_eq!(
+        transform(html, &transforms),
+        "<p>The spell failed <small>[As spells do.]</small>.</p><ol></ol>"
+    );
+}
+
+/// Converts footnotes to endnotes, with the chapter's id prefix replaced by "note".
+fn endnotes(html: &str) -> String {
+    let converted = transform(html, &[HtmlTransform::Endnotes]);
+    match converted.find("note-") {
+        Some(start) => converted.replace(&converted[start..start + 13], "note"),
+        None => converted,
+    }
+}
+
+#[test]
+fn linked_footnotes_become_endnotes() {
+    let html = concat!(
+        "<p>The spell failed<a href=\"#fn1\" id=\"ref1\">1</a>, again<a href=\"#fn1\">1</a>.</p>",
+        "<ol><li id=\"fn1\">As spells do. <a href=\"#ref1\">↩</a></li></ol>"
+    );
+    assert_eq!(
+        endnotes(html),
+        concat!(
+            "<p>The spell failed",
+            "<a href=\"#note-1\" id=\"note-ref-1\" epub:type=\"noteref\" role=\"doc-noteref\"><sup>1</sup></a>",
+            ", again",
+            "<a href=\"#note-1\" id=\"note-ref-1\" epub:type=\"noteref\" role=\"doc-noteref\"><sup>1</sup></a>",
+            ".</p>",
+            "<section epub:type=\"endnotes\" role=\"doc-endnotes\"><hr/><ol>",
+            "<li id=\"note-1\" epub:type=\"endnote\" role=\"doc-endnote\">As spells do. ",
+            "<a href=\"#note-ref-1\" epub:type=\"backlink\" role=\"doc-backlink\">↩</a></li>",
+            "</ol></section>"
+        )
+    );
+}
+
+#[test]
+fn numbered_footnotes_become_endnotes() {
+    let html =
+        "<p>Mana<sup>1</sup> and qi[2].</p><p>[2] Borrowed.</p><p><sup>1</sup> A resource.</p>";
+    assert_eq!(
+        endnotes(html),
+        concat!(
+            "<p>Mana",
+            "<a href=\"#note-2\" id=\"note-ref-2\" epub:type=\"noteref\" role=\"doc-noteref\"><sup>2</sup></a>",
+            " and qi",
+            "<a href=\"#note-1\" id=\"note-ref-1\" epub:type=\"noteref\" role=\"doc-noteref\"><sup>1</sup></a>",
+            ".</p>",
+            "<section epub:type=\"endnotes\" role=\"doc-endnotes\"><hr/><ol>",
+            "<li id=\"note-1\" epub:type=\"endnote\" role=\"doc-endnote\">Borrowed. ",
+            "<a href=\"#note-ref-1\" epub:type=\"backlink\" role=\"doc-backlink\">↩</a></li>",
+            "<li id=\"note-2\" epub:type=\"endnote\" role=\"doc-endnote\">A resource. ",
+            "<a href=\"#note-ref-2\" epub:type=\"backlink\" role=\"doc-backlink\">↩</a></li>",
+            "</ol></section>"
+        )
+    );
+    let plain = "<p>Nothing to see [here].</p>";
+    assert_eq!(endnotes(plain), plain);
+}
+
+#[test]
+fn typography_is_cleaned_up() {
+    let typography = [HtmlTransform::Typography(TypographyOptions::default())];
+    let html = concat!(
+        "<p>\"It's the '90s,\" she said -- quietly... \"<i>'Run,'</i> he said.\"</p>",
+        "<p> * * * </p><p><b>~~~</b></p><p>###</p>",
+        "<p><a href=\"a--b...html\" title=\"don't\">Link</a> <code>x -- \"y\"</code></p>"
+    );
+    assert_eq!(
+        transform(html, &typography),
+        concat!(
+            "<p>“It’s the ’90s,” she said — quietly… “<i>‘Run,’</i> he said.”</p>",
+            "<hr class=\"scene-break\"/><hr class=\"scene-break\"/><hr class=\"scene-break\"/>",
+            "<p><a href=\"a--b...html\" title=\"don't\">Link</a> <code>x -- \"y\"</code></p>"
+        )
+    );
+
+    let options: TypographyOptions =
+        serde_json::from_str(r#"{"quotes": false, "sceneBreaks": false}"#).unwrap();
+    let quotes_only = [HtmlTransform::Typography(options)];
+    assert_eq!(
+        transform("<p>\"Wait...\"</p><p>***</p>", &quotes_only),
+        "<p>\"Wait…\"</p><p>***</p>"
+    );
+}
+
+#[test]
+fn extra_args_are_limited_to_allowed_options() {
+    let args = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    assert!(validate_extra_args(&args(&[
+        "--smarten-punctuation",
+        "--margin-top",
+        "10",
+        "--epub-version=3"
+    ]))
+    .is_ok());
+    for invalid in [
+        &["--cover", "/etc/passwd"][..],
+        &["--read-metadata-from-opf=/tmp/x.opf"],
+        &["--margin-top"],
+        &["--smarten-punctuation=yes"],
+        &["input.html"],
+    ] {
+        assert!(validate_extra_args(&args(invalid)).is_err());
+    }
+}
+
+#[tokio::test]
+async fn changed_transforms_reconvert_chapters() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await;
+    let chapters = ChapterClient::new(&pool);
+    let metadata = ChapterMetadata::RoyalRoad {
+        royalroad_book_id: 1,
+        royalroad_chapter_id: 1,
+    };
+    let body = b"<p>Body</p>".to_vec();
+    let chapter = chapters
+        .create_chapter(
+            &book.id,
+            "Chapter",
+            &metadata,
+            Some(&body),
+            Some(&body),
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(chapter.state, ChapterState::Converted);
+
+    let books = BookClient::new(&pool);
+    let mut options = ConversionOptions {
+        extra_css: Some(String::from("p { margin: 0 }")),
+        ..book.conversion_options.clone()
+    };
+    books
+        .update_book(&book.id, None, None, None, Some(&options), None, None)
+        .await
+        .unwrap();
+    let unchanged = chapters.get_chapter(chapter.id).await.unwrap().unwrap();
+    assert_eq!(unchanged.state, ChapterState::Converted);
+
+    options.transforms = vec![HtmlTransform::Strip {
+        selector: String::from(".patreon-plug"),
+    }];
+    books
+        .update_book(&book.id, None, None, None, Some(&options), None, None)
+        .await
+        .unwrap();
+    let requeued = chapters.get_chapter(chapter.id).await.unwrap().unwrap();
+    assert_eq!(requeued.state, ChapterState::Hydrated);
+    assert!(requeued.epub.is_none());
+}
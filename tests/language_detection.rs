@@ -0,0 +1,47 @@
+//! Checks that a chapter's language is told from its text, and left undecided when the text is
+//! too short to tell.
+
+use cereal_rewrite::tasks::chapter_body_conversion::detect_language;
+
+#[test]
+fn languages_are_detected_from_text() {
+    for (expected, html) in [
+        (
+            "en",
+            "<p>Zorian's eyes shot open as a sharp pain erupted from his stomach. His whole body convulsed, buckling against the intruding object, and he was suddenly wide awake, not a trace of drowsiness in his mind.</p>",
+        ),
+        (
+            "es",
+            "<p>En un lugar de la Mancha, de cuyo nombre no quiero acordarme, no ha mucho tiempo que vivía un hidalgo de los de lanza en astillero, adarga antigua, rocín flaco y galgo corredor.</p>",
+        ),
+        (
+            "fr",
+            "<p>Longtemps, je me suis couché de bonne heure. Parfois, à peine ma bougie éteinte, mes yeux se fermaient si vite que je n'avais pas le temps de me dire : je m'endors. Et, une demi-heure après, la pensée qu'il était temps de chercher le sommeil m'éveillait.</p>",
+        ),
+        (
+            "de",
+            "<p>Als Gregor Samsa eines Morgens aus unruhigen Träumen erwachte, fand er sich in seinem Bett zu einem ungeheueren Ungeziefer verwandelt. Er lag auf seinem panzerartig harten Rücken und sah, wenn er den Kopf ein wenig hob, seinen gewölbten, braunen Bauch.</p>",
+        ),
+        (
+            "ja",
+            "<p>吾輩は猫である。名前はまだ無い。どこで生れたかとんと見当がつかぬ。何でも薄暗いじめじめした所でニャーニャー泣いていた事だけは記憶している。</p>",
+        ),
+        (
+            "ru",
+            "<p>Все счастливые семьи похожи друг на друга, каждая несчастливая семья несчастлива по-своему.</p>",
+        ),
+        (
+            "uk",
+            "<p>Як умру, то поховайте мене на могилі серед степу широкого, на Вкраїні милій.</p>",
+        ),
+    ] {
+        assert_eq!(detect_language(html.as_bytes()), Some(expected), "{}", html);
+    }
+}
+
+#[test]
+fn short_text_is_undecided() {
+    for html in ["", "<p>Chapter 12</p>", "<p><img src=\"map.png\"/></p>"] {
+        assert_eq!(detect_language(html.as_bytes()), None, "{}", html);
+    }
+}
@@ -0,0 +1,28 @@
+//! Checks that the log filter can be read and replaced while the process runs, and that invalid
+//! directives are refused.
+
+use cereal_rewrite::{
+    error::ApiError,
+    telemetry::{configure_telemetry, log_filter, parse_log_filter, set_log_filter},
+};
+
+#[test]
+fn log_filter_is_replaced_at_runtime() {
+    configure_telemetry();
+    assert_eq!(log_filter().as_deref(), Some("info"));
+
+    set_log_filter(parse_log_filter(Some("warn,cereal_rewrite::providers=debug")).unwrap());
+    let filter = log_filter().unwrap();
+    assert!(
+        filter.contains("cereal_rewrite::providers=debug"),
+        "{}",
+        filter
+    );
+    assert!(filter.contains("warn"), "{}", filter);
+
+    let error = parse_log_filter(Some("cereal_rewrite=loud")).unwrap_err();
+    assert!(matches!(error, ApiError::InvalidRequest(_)), "{}", error);
+
+    set_log_filter(parse_log_filter(None).unwrap());
+    assert_eq!(log_filter().as_deref(), Some("info"));
+}
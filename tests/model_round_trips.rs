@@ -0,0 +1,299 @@
+//! Checks that uuids and provider metadata survive being written to and read back from the
+//! database, including through each model's `FromRow`, and that malformed rows fail with an
+//! error naming what was wrong with them. Also checks that bodies stream back as they were
+//! stored.
+
+mod common;
+
+use cereal_rewrite::models::{
+    BookClient, BookMetadata, ChapterBody, ChapterClient, ChapterMetadata, ConversionOptions,
+    DeliveryTemplates, NewChapter, RoyalRoadOptions,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use common::{connect_memory_db, insert_book};
+use futures::TryStreamExt;
+use proptest::{option, prelude::*};
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+fn royalroad_options() -> impl Strategy<Value = RoyalRoadOptions> {
+    any::<(bool, bool, bool, bool)>().prop_map(|(before, after, spoilers, watermarks)| {
+        RoyalRoadOptions {
+            keep_author_note_before: before,
+            keep_author_note_after: after,
+            expand_spoilers: spoilers,
+            remove_watermarks: watermarks,
+        }
+    })
+}
+
+fn royalroad_book_metadata() -> impl Strategy<Value = BookMetadata> {
+    (any::<u64>(), royalroad_options())
+        .prop_map(|(book_id, options)| BookMetadata::RoyalRoad { book_id, options })
+}
+
+fn book_metadata() -> impl Strategy<Value = BookMetadata> {
+    prop_oneof![
+        royalroad_book_metadata(),
+        Just(BookMetadata::Pale),
+        Just(BookMetadata::TheWanderingInnPatreon),
+        Just(BookMetadata::TheDailyGrindPatreon),
+        Just(BookMetadata::ApparatusOfChangePatreon),
+    ]
+}
+
+fn chapter_metadata() -> impl Strategy<Value = ChapterMetadata> {
+    prop_oneof![
+        any::<(u64, u64)>().prop_map(|(royalroad_book_id, royalroad_chapter_id)| {
+            ChapterMetadata::RoyalRoad {
+                royalroad_book_id,
+                royalroad_chapter_id,
+            }
+        }),
+        any::<String>().prop_map(|url| ChapterMetadata::Pale { url }),
+        (any::<String>(), option::of(any::<String>()))
+            .prop_map(|(url, password)| ChapterMetadata::TheWanderingInnPatreon { url, password }),
+        Just(ChapterMetadata::TheDailyGrindPatreon),
+        Just(ChapterMetadata::ApparatusOfChangePatreon),
+        any::<String>().prop_map(|source| ChapterMetadata::Imported { source }),
+    ]
+}
+
+fn conversion_options() -> impl Strategy<Value = ConversionOptions> {
+    (
+        option::of(proptest::collection::vec(any::<String>(), 0..4)),
+        option::of(any::<String>()),
+        any::<bool>(),
+        proptest::collection::vec(any::<String>(), 0..4),
+        option::of("[a-z]{2}"),
+    )
+        .prop_map(
+            |(filter_css, extra_css, embed_all_fonts, extra_args, language)| ConversionOptions {
+                filter_css,
+                extra_css,
+                embed_all_fonts,
+                extra_args,
+                language,
+                ..Default::default()
+            },
+        )
+}
+
+/// Times as they are stored, without nanoseconds beyond sqlite's precision.
+fn timestamp() -> impl Strategy<Value = DateTime<Utc>> {
+    (0i64..4_102_444_800).prop_map(|x| Utc.timestamp_opt(x, 0).unwrap())
+}
+
+fn uuid() -> impl Strategy<Value = Uuid> {
+    any::<u128>().prop_map(Uuid::from_u128)
+}
+
+fn runtime() -> Runtime {
+    Runtime::new().unwrap()
+}
+
+proptest! {
+    #[test]
+    fn book_metadata_round_trips_through_json(metadata in book_metadata()) {
+        let json = metadata.json().unwrap();
+        prop_assert_eq!(serde_json::from_str::<BookMetadata>(&json).unwrap(), metadata);
+    }
+
+    #[test]
+    fn chapter_metadata_round_trips_through_json(metadata in chapter_metadata()) {
+        let json = metadata.json().unwrap();
+        prop_assert_eq!(serde_json::from_str::<ChapterMetadata>(&json).unwrap(), metadata);
+    }
+
+    #[test]
+    fn conversion_options_round_trip_through_json(options in conversion_options()) {
+        let json = options.json().unwrap();
+        prop_assert_eq!(serde_json::from_str::<ConversionOptions>(&json).unwrap(), options);
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn books_round_trip_through_the_database(
+        title in any::<String>(),
+        author in any::<String>(),
+        metadata in royalroad_book_metadata(),
+        options in conversion_options(),
+    ) {
+        let book = runtime().block_on(async {
+            let pool = connect_memory_db().await.unwrap();
+            let client = BookClient::new(&pool);
+            let created = client
+                .create_book(&title, &author, &metadata, &options, &DeliveryTemplates::default(), false)
+                .await
+                .unwrap();
+            client.get_book(&created.id).await.unwrap().unwrap()
+        });
+        prop_assert_eq!(book.title, title);
+        prop_assert_eq!(book.author, author);
+        prop_assert_eq!(book.metadata, metadata);
+        prop_assert_eq!(book.conversion_options, options);
+    }
+
+    #[test]
+    fn chapters_round_trip_through_the_database(
+        title in any::<String>(),
+        metadata in chapter_metadata(),
+        html in option::of(proptest::collection::vec(any::<u8>(), 0..256)),
+        published_at in option::of(timestamp()),
+    ) {
+        let (book_id, chapter) = runtime().block_on(async {
+            let pool = connect_memory_db().await.unwrap();
+            let book_id = insert_book(&pool, 21220).await.id;
+            let client = ChapterClient::new(&pool);
+            let created = client
+                .create_chapters(&vec![NewChapter {
+                    title: title.clone(),
+                    raw_title: None,
+                    metadata: metadata.clone(),
+                    book_id,
+                    html: html.clone(),
+                    epub: None,
+                    published_at,
+                    deliver_after: None,
+                    ordinal: 0,
+                    source: None,
+                }])
+                .await
+                .unwrap();
+            (book_id, client.get_chapter(created[0].id).await.unwrap().unwrap())
+        });
+        prop_assert_eq!(chapter.book_id, book_id);
+        prop_assert_eq!(chapter.title, title);
+        prop_assert_eq!(chapter.metadata, metadata);
+        prop_assert_eq!(chapter.html, html);
+        prop_assert_eq!(chapter.published_at, published_at);
+    }
+
+    #[test]
+    fn uuids_round_trip_through_the_database(id in uuid()) {
+        let book = runtime().block_on(async {
+            let pool = connect_memory_db().await.unwrap();
+            let book_id = insert_book(&pool, 21220).await.id;
+            sqlx::query("UPDATE books SET id = ? WHERE id = ?")
+                .bind(id.as_bytes().as_slice())
+                .bind(book_id.as_bytes().as_slice())
+                .execute(&pool)
+                .await
+                .unwrap();
+            BookClient::new(&pool).get_book(&id).await.unwrap()
+        });
+        prop_assert_eq!(book.map(|x| x.id), Some(id));
+    }
+
+    #[test]
+    fn malformed_uuids_fail_to_decode_with_their_length(
+        id in proptest::collection::vec(any::<u8>(), 0..40).prop_filter("not a uuid", |x| x.len() != 16),
+    ) {
+        let error = runtime().block_on(async {
+            let pool = connect_memory_db().await.unwrap();
+            let book_id = insert_book(&pool, 21220).await.id;
+            sqlx::query("UPDATE books SET id = ? WHERE id = ?")
+                .bind(id.as_slice())
+                .bind(book_id.as_bytes().as_slice())
+                .execute(&pool)
+                .await
+                .unwrap();
+            BookClient::new(&pool).list_books().await.unwrap_err()
+        });
+        let message = error.to_string();
+        prop_assert!(message.contains("column id"), "{}", message);
+        let expected = format!("found {} bytes", id.len());
+        prop_assert!(message.contains(&expected), "{}", message);
+    }
+}
+
+#[test]
+fn malformed_metadata_fails_to_decode_naming_the_column() {
+    let error = runtime().block_on(async {
+        let pool = connect_memory_db().await.unwrap();
+        let id = insert_book(&pool, 21220).await.id;
+        sqlx::query("UPDATE books SET metadata = '\"NotAProvider\"' WHERE id = ?")
+            .bind(id.as_bytes().as_slice())
+            .execute(&pool)
+            .await
+            .unwrap();
+        BookClient::new(&pool).get_book(&id).await.unwrap_err()
+    });
+    let message = error.to_string();
+    assert!(message.contains("column metadata"), "{}", message);
+    assert!(message.contains("NotAProvider"), "{}", message);
+}
+
+#[test]
+fn streamed_bodies_match_the_stored_body_until_it_is_replaced() {
+    let html: Vec<u8> = (0..600_000).map(|x| (x % 251) as u8).collect();
+    let (streamed, replaced) = runtime().block_on(async {
+        let pool = connect_memory_db().await.unwrap();
+        let book_id = insert_book(&pool, 21220).await.id;
+        let client = ChapterClient::new(&pool);
+        let metadata = ChapterMetadata::RoyalRoad {
+            royalroad_book_id: 21220,
+            royalroad_chapter_id: 1,
+        };
+        let chapter = client
+            .create_chapter(&book_id, "Long", &metadata, Some(&html), None, None)
+            .await
+            .unwrap();
+        let blob = client
+            .stream_body(&chapter.id, ChapterBody::Html)
+            .await
+            .unwrap()
+            .unwrap();
+        let streamed: Vec<Vec<u8>> = blob.chunks.try_collect().await.unwrap();
+
+        let blob = client
+            .stream_body(&chapter.id, ChapterBody::Html)
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .update_chapter(&chapter.id, None, Some(&b"<p>New</p>".to_vec()), None, None)
+            .await
+            .unwrap();
+        let replaced: Result<Vec<Vec<u8>>, _> = blob.chunks.try_collect().await;
+        (streamed, replaced)
+    });
+    assert_eq!(streamed.len(), 3);
+    assert_eq!(streamed.concat(), html);
+    assert!(replaced.is_err());
+}
+
+#[test]
+fn shallow_chapters_round_trip_through_the_database() {
+    let (listed, fetched, missing) = runtime().block_on(async {
+        let pool = connect_memory_db().await.unwrap();
+        let book_id = insert_book(&pool, 21220).await.id;
+        let client = ChapterClient::new(&pool);
+        let metadata = ChapterMetadata::RoyalRoad {
+            royalroad_book_id: 21220,
+            royalroad_chapter_id: 1,
+        };
+        let chapter = client
+            .create_chapter(
+                &book_id,
+                "Short",
+                &metadata,
+                Some(&b"<p>Body</p>".to_vec()),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let listed = client.list_chapters_shallow(&book_id).await.unwrap();
+        let fetched = client.get_chapter_shallow(&chapter.id).await.unwrap();
+        let missing = client.get_chapter_shallow(&Uuid::new_v4()).await.unwrap();
+        (listed, fetched, missing)
+    });
+    assert_eq!(listed.len(), 1);
+    assert_eq!(fetched.as_ref(), listed.first());
+    assert_eq!(fetched.unwrap().html_bytes, Some(11));
+    assert!(missing.is_none());
+}
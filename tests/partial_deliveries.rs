@@ -0,0 +1,80 @@
+//! Checks that a subscription which was sent part of a batch of chapters discovered together is
+//! still sent the rest of the batch.
+
+mod common;
+
+use cereal_rewrite::models::{
+    ChapterClient, ChapterMetadata, DeliveryFormat, NewChapter, SubscriberClient,
+    SubscriptionClient, SubscriptionDefaults,
+};
+use common::{connect_memory_db, insert_book};
+
+#[tokio::test]
+async fn rest_of_a_batch_follows_half_of_it() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await;
+    let batch: Vec<NewChapter> = (0..4)
+        .map(|ordinal| NewChapter {
+            title: format!("Chapter {}", ordinal),
+            raw_title: None,
+            deliver_after: None,
+            metadata: ChapterMetadata::RoyalRoad {
+                royalroad_book_id: 1,
+                royalroad_chapter_id: ordinal as u64,
+            },
+            book_id: book.id,
+            html: Some(b"<p>Body</p>".to_vec()),
+            epub: Some(b"epub".to_vec()),
+            published_at: None,
+            ordinal,
+            source: None,
+        })
+        .collect();
+    let client = ChapterClient::new(&pool);
+    let chapters = client.create_chapters(&batch).await.unwrap();
+    assert!(chapters
+        .windows(2)
+        .all(|x| x[0].created_at < x[1].created_at));
+
+    let subscriber = SubscriberClient::new(&pool)
+        .create_subscriber(
+            "reader",
+            None,
+            None,
+            Some("reader@example.com"),
+            None,
+            None,
+            &SubscriptionDefaults::default(),
+        )
+        .await
+        .unwrap();
+    let subscriptions = SubscriptionClient::new(&pool);
+    let subscription = subscriptions
+        .create_subscription(
+            &subscriber.id,
+            &book.id,
+            None,
+            None,
+            DeliveryFormat::default(),
+            None,
+        )
+        .await
+        .unwrap();
+    // Delivers the first half of the batch.
+    let subscription = subscriptions
+        .set_last_delivered_chapter(&subscription.id, &chapters[1].id, &chapters[1].created_at)
+        .await
+        .unwrap();
+
+    let pending: Vec<_> = client
+        .list_chapters_with_epub(
+            &book.id,
+            subscription.last_delivered_chapter_created_at.as_ref(),
+        )
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|x| x.id)
+        .collect();
+    assert_eq!(pending, [chapters[2].id, chapters[3].id]);
+}
@@ -0,0 +1,75 @@
+//! Runs each provider's parsing over the responses recorded under `tests/fixtures`, laid out as
+//! `<provider>/<kind>/<name>`, and compares the output with `<name>.expected.json` beside each.
+//! Record new fixtures by running with CEREAL_RECORD_FIXTURES set to a directory, then write
+//! or accept their expected output by running these tests with CEREAL_UPDATE_FIXTURES=true.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use cereal_rewrite::providers::fixtures::parse_fixture_file;
+
+const EXPECTED_SUFFIX: &str = ".expected.json";
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+fn fixture_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return files,
+    };
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(fixture_files(&path));
+        } else if !path.to_string_lossy().ends_with(EXPECTED_SUFFIX) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+fn expected_path(fixture: &Path) -> PathBuf {
+    let mut path = fixture.as_os_str().to_owned();
+    path.push(EXPECTED_SUFFIX);
+    PathBuf::from(path)
+}
+
+#[test]
+fn recorded_fixtures_parse_as_expected() {
+    let fixtures = fixtures_dir();
+    let update = env::var("CEREAL_UPDATE_FIXTURES").is_ok_and(|x| x == "true");
+    let mut failures = Vec::new();
+    for fixture in fixture_files(&fixtures) {
+        let parsed = match parse_fixture_file(&fixtures, &fixture) {
+            Ok(x) => serde_json::to_string_pretty(&x).unwrap() + "\n",
+            Err(e) => {
+                failures.push(format!("{}: {:#}", fixture.display(), e));
+                continue;
+            }
+        };
+        let expected_path = expected_path(&fixture);
+        if update {
+            fs::write(&expected_path, &parsed).unwrap();
+            continue;
+        }
+        match fs::read_to_string(&expected_path) {
+            Ok(expected) if expected == parsed => {}
+            Ok(_) => failures.push(format!(
+                "{} no longer parses as expected, got:\n{}",
+                fixture.display(),
+                parsed
+            )),
+            Err(_) => failures.push(format!(
+                "{} has no expected output, run with CEREAL_UPDATE_FIXTURES=true to write it",
+                fixture.display()
+            )),
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
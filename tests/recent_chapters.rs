@@ -0,0 +1,52 @@
+//! Checks that the recent chapters the output feeds are built from leave out chapters still in
+//! early access, until they are released.
+
+mod common;
+
+use cereal_rewrite::models::{ChapterClient, ChapterMetadata, NewChapter};
+use chrono::{Duration, Utc};
+use common::{connect_memory_db, insert_book};
+
+#[tokio::test]
+async fn early_access_chapters_stay_out_of_recent_chapters() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await;
+    let now = Utc::now();
+    let chapters: Vec<NewChapter> = [
+        ("Released", None),
+        ("Was early access", Some(now - Duration::hours(1))),
+        ("Early access", Some(now + Duration::hours(1))),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(ordinal, (title, deliver_after))| NewChapter {
+        title: String::from(title),
+        raw_title: None,
+        deliver_after,
+        metadata: ChapterMetadata::RoyalRoad {
+            royalroad_book_id: 1,
+            royalroad_chapter_id: ordinal as u64,
+        },
+        book_id: book.id,
+        html: Some(b"<p>Body</p>".to_vec()),
+        epub: None,
+        published_at: None,
+        ordinal: ordinal as i64,
+        source: None,
+    })
+    .collect();
+    let client = ChapterClient::new(&pool);
+    client.create_chapters(&chapters).await.unwrap();
+
+    for book_id in [Some(&book.id), None] {
+        let mut titles: Vec<String> = client
+            .list_recent_chapters(book_id, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.title)
+            .collect();
+        titles.sort();
+        assert_eq!(titles, ["Released", "Was early access"]);
+    }
+}
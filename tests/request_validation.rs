@@ -0,0 +1,87 @@
+//! Checks that request validation reports every field which fails, by its name in the request
+//! body, and lets valid values through.
+
+use cereal_rewrite::{
+    controllers::validation::Validator,
+    error::{ApiError, FieldError},
+    models::{max_chunk_size, ChapterMetadata},
+};
+
+fn failed_fields(validator: Validator) -> Vec<String> {
+    match validator.finish() {
+        Ok(()) => Vec::new(),
+        Err(ApiError::Validation(errors)) => errors.into_iter().map(|x| x.field).collect(),
+        Err(e) => panic!("Expected a validation error, got {}", e),
+    }
+}
+
+#[test]
+fn valid_fields_pass() {
+    let mut validator = Validator::default();
+    validator.non_empty("title", Some("1. Good Morning Brother"));
+    validator.non_empty("author", None);
+    validator.chunk_size("chunkSize", Some(1));
+    validator.chunk_size("chunkSize", Some(max_chunk_size()));
+    validator.email("email", Some("reader@example.com"));
+    validator.email("email", Some(" "));
+    validator.url("bookUrl", Some("https://www.royalroad.com/fiction/21220"));
+    validator.chapter_metadata(
+        "metadata",
+        &ChapterMetadata::Pale {
+            url: String::from("https://palewebserial.wordpress.com/2020/05/05/blood-run-cold-0-0/"),
+        },
+    );
+    assert!(validator.finish().is_ok());
+}
+
+#[test]
+fn every_invalid_field_is_reported() {
+    let mut validator = Validator::default();
+    validator.non_empty("title", Some("  "));
+    validator.chunk_size("chunkSize", Some(0));
+    validator.chunk_size("subscriptionDefaults.chunkSize", Some(-3));
+    validator.chunk_size("chunkSize", Some(max_chunk_size() + 1));
+    validator.email("email", Some("reader"));
+    validator.email("email", Some("reader@localhost"));
+    validator.email("email", Some("a reader@example.com"));
+    validator.url("bookUrl", Some("royalroad.com/fiction/21220"));
+    validator.url("bookUrl", Some("ftp://example.com"));
+    validator.chapter_metadata(
+        "metadata",
+        &ChapterMetadata::TheWanderingInnPatreon {
+            url: String::from("not a url"),
+            password: None,
+        },
+    );
+    assert_eq!(
+        failed_fields(validator),
+        [
+            "title",
+            "chunkSize",
+            "subscriptionDefaults.chunkSize",
+            "chunkSize",
+            "email",
+            "email",
+            "email",
+            "bookUrl",
+            "bookUrl",
+            "metadata.url",
+        ]
+    );
+}
+
+#[test]
+fn errors_describe_the_value() {
+    let mut validator = Validator::default();
+    validator.chunk_size("chunkSize", Some(-1));
+    match validator.finish() {
+        Err(ApiError::Validation(errors)) => assert_eq!(
+            errors,
+            [FieldError {
+                field: String::from("chunkSize"),
+                message: format!("must be between 1 and {}, found -1", max_chunk_size()),
+            }]
+        ),
+        x => panic!("Expected a validation error, got {:?}", x.err()),
+    }
+}
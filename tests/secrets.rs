@@ -0,0 +1,34 @@
+//! Checks that secrets are read from the environment, then from the file named by `_FILE`,
+//! then from the mounted secrets directory.
+
+use std::{env, fs};
+
+use cereal_rewrite::config::secret;
+
+#[test]
+fn secrets_are_read_from_the_environment_or_files() {
+    let dir = tempfile::tempdir().unwrap();
+    env::set_var("CEREAL_SECRETS_DIR", dir.path());
+    assert_eq!(secret("CEREAL_TEST_API_KEY"), None);
+
+    fs::write(dir.path().join("cereal_test_api_key"), "mounted\n").unwrap();
+    assert_eq!(secret("CEREAL_TEST_API_KEY").as_deref(), Some("mounted"));
+
+    let file = dir.path().join("api-key");
+    fs::write(&file, "from a file\r\n").unwrap();
+    env::set_var("CEREAL_TEST_API_KEY_FILE", &file);
+    assert_eq!(
+        secret("CEREAL_TEST_API_KEY").as_deref(),
+        Some("from a file")
+    );
+
+    env::set_var("CEREAL_TEST_API_KEY", "from the environment");
+    assert_eq!(
+        secret("CEREAL_TEST_API_KEY").as_deref(),
+        Some("from the environment")
+    );
+
+    env::remove_var("CEREAL_TEST_API_KEY");
+    env::set_var("CEREAL_TEST_API_KEY_FILE", dir.path().join("missing"));
+    assert_eq!(secret("CEREAL_TEST_API_KEY"), None);
+}
@@ -0,0 +1,87 @@
+//! Checks that the startup recovery pass clears the claims and leases left by a previous run of
+//! this instance, and re-queues chapters stuck in a state their bodies don't support, while
+//! leaving other workers' live claims alone. Also checks that a generated instance id is kept
+//! for the next run.
+
+mod common;
+
+use cereal_rewrite::{
+    models::{ChapterClient, ChapterMetadata, ChapterState, LeaseClient},
+    tasks::{instance_id, recovery::recover, stored_instance_id},
+};
+use common::{connect_memory_db, insert_book};
+
+#[tokio::test]
+async fn recovery_releases_claims_and_requeues_stuck_chapters() {
+    std::env::set_var("CEREAL_INSTANCE_ID", "recovering-instance");
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await;
+    let client = ChapterClient::new(&pool);
+    let mut chapters = Vec::new();
+    for royalroad_chapter_id in 1..=2 {
+        let metadata = ChapterMetadata::RoyalRoad {
+            royalroad_book_id: 1,
+            royalroad_chapter_id,
+        };
+        let html = b"<p>Body</p>".to_vec();
+        let chapter = client
+            .create_chapter(&book.id, "Chapter", &metadata, Some(&html), None, None)
+            .await
+            .unwrap();
+        chapters.push(chapter);
+    }
+    let lease = chrono::Duration::minutes(30);
+    assert!(client
+        .claim_chapter(&chapters[0].id, instance_id(), lease)
+        .await
+        .unwrap());
+    assert!(client
+        .claim_chapter(&chapters[1].id, "another-instance", lease)
+        .await
+        .unwrap());
+    // The previous run was killed between marking the chapter converted and storing its epub.
+    sqlx::query("UPDATE chapters SET state = 'converted' WHERE id = ?")
+        .bind(chapters[0].id.as_bytes().as_slice())
+        .execute(&pool)
+        .await
+        .unwrap();
+    let leases = LeaseClient::new(&pool);
+    assert!(leases
+        .acquire_lease("maintenance", instance_id(), chrono::Duration::hours(1))
+        .await
+        .unwrap());
+
+    let summary = recover(&pool).await.unwrap();
+    assert_eq!(summary.released_claims, 1);
+    assert_eq!(summary.released_leases, 1);
+    assert_eq!(summary.requeued_chapters, 1);
+
+    let chapter = client.get_chapter(chapters[0].id).await.unwrap().unwrap();
+    assert_eq!(chapter.state, ChapterState::Hydrated);
+    assert!(client
+        .claim_chapter(&chapters[0].id, "another-instance", lease)
+        .await
+        .unwrap());
+    assert!(!client
+        .claim_chapter(&chapters[1].id, instance_id(), lease)
+        .await
+        .unwrap());
+    assert!(leases
+        .acquire_lease(
+            "maintenance",
+            "another-instance",
+            chrono::Duration::hours(1)
+        )
+        .await
+        .unwrap());
+}
+
+#[test]
+fn generated_instance_ids_are_kept_across_restarts() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("instance_id");
+    let id = stored_instance_id(&path);
+    assert_eq!(stored_instance_id(&path), id);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), id);
+    assert_ne!(stored_instance_id(&dir.path().join("another")), id);
+}
@@ -0,0 +1,293 @@
+//! Checks that subscriptions can be moved between books and subscribers without sending the new
+//! book's backlog, that moves naming missing or mismatched rows are rejected, and that the
+//! chapters pending for a subscription follow its position.
+
+mod common;
+
+use cereal_rewrite::{
+    error::ApiError,
+    models::{
+        ChapterClient, ChapterMetadata, DeliveryFormat, SubscriberClient, Subscription,
+        SubscriptionClient, SubscriptionDefaults,
+    },
+};
+use common::{connect_memory_db, insert_book};
+use sqlx::{Pool, Sqlite};
+use uuid::Uuid;
+
+/// Inserts a converted chapter, ready to be delivered.
+async fn insert_chapter(pool: &Pool<Sqlite>, book_id: &Uuid, royalroad_chapter_id: u64) -> Uuid {
+    let metadata = ChapterMetadata::RoyalRoad {
+        royalroad_book_id: 0,
+        royalroad_chapter_id,
+    };
+    let body = b"<p>Chapter</p>".to_vec();
+    ChapterClient::new(pool)
+        .create_chapter(
+            book_id,
+            "Chapter",
+            &metadata,
+            Some(&body),
+            Some(&body),
+            None,
+        )
+        .await
+        .unwrap()
+        .id
+}
+
+async fn insert_subscriber(pool: &Pool<Sqlite>, name: &str) -> Uuid {
+    SubscriberClient::new(pool)
+        .create_subscriber(
+            name,
+            None,
+            None,
+            Some("reader@example.com"),
+            None,
+            None,
+            &SubscriptionDefaults::default(),
+        )
+        .await
+        .unwrap()
+        .id
+}
+
+async fn insert_subscription(
+    pool: &Pool<Sqlite>,
+    subscriber_id: &Uuid,
+    book_id: &Uuid,
+    last_delivered_chapter_id: Option<&Uuid>,
+) -> Subscription {
+    SubscriptionClient::new(pool)
+        .create_subscription(
+            subscriber_id,
+            book_id,
+            None,
+            None,
+            DeliveryFormat::default(),
+            last_delivered_chapter_id,
+        )
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn moving_to_another_book_skips_its_backlog() {
+    let pool = connect_memory_db().await.unwrap();
+    let (from, to) = (
+        insert_book(&pool, 1).await.id,
+        insert_book(&pool, 2).await.id,
+    );
+    let subscriber = insert_subscriber(&pool, "reader").await;
+    let subscription = insert_subscription(&pool, &subscriber, &from, None).await;
+    insert_chapter(&pool, &to, 1).await;
+    let latest = insert_chapter(&pool, &to, 2).await;
+
+    let moved = SubscriptionClient::new(&pool)
+        .move_subscription(&subscription.id, Some(&to), None, None, None, None, None)
+        .await
+        .unwrap();
+    assert_eq!(moved.book_id, to);
+    assert_eq!(moved.subscriber_id, subscriber);
+    assert_eq!(moved.last_delivered_chapter_id, Some(latest));
+}
+
+#[tokio::test]
+async fn moving_to_another_book_resumes_after_the_named_chapter() {
+    let pool = connect_memory_db().await.unwrap();
+    let (from, to) = (
+        insert_book(&pool, 1).await.id,
+        insert_book(&pool, 2).await.id,
+    );
+    let subscriber = insert_subscriber(&pool, "reader").await;
+    let subscription = insert_subscription(&pool, &subscriber, &from, None).await;
+    let first = insert_chapter(&pool, &to, 1).await;
+    insert_chapter(&pool, &to, 2).await;
+    let elsewhere = insert_chapter(&pool, &from, 3).await;
+    let client = SubscriptionClient::new(&pool);
+
+    let moved = client
+        .move_subscription(
+            &subscription.id,
+            Some(&to),
+            None,
+            Some(&first),
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(moved.last_delivered_chapter_id, Some(first));
+
+    let error = client
+        .move_subscription(
+            &subscription.id,
+            None,
+            None,
+            Some(&elsewhere),
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(error, ApiError::InvalidRequest(_)), "{}", error);
+}
+
+#[tokio::test]
+async fn transferring_keeps_delivery_progress_and_applies_updates() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await.id;
+    let chapter = insert_chapter(&pool, &book, 1).await;
+    let (from, to) = (
+        insert_subscriber(&pool, "reader").await,
+        insert_subscriber(&pool, "another reader").await,
+    );
+    let subscription = insert_subscription(&pool, &from, &book, Some(&chapter)).await;
+
+    let moved = SubscriptionClient::new(&pool)
+        .move_subscription(&subscription.id, None, Some(&to), None, Some(3), None, None)
+        .await
+        .unwrap();
+    assert_eq!(moved.subscriber_id, to);
+    assert_eq!(moved.chunk_size, 3);
+    assert_eq!(moved.book_id, book);
+    assert_eq!(moved.last_delivered_chapter_id, Some(chapter));
+}
+
+#[tokio::test]
+async fn moves_to_missing_rows_are_not_found() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await.id;
+    let subscriber = insert_subscriber(&pool, "reader").await;
+    let subscription = insert_subscription(&pool, &subscriber, &book, None).await;
+    let client = SubscriptionClient::new(&pool);
+    let missing = Uuid::new_v4();
+
+    for (book_id, subscriber_id, resource) in [
+        (Some(&missing), None, "book"),
+        (None, Some(&missing), "subscriber"),
+    ] {
+        let error = client
+            .move_subscription(
+                &subscription.id,
+                book_id,
+                subscriber_id,
+                None,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap_err();
+        match error {
+            ApiError::ResourceNotFound { resource_type, id } => {
+                assert_eq!(resource_type, resource);
+                assert_eq!(id, missing.to_string());
+            }
+            e => panic!("Expected {} to be missing, got {}", resource, e),
+        }
+    }
+}
+
+#[tokio::test]
+async fn pending_chapters_follow_the_position() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await.id;
+    let first = insert_chapter(&pool, &book, 1).await;
+    insert_chapter(&pool, &book, 2).await;
+    insert_chapter(&pool, &book, 3).await;
+    let subscriber = insert_subscriber(&pool, "reader").await;
+    let subscription = insert_subscription(&pool, &subscriber, &book, Some(&first)).await;
+    let client = SubscriptionClient::new(&pool);
+
+    let pending = client
+        .get_pending_subscription(subscription.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(pending.subscription, subscription);
+    assert_eq!(pending.pending_chapters, 2);
+
+    client
+        .set_position(&subscription.id, None, None)
+        .await
+        .unwrap();
+    let listed = client
+        .list_pending_subscriptions(Some(&subscriber), None)
+        .await
+        .unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].pending_chapters, 3);
+    let other_book = insert_book(&pool, 2).await.id;
+    assert!(client
+        .list_pending_subscriptions(None, Some(&other_book))
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn pending_counts_follow_moves_both_ways() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await.id;
+    let first = insert_chapter(&pool, &book, 1).await;
+    let second = insert_chapter(&pool, &book, 2).await;
+    insert_chapter(&pool, &book, 3).await;
+    // Not yet converted, so not waiting to be delivered.
+    let metadata = ChapterMetadata::RoyalRoad {
+        royalroad_book_id: 0,
+        royalroad_chapter_id: 4,
+    };
+    ChapterClient::new(&pool)
+        .create_chapter(&book, "Chapter", &metadata, None, None, None)
+        .await
+        .unwrap();
+    let subscriber = insert_subscriber(&pool, "reader").await;
+    let subscription = insert_subscription(&pool, &subscriber, &book, Some(&first)).await;
+    let client = SubscriptionClient::new(&pool);
+    assert_eq!(
+        client
+            .count_pending_chapters(
+                &book,
+                subscription.last_delivered_chapter_created_at.as_ref()
+            )
+            .await
+            .unwrap(),
+        2
+    );
+
+    let chapter = ChapterClient::new(&pool)
+        .get_chapter(second)
+        .await
+        .unwrap()
+        .unwrap();
+    let forward = client
+        .set_position(
+            &subscription.id,
+            Some(&chapter.id),
+            Some(&chapter.created_at),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .count_pending_chapters(&book, forward.last_delivered_chapter_created_at.as_ref())
+            .await
+            .unwrap(),
+        1
+    );
+
+    let backward = client
+        .set_position(&subscription.id, None, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .count_pending_chapters(&book, backward.last_delivered_chapter_created_at.as_ref())
+            .await
+            .unwrap(),
+        3
+    );
+}
@@ -0,0 +1,86 @@
+//! Checks that a wildcard subscription targeting a tag covers only the books with that tag,
+//! including books tagged after it was created, and drops books once they are untagged.
+
+mod common;
+
+use cereal_rewrite::{
+    models::{
+        ChapterClient, ChapterMetadata, SubscriberClient, SubscriptionClient, SubscriptionDefaults,
+        TagClient, WildcardSubscriptionClient,
+    },
+    tasks::delivery::sync_wildcard_subscriptions,
+};
+use common::{connect_memory_db, insert_book};
+use sqlx::{Pool, Sqlite};
+use uuid::Uuid;
+
+/// Inserts a book with one discovered chapter, so that wildcard subscriptions pick it up.
+async fn insert_book_with_chapter(pool: &Pool<Sqlite>, royalroad_book_id: u64) -> Uuid {
+    let book_id = insert_book(pool, royalroad_book_id).await.id;
+    let metadata = ChapterMetadata::RoyalRoad {
+        royalroad_book_id,
+        royalroad_chapter_id: royalroad_book_id,
+    };
+    ChapterClient::new(pool)
+        .create_chapter(&book_id, "Chapter", &metadata, None, None, None)
+        .await
+        .unwrap();
+    book_id
+}
+
+async fn subscribed_books(pool: &Pool<Sqlite>, subscriber_id: &Uuid) -> Vec<Uuid> {
+    let mut books: Vec<_> = SubscriptionClient::new(pool)
+        .list_subscriptions(subscriber_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|x| x.book_id)
+        .collect();
+    books.sort();
+    books
+}
+
+#[tokio::test]
+async fn tag_wildcards_follow_the_tag() {
+    let pool = connect_memory_db().await.unwrap();
+    let (tagged, untagged) = (
+        insert_book_with_chapter(&pool, 1).await,
+        insert_book_with_chapter(&pool, 2).await,
+    );
+    let tags = TagClient::new(&pool);
+    let tag = tags.create_tag("Progression").await.unwrap();
+    tags.tag_book(&tagged, &tag.id).await.unwrap();
+    let subscriber = SubscriberClient::new(&pool)
+        .create_subscriber(
+            "reader",
+            None,
+            None,
+            Some("reader@example.com"),
+            None,
+            None,
+            &SubscriptionDefaults::default(),
+        )
+        .await
+        .unwrap()
+        .id;
+    let wildcard = WildcardSubscriptionClient::new(&pool)
+        .create_wildcard_subscription(&subscriber, None, &[], Some(&tag.id))
+        .await
+        .unwrap();
+    assert_eq!(wildcard.tag_id, Some(tag.id));
+
+    sync_wildcard_subscriptions(&pool).await.unwrap();
+    assert_eq!(subscribed_books(&pool, &subscriber).await, [tagged]);
+
+    let later = insert_book_with_chapter(&pool, 3).await;
+    tags.tag_book(&later, &tag.id).await.unwrap();
+    sync_wildcard_subscriptions(&pool).await.unwrap();
+    let mut expected = vec![tagged, later];
+    expected.sort();
+    assert_eq!(subscribed_books(&pool, &subscriber).await, expected);
+    assert!(!expected.contains(&untagged));
+
+    tags.untag_book(&tagged, &tag.id).await.unwrap();
+    sync_wildcard_subscriptions(&pool).await.unwrap();
+    assert_eq!(subscribed_books(&pool, &subscriber).await, [later]);
+}
@@ -0,0 +1,45 @@
+//! Checks that a panic while working on one chapter is caught and recorded against that
+//! chapter, rather than taking down the loop's run.
+
+mod common;
+
+use cereal_rewrite::{
+    models::{ChapterClient, ChapterMetadata, ChapterState},
+    tasks::{catch_panic, with_panics_recorded},
+};
+use common::{connect_memory_db, insert_book};
+
+#[tokio::test]
+async fn panics_are_caught() {
+    assert_eq!(catch_panic(async { 1 }).await.unwrap(), 1);
+    let error = catch_panic(async { panic!("malformed chapter {}", 7) })
+        .await
+        .unwrap_err();
+    assert_eq!(error.to_string(), "Panicked: malformed chapter 7");
+}
+
+#[tokio::test]
+async fn panics_are_recorded_against_the_chapter() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await;
+    let client = ChapterClient::new(&pool);
+    let metadata = ChapterMetadata::RoyalRoad {
+        royalroad_book_id: 1,
+        royalroad_chapter_id: 1,
+    };
+    let chapter = client
+        .create_chapter(&book.id, "Chapter", &metadata, None, None, None)
+        .await
+        .unwrap();
+
+    with_panics_recorded(&client, &chapter.id, ChapterState::Discovered, async {
+        panic!("unexpected markup")
+    })
+    .await;
+    let chapter = client.get_chapter(chapter.id).await.unwrap().unwrap();
+    assert_eq!(chapter.failures, 1);
+    assert_eq!(
+        chapter.last_error.as_deref(),
+        Some("Panicked: unexpected markup")
+    );
+}
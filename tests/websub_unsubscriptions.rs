@@ -0,0 +1,50 @@
+//! Checks that a hub's verification of an unsubscription is only confirmed for one this server
+//! asked for, for the same book and topic, and only once.
+
+mod common;
+
+use cereal_rewrite::models::WebSubSubscriptionClient;
+use common::{connect_memory_db, insert_book};
+use uuid::Uuid;
+
+const TOPIC: &str = "https://example.com/feed";
+
+#[tokio::test]
+async fn unsubscriptions_are_confirmed_only_when_asked_for() {
+    let pool = connect_memory_db().await.unwrap();
+    let book = insert_book(&pool, 1).await.id;
+    let client = WebSubSubscriptionClient::new(&pool);
+    client
+        .upsert_subscription(&book, "https://hub.example.com", TOPIC, "secret")
+        .await
+        .unwrap();
+    // Nothing was asked for yet, nor for a book without a subscription.
+    assert!(!client.confirm_unsubscription(&book, TOPIC).await.unwrap());
+    assert!(!client
+        .confirm_unsubscription(&Uuid::new_v4(), TOPIC)
+        .await
+        .unwrap());
+
+    let unsubscribed = client.unsubscribe(&book).await.unwrap().unwrap();
+    assert_eq!(unsubscribed.topic, TOPIC);
+    assert!(client.get_subscription(&book).await.unwrap().is_none());
+    assert!(!client
+        .confirm_unsubscription(&book, "https://example.com/other")
+        .await
+        .unwrap());
+    assert!(client.confirm_unsubscription(&book, TOPIC).await.unwrap());
+    assert!(!client.confirm_unsubscription(&book, TOPIC).await.unwrap());
+    assert!(client.unsubscribe(&book).await.unwrap().is_none());
+
+    // Subscribing again drops an unsubscription the hub hasn't verified yet.
+    client
+        .upsert_subscription(&book, "https://hub.example.com", TOPIC, "secret")
+        .await
+        .unwrap();
+    client.unsubscribe(&book).await.unwrap();
+    client
+        .upsert_subscription(&book, "https://hub.example.com", TOPIC, "secret")
+        .await
+        .unwrap();
+    assert!(!client.confirm_unsubscription(&book, TOPIC).await.unwrap());
+}